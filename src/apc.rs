@@ -0,0 +1,344 @@
+//! this module provides kernel APC(asynchronous procedure call) support
+//!
+//! an APC runs a closure in the context of a specific thread:
+//! - a special kernel APC runs at APC_LEVEL as soon as the target thread is running, unless it is in a guarded region
+//! - a normal kernel APC runs at PASSIVE_LEVEL, unless the target thread is in a critical region or a guarded region
+//!
+//! # Noteworthy
+//! a queued APC references code and data of this driver, so all the APCs must be delivered or cancelled before unload,
+//! use `Apc`(cancelled on drop) or call `flush()` in `DriverUnload` for the fire-and-forget ones
+use core::{
+    mem,
+    ptr::{self, NonNull},
+    sync::atomic::{AtomicU32, AtomicUsize, Ordering},
+    time::Duration,
+};
+
+use alloc::boxed::Box;
+use wdk_sys::{
+    _MODE::KernelMode,
+    BOOLEAN, KAPC, KPRIORITY, KPROCESSOR_MODE, PKTHREAD, PRKAPC, PRKTHREAD, PVOID,
+    STATUS_UNSUCCESSFUL,
+    ntddk::{ObfDereferenceObject, ObfReferenceObject},
+};
+
+use crate::{ntstatus::NtError, thread::this_thread, utils::try_box};
+
+type KernelRoutine = Option<
+    unsafe extern "C" fn(
+        Apc: PRKAPC,
+        NormalRoutine: *mut NormalRoutine,
+        NormalContext: *mut PVOID,
+        SystemArgument1: *mut PVOID,
+        SystemArgument2: *mut PVOID,
+    ),
+>;
+
+type RundownRoutine = Option<unsafe extern "C" fn(Apc: PRKAPC)>;
+
+type NormalRoutine = Option<
+    unsafe extern "C" fn(NormalContext: PVOID, SystemArgument1: PVOID, SystemArgument2: PVOID),
+>;
+
+/// KAPC_ENVIRONMENT::OriginalApcEnvironment
+const ORIGINAL_APC_ENVIRONMENT: i32 = 0;
+
+unsafe extern "C" {
+    pub fn KeInitializeApc(
+        Apc: PRKAPC,
+        Thread: PRKTHREAD,
+        Environment: i32,
+        KernelRoutine: KernelRoutine,
+        RundownRoutine: RundownRoutine,
+        NormalRoutine: NormalRoutine,
+        ProcessorMode: KPROCESSOR_MODE,
+        NormalContext: PVOID,
+    );
+
+    pub fn KeInsertQueueApc(
+        Apc: PRKAPC,
+        SystemArgument1: PVOID,
+        SystemArgument2: PVOID,
+        Increment: KPRIORITY,
+    ) -> BOOLEAN;
+
+    pub fn KeRemoveQueueApc(Apc: PRKAPC) -> BOOLEAN;
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum ApcKind {
+    /// runs at APC_LEVEL
+    Special,
+    /// runs at PASSIVE_LEVEL
+    Normal,
+}
+
+/// number of fire-and-forget APCs that have not been delivered or run down yet
+static OUTSTANDING: AtomicUsize = AtomicUsize::new(0);
+
+#[repr(C)]
+struct ApcBlock {
+    /// must be the first field, the `PRKAPC` is used to find the block
+    kapc: KAPC,
+    callback: Box<dyn Fn() + Send>,
+    /// 1 when the APC is queued or running
+    inflight: AtomicU32,
+    thread: PKTHREAD,
+}
+
+/// A owned, re-queueable kernel APC targeting a specific thread
+///
+/// the APC is cancelled on drop, and drop waits for a running callback to return, so the closure can never be freed
+/// while it is in use
+///
+/// # Example
+/// ```
+/// let thread = ThreadObject::from_thread_id(tid)?;
+/// let apc = Apc::new(thread.as_ptr(), ApcKind::Normal, || {
+///     println!("running in thread {}", this_thread::id());
+/// })?;
+///
+/// apc.queue()?;
+/// ```
+pub struct Apc(NonNull<ApcBlock>);
+
+impl Apc {
+    pub fn new<F: Fn() + Send + 'static>(
+        thread: PKTHREAD,
+        kind: ApcKind,
+        f: F,
+    ) -> Result<Self, NtError> {
+        let block = try_box(ApcBlock {
+            kapc: unsafe { mem::zeroed() },
+            callback: try_box(f)?,
+            inflight: AtomicU32::new(0),
+            thread,
+        })?;
+
+        let block = Box::into_raw(block);
+
+        // keep the thread object alive as long as this APC
+        unsafe { ObfReferenceObject(thread.cast()) };
+
+        unsafe {
+            KeInitializeApc(
+                &mut (*block).kapc,
+                thread,
+                ORIGINAL_APC_ENVIRONMENT,
+                Some(owned_kernel_routine_stub),
+                Some(owned_rundown_routine_stub),
+                if kind == ApcKind::Normal {
+                    Some(owned_normal_routine_stub)
+                } else {
+                    None
+                },
+                KernelMode as _,
+                block.cast(),
+            );
+        }
+
+        Ok(Self(NonNull::new(block).unwrap()))
+    }
+
+    #[inline]
+    fn block(&self) -> &ApcBlock {
+        unsafe { self.0.as_ref() }
+    }
+
+    /// returns true if the APC is queued or its callback is running
+    pub fn is_pending(&self) -> bool {
+        self.block().inflight.load(Ordering::Acquire) != 0
+    }
+
+    /// queue the APC to the target thread
+    ///
+    /// fails if the APC is already pending or the thread is exiting
+    pub fn queue(&self) -> Result<(), NtError> {
+        let block = self.block();
+
+        if block
+            .inflight
+            .compare_exchange(0, 1, Ordering::AcqRel, Ordering::Relaxed)
+            .is_err()
+        {
+            return Err(NtError::new(STATUS_UNSUCCESSFUL));
+        }
+
+        if unsafe { KeInsertQueueApc(self.0.as_ptr().cast(), ptr::null_mut(), ptr::null_mut(), 0) }
+            == 0
+        {
+            block.inflight.store(0, Ordering::Release);
+
+            return Err(NtError::new(STATUS_UNSUCCESSFUL));
+        }
+
+        Ok(())
+    }
+
+    /// remove the APC from the queue of the target thread
+    ///
+    /// returns false if it is not queued, it may be running right now
+    pub fn cancel(&self) -> bool {
+        if unsafe { KeRemoveQueueApc(self.0.as_ptr().cast()) } != 0 {
+            self.block().inflight.store(0, Ordering::Release);
+            true
+        } else {
+            false
+        }
+    }
+}
+
+impl Drop for Apc {
+    fn drop(&mut self) {
+        self.cancel();
+
+        // the callback may be running in the target thread
+        while self.is_pending() {
+            this_thread::sleep(Duration::from_millis(1));
+        }
+
+        let block = unsafe { Box::from_raw(self.0.as_ptr()) };
+
+        unsafe { ObfDereferenceObject(block.thread.cast()) };
+    }
+}
+
+unsafe impl Send for Apc {}
+unsafe impl Sync for Apc {}
+
+extern "C" fn owned_kernel_routine_stub(
+    apc: PRKAPC,
+    normal_routine: *mut NormalRoutine,
+    normal_context: *mut PVOID,
+    arg1: *mut PVOID,
+    arg2: *mut PVOID,
+) {
+    let block = unsafe { &*(apc as *const ApcBlock) };
+
+    // a normal APC runs the callback in `owned_normal_routine_stub`
+    if unsafe { (*normal_routine).is_some() } {
+        return;
+    }
+
+    (block.callback)();
+
+    block.inflight.store(0, Ordering::Release);
+}
+
+extern "C" fn owned_normal_routine_stub(context: PVOID, arg1: PVOID, arg2: PVOID) {
+    let block = unsafe { &*(context as *const ApcBlock) };
+
+    (block.callback)();
+
+    block.inflight.store(0, Ordering::Release);
+}
+
+extern "C" fn owned_rundown_routine_stub(apc: PRKAPC) {
+    let block = unsafe { &*(apc as *const ApcBlock) };
+
+    block.inflight.store(0, Ordering::Release);
+}
+
+#[repr(C)]
+struct OneShotApc<F> {
+    /// must be the first field
+    kapc: KAPC,
+    callback: F,
+}
+
+fn queue_once<F: FnOnce() + Send + 'static>(
+    thread: PKTHREAD,
+    kind: ApcKind,
+    f: F,
+) -> Result<(), NtError> {
+    let context = Box::into_raw(try_box(OneShotApc {
+        kapc: unsafe { mem::zeroed() },
+        callback: f,
+    })?);
+
+    unsafe {
+        KeInitializeApc(
+            &mut (*context).kapc,
+            thread,
+            ORIGINAL_APC_ENVIRONMENT,
+            Some(once_kernel_routine_stub::<F>),
+            Some(once_rundown_routine_stub::<F>),
+            if kind == ApcKind::Normal {
+                Some(once_normal_routine_stub::<F>)
+            } else {
+                None
+            },
+            KernelMode as _,
+            context.cast(),
+        );
+    }
+
+    OUTSTANDING.fetch_add(1, Ordering::AcqRel);
+
+    if unsafe { KeInsertQueueApc(&mut (*context).kapc, ptr::null_mut(), ptr::null_mut(), 0) } == 0 {
+        OUTSTANDING.fetch_sub(1, Ordering::AcqRel);
+
+        let _ = unsafe { Box::from_raw(context) };
+
+        return Err(NtError::new(STATUS_UNSUCCESSFUL));
+    }
+
+    Ok(())
+}
+
+/// run `f` once in the context of `thread` at APC_LEVEL
+pub fn queue_special<F: FnOnce() + Send + 'static>(thread: PKTHREAD, f: F) -> Result<(), NtError> {
+    queue_once(thread, ApcKind::Special, f)
+}
+
+/// run `f` once in the context of `thread` at PASSIVE_LEVEL
+pub fn queue_normal<F: FnOnce() + Send + 'static>(thread: PKTHREAD, f: F) -> Result<(), NtError> {
+    queue_once(thread, ApcKind::Normal, f)
+}
+
+/// wait until all the fire-and-forget APCs queued by `queue_special` and `queue_normal` are delivered or run down
+///
+/// it must be called at PASSIVE_LEVEL, typically in `DriverUnload`
+pub fn flush() {
+    while OUTSTANDING.load(Ordering::Acquire) != 0 {
+        this_thread::sleep(Duration::from_millis(1));
+    }
+}
+
+/// returns the number of fire-and-forget APCs not delivered yet
+pub fn outstanding() -> usize {
+    OUTSTANDING.load(Ordering::Relaxed)
+}
+
+extern "C" fn once_kernel_routine_stub<F: FnOnce()>(
+    apc: PRKAPC,
+    normal_routine: *mut NormalRoutine,
+    normal_context: *mut PVOID,
+    arg1: *mut PVOID,
+    arg2: *mut PVOID,
+) {
+    if unsafe { (*normal_routine).is_some() } {
+        return;
+    }
+
+    let context = unsafe { Box::from_raw(apc as *mut OneShotApc<F>) };
+
+    (context.callback)();
+
+    OUTSTANDING.fetch_sub(1, Ordering::AcqRel);
+}
+
+extern "C" fn once_normal_routine_stub<F: FnOnce()>(context: PVOID, arg1: PVOID, arg2: PVOID) {
+    let context = unsafe { Box::from_raw(context as *mut OneShotApc<F>) };
+
+    (context.callback)();
+
+    OUTSTANDING.fetch_sub(1, Ordering::AcqRel);
+}
+
+/// the target thread exited before the APC is delivered, free the context without running it
+extern "C" fn once_rundown_routine_stub<F: FnOnce()>(apc: PRKAPC) {
+    let _ = unsafe { Box::from_raw(apc as *mut OneShotApc<F>) };
+
+    OUTSTANDING.fetch_sub(1, Ordering::AcqRel);
+}
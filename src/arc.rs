@@ -0,0 +1,426 @@
+//! this module provides `KArc<T>` and `KWeak<T>`, the kernel counterparts of `alloc::sync::Arc` and `alloc::sync::Weak`
+//!
+//! the differences between `KArc` and `Arc` are:
+//! - the shared block is allocated from `NonPagedPoolNx` with a caller specified pool tag, so it can be found in pool dumps
+//! - allocation failure is reported as an `NtError` instead of aborting the whole system
+//! - the shared block can be safely accessed at any IRQL
+//!
+//! `AtomicArc<T>` holds a `KArc<T>` which can be replaced atomically while readers take cheap snapshots of it
+use core::{
+    fmt::{Debug, Display},
+    marker::PhantomData,
+    mem::{self, ManuallyDrop},
+    ops::Deref,
+    pin::Pin,
+    ptr::{self, NonNull},
+    sync::atomic::{self, AtomicBool, AtomicPtr, AtomicUsize, Ordering},
+};
+
+use wdk_sys::{_POOL_TYPE::NonPagedPoolNx, STATUS_INVALID_PARAMETER};
+
+use crate::{
+    irql::{self, DISPATCH_LEVEL, RaiseIrqlGuard},
+    ntstatus::NtError,
+    thread::this_thread,
+    utils::{ex_allocate_pool_zero, ex_free_pool},
+};
+
+const ARC_TAG: u32 = u32::from_ne_bytes(*b"crak");
+
+/// pool allocations are guaranteed to be aligned on this boundary on x64
+const POOL_ALIGNMENT: usize = 16;
+
+struct ArcInner<T> {
+    strong: AtomicUsize,
+    /// all the strong references together hold one implicit weak reference
+    weak: AtomicUsize,
+    tag: u32,
+    data: T,
+}
+
+/// A thread-safe reference-counted pointer allocated from `NonPagedPoolNx`
+///
+/// # Example
+/// ```
+/// let shared = KArc::new(FastLocked::new(0u32)?)?;
+/// let observer = KArc::downgrade(&shared);
+///
+/// let worker = shared.clone();
+/// let _ = spawn(move || {
+///     if let Ok(mut counter) = worker.lock() {
+///         *counter += 1;
+///     }
+/// });
+///
+/// // the counter is alive as long as any `KArc` is alive
+/// if let Some(counter) = observer.upgrade() {
+///     // ...
+/// }
+/// ```
+pub struct KArc<T> {
+    inner: NonNull<ArcInner<T>>,
+    _phantom: PhantomData<ArcInner<T>>,
+}
+
+impl<T> KArc<T> {
+    /// allocate a new `KArc<T>` with the default pool tag
+    pub fn new(data: T) -> Result<Self, NtError> {
+        Self::new_with_tag(data, ARC_TAG)
+    }
+
+    /// allocate a new `KArc<T>` with a caller specified pool tag
+    pub fn new_with_tag(data: T, tag: u32) -> Result<Self, NtError> {
+        if mem::align_of::<ArcInner<T>>() > POOL_ALIGNMENT {
+            return Err(NtError::new(STATUS_INVALID_PARAMETER));
+        }
+
+        let layout = ex_allocate_pool_zero(NonPagedPoolNx, mem::size_of::<ArcInner<T>>() as _, tag)?
+            as *mut ArcInner<T>;
+
+        unsafe {
+            ptr::write(
+                layout,
+                ArcInner {
+                    strong: AtomicUsize::new(1),
+                    weak: AtomicUsize::new(1),
+                    tag,
+                    data,
+                },
+            );
+        }
+
+        Ok(Self::from_inner(NonNull::new(layout).unwrap()))
+    }
+
+    /// allocate a pinned `KArc<T>`
+    ///
+    /// the address of `T` will never change until the last strong reference is dropped,
+    /// it is suitable for objects whose address is passed down to kernel APIs, such as KEVENT, KDPC, KTIMER
+    pub fn pin(data: T) -> Result<Pin<KArc<T>>, NtError> {
+        Self::new(data).map(|arc| unsafe { Pin::new_unchecked(arc) })
+    }
+
+    /// see `KArc::pin` and `KArc::new_with_tag`
+    pub fn pin_with_tag(data: T, tag: u32) -> Result<Pin<KArc<T>>, NtError> {
+        Self::new_with_tag(data, tag).map(|arc| unsafe { Pin::new_unchecked(arc) })
+    }
+
+    fn from_inner(inner: NonNull<ArcInner<T>>) -> Self {
+        Self {
+            inner,
+            _phantom: PhantomData,
+        }
+    }
+
+    #[inline]
+    fn inner(&self) -> &ArcInner<T> {
+        unsafe { self.inner.as_ref() }
+    }
+
+    /// create a new `KWeak` pointer to this allocation
+    pub fn downgrade(this: &KArc<T>) -> KWeak<T> {
+        this.inner().weak.fetch_add(1, Ordering::Relaxed);
+
+        KWeak { inner: this.inner }
+    }
+
+    pub fn strong_count(this: &KArc<T>) -> usize {
+        this.inner().strong.load(Ordering::Acquire)
+    }
+
+    /// the implicit weak reference held by all the strong references is not counted
+    pub fn weak_count(this: &KArc<T>) -> usize {
+        this.inner().weak.load(Ordering::Acquire) - 1
+    }
+
+    /// returns true if the two `KArc`s point to the same allocation
+    pub fn ptr_eq(this: &KArc<T>, other: &KArc<T>) -> bool {
+        this.inner == other.inner
+    }
+
+    pub fn as_ptr(this: &KArc<T>) -> *const T {
+        unsafe { &raw const (*this.inner.as_ptr()).data }
+    }
+
+    /// returns a mutable reference into the given `KArc` if there are no other `KArc` or `KWeak` pointers to the same allocation
+    pub fn get_mut(this: &mut KArc<T>) -> Option<&mut T> {
+        // no one else can create a new reference since we borrow the only one mutably
+        if this.inner().weak.load(Ordering::Acquire) == 1
+            && this.inner().strong.load(Ordering::Acquire) == 1
+        {
+            Some(unsafe { &mut (*this.inner.as_ptr()).data })
+        } else {
+            None
+        }
+    }
+
+    /// consume the `KArc` and returns the wrapped pointer, the strong reference is transferred to the caller
+    ///
+    /// use `KArc::from_raw` to take it back, otherwise the allocation is leaked
+    pub fn into_raw(this: KArc<T>) -> *const T {
+        let ptr = Self::as_ptr(&this);
+
+        mem::forget(this);
+
+        ptr
+    }
+
+    /// # Safety
+    /// `ptr` must be returned from `KArc::into_raw` and the strong reference must not be taken back twice
+    pub unsafe fn from_raw(ptr: *const T) -> KArc<T> {
+        let offset = mem::offset_of!(ArcInner<T>, data);
+        let inner = unsafe { (ptr as *const u8).sub(offset) } as *mut ArcInner<T>;
+
+        Self::from_inner(unsafe { NonNull::new_unchecked(inner) })
+    }
+}
+
+impl<T> Clone for KArc<T> {
+    fn clone(&self) -> Self {
+        let old = self.inner().strong.fetch_add(1, Ordering::Relaxed);
+
+        // the same overflow protection as `Arc`, it is nearly impossible to hit it
+        if old > isize::MAX as usize {
+            panic!("KArc reference count overflow");
+        }
+
+        Self::from_inner(self.inner)
+    }
+}
+
+impl<T> Deref for KArc<T> {
+    type Target = T;
+    fn deref(&self) -> &Self::Target {
+        &self.inner().data
+    }
+}
+
+impl<T> Drop for KArc<T> {
+    fn drop(&mut self) {
+        if self.inner().strong.fetch_sub(1, Ordering::Release) != 1 {
+            return;
+        }
+
+        // synchronize with all the `Release` decrements done by other owners
+        atomic::fence(Ordering::Acquire);
+
+        unsafe { ptr::drop_in_place(&mut (*self.inner.as_ptr()).data) };
+
+        // drop the implicit weak reference held by strong references
+        drop(KWeak { inner: self.inner });
+    }
+}
+
+impl<T: Debug> Debug for KArc<T> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        Debug::fmt(&**self, f)
+    }
+}
+
+impl<T: Display> Display for KArc<T> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        Display::fmt(&**self, f)
+    }
+}
+
+unsafe impl<T: Send + Sync> Send for KArc<T> {}
+unsafe impl<T: Send + Sync> Sync for KArc<T> {}
+
+/// A non-owning reference to a `KArc` allocation, see `KArc::downgrade`
+pub struct KWeak<T> {
+    inner: NonNull<ArcInner<T>>,
+}
+
+impl<T> KWeak<T> {
+    #[inline]
+    fn inner(&self) -> &ArcInner<T> {
+        unsafe { self.inner.as_ref() }
+    }
+
+    /// attempt to upgrade to a `KArc`, returns `None` if the inner value has been dropped
+    pub fn upgrade(&self) -> Option<KArc<T>> {
+        let mut strong = self.inner().strong.load(Ordering::Relaxed);
+
+        loop {
+            if strong == 0 {
+                return None;
+            }
+
+            match self.inner().strong.compare_exchange_weak(
+                strong,
+                strong + 1,
+                Ordering::Acquire,
+                Ordering::Relaxed,
+            ) {
+                Ok(_) => return Some(KArc::from_inner(self.inner)),
+                Err(current) => strong = current,
+            }
+        }
+    }
+
+    pub fn strong_count(&self) -> usize {
+        self.inner().strong.load(Ordering::Acquire)
+    }
+}
+
+impl<T> Clone for KWeak<T> {
+    fn clone(&self) -> Self {
+        self.inner().weak.fetch_add(1, Ordering::Relaxed);
+
+        Self { inner: self.inner }
+    }
+}
+
+impl<T> Drop for KWeak<T> {
+    fn drop(&mut self) {
+        if self.inner().weak.fetch_sub(1, Ordering::Release) != 1 {
+            return;
+        }
+
+        atomic::fence(Ordering::Acquire);
+
+        // `T` has been dropped already, only the control block remains
+        let tag = self.inner().tag;
+
+        unsafe { ex_free_pool(self.inner.as_ptr().cast(), tag) };
+    }
+}
+
+unsafe impl<T: Send + Sync> Send for KWeak<T> {}
+unsafe impl<T: Send + Sync> Sync for KWeak<T> {}
+
+/// A `KArc<T>` that can be loaded and replaced atomically without a lock, like `ArcSwap`
+///
+/// `load` only bumps the reference count of the current value, so it is cheap enough for the hot path, e.g. a
+/// configuration read on every IRP; `store` and `swap` publish a new value and wait until no reader can still be
+/// bumping the reference count of the old one, which takes a few instructions at most
+///
+/// the readers register themselves in an epoch like `Rcu`, but only for the duration of the reference count bump,
+/// both the readers and the writers run at DISPATCH_LEVEL at least meanwhile, so a writer spinning in a DPC never
+/// waits for a reader or a writer it has preempted on the same processor
+///
+/// # Example
+/// ```
+/// static CONFIG: OnceLock<AtomicArc<Config>> = OnceLock::new();
+///
+/// // hot path, any IRQL
+/// let config = CONFIG.get().unwrap().load();
+/// if config.verbose {
+///     // ...
+/// }
+///
+/// // hot reload, any IRQL <= DISPATCH_LEVEL
+/// CONFIG.get().unwrap().store(KArc::new(new_config)?);
+/// ```
+pub struct AtomicArc<T> {
+    current: AtomicPtr<T>,
+    epoch: AtomicUsize,
+    readers: [AtomicUsize; 2],
+    writing: AtomicBool,
+    _phantom: PhantomData<KArc<T>>,
+}
+
+impl<T> AtomicArc<T> {
+    pub fn new(value: KArc<T>) -> Self {
+        Self {
+            current: AtomicPtr::new(KArc::into_raw(value) as *mut T),
+            epoch: AtomicUsize::new(0),
+            readers: [AtomicUsize::new(0), AtomicUsize::new(0)],
+            writing: AtomicBool::new(false),
+            _phantom: PhantomData,
+        }
+    }
+
+    /// a new strong reference to the current value, it can be called at any IRQL
+    pub fn load(&self) -> KArc<T> {
+        let _irql = raise_to_dispatch();
+
+        loop {
+            let epoch = self.epoch.load(Ordering::SeqCst);
+            let slot = epoch & 1;
+
+            self.readers[slot].fetch_add(1, Ordering::SeqCst);
+
+            // the writer may flip the epoch before we are registered, retry in the new epoch
+            if self.epoch.load(Ordering::SeqCst) == epoch {
+                let ptr = self.current.load(Ordering::SeqCst);
+
+                // the reference held by `self` keeps the value alive until we leave the epoch
+                let arc = unsafe { ManuallyDrop::new(KArc::from_raw(ptr)) };
+                let value = KArc::clone(&arc);
+
+                self.readers[slot].fetch_sub(1, Ordering::Release);
+
+                return value;
+            }
+
+            self.readers[slot].fetch_sub(1, Ordering::Release);
+        }
+    }
+
+    /// replace the current value and returns the previous one, it must be called at IRQL <= DISPATCH_LEVEL
+    pub fn swap(&self, value: KArc<T>) -> KArc<T> {
+        let _irql = raise_to_dispatch();
+
+        // writers are serialized, readers are not affected
+        while self
+            .writing
+            .compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
+            this_thread::pause();
+        }
+
+        let old = self
+            .current
+            .swap(KArc::into_raw(value) as *mut T, Ordering::SeqCst);
+
+        self.synchronize();
+
+        self.writing.store(false, Ordering::Release);
+
+        // no reader can be bumping the reference count of `old` any more
+        unsafe { KArc::from_raw(old) }
+    }
+
+    /// replace the current value, the previous one is dropped if it is not referenced elsewhere
+    pub fn store(&self, value: KArc<T>) {
+        drop(self.swap(value));
+    }
+
+    /// flip the epoch and wait until the readers of the previous epoch have left
+    fn synchronize(&self) {
+        let slot = self.epoch.fetch_add(1, Ordering::SeqCst) & 1;
+
+        while self.readers[slot].load(Ordering::SeqCst) != 0 {
+            this_thread::pause();
+        }
+    }
+
+    pub fn into_inner(self) -> KArc<T> {
+        let this = ManuallyDrop::new(self);
+
+        unsafe { KArc::from_raw(this.current.load(Ordering::Acquire)) }
+    }
+}
+
+/// raise to DISPATCH_LEVEL for a section of `AtomicArc`, unless the IRQL is higher already
+fn raise_to_dispatch() -> Option<RaiseIrqlGuard> {
+    (irql::current() <= DISPATCH_LEVEL).then(RaiseIrqlGuard::to_dispatch)
+}
+
+impl<T> Drop for AtomicArc<T> {
+    fn drop(&mut self) {
+        // we have exclusive access here, no reader can be alive
+        drop(unsafe { KArc::from_raw(*self.current.get_mut()) });
+    }
+}
+
+impl<T: Debug> Debug for AtomicArc<T> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "AtomicArc({:?})", &*self.load())
+    }
+}
+
+unsafe impl<T: Send + Sync> Send for AtomicArc<T> {}
+unsafe impl<T: Send + Sync> Sync for AtomicArc<T> {}
@@ -0,0 +1,263 @@
+//! this module provides `AvlTable<K, V>`, an ordered map built on the kernel generic AVL table(RTL_AVL_TABLE)
+//!
+//! # Note
+//! the table itself is not synchronized, wrap it in a `Locked<T>` to share it between threads, for example:
+//! ```
+//! let table: FastLocked<AvlTable<u64, Connection>> = FastLocked::new(AvlTable::new())?;
+//! ```
+use core::{
+    cmp::Ordering,
+    marker::PhantomData,
+    mem::{self, ManuallyDrop},
+    ptr,
+};
+
+use alloc::boxed::Box;
+use wdk_sys::{
+    _POOL_TYPE::NonPagedPoolNx,
+    _RTL_GENERIC_COMPARE_RESULTS::{GenericEqual, GenericGreaterThan, GenericLessThan},
+    CLONG, PRTL_AVL_TABLE, PVOID, RTL_AVL_TABLE, RTL_GENERIC_COMPARE_RESULTS,
+    STATUS_INSUFFICIENT_RESOURCES,
+    ntddk::{
+        RtlDeleteElementGenericTableAvl, RtlEnumerateGenericTableWithoutSplayingAvl,
+        RtlGetElementGenericTableAvl, RtlInitializeGenericTableAvl,
+        RtlInsertElementGenericTableAvl, RtlLookupElementGenericTableAvl,
+        RtlNumberGenericTableElementsAvl,
+    },
+};
+
+use crate::{
+    ntstatus::NtError,
+    utils::{ex_allocate_pool_zero, ex_free_pool},
+};
+
+const AVL_TAG: u32 = u32::from_ne_bytes(*b"lvak");
+
+/// the element layout stored by the RTL_AVL_TABLE
+///
+/// `key` must be the first field, so that a pointer to a `K` can be used as a lookup buffer
+#[repr(C)]
+struct Entry<K, V> {
+    key: K,
+    value: V,
+}
+
+/// must be pinned in memory since the RTL_AVL_TABLE points to itself
+struct AvlInner<K> {
+    table: RTL_AVL_TABLE,
+    compare: Box<dyn Fn(&K, &K) -> Ordering + Send + Sync>,
+    tag: u32,
+}
+
+/// An ordered map over RTL_AVL_TABLE, nodes are allocated from `NonPagedPoolNx` with a caller specified pool tag
+pub struct AvlTable<K, V> {
+    inner: Box<AvlInner<K>>,
+    _phantom: PhantomData<Entry<K, V>>,
+}
+
+impl<K: Ord, V> AvlTable<K, V> {
+    /// create a table ordered by `K::cmp`
+    pub fn new() -> Self {
+        Self::with_comparator(AVL_TAG, |a: &K, b: &K| a.cmp(b))
+    }
+}
+
+impl<K, V> AvlTable<K, V> {
+    /// create a table ordered by the `compare` closure, nodes are allocated with pool tag `tag`
+    ///
+    /// the closure is shared with the table, so it must be `Send + Sync` as well
+    pub fn with_comparator<F>(tag: u32, compare: F) -> Self
+    where
+        F: Fn(&K, &K) -> Ordering + Send + Sync + 'static,
+    {
+        let mut inner = Box::new(AvlInner {
+            table: unsafe { mem::zeroed() },
+            compare: Box::new(compare),
+            tag,
+        });
+
+        let context: *mut AvlInner<K> = inner.as_mut();
+
+        unsafe {
+            RtlInitializeGenericTableAvl(
+                &mut inner.table,
+                Some(avl_compare_stub::<K>),
+                Some(avl_allocate_stub::<K>),
+                Some(avl_free_stub::<K>),
+                context.cast(),
+            );
+        }
+
+        Self {
+            inner,
+            _phantom: PhantomData,
+        }
+    }
+
+    #[inline]
+    fn table(&self) -> PRTL_AVL_TABLE {
+        &self.inner.table as *const _ as _
+    }
+
+    /// insert a key-value pair into the table
+    ///
+    /// returns the old value if `key` is already present
+    pub fn insert(&mut self, key: K, value: V) -> Result<Option<V>, NtError> {
+        let entry = ManuallyDrop::new(Entry { key, value });
+        let mut new_element = 0u8;
+
+        // the table copies the bytes of `entry` into its own node
+        let node = unsafe {
+            RtlInsertElementGenericTableAvl(
+                self.table(),
+                &*entry as *const _ as PVOID,
+                mem::size_of::<Entry<K, V>>() as CLONG,
+                &mut new_element,
+            )
+        } as *mut Entry<K, V>;
+
+        let mut entry = ManuallyDrop::into_inner(entry);
+
+        if node.is_null() {
+            // allocation failed, `entry` is still owned by us and will be dropped here
+            return Err(NtError::new(STATUS_INSUFFICIENT_RESOURCES));
+        }
+
+        if new_element != 0 {
+            // ownership moved into the node
+            mem::forget(entry);
+            return Ok(None);
+        }
+
+        // the key is already present, swap the value and drop our copy of the key
+        unsafe { mem::swap(&mut (*node).value, &mut entry.value) };
+
+        Ok(Some(entry.value))
+    }
+
+    pub fn get(&self, key: &K) -> Option<&V> {
+        self.lookup(key).map(|entry| unsafe { &(*entry).value })
+    }
+
+    pub fn get_mut(&mut self, key: &K) -> Option<&mut V> {
+        self.lookup(key).map(|entry| unsafe { &mut (*entry).value })
+    }
+
+    pub fn contains_key(&self, key: &K) -> bool {
+        self.lookup(key).is_some()
+    }
+
+    fn lookup(&self, key: &K) -> Option<*mut Entry<K, V>> {
+        let node = unsafe { RtlLookupElementGenericTableAvl(self.table(), key as *const _ as _) }
+            as *mut Entry<K, V>;
+
+        if node.is_null() { None } else { Some(node) }
+    }
+
+    /// remove `key` from the table and returns its value
+    pub fn remove(&mut self, key: &K) -> Option<V> {
+        let node = self.lookup(key)?;
+
+        // move the entry out of the node before the node is freed
+        let entry = unsafe { ptr::read(node) };
+
+        unsafe {
+            RtlDeleteElementGenericTableAvl(self.table(), &entry.key as *const _ as _);
+        }
+
+        Some(entry.value)
+    }
+
+    pub fn len(&self) -> usize {
+        unsafe { RtlNumberGenericTableElementsAvl(self.table()) as usize }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// returns an in-order cursor over the table, the table is not splayed during iteration
+    pub fn iter(&self) -> Iter<'_, K, V> {
+        Iter {
+            table: self,
+            restart_key: ptr::null_mut(),
+        }
+    }
+
+    /// remove and drop all the entries
+    pub fn clear(&mut self) {
+        loop {
+            let node = unsafe { RtlGetElementGenericTableAvl(self.table(), 0) } as *mut Entry<K, V>;
+
+            if node.is_null() {
+                break;
+            }
+
+            let entry = unsafe { ptr::read(node) };
+
+            unsafe {
+                RtlDeleteElementGenericTableAvl(self.table(), &entry.key as *const _ as _);
+            }
+        }
+    }
+}
+
+impl<K, V> Drop for AvlTable<K, V> {
+    fn drop(&mut self) {
+        self.clear();
+    }
+}
+
+unsafe impl<K: Send, V: Send> Send for AvlTable<K, V> {}
+unsafe impl<K: Sync, V: Sync> Sync for AvlTable<K, V> {}
+
+/// An in-order cursor over an `AvlTable`
+pub struct Iter<'a, K, V> {
+    table: &'a AvlTable<K, V>,
+    restart_key: PVOID,
+}
+
+impl<'a, K, V> Iterator for Iter<'a, K, V> {
+    type Item = (&'a K, &'a V);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let node = unsafe {
+            RtlEnumerateGenericTableWithoutSplayingAvl(self.table.table(), &mut self.restart_key)
+        } as *const Entry<K, V>;
+
+        if node.is_null() {
+            None
+        } else {
+            Some(unsafe { (&(*node).key, &(*node).value) })
+        }
+    }
+}
+
+extern "C" fn avl_compare_stub<K>(
+    table: PRTL_AVL_TABLE,
+    first: PVOID,
+    second: PVOID,
+) -> RTL_GENERIC_COMPARE_RESULTS {
+    let inner = unsafe { &*((*table).TableContext as *const AvlInner<K>) };
+
+    // both buffers start with a `K`, see `Entry`
+    match (inner.compare)(unsafe { &*(first as *const K) }, unsafe {
+        &*(second as *const K)
+    }) {
+        Ordering::Less => GenericLessThan,
+        Ordering::Greater => GenericGreaterThan,
+        Ordering::Equal => GenericEqual,
+    }
+}
+
+extern "C" fn avl_allocate_stub<K>(table: PRTL_AVL_TABLE, size: CLONG) -> PVOID {
+    let inner = unsafe { &*((*table).TableContext as *const AvlInner<K>) };
+
+    ex_allocate_pool_zero(NonPagedPoolNx, size as _, inner.tag).unwrap_or(ptr::null_mut())
+}
+
+extern "C" fn avl_free_stub<K>(table: PRTL_AVL_TABLE, buffer: PVOID) {
+    let inner = unsafe { &*((*table).TableContext as *const AvlInner<K>) };
+
+    unsafe { ex_free_pool(buffer, inner.tag) };
+}
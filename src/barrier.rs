@@ -0,0 +1,100 @@
+//! this module provides `Barrier`, which lets a group of threads wait until all of them reach the same point
+//!
+//! a barrier is reusable, the threads can go through it phase by phase
+//!
+//! # Example
+//! ```
+//! let barrier = KArc::new(Barrier::new(4)?)?;
+//!
+//! for _ in 0..4 {
+//!     let barrier = barrier.clone();
+//!     let _ = thread::spawn(move || {
+//!         init_per_thread_state();
+//!
+//!         // no thread proceeds before all of them are initialized
+//!         if barrier.wait().is_leader() {
+//!             log!("all workers initialized");
+//!         }
+//!
+//!         run();
+//!     });
+//! }
+//! ```
+use crate::{
+    event::{Event, EventProperty},
+    kobject::Dispatchable,
+    mutex::StaticSpinLocked,
+    ntstatus::NtError,
+};
+
+struct State {
+    arrived: usize,
+    generation: usize,
+}
+
+/// A reusable barrier for `n` threads, `wait` must be called at IRQL <= APC_LEVEL
+pub struct Barrier {
+    n: usize,
+    state: StaticSpinLocked<State>,
+    /// the threads of a generation wait on the event of its parity, it is reset before it is reused two generations later
+    events: [Event; 2],
+}
+
+impl Barrier {
+    /// a barrier of `n` threads, a barrier of 0 or 1 thread never blocks
+    pub fn new(n: usize) -> Result<Self, NtError> {
+        Ok(Self {
+            n,
+            state: StaticSpinLocked::new(State {
+                arrived: 0,
+                generation: 0,
+            }),
+            events: [
+                EventProperty::new().new_event()?,
+                EventProperty::new().new_event()?,
+            ],
+        })
+    }
+
+    /// block until all the `n` threads have called `wait`
+    ///
+    /// one of the threads of each phase is the leader, see `BarrierWaitResult::is_leader`
+    pub fn wait(&self) -> BarrierWaitResult {
+        crate::assert_irql!(<= APC_LEVEL);
+
+        let generation = {
+            let mut state = self.state.lock();
+
+            state.arrived += 1;
+
+            if state.arrived < self.n {
+                state.generation
+            } else {
+                let generation = state.generation;
+
+                state.arrived = 0;
+                state.generation = generation.wrapping_add(1);
+
+                // all the threads of the previous generation have left since they all arrived at this one
+                self.events[state.generation & 1].clear();
+                self.events[generation & 1].set();
+
+                return BarrierWaitResult(true);
+            }
+        };
+
+        let _ = self.events[generation & 1].wait(false);
+
+        BarrierWaitResult(false)
+    }
+}
+
+/// the result of `Barrier::wait`
+pub struct BarrierWaitResult(bool);
+
+impl BarrierWaitResult {
+    /// true for exactly one thread of each phase, the one which arrived last
+    pub fn is_leader(&self) -> bool {
+        self.0
+    }
+}
@@ -0,0 +1,237 @@
+//! this module provides `Bitmap`, an owned `RTL_BITMAP`
+//!
+//! it is typically used as an allocation map of indexes or slots, it is not synchronized by itself, wrap it in a
+//! `SpinLocked` or another lock to share it
+//!
+//! # Example
+//! ```
+//! static IDS: LazyLock<SpinLocked<Bitmap>> = LazyLock::new(|| SpinLocked::new(Bitmap::new(1024).unwrap()).unwrap());
+//!
+//! // allocate a connection id
+//! let id = IDS.lock()?.find_clear_and_set(1, 0).ok_or(NtError::new(STATUS_INSUFFICIENT_RESOURCES))?;
+//!
+//! // release it
+//! IDS.lock()?.clear(id);
+//!
+//! for run in IDS.lock()?.set_runs() {
+//!     println!("ids {}..{} are in use", run.start, run.end);
+//! }
+//! ```
+use core::ops::Range;
+
+use alloc::vec::Vec;
+use wdk_sys::{
+    RTL_BITMAP, STATUS_INSUFFICIENT_RESOURCES,
+    ntddk::{
+        RtlAreBitsClear, RtlAreBitsSet, RtlClearAllBits, RtlClearBit, RtlClearBits,
+        RtlFindClearBits, RtlFindClearBitsAndSet, RtlFindNextForwardRunClear, RtlFindSetBits,
+        RtlFindSetBitsAndClear, RtlInitializeBitMap, RtlNumberOfClearBits, RtlNumberOfSetBits,
+        RtlSetAllBits, RtlSetBit, RtlSetBits, RtlTestBit,
+    },
+};
+
+use crate::ntstatus::NtError;
+
+/// the value returned by the `RtlFind*` routines when no run is found
+const NOT_FOUND: u32 = u32::MAX;
+
+/// An owned bitmap of a fixed number of bits, all of them are clear initially
+///
+/// the buffer is allocated from the global allocator(nonpaged pool), so it can be used at any IRQL
+pub struct Bitmap {
+    header: RTL_BITMAP,
+    buffer: Vec<u32>,
+}
+
+impl Bitmap {
+    pub fn new(bits: u32) -> Result<Self, NtError> {
+        let words = bits.div_ceil(32) as usize;
+
+        let mut buffer = Vec::new();
+
+        buffer
+            .try_reserve_exact(words)
+            .map_err(|_| NtError::new(STATUS_INSUFFICIENT_RESOURCES))?;
+
+        buffer.resize(words, 0);
+
+        let mut header = RTL_BITMAP::default();
+
+        unsafe { RtlInitializeBitMap(&mut header, buffer.as_mut_ptr(), bits) };
+
+        Ok(Self { header, buffer })
+    }
+
+    /// the header to be passed to the `Rtl*` bitmap routines
+    pub fn as_ptr(&self) -> *mut RTL_BITMAP {
+        &self.header as *const _ as _
+    }
+
+    /// the number of bits
+    pub fn len(&self) -> u32 {
+        self.header.SizeOfBitMap
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn as_slice(&self) -> &[u32] {
+        &self.buffer
+    }
+
+    pub fn is_set(&self, index: u32) -> bool {
+        index < self.len() && unsafe { RtlTestBit(self.as_ptr(), index) } != 0
+    }
+
+    pub fn set(&mut self, index: u32) {
+        assert!(index < self.len(), "bit index out of range");
+
+        unsafe { RtlSetBit(self.as_ptr(), index) };
+    }
+
+    pub fn clear(&mut self, index: u32) {
+        assert!(index < self.len(), "bit index out of range");
+
+        unsafe { RtlClearBit(self.as_ptr(), index) };
+    }
+
+    fn check_range(&self, range: &Range<u32>) {
+        assert!(
+            range.start <= range.end && range.end <= self.len(),
+            "bit range out of range"
+        );
+    }
+
+    pub fn set_range(&mut self, range: Range<u32>) {
+        self.check_range(&range);
+
+        unsafe { RtlSetBits(self.as_ptr(), range.start, range.end - range.start) };
+    }
+
+    pub fn clear_range(&mut self, range: Range<u32>) {
+        self.check_range(&range);
+
+        unsafe { RtlClearBits(self.as_ptr(), range.start, range.end - range.start) };
+    }
+
+    pub fn set_all(&mut self) {
+        unsafe { RtlSetAllBits(self.as_ptr()) };
+    }
+
+    pub fn clear_all(&mut self) {
+        unsafe { RtlClearAllBits(self.as_ptr()) };
+    }
+
+    /// true if all the bits in `range` are set
+    pub fn are_set(&self, range: Range<u32>) -> bool {
+        self.check_range(&range);
+
+        unsafe { RtlAreBitsSet(self.as_ptr(), range.start, range.end - range.start) != 0 }
+    }
+
+    /// true if all the bits in `range` are clear
+    pub fn are_clear(&self, range: Range<u32>) -> bool {
+        self.check_range(&range);
+
+        unsafe { RtlAreBitsClear(self.as_ptr(), range.start, range.end - range.start) != 0 }
+    }
+
+    pub fn count_set(&self) -> u32 {
+        unsafe { RtlNumberOfSetBits(self.as_ptr()) }
+    }
+
+    pub fn count_clear(&self) -> u32 {
+        unsafe { RtlNumberOfClearBits(self.as_ptr()) }
+    }
+
+    /// the index of a run of `count` clear bits, the search starts at `hint` and wraps around
+    pub fn find_clear(&self, count: u32, hint: u32) -> Option<u32> {
+        found(unsafe { RtlFindClearBits(self.as_ptr(), count, hint) })
+    }
+
+    /// the index of a run of `count` set bits, the search starts at `hint` and wraps around
+    pub fn find_set(&self, count: u32, hint: u32) -> Option<u32> {
+        found(unsafe { RtlFindSetBits(self.as_ptr(), count, hint) })
+    }
+
+    /// find a run of `count` clear bits and set them, it is the allocation of a bitmap allocator
+    pub fn find_clear_and_set(&mut self, count: u32, hint: u32) -> Option<u32> {
+        found(unsafe { RtlFindClearBitsAndSet(self.as_ptr(), count, hint) })
+    }
+
+    /// find a run of `count` set bits and clear them
+    pub fn find_set_and_clear(&mut self, count: u32, hint: u32) -> Option<u32> {
+        found(unsafe { RtlFindSetBitsAndClear(self.as_ptr(), count, hint) })
+    }
+
+    /// the runs of set bits in ascending order
+    pub fn set_runs(&self) -> SetRuns<'_> {
+        SetRuns {
+            bitmap: self,
+            position: 0,
+        }
+    }
+}
+
+#[inline]
+fn found(index: u32) -> Option<u32> {
+    if index == NOT_FOUND {
+        None
+    } else {
+        Some(index)
+    }
+}
+
+impl Clone for Bitmap {
+    fn clone(&self) -> Self {
+        let mut bitmap = Self::new(self.len()).expect("can not allocate memory for Bitmap");
+
+        bitmap.buffer.copy_from_slice(&self.buffer);
+        bitmap
+    }
+}
+
+// Safety
+// the header only points to the owned buffer
+unsafe impl Send for Bitmap {}
+unsafe impl Sync for Bitmap {}
+
+/// An iterator over the runs of set bits of a `Bitmap`, see `Bitmap::set_runs`
+pub struct SetRuns<'a> {
+    bitmap: &'a Bitmap,
+    position: u32,
+}
+
+impl<'a> Iterator for SetRuns<'a> {
+    type Item = Range<u32>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let len = self.bitmap.len();
+
+        while self.position < len {
+            let mut start = 0;
+
+            let count = unsafe {
+                RtlFindNextForwardRunClear(self.bitmap.as_ptr(), self.position, &mut start)
+            };
+
+            // no clear bit is left, the rest is a set run
+            if count == 0 {
+                let run = self.position..len;
+                self.position = len;
+                return Some(run);
+            }
+
+            let run = self.position..start;
+
+            self.position = start + count;
+
+            if !run.is_empty() {
+                return Some(run);
+            }
+        }
+
+        None
+    }
+}
@@ -0,0 +1,203 @@
+//! this module provides `DeviceClient`, a kernel mode client of a device of another driver
+//!
+//! the device is opened with `ZwCreateFile`, and the I/O control requests are sent synchronously with
+//! `ZwDeviceIoControlFile`, each request waits on its own event so a client can be shared by threads
+//!
+//! # Example
+//! ```
+//! #[repr(C)]
+//! #[derive(Clone, Copy)]
+//! struct Query {
+//!     pid: u32,
+//! }
+//!
+//! unsafe impl Pod for Query {}
+//!
+//! let client = DeviceClient::open("\\Device\\OtherDriver")?;
+//!
+//! let version: u32 = client.query(IOCTL_GET_VERSION)?;
+//! let state: ProcessState = client.call(IOCTL_QUERY_PROCESS, &Query { pid })?;
+//! ```
+use core::{mem, ptr};
+
+use wdk_sys::{
+    _EVENT_TYPE::NotificationEvent, ACCESS_MASK, BOOLEAN, EVENT_ALL_ACCESS, EVENT_TYPE,
+    FILE_ATTRIBUTE_NORMAL, FILE_NON_DIRECTORY_FILE, FILE_OPEN, FILE_SHARE_READ, FILE_SHARE_WRITE,
+    GENERIC_READ, GENERIC_WRITE, HANDLE, IO_STATUS_BLOCK, NTSTATUS, OBJ_CASE_INSENSITIVE,
+    OBJ_KERNEL_HANDLE, PHANDLE, PIO_APC_ROUTINE, PIO_STATUS_BLOCK, PLARGE_INTEGER,
+    POBJECT_ATTRIBUTES, PVOID, STATUS_INFO_LENGTH_MISMATCH, STATUS_PENDING, ULONG,
+    ntddk::ZwCreateFile,
+};
+
+use crate::{
+    handle::ObjectHandle,
+    initialize_object_attributes,
+    ntstatus::{NtError, cvt},
+    pod::{self, FromBytes, Pod},
+    unicode::NtUnicodeString,
+};
+
+unsafe extern "C" {
+    pub fn ZwDeviceIoControlFile(
+        FileHandle: HANDLE,
+        Event: HANDLE,
+        ApcRoutine: PIO_APC_ROUTINE,
+        ApcContext: PVOID,
+        IoStatusBlock: PIO_STATUS_BLOCK,
+        IoControlCode: ULONG,
+        InputBuffer: PVOID,
+        InputBufferLength: ULONG,
+        OutputBuffer: PVOID,
+        OutputBufferLength: ULONG,
+    ) -> NTSTATUS;
+
+    pub fn ZwCreateEvent(
+        EventHandle: PHANDLE,
+        DesiredAccess: ACCESS_MASK,
+        ObjectAttributes: POBJECT_ATTRIBUTES,
+        EventType: EVENT_TYPE,
+        InitialState: BOOLEAN,
+    ) -> NTSTATUS;
+
+    pub fn ZwWaitForSingleObject(
+        Handle: HANDLE,
+        Alertable: BOOLEAN,
+        Timeout: PLARGE_INTEGER,
+    ) -> NTSTATUS;
+}
+
+/// An opened device of another driver, the handle is a kernel handle closed on drop
+///
+/// all the methods must be called at PASSIVE_LEVEL
+pub struct DeviceClient {
+    handle: ObjectHandle,
+}
+
+impl DeviceClient {
+    /// open the device `name`(e.g. "\\Device\\Beep") for read and write
+    pub fn open(name: &str) -> Result<Self, NtError> {
+        Self::open_with_access(name, GENERIC_READ | GENERIC_WRITE)
+    }
+
+    pub fn open_with_access(name: &str, access: ACCESS_MASK) -> Result<Self, NtError> {
+        let name = NtUnicodeString::from_str(name)?;
+
+        let mut attributes = initialize_object_attributes!(
+            name.as_ptr(),
+            OBJ_KERNEL_HANDLE | OBJ_CASE_INSENSITIVE,
+            ptr::null_mut(),
+            ptr::null_mut()
+        );
+
+        let mut handle: HANDLE = ptr::null_mut();
+        let mut io_status = IO_STATUS_BLOCK::default();
+
+        cvt(unsafe {
+            ZwCreateFile(
+                &mut handle,
+                access,
+                &mut attributes,
+                &mut io_status,
+                ptr::null_mut(),
+                FILE_ATTRIBUTE_NORMAL,
+                FILE_SHARE_READ | FILE_SHARE_WRITE,
+                FILE_OPEN,
+                FILE_NON_DIRECTORY_FILE,
+                ptr::null_mut(),
+                0,
+            )
+        })?;
+
+        Ok(Self {
+            handle: ObjectHandle::new(handle),
+        })
+    }
+
+    pub fn as_raw(&self) -> HANDLE {
+        self.handle.get()
+    }
+
+    /// send an I/O control request and wait for its completion, the number of bytes written to `output` is returned
+    pub fn ioctl(&self, code: u32, input: &[u8], output: &mut [u8]) -> Result<usize, NtError> {
+        let mut attributes = initialize_object_attributes!(
+            ptr::null_mut(),
+            OBJ_KERNEL_HANDLE,
+            ptr::null_mut(),
+            ptr::null_mut()
+        );
+
+        let mut event: HANDLE = ptr::null_mut();
+
+        cvt(unsafe {
+            ZwCreateEvent(
+                &mut event,
+                EVENT_ALL_ACCESS,
+                &mut attributes,
+                NotificationEvent,
+                0,
+            )
+        })?;
+
+        let event = ObjectHandle::new(event);
+        let mut io_status = IO_STATUS_BLOCK::default();
+
+        let mut status = unsafe {
+            ZwDeviceIoControlFile(
+                self.handle.get(),
+                event.get(),
+                None,
+                ptr::null_mut(),
+                &mut io_status,
+                code,
+                if input.is_empty() {
+                    ptr::null_mut()
+                } else {
+                    input.as_ptr() as _
+                },
+                input.len() as _,
+                if output.is_empty() {
+                    ptr::null_mut()
+                } else {
+                    output.as_mut_ptr().cast()
+                },
+                output.len() as _,
+            )
+        };
+
+        if status == STATUS_PENDING {
+            cvt(unsafe { ZwWaitForSingleObject(event.get(), 0, ptr::null_mut()) })?;
+
+            status = unsafe { io_status.__bindgen_anon_1.Status };
+        }
+
+        cvt(status).map(|_| io_status.Information as usize)
+    }
+
+    /// send `input` without an output buffer
+    pub fn send<I: Pod>(&self, code: u32, input: &I) -> Result<(), NtError> {
+        self.ioctl(code, pod::as_bytes(input), &mut []).map(|_| ())
+    }
+
+    /// receive an `O` without an input buffer
+    pub fn query<O: Pod>(&self, code: u32) -> Result<O, NtError> {
+        self.call(code, &())
+    }
+
+    /// send `input` and receive an `O`, it fails with STATUS_INFO_LENGTH_MISMATCH if the device does not fill an `O`
+    pub fn call<I: Pod, O: Pod>(&self, code: u32, input: &I) -> Result<O, NtError> {
+        let mut output = O::zeroed();
+
+        if self.ioctl(code, pod::as_bytes(input), pod::as_bytes_mut(&mut output))?
+            != mem::size_of::<O>()
+        {
+            return Err(NtError::new(STATUS_INFO_LENGTH_MISMATCH));
+        }
+
+        Ok(output)
+    }
+}
+
+// Safety
+// every request waits on its own event and status block
+unsafe impl Send for DeviceClient {}
+unsafe impl Sync for DeviceClient {}
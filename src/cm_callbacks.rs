@@ -0,0 +1,326 @@
+//! this module provides a safe wrapper of `CmRegisterCallbackEx` for registry filtering
+//!
+//! the raw `REG_NOTIFY_CLASS` and the `Argument2` structure are translated into a typed `RegOperation`,
+//! a filter is an object implementing `RegistryFilter`, returning an error from `filter` blocks the operation
+//!
+//! # Example
+//! ```
+//! struct DenyRunKey;
+//!
+//! impl RegistryFilter for DenyRunKey {
+//!     fn filter(&self, op: RegOperation<'_>) -> Result<(), NtError> {
+//!         if let RegOperation::PreSetValueKey(info) = op {
+//!             if info.value_name().is_some_and(|name| name.eq_ignore_ascii_case("Evil")) {
+//!                 return Err(NtError::new(STATUS_ACCESS_DENIED));
+//!             }
+//!         }
+//!
+//!         Ok(())
+//!     }
+//! }
+//!
+//! let registration = RegistryCallback::register(driver, "380000", DenyRunKey)?;
+//! ```
+use core::ptr;
+
+use alloc::{boxed::Box, string::String, vec::Vec};
+use wdk_sys::{
+    _MODE::KernelMode,
+    _REG_NOTIFY_CLASS, LARGE_INTEGER, NTSTATUS, PDRIVER_OBJECT, PVOID, REG_CREATE_KEY_INFORMATION,
+    REG_DELETE_KEY_INFORMATION, REG_DELETE_VALUE_KEY_INFORMATION, REG_NOTIFY_CLASS,
+    REG_POST_OPERATION_INFORMATION, REG_QUERY_VALUE_KEY_INFORMATION, REG_RENAME_KEY_INFORMATION,
+    REG_SET_VALUE_KEY_INFORMATION, SIZE_T, STATUS_ACCESS_VIOLATION, STATUS_INSUFFICIENT_RESOURCES,
+    STATUS_SUCCESS, UNICODE_STRING,
+    ntddk::{CmRegisterCallbackEx, CmUnRegisterCallback, IoGetCurrentProcess},
+};
+
+use crate::{
+    ntstatus::{NtError, cvt},
+    process::{MmCopyVirtualMemory, MmUserProbeAddress},
+    utils,
+};
+
+/// a key is about to be created or opened
+pub struct CreateKey<'a>(&'a REG_CREATE_KEY_INFORMATION);
+
+impl<'a> CreateKey<'a> {
+    /// the path relative to `root_object()` or a full path that starts with "\REGISTRY\"
+    pub fn complete_name(&self) -> Option<String> {
+        utils::unicode_to_string(self.0.CompleteName)
+    }
+
+    pub fn root_object(&self) -> PVOID {
+        self.0.RootObject
+    }
+
+    pub fn desired_access(&self) -> u32 {
+        self.0.DesiredAccess
+    }
+}
+
+/// a key is about to be deleted
+pub struct DeleteKey<'a>(&'a REG_DELETE_KEY_INFORMATION);
+
+impl<'a> DeleteKey<'a> {
+    /// the key object
+    pub fn object(&self) -> PVOID {
+        self.0.Object
+    }
+}
+
+/// a value is about to be set
+pub struct SetValueKey<'a>(&'a REG_SET_VALUE_KEY_INFORMATION);
+
+impl<'a> SetValueKey<'a> {
+    pub fn object(&self) -> PVOID {
+        self.0.Object
+    }
+
+    pub fn value_name(&self) -> Option<String> {
+        utils::unicode_to_string(self.0.ValueName)
+    }
+
+    /// REG_SZ, REG_DWORD...
+    pub fn value_type(&self) -> u32 {
+        self.0.Type
+    }
+
+    /// the address and the size of the new data, it is only valid during the callback
+    ///
+    /// the data of a user mode caller is in user mode memory, which the caller can change or free at any time, so it
+    /// must not be read in place, see `copy_data`
+    pub fn raw_data(&self) -> (*const u8, usize) {
+        (self.0.Data as *const u8, self.0.DataSize as usize)
+    }
+
+    /// a copy of the new data, the user mode memory is copied by `MmCopyVirtualMemory`, which catches the faults
+    ///
+    /// it fails with STATUS_ACCESS_VIOLATION if the user buffer is invalid
+    pub fn copy_data(&self) -> Result<Vec<u8>, NtError> {
+        let (data, len) = self.raw_data();
+        let mut buffer: Vec<u8> = Vec::new();
+
+        if data.is_null() || len == 0 {
+            return Ok(buffer);
+        }
+
+        buffer
+            .try_reserve_exact(len)
+            .map_err(|_| NtError::new(STATUS_INSUFFICIENT_RESOURCES))?;
+
+        let probe = unsafe { MmUserProbeAddress } as usize;
+
+        if (data as usize) < probe {
+            if (data as usize)
+                .checked_add(len)
+                .is_none_or(|end| end > probe)
+            {
+                return Err(NtError::new(STATUS_ACCESS_VIOLATION));
+            }
+
+            let process = unsafe { IoGetCurrentProcess() };
+            let mut copied: SIZE_T = 0;
+
+            cvt(unsafe {
+                MmCopyVirtualMemory(
+                    process,
+                    data as PVOID,
+                    process,
+                    buffer.as_mut_ptr().cast(),
+                    len as _,
+                    KernelMode as _,
+                    &mut copied,
+                )
+            })?;
+        } else {
+            // the data of a kernel mode caller stays valid during the callback
+            unsafe { ptr::copy_nonoverlapping(data, buffer.as_mut_ptr(), len) };
+        }
+
+        unsafe { buffer.set_len(len) };
+
+        Ok(buffer)
+    }
+}
+
+/// a value is about to be deleted
+pub struct DeleteValueKey<'a>(&'a REG_DELETE_VALUE_KEY_INFORMATION);
+
+impl<'a> DeleteValueKey<'a> {
+    pub fn object(&self) -> PVOID {
+        self.0.Object
+    }
+
+    pub fn value_name(&self) -> Option<String> {
+        utils::unicode_to_string(self.0.ValueName)
+    }
+}
+
+/// a value is about to be queried
+pub struct QueryValueKey<'a>(&'a REG_QUERY_VALUE_KEY_INFORMATION);
+
+impl<'a> QueryValueKey<'a> {
+    pub fn object(&self) -> PVOID {
+        self.0.Object
+    }
+
+    pub fn value_name(&self) -> Option<String> {
+        utils::unicode_to_string(self.0.ValueName)
+    }
+}
+
+/// a key is about to be renamed
+pub struct RenameKey<'a>(&'a REG_RENAME_KEY_INFORMATION);
+
+impl<'a> RenameKey<'a> {
+    pub fn object(&self) -> PVOID {
+        self.0.Object
+    }
+
+    pub fn new_name(&self) -> Option<String> {
+        utils::unicode_to_string(self.0.NewName)
+    }
+}
+
+/// an operation has been performed
+pub struct PostOperation<'a>(&'a REG_POST_OPERATION_INFORMATION);
+
+impl<'a> PostOperation<'a> {
+    pub fn object(&self) -> PVOID {
+        self.0.Object
+    }
+
+    /// the status of the operation
+    pub fn status(&self) -> NTSTATUS {
+        self.0.Status
+    }
+}
+
+/// A typed registry operation
+pub enum RegOperation<'a> {
+    PreCreateKey(CreateKey<'a>),
+    PreOpenKey(CreateKey<'a>),
+    PreDeleteKey(DeleteKey<'a>),
+    PreSetValueKey(SetValueKey<'a>),
+    PreDeleteValueKey(DeleteValueKey<'a>),
+    PreQueryValueKey(QueryValueKey<'a>),
+    PreRenameKey(RenameKey<'a>),
+    /// the `REG_NOTIFY_CLASS` of the completed operation and its result
+    Post(REG_NOTIFY_CLASS, PostOperation<'a>),
+    /// an operation not translated yet, the raw `REG_NOTIFY_CLASS` and `Argument2`
+    Other(REG_NOTIFY_CLASS, PVOID),
+}
+
+impl<'a> RegOperation<'a> {
+    /// # Safety
+    /// `argument` must be the `Argument2` of a registry callback for the class `class`
+    unsafe fn from_raw(class: REG_NOTIFY_CLASS, argument: PVOID) -> Self {
+        use _REG_NOTIFY_CLASS::*;
+
+        unsafe {
+            match class {
+                RegNtPreCreateKeyEx => RegOperation::PreCreateKey(CreateKey(&*argument.cast())),
+                RegNtPreOpenKeyEx => RegOperation::PreOpenKey(CreateKey(&*argument.cast())),
+                RegNtPreDeleteKey => RegOperation::PreDeleteKey(DeleteKey(&*argument.cast())),
+                RegNtPreSetValueKey => RegOperation::PreSetValueKey(SetValueKey(&*argument.cast())),
+                RegNtPreDeleteValueKey => {
+                    RegOperation::PreDeleteValueKey(DeleteValueKey(&*argument.cast()))
+                }
+                RegNtPreQueryValueKey => {
+                    RegOperation::PreQueryValueKey(QueryValueKey(&*argument.cast()))
+                }
+                RegNtPreRenameKey => RegOperation::PreRenameKey(RenameKey(&*argument.cast())),
+                RegNtPostCreateKeyEx
+                | RegNtPostOpenKeyEx
+                | RegNtPostDeleteKey
+                | RegNtPostSetValueKey
+                | RegNtPostDeleteValueKey
+                | RegNtPostQueryValueKey
+                | RegNtPostRenameKey => RegOperation::Post(class, PostOperation(&*argument.cast())),
+                _ => RegOperation::Other(class, argument),
+            }
+        }
+    }
+}
+
+/// A registry filter
+///
+/// the filter is called at PASSIVE_LEVEL in the context of the thread performing the registry operation
+pub trait RegistryFilter: Send + Sync {
+    /// returns an error to block a pre-operation with the error status,
+    /// the return value of a post-operation is ignored
+    fn filter(&self, op: RegOperation<'_>) -> Result<(), NtError>;
+}
+
+/// A registration of a `RegistryFilter`, it is unregistered on drop
+///
+/// `CmUnRegisterCallback` waits for all the in-flight callbacks to return, so the filter is never freed while in use
+pub struct RegistryCallback<T: RegistryFilter> {
+    cookie: LARGE_INTEGER,
+    filter: Box<T>,
+    _altitude: Box<UNICODE_STRING>,
+}
+
+impl<T: RegistryFilter> RegistryCallback<T> {
+    /// register `filter` at the `altitude`
+    pub fn register(driver: PDRIVER_OBJECT, altitude: &str, filter: T) -> Result<Self, NtError> {
+        let filter = Box::new(filter);
+        let altitude =
+            utils::utf16_from_str(altitude).ok_or(NtError::new(STATUS_INSUFFICIENT_RESOURCES))?;
+
+        let mut cookie = LARGE_INTEGER { QuadPart: 0 };
+
+        cvt(unsafe {
+            CmRegisterCallbackEx(
+                Some(registry_callback_stub::<T>),
+                altitude.as_ref(),
+                driver.cast(),
+                filter.as_ref() as *const T as _,
+                &mut cookie,
+                ptr::null_mut(),
+            )
+        })?;
+
+        Ok(Self {
+            cookie,
+            filter,
+            _altitude: altitude,
+        })
+    }
+
+    /// the cookie identifying this registration
+    pub fn cookie(&self) -> i64 {
+        unsafe { self.cookie.QuadPart }
+    }
+
+    pub fn filter(&self) -> &T {
+        &self.filter
+    }
+}
+
+impl<T: RegistryFilter> Drop for RegistryCallback<T> {
+    fn drop(&mut self) {
+        unsafe {
+            let _ = CmUnRegisterCallback(self.cookie);
+        }
+    }
+}
+
+unsafe impl<T: RegistryFilter> Send for RegistryCallback<T> {}
+unsafe impl<T: RegistryFilter> Sync for RegistryCallback<T> {}
+
+extern "C" fn registry_callback_stub<T: RegistryFilter>(
+    context: PVOID,
+    argument1: PVOID,
+    argument2: PVOID,
+) -> NTSTATUS {
+    let filter = unsafe { &*(context as *const T) };
+
+    let op = unsafe { RegOperation::from_raw(argument1 as usize as REG_NOTIFY_CLASS, argument2) };
+    let is_post = matches!(op, RegOperation::Post(..));
+
+    match filter.filter(op) {
+        Err(e) if !is_post => e.code(),
+        _ => STATUS_SUCCESS,
+    }
+}
@@ -0,0 +1,475 @@
+//! this module provides the fixed-capacity collections, `ArrayVec`, `ArrayString` and `StaticRing`
+//!
+//! the elements are stored inline(on the stack, in a static or inside another object), so they never allocate and
+//! can be used at any IRQL, including DPC and ISR context, a full collection rejects new elements instead of growing
+//!
+//! they are not synchronized, wrap them in a `StaticSpinLocked` to share them between processors
+//!
+//! # Example
+//! ```
+//! // in a DPC
+//! let mut expired = ArrayVec::<u64, 32>::new();
+//!
+//! for entry in table.iter().filter(|entry| entry.deadline < now) {
+//!     if expired.try_push(entry.id).is_err() {
+//!         break;
+//!     }
+//! }
+//!
+//! let mut name = ArrayString::<64>::new();
+//! name.try_push_str("worker")?;
+//!
+//! static HISTORY: StaticSpinLocked<StaticRing<Sample, 64>> = StaticSpinLocked::new(StaticRing::new());
+//! HISTORY.lock().push_overwrite(sample);
+//! ```
+use core::{
+    fmt,
+    mem::MaybeUninit,
+    ops::{Deref, DerefMut},
+    ptr, slice,
+};
+
+use wdk_sys::STATUS_BUFFER_TOO_SMALL;
+
+use crate::ntstatus::NtError;
+
+/// A vector with an inline capacity of `N` elements
+pub struct ArrayVec<T, const N: usize> {
+    buffer: [MaybeUninit<T>; N],
+    len: usize,
+}
+
+impl<T, const N: usize> ArrayVec<T, N> {
+    pub const fn new() -> Self {
+        Self {
+            buffer: [const { MaybeUninit::uninit() }; N],
+            len: 0,
+        }
+    }
+
+    pub const fn len(&self) -> usize {
+        self.len
+    }
+
+    pub const fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub const fn is_full(&self) -> bool {
+        self.len == N
+    }
+
+    pub const fn capacity(&self) -> usize {
+        N
+    }
+
+    /// the number of elements which can still be pushed
+    pub const fn remaining(&self) -> usize {
+        N - self.len
+    }
+
+    pub fn as_slice(&self) -> &[T] {
+        unsafe { slice::from_raw_parts(self.buffer.as_ptr().cast(), self.len) }
+    }
+
+    pub fn as_mut_slice(&mut self) -> &mut [T] {
+        unsafe { slice::from_raw_parts_mut(self.buffer.as_mut_ptr().cast(), self.len) }
+    }
+
+    /// push `value`, it is returned back if the vector is full
+    pub fn try_push(&mut self, value: T) -> Result<(), T> {
+        if self.is_full() {
+            return Err(value);
+        }
+
+        self.buffer[self.len].write(value);
+        self.len += 1;
+
+        Ok(())
+    }
+
+    pub fn pop(&mut self) -> Option<T> {
+        if self.len == 0 {
+            return None;
+        }
+
+        self.len -= 1;
+
+        Some(unsafe { self.buffer[self.len].assume_init_read() })
+    }
+
+    /// insert `value` at `index`, it is returned back if the vector is full, it panics if `index > len`
+    pub fn try_insert(&mut self, index: usize, value: T) -> Result<(), T> {
+        assert!(index <= self.len, "insertion index is out of bounds");
+
+        if self.is_full() {
+            return Err(value);
+        }
+
+        unsafe {
+            let p = self.buffer.as_mut_ptr().add(index);
+
+            ptr::copy(p, p.add(1), self.len - index);
+            (*p).write(value);
+        }
+
+        self.len += 1;
+
+        Ok(())
+    }
+
+    /// remove the element at `index` and shift the rest, it panics if `index >= len`
+    pub fn remove(&mut self, index: usize) -> T {
+        assert!(index < self.len, "removal index is out of bounds");
+
+        unsafe {
+            let p = self.buffer.as_mut_ptr().add(index);
+            let value = (*p).assume_init_read();
+
+            ptr::copy(p.add(1), p, self.len - index - 1);
+
+            self.len -= 1;
+
+            value
+        }
+    }
+
+    /// remove the element at `index` and replace it with the last one, it panics if `index >= len`
+    pub fn swap_remove(&mut self, index: usize) -> T {
+        assert!(index < self.len, "removal index is out of bounds");
+
+        let last = self.len - 1;
+
+        self.as_mut_slice().swap(index, last);
+
+        self.pop().unwrap()
+    }
+
+    pub fn truncate(&mut self, len: usize) {
+        while self.len > len {
+            drop(self.pop());
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.truncate(0);
+    }
+
+    pub fn retain<F: FnMut(&T) -> bool>(&mut self, mut f: F) {
+        let mut i = 0;
+
+        while i < self.len {
+            if f(&self.as_slice()[i]) {
+                i += 1;
+            } else {
+                drop(self.remove(i));
+            }
+        }
+    }
+}
+
+impl<T: Clone, const N: usize> ArrayVec<T, N> {
+    /// clone and append the elements of `other`, nothing is appended if they do not fit
+    pub fn try_extend_from_slice(&mut self, other: &[T]) -> Result<(), NtError> {
+        if other.len() > self.remaining() {
+            return Err(NtError::new(STATUS_BUFFER_TOO_SMALL));
+        }
+
+        for value in other {
+            let _ = self.try_push(value.clone());
+        }
+
+        Ok(())
+    }
+}
+
+impl<T, const N: usize> Default for ArrayVec<T, N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T: Clone, const N: usize> Clone for ArrayVec<T, N> {
+    fn clone(&self) -> Self {
+        let mut v = Self::new();
+
+        let _ = v.try_extend_from_slice(self.as_slice());
+
+        v
+    }
+}
+
+impl<T, const N: usize> Deref for ArrayVec<T, N> {
+    type Target = [T];
+    fn deref(&self) -> &Self::Target {
+        self.as_slice()
+    }
+}
+
+impl<T, const N: usize> DerefMut for ArrayVec<T, N> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        self.as_mut_slice()
+    }
+}
+
+impl<'a, T, const N: usize> IntoIterator for &'a ArrayVec<T, N> {
+    type Item = &'a T;
+    type IntoIter = slice::Iter<'a, T>;
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+    }
+}
+
+impl<'a, T, const N: usize> IntoIterator for &'a mut ArrayVec<T, N> {
+    type Item = &'a mut T;
+    type IntoIter = slice::IterMut<'a, T>;
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter_mut()
+    }
+}
+
+impl<T: fmt::Debug, const N: usize> fmt::Debug for ArrayVec<T, N> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_list().entries(self.iter()).finish()
+    }
+}
+
+impl<T: PartialEq, const N: usize> PartialEq for ArrayVec<T, N> {
+    fn eq(&self, other: &Self) -> bool {
+        self.as_slice() == other.as_slice()
+    }
+}
+
+impl<T: Eq, const N: usize> Eq for ArrayVec<T, N> {}
+
+impl<T, const N: usize> Drop for ArrayVec<T, N> {
+    fn drop(&mut self) {
+        unsafe { ptr::drop_in_place(self.as_mut_slice()) };
+    }
+}
+
+/// A UTF-8 string with an inline capacity of `N` bytes
+///
+/// unlike `fmt::StackString` which truncates, a string which does not fit is rejected as a whole
+#[derive(Clone, Default, PartialEq, Eq)]
+pub struct ArrayString<const N: usize> {
+    bytes: ArrayVec<u8, N>,
+}
+
+impl<const N: usize> ArrayString<N> {
+    pub const fn new() -> Self {
+        Self {
+            bytes: ArrayVec::new(),
+        }
+    }
+
+    pub fn try_from_str(s: &str) -> Result<Self, NtError> {
+        let mut string = Self::new();
+
+        string.try_push_str(s)?;
+
+        Ok(string)
+    }
+
+    pub fn as_str(&self) -> &str {
+        unsafe { core::str::from_utf8_unchecked(&self.bytes) }
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+
+    pub fn len(&self) -> usize {
+        self.bytes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.bytes.is_empty()
+    }
+
+    pub const fn capacity(&self) -> usize {
+        N
+    }
+
+    /// append `s`, it fails with STATUS_BUFFER_TOO_SMALL if it does not fit
+    pub fn try_push_str(&mut self, s: &str) -> Result<(), NtError> {
+        self.bytes.try_extend_from_slice(s.as_bytes())
+    }
+
+    pub fn try_push(&mut self, c: char) -> Result<(), NtError> {
+        self.try_push_str(c.encode_utf8(&mut [0; 4]))
+    }
+
+    pub fn pop(&mut self) -> Option<char> {
+        let c = self.as_str().chars().next_back()?;
+
+        self.bytes.truncate(self.len() - c.len_utf8());
+
+        Some(c)
+    }
+
+    /// shorten the string to `len` bytes, it panics if `len` is not at a char boundary
+    pub fn truncate(&mut self, len: usize) {
+        if len < self.len() {
+            assert!(self.as_str().is_char_boundary(len), "not a char boundary");
+
+            self.bytes.truncate(len);
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.bytes.clear();
+    }
+}
+
+impl<const N: usize> Deref for ArrayString<N> {
+    type Target = str;
+    fn deref(&self) -> &Self::Target {
+        self.as_str()
+    }
+}
+
+impl<const N: usize> fmt::Write for ArrayString<N> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.try_push_str(s).map_err(|_| fmt::Error)
+    }
+}
+
+impl<const N: usize> fmt::Display for ArrayString<N> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl<const N: usize> fmt::Debug for ArrayString<N> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(self.as_str(), f)
+    }
+}
+
+/// A FIFO ring with an inline capacity of `N` elements
+///
+/// `push` rejects a new element when it is full, `push_overwrite` drops the oldest one instead, which suits a
+/// history of the latest events
+pub struct StaticRing<T, const N: usize> {
+    buffer: [MaybeUninit<T>; N],
+    head: usize,
+    len: usize,
+}
+
+impl<T, const N: usize> StaticRing<T, N> {
+    pub const fn new() -> Self {
+        Self {
+            buffer: [const { MaybeUninit::uninit() }; N],
+            head: 0,
+            len: 0,
+        }
+    }
+
+    pub const fn len(&self) -> usize {
+        self.len
+    }
+
+    pub const fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub const fn is_full(&self) -> bool {
+        self.len == N
+    }
+
+    pub const fn capacity(&self) -> usize {
+        N
+    }
+
+    fn slot(&self, i: usize) -> usize {
+        (self.head + i) % N
+    }
+
+    /// push `value` to the back, it is returned back if the ring is full
+    pub fn push(&mut self, value: T) -> Result<(), T> {
+        if self.is_full() {
+            return Err(value);
+        }
+
+        let tail = self.slot(self.len);
+
+        self.buffer[tail].write(value);
+        self.len += 1;
+
+        Ok(())
+    }
+
+    /// push `value` to the back, the oldest element is removed and returned if the ring is full
+    pub fn push_overwrite(&mut self, value: T) -> Option<T> {
+        if N == 0 {
+            return Some(value);
+        }
+
+        let oldest = if self.is_full() { self.pop() } else { None };
+
+        let _ = self.push(value);
+
+        oldest
+    }
+
+    /// pop the oldest element
+    pub fn pop(&mut self) -> Option<T> {
+        if self.is_empty() {
+            return None;
+        }
+
+        let value = unsafe { self.buffer[self.head].assume_init_read() };
+
+        self.head = self.slot(1);
+        self.len -= 1;
+
+        Some(value)
+    }
+
+    /// the oldest element
+    pub fn front(&self) -> Option<&T> {
+        self.get(0)
+    }
+
+    /// the latest element
+    pub fn back(&self) -> Option<&T> {
+        self.len.checked_sub(1).and_then(|i| self.get(i))
+    }
+
+    /// the `i`th oldest element
+    pub fn get(&self, i: usize) -> Option<&T> {
+        if i >= self.len {
+            return None;
+        }
+
+        Some(unsafe { self.buffer[self.slot(i)].assume_init_ref() })
+    }
+
+    /// iterate from the oldest to the latest
+    pub fn iter(&self) -> impl Iterator<Item = &T> {
+        (0..self.len).map(|i| unsafe { self.buffer[self.slot(i)].assume_init_ref() })
+    }
+
+    pub fn clear(&mut self) {
+        while self.pop().is_some() {}
+    }
+}
+
+impl<T, const N: usize> Default for StaticRing<T, N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T: fmt::Debug, const N: usize> fmt::Debug for StaticRing<T, N> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_list().entries(self.iter()).finish()
+    }
+}
+
+impl<T, const N: usize> Drop for StaticRing<T, N> {
+    fn drop(&mut self) {
+        self.clear();
+    }
+}
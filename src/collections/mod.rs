@@ -0,0 +1,6 @@
+//! this module provides the collections which are not covered by `alloc`
+//!
+//! - `fixed`: the fixed-capacity collections stored inline, they never allocate
+pub mod fixed;
+
+pub use fixed::{ArrayString, ArrayVec, StaticRing};
@@ -0,0 +1,793 @@
+//! this module provides a kernel to user message channel, both directions support a reply
+//!
+//! - `IoctlPort` is an inverted call channel on a device object, it works for any driver:
+//!   the client keeps `IOCTL_COMM_GET_MESSAGE` requests pending, and a kernel message completes one of them
+//! - `FltPort` is a FltMgr communication port, it requires the `minifilter` feature
+//!
+//! the messages are prefixed with a `MessageHeader` and the replies with a `ReplyHeader`, both have the same layout
+//! as FILTER_MESSAGE_HEADER and FILTER_REPLY_HEADER, so the client code is almost the same for the two ports
+//!
+//! only one client can be connected at a time
+//!
+//! # Example
+//! ```
+//! struct Policy;
+//!
+//! impl MessageHandler for Policy {
+//!     fn on_message(&self, input: &[u8], output: &mut [u8]) -> Result<usize, NtError> {
+//!         update_policy(input)?;
+//!         Ok(0)
+//!     }
+//! }
+//!
+//! let port = IoctlPort::new(Policy)?;
+//! driver.create_device(DeviceProperty::new().set_name("Edr").set_symbol_name("Edr"), Some(Box::new(port.clone())))?;
+//!
+//! // on a process creation
+//! let mut verdict = [0u8; 4];
+//! port.send(bytes_of(&event), Some(&mut verdict), Some(Duration::from_secs(3)))?;
+//! ```
+use core::{
+    mem, ptr,
+    sync::atomic::{AtomicPtr, AtomicU64, Ordering},
+    time::Duration,
+};
+
+use alloc::vec::Vec;
+use wdk_sys::{
+    _EVENT_TYPE::NotificationEvent,
+    _KWAIT_REASON::Executive,
+    _MODE::KernelMode,
+    FILE_OBJECT, IO_NO_INCREMENT, IRP_MJ_CLEANUP, IRP_MJ_CLOSE, IRP_MJ_CREATE,
+    IRP_MJ_DEVICE_CONTROL, KEVENT, NTSTATUS, PDEVICE_OBJECT, PIRP, STATUS_BUFFER_TOO_SMALL,
+    STATUS_CONNECTION_COUNT_LIMIT, STATUS_DEVICE_NOT_READY, STATUS_INSUFFICIENT_RESOURCES,
+    STATUS_INVALID_DEVICE_REQUEST, STATUS_NOT_FOUND, STATUS_NOT_IMPLEMENTED,
+    STATUS_PORT_DISCONNECTED, STATUS_SUCCESS, STATUS_TIMEOUT,
+    ntddk::{KeInitializeEvent, KeSetEvent, KeWaitForSingleObject},
+};
+
+use crate::{
+    arc::KArc,
+    csq::CancelSafeQueue,
+    irp::Irp,
+    mutex::StaticSpinLocked,
+    ntstatus::{NtError, cvt},
+    time,
+    wdm::{IrpDispatch, STATUS_IRP_QUEUED},
+};
+
+const fn ctl_code(function: u32) -> u32 {
+    // FILE_DEVICE_UNKNOWN, METHOD_BUFFERED, FILE_ANY_ACCESS
+    (0x22 << 16) | (function << 2)
+}
+
+/// pended by the client, it is completed with a `MessageHeader` followed by a message
+pub const IOCTL_COMM_GET_MESSAGE: u32 = ctl_code(0x800);
+/// a `ReplyHeader` followed by the reply of a message
+pub const IOCTL_COMM_REPLY: u32 = ctl_code(0x801);
+/// a message to the driver, it is handled by `MessageHandler::on_message`
+pub const IOCTL_COMM_SEND: u32 = ctl_code(0x802);
+
+/// FILTER_MESSAGE_HEADER
+#[repr(C)]
+#[derive(Clone, Copy, Default, Debug)]
+pub struct MessageHeader {
+    /// the size of the reply buffer of the sender, 0 if no reply is expected
+    pub reply_length: u32,
+    pub message_id: u64,
+}
+
+/// FILTER_REPLY_HEADER
+#[repr(C)]
+#[derive(Clone, Copy, Default, Debug)]
+pub struct ReplyHeader {
+    pub status: NTSTATUS,
+    pub message_id: u64,
+}
+
+/// The callbacks of a port, they are called at PASSIVE_LEVEL
+pub trait MessageHandler: Send + Sync {
+    /// a client is connecting, return an error to refuse it
+    ///
+    /// `context` is the connection context of `FilterConnectCommunicationPort`, it is empty for an `IoctlPort`
+    fn on_connect(&self, context: &[u8]) -> Result<(), NtError> {
+        Ok(())
+    }
+
+    /// the client is disconnected, the messages waiting for a reply have been failed with STATUS_PORT_DISCONNECTED
+    fn on_disconnect(&self) {}
+
+    /// a message from the client, returns the number of bytes written to `output`
+    fn on_message(&self, input: &[u8], output: &mut [u8]) -> Result<usize, NtError> {
+        Err(NtError::new(STATUS_NOT_IMPLEMENTED))
+    }
+}
+
+/// A kernel to user channel
+pub trait Port: Send + Sync {
+    /// send `message` to the client, it must be called at IRQL <= APC_LEVEL
+    ///
+    /// if `reply` is given, wait for the reply until `timeout`(forever if `None`), the number of bytes of the reply is
+    /// returned, a longer reply is truncated
+    fn send(
+        &self,
+        message: &[u8],
+        reply: Option<&mut [u8]>,
+        timeout: Option<Duration>,
+    ) -> Result<usize, NtError>;
+
+    fn is_connected(&self) -> bool;
+}
+
+/// a sender waiting for the reply, it lives on the stack of `send`
+struct Waiter {
+    message_id: u64,
+    event: KEVENT,
+    reply: *mut u8,
+    capacity: usize,
+    returned: usize,
+    status: NTSTATUS,
+}
+
+struct IoctlInner<H> {
+    handler: H,
+    pending: CancelSafeQueue,
+    client: AtomicPtr<FILE_OBJECT>,
+    next_id: AtomicU64,
+    waiters: StaticSpinLocked<Vec<*mut Waiter>>,
+}
+
+/// An inverted call port, it is the `IrpDispatch` of the device the client opens
+///
+/// the client:
+/// 1. opens the device, `on_connect` is called
+/// 2. keeps several overlapped `IOCTL_COMM_GET_MESSAGE` requests pending, a message is dropped with
+///    STATUS_DEVICE_NOT_READY if none is pending
+/// 3. replies a message with `IOCTL_COMM_REPLY` if `MessageHeader::reply_length` is not 0
+/// 4. sends its own messages with `IOCTL_COMM_SEND`
+/// 5. closes the handle, `on_disconnect` is called
+///
+/// all the IOCTLs are METHOD_BUFFERED
+pub struct IoctlPort<H: MessageHandler>(KArc<IoctlInner<H>>);
+
+impl<H: MessageHandler> IoctlPort<H> {
+    pub fn new(handler: H) -> Result<Self, NtError> {
+        Ok(Self(KArc::new(IoctlInner {
+            handler,
+            pending: CancelSafeQueue::new()?,
+            client: AtomicPtr::new(ptr::null_mut()),
+            next_id: AtomicU64::new(0),
+            waiters: StaticSpinLocked::new(Vec::new()),
+        })?))
+    }
+
+    pub fn handler(&self) -> &H {
+        &self.0.handler
+    }
+
+    fn connect(&self, file_object: *mut FILE_OBJECT) -> Result<u64, NtError> {
+        self.0
+            .client
+            .compare_exchange(
+                ptr::null_mut(),
+                file_object,
+                Ordering::AcqRel,
+                Ordering::Acquire,
+            )
+            .map_err(|_| NtError::new(STATUS_CONNECTION_COUNT_LIMIT))?;
+
+        if let Err(e) = self.0.handler.on_connect(&[]) {
+            self.0.client.store(ptr::null_mut(), Ordering::Release);
+            return Err(e);
+        }
+
+        Ok(0)
+    }
+
+    fn disconnect(&self, file_object: *mut FILE_OBJECT) {
+        if self
+            .0
+            .client
+            .compare_exchange(
+                file_object,
+                ptr::null_mut(),
+                Ordering::AcqRel,
+                Ordering::Acquire,
+            )
+            .is_err()
+        {
+            return;
+        }
+
+        self.0.pending.cancel_all(file_object.cast());
+
+        let mut waiters = self.0.waiters.lock();
+
+        for waiter in waiters.drain(..) {
+            unsafe {
+                (*waiter).status = STATUS_PORT_DISCONNECTED;
+                KeSetEvent(&mut (*waiter).event, IO_NO_INCREMENT as _, 0);
+            }
+        }
+
+        drop(waiters);
+
+        self.0.handler.on_disconnect();
+    }
+
+    fn reply(&self, irp: &mut Irp) -> Result<u64, NtError> {
+        let input_len = irp.input_buffer_len();
+        let buffer = irp.system_buffer_bytes()?;
+
+        if input_len < mem::size_of::<ReplyHeader>() {
+            return Err(NtError::new(STATUS_BUFFER_TOO_SMALL));
+        }
+
+        let header = unsafe { ptr::read_unaligned(buffer.as_ptr() as *const ReplyHeader) };
+        let data = &buffer[mem::size_of::<ReplyHeader>()..input_len];
+
+        let mut waiters = self.0.waiters.lock();
+
+        let index = waiters
+            .iter()
+            .position(|waiter| unsafe { (**waiter).message_id } == header.message_id)
+            .ok_or(NtError::new(STATUS_NOT_FOUND))?;
+
+        let waiter = waiters.swap_remove(index);
+
+        // the event is set under the lock, so a timed out sender either finds itself in the list or sees the reply
+        unsafe {
+            let len = data.len().min((*waiter).capacity);
+
+            ptr::copy_nonoverlapping(data.as_ptr(), (*waiter).reply, len);
+
+            (*waiter).returned = len;
+            (*waiter).status = header.status;
+
+            KeSetEvent(&mut (*waiter).event, IO_NO_INCREMENT as _, 0);
+        }
+
+        Ok(0)
+    }
+
+    fn receive(&self, irp: &mut Irp) -> Result<u64, NtError> {
+        let input_len = irp.input_buffer_len();
+        let output_len = irp.output_buffer_len();
+        let buffer = irp.system_buffer_bytes()?;
+
+        // the input and the output share the system buffer
+        let mut input = Vec::new();
+
+        input
+            .try_reserve_exact(input_len)
+            .map_err(|_| NtError::new(STATUS_INSUFFICIENT_RESOURCES))?;
+
+        input.extend_from_slice(&buffer[..input_len]);
+
+        let output = &mut buffer[..output_len];
+
+        self.0
+            .handler
+            .on_message(&input, output)
+            .map(|n| n.min(output_len) as u64)
+    }
+}
+
+impl<H: MessageHandler> Clone for IoctlPort<H> {
+    fn clone(&self) -> Self {
+        Self(self.0.clone())
+    }
+}
+
+impl<H: MessageHandler> IrpDispatch for IoctlPort<H> {
+    fn dispatch(&self, device: PDEVICE_OBJECT, irp: PIRP) -> Result<u64, NtError> {
+        let mut irp = unsafe { Irp::from_raw(irp) };
+        let file_object = irp.stack_location().FileObject;
+
+        match irp.major_function() {
+            IRP_MJ_CREATE => self.connect(file_object),
+            IRP_MJ_CLEANUP => {
+                self.disconnect(file_object);
+                Ok(0)
+            }
+            IRP_MJ_CLOSE => Ok(0),
+            IRP_MJ_DEVICE_CONTROL => {
+                if self.0.client.load(Ordering::Acquire) != file_object {
+                    return Err(NtError::new(STATUS_PORT_DISCONNECTED));
+                }
+
+                match irp.ioctl_code() {
+                    IOCTL_COMM_GET_MESSAGE => {
+                        if irp.output_buffer_len() < mem::size_of::<MessageHeader>() {
+                            return Err(NtError::new(STATUS_BUFFER_TOO_SMALL));
+                        }
+
+                        // the IRP is completed with the error by the dispatcher if it can not be queued, once
+                        // queued a `send` may complete it at any time, so the dispatcher must not touch it
+                        self.0.pending.insert(irp.as_raw()).map_err(|(_, e)| e)?;
+
+                        Err(NtError::new(STATUS_IRP_QUEUED))
+                    }
+                    IOCTL_COMM_REPLY => self.reply(&mut irp),
+                    IOCTL_COMM_SEND => self.receive(&mut irp),
+                    _ => Err(NtError::new(STATUS_INVALID_DEVICE_REQUEST)),
+                }
+            }
+            _ => Err(NtError::new(STATUS_INVALID_DEVICE_REQUEST)),
+        }
+    }
+}
+
+impl<H: MessageHandler> Port for IoctlPort<H> {
+    fn send(
+        &self,
+        message: &[u8],
+        reply: Option<&mut [u8]>,
+        timeout: Option<Duration>,
+    ) -> Result<usize, NtError> {
+        if !self.is_connected() {
+            return Err(NtError::new(STATUS_PORT_DISCONNECTED));
+        }
+
+        let message_id = self.0.next_id.fetch_add(1, Ordering::Relaxed) + 1;
+
+        let mut waiter = Waiter {
+            message_id,
+            event: KEVENT::default(),
+            reply: ptr::null_mut(),
+            capacity: 0,
+            returned: 0,
+            status: STATUS_SUCCESS,
+        };
+
+        let waiting = reply.is_some();
+
+        // register before the message is visible to the client, the reply may come back at once
+        if let Some(reply) = reply {
+            unsafe { KeInitializeEvent(&mut waiter.event, NotificationEvent, 0) };
+
+            waiter.reply = reply.as_mut_ptr();
+            waiter.capacity = reply.len();
+
+            let mut waiters = self.0.waiters.lock();
+
+            waiters
+                .try_reserve(1)
+                .map_err(|_| NtError::new(STATUS_INSUFFICIENT_RESOURCES))?;
+
+            waiters.push(&mut waiter);
+        }
+
+        let unregister = |waiter: &mut Waiter| -> bool {
+            let mut waiters = self.0.waiters.lock();
+
+            match waiters.iter().position(|w| ptr::eq(*w, waiter)) {
+                Some(index) => {
+                    waiters.swap_remove(index);
+                    true
+                }
+                None => false,
+            }
+        };
+
+        let Some(irp) = self.0.pending.remove_next(ptr::null_mut()) else {
+            if waiting {
+                unregister(&mut waiter);
+            }
+
+            return Err(NtError::new(STATUS_DEVICE_NOT_READY));
+        };
+
+        let mut irp = unsafe { Irp::from_raw(irp) };
+        let total = mem::size_of::<MessageHeader>() + message.len();
+
+        if irp.output_buffer_len() < total {
+            irp.complete(STATUS_BUFFER_TOO_SMALL, 0);
+
+            if waiting {
+                unregister(&mut waiter);
+            }
+
+            return Err(NtError::new(STATUS_BUFFER_TOO_SMALL));
+        }
+
+        let header = MessageHeader {
+            reply_length: waiter.capacity as _,
+            message_id,
+        };
+
+        let buffer = match irp.system_buffer_bytes() {
+            Ok(buffer) => buffer,
+            Err(e) => {
+                irp.complete(e.code(), 0);
+
+                if waiting {
+                    unregister(&mut waiter);
+                }
+
+                return Err(e);
+            }
+        };
+
+        unsafe { ptr::write_unaligned(buffer.as_mut_ptr() as *mut MessageHeader, header) };
+        buffer[mem::size_of::<MessageHeader>()..total].copy_from_slice(message);
+
+        irp.complete(STATUS_SUCCESS, total as _);
+
+        if !waiting {
+            return Ok(0);
+        }
+
+        let mut timeout = timeout.map(time::relative);
+
+        let status = unsafe {
+            KeWaitForSingleObject(
+                (&mut waiter.event as *mut KEVENT).cast(),
+                Executive,
+                KernelMode as _,
+                0,
+                timeout.as_mut().map_or(ptr::null_mut(), |t| t as *mut _),
+            )
+        };
+
+        if status == STATUS_TIMEOUT && unregister(&mut waiter) {
+            return Err(NtError::new(STATUS_TIMEOUT));
+        }
+
+        cvt(waiter.status).map(|_| waiter.returned)
+    }
+
+    fn is_connected(&self) -> bool {
+        !self.0.client.load(Ordering::Acquire).is_null()
+    }
+}
+
+// Safety
+// the waiters only point to the stack of the senders which are blocked until they are removed
+unsafe impl<H: MessageHandler> Send for IoctlInner<H> {}
+unsafe impl<H: MessageHandler> Sync for IoctlInner<H> {}
+
+#[cfg(feature = "minifilter")]
+pub use flt::FltPort;
+
+#[cfg(feature = "minifilter")]
+mod flt {
+    use core::{
+        ffi::c_void, ptr, slice, sync::atomic::AtomicPtr, sync::atomic::Ordering, time::Duration,
+    };
+
+    use alloc::{boxed::Box, vec::Vec};
+    use wdk_sys::{
+        _MODE::KernelMode, ACCESS_MASK, LONG, NTSTATUS, OBJ_CASE_INSENSITIVE, OBJ_KERNEL_HANDLE,
+        PLARGE_INTEGER, POBJECT_ATTRIBUTES, PSECURITY_DESCRIPTOR, PULONG, PVOID, SIZE_T,
+        STATUS_ACCESS_VIOLATION, STATUS_INSUFFICIENT_RESOURCES, STATUS_PORT_DISCONNECTED,
+        STATUS_SUCCESS, ULONG, ntddk::IoGetCurrentProcess,
+    };
+
+    use super::{MessageHandler, Port};
+    use crate::{
+        initialize_object_attributes,
+        minifilter::PFLT_FILTER,
+        ntstatus::{NtError, cvt},
+        process::{MmCopyVirtualMemory, MmUserProbeAddress},
+        time,
+        unicode::NtUnicodeString,
+        utils,
+    };
+
+    pub type PFLT_PORT = PVOID;
+
+    /// FLT_PORT_ALL_ACCESS
+    const FLT_PORT_ALL_ACCESS: ACCESS_MASK = 0x001F0001;
+
+    type ConnectNotify = Option<
+        unsafe extern "C" fn(
+            ClientPort: PFLT_PORT,
+            ServerPortCookie: PVOID,
+            ConnectionContext: PVOID,
+            SizeOfContext: ULONG,
+            ConnectionPortCookie: *mut PVOID,
+        ) -> NTSTATUS,
+    >;
+
+    type DisconnectNotify = Option<unsafe extern "C" fn(ConnectionCookie: PVOID)>;
+
+    type MessageNotify = Option<
+        unsafe extern "C" fn(
+            PortCookie: PVOID,
+            InputBuffer: PVOID,
+            InputBufferLength: ULONG,
+            OutputBuffer: PVOID,
+            OutputBufferLength: ULONG,
+            ReturnOutputBufferLength: PULONG,
+        ) -> NTSTATUS,
+    >;
+
+    #[link(name = "FltMgr")]
+    unsafe extern "C" {
+        fn FltBuildDefaultSecurityDescriptor(
+            SecurityDescriptor: *mut PSECURITY_DESCRIPTOR,
+            DesiredAccess: ACCESS_MASK,
+        ) -> NTSTATUS;
+
+        fn FltFreeSecurityDescriptor(SecurityDescriptor: PSECURITY_DESCRIPTOR);
+
+        fn FltCreateCommunicationPort(
+            Filter: PFLT_FILTER,
+            ServerPort: *mut PFLT_PORT,
+            ObjectAttributes: POBJECT_ATTRIBUTES,
+            ServerPortCookie: PVOID,
+            ConnectNotifyCallback: ConnectNotify,
+            DisconnectNotifyCallback: DisconnectNotify,
+            MessageNotifyCallback: MessageNotify,
+            MaxConnections: LONG,
+        ) -> NTSTATUS;
+
+        fn FltCloseCommunicationPort(ServerPort: PFLT_PORT);
+
+        fn FltCloseClientPort(Filter: PFLT_FILTER, ClientPort: *mut PFLT_PORT);
+
+        fn FltSendMessage(
+            Filter: PFLT_FILTER,
+            ClientPort: *mut PFLT_PORT,
+            SenderBuffer: PVOID,
+            SenderBufferLength: ULONG,
+            ReplyBuffer: PVOID,
+            ReplyLength: PULONG,
+            Timeout: PLARGE_INTEGER,
+        ) -> NTSTATUS;
+    }
+
+    /// copy between a user buffer `user` and a kernel buffer, the user buffer must lie in user space entirely
+    fn copy_user(user: PVOID, kernel: PVOID, len: usize, to_user: bool) -> Result<(), NtError> {
+        let in_user_space = (user as usize)
+            .checked_add(len)
+            .is_some_and(|end| end <= unsafe { MmUserProbeAddress } as usize);
+
+        if !in_user_space {
+            return Err(NtError::new(STATUS_ACCESS_VIOLATION));
+        }
+
+        let (from, to) = if to_user {
+            (kernel, user)
+        } else {
+            (user, kernel)
+        };
+
+        let process = unsafe { IoGetCurrentProcess() };
+        let mut copied: SIZE_T = 0;
+
+        // the kernel buffer does not pass the probe of UserMode, the user buffer is checked above instead
+        cvt(unsafe {
+            MmCopyVirtualMemory(
+                process,
+                from,
+                process,
+                to,
+                len as _,
+                KernelMode as _,
+                &mut copied,
+            )
+        })
+    }
+
+    struct FltInner<H> {
+        filter: PFLT_FILTER,
+        server: PFLT_PORT,
+        client: AtomicPtr<c_void>,
+        handler: H,
+    }
+
+    /// A FltMgr communication port, the client connects with `FilterConnectCommunicationPort`
+    ///
+    /// the port is closed on drop, it must be dropped before the `MiniFilter`
+    ///
+    /// # Note
+    /// the reply of `FilterReplyMessage` is received without the FILTER_REPLY_HEADER, and the buffers of
+    /// `FilterSendMessage` are copied from and to user mode for `MessageHandler::on_message`
+    pub struct FltPort<H: MessageHandler>(Box<FltInner<H>>);
+
+    impl<H: MessageHandler> FltPort<H> {
+        /// create the port `name`(e.g. "\\EdrPort"), only the administrators and the system can connect to it
+        pub fn create(filter: PFLT_FILTER, name: &str, handler: H) -> Result<Self, NtError> {
+            let name = NtUnicodeString::from_str(name)?;
+
+            let mut inner = utils::try_box(FltInner {
+                filter,
+                server: ptr::null_mut(),
+                client: AtomicPtr::new(ptr::null_mut()),
+                handler,
+            })?;
+
+            let mut descriptor: PSECURITY_DESCRIPTOR = ptr::null_mut();
+
+            cvt(unsafe {
+                FltBuildDefaultSecurityDescriptor(&mut descriptor, FLT_PORT_ALL_ACCESS)
+            })?;
+
+            let mut attributes = initialize_object_attributes!(
+                name.as_ptr(),
+                OBJ_KERNEL_HANDLE | OBJ_CASE_INSENSITIVE,
+                ptr::null_mut(),
+                descriptor
+            );
+
+            let cookie = inner.as_mut() as *mut FltInner<H> as PVOID;
+
+            let status = unsafe {
+                FltCreateCommunicationPort(
+                    filter,
+                    &mut inner.server,
+                    &mut attributes,
+                    cookie,
+                    Some(connect_stub::<H>),
+                    Some(disconnect_stub::<H>),
+                    Some(message_stub::<H>),
+                    1,
+                )
+            };
+
+            unsafe { FltFreeSecurityDescriptor(descriptor) };
+
+            cvt(status)?;
+
+            Ok(Self(inner))
+        }
+
+        pub fn handler(&self) -> &H {
+            &self.0.handler
+        }
+    }
+
+    impl<H: MessageHandler> Port for FltPort<H> {
+        fn send(
+            &self,
+            message: &[u8],
+            reply: Option<&mut [u8]>,
+            timeout: Option<Duration>,
+        ) -> Result<usize, NtError> {
+            if !self.is_connected() {
+                return Err(NtError::new(STATUS_PORT_DISCONNECTED));
+            }
+
+            let mut timeout = timeout.map(time::relative);
+
+            let (reply, mut reply_len) = match reply {
+                Some(reply) => (reply.as_mut_ptr(), reply.len() as ULONG),
+                None => (ptr::null_mut(), 0),
+            };
+
+            let status = unsafe {
+                FltSendMessage(
+                    self.0.filter,
+                    self.0.client.as_ptr(),
+                    message.as_ptr() as _,
+                    message.len() as _,
+                    reply.cast(),
+                    if reply.is_null() {
+                        ptr::null_mut()
+                    } else {
+                        &mut reply_len
+                    },
+                    timeout.as_mut().map_or(ptr::null_mut(), |t| t as *mut _),
+                )
+            };
+
+            // STATUS_TIMEOUT is a success status
+            match status {
+                STATUS_SUCCESS => Ok(reply_len as usize),
+                _ => Err(NtError::new(status)),
+            }
+        }
+
+        fn is_connected(&self) -> bool {
+            !self.0.client.load(Ordering::Acquire).is_null()
+        }
+    }
+
+    impl<H: MessageHandler> Drop for FltPort<H> {
+        fn drop(&mut self) {
+            unsafe {
+                // no new connection after this
+                FltCloseCommunicationPort(self.0.server);
+
+                if !self.0.client.load(Ordering::Acquire).is_null() {
+                    FltCloseClientPort(self.0.filter, self.0.client.as_ptr());
+                }
+            }
+        }
+    }
+
+    unsafe impl<H: MessageHandler> Send for FltPort<H> {}
+    unsafe impl<H: MessageHandler> Sync for FltPort<H> {}
+
+    extern "C" fn connect_stub<H: MessageHandler>(
+        client: PFLT_PORT,
+        server_cookie: PVOID,
+        context: PVOID,
+        context_len: ULONG,
+        connection_cookie: *mut PVOID,
+    ) -> NTSTATUS {
+        let inner = unsafe { &*(server_cookie as *const FltInner<H>) };
+
+        // the connection context is captured by FltMgr
+        let context = if context.is_null() || context_len == 0 {
+            &[][..]
+        } else {
+            unsafe { slice::from_raw_parts(context as *const u8, context_len as _) }
+        };
+
+        if let Err(e) = inner.handler.on_connect(context) {
+            return e.code();
+        }
+
+        inner.client.store(client, Ordering::Release);
+
+        unsafe { *connection_cookie = server_cookie };
+
+        STATUS_SUCCESS
+    }
+
+    extern "C" fn disconnect_stub<H: MessageHandler>(cookie: PVOID) {
+        let inner = unsafe { &*(cookie as *const FltInner<H>) };
+
+        // FltMgr fails the pending `FltSendMessage` of this client
+        unsafe { FltCloseClientPort(inner.filter, inner.client.as_ptr()) };
+
+        inner.client.store(ptr::null_mut(), Ordering::Release);
+
+        inner.handler.on_disconnect();
+    }
+
+    extern "C" fn message_stub<H: MessageHandler>(
+        cookie: PVOID,
+        input: PVOID,
+        input_len: ULONG,
+        output: PVOID,
+        output_len: ULONG,
+        returned: PULONG,
+    ) -> NTSTATUS {
+        let inner = unsafe { &*(cookie as *const FltInner<H>) };
+
+        let capture = |len: ULONG| -> Result<Vec<u8>, NtError> {
+            let mut buffer = Vec::new();
+
+            buffer
+                .try_reserve_exact(len as _)
+                .map_err(|_| NtError::new(STATUS_INSUFFICIENT_RESOURCES))?;
+
+            buffer.resize(len as _, 0);
+
+            Ok(buffer)
+        };
+
+        let result = (|| {
+            let mut request = capture(if input.is_null() { 0 } else { input_len })?;
+
+            if !request.is_empty() {
+                copy_user(input, request.as_mut_ptr().cast(), request.len(), false)?;
+            }
+
+            let mut response = capture(if output.is_null() { 0 } else { output_len })?;
+
+            let len = inner
+                .handler
+                .on_message(&request, &mut response)?
+                .min(response.len());
+
+            if len != 0 {
+                copy_user(output, response.as_mut_ptr().cast(), len, true)?;
+            }
+
+            Ok::<_, NtError>(len)
+        })();
+
+        match result {
+            Ok(len) => {
+                unsafe { *returned = len as _ };
+                STATUS_SUCCESS
+            }
+            Err(e) => e.code(),
+        }
+    }
+}
@@ -0,0 +1,326 @@
+//! this module provides a typed configuration loaded from the registry, usually the `Parameters` key of the driver
+//!
+//! a `Schema` maps the registry values onto the fields of a user-defined struct, the values which are missing or of
+//! a wrong type keep the defaults, the loaded struct is published through an `AtomicArc`, so the hot paths always
+//! see a consistent snapshot and a reload never blocks them
+//!
+//! `Config::watch` polls the key in a system thread and publishes the changes
+//!
+//! # Example
+//! ```
+//! #[derive(Clone, Default)]
+//! struct Settings {
+//!     max_pending: u32,
+//!     verbose: bool,
+//!     log_path: String,
+//!     excluded: Vec<String>,
+//! }
+//!
+//! let schema = Schema::new()
+//!     .dword("MaxPending", |s: &mut Settings, v| s.max_pending = v)
+//!     .bool("Verbose", |s, v| s.verbose = v)
+//!     .string("LogPath", |s, v| s.log_path = v)
+//!     .multi_string("Excluded", |s, v| s.excluded = v);
+//!
+//! // `registry_path` is the one passed to DriverEntry
+//! let path = alloc::format!("{}\\Parameters", registry_path);
+//! let config = KArc::new(Config::load(&path, schema, Settings::default())?)?;
+//! let watcher = Config::watch(&config, Duration::from_secs(10))?;
+//!
+//! // hot path, any IRQL
+//! if config.current().verbose {
+//!     // ...
+//! }
+//! ```
+use core::{mem, ptr, slice, time::Duration};
+
+use alloc::{string::String, vec::Vec};
+use wdk_sys::{
+    _KEY_VALUE_INFORMATION_CLASS::KeyValuePartialInformation,
+    HANDLE, KEY_READ, KEY_VALUE_PARTIAL_INFORMATION, OBJ_CASE_INSENSITIVE, OBJ_KERNEL_HANDLE,
+    REG_DWORD, REG_EXPAND_SZ, REG_MULTI_SZ, REG_SZ, STATUS_BUFFER_OVERFLOW,
+    STATUS_BUFFER_TOO_SMALL, STATUS_INSUFFICIENT_RESOURCES, STATUS_OBJECT_NAME_NOT_FOUND, ULONG,
+    ntddk::{ZwOpenKey, ZwQueryValueKey},
+};
+
+use crate::{
+    arc::{AtomicArc, KArc},
+    event::NotificationEvent,
+    handle::ObjectHandle,
+    initialize_object_attributes,
+    ntstatus::{NtError, cvt},
+    thread::{self, JoinHandle},
+    unicode::NtUnicodeString,
+};
+
+/// how a registry value is stored into a field
+enum Setter<T> {
+    Dword(fn(&mut T, u32)),
+    Bool(fn(&mut T, bool)),
+    String(fn(&mut T, String)),
+    MultiString(fn(&mut T, Vec<String>)),
+}
+
+struct Field<T> {
+    name: &'static str,
+    setter: Setter<T>,
+}
+
+/// The mapping from the registry values to the fields of `T`
+pub struct Schema<T> {
+    fields: Vec<Field<T>>,
+}
+
+impl<T> Schema<T> {
+    pub fn new() -> Self {
+        Self { fields: Vec::new() }
+    }
+
+    fn field(mut self, name: &'static str, setter: Setter<T>) -> Self {
+        self.fields.push(Field { name, setter });
+
+        self
+    }
+
+    /// a REG_DWORD value
+    pub fn dword(self, name: &'static str, setter: fn(&mut T, u32)) -> Self {
+        self.field(name, Setter::Dword(setter))
+    }
+
+    /// a REG_DWORD value, any non-zero value is true
+    pub fn bool(self, name: &'static str, setter: fn(&mut T, bool)) -> Self {
+        self.field(name, Setter::Bool(setter))
+    }
+
+    /// a REG_SZ or REG_EXPAND_SZ value, the environment variables are not expanded
+    pub fn string(self, name: &'static str, setter: fn(&mut T, String)) -> Self {
+        self.field(name, Setter::String(setter))
+    }
+
+    /// a REG_MULTI_SZ value
+    pub fn multi_string(self, name: &'static str, setter: fn(&mut T, Vec<String>)) -> Self {
+        self.field(name, Setter::MultiString(setter))
+    }
+}
+
+impl<T> Default for Schema<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// A configuration loaded from a registry key
+pub struct Config<T> {
+    path: NtUnicodeString,
+    schema: Schema<T>,
+    defaults: T,
+    current: AtomicArc<T>,
+}
+
+impl<T: Clone> Config<T> {
+    /// load the values of the key `path`, e.g. "\Registry\Machine\System\CurrentControlSet\Services\xxx\Parameters"
+    ///
+    /// it must be called at PASSIVE_LEVEL, it fails with STATUS_OBJECT_NAME_NOT_FOUND if the key does not exist
+    pub fn load(path: &str, schema: Schema<T>, defaults: T) -> Result<Self, NtError> {
+        let path = NtUnicodeString::from_str(path)?;
+        let value = read(&path, &schema, defaults.clone())?;
+
+        Ok(Self {
+            path,
+            schema,
+            defaults,
+            current: AtomicArc::new(KArc::new(value)?),
+        })
+    }
+
+    /// the latest snapshot, it can be called at any IRQL <= DISPATCH_LEVEL
+    pub fn current(&self) -> KArc<T> {
+        self.current.load()
+    }
+
+    /// read the key again and publish the result, it must be called at PASSIVE_LEVEL
+    ///
+    /// the current snapshot is kept if the key can not be read
+    pub fn reload(&self) -> Result<(), NtError> {
+        let value = read(&self.path, &self.schema, self.defaults.clone())?;
+
+        self.current.store(KArc::new(value)?);
+
+        Ok(())
+    }
+}
+
+impl<T: Clone + Send + Sync + 'static> Config<T> {
+    /// reload `config` every `interval` in a system thread until the returned watcher is dropped
+    pub fn watch(config: &KArc<Self>, interval: Duration) -> Result<ConfigWatcher, NtError> {
+        let stop = KArc::new(NotificationEvent::new(false)?)?;
+
+        let thread = {
+            let config = config.clone();
+            let stop = stop.clone();
+
+            thread::spawn(move || {
+                while stop.wait_timeout(interval).timed_out() {
+                    let _ = config.reload();
+                }
+            })?
+        };
+
+        Ok(ConfigWatcher {
+            stop,
+            thread: Some(thread),
+        })
+    }
+}
+
+unsafe impl<T: Send + Sync> Send for Config<T> {}
+unsafe impl<T: Send + Sync> Sync for Config<T> {}
+
+/// The polling thread of `Config::watch`, it is stopped and joined on drop at PASSIVE_LEVEL
+pub struct ConfigWatcher {
+    stop: KArc<NotificationEvent>,
+    thread: Option<JoinHandle>,
+}
+
+impl Drop for ConfigWatcher {
+    fn drop(&mut self) {
+        self.stop.set();
+
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// read all the fields of `schema` into `value`
+fn read<T>(path: &NtUnicodeString, schema: &Schema<T>, mut value: T) -> Result<T, NtError> {
+    crate::assert_irql!(== PASSIVE_LEVEL);
+
+    let mut attributes = initialize_object_attributes!(
+        path.as_ptr(),
+        OBJ_KERNEL_HANDLE | OBJ_CASE_INSENSITIVE,
+        ptr::null_mut(),
+        ptr::null_mut()
+    );
+
+    let mut handle: HANDLE = ptr::null_mut();
+
+    cvt(unsafe { ZwOpenKey(&mut handle, KEY_READ, &mut attributes) })?;
+
+    let key = ObjectHandle::new(handle);
+
+    for field in schema.fields.iter() {
+        let (kind, data) = match query(&key, field.name) {
+            Ok(raw) => raw,
+            Err(e) if e.code() == STATUS_OBJECT_NAME_NOT_FOUND => continue,
+            Err(e) => return Err(e),
+        };
+
+        match (&field.setter, kind) {
+            (Setter::Dword(set), REG_DWORD) if data.len() >= 4 => set(
+                &mut value,
+                u32::from_le_bytes([data[0], data[1], data[2], data[3]]),
+            ),
+            (Setter::Bool(set), REG_DWORD) if data.len() >= 4 => {
+                set(&mut value, data[..4].iter().any(|b| *b != 0))
+            }
+            (Setter::String(set), REG_SZ | REG_EXPAND_SZ) => {
+                // an empty string keeps the default as well
+                if let Some(s) = split_strings(&data)?.into_iter().next() {
+                    set(&mut value, s);
+                }
+            }
+            (Setter::MultiString(set), REG_MULTI_SZ) => set(&mut value, split_strings(&data)?),
+            // a value of a wrong type keeps the default
+            _ => {}
+        }
+    }
+
+    Ok(value)
+}
+
+/// the type and the data of the value `name`
+fn query(key: &ObjectHandle, name: &str) -> Result<(ULONG, Vec<u8>), NtError> {
+    let name = NtUnicodeString::from_str(name)?;
+
+    let header = mem::offset_of!(KEY_VALUE_PARTIAL_INFORMATION, Data);
+    let mut buffer: Vec<u64> = Vec::new();
+
+    grow(&mut buffer, (header + 64).div_ceil(8))?;
+
+    loop {
+        let mut needed: ULONG = 0;
+
+        let status = unsafe {
+            ZwQueryValueKey(
+                key.get(),
+                name.as_ptr(),
+                KeyValuePartialInformation,
+                buffer.as_mut_ptr().cast(),
+                (buffer.len() * 8) as _,
+                &mut needed,
+            )
+        };
+
+        if status == STATUS_BUFFER_OVERFLOW || status == STATUS_BUFFER_TOO_SMALL {
+            grow(&mut buffer, (needed as usize).div_ceil(8))?;
+            continue;
+        }
+
+        cvt(status)?;
+
+        let info = unsafe { &*(buffer.as_ptr() as *const KEY_VALUE_PARTIAL_INFORMATION) };
+        let length = (info.DataLength as usize).min(buffer.len() * 8 - header);
+
+        // `Data` is declared as a single byte, so the data is reached from the whole buffer
+        let data =
+            unsafe { slice::from_raw_parts(buffer.as_ptr().cast::<u8>().add(header), length) };
+
+        let mut copy = Vec::new();
+
+        copy.try_reserve_exact(length)
+            .map_err(|_| NtError::new(STATUS_INSUFFICIENT_RESOURCES))?;
+        copy.extend_from_slice(data);
+
+        return Ok((info.Type, copy));
+    }
+}
+
+/// zero-extend `buffer` to `len` elements
+fn grow(buffer: &mut Vec<u64>, len: usize) -> Result<(), NtError> {
+    buffer
+        .try_reserve(len.saturating_sub(buffer.len()))
+        .map_err(|_| NtError::new(STATUS_INSUFFICIENT_RESOURCES))?;
+    buffer.resize(len.max(buffer.len()), 0);
+
+    Ok(())
+}
+
+/// the nul separated UTF-16 strings of a REG_SZ or REG_MULTI_SZ value, an empty string ends the list
+fn split_strings(data: &[u8]) -> Result<Vec<String>, NtError> {
+    let mut units = data
+        .chunks_exact(2)
+        .map(|c| u16::from_le_bytes([c[0], c[1]]));
+    let mut strings = Vec::new();
+
+    loop {
+        let mut s = String::new();
+
+        for c in char::decode_utf16(units.by_ref().take_while(|u| *u != 0)) {
+            let c = c.unwrap_or(char::REPLACEMENT_CHARACTER);
+
+            s.try_reserve(c.len_utf8())
+                .map_err(|_| NtError::new(STATUS_INSUFFICIENT_RESOURCES))?;
+            s.push(c);
+        }
+
+        if s.is_empty() {
+            return Ok(strings);
+        }
+
+        strings
+            .try_reserve(1)
+            .map_err(|_| NtError::new(STATUS_INSUFFICIENT_RESOURCES))?;
+        strings.push(s);
+    }
+}
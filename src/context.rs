@@ -0,0 +1,187 @@
+//! this module provides `DriverContext<T>`, a driver-wide singleton with an explicit init/teardown lifecycle
+//!
+//! it formalizes the pattern of a static `OnceLock` initialized in `DriverEntry` and dropped in `DriverUnload`:
+//! - `init` stores the driver object and the context `T` once
+//! - other objects(threads, timers, trace sinks, etc.) can be registered to be released at teardown
+//! - `teardown` releases the registered objects in reverse order, then drops `T`
+//!
+//! accessing the context after teardown is a bug, it is caught by a debug assertion
+//!
+//! # Example
+//! ```
+//! struct Globals {
+//!     config: Config,
+//!     stats: SpinLocked<Stats>,
+//! }
+//!
+//! static CONTEXT: DriverContext<Globals> = DriverContext::new();
+//!
+//! fn driver_entry(driver: PDRIVER_OBJECT, ...) -> NTSTATUS {
+//!     CONTEXT.init(driver, Globals { ... })?;
+//!     CONTEXT.set_trace_sink(&ETW_SINK);
+//!     CONTEXT.register(timer);
+//! }
+//!
+//! fn on_event() {
+//!     CONTEXT.get().stats.lock().unwrap().events += 1;
+//! }
+//!
+//! fn driver_unload(driver: PDRIVER_OBJECT) {
+//!     // all the callbacks, threads and timers using the context are gone
+//!     unsafe { CONTEXT.teardown() };
+//! }
+//! ```
+use core::{
+    any::Any,
+    ptr::NonNull,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use alloc::{boxed::Box, vec::Vec};
+use wdk_sys::{DRIVER_OBJECT, PDRIVER_OBJECT, STATUS_INVALID_PARAMETER, STATUS_UNSUCCESSFUL};
+
+use crate::{
+    lazy::OnceLock,
+    mutex::FastLocked,
+    ntstatus::NtError,
+    trace::{self, Sink},
+};
+
+enum Registration {
+    Object(Box<dyn Any + Send>),
+    Routine(Box<dyn FnOnce() + Send>),
+}
+
+struct Inner<T> {
+    driver: NonNull<DRIVER_OBJECT>,
+    data: T,
+    registrations: FastLocked<Vec<Registration>>,
+}
+
+/// A driver-wide context, it is typically declared as a `static`
+pub struct DriverContext<T> {
+    inner: OnceLock<Inner<T>>,
+    torn_down: AtomicBool,
+}
+
+impl<T> DriverContext<T> {
+    pub const fn new() -> Self {
+        Self {
+            inner: OnceLock::new(),
+            torn_down: AtomicBool::new(false),
+        }
+    }
+
+    /// initialize the context, it fails with STATUS_UNSUCCESSFUL if it is already initialized or torn down
+    pub fn init(&self, driver: PDRIVER_OBJECT, data: T) -> Result<&T, NtError> {
+        if self.torn_down.load(Ordering::Acquire) {
+            return Err(NtError::new(STATUS_UNSUCCESSFUL));
+        }
+
+        let driver = NonNull::new(driver).ok_or(NtError::new(STATUS_INVALID_PARAMETER))?;
+
+        let inner = Inner {
+            driver,
+            data,
+            registrations: FastLocked::new(Vec::new())?,
+        };
+
+        self.inner
+            .set(inner)
+            .map_err(|_| NtError::new(STATUS_UNSUCCESSFUL))?;
+
+        Ok(&self.inner().data)
+    }
+
+    #[inline]
+    fn inner(&self) -> &Inner<T> {
+        debug_assert!(
+            !self.torn_down.load(Ordering::Acquire),
+            "DriverContext used after teardown"
+        );
+
+        self.inner.get().expect("DriverContext is not initialized")
+    }
+
+    /// the context, it panics if the context is not initialized
+    pub fn get(&self) -> &T {
+        &self.inner().data
+    }
+
+    /// the context, `None` if it is not initialized or torn down
+    pub fn try_get(&self) -> Option<&T> {
+        if self.torn_down.load(Ordering::Acquire) {
+            return None;
+        }
+
+        self.inner.get().map(|inner| &inner.data)
+    }
+
+    pub fn is_initialized(&self) -> bool {
+        self.try_get().is_some()
+    }
+
+    pub fn driver(&self) -> PDRIVER_OBJECT {
+        self.inner().driver.as_ptr()
+    }
+
+    fn push(&self, registration: Registration) {
+        if let Ok(mut registrations) = self.inner().registrations.lock() {
+            registrations.push(registration);
+        }
+    }
+
+    /// keep `object` alive until teardown, it is dropped before the context
+    pub fn register<R: Send + 'static>(&self, object: R) {
+        self.push(Registration::Object(Box::new(object)));
+    }
+
+    /// run `f` at teardown before the context is dropped
+    pub fn on_teardown<F: FnOnce() + Send + 'static>(&self, f: F) {
+        self.push(Registration::Routine(Box::new(f)));
+    }
+
+    /// install a trace sink, the default sink is restored at teardown
+    pub fn set_trace_sink(&self, sink: &'static dyn Sink) {
+        trace::set_sink(sink);
+
+        self.on_teardown(trace::reset_sink);
+    }
+
+    /// release the registrations in reverse order and drop the context, it must be called at PASSIVE_LEVEL
+    ///
+    /// it does nothing if the context is not initialized or already torn down
+    ///
+    /// # Safety
+    /// no reference returned by `init`, `get` or `try_get` may be alive, and no other thread may use the context
+    /// meanwhile, e.g. all the callbacks are unregistered and the threads are joined before
+    pub unsafe fn teardown(&self) {
+        if self.torn_down.load(Ordering::Acquire) || self.inner.get().is_none() {
+            return;
+        }
+
+        if self.torn_down.swap(true, Ordering::AcqRel) {
+            return;
+        }
+
+        let inner = self.inner.get().unwrap();
+
+        let mut registrations = match inner.registrations.lock() {
+            Ok(mut registrations) => core::mem::take(&mut *registrations),
+            Err(_) => Vec::new(),
+        };
+
+        while let Some(registration) = registrations.pop() {
+            match registration {
+                Registration::Object(object) => drop(object),
+                Registration::Routine(routine) => routine(),
+            }
+        }
+
+        OnceLock::drop(&self.inner);
+    }
+}
+
+// Safety
+// the registrations are only accessed under the lock, and released in `teardown`
+unsafe impl<T: Send + Sync> Sync for DriverContext<T> {}
@@ -0,0 +1,245 @@
+//! this module provides group-aware processor enumeration and affinity utilities
+//!
+//! a system can have more than 64 processors, which are split into processor groups; a processor is identified by
+//! its system wide index or by its group and its number in the group(`PROCESSOR_NUMBER`)
+//!
+//! # Example
+//! ```
+//! let topology = cpu::topology();
+//! println!("{} processors in {} groups", topology.active_count(), topology.group_count());
+//!
+//! // per-CPU initialization, e.g. MSR setup
+//! cpu::run_on_each_cpu(|processor| {
+//!     init_per_cpu(processor.index());
+//! })?;
+//!
+//! // run on all the processors at DISPATCH_LEVEL at once
+//! cpu::broadcast_dpc(|index| flush_per_cpu_cache(index));
+//! ```
+use wdk_sys::{
+    ALL_PROCESSOR_GROUPS, GROUP_AFFINITY, LOGICAL, PKDEFERRED_ROUTINE, PKDPC, PROCESSOR_NUMBER,
+    PVOID, STATUS_NOT_FOUND, USHORT,
+    ntddk::{
+        KeGetCurrentProcessorNumberEx, KeGetProcessorNumberFromIndex, KeQueryActiveGroupCount,
+        KeQueryActiveProcessorCountEx, KeQueryHighestNodeNumber, KeQueryNodeActiveAffinity,
+        KeRevertToUserGroupAffinityThread, KeSetSystemGroupAffinityThread,
+    },
+};
+
+use crate::ntstatus::{NtError, cvt};
+
+// undocumented but exported since Windows Vista
+unsafe extern "C" {
+    pub fn KeGenericCallDpc(Routine: PKDEFERRED_ROUTINE, Context: PVOID);
+    pub fn KeSignalCallDpcDone(SystemArgument1: PVOID);
+    pub fn KeSignalCallDpcSynchronize(SystemArgument2: PVOID) -> LOGICAL;
+}
+
+/// An active processor
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Processor {
+    index: u32,
+    group: u16,
+    number: u8,
+}
+
+impl Processor {
+    /// the processor of the system wide `index`
+    pub fn from_index(index: u32) -> Result<Self, NtError> {
+        let mut number = PROCESSOR_NUMBER::default();
+
+        cvt(unsafe { KeGetProcessorNumberFromIndex(index, &mut number) })?;
+
+        Ok(Self {
+            index,
+            group: number.Group,
+            number: number.Number,
+        })
+    }
+
+    /// the processor the caller is running on, it may change at IRQL < DISPATCH_LEVEL
+    pub fn current() -> Self {
+        let mut number = PROCESSOR_NUMBER::default();
+
+        let index = unsafe { KeGetCurrentProcessorNumberEx(&mut number) };
+
+        Self {
+            index,
+            group: number.Group,
+            number: number.Number,
+        }
+    }
+
+    /// the system wide index
+    pub fn index(&self) -> u32 {
+        self.index
+    }
+
+    pub fn group(&self) -> u16 {
+        self.group
+    }
+
+    /// the number in its group
+    pub fn number(&self) -> u8 {
+        self.number
+    }
+
+    pub fn as_processor_number(&self) -> PROCESSOR_NUMBER {
+        PROCESSOR_NUMBER {
+            Group: self.group,
+            Number: self.number,
+            Reserved: 0,
+        }
+    }
+
+    /// the affinity of this processor alone
+    pub fn affinity(&self) -> GROUP_AFFINITY {
+        GROUP_AFFINITY {
+            Mask: 1 << self.number,
+            Group: self.group,
+            ..Default::default()
+        }
+    }
+
+    /// the NUMA node this processor belongs to
+    pub fn node(&self) -> Result<u16, NtError> {
+        let highest = unsafe { KeQueryHighestNodeNumber() };
+
+        for node in 0..=highest {
+            let mut affinity = GROUP_AFFINITY::default();
+            let mut count: USHORT = 0;
+
+            unsafe { KeQueryNodeActiveAffinity(node, &mut affinity, &mut count) };
+
+            if affinity.Group == self.group && affinity.Mask & (1 << self.number) != 0 {
+                return Ok(node);
+            }
+        }
+
+        Err(NtError::new(STATUS_NOT_FOUND))
+    }
+
+    /// run the current thread on this processor until the guard is dropped, it must be called at IRQL <= APC_LEVEL
+    pub fn pin_current_thread(&self) -> AffinityGuard {
+        AffinityGuard::new(self.affinity())
+    }
+}
+
+/// The active processors of the system
+#[derive(Clone, Copy, Debug)]
+pub struct Topology {
+    active_count: u32,
+    group_count: u16,
+}
+
+impl Topology {
+    /// the number of the active processors in all the groups
+    pub fn active_count(&self) -> u32 {
+        self.active_count
+    }
+
+    pub fn group_count(&self) -> u16 {
+        self.group_count
+    }
+
+    /// the number of the active processors in `group`
+    pub fn group_active_count(&self, group: u16) -> u32 {
+        unsafe { KeQueryActiveProcessorCountEx(group) }
+    }
+
+    /// the number of the NUMA nodes
+    pub fn node_count(&self) -> u16 {
+        unsafe { KeQueryHighestNodeNumber() + 1 }
+    }
+
+    /// the active processors in the order of their system wide indexes
+    pub fn processors(&self) -> impl Iterator<Item = Processor> {
+        (0..self.active_count).filter_map(|index| Processor::from_index(index).ok())
+    }
+}
+
+/// a snapshot of the active processors, it can change if processors are hot-added
+pub fn topology() -> Topology {
+    unsafe {
+        Topology {
+            active_count: KeQueryActiveProcessorCountEx(ALL_PROCESSOR_GROUPS as _),
+            group_count: KeQueryActiveGroupCount(),
+        }
+    }
+}
+
+/// A guard of a system affinity of the current thread, the previous affinity is restored on drop
+///
+/// it must not be sent to another thread
+pub struct AffinityGuard {
+    previous: GROUP_AFFINITY,
+    _not_send: core::marker::PhantomData<*const ()>,
+}
+
+impl AffinityGuard {
+    /// set the affinity of the current thread, it must be called at IRQL <= APC_LEVEL
+    pub fn new(affinity: GROUP_AFFINITY) -> Self {
+        let mut previous = GROUP_AFFINITY::default();
+
+        unsafe { KeSetSystemGroupAffinityThread(&affinity as *const _ as _, &mut previous) };
+
+        Self {
+            previous,
+            _not_send: core::marker::PhantomData,
+        }
+    }
+}
+
+impl Drop for AffinityGuard {
+    fn drop(&mut self) {
+        unsafe { KeRevertToUserGroupAffinityThread(&mut self.previous) };
+    }
+}
+
+/// run `f` on every active processor in turn by switching the affinity of the current thread
+///
+/// it must be called at PASSIVE_LEVEL, `f` runs at the IRQL of the caller, use `dpc::run_once_per_core` for
+/// DISPATCH_LEVEL work
+pub fn run_on_each_cpu<F: FnMut(Processor)>(mut f: F) -> Result<(), NtError> {
+    for index in 0..topology().active_count() {
+        let processor = Processor::from_index(index)?;
+
+        let _guard = processor.pin_current_thread();
+
+        f(processor);
+    }
+
+    Ok(())
+}
+
+/// run `f` once on every active processor at DISPATCH_LEVEL, with `KeGenericCallDpc`
+///
+/// `f` is called with the system wide index of the processor; all the processors wait for each other after `f`
+/// returns, so no processor leaves before all of them have run `f`, and `broadcast_dpc` returns after that
+///
+/// it must be called at PASSIVE_LEVEL, and `f` must not wait or touch pageable memory
+///
+/// # Note
+/// the processors spin while waiting for each other, so `f` should be short
+pub fn broadcast_dpc<F: Fn(u32) + Sync>(f: F) {
+    unsafe {
+        KeGenericCallDpc(Some(broadcast_routine_stub::<F>), &f as *const F as _);
+    }
+}
+
+extern "C" fn broadcast_routine_stub<F: Fn(u32) + Sync>(
+    dpc: PKDPC,
+    context: PVOID,
+    arg1: PVOID,
+    arg2: PVOID,
+) {
+    // `broadcast_dpc` does not return before all the DPCs are done, so the borrow is alive here
+    let f = unsafe { &*(context as *const F) };
+
+    f(Processor::current().index());
+
+    unsafe {
+        KeSignalCallDpcSynchronize(arg2);
+        KeSignalCallDpcDone(arg1);
+    }
+}
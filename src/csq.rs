@@ -0,0 +1,213 @@
+//! this module provides `CancelSafeQueue`, a wrapper of the cancel-safe IRP queue(IoCsqXxx)
+//!
+//! the pending IRPs are linked through `Irp->Tail.Overlay.ListEntry` in a spinlock protected list,
+//! the I/O manager handles the cancellation races, and a cancelled IRP is completed with STATUS_CANCELLED
+//!
+//! # Example
+//! ```
+//! // in IRP_MJ_READ dispatch
+//! fn dispatch(&self, device: PDEVICE_OBJECT, irp: PIRP) -> Result<u64, NtError> {
+//!     // the dispatcher completes the IRP with the error if it can not be queued
+//!     self.pending.insert(irp).map_err(|(_, e)| e)?;
+//!
+//!     // the IRP is marked as pending by the queue and may be completed already, the dispatcher must not touch it
+//!     Err(NtError::new(STATUS_IRP_QUEUED))
+//! }
+//!
+//! // when data arrives
+//! if let Some(irp) = pending.remove_next(ptr::null_mut()) {
+//!     let irp = unsafe { Irp::from_raw(irp) };
+//!     irp.complete(STATUS_SUCCESS, 0);
+//! }
+//! ```
+use core::{mem, ptr};
+
+use alloc::boxed::Box;
+use wdk_sys::{
+    IO_CSQ, IO_NO_INCREMENT, IRP, KIRQL, KSPIN_LOCK, LIST_ENTRY, NTSTATUS, PIO_CSQ, PIRP, PKIRQL,
+    PLIST_ENTRY, PVOID, STATUS_CANCELLED, STATUS_SUCCESS,
+    ntddk::{
+        IoCsqInitializeEx, IoCsqInsertIrpEx, IoCsqRemoveNextIrp, IofCompleteRequest,
+        KeAcquireSpinLockRaiseToDpc, KeInitializeSpinLock, KeReleaseSpinLock,
+    },
+};
+
+use crate::{
+    irp::IoGetCurrentIrpStackLocation,
+    list::{InitializeListHead, InsertTailList, RemoveEntryList},
+    ntstatus::{NtError, cvt},
+    utils::try_box,
+};
+
+/// the `IO_CSQ` must be the first field, the callbacks find the queue with the `PIO_CSQ`
+#[repr(C)]
+struct CsqInner {
+    csq: IO_CSQ,
+    lock: KSPIN_LOCK,
+    head: LIST_ENTRY,
+}
+
+#[inline]
+fn irp_to_entry(irp: PIRP) -> PLIST_ENTRY {
+    unsafe { &mut (*irp).Tail.Overlay.__bindgen_anon_2.ListEntry }
+}
+
+#[inline]
+fn entry_to_irp(entry: PLIST_ENTRY) -> PIRP {
+    unsafe {
+        (entry as *mut u8)
+            .sub(mem::offset_of!(
+                IRP,
+                Tail.Overlay.__bindgen_anon_2.ListEntry
+            ))
+            .cast()
+    }
+}
+
+/// A cancel-safe queue of pending IRPs
+///
+/// all the IRPs left in the queue are completed with STATUS_CANCELLED on drop
+pub struct CancelSafeQueue(Box<CsqInner>);
+
+impl CancelSafeQueue {
+    pub fn new() -> Result<Self, NtError> {
+        let mut inner = try_box(CsqInner {
+            csq: unsafe { mem::zeroed() },
+            lock: 0,
+            head: LIST_ENTRY {
+                Flink: ptr::null_mut(),
+                Blink: ptr::null_mut(),
+            },
+        })?;
+
+        unsafe { KeInitializeSpinLock(&mut inner.lock) };
+
+        InitializeListHead(&mut inner.head);
+
+        cvt(unsafe {
+            IoCsqInitializeEx(
+                &mut inner.csq,
+                Some(csq_insert_irp_stub),
+                Some(csq_remove_irp_stub),
+                Some(csq_peek_next_irp_stub),
+                Some(csq_acquire_lock_stub),
+                Some(csq_release_lock_stub),
+                Some(csq_complete_canceled_irp_stub),
+            )
+        })?;
+
+        Ok(Self(inner))
+    }
+
+    #[inline]
+    fn csq(&self) -> PIO_CSQ {
+        &self.0.csq as *const _ as _
+    }
+
+    /// pend the IRP in this queue, the IRP is marked as pending
+    ///
+    /// once it returns `Ok`, the IRP belongs to the queue: it may be removed and completed on another processor at any
+    /// time, and a cancelled IRP is completed with STATUS_CANCELLED by the queue itself, so the caller must not touch
+    /// it anymore and the dispatch routine returns `STATUS_IRP_QUEUED`
+    ///
+    /// it fails only if the IRP can not be inserted, the IRP is not pending then and it is returned with the error,
+    /// the caller still owns it and must complete it, e.g. with the error status
+    pub fn insert(&self, irp: PIRP) -> Result<(), (PIRP, NtError)> {
+        cvt(unsafe { IoCsqInsertIrpEx(self.csq(), irp, ptr::null_mut(), ptr::null_mut()) })
+            .map_err(|e| (irp, e))
+    }
+
+    /// remove the first IRP that is not being cancelled
+    ///
+    /// # Parameters
+    /// - peek_ctx: null to match any IRP, or a `PFILE_OBJECT` to match the IRPs issued on this file object
+    pub fn remove_next(&self, peek_ctx: PVOID) -> Option<PIRP> {
+        let irp = unsafe { IoCsqRemoveNextIrp(self.csq(), peek_ctx) };
+
+        if irp.is_null() { None } else { Some(irp) }
+    }
+
+    /// complete all the IRPs matching `peek_ctx` with STATUS_CANCELLED, typically used in IRP_MJ_CLEANUP
+    pub fn cancel_all(&self, peek_ctx: PVOID) {
+        while let Some(irp) = self.remove_next(peek_ctx) {
+            complete_canceled(irp);
+        }
+    }
+}
+
+impl Drop for CancelSafeQueue {
+    fn drop(&mut self) {
+        self.cancel_all(ptr::null_mut());
+    }
+}
+
+unsafe impl Send for CancelSafeQueue {}
+unsafe impl Sync for CancelSafeQueue {}
+
+fn complete_canceled(irp: PIRP) {
+    unsafe {
+        (*irp).IoStatus.__bindgen_anon_1.Status = STATUS_CANCELLED;
+        (*irp).IoStatus.Information = 0;
+
+        IofCompleteRequest(irp, IO_NO_INCREMENT as _);
+    }
+}
+
+/// the queue of a `PIO_CSQ`, a raw pointer since the lock callbacks run concurrently on different processors
+#[inline]
+fn inner(csq: PIO_CSQ) -> *mut CsqInner {
+    csq.cast()
+}
+
+extern "C" fn csq_insert_irp_stub(csq: PIO_CSQ, irp: PIRP, _insert_context: PVOID) -> NTSTATUS {
+    let inner = inner(csq);
+
+    InsertTailList(unsafe { &raw mut (*inner).head }, irp_to_entry(irp));
+
+    STATUS_SUCCESS
+}
+
+extern "C" fn csq_remove_irp_stub(_csq: PIO_CSQ, irp: PIRP) {
+    RemoveEntryList(irp_to_entry(irp));
+}
+
+extern "C" fn csq_peek_next_irp_stub(csq: PIO_CSQ, irp: PIRP, peek_context: PVOID) -> PIRP {
+    let inner = inner(csq);
+    let head: PLIST_ENTRY = unsafe { &raw mut (*inner).head };
+
+    let mut entry = if irp.is_null() {
+        unsafe { (*head).Flink }
+    } else {
+        unsafe { (*irp_to_entry(irp)).Flink }
+    };
+
+    while entry != head {
+        let next = entry_to_irp(entry);
+
+        if peek_context.is_null()
+            || unsafe { (*IoGetCurrentIrpStackLocation(next)).FileObject } as PVOID == peek_context
+        {
+            return next;
+        }
+
+        entry = unsafe { (*entry).Flink };
+    }
+
+    ptr::null_mut()
+}
+
+extern "C" fn csq_acquire_lock_stub(csq: PIO_CSQ, irql: PKIRQL) {
+    let inner = inner(csq);
+
+    unsafe { *irql = KeAcquireSpinLockRaiseToDpc(&raw mut (*inner).lock) };
+}
+
+extern "C" fn csq_release_lock_stub(csq: PIO_CSQ, irql: KIRQL) {
+    let inner = inner(csq);
+
+    unsafe { KeReleaseSpinLock(&raw mut (*inner).lock, irql) };
+}
+
+extern "C" fn csq_complete_canceled_irp_stub(_csq: PIO_CSQ, irp: PIRP) {
+    complete_canceled(irp);
+}
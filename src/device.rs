@@ -0,0 +1,369 @@
+//! this module provides `Device<T>`, a device object that stores a user defined `T` as its device extension
+//!
+//! unlike `OwnedDevice` which boxes the dispatch handler, the `T` of a `Device<T>` lives inside the
+//! `DeviceExtension` right behind the DEVICE_OBJECT, and it is recoverable from a raw `PDEVICE_OBJECT`
+//!
+//! # Example
+//! ```
+//! struct Control {
+//!     opened: AtomicU32,
+//! }
+//!
+//! impl IrpDispatch for Control {
+//!     fn dispatch(&self, device: PDEVICE_OBJECT, irp: PIRP) -> Result<u64, NtError> {
+//!         self.opened.fetch_add(1, Ordering::Relaxed);
+//!         Ok(0)
+//!     }
+//! }
+//!
+//! // the dispatch routines must be set up by `Driver::new`
+//! let driver = Driver::new(driver_object);
+//!
+//! let mut device = Device::create(driver.as_raw(), "MyDevice", FILE_DEVICE_UNKNOWN, Control {
+//!     opened: AtomicU32::new(0),
+//! })?;
+//!
+//! device.create_symbolic_link("MyDevice")?;
+//! ```
+//!
+//! a function device of a PnP stack is better found by its device interface than by a fixed `\\.\` name, the
+//! interface is registered on the PDO and enabled once the device is started
+//! ```
+//! device.register_interface(pdo, &MY_INTERFACE_GUID)?;
+//! device.enable_interface()?;
+//!
+//! // the name user mode gets from SetupDiGetDeviceInterfaceDetail
+//! println!("{}", device.interface().unwrap().symbolic_link_name());
+//! ```
+use core::{
+    marker::PhantomData,
+    mem,
+    ops::Deref,
+    ptr::{self, NonNull},
+};
+
+use alloc::{borrow::ToOwned, boxed::Box};
+use wdk_sys::{
+    _DEVICE_OBJECT, DO_DEVICE_INITIALIZING, FALSE, FILE_DEVICE_SECURE_OPEN, PDEVICE_OBJECT,
+    PDRIVER_OBJECT, STATUS_INSUFFICIENT_RESOURCES, STATUS_INVALID_DEVICE_STATE,
+    STATUS_OBJECT_NAME_EXISTS, TRUE, UNICODE_STRING,
+    ntddk::{
+        IoCreateDevice, IoCreateSymbolicLink, IoDeleteDevice, IoDeleteSymbolicLink, IoDetachDevice,
+        IoRegisterDeviceInterface, IoSetDeviceInterfaceState, RtlFreeUnicodeString,
+    },
+};
+
+use crate::{
+    guid::Guid,
+    ntstatus::{NtError, cvt},
+    sd::SecurityDescriptor,
+    unicode::NtUnicodeString,
+    utils,
+    wdm::{DispatchContext, IrpDispatch},
+};
+
+/// the layout of the device extension of a `Device<T>`
+///
+/// the `DispatchContext` must be the first field, so the IRPs are dispatched to `data` by the `Driver`
+#[repr(C)]
+struct Extension<T> {
+    context: DispatchContext<'static>,
+    data: T,
+}
+
+/// An owned device object with a `T` stored in the device extension
+///
+/// the symbolic link(if any) and the device object are deleted on drop, `T` is dropped in place
+pub struct Device<T: IrpDispatch + 'static> {
+    object: NonNull<_DEVICE_OBJECT>,
+    name: Box<UNICODE_STRING>,
+    symbolic_link: Option<Box<UNICODE_STRING>>,
+    interface: Option<DeviceInterface>,
+    _phantom: PhantomData<T>,
+}
+
+impl<T: IrpDispatch + 'static> Device<T> {
+    /// create a device named `\Device\{name}` of `device_type`, `data` is moved into the device extension
+    pub fn create(
+        driver: PDRIVER_OBJECT,
+        name: &str,
+        device_type: u32,
+        data: T,
+    ) -> Result<Self, NtError> {
+        Self::create_inner(driver, name, device_type, data, None)
+    }
+
+    /// the same as `create` but the DACL of the device is replaced by `sd` before it can be opened
+    ///
+    /// # Example
+    /// ```
+    /// let sd = SecurityDescriptor::system_and_admins()?;
+    /// let device = Device::create_secure(driver, "MyDevice", FILE_DEVICE_UNKNOWN, control, &sd)?;
+    /// ```
+    pub fn create_secure(
+        driver: PDRIVER_OBJECT,
+        name: &str,
+        device_type: u32,
+        data: T,
+        sd: &SecurityDescriptor,
+    ) -> Result<Self, NtError> {
+        Self::create_inner(driver, name, device_type, data, Some(sd))
+    }
+
+    fn create_inner(
+        driver: PDRIVER_OBJECT,
+        name: &str,
+        device_type: u32,
+        data: T,
+        sd: Option<&SecurityDescriptor>,
+    ) -> Result<Self, NtError> {
+        let mut name = utils::utf16_from_str(("\\Device\\".to_owned() + name).as_str())
+            .ok_or(NtError::new(STATUS_INSUFFICIENT_RESOURCES))?;
+
+        let mut device: PDEVICE_OBJECT = ptr::null_mut();
+
+        cvt(unsafe {
+            IoCreateDevice(
+                driver,
+                mem::size_of::<Extension<T>>() as _,
+                name.as_mut(),
+                device_type,
+                FILE_DEVICE_SECURE_OPEN,
+                0,
+                &mut device,
+            )
+        })?;
+
+        unsafe {
+            let ext = (*device).DeviceExtension as *mut Extension<T>;
+
+            ptr::write(&mut (*ext).data, data);
+            ptr::write(
+                &mut (*ext).context,
+                DispatchContext {
+                    irp_handler: &mut (*ext).data,
+                    attach_to: ptr::null_mut(),
+                    filter: false,
+                },
+            );
+        }
+
+        let device = Self {
+            object: NonNull::new(device).unwrap(),
+            name,
+            symbolic_link: None,
+            interface: None,
+            _phantom: PhantomData,
+        };
+
+        // the device can not be opened while it is initializing, so the default DACL is never exposed
+        if let Some(sd) = sd {
+            sd.apply_to_object(device.object.as_ptr().cast())?;
+        }
+
+        unsafe { (*device.object.as_ptr()).Flags &= !DO_DEVICE_INITIALIZING };
+
+        Ok(device)
+    }
+
+    /// replace the DACL of the device, it must be called at PASSIVE_LEVEL
+    pub fn set_security(&self, sd: &SecurityDescriptor) -> Result<(), NtError> {
+        sd.apply_to_object(self.object.as_ptr().cast())
+    }
+
+    /// create a symbolic link `\DosDevices\{link}` to this device, so it can be opened from user mode
+    pub fn create_symbolic_link(&mut self, link: &str) -> Result<(), NtError> {
+        if self.symbolic_link.is_some() {
+            return Err(NtError::new(STATUS_OBJECT_NAME_EXISTS));
+        }
+
+        let mut link = utils::utf16_from_str(("\\DosDevices\\".to_owned() + link).as_str())
+            .ok_or(NtError::new(STATUS_INSUFFICIENT_RESOURCES))?;
+
+        cvt(unsafe { IoCreateSymbolicLink(link.as_mut(), self.name.as_mut()) })?;
+
+        self.symbolic_link = Some(link);
+
+        Ok(())
+    }
+
+    /// register a device interface of the class `guid` on `pdo`, the PDO of the stack this device belongs to
+    ///
+    /// the interface is disabled until `enable_interface`, it must be called at PASSIVE_LEVEL, it fails with
+    /// STATUS_OBJECT_NAME_EXISTS if an interface is registered already
+    pub fn register_interface(&mut self, pdo: PDEVICE_OBJECT, guid: &Guid) -> Result<(), NtError> {
+        if self.interface.is_some() {
+            return Err(NtError::new(STATUS_OBJECT_NAME_EXISTS));
+        }
+
+        self.interface = Some(DeviceInterface::register(pdo, guid, None)?);
+
+        Ok(())
+    }
+
+    /// enable the interface registered by `register_interface`, usually when the device is started
+    ///
+    /// it fails with STATUS_INVALID_DEVICE_STATE if no interface is registered
+    pub fn enable_interface(&mut self) -> Result<(), NtError> {
+        self.interface
+            .as_mut()
+            .ok_or_else(|| NtError::new(STATUS_INVALID_DEVICE_STATE))?
+            .enable()
+    }
+
+    /// disable the interface, e.g. when the device is stopped or surprise removed
+    pub fn disable_interface(&mut self) -> Result<(), NtError> {
+        self.interface
+            .as_mut()
+            .ok_or_else(|| NtError::new(STATUS_INVALID_DEVICE_STATE))?
+            .disable()
+    }
+
+    pub fn interface(&self) -> Option<&DeviceInterface> {
+        self.interface.as_ref()
+    }
+
+    /// recover the `T` stored in the device extension of a raw device object
+    ///
+    /// # Safety
+    /// `device` must be created by `Device<T>::create` with the same `T`, and it must not be deleted yet
+    pub unsafe fn from_raw<'a>(device: PDEVICE_OBJECT) -> &'a T {
+        unsafe { &(*((*device).DeviceExtension as *const Extension<T>)).data }
+    }
+
+    pub fn data(&self) -> &T {
+        unsafe { Self::from_raw(self.object.as_ptr()) }
+    }
+
+    pub fn as_raw(&self) -> PDEVICE_OBJECT {
+        self.object.as_ptr()
+    }
+
+    pub fn device_name(&self) -> &UNICODE_STRING {
+        &self.name
+    }
+
+    pub fn symbolic_link(&self) -> Option<&UNICODE_STRING> {
+        self.symbolic_link.as_deref()
+    }
+}
+
+impl<T: IrpDispatch + 'static> Deref for Device<T> {
+    type Target = T;
+    fn deref(&self) -> &Self::Target {
+        self.data()
+    }
+}
+
+impl<T: IrpDispatch + 'static> Drop for Device<T> {
+    fn drop(&mut self) {
+        // disabled before the device object goes away
+        self.interface = None;
+
+        if let Some(link) = &mut self.symbolic_link {
+            let _ = unsafe { IoDeleteSymbolicLink(link.as_mut()) };
+        }
+
+        unsafe {
+            let ext = self.object.as_ref().DeviceExtension as *mut Extension<T>;
+
+            if !(*ext).context.attach_to.is_null() {
+                IoDetachDevice((*ext).context.attach_to);
+            }
+
+            ptr::drop_in_place(&mut (*ext).data);
+
+            IoDeleteDevice(self.object.as_ptr());
+        }
+    }
+}
+
+unsafe impl<T: IrpDispatch + Send + Sync + 'static> Send for Device<T> {}
+unsafe impl<T: IrpDispatch + Send + Sync + 'static> Sync for Device<T> {}
+
+/// A device interface registered on a PDO, it is disabled on drop
+pub struct DeviceInterface {
+    symbolic_link_name: NtUnicodeString,
+    enabled: bool,
+}
+
+impl DeviceInterface {
+    /// register an interface of the class `guid` on `pdo`, `reference` tells apart several interfaces of the same
+    /// class on one device, it must be called at PASSIVE_LEVEL
+    pub fn register(
+        pdo: PDEVICE_OBJECT,
+        guid: &Guid,
+        reference: Option<&str>,
+    ) -> Result<Self, NtError> {
+        crate::assert_irql!(== PASSIVE_LEVEL);
+
+        let reference = reference.map(NtUnicodeString::from_str).transpose()?;
+
+        let mut name: UNICODE_STRING = unsafe { mem::zeroed() };
+
+        cvt(unsafe {
+            IoRegisterDeviceInterface(
+                pdo,
+                guid.as_ptr(),
+                reference.as_ref().map_or(ptr::null_mut(), |r| r.as_ptr()),
+                &mut name,
+            )
+        })?;
+
+        // the name is allocated by the system, it is copied so it can be freed at once
+        let chars = unsafe { core::slice::from_raw_parts(name.Buffer, name.Length as usize / 2) };
+        let copied = NtUnicodeString::from_utf16(chars);
+
+        unsafe { RtlFreeUnicodeString(&mut name) };
+
+        Ok(Self {
+            symbolic_link_name: copied?,
+            enabled: false,
+        })
+    }
+
+    /// the name user mode opens the device with, as returned by `SetupDiGetDeviceInterfaceDetail`
+    pub fn symbolic_link_name(&self) -> &NtUnicodeString {
+        &self.symbolic_link_name
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+
+    /// make the interface visible to user mode, it must be called at PASSIVE_LEVEL
+    pub fn enable(&mut self) -> Result<(), NtError> {
+        self.set_state(true)
+    }
+
+    /// hide the interface from user mode, it must be called at PASSIVE_LEVEL
+    pub fn disable(&mut self) -> Result<(), NtError> {
+        self.set_state(false)
+    }
+
+    fn set_state(&mut self, enable: bool) -> Result<(), NtError> {
+        if self.enabled == enable {
+            return Ok(());
+        }
+
+        cvt(unsafe {
+            IoSetDeviceInterfaceState(
+                self.symbolic_link_name.as_ptr(),
+                if enable { TRUE } else { FALSE } as _,
+            )
+        })?;
+
+        self.enabled = enable;
+
+        Ok(())
+    }
+}
+
+impl Drop for DeviceInterface {
+    fn drop(&mut self) {
+        let _ = self.disable();
+    }
+}
+
+unsafe impl Send for DeviceInterface {}
+unsafe impl Sync for DeviceInterface {}
@@ -1,130 +1,294 @@
 use core::{
+    cell::UnsafeCell,
     mem::{self},
     ptr,
+    sync::atomic::{AtomicUsize, Ordering},
 };
 
 use alloc::boxed::Box;
 use wdk_sys::{
     _KDPC,
     _POOL_TYPE::NonPagedPoolNx,
-    ALL_PROCESSOR_GROUPS, PKDPC, PVOID, STATUS_INSUFFICIENT_RESOURCES,
+    ALL_PROCESSOR_GROUPS, PKDPC, PVOID,
     ntddk::{
-        ExFreePoolWithTag, KeInitializeDpc, KeInitializeThreadedDpc, KeInsertQueueDpc,
-        KeQueryActiveProcessorCountEx, KeSetTargetProcessorDpc,
+        KeFlushQueuedDpcs, KeInitializeDpc, KeInitializeThreadedDpc, KeInsertQueueDpc,
+        KeQueryActiveProcessorCountEx, KeRemoveQueueDpc, KeSetTargetProcessorDpc,
     },
 };
 
-use crate::{utils::ex_allocate_pool_zero, ntstatus::NtError};
+use crate::{
+    mutex::StaticSpinLocked,
+    ntstatus::NtError,
+    utils::{ex_allocate_pool_zero, ex_free_pool, try_box},
+};
 
 const DPC_TAG: u32 = u32::from_ne_bytes(*b"cpdk");
 
-/// A owned Ordinary DPC
-///
-/// keep DPC resident in memory until dropped user, can re-insert this DPC repeatedly
-#[repr(transparent)]
-pub struct Dpc(PKDPC);
+/// the callback stored in a reusable DPC
+trait Callback {
+    fn call(&self);
+}
 
-impl Dpc {
-    pub fn new<F: Fn()>(f: F) -> Result<Self, NtError> {
-        let layout = ex_allocate_pool_zero(NonPagedPoolNx, mem::size_of::<_KDPC>() as _, DPC_TAG);
+/// a `FnMut` callback, the invocations on different processors are serialized by the lock
+impl<F: FnMut()> Callback for StaticSpinLocked<F> {
+    fn call(&self) {
+        let mut f = self.lock();
+
+        (*f)();
+    }
+}
 
-        if layout.is_null() {
-            return Err(NtError::new(STATUS_INSUFFICIENT_RESOURCES));
+/// a `Fn` callback called without a lock, a threaded DPC keeps running at PASSIVE_LEVEL this way
+struct Shared<F>(F);
+
+impl<F: Fn()> Callback for Shared<F> {
+    fn call(&self) {
+        (self.0)();
+    }
+}
+
+/// how the DPC of a `RawDpc` is queued
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Kind {
+    /// queued by `activate`
+    Ordinary,
+    /// queued by `activate`, the routine runs at PASSIVE_LEVEL if threaded DPCs are enabled
+    Threaded,
+    /// queued by the system when a timer expires
+    Timer,
+}
+
+/// the DPC and its callback, it is freed by the last reference
+///
+/// the owner holds a reference and every insertion made by `activate` holds another one until the routine returns
+/// or the DPC is removed from the queue, so the callback is never dropped under a queued or running routine
+#[repr(C)]
+struct Context<C> {
+    dpc: UnsafeCell<_KDPC>,
+    refs: AtomicUsize,
+    callback: C,
+}
+
+impl<C: Callback> Context<C> {
+    /// drop a reference, the context is freed with the last one
+    unsafe fn release(this: *mut Self) {
+        if unsafe { (*this).refs.fetch_sub(1, Ordering::AcqRel) } == 1 {
+            unsafe {
+                ptr::drop_in_place(this);
+                ex_free_pool(this.cast(), DPC_TAG);
+            }
         }
+    }
+
+    /// type erased `release`, the DPC is the first field of the context
+    unsafe fn release_raw(dpc: PKDPC) {
+        unsafe { Self::release(dpc.cast()) };
+    }
+}
+
+/// A reusable DPC with a reference counted callback
+///
+/// dropping it releases the reference of the owner, the callback lives on until the queued routine has run
+pub(crate) struct RawDpc {
+    dpc: PKDPC,
+    refs: *const AtomicUsize,
+    release: unsafe fn(PKDPC),
+    kind: Kind,
+}
+
+impl RawDpc {
+    /// it fails with STATUS_INSUFFICIENT_RESOURCES if the DPC can not be allocated
+    fn new<C: Callback + 'static>(callback: C, kind: Kind) -> Result<Self, NtError> {
+        let layout =
+            ex_allocate_pool_zero(NonPagedPoolNx, mem::size_of::<Context<C>>() as _, DPC_TAG)?;
 
-        let callback = Box::new(f);
+        let context = layout.cast::<Context<C>>();
 
         unsafe {
-            KeInitializeDpc(
-                layout.cast(),
-                Some(deferred_routine_stub::<F>),
-                Box::into_raw(callback).cast(),
-            );
+            context.write(Context {
+                dpc: UnsafeCell::new(_KDPC::default()),
+                refs: AtomicUsize::new(1),
+                callback,
+            });
+
+            let dpc = (*context).dpc.get();
+
+            match kind {
+                Kind::Ordinary => {
+                    KeInitializeDpc(dpc, Some(queued_routine_stub::<C>), context.cast())
+                }
+                Kind::Threaded => {
+                    KeInitializeThreadedDpc(dpc, Some(queued_routine_stub::<C>), context.cast())
+                }
+                Kind::Timer => KeInitializeDpc(dpc, Some(timer_routine_stub::<C>), context.cast()),
+            }
+
+            Ok(Self {
+                dpc,
+                refs: &(*context).refs,
+                release: Context::<C>::release_raw,
+                kind,
+            })
         }
+    }
 
-        Ok(Self(layout.cast()))
+    /// the DPC of a timer, the callback can be `FnMut`
+    pub(crate) fn for_timer<F: FnMut() + Send + 'static>(f: F) -> Result<Self, NtError> {
+        Self::new(StaticSpinLocked::new(f), Kind::Timer)
     }
 
-    pub fn get(&self) -> PKDPC {
-        self.0
+    pub(crate) fn get(&self) -> PKDPC {
+        self.dpc
     }
 
-    pub fn set_affinity(&self, core: u32) {
+    fn set_affinity(&self, core: u32) {
         unsafe {
-            KeSetTargetProcessorDpc(self.0, core as _);
+            KeSetTargetProcessorDpc(self.dpc, core as _);
         }
     }
 
-    pub fn activate(&self) {
-        unsafe {
-            KeInsertQueueDpc(self.0, ptr::null_mut(), ptr::null_mut());
+    /// queue the DPC with a reference for the routine, returns false if it is queued already
+    fn activate(&self) -> bool {
+        debug_assert!(self.kind != Kind::Timer);
+
+        // the reference is taken first, the routine may run on another processor at once
+        unsafe { (*self.refs).fetch_add(1, Ordering::Relaxed) };
+
+        let queued = unsafe { KeInsertQueueDpc(self.dpc, ptr::null_mut(), ptr::null_mut()) != 0 };
+
+        if !queued {
+            unsafe { (self.release)(self.dpc) };
         }
+
+        queued
+    }
+
+    /// remove the DPC from the queue, returns true if it was queued
+    pub(crate) fn cancel(&self) -> bool {
+        let removed = unsafe { KeRemoveQueueDpc(self.dpc) != 0 };
+
+        // a timer does not take a reference when the system queues its DPC
+        if removed && self.kind != Kind::Timer {
+            unsafe { (self.release)(self.dpc) };
+        }
+
+        removed
+    }
+}
+
+impl Drop for RawDpc {
+    fn drop(&mut self) {
+        unsafe { (self.release)(self.dpc) };
+    }
+}
+
+unsafe impl Send for RawDpc {}
+unsafe impl Sync for RawDpc {}
+
+/// A owned Ordinary DPC
+///
+/// keep DPC resident in memory until dropped user, can re-insert this DPC repeatedly
+///
+/// the callback is dropped after the last queued routine has returned, even if the DPC is dropped before
+pub struct Dpc(RawDpc);
+
+impl Dpc {
+    /// same as `try_new`
+    pub fn new<F: FnMut() + Send + 'static>(f: F) -> Result<Self, NtError> {
+        Self::try_new(f)
+    }
+
+    /// it fails with STATUS_INSUFFICIENT_RESOURCES if the DPC or the callback can not be allocated
+    ///
+    /// the callback can keep a mutable state, the routines running on different processors take turns
+    pub fn try_new<F: FnMut() + Send + 'static>(f: F) -> Result<Self, NtError> {
+        RawDpc::new(StaticSpinLocked::new(f), Kind::Ordinary).map(Self)
+    }
+
+    pub fn get(&self) -> PKDPC {
+        self.0.get()
+    }
+
+    pub fn set_affinity(&self, core: u32) {
+        self.0.set_affinity(core);
+    }
+
+    /// queue the DPC, returns false if it is queued already
+    pub fn activate(&self) -> bool {
+        self.0.activate()
+    }
+
+    /// remove the DPC from the queue if it is queued, returns true if it was
+    ///
+    /// a DPC already running on another processor is not affected, use `flush_all` to wait for it
+    pub fn cancel(&self) -> bool {
+        self.0.cancel()
+    }
+
+    /// wait until all the DPCs queued on any processor have run, it must be called at PASSIVE_LEVEL
+    ///
+    /// it is the only way to know a DPC routine has returned, e.g. before the data it uses is freed
+    pub fn flush_all() {
+        flush_all();
     }
 }
 
 impl Drop for Dpc {
     fn drop(&mut self) {
-        unsafe {
-            ExFreePoolWithTag(self.0.cast(), DPC_TAG);
-        }
+        self.cancel();
     }
 }
 
 /// A owned Threaded DPC
 ///
 /// keep DPC resident in memory until dropped, user can re-insert this DPC repeatedly
-#[repr(transparent)]
-pub struct ThreadedDpc(PKDPC);
+///
+/// the callback is not serialized by a lock like the one of `Dpc`, so it must be `Fn` and `Sync`
+pub struct ThreadedDpc(RawDpc);
 
 impl ThreadedDpc {
-    pub fn new<F: Fn()>(f: F) -> Result<Self, NtError> {
-        let layout = ex_allocate_pool_zero(NonPagedPoolNx, mem::size_of::<_KDPC>() as _, DPC_TAG);
-
-        if layout.is_null() {
-            return Err(NtError::new(STATUS_INSUFFICIENT_RESOURCES));
-        }
-
-        let callback = Box::new(f);
-
-        unsafe {
-            KeInitializeThreadedDpc(
-                layout.cast(),
-                Some(deferred_routine_stub::<F>),
-                Box::into_raw(callback).cast(),
-            );
-        }
+    /// same as `try_new`
+    pub fn new<F: Fn() + Send + Sync + 'static>(f: F) -> Result<Self, NtError> {
+        Self::try_new(f)
+    }
 
-        Ok(Self(layout.cast()))
+    /// it fails with STATUS_INSUFFICIENT_RESOURCES if the DPC or the callback can not be allocated
+    pub fn try_new<F: Fn() + Send + Sync + 'static>(f: F) -> Result<Self, NtError> {
+        RawDpc::new(Shared(f), Kind::Threaded).map(Self)
     }
 
     pub fn get(&self) -> PKDPC {
-        self.0
+        self.0.get()
     }
 
-    pub fn activate(&self) {
-        unsafe {
-            KeInsertQueueDpc(self.0, ptr::null_mut(), ptr::null_mut());
-        }
+    /// the same as `Dpc::activate`
+    pub fn activate(&self) -> bool {
+        self.0.activate()
+    }
+
+    /// the same as `Dpc::cancel`
+    pub fn cancel(&self) -> bool {
+        self.0.cancel()
     }
 }
 
 impl Drop for ThreadedDpc {
     fn drop(&mut self) {
-        unsafe {
-            ExFreePoolWithTag(self.0.cast(), DPC_TAG);
-        }
+        self.cancel();
     }
 }
 
 /// create a Ordinary DPC for "run only once" semantic
 fn create_ordinary_dpc<F: FnOnce()>(f: F) -> Result<PKDPC, NtError> {
-    let layout = ex_allocate_pool_zero(NonPagedPoolNx, mem::size_of::<_KDPC>() as _, DPC_TAG);
+    let layout = ex_allocate_pool_zero(NonPagedPoolNx, mem::size_of::<_KDPC>() as _, DPC_TAG)?;
 
-    if layout.is_null() {
-        return Err(NtError::new(STATUS_INSUFFICIENT_RESOURCES));
-    }
-
-    let callback = Box::new(f);
+    let callback = match try_box(f) {
+        Ok(callback) => callback,
+        Err(e) => {
+            unsafe { ex_free_pool(layout, DPC_TAG) };
+            return Err(e);
+        }
+    };
 
     unsafe {
         KeInitializeDpc(
@@ -139,13 +303,15 @@ fn create_ordinary_dpc<F: FnOnce()>(f: F) -> Result<PKDPC, NtError> {
 
 /// create a Threaded DPC for "run only once" semantic
 fn create_threaded_dpc<F: FnOnce()>(f: F) -> Result<PKDPC, NtError> {
-    let layout = ex_allocate_pool_zero(NonPagedPoolNx, mem::size_of::<_KDPC>() as _, DPC_TAG);
+    let layout = ex_allocate_pool_zero(NonPagedPoolNx, mem::size_of::<_KDPC>() as _, DPC_TAG)?;
 
-    if layout.is_null() {
-        return Err(NtError::new(STATUS_INSUFFICIENT_RESOURCES));
-    }
-
-    let callback = Box::new(f);
+    let callback = match try_box(f) {
+        Ok(callback) => callback,
+        Err(e) => {
+            unsafe { ex_free_pool(layout, DPC_TAG) };
+            return Err(e);
+        }
+    };
 
     unsafe {
         KeInitializeThreadedDpc(
@@ -158,6 +324,13 @@ fn create_threaded_dpc<F: FnOnce()>(f: F) -> Result<PKDPC, NtError> {
     Ok(layout.cast())
 }
 
+/// wait until all the queued DPCs, ordinary and threaded, have run, it must be called at PASSIVE_LEVEL
+pub fn flush_all() {
+    crate::assert_irql!(== PASSIVE_LEVEL);
+
+    unsafe { KeFlushQueuedDpcs() };
+}
+
 /// run a ordinary DPC only once
 pub fn run_once<F: FnOnce() + 'static>(f: F) {
     if let Ok(dpc) = create_ordinary_dpc(f) {
@@ -200,16 +373,29 @@ pub fn run_once_threaded<F: FnOnce() + 'static>(f: F) {
     }
 }
 
-/// DPC callback stub routine
-extern "C" fn deferred_routine_stub<F: FnOnce()>(
-    dpc: PKDPC,
+/// the routine of a DPC queued by `activate`, it releases the reference taken for the insertion
+extern "C" fn queued_routine_stub<C: Callback>(
+    _dpc: PKDPC,
     context: PVOID,
-    arg1: PVOID,
-    arg2: PVOID,
+    _arg1: PVOID,
+    _arg2: PVOID,
 ) {
-    let callback: Box<F> = unsafe { Box::from_raw(mem::transmute::<_, *mut F>(context)) };
+    let context = context as *mut Context<C>;
 
-    callback();
+    unsafe {
+        (*context).callback.call();
+        Context::release(context);
+    }
+}
+
+/// the routine of a timer DPC, the `Timer` keeps the context until the routine can not run anymore
+extern "C" fn timer_routine_stub<C: Callback>(
+    _dpc: PKDPC,
+    context: PVOID,
+    _arg1: PVOID,
+    _arg2: PVOID,
+) {
+    unsafe { (*(context as *const Context<C>)).callback.call() };
 }
 
 extern "C" fn deferred_routine_once_stub<F: FnOnce()>(
@@ -223,6 +409,6 @@ extern "C" fn deferred_routine_once_stub<F: FnOnce()>(
     callback();
 
     unsafe {
-        ExFreePoolWithTag(dpc.cast(), DPC_TAG);
+        ex_free_pool(dpc.cast(), DPC_TAG);
     }
 }
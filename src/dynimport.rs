@@ -0,0 +1,153 @@
+//! this module resolves the kernel exports at runtime with `MmGetSystemRoutineAddress`
+//!
+//! a routine that is not exported by every supported system must not be linked statically, otherwise the driver
+//! fails to load on the older systems, `kernel_import!` declares such a routine as a `KernelImport` which is
+//! resolved on the first use and cached
+//!
+//! # Example
+//! ```
+//! kernel_import! {
+//!     /// Windows 10 2004+
+//!     pub fn ExAllocatePool2(flags: u64, size: SIZE_T, tag: ULONG) -> PVOID;
+//! }
+//!
+//! let buffer = match ExAllocatePool2.get() {
+//!     Some(allocate) => unsafe { allocate(POOL_FLAG_NON_PAGED, size, TAG) },
+//!     None => unsafe { ExAllocatePoolWithTag(NonPagedPoolNx, size, TAG) },
+//! };
+//! ```
+use core::{
+    ffi::c_void,
+    marker::PhantomData,
+    mem,
+    ptr::{self, NonNull},
+    sync::atomic::{AtomicPtr, Ordering},
+};
+
+use wdk_sys::{
+    PASSIVE_LEVEL, PVOID, SIZE_T, ULONG, UNICODE_STRING,
+    ntddk::{KeGetCurrentIrql, MmGetSystemRoutineAddress},
+};
+
+/// the longest routine name can be resolved
+const MAX_NAME_LEN: usize = 128;
+
+/// the cached result of a routine that is not exported
+const MISSING: *mut c_void = 1 as _;
+
+/// resolve an export of ntoskrnl or hal, it must be called at PASSIVE_LEVEL
+///
+/// the name is converted on the stack, nothing is allocated
+pub fn resolve(name: &str) -> Option<NonNull<c_void>> {
+    let mut buffer = [0u16; MAX_NAME_LEN];
+    let mut len = 0;
+
+    for c in name.encode_utf16() {
+        *buffer.get_mut(len)? = c;
+        len += 1;
+    }
+
+    let mut name = UNICODE_STRING {
+        Length: (len * 2) as _,
+        MaximumLength: (len * 2) as _,
+        Buffer: buffer.as_mut_ptr(),
+    };
+
+    NonNull::new(unsafe { MmGetSystemRoutineAddress(&mut name) })
+}
+
+/// A kernel routine resolved on the first use, it is declared by `kernel_import!`
+pub struct KernelImport<F> {
+    name: &'static str,
+    address: AtomicPtr<c_void>,
+    _phantom: PhantomData<F>,
+}
+
+impl<F: Copy> KernelImport<F> {
+    /// prefer `kernel_import!`, which upholds the requirement below
+    ///
+    /// # Safety
+    /// `F` must be an `unsafe extern "C" fn` type, `get` turns the address of the routine into an `F`
+    pub const unsafe fn new(name: &'static str) -> Self {
+        Self {
+            name,
+            address: AtomicPtr::new(ptr::null_mut()),
+            _phantom: PhantomData,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    /// the address of the routine, `None` if it is not exported
+    ///
+    /// the routine is resolved at PASSIVE_LEVEL only, at a higher IRQL `None` is returned until it has been resolved,
+    /// so it is a good idea to resolve it once in DriverEntry
+    pub fn address(&self) -> Option<NonNull<c_void>> {
+        let mut address = self.address.load(Ordering::Acquire);
+
+        if address.is_null() {
+            if unsafe { KeGetCurrentIrql() } != PASSIVE_LEVEL as u8 {
+                return None;
+            }
+
+            // resolving twice on a race yields the same result
+            address = resolve(self.name).map_or(MISSING, |address| address.as_ptr());
+
+            self.address.store(address, Ordering::Release);
+        }
+
+        if address == MISSING {
+            None
+        } else {
+            NonNull::new(address)
+        }
+    }
+
+    /// the routine, `None` if it is not exported
+    pub fn get(&self) -> Option<F> {
+        const { assert!(mem::size_of::<F>() == mem::size_of::<PVOID>()) };
+
+        self.address()
+            .map(|address| unsafe { mem::transmute_copy::<PVOID, F>(&address.as_ptr()) })
+    }
+
+    pub fn is_available(&self) -> bool {
+        self.address().is_some()
+    }
+}
+
+unsafe impl<F> Sync for KernelImport<F> {}
+unsafe impl<F> Send for KernelImport<F> {}
+
+/// declare the kernel routines resolved at runtime, each one is a `static KernelImport` named after the routine
+///
+/// # Example
+/// ```
+/// kernel_import! {
+///     pub fn PsGetProcessImageFileName(process: PEPROCESS) -> *const u8;
+///     fn KeQueryActiveProcessorCountEx(group: u16) -> u32;
+/// }
+///
+/// if let Some(routine) = PsGetProcessImageFileName.get() {
+///     let name = unsafe { routine(process) };
+/// }
+/// ```
+#[macro_export]
+macro_rules! kernel_import {
+    ($($(#[$meta:meta])* $vis:vis fn $name:ident($($arg:ident: $ty:ty),* $(,)?) $(-> $ret:ty)?;)+) => {
+        $(
+            $(#[$meta])*
+            #[allow(non_upper_case_globals)]
+            $vis static $name: $crate::dynimport::KernelImport<unsafe extern "C" fn($($ty),*) $(-> $ret)?> =
+                // `F` is an `unsafe extern "C" fn` by construction
+                unsafe { $crate::dynimport::KernelImport::new(stringify!($name)) };
+        )+
+    };
+}
+
+kernel_import! {
+    /// Windows 10 2004+, the memory is zeroed unless POOL_FLAG_UNINITIALIZED is given
+    pub fn ExAllocatePool2(flags: u64, size: SIZE_T, tag: ULONG) -> PVOID;
+}
@@ -0,0 +1,477 @@
+//! this module provides an ETW provider writing TraceLogging-style self-describing events
+//!
+//! the event layout(field names and types) is sent along with every event, so the events can be decoded by WPA or
+//! `tracefmt` without a manifest
+//!
+//! # Example
+//! ```
+//! // {8a1f3e2b-6f0c-4c5e-9d1a-2b3c4d5e6f70}
+//! const PROVIDER_ID: GUID = GUID {
+//!     Data1: 0x8a1f3e2b,
+//!     Data2: 0x6f0c,
+//!     Data3: 0x4c5e,
+//!     Data4: [0x9d, 0x1a, 0x2b, 0x3c, 0x4d, 0x5e, 0x6f, 0x70],
+//! };
+//!
+//! static PROVIDER: OnceLock<Provider> = OnceLock::new();
+//!
+//! let provider = PROVIDER.get_or_try_init(|| Provider::register("MyDriver", &PROVIDER_ID))?;
+//!
+//! provider.write_event(
+//!     "ProcessCreated",
+//!     EventLevel::Info,
+//!     0x1,
+//!     &[EventField::u32("Pid", pid), EventField::str("Image", image_name)],
+//! );
+//!
+//! // route the `trace_xxx!` macros to ETW
+//! trace::set_sink(provider);
+//! ```
+use core::{
+    mem, ptr,
+    sync::atomic::{AtomicBool, AtomicU8, AtomicU64, Ordering},
+};
+
+use alloc::{boxed::Box, vec::Vec};
+use wdk_sys::{
+    EVENT_DATA_DESCRIPTOR, EVENT_DESCRIPTOR, GUID, LPCGUID, PEVENT_FILTER_DESCRIPTOR, PVOID,
+    REGHANDLE, STATUS_INVALID_PARAMETER, UCHAR, ULONG, ULONGLONG,
+    ntddk::{EtwRegister, EtwUnregister, EtwWrite},
+};
+
+use crate::{
+    ntstatus::{NtError, cvt},
+    trace::{self, Sink},
+};
+
+/// the maximum number of fields of an event
+pub const MAX_FIELDS: usize = 16;
+
+/// the maximum size of the metadata(event name and field names) of an event
+const MAX_METADATA: usize = 512;
+
+/// EVENT_DATA_DESCRIPTOR_TYPE_EVENT_METADATA
+const DESCRIPTOR_TYPE_EVENT_METADATA: u8 = 1;
+/// EVENT_DATA_DESCRIPTOR_TYPE_PROVIDER_METADATA
+const DESCRIPTOR_TYPE_PROVIDER_METADATA: u8 = 2;
+
+/// the channel reserved for TraceLogging events
+const TRACELOGGING_CHANNEL: u8 = 11;
+
+/// EVENT_CONTROL_CODE_ENABLE_PROVIDER
+const CONTROL_CODE_ENABLE: ULONG = 1;
+/// EVENT_CONTROL_CODE_DISABLE_PROVIDER
+const CONTROL_CODE_DISABLE: ULONG = 0;
+
+// TraceLogging in/out types
+const IN_INT32: u8 = 7;
+const IN_UINT32: u8 = 8;
+const IN_INT64: u8 = 9;
+const IN_UINT64: u8 = 10;
+const IN_BOOL32: u8 = 13;
+const IN_BINARY: u8 = 14;
+const IN_GUID: u8 = 15;
+const IN_HEXINT64: u8 = 21;
+const IN_COUNTEDANSISTRING: u8 = 23;
+const OUT_UTF8: u8 = 35;
+const CHAIN_FLAG: u8 = 0x80;
+
+#[repr(u8)]
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub enum EventLevel {
+    Critical = 1,
+    Error = 2,
+    Warning = 3,
+    Info = 4,
+    Verbose = 5,
+}
+
+impl From<trace::Level> for EventLevel {
+    fn from(level: trace::Level) -> Self {
+        match level {
+            trace::Level::Error => EventLevel::Error,
+            trace::Level::Warn => EventLevel::Warning,
+            trace::Level::Info => EventLevel::Info,
+            trace::Level::Debug => EventLevel::Verbose,
+        }
+    }
+}
+
+enum FieldValue<'a> {
+    I32(i32),
+    U32(u32),
+    I64(i64),
+    U64(u64),
+    Bool(i32),
+    Pointer(u64),
+    Guid(GUID),
+    /// byte length and the utf-8 bytes
+    Str(u16, &'a str),
+    /// byte length and the bytes
+    Binary(u16, &'a [u8]),
+}
+
+/// A named value of an event
+pub struct EventField<'a> {
+    name: &'a str,
+    value: FieldValue<'a>,
+}
+
+impl<'a> EventField<'a> {
+    pub fn i32(name: &'a str, value: i32) -> Self {
+        Self {
+            name,
+            value: FieldValue::I32(value),
+        }
+    }
+
+    pub fn u32(name: &'a str, value: u32) -> Self {
+        Self {
+            name,
+            value: FieldValue::U32(value),
+        }
+    }
+
+    pub fn i64(name: &'a str, value: i64) -> Self {
+        Self {
+            name,
+            value: FieldValue::I64(value),
+        }
+    }
+
+    pub fn u64(name: &'a str, value: u64) -> Self {
+        Self {
+            name,
+            value: FieldValue::U64(value),
+        }
+    }
+
+    pub fn bool(name: &'a str, value: bool) -> Self {
+        Self {
+            name,
+            value: FieldValue::Bool(value as i32),
+        }
+    }
+
+    /// a pointer or handle, displayed in hex
+    pub fn pointer<T>(name: &'a str, value: *const T) -> Self {
+        Self {
+            name,
+            value: FieldValue::Pointer(value as u64),
+        }
+    }
+
+    pub fn guid(name: &'a str, value: &GUID) -> Self {
+        Self {
+            name,
+            value: FieldValue::Guid(*value),
+        }
+    }
+
+    /// a string, truncated to 65535 bytes
+    pub fn str(name: &'a str, value: &'a str) -> Self {
+        let len = value.len().min(u16::MAX as usize);
+
+        Self {
+            name,
+            value: FieldValue::Str(len as u16, value),
+        }
+    }
+
+    /// a byte array, truncated to 65535 bytes
+    pub fn binary(name: &'a str, value: &'a [u8]) -> Self {
+        let len = value.len().min(u16::MAX as usize);
+
+        Self {
+            name,
+            value: FieldValue::Binary(len as u16, value),
+        }
+    }
+}
+
+/// a fixed-size writer used to build the metadata blobs on the stack
+struct MetadataWriter<const N: usize> {
+    buffer: [u8; N],
+    len: usize,
+}
+
+impl<const N: usize> MetadataWriter<N> {
+    fn new() -> Self {
+        Self {
+            buffer: [0; N],
+            len: 0,
+        }
+    }
+
+    fn push(&mut self, bytes: &[u8]) -> bool {
+        if self.len + bytes.len() > N {
+            return false;
+        }
+
+        self.buffer[self.len..self.len + bytes.len()].copy_from_slice(bytes);
+        self.len += bytes.len();
+
+        true
+    }
+
+    /// push a nul terminated name, embedded nul characters are not allowed
+    fn push_name(&mut self, name: &str) -> bool {
+        !name.as_bytes().contains(&0) && self.push(name.as_bytes()) && self.push(&[0])
+    }
+
+    /// write the total size into the leading u16
+    fn finish(&mut self) -> &[u8] {
+        let size = (self.len as u16).to_le_bytes();
+
+        self.buffer[..2].copy_from_slice(&size);
+
+        &self.buffer[..self.len]
+    }
+}
+
+fn data_descriptor(ptr: *const u8, size: usize, r#type: u8) -> EVENT_DATA_DESCRIPTOR {
+    let mut descriptor: EVENT_DATA_DESCRIPTOR = unsafe { mem::zeroed() };
+
+    descriptor.Ptr = ptr as ULONGLONG;
+    descriptor.Size = size as ULONG;
+    descriptor.__bindgen_anon_1.__bindgen_anon_1.Type = r#type;
+
+    descriptor
+}
+
+/// the state updated by the enable callback, boxed so it has a stable address
+struct ProviderState {
+    enabled: AtomicBool,
+    level: AtomicU8,
+    keywords: AtomicU64,
+}
+
+/// A registered ETW provider, it is unregistered on drop
+pub struct Provider {
+    handle: REGHANDLE,
+    state: Box<ProviderState>,
+    /// the provider traits: u16 size + nul terminated provider name
+    traits: Vec<u8>,
+}
+
+impl Provider {
+    /// register a provider with the `name` and the provider `id`
+    pub fn register(name: &str, id: &GUID) -> Result<Self, NtError> {
+        if name.as_bytes().contains(&0) {
+            return Err(NtError::new(STATUS_INVALID_PARAMETER));
+        }
+
+        let mut traits = Vec::with_capacity(name.len() + 3);
+
+        traits.extend_from_slice(&((name.len() + 3) as u16).to_le_bytes());
+        traits.extend_from_slice(name.as_bytes());
+        traits.push(0);
+
+        let state = Box::new(ProviderState {
+            enabled: AtomicBool::new(false),
+            level: AtomicU8::new(0),
+            keywords: AtomicU64::new(0),
+        });
+
+        let mut handle: REGHANDLE = 0;
+
+        cvt(unsafe {
+            EtwRegister(
+                id,
+                Some(enable_callback_stub),
+                state.as_ref() as *const ProviderState as _,
+                &mut handle,
+            )
+        })?;
+
+        Ok(Self {
+            handle,
+            state,
+            traits,
+        })
+    }
+
+    /// returns true if any session is listening for events of `level` and `keyword`
+    pub fn is_enabled(&self, level: EventLevel, keyword: u64) -> bool {
+        let state = &self.state;
+
+        if !state.enabled.load(Ordering::Relaxed) {
+            return false;
+        }
+
+        let max_level = state.level.load(Ordering::Relaxed);
+        let keywords = state.keywords.load(Ordering::Relaxed);
+
+        (max_level == 0 || level as u8 <= max_level)
+            && (keyword == 0 || keywords == 0 || keywords & keyword != 0)
+    }
+
+    /// write a self-describing event
+    ///
+    /// returns an error if the event can not be described, for example, too many fields or names too long
+    ///
+    /// # Note
+    /// it can be called at any IRQL as long as all the data is resident in memory
+    pub fn write_event(
+        &self,
+        name: &str,
+        level: EventLevel,
+        keyword: u64,
+        fields: &[EventField],
+    ) -> Result<(), NtError> {
+        if !self.is_enabled(level, keyword) {
+            return Ok(());
+        }
+
+        if fields.len() > MAX_FIELDS {
+            return Err(NtError::new(STATUS_INVALID_PARAMETER));
+        }
+
+        let mut metadata = MetadataWriter::<MAX_METADATA>::new();
+
+        // size placeholder and no tags
+        let mut described = metadata.push(&[0, 0, 0]) && metadata.push_name(name);
+
+        // provider traits, event metadata, and at most 2 descriptors per field
+        let mut descriptors: [EVENT_DATA_DESCRIPTOR; 2 + 2 * MAX_FIELDS] = unsafe { mem::zeroed() };
+        let mut count = 2;
+
+        for field in fields {
+            described = described && metadata.push_name(field.name);
+
+            let mut push = |ptr: *const u8, size: usize| {
+                descriptors[count] = data_descriptor(ptr, size, 0);
+                count += 1;
+            };
+
+            described = described
+                && match &field.value {
+                    FieldValue::I32(v) => {
+                        push(v as *const _ as _, 4);
+                        metadata.push(&[IN_INT32])
+                    }
+                    FieldValue::U32(v) => {
+                        push(v as *const _ as _, 4);
+                        metadata.push(&[IN_UINT32])
+                    }
+                    FieldValue::I64(v) => {
+                        push(v as *const _ as _, 8);
+                        metadata.push(&[IN_INT64])
+                    }
+                    FieldValue::U64(v) => {
+                        push(v as *const _ as _, 8);
+                        metadata.push(&[IN_UINT64])
+                    }
+                    FieldValue::Bool(v) => {
+                        push(v as *const _ as _, 4);
+                        metadata.push(&[IN_BOOL32])
+                    }
+                    FieldValue::Pointer(v) => {
+                        push(v as *const _ as _, 8);
+                        metadata.push(&[IN_HEXINT64])
+                    }
+                    FieldValue::Guid(v) => {
+                        push(v as *const _ as _, mem::size_of::<GUID>());
+                        metadata.push(&[IN_GUID])
+                    }
+                    FieldValue::Str(len, s) => {
+                        push(len as *const _ as _, 2);
+                        push(s.as_ptr(), *len as _);
+                        metadata.push(&[IN_COUNTEDANSISTRING | CHAIN_FLAG, OUT_UTF8])
+                    }
+                    FieldValue::Binary(len, bytes) => {
+                        push(len as *const _ as _, 2);
+                        push(bytes.as_ptr(), *len as _);
+                        metadata.push(&[IN_BINARY])
+                    }
+                };
+        }
+
+        if !described {
+            return Err(NtError::new(STATUS_INVALID_PARAMETER));
+        }
+
+        let metadata = metadata.finish();
+
+        descriptors[0] = data_descriptor(
+            self.traits.as_ptr(),
+            self.traits.len(),
+            DESCRIPTOR_TYPE_PROVIDER_METADATA,
+        );
+        descriptors[1] = data_descriptor(
+            metadata.as_ptr(),
+            metadata.len(),
+            DESCRIPTOR_TYPE_EVENT_METADATA,
+        );
+
+        let descriptor = EVENT_DESCRIPTOR {
+            Id: 0,
+            Version: 0,
+            Channel: TRACELOGGING_CHANNEL,
+            Level: level as UCHAR,
+            Opcode: 0,
+            Task: 0,
+            Keyword: keyword,
+        };
+
+        cvt(unsafe {
+            EtwWrite(
+                self.handle,
+                &descriptor,
+                ptr::null(),
+                count as ULONG,
+                descriptors.as_mut_ptr(),
+            )
+        })
+    }
+}
+
+impl Drop for Provider {
+    fn drop(&mut self) {
+        unsafe {
+            let _ = EtwUnregister(self.handle);
+        }
+    }
+}
+
+unsafe impl Send for Provider {}
+unsafe impl Sync for Provider {}
+
+/// a `Provider` can be installed as the sink of the `trace` module, messages are written as "Trace" events
+impl Sink for Provider {
+    fn write(&self, level: trace::Level, component: u32, message: &str) {
+        let _ = self.write_event(
+            "Trace",
+            level.into(),
+            0,
+            &[
+                EventField::u32("Component", component),
+                EventField::str("Message", message),
+            ],
+        );
+    }
+}
+
+extern "C" fn enable_callback_stub(
+    _source_id: LPCGUID,
+    control_code: ULONG,
+    level: UCHAR,
+    match_any_keyword: ULONGLONG,
+    _match_all_keyword: ULONGLONG,
+    _filter_data: PEVENT_FILTER_DESCRIPTOR,
+    context: PVOID,
+) {
+    let state = unsafe { &*(context as *const ProviderState) };
+
+    match control_code {
+        CONTROL_CODE_ENABLE => {
+            state.level.store(level, Ordering::Relaxed);
+            state.keywords.store(match_any_keyword, Ordering::Relaxed);
+            state.enabled.store(true, Ordering::Release);
+        }
+        CONTROL_CODE_DISABLE => {
+            state.enabled.store(false, Ordering::Release);
+        }
+        _ => {}
+    }
+}
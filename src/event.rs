@@ -1,19 +1,45 @@
-use core::mem;
+use core::{
+    mem::{self, ManuallyDrop},
+    ops::Deref,
+    ptr,
+    time::Duration,
+};
 use wdk_sys::{
-    _EVENT_TYPE::{NotificationEvent, SynchronizationEvent},
-    _KEVENT,
+    _EVENT_TYPE, _KEVENT,
+    _MODE::KernelMode,
     _POOL_TYPE::NonPagedPoolNx,
-    IO_NO_INCREMENT, PKEVENT, STATUS_INSUFFICIENT_RESOURCES,
+    ACCESS_MASK, BOOLEAN, EVENT_ALL_ACCESS, HANDLE, IO_NO_INCREMENT, KPRIORITY, LONG, NTSTATUS,
+    OBJ_CASE_INSENSITIVE, OBJ_KERNEL_HANDLE, PHANDLE, PKEVENT, POBJECT_ATTRIBUTES, POBJECT_TYPE,
+    PRKEVENT, PVOID, STATUS_INSUFFICIENT_RESOURCES,
     ntddk::{
-        ExFreePoolWithTag, KeClearEvent, KeInitializeEvent, KeReadStateEvent, KeResetEvent,
-        KeSetEvent,
+        KeClearEvent, KeInitializeEvent, KeReadStateEvent, KeResetEvent, KeSetEvent,
+        ObReferenceObjectByHandle, ObfDereferenceObject,
     },
 };
 
 use crate::{
-    kobject::Dispatchable, ntstatus::NtError, raw::AsRawObject, utils::ex_allocate_pool_zero,
+    client::ZwCreateEvent,
+    handle::ObjectHandle,
+    initialize_object_attributes,
+    kobject::{Dispatchable, WaitResult},
+    ntstatus::{NtError, cvt},
+    raw::AsRawObject,
+    sd::SecurityDescriptor,
+    utils::{self, ex_allocate_pool_zero, ex_free_pool},
 };
 
+unsafe extern "C" {
+    pub fn KePulseEvent(Event: PRKEVENT, Increment: KPRIORITY, Wait: BOOLEAN) -> LONG;
+
+    pub fn ZwOpenEvent(
+        EventHandle: PHANDLE,
+        DesiredAccess: ACCESS_MASK,
+        ObjectAttributes: POBJECT_ATTRIBUTES,
+    ) -> NTSTATUS;
+
+    pub static ExEventObjectType: *mut POBJECT_TYPE;
+}
+
 /// A kernel mode synchronous Event
 #[repr(transparent)]
 pub struct Event(PKEVENT);
@@ -59,16 +85,12 @@ impl Event {
     /// allocate a new event object on the kernel heap
     pub fn new(prop: EventProperty) -> Result<Self, NtError> {
         let layout =
-            ex_allocate_pool_zero(NonPagedPoolNx, mem::size_of::<_KEVENT>() as _, EVENT_TAG);
-
-        if layout.is_null() {
-            return Err(NtError::new(STATUS_INSUFFICIENT_RESOURCES));
-        }
+            ex_allocate_pool_zero(NonPagedPoolNx, mem::size_of::<_KEVENT>() as _, EVENT_TAG)?;
 
         let r#type = if prop.auto_reset {
-            SynchronizationEvent
+            _EVENT_TYPE::SynchronizationEvent
         } else {
-            NotificationEvent
+            _EVENT_TYPE::NotificationEvent
         };
 
         unsafe { KeInitializeEvent(layout.cast(), r#type, prop.initial_state as u8) };
@@ -108,6 +130,26 @@ impl Event {
     pub fn get_state(&self) -> bool {
         unsafe { KeReadStateEvent(self.0) != 0 }
     }
+
+    /// signal the event and reset it at once, the waiters at this moment are satisfied
+    ///
+    /// returns the previous state
+    #[inline]
+    pub fn pulse(&self) -> bool {
+        unsafe { KePulseEvent(self.0, IO_NO_INCREMENT as _, 0) != 0 }
+    }
+
+    /// wait for the event with a timeout, use `WaitResult::status` to tell a signal from a timeout
+    #[inline]
+    pub fn wait_timeout(&self, timeout: Duration) -> WaitResult {
+        self.wait_for(timeout, false)
+    }
+
+    /// the same as `wait_timeout` but the wait can be interrupted by an alert or a user APC
+    #[inline]
+    pub fn wait_timeout_alertable(&self, timeout: Duration) -> WaitResult {
+        self.wait_for(timeout, true)
+    }
 }
 
 impl AsRawObject for Event {
@@ -122,10 +164,213 @@ impl Dispatchable for Event {}
 impl Drop for Event {
     fn drop(&mut self) {
         unsafe {
-            ExFreePoolWithTag(self.0.cast(), EVENT_TAG);
+            ex_free_pool(self.0.cast(), EVENT_TAG);
         }
     }
 }
 
 unsafe impl Send for Event {}
-unsafe impl Sync for Event {}
\ No newline at end of file
+unsafe impl Sync for Event {}
+
+/// An event which stays signaled until it is cleared, all the waiters are released when it is set
+///
+/// # Example
+/// ```
+/// let stop = NotificationEvent::new(false)?;
+///
+/// // in worker threads
+/// while stop.wait_timeout(Duration::from_millis(100)).timed_out() {
+///     poll();
+/// }
+///
+/// // all the workers are released
+/// stop.set();
+/// ```
+#[repr(transparent)]
+pub struct NotificationEvent(Event);
+
+impl NotificationEvent {
+    pub fn new(initial_state: bool) -> Result<Self, NtError> {
+        EventProperty::new()
+            .auto_reset(false)
+            .initial_state(initial_state)
+            .new_event()
+            .map(Self)
+    }
+}
+
+/// An auto-reset event, setting it releases a single waiter and the event is reset automatically
+#[repr(transparent)]
+pub struct SynchronizationEvent(Event);
+
+impl SynchronizationEvent {
+    pub fn new(initial_state: bool) -> Result<Self, NtError> {
+        EventProperty::new()
+            .auto_reset(true)
+            .initial_state(initial_state)
+            .new_event()
+            .map(Self)
+    }
+}
+
+macro_rules! typed_event {
+    ($($ty:ident),+) => {
+        $(
+            impl Deref for $ty {
+                type Target = Event;
+                fn deref(&self) -> &Self::Target {
+                    &self.0
+                }
+            }
+
+            impl AsRawObject for $ty {
+                type Target = _KEVENT;
+                fn as_raw(&self) -> *mut Self::Target {
+                    self.0.0
+                }
+            }
+
+            impl Dispatchable for $ty {}
+
+            impl From<$ty> for Event {
+                fn from(value: $ty) -> Self {
+                    value.0
+                }
+            }
+        )+
+    };
+}
+
+typed_event!(NotificationEvent, SynchronizationEvent);
+
+/// A named event shared with other drivers or user mode, e.g. "\\BaseNamedObjects\\MyEvent"
+///
+/// it dereferences to `Event`, the handle and the reference are released on drop
+///
+/// # Example
+/// ```
+/// let sd = SecurityDescriptor::from_sddl("D:P(A;;GA;;;SY)(A;;GA;;;BA)")?;
+///
+/// let event = NamedEvent::create("\\BaseNamedObjects\\MyEvent", EventProperty::new(), Some(&sd))?;
+/// event.set();
+/// ```
+pub struct NamedEvent {
+    event: ManuallyDrop<Event>,
+    handle: ObjectHandle,
+}
+
+impl NamedEvent {
+    /// create a named event, it fails with STATUS_OBJECT_NAME_COLLISION if the name exists
+    ///
+    /// the default DACL of the namespace is used if `sd` is `None`
+    pub fn create(
+        name: &str,
+        prop: EventProperty,
+        sd: Option<&SecurityDescriptor>,
+    ) -> Result<Self, NtError> {
+        let mut name =
+            utils::utf16_from_str(name).ok_or(NtError::new(STATUS_INSUFFICIENT_RESOURCES))?;
+
+        let mut attr = initialize_object_attributes!(
+            name.as_mut(),
+            OBJ_CASE_INSENSITIVE | OBJ_KERNEL_HANDLE,
+            ptr::null_mut(),
+            sd.map_or(ptr::null_mut(), |sd| sd.as_ptr())
+        );
+
+        let r#type = if prop.auto_reset {
+            _EVENT_TYPE::SynchronizationEvent
+        } else {
+            _EVENT_TYPE::NotificationEvent
+        };
+
+        let mut handle: HANDLE = ptr::null_mut();
+
+        cvt(unsafe {
+            ZwCreateEvent(
+                &mut handle,
+                EVENT_ALL_ACCESS,
+                &mut attr,
+                r#type,
+                prop.initial_state as u8,
+            )
+        })?;
+
+        Self::reference(ObjectHandle::new(handle))
+    }
+
+    /// open an existing named event
+    pub fn open(name: &str) -> Result<Self, NtError> {
+        let mut name =
+            utils::utf16_from_str(name).ok_or(NtError::new(STATUS_INSUFFICIENT_RESOURCES))?;
+
+        let mut attr = initialize_object_attributes!(
+            name.as_mut(),
+            OBJ_CASE_INSENSITIVE | OBJ_KERNEL_HANDLE,
+            ptr::null_mut(),
+            ptr::null_mut()
+        );
+
+        let mut handle: HANDLE = ptr::null_mut();
+
+        cvt(unsafe { ZwOpenEvent(&mut handle, EVENT_ALL_ACCESS, &mut attr) })?;
+
+        Self::reference(ObjectHandle::new(handle))
+    }
+
+    fn reference(handle: ObjectHandle) -> Result<Self, NtError> {
+        let mut object: PVOID = ptr::null_mut();
+
+        cvt(unsafe {
+            ObReferenceObjectByHandle(
+                handle.get(),
+                EVENT_ALL_ACCESS,
+                *ExEventObjectType,
+                KernelMode as _,
+                &mut object,
+                ptr::null_mut(),
+            )
+        })?;
+
+        Ok(Self {
+            event: ManuallyDrop::new(Event(object.cast())),
+            handle,
+        })
+    }
+
+    /// the kernel handle of the event
+    pub fn handle(&self) -> HANDLE {
+        self.handle.get()
+    }
+
+    /// replace the DACL of the event, it must be called at PASSIVE_LEVEL
+    pub fn set_security(&self, sd: &SecurityDescriptor) -> Result<(), NtError> {
+        sd.apply_to_object(self.event.0.cast())
+    }
+}
+
+impl Deref for NamedEvent {
+    type Target = Event;
+    fn deref(&self) -> &Self::Target {
+        &self.event
+    }
+}
+
+impl AsRawObject for NamedEvent {
+    type Target = _KEVENT;
+    fn as_raw(&self) -> *mut Self::Target {
+        self.event.0
+    }
+}
+
+impl Dispatchable for NamedEvent {}
+
+impl Drop for NamedEvent {
+    fn drop(&mut self) {
+        // the event is owned by the object manager, it is not freed by `Event::drop`
+        unsafe { ObfDereferenceObject(self.event.0.cast()) };
+    }
+}
+
+unsafe impl Send for NamedEvent {}
+unsafe impl Sync for NamedEvent {}
@@ -0,0 +1,209 @@
+//! this module provides a typed publish/subscribe bus, so the subsystems of a driver can exchange events without
+//! sharing their state
+//!
+//! a handler is registered for an event type with `subscribe` and stays registered while the returned
+//! `Subscription` lives, `post_sync` calls the handlers of the event type at once, `post` hands the event over to
+//! the worker pool set up by `init` and returns
+//!
+//! the events of the same type posted by `post` are delivered in order, the events of different types are
+//! delivered in parallel
+//!
+//! # Example
+//! ```
+//! struct ProcessCreated {
+//!     pid: usize,
+//! }
+//!
+//! impl Event for ProcessCreated {}
+//!
+//! // in DriverEntry
+//! events::init(device, 4)?;
+//!
+//! let subscription = events::subscribe(|e: &ProcessCreated| scan(e.pid))?;
+//!
+//! // in a process notify routine
+//! let _ = events::post(ProcessCreated { pid });
+//!
+//! // in DriverUnload, at PASSIVE_LEVEL
+//! drop(subscription);
+//! events::shutdown();
+//! ```
+use core::{
+    any::{Any, TypeId},
+    cell::UnsafeCell,
+    mem,
+};
+
+use alloc::{boxed::Box, vec::Vec};
+use wdk_sys::{
+    EX_RUNDOWN_REF, PDEVICE_OBJECT, STATUS_DEVICE_NOT_READY, STATUS_INSUFFICIENT_RESOURCES,
+    ntddk::{
+        ExAcquireRundownProtection, ExInitializeRundownProtection, ExReleaseRundownProtection,
+        ExWaitForRundownProtectionRelease,
+    },
+};
+
+use crate::{
+    arc::KArc, mutex::StaticSpinLocked, ntstatus::NtError, utils::try_box,
+    workitem::OrderedWorkQueue,
+};
+
+/// A type which can be posted on the bus, the handlers borrow it
+pub trait Event: Send + Sync + 'static {}
+
+type Handler = Box<dyn Fn(&dyn Any) + Send + Sync>;
+
+struct Subscriber {
+    event: TypeId,
+    handler: Handler,
+    /// held by every running handler, the subscription waits for its release before the handler is freed
+    rundown: UnsafeCell<EX_RUNDOWN_REF>,
+}
+
+unsafe impl Send for Subscriber {}
+unsafe impl Sync for Subscriber {}
+
+impl Subscriber {
+    fn call(&self, event: &dyn Any) {
+        if unsafe { ExAcquireRundownProtection(self.rundown.get()) } == 0 {
+            // the subscription is being dropped
+            return;
+        }
+
+        (self.handler)(event);
+
+        unsafe { ExReleaseRundownProtection(self.rundown.get()) };
+    }
+}
+
+static SUBSCRIBERS: StaticSpinLocked<Vec<KArc<Subscriber>>> = StaticSpinLocked::new(Vec::new());
+
+/// the worker pool of `post`, the events are keyed by their type
+static QUEUE: StaticSpinLocked<Option<KArc<OrderedWorkQueue<TypeId>>>> =
+    StaticSpinLocked::new(None);
+
+/// set up the worker pool of `post`, the work items are allocated for `device`
+///
+/// at most `max_in_flight` event types are delivered at once, calling it again replaces the pool, the events
+/// already posted are still delivered by the old one
+pub fn init(device: PDEVICE_OBJECT, max_in_flight: usize) -> Result<(), NtError> {
+    let queue = KArc::new(OrderedWorkQueue::new(device, max_in_flight)?)?;
+
+    let old = QUEUE.lock().replace(queue);
+
+    drop(old);
+
+    Ok(())
+}
+
+/// tear down the worker pool and wait until the posted events are delivered, it must be called at PASSIVE_LEVEL
+///
+/// `post` fails with STATUS_DEVICE_NOT_READY afterwards
+pub fn shutdown() {
+    crate::assert_irql!(== PASSIVE_LEVEL);
+
+    let queue = QUEUE.lock().take();
+
+    if let Some(queue) = queue {
+        queue.wait_idle();
+    }
+}
+
+/// call `handler` for every event of type `T` until the returned subscription is dropped
+///
+/// it can be called at IRQL <= DISPATCH_LEVEL, it fails with STATUS_INSUFFICIENT_RESOURCES if the subscription
+/// can not be allocated
+pub fn subscribe<T, F>(handler: F) -> Result<Subscription, NtError>
+where
+    T: Event,
+    F: Fn(&T) + Send + Sync + 'static,
+{
+    let handler: Handler = try_box(move |event: &dyn Any| {
+        if let Some(event) = event.downcast_ref::<T>() {
+            handler(event);
+        }
+    })?;
+
+    let subscriber = KArc::new(Subscriber {
+        event: TypeId::of::<T>(),
+        handler,
+        rundown: UnsafeCell::new(unsafe { mem::zeroed() }),
+    })?;
+
+    unsafe { ExInitializeRundownProtection(subscriber.rundown.get()) };
+
+    {
+        let mut subscribers = SUBSCRIBERS.lock();
+
+        subscribers
+            .try_reserve(1)
+            .map_err(|_| NtError::new(STATUS_INSUFFICIENT_RESOURCES))?;
+        subscribers.push(subscriber.clone());
+    }
+
+    Ok(Subscription { subscriber })
+}
+
+/// the subscribers of `event` at the moment, they are called without the registry lock
+fn subscribers_of(event: TypeId) -> Result<Vec<KArc<Subscriber>>, NtError> {
+    let subscribers = SUBSCRIBERS.lock();
+
+    let mut matched = Vec::new();
+
+    matched
+        .try_reserve(subscribers.len())
+        .map_err(|_| NtError::new(STATUS_INSUFFICIENT_RESOURCES))?;
+
+    matched.extend(subscribers.iter().filter(|s| s.event == event).cloned());
+
+    Ok(matched)
+}
+
+/// call the handlers of `T` on the current thread, returns the number of the handlers called
+///
+/// the handlers run at the IRQL of the caller, which must be <= DISPATCH_LEVEL
+pub fn post_sync<T: Event>(event: &T) -> Result<usize, NtError> {
+    let subscribers = subscribers_of(TypeId::of::<T>())?;
+
+    for subscriber in subscribers.iter() {
+        subscriber.call(event);
+    }
+
+    Ok(subscribers.len())
+}
+
+/// deliver `event` in a system worker thread at PASSIVE_LEVEL, it can be called at IRQL <= DISPATCH_LEVEL
+///
+/// it fails with STATUS_DEVICE_NOT_READY if the pool is not set up by `init`, or STATUS_INSUFFICIENT_RESOURCES if
+/// the event can not be queued
+pub fn post<T: Event>(event: T) -> Result<(), NtError> {
+    let queue = QUEUE
+        .lock()
+        .clone()
+        .ok_or_else(|| NtError::new(STATUS_DEVICE_NOT_READY))?;
+
+    queue.push(TypeId::of::<T>(), move || {
+        let _ = post_sync(&event);
+    })
+}
+
+/// A registered handler, it is unsubscribed when dropped
+///
+/// drop waits until the running calls of the handler return, so it must be dropped at IRQL <= APC_LEVEL and never
+/// from within the handler itself
+pub struct Subscription {
+    subscriber: KArc<Subscriber>,
+}
+
+impl Drop for Subscription {
+    fn drop(&mut self) {
+        {
+            let mut subscribers = SUBSCRIBERS.lock();
+
+            subscribers.retain(|s| !KArc::ptr_eq(s, &self.subscriber));
+        }
+
+        // a post may still hold a reference, but the handler is not called anymore once the wait returns
+        unsafe { ExWaitForRundownProtectionRelease(self.subscriber.rundown.get()) };
+    }
+}
@@ -0,0 +1,582 @@
+//! this module provides a minimal single-threaded executor, it is enabled by the `async` feature
+//!
+//! the tasks are polled on the thread calling `Executor::run` at PASSIVE_LEVEL, when no task is ready the thread
+//! waits for the wake-up event of the executor together with the dispatcher objects awaited by the tasks, so the
+//! executor is its own timer and event reactor
+//!
+//! - `Dispatchable::wait_async` awaits any dispatcher object, e.g. an `Event`, a `Semaphore` or a `Thread`
+//! - `Timer::delay_async` and `oneshot::Receiver` complete from a DPC
+//! - `mpsc::Receiver::recv_async` awaits the next item of a channel
+//!
+//! # Example
+//! ```
+//! let executor = Executor::new()?;
+//!
+//! executor.spawn(async move {
+//!     loop {
+//!         stop.wait_async().await;
+//!
+//!         flush().await;
+//!
+//!         if let Ok(delay) = Timer::delay_async(Duration::from_secs(1)) {
+//!             let _ = delay.await;
+//!         }
+//!     }
+//! })?;
+//!
+//! // run the tasks in a dedicated system thread, `executor.stop()` makes it return
+//! let runner = executor.clone();
+//! let thread = thread::spawn(move || {
+//!     let _ = runner.run();
+//! })?;
+//! ```
+//!
+//! # Note
+//! the wakers must be woken at IRQL <= DISPATCH_LEVEL, at most `MAX_REGISTRATIONS` objects can be awaited at the same
+//! time, the objects beyond it are polled again on every turn of the executor
+use core::{
+    cell::UnsafeCell,
+    future::Future,
+    mem::ManuallyDrop,
+    pin::Pin,
+    ptr,
+    sync::atomic::{AtomicBool, AtomicUsize, Ordering},
+    task::{Context, Poll, RawWaker, RawWakerVTable, Waker},
+    time::Duration,
+};
+
+use alloc::{boxed::Box, vec::Vec};
+use wdk_sys::{
+    _KWAIT_REASON::Executive, _MODE::KernelMode, _POOL_TYPE::NonPagedPoolNx, _WAIT_TYPE::WaitAny,
+    FALSE, KWAIT_BLOCK, MAXIMUM_WAIT_OBJECTS, PKWAIT_BLOCK, PVOID, STATUS_INSUFFICIENT_RESOURCES,
+    STATUS_INVALID_DEVICE_STATE, STATUS_PIPE_BROKEN, ntddk::KeWaitForMultipleObjects,
+};
+
+use crate::{
+    arc::{KArc, KWeak},
+    event::{Event, EventProperty},
+    kobject::{Dispatchable, WaitResult},
+    mutex::StaticSpinLocked,
+    ntstatus::NtError,
+    oneshot,
+    raw::AsRawObject,
+    utils::{KeGetCurrentThread, ex_allocate_pool_zero, ex_free_pool, try_box},
+};
+
+const EXECUTOR_TAG: u32 = u32::from_ne_bytes(*b"cexe");
+
+/// the number of objects the reactor waits for besides the wake-up event
+pub const MAX_REGISTRATIONS: usize = MAXIMUM_WAIT_OBJECTS as usize - 1;
+
+/// the handle of a spawned task, it resolves to STATUS_PIPE_BROKEN if the task is dropped before it completes
+pub type JoinHandle<T> = oneshot::Receiver<T>;
+
+type BoxFuture = Pin<Box<dyn Future<Output = ()> + Send>>;
+
+/// the executors running on the threads, `WaitFuture` finds its reactor here
+static RUNNING: StaticSpinLocked<Vec<(usize, KArc<Shared>)>> = StaticSpinLocked::new(Vec::new());
+
+fn current() -> Option<KArc<Shared>> {
+    let thread = KeGetCurrentThread() as usize;
+
+    RUNNING
+        .lock()
+        .iter()
+        .find(|(t, _)| *t == thread)
+        .map(|(_, shared)| shared.clone())
+}
+
+struct Task {
+    /// only polled by the thread running the executor
+    future: UnsafeCell<Option<BoxFuture>>,
+    /// true while the task is linked in the ready queue
+    queued: AtomicBool,
+    /// the next task in the ready queue, protected by the lock of the queue
+    next: UnsafeCell<*const Task>,
+    shared: KWeak<Shared>,
+}
+
+unsafe impl Send for Task {}
+unsafe impl Sync for Task {}
+
+/// an intrusive queue of the ready tasks, every linked task holds a strong reference
+struct ReadyQueue {
+    head: *const Task,
+    tail: *const Task,
+}
+
+impl ReadyQueue {
+    fn push(&mut self, task: KArc<Task>) {
+        let task = KArc::into_raw(task);
+
+        unsafe { *(*task).next.get() = ptr::null() };
+
+        if self.tail.is_null() {
+            self.head = task;
+        } else {
+            unsafe { *(*self.tail).next.get() = task };
+        }
+
+        self.tail = task;
+    }
+
+    fn pop(&mut self) -> Option<KArc<Task>> {
+        if self.head.is_null() {
+            return None;
+        }
+
+        let task = self.head;
+
+        self.head = unsafe { *(*task).next.get() };
+
+        if self.head.is_null() {
+            self.tail = ptr::null();
+        }
+
+        Some(unsafe { KArc::from_raw(task) })
+    }
+}
+
+unsafe impl Send for ReadyQueue {}
+
+struct Registration {
+    object: PVOID,
+    token: u64,
+    waker: Option<Waker>,
+    fired: bool,
+}
+
+struct Reactor {
+    registrations: Vec<Registration>,
+    next_token: u64,
+}
+
+impl Reactor {
+    fn register(&mut self, object: PVOID, waker: &Waker) -> Option<u64> {
+        if self.registrations.len() >= MAX_REGISTRATIONS
+            || self.registrations.try_reserve(1).is_err()
+        {
+            return None;
+        }
+
+        let token = self.next_token;
+
+        self.next_token += 1;
+        self.registrations.push(Registration {
+            object,
+            token,
+            waker: Some(waker.clone()),
+            fired: false,
+        });
+
+        Some(token)
+    }
+
+    fn position(&self, token: u64) -> Option<usize> {
+        self.registrations.iter().position(|r| r.token == token)
+    }
+
+    fn unregister(&mut self, token: u64) -> Option<Registration> {
+        self.position(token)
+            .map(|index| self.registrations.swap_remove(index))
+    }
+
+    /// mark the registration satisfied and returns its waker
+    fn fire(&mut self, token: u64) -> Option<Waker> {
+        let index = self.position(token)?;
+        let registration = &mut self.registrations[index];
+
+        registration.fired = true;
+        registration.waker.take()
+    }
+}
+
+unsafe impl Send for Reactor {}
+
+struct Shared {
+    ready: StaticSpinLocked<ReadyQueue>,
+    reactor: StaticSpinLocked<Reactor>,
+    /// an auto-reset event, set when a task is scheduled or the executor is stopped
+    wake: Event,
+    wait_blocks: PKWAIT_BLOCK,
+    tasks: AtomicUsize,
+    running: AtomicBool,
+    stopped: AtomicBool,
+}
+
+unsafe impl Send for Shared {}
+unsafe impl Sync for Shared {}
+
+impl Shared {
+    fn schedule(&self, task: KArc<Task>) {
+        self.ready.lock().push(task);
+        self.wake.set();
+    }
+
+    fn poll(&self, task: KArc<Task>) {
+        // a wake up during the poll queues the task again
+        task.queued.store(false, Ordering::Release);
+
+        let waker = waker(&task);
+        let mut cx = Context::from_waker(&waker);
+
+        let slot = unsafe { &mut *task.future.get() };
+
+        let ready = match slot {
+            Some(future) => future.as_mut().poll(&mut cx).is_ready(),
+            None => false,
+        };
+
+        if ready {
+            *slot = None;
+            self.tasks.fetch_sub(1, Ordering::AcqRel);
+        }
+    }
+
+    /// wait for the wake-up event and the registered objects, the waker of a satisfied object is woken
+    fn idle(&self) {
+        let mut objects = [ptr::null_mut(); MAXIMUM_WAIT_OBJECTS as usize];
+        let mut tokens = [0u64; MAX_REGISTRATIONS];
+        let mut count = 1;
+
+        objects[0] = self.wake.as_raw().cast();
+
+        for registration in self.reactor.lock().registrations.iter() {
+            if !registration.fired {
+                objects[count] = registration.object;
+                tokens[count - 1] = registration.token;
+                count += 1;
+            }
+        }
+
+        let status = unsafe {
+            KeWaitForMultipleObjects(
+                count as _,
+                objects.as_mut_ptr(),
+                WaitAny,
+                Executive,
+                KernelMode as _,
+                FALSE as _,
+                ptr::null_mut(),
+                self.wait_blocks,
+            )
+        };
+
+        let index = match WaitResult::new(status).index() {
+            Some(index) if index > 0 => index,
+            _ => return,
+        };
+
+        let waker = self.reactor.lock().fire(tokens[index - 1]);
+
+        if let Some(waker) = waker {
+            waker.wake();
+        }
+    }
+}
+
+impl Drop for Shared {
+    fn drop(&mut self) {
+        // the futures of the queued tasks are dropped here
+        while let Some(task) = self.ready.get_mut().pop() {
+            drop(task);
+        }
+
+        unsafe { ex_free_pool(self.wait_blocks.cast(), EXECUTOR_TAG) };
+    }
+}
+
+fn schedule(task: KArc<Task>) {
+    if task.queued.swap(true, Ordering::AcqRel) {
+        return;
+    }
+
+    if let Some(shared) = task.shared.upgrade() {
+        shared.schedule(task);
+    }
+}
+
+static VTABLE: RawWakerVTable = RawWakerVTable::new(clone_waker, wake, wake_by_ref, drop_waker);
+
+unsafe fn clone_waker(data: *const ()) -> RawWaker {
+    let task = ManuallyDrop::new(unsafe { KArc::from_raw(data as *const Task) });
+
+    let _ = KArc::into_raw(KArc::clone(&task));
+
+    RawWaker::new(data, &VTABLE)
+}
+
+unsafe fn wake(data: *const ()) {
+    schedule(unsafe { KArc::from_raw(data as *const Task) });
+}
+
+unsafe fn wake_by_ref(data: *const ()) {
+    let task = ManuallyDrop::new(unsafe { KArc::from_raw(data as *const Task) });
+
+    schedule(KArc::clone(&task));
+}
+
+unsafe fn drop_waker(data: *const ()) {
+    drop(unsafe { KArc::from_raw(data as *const Task) });
+}
+
+fn waker(task: &KArc<Task>) -> Waker {
+    let data = KArc::into_raw(task.clone());
+
+    unsafe { Waker::from_raw(RawWaker::new(data.cast(), &VTABLE)) }
+}
+
+/// A single-threaded executor, the clones share the same tasks
+#[derive(Clone)]
+pub struct Executor {
+    shared: KArc<Shared>,
+}
+
+impl Executor {
+    /// it fails with STATUS_INSUFFICIENT_RESOURCES if the executor can not be allocated
+    pub fn new() -> Result<Self, NtError> {
+        let wait_blocks = ex_allocate_pool_zero(
+            NonPagedPoolNx,
+            (core::mem::size_of::<KWAIT_BLOCK>() * MAXIMUM_WAIT_OBJECTS as usize) as _,
+            EXECUTOR_TAG,
+        )?;
+
+        let wake = match EventProperty::new().auto_reset(true).new_event() {
+            Ok(wake) => wake,
+            Err(e) => {
+                unsafe { ex_free_pool(wait_blocks, EXECUTOR_TAG) };
+                return Err(e);
+            }
+        };
+
+        // the wait blocks are freed by `Shared` from now on
+        let shared = KArc::new(Shared {
+            ready: StaticSpinLocked::new(ReadyQueue {
+                head: ptr::null(),
+                tail: ptr::null(),
+            }),
+            reactor: StaticSpinLocked::new(Reactor {
+                registrations: Vec::new(),
+                next_token: 0,
+            }),
+            wake,
+            wait_blocks: wait_blocks.cast(),
+            tasks: AtomicUsize::new(0),
+            running: AtomicBool::new(false),
+            stopped: AtomicBool::new(false),
+        })?;
+
+        Ok(Self { shared })
+    }
+
+    /// spawn a task at IRQL <= DISPATCH_LEVEL, it is polled by the thread running the executor
+    pub fn spawn<F>(&self, future: F) -> Result<JoinHandle<F::Output>, NtError>
+    where
+        F: Future + Send + 'static,
+        F::Output: Send + 'static,
+    {
+        let (sender, receiver) = oneshot::channel()?;
+
+        let future: BoxFuture = Box::into_pin(try_box(async move {
+            let _ = sender.send(future.await);
+        })?);
+
+        let task = KArc::new(Task {
+            future: UnsafeCell::new(Some(future)),
+            queued: AtomicBool::new(true),
+            next: UnsafeCell::new(ptr::null()),
+            shared: KArc::downgrade(&self.shared),
+        })?;
+
+        self.shared.tasks.fetch_add(1, Ordering::AcqRel);
+        self.shared.schedule(task);
+
+        Ok(receiver)
+    }
+
+    /// run the tasks on the current thread at PASSIVE_LEVEL until all of them complete or `stop` is called
+    ///
+    /// it fails with STATUS_INVALID_DEVICE_STATE if the executor is already running on another thread
+    pub fn run(&self) -> Result<(), NtError> {
+        let shared = &self.shared;
+
+        if shared.running.swap(true, Ordering::AcqRel) {
+            return Err(NtError::new(STATUS_INVALID_DEVICE_STATE));
+        }
+
+        let thread = KeGetCurrentThread() as usize;
+
+        {
+            let mut running = RUNNING.lock();
+
+            if running.try_reserve(1).is_err() {
+                shared.running.store(false, Ordering::Release);
+                return Err(NtError::new(STATUS_INSUFFICIENT_RESOURCES));
+            }
+
+            running.push((thread, shared.clone()));
+        }
+
+        loop {
+            loop {
+                let task = shared.ready.lock().pop();
+
+                match task {
+                    Some(task) => shared.poll(task),
+                    None => break,
+                }
+            }
+
+            if shared.stopped.swap(false, Ordering::AcqRel)
+                || shared.tasks.load(Ordering::Acquire) == 0
+            {
+                break;
+            }
+
+            shared.idle();
+        }
+
+        let this = {
+            let mut running = RUNNING.lock();
+
+            running
+                .iter()
+                .position(|(t, _)| *t == thread)
+                .map(|index| running.swap_remove(index))
+        };
+
+        drop(this);
+
+        shared.running.store(false, Ordering::Release);
+
+        Ok(())
+    }
+
+    /// make `run` return after the current poll, the pending tasks are kept for the next `run`
+    pub fn stop(&self) {
+        self.shared.stopped.store(true, Ordering::Release);
+        self.shared.wake.set();
+    }
+
+    /// the number of tasks which have not completed
+    pub fn len(&self) -> usize {
+        self.shared.tasks.load(Ordering::Acquire)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// run a future to completion on a new executor on the current thread, it must be called at PASSIVE_LEVEL
+///
+/// the tasks spawned on the executor by the future are run to completion as well
+pub fn block_on<F>(future: F) -> Result<F::Output, NtError>
+where
+    F: Future + Send + 'static,
+    F::Output: Send + 'static,
+{
+    let executor = Executor::new()?;
+    let mut handle = executor.spawn(future)?;
+
+    executor.run()?;
+
+    handle
+        .try_recv()?
+        .ok_or_else(|| NtError::new(STATUS_PIPE_BROKEN))
+}
+
+/// The future returned by `Dispatchable::wait_async`, it resolves once the object is signaled
+///
+/// a satisfied wait has the same side effect as `Dispatchable::wait`, e.g. an auto-reset event is reset
+pub struct WaitFuture<'a, D: ?Sized> {
+    object: &'a D,
+    /// a weak reference, the registration must not keep the executor alive
+    registration: Option<(KWeak<Shared>, u64)>,
+}
+
+impl<'a, D: Dispatchable + ?Sized> WaitFuture<'a, D> {
+    pub fn new(object: &'a D) -> Self {
+        Self {
+            object,
+            registration: None,
+        }
+    }
+}
+
+impl<D: Dispatchable + ?Sized> Future for WaitFuture<'_, D> {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.get_mut();
+
+        // the executor is gone if the registration can not be upgraded, the object is polled again below
+        if let Some((shared, token)) = this
+            .registration
+            .take()
+            .and_then(|(shared, token)| Some((shared.upgrade()?, token)))
+        {
+            let mut reactor = shared.reactor.lock();
+
+            match reactor.position(token) {
+                Some(index) if !reactor.registrations[index].fired => {
+                    let old = reactor.registrations[index]
+                        .waker
+                        .replace(cx.waker().clone());
+
+                    // the old waker is dropped without the lock
+                    drop(reactor);
+                    drop(old);
+
+                    this.registration = Some((KArc::downgrade(&shared), token));
+
+                    return Poll::Pending;
+                }
+                _ => {
+                    let registration = reactor.unregister(token);
+
+                    drop(reactor);
+                    drop(registration);
+
+                    return Poll::Ready(());
+                }
+            }
+        }
+
+        if this.object.wait_for(Duration::ZERO, false).success() {
+            return Poll::Ready(());
+        }
+
+        let object = this.object.as_raw().cast();
+
+        if let Some(shared) = current() {
+            let token = shared.reactor.lock().register(object, cx.waker());
+
+            if let Some(token) = token {
+                this.registration = Some((KArc::downgrade(&shared), token));
+                return Poll::Pending;
+            }
+        }
+
+        // polled by another executor or the reactor is full, poll again on the next turn
+        cx.waker().wake_by_ref();
+
+        Poll::Pending
+    }
+}
+
+impl<D: ?Sized> Drop for WaitFuture<'_, D> {
+    fn drop(&mut self) {
+        let Some((shared, token)) = self.registration.take() else {
+            return;
+        };
+
+        if let Some(shared) = shared.upgrade() {
+            let registration = shared.reactor.lock().unregister(token);
+
+            drop(registration);
+        }
+    }
+}
+
+unsafe impl<D: Sync + ?Sized> Send for WaitFuture<'_, D> {}
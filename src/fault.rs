@@ -0,0 +1,156 @@
+//! this module provides the fault injection hooks, enabled by the `fault-injection` feature
+//!
+//! the pool allocations of this crate and the `try_lock` of the locks consult a per-site `FaultPolicy` before doing
+//! the real work, a failure injected into an allocation surfaces as STATUS_INSUFFICIENT_RESOURCES and a failure
+//! injected into a `try_lock` as a busy lock, so the OOM and contention paths of a driver can be exercised without
+//! exhausting the pool
+//!
+//! the policies and the counters are atomics, so they can be changed and consulted at any IRQL, the number of the
+//! injected failures is reported by `stats::faults()`
+//!
+//! # Example
+//! ```
+//! fault::set_policy(FaultSite::Allocation, FaultPolicy::EveryNth(10));
+//!
+//! run_scenario();
+//!
+//! fault::set_policy(FaultSite::Allocation, FaultPolicy::Never);
+//! println!("{} allocations failed", stats::faults().allocations);
+//! ```
+use core::sync::atomic::{AtomicU8, AtomicU32, AtomicU64, Ordering};
+
+/// The call sites which consult a policy
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum FaultSite {
+    /// the pool allocations, e.g. `KArc::new`, `Locked::new` or a `Timer`
+    Allocation,
+    /// `InlineLocked::try_lock` and `StaticSpinLocked::try_lock`
+    TryLock,
+}
+
+/// When a call fails
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum FaultPolicy {
+    Never,
+    /// every `n`th call fails, 0 is the same as `Never`
+    EveryNth(u32),
+    /// a call fails with the probability `percent`/100, the sequence is reproducible for the same `seed`
+    Random {
+        seed: u64,
+        percent: u8,
+    },
+}
+
+const MODE_NEVER: u8 = 0;
+const MODE_NTH: u8 = 1;
+const MODE_RANDOM: u8 = 2;
+
+struct SiteState {
+    mode: AtomicU8,
+    /// `n` of `EveryNth` or `percent` of `Random`
+    param: AtomicU32,
+    /// the calls since the policy is set, or the state of the random generator
+    state: AtomicU64,
+    injected: AtomicU64,
+}
+
+impl SiteState {
+    const fn new() -> Self {
+        Self {
+            mode: AtomicU8::new(MODE_NEVER),
+            param: AtomicU32::new(0),
+            state: AtomicU64::new(0),
+            injected: AtomicU64::new(0),
+        }
+    }
+
+    fn set(&self, policy: FaultPolicy) {
+        // disable the site first, so a concurrent call never sees a half updated policy as active
+        self.mode.store(MODE_NEVER, Ordering::Release);
+
+        let (mode, param, state) = match policy {
+            FaultPolicy::Never => return,
+            FaultPolicy::EveryNth(0) => return,
+            FaultPolicy::EveryNth(n) => (MODE_NTH, n, 0),
+            // the xorshift state must not be zero
+            FaultPolicy::Random { seed, percent } => {
+                (MODE_RANDOM, percent.min(100) as u32, seed | 1)
+            }
+        };
+
+        self.param.store(param, Ordering::Relaxed);
+        self.state.store(state, Ordering::Relaxed);
+        self.mode.store(mode, Ordering::Release);
+    }
+
+    fn inject(&self) -> bool {
+        let fail = match self.mode.load(Ordering::Acquire) {
+            MODE_NTH => {
+                let n = self.param.load(Ordering::Relaxed) as u64;
+
+                (self.state.fetch_add(1, Ordering::Relaxed) + 1) % n == 0
+            }
+            MODE_RANDOM => {
+                let percent = self.param.load(Ordering::Relaxed) as u64;
+                let previous = self
+                    .state
+                    .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |x| Some(xorshift(x)))
+                    .unwrap_or_default();
+
+                xorshift(previous) % 100 < percent
+            }
+            _ => false,
+        };
+
+        if fail {
+            self.injected.fetch_add(1, Ordering::Relaxed);
+        }
+
+        fail
+    }
+}
+
+fn xorshift(mut x: u64) -> u64 {
+    x ^= x << 13;
+    x ^= x >> 7;
+    x ^= x << 17;
+    x
+}
+
+static ALLOCATION: SiteState = SiteState::new();
+static TRY_LOCK: SiteState = SiteState::new();
+
+fn site(site: FaultSite) -> &'static SiteState {
+    match site {
+        FaultSite::Allocation => &ALLOCATION,
+        FaultSite::TryLock => &TRY_LOCK,
+    }
+}
+
+/// replace the policy of a site, the call count of `EveryNth` restarts from zero
+pub fn set_policy(at: FaultSite, policy: FaultPolicy) {
+    site(at).set(policy);
+}
+
+/// disable the injection at all the sites, the counters are kept
+pub fn disable_all() {
+    set_policy(FaultSite::Allocation, FaultPolicy::Never);
+    set_policy(FaultSite::TryLock, FaultPolicy::Never);
+}
+
+/// the number of the failures injected at a site
+pub fn injected(at: FaultSite) -> u64 {
+    site(at).injected.load(Ordering::Relaxed)
+}
+
+/// clear the counters of the injected failures
+pub fn reset_counters() {
+    ALLOCATION.injected.store(0, Ordering::Relaxed);
+    TRY_LOCK.injected.store(0, Ordering::Relaxed);
+}
+
+/// true if the current call at `at` must fail, it is consulted by the hooked call sites
+#[inline]
+pub(crate) fn inject(at: FaultSite) -> bool {
+    site(at).inject()
+}
@@ -0,0 +1,161 @@
+//! this module provides formatting without heap allocation, which is safe at any IRQL
+//!
+//! - `StackString<N>` is a fixed-capacity string implementing `core::fmt::Write`, the output is truncated when full
+//! - `kprintf!` formats into a `StackString` on the stack and prints it with `DbgPrintEx`
+//!
+//! so it can be used in a DPC routine, an ISR or while holding a `SpinLocked` guard
+//!
+//! # Example
+//! ```
+//! // at DISPATCH_LEVEL
+//! let guard = STATE.lock()?;
+//! kprintf!("state: {:?}, pending: {}", guard.state, guard.pending);
+//! kprintf!(level: DPFLTR_INFO_LEVEL, "verbose {}", 1);
+//!
+//! let mut name = StackString::<64>::new();
+//! write!(name, "\\Device\\Worker{}", index)?;
+//! ```
+use core::{
+    fmt::{self, Write},
+    ops::Deref,
+};
+
+use wdk_sys::{_DPFLTR_TYPE::DPFLTR_IHVDRIVER_ID, DPFLTR_ERROR_LEVEL, ntddk::DbgPrintEx};
+
+/// the capacity of the buffer of `kprintf!`, longer messages are truncated
+pub const KPRINTF_BUFFER_LEN: usize = 512;
+
+/// the level of `kprintf!` without a `level`, DPFLTR_ERROR_LEVEL is not filtered by the debug print filter
+pub const KPRINTF_DEFAULT_LEVEL: u32 = DPFLTR_ERROR_LEVEL;
+
+/// A fixed-capacity UTF-8 string, the output is truncated at a char boundary when it is full
+#[derive(Clone)]
+pub struct StackString<const N: usize> {
+    buffer: [u8; N],
+    len: usize,
+    truncated: bool,
+}
+
+impl<const N: usize> StackString<N> {
+    pub const fn new() -> Self {
+        Self {
+            buffer: [0; N],
+            len: 0,
+            truncated: false,
+        }
+    }
+
+    /// format `args` into a new string, see `format_args!`
+    pub fn from_fmt(args: fmt::Arguments) -> Self {
+        let mut s = Self::new();
+
+        let _ = s.write_fmt(args);
+
+        s
+    }
+
+    pub fn as_str(&self) -> &str {
+        unsafe { core::str::from_utf8_unchecked(&self.buffer[..self.len]) }
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.buffer[..self.len]
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub const fn capacity(&self) -> usize {
+        N
+    }
+
+    /// true if some output has been discarded since the string is full
+    pub fn is_truncated(&self) -> bool {
+        self.truncated
+    }
+
+    pub fn clear(&mut self) {
+        self.len = 0;
+        self.truncated = false;
+    }
+}
+
+impl<const N: usize> Default for StackString<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const N: usize> Write for StackString<N> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let available = N - self.len;
+
+        let mut n = s.len().min(available);
+
+        while !s.is_char_boundary(n) {
+            n -= 1;
+        }
+
+        self.buffer[self.len..self.len + n].copy_from_slice(&s.as_bytes()[..n]);
+        self.len += n;
+
+        if n < s.len() {
+            self.truncated = true;
+            Err(fmt::Error)
+        } else {
+            Ok(())
+        }
+    }
+}
+
+impl<const N: usize> Deref for StackString<N> {
+    type Target = str;
+    fn deref(&self) -> &Self::Target {
+        self.as_str()
+    }
+}
+
+impl<const N: usize> fmt::Display for StackString<N> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl<const N: usize> fmt::Debug for StackString<N> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(self.as_str(), f)
+    }
+}
+
+/// format `args` on the stack and print it with `DbgPrintEx` at `level`(DPFLTR_XXX_LEVEL), use `kprintf!` instead
+pub fn print(level: u32, args: fmt::Arguments) {
+    let message = StackString::<KPRINTF_BUFFER_LEN>::from_fmt(args);
+
+    unsafe {
+        DbgPrintEx(
+            DPFLTR_IHVDRIVER_ID as _,
+            level,
+            c"%.*s\n".as_ptr(),
+            message.len() as i32,
+            message.as_ptr(),
+        );
+    }
+}
+
+/// print a formatted message to the kernel debugger without allocation, a new line is appended
+///
+/// the message is printed at `KPRINTF_DEFAULT_LEVEL` unless a `level` is given
+#[macro_export]
+macro_rules! kprintf {
+    (level: $level:expr, $($arg:tt)+) => {
+        $crate::fmt::print($level, format_args!($($arg)+))
+    };
+    ($($arg:tt)+) => {
+        $crate::fmt::print($crate::fmt::KPRINTF_DEFAULT_LEVEL, format_args!($($arg)+))
+    };
+}
@@ -0,0 +1,178 @@
+//! this module provides `Guid`, a GUID which can be generated, parsed, formatted, compared and hashed
+//!
+//! it has the layout of `wdk_sys::GUID`, so a `&Guid` can be passed wherever the system expects a `LPCGUID`
+//!
+//! # Example
+//! ```
+//! const PROVIDER_ID: Guid = Guid::from_u128(0x3d6fa8d0_fe05_11d0_9dda_00c04fd7ba7c);
+//!
+//! let provider = Provider::register("MyDriver", PROVIDER_ID.as_raw())?;
+//!
+//! let id = Guid::parse("{6FE69556-704A-47A0-8F24-C28D936FDA47}")?;
+//! let session = Guid::new_random()?;
+//! println!("session {}", session);
+//! ```
+use core::{fmt, str::FromStr};
+
+use wdk_sys::{GUID, LPCGUID, STATUS_INVALID_PARAMETER, ntddk::ExUuidCreate};
+
+use crate::ntstatus::{NtError, check};
+
+/// A GUID, see the module documents
+#[repr(C)]
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+pub struct Guid {
+    pub data1: u32,
+    pub data2: u16,
+    pub data3: u16,
+    pub data4: [u8; 8],
+}
+
+impl Guid {
+    /// the all zero GUID
+    pub const NIL: Self = Self::from_u128(0);
+
+    /// a GUID from its big endian value, e.g. `0x6fe69556_704a_47a0_8f24_c28d936fda47`
+    pub const fn from_u128(value: u128) -> Self {
+        let bytes = value.to_be_bytes();
+
+        Self {
+            data1: (value >> 96) as u32,
+            data2: (value >> 80) as u16,
+            data3: (value >> 64) as u16,
+            data4: [
+                bytes[8], bytes[9], bytes[10], bytes[11], bytes[12], bytes[13], bytes[14],
+                bytes[15],
+            ],
+        }
+    }
+
+    pub const fn to_u128(&self) -> u128 {
+        ((self.data1 as u128) << 96)
+            | ((self.data2 as u128) << 80)
+            | ((self.data3 as u128) << 64)
+            | (u64::from_be_bytes(self.data4) as u128)
+    }
+
+    /// a new GUID from `ExUuidCreate`, it must be called at PASSIVE_LEVEL
+    ///
+    /// the GUID is unique on this machine only if the system can not get a network address
+    pub fn new_random() -> Result<Self, NtError> {
+        crate::assert_irql!(== PASSIVE_LEVEL);
+
+        let mut guid = GUID::default();
+
+        check(unsafe { ExUuidCreate(&mut guid) })?;
+
+        Ok(guid.into())
+    }
+
+    /// parse the registry format `{XXXXXXXX-XXXX-XXXX-XXXX-XXXXXXXXXXXX}`, the braces are optional and the digits
+    /// are case insensitive
+    ///
+    /// it fails with STATUS_INVALID_PARAMETER if `s` is not a GUID
+    pub fn parse(s: &str) -> Result<Self, NtError> {
+        let s = s.as_bytes();
+
+        let s = match s {
+            [b'{', inner @ .., b'}'] => inner,
+            _ => s,
+        };
+
+        if s.len() != 36 {
+            return Err(NtError::new(STATUS_INVALID_PARAMETER));
+        }
+
+        let mut value: u128 = 0;
+
+        for (i, c) in s.iter().enumerate() {
+            if matches!(i, 8 | 13 | 18 | 23) {
+                if *c != b'-' {
+                    return Err(NtError::new(STATUS_INVALID_PARAMETER));
+                }
+
+                continue;
+            }
+
+            let digit = (*c as char)
+                .to_digit(16)
+                .ok_or_else(|| NtError::new(STATUS_INVALID_PARAMETER))?;
+
+            value = (value << 4) | digit as u128;
+        }
+
+        Ok(Self::from_u128(value))
+    }
+
+    pub const fn is_nil(&self) -> bool {
+        self.to_u128() == 0
+    }
+
+    /// the GUID as a `wdk_sys::GUID`, which has the same layout
+    pub fn as_raw(&self) -> &GUID {
+        unsafe { &*(self as *const Self as *const GUID) }
+    }
+
+    pub fn as_ptr(&self) -> LPCGUID {
+        self.as_raw()
+    }
+}
+
+impl From<GUID> for Guid {
+    fn from(guid: GUID) -> Self {
+        Self {
+            data1: guid.Data1,
+            data2: guid.Data2,
+            data3: guid.Data3,
+            data4: guid.Data4,
+        }
+    }
+}
+
+impl From<Guid> for GUID {
+    fn from(guid: Guid) -> Self {
+        Self {
+            Data1: guid.data1,
+            Data2: guid.data2,
+            Data3: guid.data3,
+            Data4: guid.data4,
+        }
+    }
+}
+
+impl FromStr for Guid {
+    type Err = NtError;
+
+    fn from_str(s: &str) -> Result<Self, NtError> {
+        Self::parse(s)
+    }
+}
+
+/// the registry format with braces and upper case digits, `{:#}` omits the braces
+impl fmt::Display for Guid {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let d = &self.data4;
+
+        if !f.alternate() {
+            f.write_str("{")?;
+        }
+
+        write!(
+            f,
+            "{:08X}-{:04X}-{:04X}-{:02X}{:02X}-{:02X}{:02X}{:02X}{:02X}{:02X}{:02X}",
+            self.data1, self.data2, self.data3, d[0], d[1], d[2], d[3], d[4], d[5], d[6], d[7]
+        )?;
+
+        if !f.alternate() {
+            f.write_str("}")?;
+        }
+
+        Ok(())
+    }
+}
+
+impl fmt::Debug for Guid {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(self, f)
+    }
+}
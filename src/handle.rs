@@ -1,17 +1,21 @@
-use core::{ops::{Deref, DerefMut}, ptr::{self, NonNull}};
+use core::{mem, ops::{Deref, DerefMut}, ptr::{self, NonNull}};
 
+use alloc::vec::Vec;
 use wdk_sys::{
     _MODE::KernelMode,
-    GENERIC_ALL, HANDLE, OBJ_KERNEL_HANDLE, PEPROCESS, PETHREAD, PsProcessType, PsThreadType,
-    ULONG,
-    ntddk::{ObOpenObjectByPointer, ZwClose},
+    ACCESS_MASK, GENERIC_ALL, HANDLE, NTSTATUS, OBJ_KERNEL_HANDLE, PEPROCESS, PETHREAD, PULONG,
+    PVOID, PsProcessType, PsThreadType, STATUS_BUFFER_OVERFLOW, STATUS_BUFFER_TOO_SMALL,
+    STATUS_INFO_LENGTH_MISMATCH, STATUS_INSUFFICIENT_RESOURCES, ULONG, UNICODE_STRING,
+    ntddk::{ObOpenObjectByPointer, ObReferenceObjectByHandle, ObfDereferenceObject, ZwClose},
 };
 
 use crate::raw::AsRawObject;
 use crate::{
+    NtCurrentProcess,
     kobject::KernelObject,
     ntstatus::{NtError, cvt},
     raw::AsRawHandle,
+    unicode::NtUnicodeString,
 };
 
 /// an abstract concept for "close kernel handle"
@@ -164,4 +168,160 @@ impl<T: CloseHandle> Drop for OwnedHandle<T> {
     fn drop(&mut self) {
         self.0.close();
     }
-}
\ No newline at end of file
+}
+unsafe extern "C" {
+    pub fn ZwDuplicateObject(
+        SourceProcessHandle: HANDLE,
+        SourceHandle: HANDLE,
+        TargetProcessHandle: HANDLE,
+        TargetHandle: *mut HANDLE,
+        DesiredAccess: ACCESS_MASK,
+        HandleAttributes: ULONG,
+        Options: ULONG,
+    ) -> NTSTATUS;
+
+    pub fn ZwQueryObject(
+        Handle: HANDLE,
+        ObjectInformationClass: ULONG,
+        ObjectInformation: PVOID,
+        ObjectInformationLength: ULONG,
+        ReturnLength: PULONG,
+    ) -> NTSTATUS;
+
+    pub fn ObQueryNameString(
+        Object: PVOID,
+        ObjectNameInfo: PVOID,
+        Length: ULONG,
+        ReturnLength: PULONG,
+    ) -> NTSTATUS;
+}
+
+pub const ObjectBasicInformation: ULONG = 0;
+pub const ObjectTypeInformation: ULONG = 2;
+
+#[repr(C)]
+#[derive(Default)]
+pub struct PUBLIC_OBJECT_BASIC_INFORMATION {
+    pub Attributes: ULONG,
+    pub GrantedAccess: ACCESS_MASK,
+    pub HandleCount: ULONG,
+    pub PointerCount: ULONG,
+    pub Reserved: [ULONG; 10],
+}
+
+/// duplicate `handle` of process `src_process` into a kernel handle
+///
+/// # Parameters
+/// - src_process: a handle of the source process, `NtCurrentProcess` if `handle` belongs to the current process
+/// - access: the desired access of the new handle, ignored with DUPLICATE_SAME_ACCESS
+/// - options: a combination of DUPLICATE_SAME_ACCESS, DUPLICATE_SAME_ATTRIBUTES and DUPLICATE_CLOSE_SOURCE
+pub fn duplicate(
+    src_process: HANDLE,
+    handle: HANDLE,
+    access: ACCESS_MASK,
+    options: ULONG,
+) -> Result<ObjectHandle, NtError> {
+    let mut target: HANDLE = ptr::null_mut();
+
+    cvt(unsafe {
+        ZwDuplicateObject(
+            src_process,
+            handle,
+            NtCurrentProcess,
+            &mut target,
+            access,
+            OBJ_KERNEL_HANDLE,
+            options,
+        )
+    })?;
+
+    Ok(ObjectHandle::new(target))
+}
+
+/// query into an 8-byte aligned buffer, the buffer is grown while the information does not fit
+pub(crate) fn query_variable<F: FnMut(PVOID, ULONG, &mut ULONG) -> NTSTATUS>(
+    mut f: F,
+) -> Result<Vec<u64>, NtError> {
+    let mut required: ULONG = 0x100;
+
+    loop {
+        let words = (required as usize).div_ceil(mem::size_of::<u64>());
+
+        let mut buffer: Vec<u64> = Vec::new();
+
+        buffer
+            .try_reserve_exact(words)
+            .map_err(|_| NtError::new(STATUS_INSUFFICIENT_RESOURCES))?;
+
+        buffer.resize(words, 0);
+
+        let capacity = (words * mem::size_of::<u64>()) as ULONG;
+
+        match f(buffer.as_mut_ptr().cast(), capacity, &mut required) {
+            STATUS_INFO_LENGTH_MISMATCH | STATUS_BUFFER_TOO_SMALL | STATUS_BUFFER_OVERFLOW => {
+                // some queries do not report the required length
+                required = required.max(capacity * 2);
+            }
+            status => {
+                cvt(status)?;
+
+                return Ok(buffer);
+            }
+        }
+    }
+}
+
+/// the name of the object referenced by `handle`, e.g. "\Device\HarddiskVolume3\Windows\notepad.exe"
+///
+/// it must be called at PASSIVE_LEVEL, `handle` must be a kernel handle or a handle of the current process
+pub fn query_object_name(handle: HANDLE) -> Result<NtUnicodeString, NtError> {
+    let mut object: PVOID = ptr::null_mut();
+
+    cvt(unsafe {
+        ObReferenceObjectByHandle(
+            handle,
+            0,
+            ptr::null_mut(),
+            KernelMode as _,
+            &mut object,
+            ptr::null_mut(),
+        )
+    })?;
+
+    let buffer = query_variable(|info, length, required| unsafe {
+        ObQueryNameString(object, info, length, required)
+    });
+
+    unsafe { ObfDereferenceObject(object) };
+
+    // OBJECT_NAME_INFORMATION is a single UNICODE_STRING
+    unsafe { NtUnicodeString::from_raw(&*buffer?.as_ptr().cast::<UNICODE_STRING>()) }
+}
+
+/// the name of the type of the object referenced by `handle`, e.g. "File", "Process"
+pub fn query_type_name(handle: HANDLE) -> Result<NtUnicodeString, NtError> {
+    let buffer = query_variable(|info, length, required| unsafe {
+        ZwQueryObject(handle, ObjectTypeInformation, info, length, required)
+    })?;
+
+    // the type name is the first field of OBJECT_TYPE_INFORMATION
+    unsafe { NtUnicodeString::from_raw(&*buffer.as_ptr().cast::<UNICODE_STRING>()) }
+}
+
+/// the access granted to `handle` when it was opened
+pub fn query_granted_access(handle: HANDLE) -> Result<ACCESS_MASK, NtError> {
+    let mut info = PUBLIC_OBJECT_BASIC_INFORMATION::default();
+    let mut length: ULONG = 0;
+
+    cvt(unsafe {
+        ZwQueryObject(
+            handle,
+            ObjectBasicInformation,
+            &mut info as *mut _ as _,
+            mem::size_of::<PUBLIC_OBJECT_BASIC_INFORMATION>() as _,
+            &mut length,
+        )
+    })?;
+
+    Ok(info.GrantedAccess)
+}
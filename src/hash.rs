@@ -0,0 +1,285 @@
+//! this module provides the checksums and hashers of the crate, they never allocate and can be used at any IRQL
+//!
+//! - `crc32c`/`Crc32c`, the CRC-32C(Castagnoli) checksum, computed by the SSE4.2 `crc32` instruction when the
+//!   processor has it, by a table otherwise
+//! - `FnvHasher`/`fnv1a`, the FNV-1a 64 bit hash, fast on the short keys but trivial to flood, the default of
+//!   `KHashMap`
+//! - `SipHasher13`, SipHash-1-3 keyed by a secret, for the maps whose keys come from an untrusted source
+//!
+//! # Example
+//! ```
+//! let checksum = hash::crc32c(&header);
+//!
+//! let mut crc = Crc32c::new();
+//! crc.update(&header);
+//! crc.update(&payload);
+//! let checksum = crc.finish();
+//!
+//! // a keyed hasher, the key is chosen at the start of the driver
+//! let build = SipBuildHasher::new(k0, k1);
+//! let hash = build.hash_one(&name);
+//!
+//! // a map keyed by the names sent from user mode
+//! let names: KHashMap<String, u32, SipBuildHasher> = KHashMap::with_hasher(build)?;
+//! ```
+use core::hash::{BuildHasher, BuildHasherDefault, Hasher};
+#[cfg(target_arch = "x86_64")]
+use core::sync::atomic::{AtomicU8, Ordering};
+
+/// the reflected CRC-32C polynomial
+const CRC32C_POLY: u32 = 0x82f6_3b78;
+
+const fn crc32c_table() -> [u32; 256] {
+    let mut table = [0u32; 256];
+    let mut i = 0;
+
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+
+        while bit < 8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ CRC32C_POLY
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+
+        table[i] = crc;
+        i += 1;
+    }
+
+    table
+}
+
+static CRC32C_TABLE: [u32; 256] = crc32c_table();
+
+fn crc32c_software(mut crc: u32, data: &[u8]) -> u32 {
+    for byte in data {
+        crc = CRC32C_TABLE[((crc ^ *byte as u32) & 0xff) as usize] ^ (crc >> 8);
+    }
+
+    crc
+}
+
+#[cfg(target_arch = "x86_64")]
+#[target_feature(enable = "sse4.2")]
+fn crc32c_sse42(crc: u32, data: &[u8]) -> u32 {
+    use core::arch::x86_64::{_mm_crc32_u8, _mm_crc32_u64};
+
+    let mut chunks = data.chunks_exact(8);
+    let mut crc = crc as u64;
+
+    for chunk in &mut chunks {
+        let mut word = [0u8; 8];
+        word.copy_from_slice(chunk);
+
+        crc = _mm_crc32_u64(crc, u64::from_le_bytes(word));
+    }
+
+    let mut crc = crc as u32;
+
+    for byte in chunks.remainder() {
+        crc = _mm_crc32_u8(crc, *byte);
+    }
+
+    crc
+}
+
+/// 0 before the processor is queried, 1 without SSE4.2, 2 with it
+#[cfg(target_arch = "x86_64")]
+static SSE42: AtomicU8 = AtomicU8::new(0);
+
+#[cfg(target_arch = "x86_64")]
+fn has_sse42() -> bool {
+    match SSE42.load(Ordering::Relaxed) {
+        0 => {
+            // CPUID.01H:ECX.SSE4_2[bit 20]
+            let supported = core::arch::x86_64::__cpuid(1).ecx & (1 << 20) != 0;
+
+            SSE42.store(if supported { 2 } else { 1 }, Ordering::Relaxed);
+
+            supported
+        }
+        state => state == 2,
+    }
+}
+
+fn crc32c_update(crc: u32, data: &[u8]) -> u32 {
+    #[cfg(target_arch = "x86_64")]
+    if has_sse42() {
+        return unsafe { crc32c_sse42(crc, data) };
+    }
+
+    crc32c_software(crc, data)
+}
+
+/// the CRC-32C of `data`
+pub fn crc32c(data: &[u8]) -> u32 {
+    !crc32c_update(!0, data)
+}
+
+/// A CRC-32C computed over several pieces
+#[derive(Clone, Copy, Debug)]
+pub struct Crc32c(u32);
+
+impl Crc32c {
+    pub const fn new() -> Self {
+        Self(!0)
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        self.0 = crc32c_update(self.0, data);
+    }
+
+    /// the checksum of the data so far, more data can still be added
+    pub fn finish(&self) -> u32 {
+        !self.0
+    }
+}
+
+impl Default for Crc32c {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// FNV-1a 64 bit hasher
+#[derive(Clone, Copy, Debug)]
+pub struct FnvHasher(u64);
+
+impl FnvHasher {
+    pub const fn new() -> Self {
+        Self(0xcbf2_9ce4_8422_2325)
+    }
+}
+
+impl Default for FnvHasher {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Hasher for FnvHasher {
+    fn finish(&self) -> u64 {
+        self.0
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.0 ^= *byte as u64;
+            self.0 = self.0.wrapping_mul(0x0000_0100_0000_01b3);
+        }
+    }
+}
+
+/// A `BuildHasher` of `FnvHasher`
+pub type FnvBuildHasher = BuildHasherDefault<FnvHasher>;
+
+/// the FNV-1a hash of `data`
+pub fn fnv1a(data: &[u8]) -> u64 {
+    let mut hasher = FnvHasher::new();
+
+    hasher.write(data);
+    hasher.finish()
+}
+
+/// SipHash-1-3 hasher keyed by `k0` and `k1`, the algorithm of the std `HashMap`
+#[derive(Clone, Copy, Debug)]
+pub struct SipHasher13 {
+    v0: u64,
+    v1: u64,
+    v2: u64,
+    v3: u64,
+    /// the bytes which do not fill a word yet, little endian
+    tail: u64,
+    ntail: usize,
+    length: usize,
+}
+
+impl SipHasher13 {
+    pub const fn new_with_keys(k0: u64, k1: u64) -> Self {
+        Self {
+            v0: k0 ^ 0x736f_6d65_7073_6575,
+            v1: k1 ^ 0x646f_7261_6e64_6f6d,
+            v2: k0 ^ 0x6c79_6765_6e65_7261,
+            v3: k1 ^ 0x7465_6462_7974_6573,
+            tail: 0,
+            ntail: 0,
+            length: 0,
+        }
+    }
+
+    #[inline]
+    fn round(&mut self) {
+        self.v0 = self.v0.wrapping_add(self.v1);
+        self.v1 = self.v1.rotate_left(13) ^ self.v0;
+        self.v0 = self.v0.rotate_left(32);
+        self.v2 = self.v2.wrapping_add(self.v3);
+        self.v3 = self.v3.rotate_left(16) ^ self.v2;
+        self.v0 = self.v0.wrapping_add(self.v3);
+        self.v3 = self.v3.rotate_left(21) ^ self.v0;
+        self.v2 = self.v2.wrapping_add(self.v1);
+        self.v1 = self.v1.rotate_left(17) ^ self.v2;
+        self.v2 = self.v2.rotate_left(32);
+    }
+
+    #[inline]
+    fn compress(&mut self, word: u64) {
+        self.v3 ^= word;
+        self.round();
+        self.v0 ^= word;
+    }
+}
+
+impl Hasher for SipHasher13 {
+    fn write(&mut self, bytes: &[u8]) {
+        self.length = self.length.wrapping_add(bytes.len());
+
+        for byte in bytes {
+            self.tail |= (*byte as u64) << (8 * self.ntail);
+            self.ntail += 1;
+
+            if self.ntail == 8 {
+                self.compress(self.tail);
+                self.tail = 0;
+                self.ntail = 0;
+            }
+        }
+    }
+
+    fn finish(&self) -> u64 {
+        let mut state = *self;
+
+        state.compress(((self.length as u64 & 0xff) << 56) | self.tail);
+
+        state.v2 ^= 0xff;
+        state.round();
+        state.round();
+        state.round();
+
+        state.v0 ^ state.v1 ^ state.v2 ^ state.v3
+    }
+}
+
+/// A `BuildHasher` of `SipHasher13` with a fixed key
+#[derive(Clone, Copy, Debug)]
+pub struct SipBuildHasher {
+    k0: u64,
+    k1: u64,
+}
+
+impl SipBuildHasher {
+    pub const fn new(k0: u64, k1: u64) -> Self {
+        Self { k0, k1 }
+    }
+}
+
+impl BuildHasher for SipBuildHasher {
+    type Hasher = SipHasher13;
+
+    fn build_hasher(&self) -> SipHasher13 {
+        SipHasher13::new_with_keys(self.k0, self.k1)
+    }
+}
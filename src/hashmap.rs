@@ -0,0 +1,378 @@
+//! this module provides `KHashMap<K, V>`, a sharded hash map that can be used at IRQL <= DISPATCH_LEVEL
+//!
+//! the map is split into shards, each shard is a chained hash table guarded by its own `SpinMutex`,
+//! so threads touching different shards never contend with each other
+//!
+//! the keys are hashed with FNV-1a by default, a map whose keys come from an untrusted source should use a keyed
+//! hasher, e.g. `SipBuildHasher`, see `with_hasher`
+use core::{
+    hash::{BuildHasher, Hash},
+    mem,
+};
+
+use alloc::vec::Vec;
+use wdk_sys::STATUS_INSUFFICIENT_RESOURCES;
+
+use crate::{hash::FnvBuildHasher, mutex::SpinLocked, ntstatus::NtError};
+
+const DEFAULT_SHARDS: usize = 16;
+const INITIAL_BUCKETS: usize = 8;
+
+struct Shard<K, V> {
+    buckets: Vec<Vec<(K, V)>>,
+    len: usize,
+}
+
+impl<K: Hash + Eq, V> Shard<K, V> {
+    const fn new() -> Self {
+        Self {
+            buckets: Vec::new(),
+            len: 0,
+        }
+    }
+
+    #[inline]
+    fn bucket_of(&self, hash: u64) -> usize {
+        // the low bits pick the shard, so the bucket is picked by the high half, the two ranges never overlap
+        ((hash >> 32) as usize) & (self.buckets.len() - 1)
+    }
+
+    fn find(&self, hash: u64, key: &K) -> Option<(usize, usize)> {
+        if self.buckets.is_empty() {
+            return None;
+        }
+
+        let bucket = self.bucket_of(hash);
+
+        self.buckets[bucket]
+            .iter()
+            .position(|(k, _)| k == key)
+            .map(|pos| (bucket, pos))
+    }
+
+    /// make sure one more element can be inserted without any infallible allocation
+    fn reserve_one<S: BuildHasher>(&mut self, hash: u64, build: &S) -> Result<usize, NtError> {
+        if self.buckets.is_empty() || self.len >= self.buckets.len() * 2 {
+            self.grow(build)?;
+        }
+
+        let bucket = self.bucket_of(hash);
+
+        self.buckets[bucket]
+            .try_reserve(1)
+            .map_err(|_| NtError::new(STATUS_INSUFFICIENT_RESOURCES))?;
+
+        Ok(bucket)
+    }
+
+    fn grow<S: BuildHasher>(&mut self, build: &S) -> Result<(), NtError> {
+        let count = if self.buckets.is_empty() {
+            INITIAL_BUCKETS
+        } else {
+            self.buckets.len() * 2
+        };
+
+        let mut buckets: Vec<Vec<(K, V)>> = Vec::new();
+
+        buckets
+            .try_reserve_exact(count)
+            .map_err(|_| NtError::new(STATUS_INSUFFICIENT_RESOURCES))?;
+
+        buckets.resize_with(count, Vec::new);
+
+        let old = mem::replace(&mut self.buckets, buckets);
+
+        // size every new bucket before moving any element, a failure puts the old buckets back untouched
+        if let Err(e) = self.reserve_rehash(&old, build) {
+            self.buckets = old;
+            return Err(e);
+        }
+
+        for (key, value) in old.into_iter().flatten() {
+            let bucket = self.bucket_of(build.hash_one(&key));
+
+            self.push_reserved(bucket, key, value);
+        }
+
+        Ok(())
+    }
+
+    /// reserve the room of the elements of `old` in the new buckets
+    fn reserve_rehash<S: BuildHasher>(
+        &mut self,
+        old: &[Vec<(K, V)>],
+        build: &S,
+    ) -> Result<(), NtError> {
+        let mut counts: Vec<usize> = Vec::new();
+
+        counts
+            .try_reserve_exact(self.buckets.len())
+            .map_err(|_| NtError::new(STATUS_INSUFFICIENT_RESOURCES))?;
+        counts.resize(self.buckets.len(), 0);
+
+        for (key, _) in old.iter().flatten() {
+            counts[self.bucket_of(build.hash_one(key))] += 1;
+        }
+
+        for (bucket, count) in self.buckets.iter_mut().zip(counts) {
+            bucket
+                .try_reserve_exact(count)
+                .map_err(|_| NtError::new(STATUS_INSUFFICIENT_RESOURCES))?;
+        }
+
+        Ok(())
+    }
+
+    /// push into a bucket whose room has been reserved, so it never allocates
+    fn push_reserved(&mut self, bucket: usize, key: K, value: V) {
+        let bucket = &mut self.buckets[bucket];
+
+        debug_assert!(bucket.len() < bucket.capacity());
+
+        bucket.push((key, value));
+    }
+}
+
+/// A sharded, spinlock protected hash map allocated from non-paged memory, the keys are hashed by `S`
+///
+/// # Safety
+/// - all the methods can be called at IRQL <= DISPATCH_LEVEL
+/// - closures passed to `entry`, `retain` and `for_each` run while a shard spin lock is held,
+/// keep them short and never touch the same map inside them
+///
+/// # Example
+/// ```
+/// let map: KHashMap<u32, u64> = KHashMap::new()?;
+///
+/// map.insert(4, 0)?;
+///
+/// // update in place while the shard lock is held
+/// map.entry(4, |entry| *entry.or_insert_with(|| 0) += 1)?;
+///
+/// assert_eq!(map.get_cloned(&4), Some(1));
+/// ```
+pub struct KHashMap<K, V, S = FnvBuildHasher> {
+    shards: Vec<SpinLocked<Shard<K, V>>>,
+    build: S,
+}
+
+impl<K: Hash + Eq, V> KHashMap<K, V, FnvBuildHasher> {
+    pub fn new() -> Result<Self, NtError> {
+        Self::with_shards(DEFAULT_SHARDS)
+    }
+
+    /// create a map with `count` shards, `count` is rounded up to a power of 2
+    pub fn with_shards(count: usize) -> Result<Self, NtError> {
+        Self::with_shards_and_hasher(count, FnvBuildHasher::default())
+    }
+}
+
+impl<K: Hash + Eq, V, S: BuildHasher> KHashMap<K, V, S> {
+    /// create a map which hashes the keys with `build`
+    pub fn with_hasher(build: S) -> Result<Self, NtError> {
+        Self::with_shards_and_hasher(DEFAULT_SHARDS, build)
+    }
+
+    /// create a map with `count` shards which hashes the keys with `build`, `count` is rounded up to a power of 2
+    pub fn with_shards_and_hasher(count: usize, build: S) -> Result<Self, NtError> {
+        let count = count.max(1).next_power_of_two();
+        let mut shards = Vec::new();
+
+        shards
+            .try_reserve_exact(count)
+            .map_err(|_| NtError::new(STATUS_INSUFFICIENT_RESOURCES))?;
+
+        for _ in 0..count {
+            shards.push(SpinLocked::new(Shard::new())?);
+        }
+
+        Ok(Self { shards, build })
+    }
+
+    #[inline]
+    fn shard(&self, hash: u64) -> &SpinLocked<Shard<K, V>> {
+        &self.shards[(hash as usize) & (self.shards.len() - 1)]
+    }
+
+    /// insert a key-value pair, returns the old value if `key` is already present
+    pub fn insert(&self, key: K, value: V) -> Result<Option<V>, NtError> {
+        self.entry(key, |entry| entry.insert(value))
+    }
+
+    pub fn get_cloned(&self, key: &K) -> Option<V>
+    where
+        V: Clone,
+    {
+        let hash = self.build.hash_one(key);
+        let shard = self.shard(hash).lock().ok()?;
+
+        shard
+            .find(hash, key)
+            .map(|(bucket, pos)| shard.buckets[bucket][pos].1.clone())
+    }
+
+    pub fn contains_key(&self, key: &K) -> bool {
+        let hash = self.build.hash_one(key);
+
+        self.shard(hash)
+            .lock()
+            .map(|shard| shard.find(hash, key).is_some())
+            .unwrap_or(false)
+    }
+
+    pub fn remove(&self, key: &K) -> Option<V> {
+        let hash = self.build.hash_one(key);
+        let mut shard = self.shard(hash).lock().ok()?;
+
+        let (bucket, pos) = shard.find(hash, key)?;
+
+        shard.len -= 1;
+
+        Some(shard.buckets[bucket].swap_remove(pos).1)
+    }
+
+    /// retain only the elements specified by the predicate
+    pub fn retain<F: FnMut(&K, &mut V) -> bool>(&self, mut f: F) {
+        for shard in &self.shards {
+            if let Ok(mut shard) = shard.lock() {
+                let mut removed = 0;
+
+                for bucket in shard.buckets.iter_mut() {
+                    let before = bucket.len();
+                    bucket.retain_mut(|(k, v)| f(k, v));
+                    removed += before - bucket.len();
+                }
+
+                shard.len -= removed;
+            }
+        }
+    }
+
+    /// visit all the elements, shards are locked one at a time
+    pub fn for_each<F: FnMut(&K, &V)>(&self, mut f: F) {
+        for shard in &self.shards {
+            if let Ok(shard) = shard.lock() {
+                for (k, v) in shard.buckets.iter().flatten() {
+                    f(k, v);
+                }
+            }
+        }
+    }
+
+    /// run `f` with the entry of `key` while the shard lock is held
+    ///
+    /// room for one more element is reserved before `f` is called, so inserting through the `Entry` never fails
+    pub fn entry<R, F: FnOnce(&mut Entry<'_, K, V>) -> R>(
+        &self,
+        key: K,
+        f: F,
+    ) -> Result<R, NtError> {
+        let hash = self.build.hash_one(&key);
+        let mut shard = self.shard(hash).lock()?;
+
+        let slot = match shard.find(hash, &key) {
+            Some(found) => Some(found),
+            None => {
+                shard.reserve_one(hash, &self.build)?;
+                None
+            }
+        };
+
+        let mut entry = Entry {
+            shard: &mut *shard,
+            hash,
+            key: Some(key),
+            slot,
+        };
+
+        Ok(f(&mut entry))
+    }
+
+    /// returns the number of elements, it is only a snapshot
+    pub fn len(&self) -> usize {
+        self.shards
+            .iter()
+            .map(|shard| shard.lock().map(|s| s.len).unwrap_or(0))
+            .sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// A view into a single slot of a `KHashMap`, only lives while the shard lock is held
+pub struct Entry<'a, K, V> {
+    shard: &'a mut Shard<K, V>,
+    hash: u64,
+    /// `None` once the key has been moved into the map
+    key: Option<K>,
+    slot: Option<(usize, usize)>,
+}
+
+impl<'a, K: Hash + Eq, V> Entry<'a, K, V> {
+    pub fn is_occupied(&self) -> bool {
+        self.slot.is_some()
+    }
+
+    pub fn get(&self) -> Option<&V> {
+        self.slot
+            .map(|(bucket, pos)| &self.shard.buckets[bucket][pos].1)
+    }
+
+    pub fn get_mut(&mut self) -> Option<&mut V> {
+        self.slot
+            .map(|(bucket, pos)| &mut self.shard.buckets[bucket][pos].1)
+    }
+
+    /// set the value of this entry and returns the old one if any
+    pub fn insert(&mut self, value: V) -> Option<V> {
+        match self.slot {
+            Some((bucket, pos)) => {
+                Some(mem::replace(&mut self.shard.buckets[bucket][pos].1, value))
+            }
+            None => {
+                self.push(value);
+                None
+            }
+        }
+    }
+
+    /// ensure a value is in the entry by inserting the result of `f` if it is vacant
+    pub fn or_insert_with<F: FnOnce() -> V>(&mut self, f: F) -> &mut V {
+        if self.slot.is_none() {
+            self.push(f());
+        }
+
+        self.get_mut().unwrap()
+    }
+
+    /// remove the entry from the map and returns its value
+    pub fn remove(&mut self) -> Option<V> {
+        let (bucket, pos) = self.slot.take()?;
+
+        self.shard.len -= 1;
+
+        let (key, value) = self.shard.buckets[bucket].swap_remove(pos);
+
+        // keep the key so that the entry can be re-inserted
+        self.key = Some(key);
+
+        Some(value)
+    }
+
+    fn push(&mut self, value: V) {
+        // room has been reserved in `KHashMap::entry`
+        let Some(key) = self.key.take() else {
+            return;
+        };
+
+        let bucket = self.shard.bucket_of(self.hash);
+
+        // a vacant entry has a reserved room, and a removed entry has left its room in the bucket
+        self.shard.push_reserved(bucket, key, value);
+        self.shard.len += 1;
+
+        self.slot = Some((bucket, self.shard.buckets[bucket].len() - 1));
+    }
+}
@@ -0,0 +1,135 @@
+//! this module provides a concurrent handle table that maps u64 cookies to driver objects
+//!
+//! it is designed for mapping user-mode handles or filter contexts to driver state, every IOCTL-serving driver needs one
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+
+use alloc::{collections::BTreeMap, vec::Vec};
+
+use crate::{mutex::SpinLocked, ntstatus::NtError};
+
+const SHARD_COUNT: usize = 16;
+
+/// A concurrent object registry keyed by u64 cookie
+///
+/// the table is split into `SHARD_COUNT` buckets and each bucket is guarded by a spin lock,
+/// so it can be used at IRQL <= DISPATCH_LEVEL
+///
+/// # Note
+/// since the value can only be touched while the bucket lock is held, `get` returns a clone of `T`,
+/// wrap the state in a `KArc<T>` to share it cheaply
+///
+/// # Example
+/// ```
+/// static SESSIONS: LazyLock<HandleTable<KArc<Session>>> = LazyLock::new(|| HandleTable::new().unwrap());
+///
+/// fn open_session() -> Result<u64, NtError> {
+///     SESSIONS.insert(KArc::new(Session::new())?)
+/// }
+///
+/// fn session_ioctl(cookie: u64) -> Result<u64, NtError> {
+///     let session = SESSIONS.get(cookie).ok_or(NtError::new(STATUS_INVALID_HANDLE))?;
+///     // ...
+/// }
+///
+/// fn close_session(cookie: u64) {
+///     let _ = SESSIONS.remove(cookie);
+/// }
+/// ```
+pub struct HandleTable<T> {
+    shards: Vec<SpinLocked<BTreeMap<u64, T>>>,
+    next_cookie: AtomicU64,
+    count: AtomicUsize,
+}
+
+impl<T> HandleTable<T> {
+    pub fn new() -> Result<Self, NtError> {
+        let mut shards = Vec::with_capacity(SHARD_COUNT);
+
+        for _ in 0..SHARD_COUNT {
+            shards.push(SpinLocked::new(BTreeMap::new())?);
+        }
+
+        Ok(Self {
+            shards,
+            // 0 is never a valid cookie
+            next_cookie: AtomicU64::new(1),
+            count: AtomicUsize::new(0),
+        })
+    }
+
+    #[inline]
+    fn shard(&self, cookie: u64) -> &SpinLocked<BTreeMap<u64, T>> {
+        &self.shards[(cookie % SHARD_COUNT as u64) as usize]
+    }
+
+    /// insert `value` into the table and returns a new unique cookie for it
+    pub fn insert(&self, value: T) -> Result<u64, NtError> {
+        let cookie = self.next_cookie.fetch_add(1, Ordering::Relaxed);
+
+        self.shard(cookie).lock()?.insert(cookie, value);
+        self.count.fetch_add(1, Ordering::Relaxed);
+
+        Ok(cookie)
+    }
+
+    /// returns a clone of the value associated with `cookie`
+    pub fn get(&self, cookie: u64) -> Option<T>
+    where
+        T: Clone,
+    {
+        self.with(cookie, |value| value.clone())
+    }
+
+    /// run `f` with the value associated with `cookie` while the bucket lock is held
+    pub fn with<R, F: FnOnce(&T) -> R>(&self, cookie: u64, f: F) -> Option<R> {
+        let shard = self.shard(cookie).lock().ok()?;
+
+        shard.get(&cookie).map(f)
+    }
+
+    /// remove the value associated with `cookie` and returns it
+    pub fn remove(&self, cookie: u64) -> Option<T> {
+        let value = self.shard(cookie).lock().ok()?.remove(&cookie);
+
+        if value.is_some() {
+            self.count.fetch_sub(1, Ordering::Relaxed);
+        }
+
+        value
+    }
+
+    /// visit all the entries, buckets are locked one at a time
+    ///
+    /// # Note
+    /// `f` is called while a spin lock is held, keep it short and never touch this table inside `f`
+    pub fn for_each<F: FnMut(u64, &T)>(&self, mut f: F) {
+        for shard in &self.shards {
+            if let Ok(entries) = shard.lock() {
+                for (cookie, value) in entries.iter() {
+                    f(*cookie, value);
+                }
+            }
+        }
+    }
+
+    /// remove all the entries and drop them
+    pub fn clear(&self) {
+        for shard in &self.shards {
+            if let Ok(mut entries) = shard.lock() {
+                let removed = entries.len();
+
+                entries.clear();
+                self.count.fetch_sub(removed, Ordering::Relaxed);
+            }
+        }
+    }
+
+    /// returns the number of entries in the table, it is only a snapshot
+    pub fn len(&self) -> usize {
+        self.count.load(Ordering::Relaxed)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
@@ -0,0 +1,184 @@
+//! this module provides the interlocked operations which `core::sync::atomic` does not cover
+//!
+//! - 128-bit compare-exchange(`InterlockedCompareExchange128`), required by the lock-free structures which pair a
+//!   pointer with a sequence number to avoid the ABA problem, like SLIST
+//! - interlocked bit operations on a bitmap of any size, e.g. a `RTL_BITMAP` buffer shared between processors
+//! - explicit memory barriers, like `KeMemoryBarrier`
+//!
+//! # Example
+//! ```
+//! // a tagged pointer: the low 64 bits are the pointer and the high 64 bits are a sequence number
+//! static HEAD: Atomic128 = Atomic128::new(0);
+//!
+//! let mut current = HEAD.load();
+//! loop {
+//!     let next = (((current >> 64) + 1) << 64) | new_node as u128;
+//!     match HEAD.compare_exchange(current, next) {
+//!         Ok(_) => break,
+//!         Err(actual) => current = actual,
+//!     }
+//! }
+//! ```
+use core::{
+    arch::asm,
+    cell::UnsafeCell,
+    sync::atomic::{AtomicU64, Ordering, compiler_fence, fence},
+};
+
+/// compare `*dest` with `current` and replace it with `new` if they are equal, atomically
+///
+/// returns `Ok(current)` on success, otherwise `Err` of the actual value, like `AtomicU64::compare_exchange` with
+/// `SeqCst` ordering
+///
+/// # Safety
+/// `dest` must be valid for reads and writes and aligned on 16 bytes
+#[inline]
+pub unsafe fn compare_exchange_128(
+    dest: *mut u128,
+    current: u128,
+    new: u128,
+) -> Result<u128, u128> {
+    debug_assert!(
+        dest as usize % 16 == 0,
+        "compare_exchange_128 requires 16-byte alignment"
+    );
+
+    let previous_low: u64;
+    let previous_high: u64;
+    let exchanged: u8;
+
+    // `rbx` is reserved by LLVM, so the low half of `new` is swapped in and out around `cmpxchg16b`
+    unsafe {
+        asm!(
+            "xchg {new_low}, rbx",
+            "lock cmpxchg16b xmmword ptr [{dest}]",
+            "setz {exchanged}",
+            "mov rbx, {new_low}",
+            dest = in(reg) dest,
+            new_low = inout(reg) new as u64 => _,
+            exchanged = out(reg_byte) exchanged,
+            inout("rax") current as u64 => previous_low,
+            inout("rdx") (current >> 64) as u64 => previous_high,
+            in("rcx") (new >> 64) as u64,
+            options(nostack),
+        );
+    }
+
+    let previous = (previous_high as u128) << 64 | previous_low as u128;
+
+    if exchanged != 0 {
+        Ok(previous)
+    } else {
+        Err(previous)
+    }
+}
+
+/// A 128-bit integer which can be accessed atomically, it is aligned on 16 bytes
+#[repr(C, align(16))]
+pub struct Atomic128(UnsafeCell<u128>);
+
+impl Atomic128 {
+    pub const fn new(value: u128) -> Self {
+        Self(UnsafeCell::new(value))
+    }
+
+    /// read the value atomically, it is a compare-exchange which never replaces the value
+    pub fn load(&self) -> u128 {
+        match unsafe { compare_exchange_128(self.0.get(), 0, 0) } {
+            Ok(value) | Err(value) => value,
+        }
+    }
+
+    pub fn store(&self, value: u128) {
+        let _ = self.swap(value);
+    }
+
+    pub fn swap(&self, value: u128) -> u128 {
+        let mut current = self.load();
+
+        loop {
+            match self.compare_exchange(current, value) {
+                Ok(previous) => return previous,
+                Err(actual) => current = actual,
+            }
+        }
+    }
+
+    pub fn compare_exchange(&self, current: u128, new: u128) -> Result<u128, u128> {
+        unsafe { compare_exchange_128(self.0.get(), current, new) }
+    }
+
+    pub fn get_mut(&mut self) -> &mut u128 {
+        self.0.get_mut()
+    }
+
+    pub fn into_inner(self) -> u128 {
+        self.0.into_inner()
+    }
+}
+
+unsafe impl Sync for Atomic128 {}
+
+#[inline]
+unsafe fn bit_word<'a>(base: *mut u64, bit: usize) -> (&'a AtomicU64, u64) {
+    (
+        unsafe { AtomicU64::from_ptr(base.add(bit / 64)) },
+        1 << (bit % 64),
+    )
+}
+
+/// set the `bit` of the bitmap at `base` and returns its previous value, like `InterlockedBitTestAndSet64`
+///
+/// # Safety
+/// `base` must be aligned on 8 bytes and the bitmap must be valid for at least `bit + 1` bits, rounded up to 64 bits
+#[inline]
+pub unsafe fn bit_test_and_set(base: *mut u64, bit: usize) -> bool {
+    let (word, mask) = unsafe { bit_word(base, bit) };
+
+    word.fetch_or(mask, Ordering::SeqCst) & mask != 0
+}
+
+/// clear the `bit` and returns its previous value, see `bit_test_and_set`
+///
+/// # Safety
+/// the same as `bit_test_and_set`
+#[inline]
+pub unsafe fn bit_test_and_reset(base: *mut u64, bit: usize) -> bool {
+    let (word, mask) = unsafe { bit_word(base, bit) };
+
+    word.fetch_and(!mask, Ordering::SeqCst) & mask != 0
+}
+
+/// flip the `bit` and returns its previous value, see `bit_test_and_set`
+///
+/// # Safety
+/// the same as `bit_test_and_set`
+#[inline]
+pub unsafe fn bit_test_and_complement(base: *mut u64, bit: usize) -> bool {
+    let (word, mask) = unsafe { bit_word(base, bit) };
+
+    word.fetch_xor(mask, Ordering::SeqCst) & mask != 0
+}
+
+/// read the `bit`, see `bit_test_and_set`
+///
+/// # Safety
+/// the same as `bit_test_and_set`
+#[inline]
+pub unsafe fn bit_test(base: *mut u64, bit: usize) -> bool {
+    let (word, mask) = unsafe { bit_word(base, bit) };
+
+    word.load(Ordering::SeqCst) & mask != 0
+}
+
+/// a full memory barrier for both the processor and the compiler, like `KeMemoryBarrier`
+#[inline]
+pub fn memory_barrier() {
+    fence(Ordering::SeqCst);
+}
+
+/// a barrier which only prevents the compiler from reordering memory accesses, like `KeMemoryBarrierWithoutFence`
+#[inline]
+pub fn compiler_barrier() {
+    compiler_fence(Ordering::SeqCst);
+}
@@ -0,0 +1,510 @@
+//! this module provides helpers for IRP processing
+//!
+//! the inline functions and macros of wdm.h that are not exported by the kernel are reimplemented here,
+//! and `Irp<'a>` is a safe view of an IRP inside a dispatch routine
+//!
+//! the IRPs can be sent to a lower driver in three ways:
+//! - `pass_down`, the lower driver completes the IRP, nothing is done after that
+//! - `forward_and_wait`, the IRP is given back when the lower driver completes it, e.g. IRP_MN_START_DEVICE
+//! - `on_completion`, a closure runs in the completion of the lower driver
+//!
+//! # Example
+//! ```
+//! fn dispatch(&self, device: PDEVICE_OBJECT, irp: PIRP) -> Result<u64, NtError> {
+//!     let mut irp = unsafe { Irp::from_raw(irp) };
+//!
+//!     match irp.ioctl_code() {
+//!         IOCTL_QUERY_VERSION => {
+//!             *irp.system_buffer::<u32>()? = VERSION;
+//!             Ok(mem::size_of::<u32>() as _)
+//!         }
+//!         _ => Err(NtError::new(STATUS_INVALID_DEVICE_REQUEST)),
+//!     }
+//! }
+//! ```
+use core::{marker::PhantomData, mem, ptr, slice};
+
+use alloc::boxed::Box;
+
+use wdk_sys::{
+    _EVENT_TYPE::NotificationEvent,
+    _KWAIT_REASON::Executive,
+    _MEMORY_CACHING_TYPE::MmCached,
+    _MM_PAGE_PRIORITY::NormalPagePriority,
+    _MODE::KernelMode,
+    DO_BUFFERED_IO, IO_NO_INCREMENT, IO_STACK_LOCATION, IRP, IRP_MJ_DEVICE_CONTROL,
+    IRP_MJ_INTERNAL_DEVICE_CONTROL, IRP_MJ_READ, IRP_MJ_WRITE, KEVENT, KPROCESSOR_MODE,
+    MDL_MAPPED_TO_SYSTEM_VA, MDL_SOURCE_IS_NONPAGED_POOL, METHOD_BUFFERED, NTSTATUS,
+    PDEVICE_OBJECT, PIO_COMPLETION_ROUTINE, PIO_STACK_LOCATION, PIRP, PKEVENT, PMDL, PVOID,
+    SL_INVOKE_ON_CANCEL, SL_INVOKE_ON_ERROR, SL_INVOKE_ON_SUCCESS, SL_PENDING_RETURNED,
+    STATUS_BUFFER_TOO_SMALL, STATUS_INSUFFICIENT_RESOURCES, STATUS_INVALID_DEVICE_REQUEST,
+    STATUS_INVALID_PARAMETER, STATUS_MORE_PROCESSING_REQUIRED, STATUS_PENDING, STATUS_SUCCESS,
+    ULONG, ULONG_PTR,
+    ntddk::{
+        IofCallDriver, IofCompleteRequest, KeInitializeEvent, KeSetEvent, KeWaitForSingleObject,
+        MmMapLockedPagesSpecifyCache,
+    },
+};
+
+use crate::{
+    ntstatus::NtError,
+    pod::{self, Pod},
+    utils,
+};
+
+/// MdlMappingNoExecute
+pub(crate) const MDL_MAPPING_NO_EXECUTE: ULONG = 0x40000000;
+
+#[allow(non_snake_case)]
+pub fn IoGetCurrentIrpStackLocation(irp: PIRP) -> PIO_STACK_LOCATION {
+    if !(unsafe { (*irp).CurrentLocation <= (*irp).StackCount }) {
+        panic!();
+    }
+
+    unsafe {
+        (*irp)
+            .Tail
+            .Overlay
+            .__bindgen_anon_2
+            .__bindgen_anon_1
+            .CurrentStackLocation
+    }
+}
+
+#[allow(non_snake_case)]
+pub fn IoMarkIrpPending(irp: PIRP) {
+    unsafe { (*IoGetCurrentIrpStackLocation(irp)).Control |= SL_PENDING_RETURNED as u8 };
+}
+
+#[allow(non_snake_case)]
+pub fn IoGetNextIrpStackLocation(irp: PIRP) -> PIO_STACK_LOCATION {
+    unsafe { IoGetCurrentIrpStackLocation(irp).sub(1) }
+}
+
+/// copy the current stack location to the next one, except the completion routine
+#[allow(non_snake_case)]
+pub fn IoCopyCurrentIrpStackLocationToNext(irp: PIRP) {
+    let current = IoGetCurrentIrpStackLocation(irp);
+    let next = IoGetNextIrpStackLocation(irp);
+
+    unsafe {
+        ptr::copy_nonoverlapping(
+            current.cast::<u8>(),
+            next.cast::<u8>(),
+            mem::offset_of!(IO_STACK_LOCATION, CompletionRoutine),
+        );
+
+        (*next).Control = 0;
+    }
+}
+
+/// set the completion routine of the next stack location, it is always invoked on success, error and cancel
+#[allow(non_snake_case)]
+pub fn IoSetCompletionRoutine(irp: PIRP, routine: PIO_COMPLETION_ROUTINE, context: PVOID) {
+    let next = IoGetNextIrpStackLocation(irp);
+
+    unsafe {
+        (*next).CompletionRoutine = routine;
+        (*next).Context = context;
+        (*next).Control = (SL_INVOKE_ON_SUCCESS | SL_INVOKE_ON_ERROR | SL_INVOKE_ON_CANCEL) as _;
+    }
+}
+
+/// let the next lower driver use the current stack location, the IRP must not be touched after it is passed down
+#[allow(non_snake_case)]
+pub fn IoSkipCurrentIrpStackLocation(irp: PIRP) {
+    unsafe {
+        let location = &mut (*irp)
+            .Tail
+            .Overlay
+            .__bindgen_anon_2
+            .__bindgen_anon_1
+            .CurrentStackLocation;
+
+        (*irp).CurrentLocation += 1;
+        *location = location.add(1);
+    }
+}
+
+#[allow(non_snake_case)]
+pub fn IoCallDriver(device: PDEVICE_OBJECT, irp: PIRP) -> NTSTATUS {
+    unsafe { IofCallDriver(device, irp) }
+}
+
+/// pass an IRP down to `device` without a completion routine, the status of the lower driver is returned as is
+///
+/// it is what a filter returns from its dispatch routine for the IRPs it is not interested in
+pub fn pass_down(device: PDEVICE_OBJECT, irp: PIRP) -> NTSTATUS {
+    IoSkipCurrentIrpStackLocation(irp);
+
+    IoCallDriver(device, irp)
+}
+
+extern "C" fn signal_completion(_device: PDEVICE_OBJECT, _irp: PIRP, context: PVOID) -> NTSTATUS {
+    unsafe { KeSetEvent(context as PKEVENT, IO_NO_INCREMENT as _, 0) };
+
+    // the IRP is given back to the waiter in `forward_and_wait`
+    STATUS_MORE_PROCESSING_REQUIRED
+}
+
+/// send an IRP to `device` and wait until the lower driver completes it, the status of the IRP is returned
+///
+/// the IRP still belongs to the caller after that, it must be completed again(or freed if it is created by the caller)
+/// it must be called at PASSIVE_LEVEL
+///
+/// # Example
+/// ```
+/// // IRP_MN_START_DEVICE is handled by the lower driver first, a failure is returned as the status of the IRP
+/// check(irp::forward_and_wait(lower, irp))?;
+///
+/// start()?;
+///
+/// Ok(0)
+/// ```
+pub fn forward_and_wait(device: PDEVICE_OBJECT, irp: PIRP) -> NTSTATUS {
+    let mut event = KEVENT::default();
+
+    unsafe { KeInitializeEvent(&mut event, NotificationEvent, 0) };
+
+    IoCopyCurrentIrpStackLocationToNext(irp);
+    IoSetCompletionRoutine(irp, Some(signal_completion), (&mut event as PKEVENT).cast());
+
+    let mut status = IoCallDriver(device, irp);
+
+    if status == STATUS_PENDING {
+        unsafe {
+            KeWaitForSingleObject(
+                (&mut event as PKEVENT).cast(),
+                Executive,
+                KernelMode as _,
+                0,
+                ptr::null_mut(),
+            );
+        }
+
+        status = unsafe { (*irp).IoStatus.__bindgen_anon_1.Status };
+    }
+
+    status
+}
+
+/// What to do with an IRP after a completion closure of `on_completion` returns
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Completion {
+    /// the completion goes on to the upper drivers
+    Continue,
+    /// the closure keeps the IRP, it must be completed again(or freed) by the closure or a later code
+    MoreProcessingRequired,
+}
+
+extern "C" fn completion_stub<F>(device: PDEVICE_OBJECT, irp: PIRP, context: PVOID) -> NTSTATUS
+where
+    F: FnOnce(PDEVICE_OBJECT, PIRP) -> Completion,
+{
+    let f = unsafe { Box::from_raw(context as *mut F) };
+
+    match f(device, irp) {
+        Completion::Continue => {
+            // the pending state must be propagated to the upper stack location when the completion goes on
+            if unsafe { (*irp).PendingReturned } != 0 {
+                IoMarkIrpPending(irp);
+            }
+
+            STATUS_SUCCESS
+        }
+        Completion::MoreProcessingRequired => STATUS_MORE_PROCESSING_REQUIRED,
+    }
+}
+
+/// copy the current stack location to the next one and set `f` as its completion routine, the IRP is then sent
+/// down with `IoCallDriver` by the caller
+///
+/// `f` is called exactly once at IRQL <= DISPATCH_LEVEL, it is leaked if the IRP is never sent down
+///
+/// # Example
+/// ```
+/// irp::on_completion(irp, |_, irp| {
+///     let bytes = unsafe { (*irp).IoStatus.Information };
+///     STATS.read.fetch_add(bytes as _, Ordering::Relaxed);
+///
+///     Completion::Continue
+/// })?;
+///
+/// IoCallDriver(lower, irp)
+/// ```
+pub fn on_completion<F>(irp: PIRP, f: F) -> Result<(), NtError>
+where
+    F: FnOnce(PDEVICE_OBJECT, PIRP) -> Completion + Send + 'static,
+{
+    let context = utils::try_box(f)?;
+
+    IoCopyCurrentIrpStackLocationToNext(irp);
+    IoSetCompletionRoutine(
+        irp,
+        Some(completion_stub::<F>),
+        Box::into_raw(context).cast(),
+    );
+
+    Ok(())
+}
+
+/// returns a system address of the buffer described by `mdl`, null if the mapping failed
+///
+/// # Parameters
+/// - priority: one of `MM_PAGE_PRIORITY`, `MdlMappingNoExecute` is always applied
+#[allow(non_snake_case)]
+pub fn MmGetSystemAddressForMdlSafe(mdl: PMDL, priority: ULONG) -> PVOID {
+    unsafe {
+        if (*mdl).MdlFlags as u32 & (MDL_MAPPED_TO_SYSTEM_VA | MDL_SOURCE_IS_NONPAGED_POOL) != 0 {
+            (*mdl).MappedSystemVa
+        } else {
+            MmMapLockedPagesSpecifyCache(
+                mdl,
+                KernelMode as _,
+                MmCached,
+                ptr::null_mut(),
+                0,
+                priority | MDL_MAPPING_NO_EXECUTE,
+            )
+        }
+    }
+}
+
+/// A borrowed IRP in its current stack location
+pub struct Irp<'a> {
+    irp: PIRP,
+    _phantom: PhantomData<&'a mut IRP>,
+}
+
+impl<'a> Irp<'a> {
+    /// # Safety
+    /// `irp` must be a valid IRP owned by the caller(i.e. not completed or passed down yet) during `'a`
+    pub unsafe fn from_raw(irp: PIRP) -> Self {
+        Self {
+            irp,
+            _phantom: PhantomData,
+        }
+    }
+
+    pub fn as_raw(&self) -> PIRP {
+        self.irp
+    }
+
+    pub fn stack_location(&self) -> &IO_STACK_LOCATION {
+        unsafe { &*IoGetCurrentIrpStackLocation(self.irp) }
+    }
+
+    pub fn major_function(&self) -> u32 {
+        self.stack_location().MajorFunction as _
+    }
+
+    pub fn minor_function(&self) -> u32 {
+        self.stack_location().MinorFunction as _
+    }
+
+    /// UserMode if the request comes from user mode, the buffers must be probed before being accessed
+    pub fn requestor_mode(&self) -> KPROCESSOR_MODE {
+        unsafe { (*self.irp).RequestorMode }
+    }
+
+    fn is_device_control(&self) -> bool {
+        matches!(
+            self.major_function(),
+            IRP_MJ_DEVICE_CONTROL | IRP_MJ_INTERNAL_DEVICE_CONTROL
+        )
+    }
+
+    /// the I/O control code of a (internal) device control request, 0 for the other requests
+    pub fn ioctl_code(&self) -> u32 {
+        if self.is_device_control() {
+            unsafe {
+                self.stack_location()
+                    .Parameters
+                    .DeviceIoControl
+                    .IoControlCode
+            }
+        } else {
+            0
+        }
+    }
+
+    /// the input buffer length of a device control request or the length of a write request
+    pub fn input_buffer_len(&self) -> usize {
+        let stack = self.stack_location();
+
+        unsafe {
+            match self.major_function() {
+                IRP_MJ_DEVICE_CONTROL | IRP_MJ_INTERNAL_DEVICE_CONTROL => {
+                    stack.Parameters.DeviceIoControl.InputBufferLength as _
+                }
+                IRP_MJ_WRITE => stack.Parameters.Write.Length as _,
+                _ => 0,
+            }
+        }
+    }
+
+    /// the output buffer length of a device control request or the length of a read request
+    pub fn output_buffer_len(&self) -> usize {
+        let stack = self.stack_location();
+
+        unsafe {
+            match self.major_function() {
+                IRP_MJ_DEVICE_CONTROL | IRP_MJ_INTERNAL_DEVICE_CONTROL => {
+                    stack.Parameters.DeviceIoControl.OutputBufferLength as _
+                }
+                IRP_MJ_READ => stack.Parameters.Read.Length as _,
+                _ => 0,
+            }
+        }
+    }
+
+    /// the length of the buffer the caller supplied in `UserBuffer` or `MdlAddress`
+    pub fn user_buffer_len(&self) -> usize {
+        match self.major_function() {
+            IRP_MJ_WRITE => self.input_buffer_len(),
+            _ => self.output_buffer_len(),
+        }
+    }
+
+    /// the raw user buffer, it is a user mode address if `requestor_mode()` is UserMode
+    pub fn user_buffer(&self) -> PVOID {
+        unsafe { (*self.irp).UserBuffer }
+    }
+
+    /// check that `SystemBuffer` is the buffer of the request, i.e. a METHOD_BUFFERED control code or a read/write
+    /// of a DO_BUFFERED_IO device, it fails with STATUS_INVALID_DEVICE_REQUEST otherwise
+    fn check_buffered(&self) -> Result<(), NtError> {
+        let buffered = match self.major_function() {
+            IRP_MJ_DEVICE_CONTROL | IRP_MJ_INTERNAL_DEVICE_CONTROL => {
+                self.ioctl_code() & 3 == METHOD_BUFFERED
+            }
+            IRP_MJ_READ | IRP_MJ_WRITE => {
+                let device = self.stack_location().DeviceObject;
+
+                !device.is_null() && unsafe { (*device).Flags } & DO_BUFFERED_IO != 0
+            }
+            _ => false,
+        };
+
+        if !buffered {
+            return Err(NtError::new(STATUS_INVALID_DEVICE_REQUEST));
+        }
+
+        Ok(())
+    }
+
+    /// the system buffer of a buffered I/O request, its length is the larger one of the input and output length
+    ///
+    /// it fails with STATUS_INVALID_DEVICE_REQUEST if the request does not use buffered I/O
+    pub fn system_buffer_bytes(&mut self) -> Result<&mut [u8], NtError> {
+        self.check_buffered()?;
+
+        let buffer = unsafe { (*self.irp).AssociatedIrp.SystemBuffer };
+        let len = self.input_buffer_len().max(self.output_buffer_len());
+
+        if buffer.is_null() || len == 0 {
+            return Ok(&mut []);
+        }
+
+        Ok(unsafe { slice::from_raw_parts_mut(buffer.cast(), len) })
+    }
+
+    /// view the system buffer of a buffered I/O request as a `T`
+    ///
+    /// returns STATUS_BUFFER_TOO_SMALL if the buffer can not hold a `T`, or STATUS_INVALID_DEVICE_REQUEST if the
+    /// request does not use buffered I/O
+    pub fn system_buffer<T: Pod>(&mut self) -> Result<&mut T, NtError> {
+        self.check_buffered()?;
+
+        let buffer = unsafe { (*self.irp).AssociatedIrp.SystemBuffer };
+
+        if buffer.is_null() || buffer as usize % mem::align_of::<T>() != 0 {
+            return Err(NtError::new(STATUS_INVALID_PARAMETER));
+        }
+
+        if self.input_buffer_len().max(self.output_buffer_len()) < mem::size_of::<T>() {
+            return Err(NtError::new(STATUS_BUFFER_TOO_SMALL));
+        }
+
+        Ok(unsafe { &mut *buffer.cast() })
+    }
+
+    /// copy a `T` out of the input of a buffered I/O request
+    ///
+    /// returns STATUS_BUFFER_TOO_SMALL if the input is shorter than `T`
+    pub fn read_input<T: Pod>(&mut self) -> Result<T, NtError> {
+        let len = self.input_buffer_len();
+        let buffer = self.system_buffer_bytes()?;
+
+        pod::read_from(&buffer[..len.min(buffer.len())])
+    }
+
+    /// copy `value` to the output of a buffered I/O request, returns the size of `T` to be set as the information
+    ///
+    /// returns STATUS_BUFFER_TOO_SMALL if the output is shorter than `T`
+    pub fn write_output<T: Pod>(&mut self, value: &T) -> Result<u64, NtError> {
+        let len = self.output_buffer_len();
+        let buffer = self.system_buffer_bytes()?;
+        let len = len.min(buffer.len());
+
+        pod::write_to(value, &mut buffer[..len]).map(|n| n as _)
+    }
+
+    pub fn mdl(&self) -> PMDL {
+        unsafe { (*self.irp).MdlAddress }
+    }
+
+    /// map the MDL of a direct I/O request into system space
+    pub fn map_mdl(&mut self) -> Result<&mut [u8], NtError> {
+        let mdl = self.mdl();
+
+        if mdl.is_null() {
+            return Err(NtError::new(STATUS_INVALID_PARAMETER));
+        }
+
+        let buffer = MmGetSystemAddressForMdlSafe(mdl, NormalPagePriority as _);
+
+        if buffer.is_null() {
+            return Err(NtError::new(STATUS_INSUFFICIENT_RESOURCES));
+        }
+
+        Ok(unsafe { slice::from_raw_parts_mut(buffer.cast(), (*mdl).ByteCount as _) })
+    }
+
+    pub fn status(&self) -> NTSTATUS {
+        unsafe { (*self.irp).IoStatus.__bindgen_anon_1.Status }
+    }
+
+    pub fn set_status(&mut self, status: NTSTATUS) {
+        unsafe { (*self.irp).IoStatus.__bindgen_anon_1.Status = status };
+    }
+
+    pub fn set_information(&mut self, information: ULONG_PTR) {
+        unsafe { (*self.irp).IoStatus.Information = information };
+    }
+
+    /// mark the IRP as pending, the dispatch routine must return STATUS_PENDING after this
+    pub fn mark_pending(&mut self) {
+        IoMarkIrpPending(self.irp);
+    }
+
+    /// pass the IRP down to the lower device `device`, see `irp::pass_down`
+    pub fn pass_down(self, device: PDEVICE_OBJECT) -> NTSTATUS {
+        pass_down(device, self.irp)
+    }
+
+    /// send the IRP to `device` and wait for its completion, see `irp::forward_and_wait`
+    pub fn forward_and_wait(&mut self, device: PDEVICE_OBJECT) -> NTSTATUS {
+        forward_and_wait(device, self.irp)
+    }
+
+    /// complete the IRP, it is consumed since the IRP must not be touched after completion
+    pub fn complete(self, status: NTSTATUS, information: ULONG_PTR) {
+        unsafe {
+            (*self.irp).IoStatus.__bindgen_anon_1.Status = status;
+            (*self.irp).IoStatus.Information = information;
+
+            IofCompleteRequest(self.irp, IO_NO_INCREMENT as _);
+        }
+    }
+}
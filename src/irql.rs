@@ -0,0 +1,272 @@
+//! this module provides the debug-only IRQL checks
+//!
+//! `assert_irql!` checks the current IRQL once, `irql_scope!` checks it at the call site and again when the
+//! enclosing scope is left, which catches a lock or a guard which raised the IRQL and was not released, both of them
+//! panic with the expected and the current IRQL, and expand to nothing when `debug_assertions` is off
+//!
+//! the blocking APIs of this crate, e.g. `this_thread::sleep`, `JoinHandle::join` or `Dispatchable::wait`, are
+//! checked this way, so a call at a wrong IRQL fails at once in a debug build rather than as a sporadic bugcheck
+//! when the wait happens to block
+//!
+//! `RaiseIrqlGuard` raises the IRQL for a scope and restores it on drop, e.g. to access per-processor data without
+//! being preempted by a DPC
+//!
+//! # Example
+//! ```
+//! fn reload_config(&self) -> Result<(), NtError> {
+//!     irql_scope!(passive);
+//!
+//!     // ...
+//! }
+//!
+//! extern "C" fn dpc_routine(/* ... */) {
+//!     assert_irql!(== DISPATCH_LEVEL);
+//!
+//!     // ...
+//! }
+//!
+//! let count = irql::with_dispatch_level(|| per_cpu_counter().get());
+//! ```
+use core::{arch::asm, marker::PhantomData};
+
+use wdk_sys::{KIRQL, ntddk::KeGetCurrentIrql};
+
+// KfRaiseIrql and KeLowerIrql are FORCEINLINE on x64 and not exported, they write the IRQL to CR8 as the WDK
+// headers do
+#[allow(non_snake_case)]
+#[inline(always)]
+unsafe fn KfRaiseIrql(NewIrql: KIRQL) -> KIRQL {
+    let old: u64;
+
+    unsafe {
+        asm!("mov {}, cr8", out(reg) old, options(nomem, nostack, preserves_flags));
+        asm!("mov cr8, {}", in(reg) NewIrql as u64, options(nostack, preserves_flags));
+    }
+
+    old as KIRQL
+}
+
+#[allow(non_snake_case)]
+#[inline(always)]
+unsafe fn KeLowerIrql(NewIrql: KIRQL) {
+    unsafe {
+        asm!("mov cr8, {}", in(reg) NewIrql as u64, options(nostack, preserves_flags));
+    }
+}
+
+pub const PASSIVE_LEVEL: KIRQL = 0;
+pub const APC_LEVEL: KIRQL = 1;
+pub const DISPATCH_LEVEL: KIRQL = 2;
+pub const HIGH_LEVEL: KIRQL = 15;
+
+/// The comparison of an IRQL check
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum IrqlOp {
+    Eq,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+impl IrqlOp {
+    pub fn test(self, irql: KIRQL, level: KIRQL) -> bool {
+        match self {
+            IrqlOp::Eq => irql == level,
+            IrqlOp::Lt => irql < level,
+            IrqlOp::Le => irql <= level,
+            IrqlOp::Gt => irql > level,
+            IrqlOp::Ge => irql >= level,
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            IrqlOp::Eq => "==",
+            IrqlOp::Lt => "<",
+            IrqlOp::Le => "<=",
+            IrqlOp::Gt => ">",
+            IrqlOp::Ge => ">=",
+        }
+    }
+}
+
+/// the current IRQL
+#[inline]
+pub fn current() -> KIRQL {
+    unsafe { KeGetCurrentIrql() }
+}
+
+/// An IRQL requirement, checked by `assert_irql!` and `irql_scope!`
+#[derive(Clone, Copy, Debug)]
+pub struct IrqlCheck {
+    pub op: IrqlOp,
+    pub level: KIRQL,
+    /// the level as it is written, e.g. "APC_LEVEL"
+    pub level_name: &'static str,
+    pub file: &'static str,
+    pub line: u32,
+}
+
+impl IrqlCheck {
+    /// panic if the current IRQL does not meet the requirement, `when` tells which check of a scope failed
+    #[track_caller]
+    pub fn check(&self, when: Option<&str>) {
+        let irql = current();
+
+        if !self.op.test(irql, self.level) {
+            panic!(
+                "IRQL {} {} is required{}{} at {}:{}, the current IRQL is {}",
+                self.op.as_str(),
+                self.level_name,
+                if when.is_some() { " " } else { "" },
+                when.unwrap_or_default(),
+                self.file,
+                self.line,
+                irql
+            );
+        }
+    }
+}
+
+/// The guard of `irql_scope!`, it checks the requirement again on drop
+pub struct IrqlScope(IrqlCheck);
+
+impl IrqlScope {
+    #[track_caller]
+    pub fn enter(check: IrqlCheck) -> Self {
+        check.check(Some("on entry"));
+
+        Self(check)
+    }
+}
+
+impl Drop for IrqlScope {
+    fn drop(&mut self) {
+        self.0.check(Some("on exit"));
+    }
+}
+
+/// Raise the IRQL of the current processor until dropped, the previous IRQL is restored then
+///
+/// the guard is bound to the processor it is created on, so it is neither `Send` nor `Sync`
+pub struct RaiseIrqlGuard {
+    old: KIRQL,
+    _not_send: PhantomData<*const ()>,
+}
+
+impl RaiseIrqlGuard {
+    /// raise to `level`, which must not be lower than the current IRQL
+    pub fn raise(level: KIRQL) -> Self {
+        debug_assert!(
+            level >= current(),
+            "can not raise the IRQL to {} from {}",
+            level,
+            current()
+        );
+
+        Self {
+            old: unsafe { KfRaiseIrql(level) },
+            _not_send: PhantomData,
+        }
+    }
+
+    /// raise to DISPATCH_LEVEL, it must be called at IRQL <= DISPATCH_LEVEL
+    pub fn to_dispatch() -> Self {
+        Self::raise(DISPATCH_LEVEL)
+    }
+
+    /// the IRQL which is restored on drop
+    pub fn old_irql(&self) -> KIRQL {
+        self.old
+    }
+}
+
+impl Drop for RaiseIrqlGuard {
+    fn drop(&mut self) {
+        unsafe { KeLowerIrql(self.old) };
+    }
+}
+
+/// run `f` at DISPATCH_LEVEL, it must be called at IRQL <= DISPATCH_LEVEL
+pub fn with_dispatch_level<R, F: FnOnce() -> R>(f: F) -> R {
+    let _guard = RaiseIrqlGuard::to_dispatch();
+
+    f()
+}
+
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __irql_check {
+    ($op:tt $level:expr) => {
+        $crate::irql::IrqlCheck {
+            op: $crate::__irql_op!($op),
+            level: {
+                #[allow(unused_imports)]
+                use $crate::irql::{APC_LEVEL, DISPATCH_LEVEL, HIGH_LEVEL, PASSIVE_LEVEL};
+
+                $level
+            },
+            level_name: stringify!($level),
+            file: file!(),
+            line: line!(),
+        }
+    };
+}
+
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __irql_op {
+    (==) => {
+        $crate::irql::IrqlOp::Eq
+    };
+    (<) => {
+        $crate::irql::IrqlOp::Lt
+    };
+    (<=) => {
+        $crate::irql::IrqlOp::Le
+    };
+    (>) => {
+        $crate::irql::IrqlOp::Gt
+    };
+    (>=) => {
+        $crate::irql::IrqlOp::Ge
+    };
+}
+
+/// panic in a debug build if the current IRQL does not meet the requirement, e.g. `assert_irql!(<= APC_LEVEL)`
+///
+/// the level can be `PASSIVE_LEVEL`, `APC_LEVEL`, `DISPATCH_LEVEL`, `HIGH_LEVEL` or any `KIRQL` expression
+#[macro_export]
+macro_rules! assert_irql {
+    ($op:tt $level:expr) => {
+        #[cfg(debug_assertions)]
+        {
+            $crate::__irql_check!($op $level).check(None);
+        }
+    };
+}
+
+/// check the current IRQL at the call site and again when the enclosing scope is left, only in a debug build
+///
+/// besides a comparison like `assert_irql!`, it accepts `passive`(== PASSIVE_LEVEL), `apc`(<= APC_LEVEL) and
+/// `dispatch`(<= DISPATCH_LEVEL)
+#[macro_export]
+macro_rules! irql_scope {
+    (passive) => {
+        #[cfg(debug_assertions)]
+        let _irql_scope = $crate::irql::IrqlScope::enter($crate::__irql_check!(== PASSIVE_LEVEL));
+    };
+    (apc) => {
+        #[cfg(debug_assertions)]
+        let _irql_scope = $crate::irql::IrqlScope::enter($crate::__irql_check!(<= APC_LEVEL));
+    };
+    (dispatch) => {
+        #[cfg(debug_assertions)]
+        let _irql_scope = $crate::irql::IrqlScope::enter($crate::__irql_check!(<= DISPATCH_LEVEL));
+    };
+    ($op:tt $level:expr) => {
+        #[cfg(debug_assertions)]
+        let _irql_scope = $crate::irql::IrqlScope::enter($crate::__irql_check!($op $level));
+    };
+}
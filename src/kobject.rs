@@ -6,11 +6,13 @@ use core::{
 
 use wdk::nt_success;
 use wdk_sys::{
-    _KPROCESS, _KTHREAD,
+    _FILE_OBJECT, _KEVENT, _KPROCESS, _KTHREAD,
     _KWAIT_REASON::Executive,
     _MODE::KernelMode,
-    GENERIC_ALL, HANDLE, LARGE_INTEGER, NTSTATUS, PEPROCESS, PETHREAD, PVOID, PsProcessType,
-    PsThreadType, STATUS_ALERTED, STATUS_SUCCESS, STATUS_TIMEOUT, STATUS_USER_APC,
+    ExEventObjectType, GENERIC_ALL, HANDLE, IoFileObjectType, NTSTATUS, PEPROCESS, PETHREAD,
+    POBJECT_TYPE, PVOID, PsProcessType, PsThreadType, STATUS_ABANDONED_WAIT_0,
+    STATUS_ABANDONED_WAIT_63, STATUS_ALERTED, STATUS_SUCCESS, STATUS_TIMEOUT, STATUS_USER_APC,
+    STATUS_WAIT_0, STATUS_WAIT_63,
     ntddk::{
         KeWaitForSingleObject, ObReferenceObjectByHandle, ObfDereferenceObject,
         PsLookupProcessByProcessId, PsLookupThreadByThreadId,
@@ -21,9 +23,25 @@ use crate::{
     handle::ObjectHandle,
     ntstatus::{NtError, cvt},
     raw::{AsRawHandle, AsRawObject},
+    time,
 };
 
+/// the outcome of a wait, see `WaitResult::status`
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum WaitStatus {
+    /// the object(or the object at the index of a multiple wait) was signaled
+    Signaled(usize),
+    /// the mutant(or the mutant at the index of a multiple wait) was abandoned by its owner
+    Abandoned(usize),
+    TimedOut,
+    Alerted,
+    UserApc,
+    /// any other status
+    Other(NTSTATUS),
+}
+
 #[repr(transparent)]
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
 pub struct WaitResult(i32);
 
 impl WaitResult {
@@ -54,11 +72,42 @@ impl WaitResult {
     pub fn timed_out(self) -> bool {
         self.0 == STATUS_TIMEOUT
     }
+
+    /// the raw status returned by the wait routine
+    #[inline]
+    pub fn code(self) -> NTSTATUS {
+        self.0
+    }
+
+    pub fn status(self) -> WaitStatus {
+        match self.0 {
+            STATUS_TIMEOUT => WaitStatus::TimedOut,
+            STATUS_ALERTED => WaitStatus::Alerted,
+            STATUS_USER_APC => WaitStatus::UserApc,
+            status if (STATUS_WAIT_0..=STATUS_WAIT_63).contains(&status) => {
+                WaitStatus::Signaled((status - STATUS_WAIT_0) as usize)
+            }
+            status if (STATUS_ABANDONED_WAIT_0..=STATUS_ABANDONED_WAIT_63).contains(&status) => {
+                WaitStatus::Abandoned((status - STATUS_ABANDONED_WAIT_0) as usize)
+            }
+            status => WaitStatus::Other(status),
+        }
+    }
+
+    /// the index of the object which satisfied the wait, it is always 0 for a single object wait
+    pub fn index(self) -> Option<usize> {
+        match self.status() {
+            WaitStatus::Signaled(index) | WaitStatus::Abandoned(index) => Some(index),
+            _ => None,
+        }
+    }
 }
 
 /// kernel dispatchable object must implement this trait, just like Process, Thread, Event, Semaphore, Timer etc.
 pub trait Dispatchable: AsRawObject {
     fn wait(&self, alertable: bool) -> WaitResult {
+        crate::assert_irql!(<= APC_LEVEL);
+
         let status = unsafe {
             KeWaitForSingleObject(
                 <Self as AsRawObject>::as_raw(self).cast(),
@@ -73,9 +122,15 @@ pub trait Dispatchable: AsRawObject {
     }
 
     fn wait_for(&self, ms: Duration, alertable: bool) -> WaitResult {
-        let mut timeout = LARGE_INTEGER {
-            QuadPart: -1 * ms.as_nanos() as i64 / 100,
-        };
+        // a zero timeout only tests the state, which is allowed at DISPATCH_LEVEL
+        #[cfg(debug_assertions)]
+        if !ms.is_zero() {
+            crate::assert_irql!(<= APC_LEVEL);
+        }
+
+        crate::assert_irql!(<= DISPATCH_LEVEL);
+
+        let mut timeout = time::relative(ms);
 
         let status = unsafe {
             KeWaitForSingleObject(
@@ -89,6 +144,15 @@ pub trait Dispatchable: AsRawObject {
 
         WaitResult::new(status)
     }
+
+    /// returns a future which resolves once the object is signaled, see `executor` for details
+    #[cfg(feature = "async")]
+    fn wait_async(&self) -> crate::executor::WaitFuture<'_, Self>
+    where
+        Self: Sized,
+    {
+        crate::executor::WaitFuture::new(self)
+    }
 }
 
 /// for a kernel object we must release the reference count when no needed
@@ -148,52 +212,76 @@ pub trait FromThreadId {
     fn from_thread_id(id: HANDLE) -> Result<KernelObject<_KTHREAD>, NtError>;
 }
 
-impl FromRawProcessHandle for KernelObject<_KPROCESS> {
-    fn from_process_handle(
-        handle: HANDLE,
-        access: u32,
-    ) -> Result<KernelObject<_KPROCESS>, NtError> {
-        let mut value: PVOID = ptr::null_mut();
+/// a kernel object type whose handles can be referenced with type checking
+pub trait ObjectType {
+    fn object_type() -> POBJECT_TYPE;
+}
 
-        let status = unsafe {
-            ObReferenceObjectByHandle(
-                handle,
-                access,
-                *PsProcessType,
-                KernelMode as _,
-                &mut value,
-                ptr::null_mut(),
-            )
-        };
+impl ObjectType for _KPROCESS {
+    fn object_type() -> POBJECT_TYPE {
+        unsafe { *PsProcessType }
+    }
+}
 
-        if !nt_success(status) {
-            return Err(NtError::from(status));
-        }
+impl ObjectType for _KTHREAD {
+    fn object_type() -> POBJECT_TYPE {
+        unsafe { *PsThreadType }
+    }
+}
 
-        Ok(KernelObject::<_KPROCESS>(value.cast()))
+impl ObjectType for _KEVENT {
+    fn object_type() -> POBJECT_TYPE {
+        unsafe { *ExEventObjectType }
     }
 }
 
-impl FromRawThreadHandle for KernelObject<_KTHREAD> {
-    fn from_thread_handle(h: HANDLE, access: u32) -> Result<KernelObject<_KTHREAD>, NtError> {
+impl ObjectType for _FILE_OBJECT {
+    fn object_type() -> POBJECT_TYPE {
+        unsafe { *IoFileObjectType }
+    }
+}
+
+impl<T: ObjectType> KernelObject<T> {
+    /// reference the object of a kernel `handle`, it fails with STATUS_OBJECT_TYPE_MISMATCH if the handle
+    /// does not refer to a `T`
+    ///
+    /// # Example
+    /// ```
+    /// let thread = ThreadObject::from_handle(handle, SYNCHRONIZE)?;
+    ///
+    /// thread.wait(false);
+    /// // the reference is released here
+    /// ```
+    pub fn from_handle(handle: HANDLE, access: u32) -> Result<Self, NtError> {
         let mut value: PVOID = ptr::null_mut();
 
-        let status = unsafe {
+        cvt(unsafe {
             ObReferenceObjectByHandle(
-                h,
+                handle,
                 access,
-                *PsThreadType,
+                T::object_type(),
                 KernelMode as _,
                 &mut value,
                 ptr::null_mut(),
             )
-        };
+        })?;
 
-        if !nt_success(status) {
-            return Err(NtError::from(status));
-        }
+        Ok(KernelObject(value.cast()))
+    }
+}
+
+impl FromRawProcessHandle for KernelObject<_KPROCESS> {
+    fn from_process_handle(
+        handle: HANDLE,
+        access: u32,
+    ) -> Result<KernelObject<_KPROCESS>, NtError> {
+        KernelObject::<_KPROCESS>::from_handle(handle, access)
+    }
+}
 
-        Ok(KernelObject::<_KTHREAD>(value.cast()))
+impl FromRawThreadHandle for KernelObject<_KTHREAD> {
+    fn from_thread_handle(h: HANDLE, access: u32) -> Result<KernelObject<_KTHREAD>, NtError> {
+        KernelObject::<_KTHREAD>::from_handle(h, access)
     }
 }
 
@@ -251,6 +339,9 @@ impl Dispatchable for KernelObject<_KPROCESS> {}
 // implement `Dispatchable`
 impl Dispatchable for KernelObject<_KTHREAD> {}
 
+// implement `Dispatchable`
+impl Dispatchable for KernelObject<_KEVENT> {}
+
 // implement `Dereference` for all `T`
 impl<T> Dereference for KernelObject<T> {}
 
@@ -285,3 +376,5 @@ impl<T> Drop for KernelObject<T> {
 
 pub type ProcessObject = KernelObject<_KPROCESS>;
 pub type ThreadObject = KernelObject<_KTHREAD>;
+pub type EventObject = KernelObject<_KEVENT>;
+pub type FileObject = KernelObject<_FILE_OBJECT>;
@@ -0,0 +1,466 @@
+//! this module provides `KVec<T>` and `KString`, a vector and a string allocated from the pool with a chosen tag
+//!
+//! unlike `alloc::vec::Vec`, every allocating method is fallible and returns STATUS_INSUFFICIENT_RESOURCES, so an
+//! allocation failure in an IRP path fails the request instead of bugchecking in the allocation error handler
+//!
+//! the buffer is allocated from NonPagedPoolNx by default, it can be freed at IRQL <= DISPATCH_LEVEL
+//!
+//! # Example
+//! ```
+//! const TAG: u32 = u32::from_ne_bytes(*b"tsil");
+//!
+//! let mut pids = KVec::with_tag(TAG);
+//! pids.try_reserve(16)?;
+//! pids.try_push(4u32)?;
+//! pids.try_extend_from_slice(&[100, 200])?;
+//!
+//! let mut name = KString::with_tag(TAG);
+//! name.try_push_str("\\Device\\")?;
+//! write!(name, "Worker{}", index)?;
+//! ```
+use core::{
+    fmt, mem,
+    ops::{Deref, DerefMut},
+    ptr::{self, NonNull},
+    slice,
+};
+
+use wdk_sys::{
+    _POOL_TYPE::NonPagedPoolNx, POOL_TYPE, STATUS_DATATYPE_MISALIGNMENT,
+    STATUS_INSUFFICIENT_RESOURCES,
+};
+
+use crate::{
+    ntstatus::NtError,
+    utils::{ex_allocate_pool_zero, ex_free_pool},
+};
+
+/// the tag used by `KVec::new` and `KString::new`
+pub const DEFAULT_TAG: u32 = u32::from_ne_bytes(*b"cevk");
+
+/// the alignment of the pool allocations on x64
+const POOL_ALIGNMENT: usize = 16;
+
+/// A growable array allocated from the pool, all the allocating methods are fallible
+pub struct KVec<T> {
+    ptr: NonNull<T>,
+    len: usize,
+    capacity: usize,
+    pool_type: POOL_TYPE,
+    tag: u32,
+}
+
+impl<T> KVec<T> {
+    /// an empty vector, it does not allocate until an element is pushed
+    pub const fn new() -> Self {
+        Self::with_pool(NonPagedPoolNx, DEFAULT_TAG)
+    }
+
+    pub const fn with_tag(tag: u32) -> Self {
+        Self::with_pool(NonPagedPoolNx, tag)
+    }
+
+    /// an empty vector allocated from `pool_type`, a PagedPool vector must only be used at IRQL <= APC_LEVEL
+    pub const fn with_pool(pool_type: POOL_TYPE, tag: u32) -> Self {
+        Self {
+            ptr: NonNull::dangling(),
+            len: 0,
+            capacity: if mem::size_of::<T>() == 0 {
+                usize::MAX
+            } else {
+                0
+            },
+            pool_type,
+            tag,
+        }
+    }
+
+    pub fn try_with_capacity(capacity: usize) -> Result<Self, NtError> {
+        let mut v = Self::new();
+
+        v.try_reserve_exact(capacity)?;
+
+        Ok(v)
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    pub fn tag(&self) -> u32 {
+        self.tag
+    }
+
+    pub fn as_slice(&self) -> &[T] {
+        unsafe { slice::from_raw_parts(self.ptr.as_ptr(), self.len) }
+    }
+
+    pub fn as_mut_slice(&mut self) -> &mut [T] {
+        unsafe { slice::from_raw_parts_mut(self.ptr.as_ptr(), self.len) }
+    }
+
+    /// reserve room for at least `additional` more elements, the capacity grows geometrically
+    pub fn try_reserve(&mut self, additional: usize) -> Result<(), NtError> {
+        let required = self
+            .len
+            .checked_add(additional)
+            .ok_or(NtError::new(STATUS_INSUFFICIENT_RESOURCES))?;
+
+        if required <= self.capacity {
+            return Ok(());
+        }
+
+        self.grow(required.max(self.capacity.saturating_mul(2)).max(4))
+    }
+
+    /// reserve room for exactly `additional` more elements
+    pub fn try_reserve_exact(&mut self, additional: usize) -> Result<(), NtError> {
+        let required = self
+            .len
+            .checked_add(additional)
+            .ok_or(NtError::new(STATUS_INSUFFICIENT_RESOURCES))?;
+
+        if required <= self.capacity {
+            return Ok(());
+        }
+
+        self.grow(required)
+    }
+
+    fn grow(&mut self, capacity: usize) -> Result<(), NtError> {
+        if mem::align_of::<T>() > POOL_ALIGNMENT {
+            return Err(NtError::new(STATUS_DATATYPE_MISALIGNMENT));
+        }
+
+        let size = capacity
+            .checked_mul(mem::size_of::<T>())
+            .ok_or(NtError::new(STATUS_INSUFFICIENT_RESOURCES))?;
+
+        let ptr = ex_allocate_pool_zero(self.pool_type, size as _, self.tag)? as *mut T;
+
+        let ptr = NonNull::new(ptr).ok_or(NtError::new(STATUS_INSUFFICIENT_RESOURCES))?;
+
+        unsafe { ptr::copy_nonoverlapping(self.ptr.as_ptr(), ptr.as_ptr(), self.len) };
+
+        self.free();
+
+        self.ptr = ptr;
+        self.capacity = capacity;
+
+        Ok(())
+    }
+
+    /// free the buffer without dropping the elements
+    fn free(&mut self) {
+        if mem::size_of::<T>() != 0 && self.capacity != 0 {
+            unsafe { ex_free_pool(self.ptr.as_ptr().cast(), self.tag) };
+        }
+    }
+
+    pub fn try_push(&mut self, value: T) -> Result<(), NtError> {
+        self.try_reserve(1)?;
+
+        unsafe { ptr::write(self.ptr.as_ptr().add(self.len), value) };
+
+        self.len += 1;
+
+        Ok(())
+    }
+
+    /// push `value` if there is room without reallocation, it is returned back otherwise
+    pub fn push_within_capacity(&mut self, value: T) -> Result<(), T> {
+        if self.len == self.capacity {
+            return Err(value);
+        }
+
+        unsafe { ptr::write(self.ptr.as_ptr().add(self.len), value) };
+
+        self.len += 1;
+
+        Ok(())
+    }
+
+    pub fn pop(&mut self) -> Option<T> {
+        if self.len == 0 {
+            return None;
+        }
+
+        self.len -= 1;
+
+        Some(unsafe { ptr::read(self.ptr.as_ptr().add(self.len)) })
+    }
+
+    /// insert `value` at `index`, it panics if `index > len`
+    pub fn try_insert(&mut self, index: usize, value: T) -> Result<(), NtError> {
+        assert!(index <= self.len, "insertion index is out of bounds");
+
+        self.try_reserve(1)?;
+
+        unsafe {
+            let p = self.ptr.as_ptr().add(index);
+
+            ptr::copy(p, p.add(1), self.len - index);
+            ptr::write(p, value);
+        }
+
+        self.len += 1;
+
+        Ok(())
+    }
+
+    /// remove the element at `index` and shift the rest, it panics if `index >= len`
+    pub fn remove(&mut self, index: usize) -> T {
+        assert!(index < self.len, "removal index is out of bounds");
+
+        unsafe {
+            let p = self.ptr.as_ptr().add(index);
+            let value = ptr::read(p);
+
+            ptr::copy(p.add(1), p, self.len - index - 1);
+
+            self.len -= 1;
+
+            value
+        }
+    }
+
+    /// remove the element at `index` and replace it with the last one, it panics if `index >= len`
+    pub fn swap_remove(&mut self, index: usize) -> T {
+        assert!(index < self.len, "removal index is out of bounds");
+
+        let last = self.len - 1;
+
+        self.as_mut_slice().swap(index, last);
+
+        self.pop().unwrap()
+    }
+
+    pub fn truncate(&mut self, len: usize) {
+        while self.len > len {
+            drop(self.pop());
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.truncate(0);
+    }
+
+    pub fn retain<F: FnMut(&T) -> bool>(&mut self, mut f: F) {
+        let mut i = 0;
+
+        while i < self.len {
+            if f(&self.as_slice()[i]) {
+                i += 1;
+            } else {
+                drop(self.remove(i));
+            }
+        }
+    }
+}
+
+impl<T: Clone> KVec<T> {
+    /// clone and append the elements of `other`, nothing is appended if the allocation fails
+    pub fn try_extend_from_slice(&mut self, other: &[T]) -> Result<(), NtError> {
+        self.try_reserve(other.len())?;
+
+        for value in other {
+            // the room is reserved above
+            let _ = self.push_within_capacity(value.clone());
+        }
+
+        Ok(())
+    }
+
+    /// clone the vector with the same pool type and tag
+    pub fn try_clone(&self) -> Result<Self, NtError> {
+        let mut v = Self::with_pool(self.pool_type, self.tag);
+
+        v.try_extend_from_slice(self.as_slice())?;
+
+        Ok(v)
+    }
+}
+
+impl<T> Default for KVec<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T> Deref for KVec<T> {
+    type Target = [T];
+    fn deref(&self) -> &Self::Target {
+        self.as_slice()
+    }
+}
+
+impl<T> DerefMut for KVec<T> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        self.as_mut_slice()
+    }
+}
+
+impl<'a, T> IntoIterator for &'a KVec<T> {
+    type Item = &'a T;
+    type IntoIter = slice::Iter<'a, T>;
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+    }
+}
+
+impl<'a, T> IntoIterator for &'a mut KVec<T> {
+    type Item = &'a mut T;
+    type IntoIter = slice::IterMut<'a, T>;
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter_mut()
+    }
+}
+
+impl<T: fmt::Debug> fmt::Debug for KVec<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_list().entries(self.iter()).finish()
+    }
+}
+
+impl<T: PartialEq> PartialEq for KVec<T> {
+    fn eq(&self, other: &Self) -> bool {
+        self.as_slice() == other.as_slice()
+    }
+}
+
+impl<T: Eq> Eq for KVec<T> {}
+
+impl<T> Drop for KVec<T> {
+    fn drop(&mut self) {
+        unsafe { ptr::drop_in_place(self.as_mut_slice()) };
+
+        self.free();
+    }
+}
+
+unsafe impl<T: Send> Send for KVec<T> {}
+unsafe impl<T: Sync> Sync for KVec<T> {}
+
+/// A growable UTF-8 string allocated from the pool, all the allocating methods are fallible
+///
+/// `fmt::Write` returns an error when the allocation fails, the string keeps what was written before
+#[derive(Default, PartialEq, Eq)]
+pub struct KString {
+    bytes: KVec<u8>,
+}
+
+impl KString {
+    pub const fn new() -> Self {
+        Self { bytes: KVec::new() }
+    }
+
+    pub const fn with_tag(tag: u32) -> Self {
+        Self {
+            bytes: KVec::with_tag(tag),
+        }
+    }
+
+    pub const fn with_pool(pool_type: POOL_TYPE, tag: u32) -> Self {
+        Self {
+            bytes: KVec::with_pool(pool_type, tag),
+        }
+    }
+
+    pub fn try_from_str(s: &str) -> Result<Self, NtError> {
+        let mut string = Self::new();
+
+        string.try_push_str(s)?;
+
+        Ok(string)
+    }
+
+    pub fn as_str(&self) -> &str {
+        unsafe { core::str::from_utf8_unchecked(&self.bytes) }
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+
+    pub fn len(&self) -> usize {
+        self.bytes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.bytes.is_empty()
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.bytes.capacity()
+    }
+
+    pub fn try_reserve(&mut self, additional: usize) -> Result<(), NtError> {
+        self.bytes.try_reserve(additional)
+    }
+
+    pub fn try_push_str(&mut self, s: &str) -> Result<(), NtError> {
+        self.bytes.try_extend_from_slice(s.as_bytes())
+    }
+
+    pub fn try_push(&mut self, c: char) -> Result<(), NtError> {
+        self.try_push_str(c.encode_utf8(&mut [0; 4]))
+    }
+
+    pub fn pop(&mut self) -> Option<char> {
+        let c = self.as_str().chars().next_back()?;
+
+        self.bytes.truncate(self.len() - c.len_utf8());
+
+        Some(c)
+    }
+
+    /// shorten the string to `len` bytes, it panics if `len` is not at a char boundary
+    pub fn truncate(&mut self, len: usize) {
+        if len < self.len() {
+            assert!(self.as_str().is_char_boundary(len), "not a char boundary");
+
+            self.bytes.truncate(len);
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.bytes.clear();
+    }
+
+    pub fn try_clone(&self) -> Result<Self, NtError> {
+        self.bytes.try_clone().map(|bytes| Self { bytes })
+    }
+}
+
+impl Deref for KString {
+    type Target = str;
+    fn deref(&self) -> &Self::Target {
+        self.as_str()
+    }
+}
+
+impl fmt::Write for KString {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.try_push_str(s).map_err(|_| fmt::Error)
+    }
+}
+
+impl fmt::Display for KString {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl fmt::Debug for KString {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(self.as_str(), f)
+    }
+}
@@ -183,7 +183,7 @@ impl<T, F: FnOnce() -> T> LazyLock<T, F> {
         let data = unsafe { &mut *this.data.get() };
         let f = unsafe { ManuallyDrop::take(&mut data.f) };
 
-        if let Some(mut value) = this.get_once().call_once(f) {
+        if let Some(mut value) = this.get_once().try_call_once(f) {
             unsafe { &mut *this.data.get() }.value = ManuallyDrop::new(value.take());
             this.get_unchecked()
         } else {
@@ -611,7 +611,7 @@ impl<T> OnceLock<T> {
     #[inline]
     /// ensure the inside `T` is initialized only once
     fn init_once<F: FnOnce() -> T>(&self, f: F) -> &T {
-        if let Some(mut value) = self.get_once().call_once(f) {
+        if let Some(mut value) = self.get_once().try_call_once(f) {
             unsafe { *self.value.get() = MaybeUninit::new(value.take()) };
             self.get_unchecked()
         } else {
@@ -1,33 +1,131 @@
-#![no_std]
+#![cfg_attr(not(feature = "std-sim"), no_std)]
 #![allow(non_snake_case)]
 #![allow(non_camel_case_types)]
 #![allow(non_upper_case_globals)]
 
-pub mod wdm;
-pub mod dpc;
-pub mod event;
-pub mod handle;
-pub mod kobject;
-pub mod lazy;
-pub mod mutex;
-pub mod ntstatus;
-pub mod once;
-pub mod sema;
-pub mod thread;
-pub mod timer;
-pub mod utils;
-pub mod workitem;
-
-// just for testing purpose
-#[cfg(test)]
-pub mod test;
-
-#[deprecated(since = "0.1.1", note = "please use `mutex` instead")]
-pub mod lock;
-
-mod constants;
-pub(crate) use constants::*;
-
-pub(crate) mod raw;
-
-extern crate alloc;
\ No newline at end of file
+#[cfg(all(feature = "kernel", feature = "std-sim"))]
+compile_error!(
+    "`std-sim` replaces the kernel implementation, build it with `default-features = false`"
+);
+
+/// the kernel implementation is compiled only with the `kernel` feature, which is enabled by default
+macro_rules! cfg_kernel {
+    ($($item:item)*) => {
+        $(
+            #[cfg(feature = "kernel")]
+            $item
+        )*
+    };
+}
+
+cfg_kernel! {
+    pub mod wdm;
+    pub mod apc;
+    pub mod arc;
+    pub mod avl;
+    pub mod barrier;
+    pub mod bitmap;
+    pub mod cm_callbacks;
+    pub mod client;
+    pub mod collections;
+    pub mod comm;
+    pub mod config;
+    pub mod context;
+    pub mod cpu;
+    pub mod csq;
+    pub mod device;
+    pub mod dpc;
+    pub mod dynimport;
+    pub mod etw;
+    pub mod events;
+    pub mod event;
+    #[cfg(feature = "async")]
+    pub mod executor;
+    #[cfg(feature = "fault-injection")]
+    pub mod fault;
+    pub mod fmt;
+    pub mod guid;
+    pub mod handle;
+    pub mod hash;
+    pub mod hashmap;
+    pub mod htable;
+    pub mod interlocked;
+    pub mod irp;
+    pub mod irql;
+    pub mod kobject;
+    pub mod kvec;
+    pub mod lazy;
+    pub mod list;
+    pub mod memory;
+    #[cfg(feature = "minifilter")]
+    pub mod minifilter;
+    pub mod mpsc;
+    pub mod mutex;
+    pub mod notify;
+    pub mod ntstatus;
+    pub mod ob;
+    pub mod ob_callbacks;
+    pub mod oneshot;
+    pub mod once;
+    pub mod os;
+    pub mod parallel;
+    #[cfg(feature = "panic_handler")]
+    pub mod panic;
+    pub mod pod;
+    pub mod pool;
+    pub mod power;
+    pub mod process;
+    pub mod queue;
+    pub mod rand;
+    pub mod rcu;
+    pub mod reentrancy;
+    pub mod region;
+    pub mod retry;
+    pub mod ring;
+    pub mod scheduler;
+    pub mod sd;
+    pub mod secret;
+    pub mod section;
+    pub mod security;
+    #[cfg(feature = "selftest")]
+    pub mod selftest;
+    pub mod sema;
+    pub mod software;
+    pub mod statemachine;
+    pub mod stats;
+    pub mod sysinfo;
+    pub mod thread;
+    pub mod throttle;
+    pub mod time;
+    pub mod timer;
+    pub mod timerwheel;
+    pub mod tls;
+    pub mod trace;
+    pub mod unicode;
+    pub mod unload;
+    pub mod utils;
+    pub mod wait;
+    pub mod waitgroup;
+    pub mod watchdog;
+    pub mod workitem;
+
+    // just for testing purpose
+    #[cfg(test)]
+    pub mod test;
+
+    #[deprecated(since = "0.1.1", note = "please use `mutex` instead")]
+    pub mod lock;
+
+    mod constants;
+    pub(crate) use constants::*;
+
+    pub(crate) mod raw;
+}
+
+#[cfg(all(feature = "std-sim", not(feature = "kernel")))]
+mod sim;
+
+#[cfg(all(feature = "std-sim", not(feature = "kernel")))]
+pub use sim::{event, kobject, mutex, ntstatus, thread, time, timer};
+
+extern crate alloc;
@@ -0,0 +1,339 @@
+//! this module provides an intrusive doubly-linked list built on the kernel `LIST_ENTRY`
+//!
+//! the elements embed a `ListNode` field which has the same layout as `LIST_ENTRY`, so the list can be shared with
+//! kernel structures or native code that expect a plain `LIST_ENTRY` chain
+//!
+//! # Example
+//! ```
+//! struct Request {
+//!     id: u32,
+//!     link: ListNode,
+//! }
+//!
+//! list_adapter!(Request, link);
+//!
+//! let queue: SpinLockedList<Request> = SpinLocked::new(IntrusiveList::new())?;
+//!
+//! if let Ok(mut list) = queue.lock() {
+//!     list.push_back(Box::new(Request { id: 1, link: ListNode::new() }));
+//! }
+//!
+//! if let Ok(mut list) = queue.lock() {
+//!     while let Some(request) = list.pop_front() {
+//!         println!("request#{}", request.id);
+//!     }
+//! }
+//! ```
+use core::{cell::UnsafeCell, marker::PhantomData, ptr};
+
+use alloc::boxed::Box;
+use wdk_sys::{LIST_ENTRY, PLIST_ENTRY};
+
+use crate::mutex::SpinLocked;
+
+#[allow(non_snake_case)]
+#[inline]
+pub fn InitializeListHead(head: PLIST_ENTRY) {
+    unsafe {
+        (*head).Flink = head;
+        (*head).Blink = head;
+    }
+}
+
+#[allow(non_snake_case)]
+#[inline]
+pub fn IsListEmpty(head: PLIST_ENTRY) -> bool {
+    unsafe { (*head).Flink == head }
+}
+
+#[allow(non_snake_case)]
+#[inline]
+pub fn InsertTailList(head: PLIST_ENTRY, entry: PLIST_ENTRY) {
+    unsafe {
+        let blink = (*head).Blink;
+
+        (*entry).Flink = head;
+        (*entry).Blink = blink;
+        (*blink).Flink = entry;
+        (*head).Blink = entry;
+    }
+}
+
+#[allow(non_snake_case)]
+#[inline]
+pub fn InsertHeadList(head: PLIST_ENTRY, entry: PLIST_ENTRY) {
+    unsafe {
+        let flink = (*head).Flink;
+
+        (*entry).Flink = flink;
+        (*entry).Blink = head;
+        (*flink).Blink = entry;
+        (*head).Flink = entry;
+    }
+}
+
+/// returns true if the list becomes empty
+///
+/// # Panic
+/// the list is corrupted, the same as the `FAST_FAIL_CORRUPT_LIST_ENTRY` check done by the kernel
+#[allow(non_snake_case)]
+#[inline]
+pub fn RemoveEntryList(entry: PLIST_ENTRY) -> bool {
+    unsafe {
+        let flink = (*entry).Flink;
+        let blink = (*entry).Blink;
+
+        if (*flink).Blink != entry || (*blink).Flink != entry {
+            panic!("LIST_ENTRY corrupted");
+        }
+
+        (*blink).Flink = flink;
+        (*flink).Blink = blink;
+
+        flink == blink
+    }
+}
+
+#[allow(non_snake_case)]
+#[inline]
+pub fn RemoveHeadList(head: PLIST_ENTRY) -> PLIST_ENTRY {
+    let entry = unsafe { (*head).Flink };
+
+    RemoveEntryList(entry);
+
+    entry
+}
+
+/// the link field embedded in a list element, it has the same layout as `LIST_ENTRY`
+///
+/// the links are rewritten while the element is shared, e.g. when the neighbours are unlinked, so they live in an
+/// `UnsafeCell`
+#[repr(transparent)]
+pub struct ListNode(UnsafeCell<LIST_ENTRY>);
+
+impl ListNode {
+    pub const fn new() -> Self {
+        Self(UnsafeCell::new(LIST_ENTRY {
+            Flink: ptr::null_mut(),
+            Blink: ptr::null_mut(),
+        }))
+    }
+
+    /// returns true if the node is linked in some list
+    pub fn is_linked(&self) -> bool {
+        unsafe { !(*self.0.get()).Flink.is_null() }
+    }
+
+    pub fn as_raw(&self) -> PLIST_ENTRY {
+        self.0.get()
+    }
+}
+
+/// describe where the `ListNode` lives inside `Self`, it is the Rust version of `CONTAINING_RECORD`
+///
+/// # Safety
+/// `OFFSET` must be the offset of a `ListNode` field of `Self`, use `list_adapter!` to implement it
+pub unsafe trait Linked {
+    const OFFSET: usize;
+
+    fn to_node(this: *const Self) -> PLIST_ENTRY {
+        unsafe { (*(this as *const u8).add(Self::OFFSET).cast::<ListNode>()).as_raw() }
+    }
+
+    fn from_node(node: PLIST_ENTRY) -> *mut Self {
+        unsafe { (node as *mut u8).sub(Self::OFFSET).cast() }
+    }
+}
+
+/// implement `Linked` for a type by naming its `ListNode` field
+#[macro_export]
+macro_rules! list_adapter {
+    ($type:ty, $field:ident) => {
+        unsafe impl $crate::list::Linked for $type {
+            const OFFSET: usize = core::mem::offset_of!($type, $field);
+        }
+    };
+}
+
+/// An owning intrusive list, elements are boxed and linked through their `ListNode`
+///
+/// none of the operations allocate memory, so it can be used at any IRQL once the elements are allocated
+pub struct IntrusiveList<T: Linked> {
+    /// the head must be pinned since the first and last element point to it
+    head: Box<ListNode>,
+    len: usize,
+    _phantom: PhantomData<Box<T>>,
+}
+
+impl<T: Linked> IntrusiveList<T> {
+    pub fn new() -> Self {
+        let head = Box::new(ListNode::new());
+
+        InitializeListHead(head.as_raw());
+
+        Self {
+            head,
+            len: 0,
+            _phantom: PhantomData,
+        }
+    }
+
+    #[inline]
+    fn head(&self) -> PLIST_ENTRY {
+        self.head.as_raw()
+    }
+
+    /// the raw list head, can be passed to native code that walks `LIST_ENTRY` chains
+    pub fn as_raw(&self) -> PLIST_ENTRY {
+        self.head()
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        IsListEmpty(self.head())
+    }
+
+    pub fn push_back(&mut self, value: Box<T>) {
+        InsertTailList(self.head(), T::to_node(Box::into_raw(value)));
+        self.len += 1;
+    }
+
+    pub fn push_front(&mut self, value: Box<T>) {
+        InsertHeadList(self.head(), T::to_node(Box::into_raw(value)));
+        self.len += 1;
+    }
+
+    pub fn pop_front(&mut self) -> Option<Box<T>> {
+        if self.is_empty() {
+            return None;
+        }
+
+        Some(self.take(RemoveHeadList(self.head())))
+    }
+
+    pub fn pop_back(&mut self) -> Option<Box<T>> {
+        if self.is_empty() {
+            return None;
+        }
+
+        let node = unsafe { (*self.head()).Blink };
+
+        RemoveEntryList(node);
+
+        Some(self.take(node))
+    }
+
+    pub fn front(&self) -> Option<&T> {
+        self.iter().next()
+    }
+
+    /// unlink `value` from this list
+    ///
+    /// # Safety
+    /// `value` must be an element of this list
+    pub unsafe fn remove(&mut self, value: &T) -> Box<T> {
+        let node = T::to_node(value);
+
+        RemoveEntryList(node);
+
+        self.take(node)
+    }
+
+    /// remove the first element that matches `f`
+    pub fn remove_first<F: FnMut(&T) -> bool>(&mut self, mut f: F) -> Option<Box<T>> {
+        let node = self
+            .iter()
+            .find(|value| f(value))
+            .map(|value| T::to_node(value))?;
+
+        RemoveEntryList(node);
+
+        Some(self.take(node))
+    }
+
+    /// retain only the elements specified by the predicate, the others are dropped
+    pub fn retain<F: FnMut(&T) -> bool>(&mut self, mut f: F) {
+        let head = self.head();
+        let mut node = unsafe { (*head).Flink };
+
+        while node != head {
+            let next = unsafe { (*node).Flink };
+
+            if !f(unsafe { &*T::from_node(node) }) {
+                RemoveEntryList(node);
+                drop(self.take(node));
+            }
+
+            node = next;
+        }
+    }
+
+    pub fn iter(&self) -> Iter<'_, T> {
+        unsafe { Iter::from_head(self.head()) }
+    }
+
+    /// take back the ownership of an unlinked node
+    fn take(&mut self, node: PLIST_ENTRY) -> Box<T> {
+        unsafe {
+            (*node).Flink = ptr::null_mut();
+            (*node).Blink = ptr::null_mut();
+        }
+
+        self.len -= 1;
+
+        unsafe { Box::from_raw(T::from_node(node)) }
+    }
+}
+
+impl<T: Linked> Drop for IntrusiveList<T> {
+    fn drop(&mut self) {
+        while self.pop_front().is_some() {}
+    }
+}
+
+unsafe impl<T: Linked + Send> Send for IntrusiveList<T> {}
+unsafe impl<T: Linked + Sync> Sync for IntrusiveList<T> {}
+
+/// An intrusive list guarded by a `SpinMutex`, can be used as an IRQL-safe queue
+pub type SpinLockedList<T> = SpinLocked<IntrusiveList<T>>;
+
+/// A borrowing iterator over a `LIST_ENTRY` chain
+pub struct Iter<'a, T: Linked> {
+    head: PLIST_ENTRY,
+    current: PLIST_ENTRY,
+    _phantom: PhantomData<&'a T>,
+}
+
+impl<'a, T: Linked> Iter<'a, T> {
+    /// walk a list that is not owned by an `IntrusiveList`, for example a list head embedded in a kernel structure
+    ///
+    /// # Safety
+    /// - `head` must be an initialized list head and all the entries must be the `ListNode` of a `T`
+    /// - the list must not be modified during iteration
+    pub unsafe fn from_head(head: PLIST_ENTRY) -> Self {
+        Self {
+            head,
+            current: unsafe { (*head).Flink },
+            _phantom: PhantomData,
+        }
+    }
+}
+
+impl<'a, T: Linked + 'a> Iterator for Iter<'a, T> {
+    type Item = &'a T;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.current == self.head {
+            return None;
+        }
+
+        let value = unsafe { &*T::from_node(self.current) };
+
+        self.current = unsafe { (*self.current).Flink };
+
+        Some(value)
+    }
+}
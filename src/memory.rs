@@ -0,0 +1,223 @@
+//! this module provides allocations of physical memory for DMA-adjacent drivers
+//!
+//! - `ContiguousBuffer` is a physically contiguous, nonpaged buffer mapped in system space
+//! - `PageSet` is a set of physical pages described by an MDL, which are not necessarily contiguous
+//!
+//! # Example
+//! ```
+//! let mut buffer = ContiguousBuffer::alloc(0x4000, MmNonCached)?;
+//!
+//! buffer.as_mut_slice().fill(0);
+//! program_device(buffer.physical_address());
+//! ```
+use core::{
+    ptr::{self, NonNull},
+    slice,
+};
+
+use wdk_sys::{
+    _MEMORY_CACHING_TYPE::MmCached,
+    _MM_PAGE_PRIORITY::NormalPagePriority,
+    _MODE::KernelMode,
+    MEMORY_CACHING_TYPE, MM_ANY_NODE_OK, PFN_NUMBER, PHYSICAL_ADDRESS, PMDL, PVOID,
+    STATUS_INSUFFICIENT_RESOURCES, STATUS_INVALID_PARAMETER,
+    ntddk::{
+        IoFreeMdl, MmAllocateContiguousMemorySpecifyCacheNode, MmAllocatePagesForMdlEx,
+        MmFreeContiguousMemory, MmFreePagesFromMdl, MmGetPhysicalAddress,
+        MmMapLockedPagesSpecifyCache, MmUnmapLockedPages,
+    },
+};
+
+use crate::{irp::MDL_MAPPING_NO_EXECUTE, ntstatus::NtError};
+
+/// MM_ALLOCATE_FULLY_REQUIRED
+const MM_ALLOCATE_FULLY_REQUIRED: u32 = 0x00000004;
+
+/// the page size of x86/x64
+pub const PAGE_SIZE: usize = 0x1000;
+
+#[inline]
+fn physical(address: u64) -> PHYSICAL_ADDRESS {
+    PHYSICAL_ADDRESS {
+        QuadPart: address as _,
+    }
+}
+
+/// A physically contiguous nonpaged buffer, freed on drop
+pub struct ContiguousBuffer {
+    base: NonNull<u8>,
+    len: usize,
+}
+
+impl ContiguousBuffer {
+    /// allocate `len` bytes of physically contiguous memory anywhere in the physical address space
+    ///
+    /// it must be called at IRQL <= DISPATCH_LEVEL, and should be called early since the physical memory
+    /// becomes fragmented over time
+    pub fn alloc(len: usize, cache_type: MEMORY_CACHING_TYPE) -> Result<Self, NtError> {
+        Self::alloc_in_range(len, 0, u64::MAX, 0, cache_type)
+    }
+
+    /// allocate `len` bytes of physically contiguous memory in [`lowest`, `highest`], which does not cross a
+    /// multiple of `boundary`(0 for no restriction)
+    pub fn alloc_in_range(
+        len: usize,
+        lowest: u64,
+        highest: u64,
+        boundary: u64,
+        cache_type: MEMORY_CACHING_TYPE,
+    ) -> Result<Self, NtError> {
+        if len == 0 {
+            return Err(NtError::new(STATUS_INVALID_PARAMETER));
+        }
+
+        let base = unsafe {
+            MmAllocateContiguousMemorySpecifyCacheNode(
+                len as _,
+                physical(lowest),
+                physical(highest),
+                physical(boundary),
+                cache_type,
+                MM_ANY_NODE_OK,
+            )
+        };
+
+        let base = NonNull::new(base.cast()).ok_or(NtError::new(STATUS_INSUFFICIENT_RESOURCES))?;
+
+        Ok(Self { base, len })
+    }
+
+    /// the physical address of the first byte
+    pub fn physical_address(&self) -> u64 {
+        unsafe { MmGetPhysicalAddress(self.base.as_ptr().cast()).QuadPart as _ }
+    }
+
+    pub fn as_ptr(&self) -> PVOID {
+        self.base.as_ptr().cast()
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn as_slice(&self) -> &[u8] {
+        unsafe { slice::from_raw_parts(self.base.as_ptr(), self.len) }
+    }
+
+    pub fn as_mut_slice(&mut self) -> &mut [u8] {
+        unsafe { slice::from_raw_parts_mut(self.base.as_ptr(), self.len) }
+    }
+}
+
+impl Drop for ContiguousBuffer {
+    fn drop(&mut self) {
+        unsafe { MmFreeContiguousMemory(self.base.as_ptr().cast()) };
+    }
+}
+
+unsafe impl Send for ContiguousBuffer {}
+unsafe impl Sync for ContiguousBuffer {}
+
+/// A set of locked physical pages described by an MDL, the pages are freed on drop
+pub struct PageSet {
+    mdl: NonNull<wdk_sys::MDL>,
+    mapped: Option<NonNull<u8>>,
+    /// the caching type of the allocation, the mapping must use the same one
+    cache_type: MEMORY_CACHING_TYPE,
+}
+
+impl PageSet {
+    /// allocate `len` bytes(rounded up to pages) of physical memory, all the pages must be allocated
+    pub fn alloc(len: usize, cache_type: MEMORY_CACHING_TYPE) -> Result<Self, NtError> {
+        if len == 0 {
+            return Err(NtError::new(STATUS_INVALID_PARAMETER));
+        }
+
+        let mdl = unsafe {
+            MmAllocatePagesForMdlEx(
+                physical(0),
+                physical(u64::MAX),
+                physical(0),
+                len as _,
+                cache_type,
+                MM_ALLOCATE_FULLY_REQUIRED,
+            )
+        };
+
+        let mdl = NonNull::new(mdl).ok_or(NtError::new(STATUS_INSUFFICIENT_RESOURCES))?;
+
+        Ok(Self {
+            mdl,
+            mapped: None,
+            cache_type,
+        })
+    }
+
+    /// allocate cached pages
+    pub fn alloc_cached(len: usize) -> Result<Self, NtError> {
+        Self::alloc(len, MmCached)
+    }
+
+    pub fn as_mdl(&self) -> PMDL {
+        self.mdl.as_ptr()
+    }
+
+    /// the number of bytes described by the MDL
+    pub fn len(&self) -> usize {
+        unsafe { self.mdl.as_ref().ByteCount as _ }
+    }
+
+    /// the page frame numbers of the pages
+    pub fn pfns(&self) -> &[PFN_NUMBER] {
+        let count = self.len().div_ceil(PAGE_SIZE);
+
+        // the PFN array follows the MDL header
+        unsafe { slice::from_raw_parts(self.mdl.as_ptr().add(1).cast(), count) }
+    }
+
+    /// the physical address of page `index`
+    pub fn physical_address(&self, index: usize) -> Option<u64> {
+        self.pfns()
+            .get(index)
+            .map(|pfn| *pfn as u64 * PAGE_SIZE as u64)
+    }
+
+    /// map the pages into system space with the caching type of the allocation, the mapping is kept until drop
+    pub fn map(&mut self) -> Result<&mut [u8], NtError> {
+        if self.mapped.is_none() {
+            let base = unsafe {
+                MmMapLockedPagesSpecifyCache(
+                    self.mdl.as_ptr(),
+                    KernelMode as _,
+                    self.cache_type,
+                    ptr::null_mut(),
+                    0,
+                    NormalPagePriority as u32 | MDL_MAPPING_NO_EXECUTE,
+                )
+            };
+
+            self.mapped =
+                Some(NonNull::new(base.cast()).ok_or(NtError::new(STATUS_INSUFFICIENT_RESOURCES))?);
+        }
+
+        let base = self.mapped.unwrap();
+
+        Ok(unsafe { slice::from_raw_parts_mut(base.as_ptr(), self.len()) })
+    }
+}
+
+impl Drop for PageSet {
+    fn drop(&mut self) {
+        unsafe {
+            if let Some(base) = self.mapped {
+                MmUnmapLockedPages(base.as_ptr().cast(), self.mdl.as_ptr());
+            }
+
+            MmFreePagesFromMdl(self.mdl.as_ptr());
+            IoFreeMdl(self.mdl.as_ptr());
+        }
+    }
+}
+
+unsafe impl Send for PageSet {}
+unsafe impl Sync for PageSet {}
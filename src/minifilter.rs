@@ -0,0 +1,646 @@
+//! this module provides a file system minifilter registration built on FltMgr, it requires the `minifilter` feature
+//!
+//! a user type implements `PreOperation` and `PostOperation`, and receives the callbacks of the registered major
+//! functions, including the fast I/O and the file system filter callbacks routed by FltMgr
+//!
+//! # Note
+//! FltMgr loads the filter at the altitude stored in `{RegistryPath}\Instances`, it is usually written by the INF,
+//! `install_instance` writes it for a driver installed without an INF
+//!
+//! # Example
+//! ```
+//! struct Monitor;
+//!
+//! impl PreOperation for Monitor {
+//!     fn pre_operation(&self, data: &mut CallbackData, objects: &RelatedObjects) -> PreOpStatus {
+//!         if data.is_fast_io() {
+//!             return PreOpStatus::DisallowFastIo;
+//!         }
+//!
+//!         PreOpStatus::SuccessWithCallback
+//!     }
+//! }
+//!
+//! impl PostOperation for Monitor {}
+//!
+//! install_instance(registry_path, "Monitor", "370020")?;
+//!
+//! let filter = MiniFilter::builder(Monitor)
+//!     .operation(IRP_MJ_CREATE as _)
+//!     .operation(IRP_MJ_WRITE as _)
+//!     .register(driver)?;
+//!
+//! filter.start()?;
+//! ```
+use core::{
+    cell::UnsafeCell,
+    ffi::c_void,
+    mem, ptr,
+    sync::atomic::{AtomicPtr, Ordering},
+};
+
+use alloc::{boxed::Box, format, vec::Vec};
+use wdk_sys::{
+    EX_PUSH_LOCK, HANDLE, IO_STATUS_BLOCK, KEY_ALL_ACCESS, KPROCESSOR_MODE, NTSTATUS,
+    OBJ_CASE_INSENSITIVE, OBJ_KERNEL_HANDLE, PDRIVER_OBJECT, PETHREAD, PFILE_OBJECT, PVOID,
+    REG_DWORD, REG_OPTION_NON_VOLATILE, REG_SZ, SIZE_T, STATUS_INSUFFICIENT_RESOURCES, ULONG,
+    UNICODE_STRING, USHORT,
+    ntddk::{ZwClose, ZwCreateKey, ZwSetValueKey},
+};
+
+use crate::{
+    initialize_object_attributes,
+    mutex::{Locked, Mutex, try_new_zeroed},
+    ntstatus::{NtError, cvt},
+    utils,
+};
+
+pub type PFLT_FILTER = PVOID;
+pub type PFLT_VOLUME = PVOID;
+pub type PFLT_INSTANCE = PVOID;
+
+/// FLT_PREOP_CALLBACK_STATUS
+#[repr(i32)]
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum PreOpStatus {
+    /// call the post-operation callback
+    SuccessWithCallback = 0,
+    /// do not call the post-operation callback
+    SuccessNoCallback = 1,
+    /// the filter completed the operation, the status is set with `CallbackData::set_status`
+    Complete = 4,
+    /// ask the I/O manager to reissue a fast I/O operation as an IRP
+    DisallowFastIo = 3,
+    /// call the post-operation callback in the context of the same thread at IRQL <= APC_LEVEL
+    Synchronize = 5,
+}
+
+/// FLT_POSTOP_CALLBACK_STATUS
+#[repr(i32)]
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum PostOpStatus {
+    FinishedProcessing = 0,
+}
+
+/// FLTFL_CALLBACK_DATA_IRP_OPERATION
+const CALLBACK_DATA_IRP_OPERATION: ULONG = 0x00000001;
+/// FLTFL_CALLBACK_DATA_FAST_IO_OPERATION
+const CALLBACK_DATA_FAST_IO_OPERATION: ULONG = 0x00000002;
+/// FLTFL_POST_OPERATION_DRAINING
+const POST_OPERATION_DRAINING: ULONG = 0x00000001;
+
+/// IRP_MJ_OPERATION_END
+const OPERATION_END: u8 = 0x80;
+/// FLT_CONTEXT_END
+const CONTEXT_END: USHORT = 0xffff;
+/// FLT_REGISTRATION_VERSION_0202
+const REGISTRATION_VERSION: USHORT = 0x0202;
+
+#[repr(C)]
+pub struct FLT_IO_PARAMETER_BLOCK {
+    pub IrpFlags: ULONG,
+    pub MajorFunction: u8,
+    pub MinorFunction: u8,
+    pub OperationFlags: u8,
+    pub Reserved: u8,
+    pub TargetFileObject: PFILE_OBJECT,
+    pub TargetInstance: PFLT_INSTANCE,
+    /// FLT_PARAMETERS, a union of the operation specific parameters, only the header fields are declared
+    pub Parameters: [PVOID; 0],
+}
+
+#[repr(C)]
+pub struct FLT_CALLBACK_DATA {
+    pub Flags: ULONG,
+    pub Thread: PETHREAD,
+    pub Iopb: *mut FLT_IO_PARAMETER_BLOCK,
+    pub IoStatus: IO_STATUS_BLOCK,
+    pub TagData: PVOID,
+    /// QueueLinks + QueueContext or FilterContext
+    pub Reserved: [PVOID; 4],
+    pub RequestorMode: KPROCESSOR_MODE,
+}
+
+#[repr(C)]
+pub struct FLT_RELATED_OBJECTS {
+    pub Size: USHORT,
+    pub TransactionContext: USHORT,
+    pub Filter: PFLT_FILTER,
+    pub Volume: PFLT_VOLUME,
+    pub Instance: PFLT_INSTANCE,
+    pub FileObject: PFILE_OBJECT,
+    pub Transaction: PVOID,
+}
+
+type PreOperationCallback = Option<
+    unsafe extern "C" fn(
+        Data: *mut FLT_CALLBACK_DATA,
+        FltObjects: *const FLT_RELATED_OBJECTS,
+        CompletionContext: *mut PVOID,
+    ) -> i32,
+>;
+
+type PostOperationCallback = Option<
+    unsafe extern "C" fn(
+        Data: *mut FLT_CALLBACK_DATA,
+        FltObjects: *const FLT_RELATED_OBJECTS,
+        CompletionContext: PVOID,
+        Flags: ULONG,
+    ) -> i32,
+>;
+
+pub type FilterUnloadCallback = Option<unsafe extern "C" fn(Flags: ULONG) -> NTSTATUS>;
+
+type ContextCleanupCallback = Option<unsafe extern "C" fn(Context: PVOID, ContextType: USHORT)>;
+
+#[repr(C)]
+struct FLT_OPERATION_REGISTRATION {
+    MajorFunction: u8,
+    Flags: ULONG,
+    PreOperation: PreOperationCallback,
+    PostOperation: PostOperationCallback,
+    Reserved1: PVOID,
+}
+
+#[repr(C)]
+struct FLT_CONTEXT_REGISTRATION {
+    ContextType: USHORT,
+    Flags: USHORT,
+    ContextCleanupCallback: ContextCleanupCallback,
+    Size: SIZE_T,
+    PoolTag: ULONG,
+    ContextAllocateCallback: PVOID,
+    ContextFreeCallback: PVOID,
+    Reserved1: PVOID,
+}
+
+#[repr(C)]
+struct FLT_REGISTRATION {
+    Size: USHORT,
+    Version: USHORT,
+    Flags: ULONG,
+    ContextRegistration: *const FLT_CONTEXT_REGISTRATION,
+    OperationRegistration: *const FLT_OPERATION_REGISTRATION,
+    FilterUnloadCallback: FilterUnloadCallback,
+    InstanceSetupCallback: PVOID,
+    InstanceQueryTeardownCallback: PVOID,
+    InstanceTeardownStartCallback: PVOID,
+    InstanceTeardownCompleteCallback: PVOID,
+    GenerateFileNameCallback: PVOID,
+    NormalizeNameComponentCallback: PVOID,
+    NormalizeContextCleanupCallback: PVOID,
+    TransactionNotificationCallback: PVOID,
+    NormalizeNameComponentExCallback: PVOID,
+}
+
+#[link(name = "FltMgr")]
+unsafe extern "C" {
+    fn FltRegisterFilter(
+        Driver: PDRIVER_OBJECT,
+        Registration: *const FLT_REGISTRATION,
+        RetFilter: *mut PFLT_FILTER,
+    ) -> NTSTATUS;
+
+    fn FltStartFiltering(Filter: PFLT_FILTER) -> NTSTATUS;
+
+    fn FltUnregisterFilter(Filter: PFLT_FILTER);
+
+    fn FltInitializePushLock(PushLock: *mut EX_PUSH_LOCK);
+
+    fn FltDeletePushLock(PushLock: *mut EX_PUSH_LOCK);
+
+    fn FltAcquirePushLockExclusive(PushLock: *mut EX_PUSH_LOCK);
+
+    fn FltAcquirePushLockShared(PushLock: *mut EX_PUSH_LOCK);
+
+    fn FltReleasePushLock(PushLock: *mut EX_PUSH_LOCK);
+}
+
+/// the parameters of an I/O operation seen by a minifilter
+pub struct CallbackData<'a>(&'a mut FLT_CALLBACK_DATA);
+
+impl<'a> CallbackData<'a> {
+    pub fn as_raw(&self) -> *mut FLT_CALLBACK_DATA {
+        self.0 as *const _ as _
+    }
+
+    fn iopb(&self) -> &FLT_IO_PARAMETER_BLOCK {
+        unsafe { &*self.0.Iopb }
+    }
+
+    pub fn major_function(&self) -> u8 {
+        self.iopb().MajorFunction
+    }
+
+    pub fn minor_function(&self) -> u8 {
+        self.iopb().MinorFunction
+    }
+
+    pub fn is_irp(&self) -> bool {
+        self.0.Flags & CALLBACK_DATA_IRP_OPERATION != 0
+    }
+
+    pub fn is_fast_io(&self) -> bool {
+        self.0.Flags & CALLBACK_DATA_FAST_IO_OPERATION != 0
+    }
+
+    pub fn requestor_mode(&self) -> KPROCESSOR_MODE {
+        self.0.RequestorMode
+    }
+
+    pub fn thread(&self) -> PETHREAD {
+        self.0.Thread
+    }
+
+    pub fn file_object(&self) -> PFILE_OBJECT {
+        self.iopb().TargetFileObject
+    }
+
+    pub fn status(&self) -> NTSTATUS {
+        unsafe { self.0.IoStatus.__bindgen_anon_1.Status }
+    }
+
+    /// set the final status, used with `PreOpStatus::Complete`
+    pub fn set_status(&mut self, status: NTSTATUS, information: u64) {
+        self.0.IoStatus.__bindgen_anon_1.Status = status;
+        self.0.IoStatus.Information = information;
+    }
+}
+
+/// the objects related to an I/O operation
+pub struct RelatedObjects<'a>(&'a FLT_RELATED_OBJECTS);
+
+impl<'a> RelatedObjects<'a> {
+    pub fn filter(&self) -> PFLT_FILTER {
+        self.0.Filter
+    }
+
+    pub fn volume(&self) -> PFLT_VOLUME {
+        self.0.Volume
+    }
+
+    pub fn instance(&self) -> PFLT_INSTANCE {
+        self.0.Instance
+    }
+
+    pub fn file_object(&self) -> PFILE_OBJECT {
+        self.0.FileObject
+    }
+}
+
+/// the pre-operation callback of a minifilter
+pub trait PreOperation: Send + Sync {
+    fn pre_operation(&self, data: &mut CallbackData, objects: &RelatedObjects) -> PreOpStatus {
+        PreOpStatus::SuccessNoCallback
+    }
+}
+
+/// the post-operation callback of a minifilter, it is only called when the pre-operation returns
+/// `PreOpStatus::SuccessWithCallback` or `PreOpStatus::Synchronize`
+///
+/// `draining` is true when the instance is being detached, the callback must clean up and return
+pub trait PostOperation: Send + Sync {
+    fn post_operation(
+        &self,
+        _data: &mut CallbackData,
+        _objects: &RelatedObjects,
+        _draining: bool,
+    ) -> PostOpStatus {
+        PostOpStatus::FinishedProcessing
+    }
+}
+
+/// the handler of the registered filter, FltMgr callbacks carry no registration context
+static HANDLER: AtomicPtr<c_void> = AtomicPtr::new(ptr::null_mut());
+
+/// A builder of `MiniFilter`
+pub struct MiniFilterBuilder<T: PreOperation + PostOperation> {
+    handler: Box<T>,
+    operations: Vec<FLT_OPERATION_REGISTRATION>,
+    contexts: Vec<FLT_CONTEXT_REGISTRATION>,
+    unload: FilterUnloadCallback,
+}
+
+impl<T: PreOperation + PostOperation + 'static> MiniFilterBuilder<T> {
+    /// intercept the major function `major`(IRP_MJ_XXX)
+    pub fn operation(mut self, major: u8) -> Self {
+        self.operations.push(FLT_OPERATION_REGISTRATION {
+            MajorFunction: major,
+            Flags: 0,
+            PreOperation: Some(pre_operation_stub::<T>),
+            PostOperation: Some(post_operation_stub::<T>),
+            Reserved1: ptr::null_mut(),
+        });
+
+        self
+    }
+
+    /// register a context type(FLT_STREAM_CONTEXT, FLT_INSTANCE_CONTEXT...) of `size` bytes allocated with `tag`
+    pub fn context(mut self, context_type: USHORT, size: usize, tag: u32) -> Self {
+        self.contexts.push(FLT_CONTEXT_REGISTRATION {
+            ContextType: context_type,
+            Flags: 0,
+            ContextCleanupCallback: None,
+            Size: size as _,
+            PoolTag: tag,
+            ContextAllocateCallback: ptr::null_mut(),
+            ContextFreeCallback: ptr::null_mut(),
+            Reserved1: ptr::null_mut(),
+        });
+
+        self
+    }
+
+    /// the FilterUnloadCallback, the filter can not be unloaded by FltMgr without it
+    ///
+    /// the callback must drop the `MiniFilter`, which unregisters the filter
+    pub fn unload_callback(mut self, callback: FilterUnloadCallback) -> Self {
+        self.unload = callback;
+        self
+    }
+
+    pub fn register(mut self, driver: PDRIVER_OBJECT) -> Result<MiniFilter<T>, NtError> {
+        self.operations.push(FLT_OPERATION_REGISTRATION {
+            MajorFunction: OPERATION_END,
+            Flags: 0,
+            PreOperation: None,
+            PostOperation: None,
+            Reserved1: ptr::null_mut(),
+        });
+
+        self.contexts.push(FLT_CONTEXT_REGISTRATION {
+            ContextType: CONTEXT_END,
+            Flags: 0,
+            ContextCleanupCallback: None,
+            Size: 0,
+            PoolTag: 0,
+            ContextAllocateCallback: ptr::null_mut(),
+            ContextFreeCallback: ptr::null_mut(),
+            Reserved1: ptr::null_mut(),
+        });
+
+        let registration = FLT_REGISTRATION {
+            Size: mem::size_of::<FLT_REGISTRATION>() as _,
+            Version: REGISTRATION_VERSION,
+            Flags: 0,
+            ContextRegistration: self.contexts.as_ptr(),
+            OperationRegistration: self.operations.as_ptr(),
+            FilterUnloadCallback: self.unload,
+            InstanceSetupCallback: ptr::null_mut(),
+            InstanceQueryTeardownCallback: ptr::null_mut(),
+            InstanceTeardownStartCallback: ptr::null_mut(),
+            InstanceTeardownCompleteCallback: ptr::null_mut(),
+            GenerateFileNameCallback: ptr::null_mut(),
+            NormalizeNameComponentCallback: ptr::null_mut(),
+            NormalizeContextCleanupCallback: ptr::null_mut(),
+            TransactionNotificationCallback: ptr::null_mut(),
+            NormalizeNameComponentExCallback: ptr::null_mut(),
+        };
+
+        HANDLER.store(self.handler.as_ref() as *const T as _, Ordering::Release);
+
+        let mut filter: PFLT_FILTER = ptr::null_mut();
+
+        if let Err(e) = cvt(unsafe { FltRegisterFilter(driver, &registration, &mut filter) }) {
+            HANDLER.store(ptr::null_mut(), Ordering::Release);
+            return Err(e);
+        }
+
+        Ok(MiniFilter {
+            filter,
+            handler: self.handler,
+            _operations: self.operations,
+            _contexts: self.contexts,
+        })
+    }
+}
+
+/// A registered minifilter, it is unregistered on drop
+///
+/// only one minifilter can be registered by a driver
+pub struct MiniFilter<T: PreOperation + PostOperation> {
+    filter: PFLT_FILTER,
+    handler: Box<T>,
+    _operations: Vec<FLT_OPERATION_REGISTRATION>,
+    _contexts: Vec<FLT_CONTEXT_REGISTRATION>,
+}
+
+impl<T: PreOperation + PostOperation + 'static> MiniFilter<T> {
+    pub fn builder(handler: T) -> MiniFilterBuilder<T> {
+        MiniFilterBuilder {
+            handler: Box::new(handler),
+            operations: Vec::new(),
+            contexts: Vec::new(),
+            unload: None,
+        }
+    }
+
+    /// start receiving the callbacks
+    pub fn start(&self) -> Result<(), NtError> {
+        cvt(unsafe { FltStartFiltering(self.filter) })
+    }
+
+    pub fn as_raw(&self) -> PFLT_FILTER {
+        self.filter
+    }
+
+    pub fn handler(&self) -> &T {
+        &self.handler
+    }
+}
+
+impl<T: PreOperation + PostOperation> Drop for MiniFilter<T> {
+    fn drop(&mut self) {
+        // FltUnregisterFilter waits for all the outstanding callbacks
+        unsafe { FltUnregisterFilter(self.filter) };
+
+        HANDLER.store(ptr::null_mut(), Ordering::Release);
+    }
+}
+
+unsafe impl<T: PreOperation + PostOperation> Send for MiniFilter<T> {}
+unsafe impl<T: PreOperation + PostOperation> Sync for MiniFilter<T> {}
+
+extern "C" fn pre_operation_stub<T: PreOperation>(
+    data: *mut FLT_CALLBACK_DATA,
+    objects: *const FLT_RELATED_OBJECTS,
+    _completion_context: *mut PVOID,
+) -> i32 {
+    let handler = HANDLER.load(Ordering::Acquire);
+
+    if handler.is_null() {
+        return PreOpStatus::SuccessNoCallback as _;
+    }
+
+    let handler = unsafe { &*(handler as *const T) };
+
+    let mut data = CallbackData(unsafe { &mut *data });
+    let objects = RelatedObjects(unsafe { &*objects });
+
+    handler.pre_operation(&mut data, &objects) as _
+}
+
+extern "C" fn post_operation_stub<T: PostOperation>(
+    data: *mut FLT_CALLBACK_DATA,
+    objects: *const FLT_RELATED_OBJECTS,
+    _completion_context: PVOID,
+    flags: ULONG,
+) -> i32 {
+    let handler = HANDLER.load(Ordering::Acquire);
+
+    if handler.is_null() {
+        return PostOpStatus::FinishedProcessing as _;
+    }
+
+    let handler = unsafe { &*(handler as *const T) };
+
+    let mut data = CallbackData(unsafe { &mut *data });
+    let objects = RelatedObjects(unsafe { &*objects });
+
+    handler.post_operation(&mut data, &objects, flags & POST_OPERATION_DRAINING != 0) as _
+}
+
+/// A FltMgr push lock, shareable, it can be used with `Locked<T, FltPushLockMutex>`
+///
+/// the Flt push lock routines disable normal kernel APCs by themselves
+#[repr(transparent)]
+pub struct FltPushLockMutex(UnsafeCell<EX_PUSH_LOCK>);
+
+impl Mutex for FltPushLockMutex {
+    type Target = Self;
+
+    fn uninit() -> Self {
+        Self(UnsafeCell::new(unsafe { core::mem::zeroed() }))
+    }
+
+    fn try_new() -> Result<Box<Self>, NtError> {
+        // a zeroed EX_PUSH_LOCK is valid before `FltInitializePushLock`
+        unsafe { try_new_zeroed(Self::init) }
+    }
+
+    fn init(&mut self) -> Result<(), NtError> {
+        unsafe { FltInitializePushLock(self.0.get()) };
+        Ok(())
+    }
+
+    fn shareable() -> bool {
+        true
+    }
+
+    fn lock(&self) {
+        unsafe { FltAcquirePushLockExclusive(self.0.get()) };
+    }
+
+    fn unlock(&self) {
+        unsafe { FltReleasePushLock(self.0.get()) };
+    }
+
+    fn lock_shared(&self) {
+        unsafe { FltAcquirePushLockShared(self.0.get()) };
+    }
+
+    fn unlock_shared(&self) {
+        unsafe { FltReleasePushLock(self.0.get()) };
+    }
+}
+
+impl Drop for FltPushLockMutex {
+    fn drop(&mut self) {
+        unsafe { FltDeletePushLock(self.0.get()) };
+    }
+}
+
+pub type FltPushLocked<T> = Locked<T, FltPushLockMutex>;
+
+fn create_key(path: &str) -> Result<HANDLE, NtError> {
+    let mut name =
+        utils::utf16_from_str(path).ok_or(NtError::new(STATUS_INSUFFICIENT_RESOURCES))?;
+
+    let mut attr = initialize_object_attributes!(
+        name.as_mut(),
+        OBJ_CASE_INSENSITIVE | OBJ_KERNEL_HANDLE,
+        ptr::null_mut(),
+        ptr::null_mut()
+    );
+
+    let mut key: HANDLE = ptr::null_mut();
+
+    cvt(unsafe {
+        ZwCreateKey(
+            &mut key,
+            KEY_ALL_ACCESS,
+            &mut attr,
+            0,
+            ptr::null_mut(),
+            REG_OPTION_NON_VOLATILE,
+            ptr::null_mut(),
+        )
+    })?;
+
+    Ok(key)
+}
+
+fn set_value(key: HANDLE, name: &str, r#type: u32, data: &[u8]) -> Result<(), NtError> {
+    let mut name =
+        utils::utf16_from_str(name).ok_or(NtError::new(STATUS_INSUFFICIENT_RESOURCES))?;
+
+    cvt(unsafe {
+        ZwSetValueKey(
+            key,
+            name.as_mut(),
+            0,
+            r#type,
+            data.as_ptr() as _,
+            data.len() as _,
+        )
+    })
+}
+
+fn set_string_value(key: HANDLE, name: &str, value: &str) -> Result<(), NtError> {
+    let data: Vec<u8> = value
+        .encode_utf16()
+        .chain(Some(0))
+        .flat_map(|c| c.to_le_bytes())
+        .collect();
+
+    set_value(key, name, REG_SZ, &data)
+}
+
+/// write the default instance and its `altitude` under `{registry_path}\Instances`, it must be called before
+/// `MiniFilter::register`
+///
+/// # Parameters
+/// - registry_path: the `RegistryPath` passed to DriverEntry
+/// - instance: the name of the instance, "{instance} Instance" is used as the key name
+/// - altitude: the altitude allocated by Microsoft, for example "370020"
+pub fn install_instance(
+    registry_path: &UNICODE_STRING,
+    instance: &str,
+    altitude: &str,
+) -> Result<(), NtError> {
+    let path = unsafe {
+        alloc::string::String::from_utf16_lossy(core::slice::from_raw_parts(
+            registry_path.Buffer,
+            registry_path.Length as usize / 2,
+        ))
+    };
+
+    let instance = format!("{} Instance", instance);
+
+    let instances = create_key(&format!("{}\\Instances", path))?;
+
+    let result = set_string_value(instances, "DefaultInstance", &instance);
+
+    unsafe { ZwClose(instances) };
+
+    result?;
+
+    let key = create_key(&format!("{}\\Instances\\{}", path, instance))?;
+
+    let result = set_string_value(key, "Altitude", altitude)
+        .and_then(|_| set_value(key, "Flags", REG_DWORD, &0u32.to_le_bytes()));
+
+    unsafe { ZwClose(key) };
+
+    result
+}
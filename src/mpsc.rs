@@ -0,0 +1,273 @@
+//! this module provides a bounded multi-producer single-consumer channel
+//!
+//! the buffer is allocated when the channel is created, so `send` never allocates and can be called from a DPC,
+//! the receiver can poll with `try_recv` or block with `recv` at PASSIVE_LEVEL, with the `async` feature it can
+//! also be awaited with `recv_async`
+//!
+//! # Example
+//! ```
+//! let (tx, rx) = mpsc::channel::<Request>(64)?;
+//!
+//! for _ in 0..4 {
+//!     let tx = tx.clone();
+//!
+//!     thread::spawn(move || {
+//!         while let Some(request) = next_request() {
+//!             let _ = tx.send(request);
+//!         }
+//!     })?;
+//! }
+//!
+//! drop(tx);
+//!
+//! // fails with STATUS_PIPE_BROKEN once all the senders are dropped and the channel is drained
+//! while let Ok(request) = rx.recv() {
+//!     handle(request);
+//! }
+//! ```
+use core::time::Duration;
+
+#[cfg(feature = "async")]
+use core::{
+    future::Future,
+    pin::Pin,
+    task::{Context, Poll, Waker},
+};
+
+use alloc::collections::VecDeque;
+use wdk_sys::{
+    STATUS_INSUFFICIENT_RESOURCES, STATUS_INVALID_PARAMETER, STATUS_PIPE_BROKEN, STATUS_TIMEOUT,
+};
+
+use crate::{
+    arc::KArc,
+    event::{Event, EventProperty},
+    kobject::Dispatchable,
+    mutex::StaticSpinLocked,
+    ntstatus::NtError,
+};
+
+struct State<T> {
+    items: VecDeque<T>,
+    capacity: usize,
+    senders: usize,
+    receiver_alive: bool,
+    #[cfg(feature = "async")]
+    waker: Option<Waker>,
+}
+
+struct Inner<T> {
+    state: StaticSpinLocked<State<T>>,
+    /// an auto-reset event, set whenever an item is sent or the last sender is dropped
+    event: Event,
+}
+
+impl<T> Inner<T> {
+    fn notify(&self) {
+        self.event.set();
+
+        #[cfg(feature = "async")]
+        {
+            let waker = self.state.lock().waker.take();
+
+            if let Some(waker) = waker {
+                waker.wake();
+            }
+        }
+    }
+}
+
+/// create a channel which buffers up to `capacity` items
+///
+/// it fails with STATUS_INVALID_PARAMETER if `capacity` is 0, or STATUS_INSUFFICIENT_RESOURCES if the buffer can
+/// not be allocated
+pub fn channel<T: Send>(capacity: usize) -> Result<(Sender<T>, Receiver<T>), NtError> {
+    if capacity == 0 {
+        return Err(NtError::new(STATUS_INVALID_PARAMETER));
+    }
+
+    let mut items = VecDeque::new();
+
+    items
+        .try_reserve_exact(capacity)
+        .map_err(|_| NtError::new(STATUS_INSUFFICIENT_RESOURCES))?;
+
+    let inner = KArc::new(Inner {
+        state: StaticSpinLocked::new(State {
+            items,
+            capacity,
+            senders: 1,
+            receiver_alive: true,
+            #[cfg(feature = "async")]
+            waker: None,
+        }),
+        event: EventProperty::new().auto_reset(true).new_event()?,
+    })?;
+
+    Ok((
+        Sender {
+            inner: inner.clone(),
+        },
+        Receiver { inner },
+    ))
+}
+
+/// The sending half of a channel, it can be cloned for more producers
+pub struct Sender<T> {
+    inner: KArc<Inner<T>>,
+}
+
+impl<T> Sender<T> {
+    /// send a value at IRQL <= DISPATCH_LEVEL, the value is given back if the channel is full or the receiver has
+    /// been dropped
+    pub fn send(&self, value: T) -> Result<(), T> {
+        {
+            let mut state = self.inner.state.lock();
+
+            if !state.receiver_alive || state.items.len() >= state.capacity {
+                return Err(value);
+            }
+
+            state.items.push_back(value);
+        }
+
+        self.inner.notify();
+
+        Ok(())
+    }
+
+    /// true if the receiver has been dropped
+    pub fn is_closed(&self) -> bool {
+        !self.inner.state.lock().receiver_alive
+    }
+}
+
+impl<T> Clone for Sender<T> {
+    fn clone(&self) -> Self {
+        self.inner.state.lock().senders += 1;
+
+        Self {
+            inner: self.inner.clone(),
+        }
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        let last = {
+            let mut state = self.inner.state.lock();
+
+            state.senders -= 1;
+            state.senders == 0
+        };
+
+        if last {
+            self.inner.notify();
+        }
+    }
+}
+
+/// The receiving half of a channel
+pub struct Receiver<T> {
+    inner: KArc<Inner<T>>,
+}
+
+impl<T> Receiver<T> {
+    /// take the oldest item at IRQL <= DISPATCH_LEVEL
+    ///
+    /// returns `Ok(None)` if the channel is empty, or STATUS_PIPE_BROKEN if it is empty and all the senders are
+    /// dropped
+    pub fn try_recv(&self) -> Result<Option<T>, NtError> {
+        let mut state = self.inner.state.lock();
+
+        match state.items.pop_front() {
+            Some(value) => Ok(Some(value)),
+            None if state.senders == 0 => Err(NtError::new(STATUS_PIPE_BROKEN)),
+            None => Ok(None),
+        }
+    }
+
+    /// block until an item is received, it must be called at IRQL <= APC_LEVEL
+    pub fn recv(&self) -> Result<T, NtError> {
+        loop {
+            if let Some(value) = self.try_recv()? {
+                return Ok(value);
+            }
+
+            self.inner.event.wait(false);
+        }
+    }
+
+    /// same as `recv`, but fails with STATUS_TIMEOUT if nothing is received in time
+    pub fn recv_timeout(&self, timeout: Duration) -> Result<T, NtError> {
+        loop {
+            if let Some(value) = self.try_recv()? {
+                return Ok(value);
+            }
+
+            if self.inner.event.wait_for(timeout, false).timed_out() {
+                return self.try_recv()?.ok_or_else(|| NtError::new(STATUS_TIMEOUT));
+            }
+        }
+    }
+
+    /// returns a future of the next item, it resolves to STATUS_PIPE_BROKEN like `recv`
+    #[cfg(feature = "async")]
+    pub fn recv_async(&mut self) -> Recv<'_, T> {
+        Recv { receiver: self }
+    }
+
+    pub fn len(&self) -> usize {
+        self.inner.state.lock().items.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl<T> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        // the pending items are dropped without the lock
+        let items = {
+            let mut state = self.inner.state.lock();
+
+            state.receiver_alive = false;
+            core::mem::take(&mut state.items)
+        };
+
+        drop(items);
+    }
+}
+
+/// The future returned by `Receiver::recv_async`
+#[cfg(feature = "async")]
+pub struct Recv<'a, T> {
+    receiver: &'a mut Receiver<T>,
+}
+
+#[cfg(feature = "async")]
+impl<T> Future for Recv<'_, T> {
+    type Output = Result<T, NtError>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let old = {
+            let mut state = self.receiver.inner.state.lock();
+
+            if let Some(value) = state.items.pop_front() {
+                return Poll::Ready(Ok(value));
+            }
+
+            if state.senders == 0 {
+                return Poll::Ready(Err(NtError::new(STATUS_PIPE_BROKEN)));
+            }
+
+            // the item and the waker are checked under the same lock, so no wake up is missed
+            state.waker.replace(cx.waker().clone())
+        };
+
+        drop(old);
+
+        Poll::Pending
+    }
+}
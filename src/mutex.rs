@@ -1,29 +1,49 @@
-use crate::{ntstatus::{cvt, NtError}, utils::ex_allocate_pool_zero};
+#[cfg(feature = "fault-injection")]
+use crate::fault::{self, FaultSite};
+#[cfg(feature = "lock-stats")]
+use crate::stats::{LockCounters, LockStats};
+#[cfg(feature = "lock-boost")]
+use crate::utils::KeGetCurrentThread;
+use crate::{
+    ntstatus::{NtError, cvt},
+    time::KInstant,
+    utils::{ex_allocate_pool_zero, ex_free_pool, try_box},
+};
+use alloc::boxed::Box;
 use core::{
     cell::UnsafeCell,
     fmt::{Debug, Display},
-    mem::{self},
+    marker::PhantomPinned,
+    mem::{self, ManuallyDrop, MaybeUninit},
     ops::{Deref, DerefMut},
+    pin::Pin,
     ptr::{self, NonNull, drop_in_place},
+    sync::atomic::{AtomicBool, Ordering},
 };
 use wdk_sys::{
     _EVENT_TYPE::SynchronizationEvent,
     _POOL_TYPE::NonPagedPoolNx,
     APC_LEVEL, DISPATCH_LEVEL, ERESOURCE, FALSE, FAST_MUTEX, FM_LOCK_BIT, KGUARDED_MUTEX, KIRQL,
-    KLOCK_QUEUE_HANDLE, KSPIN_LOCK, PKLOCK_QUEUE_HANDLE, PVOID, SIZE_T,
-    STATUS_INSUFFICIENT_RESOURCES, STATUS_SUCCESS, STATUS_UNSUCCESSFUL, TRUE, ULONG,
+    KLOCK_QUEUE_HANDLE, KSPIN_LOCK, PKLOCK_QUEUE_HANDLE, PVOID, SIZE_T, STATUS_SUCCESS,
+    STATUS_UNSUCCESSFUL, TRUE, ULONG,
     ntddk::{
         ExAcquireFastMutex, ExAcquireResourceExclusiveLite, ExAcquireResourceSharedLite,
-        ExDeleteResourceLite, ExFreePoolWithTag, ExInitializeResourceLite, ExReleaseFastMutex,
-        ExReleaseResourceLite, ExTryToAcquireFastMutex, KeAcquireGuardedMutex,
-        KeAcquireInStackQueuedSpinLock, KeAcquireInStackQueuedSpinLockAtDpcLevel,
-        KeAcquireSpinLockAtDpcLevel, KeAcquireSpinLockRaiseToDpc, KeGetCurrentIrql,
-        KeInitializeEvent, KeInitializeGuardedMutex, KeInitializeSpinLock, KeReleaseGuardedMutex,
-        KeReleaseInStackQueuedSpinLock, KeReleaseInStackQueuedSpinLockFromDpcLevel,
-        KeReleaseSpinLock, KeReleaseSpinLockFromDpcLevel, KeTryToAcquireGuardedMutex,
+        ExDeleteResourceLite, ExInitializeResourceLite, ExReleaseFastMutex, ExReleaseResourceLite,
+        ExTryToAcquireFastMutex, KeAcquireGuardedMutex, KeAcquireInStackQueuedSpinLock,
+        KeAcquireInStackQueuedSpinLockAtDpcLevel, KeAcquireSpinLockAtDpcLevel,
+        KeAcquireSpinLockRaiseToDpc, KeEnterCriticalRegion, KeGetCurrentIrql, KeInitializeEvent,
+        KeInitializeGuardedMutex, KeInitializeSpinLock, KeLeaveCriticalRegion,
+        KeReleaseGuardedMutex, KeReleaseInStackQueuedSpinLock,
+        KeReleaseInStackQueuedSpinLockFromDpcLevel, KeReleaseSpinLock,
+        KeReleaseSpinLockFromDpcLevel, KeTryToAcquireGuardedMutex,
         KeTryToAcquireSpinLockAtDpcLevel, memset,
     },
 };
+#[cfg(feature = "lock-boost")]
+use wdk_sys::{
+    KPRIORITY, PKTHREAD, STATUS_NOT_SUPPORTED,
+    ntddk::{KeQueryPriorityThread, KeSetPriorityThread},
+};
 
 fn ExInitializeFastMutex(fast_mutex: *mut FAST_MUTEX) {
     unsafe {
@@ -37,17 +57,59 @@ fn ExInitializeFastMutex(fast_mutex: *mut FAST_MUTEX) {
 
 const MUTEX_TAG: ULONG = u32::from_ne_bytes(*b"xetm");
 
+/// allocate a zeroed `M` and initialize it with `init`, the usual body of `Mutex::try_new`
+///
+/// # Safety
+/// `M` must be valid when all its bytes are zero, e.g. a wrapper of a kernel lock object
+pub(crate) unsafe fn try_new_zeroed<M>(
+    init: impl FnOnce(&mut M) -> Result<(), NtError>,
+) -> Result<Box<M>, NtError> {
+    let mut mutex = try_box(MaybeUninit::<M>::zeroed())?;
+
+    // a failed `init` drops the storage without dropping `M`
+    unsafe {
+        init(&mut *mutex.as_mut_ptr())?;
+
+        Ok(Box::from_raw(Box::into_raw(mutex).cast()))
+    }
+}
+
 pub trait Mutex {
     type Target: Mutex;
 
     fn init(&mut self) -> Result<(), NtError>;
 
+    /// allocate and initialize a standalone mutex, it fails with STATUS_INSUFFICIENT_RESOURCES instead of panicking
+    ///
+    /// an initialized kernel lock object must not be moved, so it is returned boxed, see `try_new_zeroed`
+    fn try_new() -> Result<Box<Self>, NtError>
+    where
+        Self: Sized;
+
+    /// an uninitialized mutex for `InlineLocked`, it is not usable before `init`
+    fn uninit() -> Self
+    where
+        Self: Sized;
+
     fn shareable() -> bool {
         false
     }
 
+    /// true if the owner can be boosted by `Locked::with_boost`, it requires `try_lock`
+    fn boostable() -> bool {
+        false
+    }
+
     fn lock(&self);
 
+    /// acquire the lock exclusively, returns true if it was held by another thread, which is only known when the
+    /// mutex overrides it
+    fn lock_contended(&self) -> bool {
+        self.lock();
+
+        false
+    }
+
     fn try_lock(&self) -> bool {
         unimplemented!("try_lock")
     }
@@ -76,8 +138,22 @@ pub trait QueuedMutex {
 
     fn init(&mut self) -> Result<(), NtError>;
 
+    /// allocate and initialize a standalone mutex, it fails with STATUS_INSUFFICIENT_RESOURCES instead of panicking
+    ///
+    /// an initialized kernel lock object must not be moved, so it is returned boxed, see `try_new_zeroed`
+    fn try_new() -> Result<Box<Self>, NtError>
+    where
+        Self: Sized;
+
     fn lock(&self, handle: PKLOCK_QUEUE_HANDLE);
 
+    /// the same as `Mutex::lock_contended`
+    fn lock_contended(&self, handle: PKLOCK_QUEUE_HANDLE) -> bool {
+        self.lock(handle);
+
+        false
+    }
+
     fn unlock(&self, handle: PKLOCK_QUEUE_HANDLE);
 
     fn irql_ok() -> bool {
@@ -93,8 +169,10 @@ pub struct FastMutex(UnsafeCell<FAST_MUTEX>);
 #[repr(transparent)]
 pub struct GuardedMutex(UnsafeCell<KGUARDED_MUTEX>);
 
+/// an ERESOURCE, `REGION` tells whether it enters a critical region on every acquisition, see `ResourceLockedInRegion`
+/// for a mutex which is only used inside a critical region already
 #[repr(transparent)]
-pub struct ResourceMutex(UnsafeCell<ERESOURCE>);
+pub struct ResourceMutex<const REGION: bool = true>(UnsafeCell<ERESOURCE>);
 
 #[repr(transparent)]
 pub struct SpinMutex(UnsafeCell<SpinLockInner>);
@@ -102,6 +180,14 @@ pub struct SpinMutex(UnsafeCell<SpinLockInner>);
 impl Mutex for EmptyMutex {
     type Target = Self;
 
+    fn uninit() -> Self {
+        EmptyMutex
+    }
+
+    fn try_new() -> Result<Box<Self>, NtError> {
+        try_box(EmptyMutex)
+    }
+
     fn init(&mut self) -> Result<(), NtError> {
         Ok(())
     }
@@ -114,6 +200,29 @@ impl Mutex for EmptyMutex {
 impl Mutex for FastMutex {
     type Target = Self;
 
+    fn uninit() -> Self {
+        // a zeroed FAST_MUTEX is plain data until `ExInitializeFastMutex`
+        Self(UnsafeCell::new(unsafe { mem::zeroed() }))
+    }
+
+    fn try_new() -> Result<Box<Self>, NtError> {
+        unsafe { try_new_zeroed(Self::init) }
+    }
+
+    fn boostable() -> bool {
+        true
+    }
+
+    fn lock_contended(&self) -> bool {
+        if self.try_lock() {
+            return false;
+        }
+
+        self.lock();
+
+        true
+    }
+
     fn init(&mut self) -> Result<(), NtError> {
         ExInitializeFastMutex(self.0.get());
         Ok(())
@@ -137,6 +246,28 @@ impl Mutex for FastMutex {
 impl Mutex for GuardedMutex {
     type Target = Self;
 
+    fn uninit() -> Self {
+        Self(UnsafeCell::new(unsafe { mem::zeroed() }))
+    }
+
+    fn try_new() -> Result<Box<Self>, NtError> {
+        unsafe { try_new_zeroed(Self::init) }
+    }
+
+    fn boostable() -> bool {
+        true
+    }
+
+    fn lock_contended(&self) -> bool {
+        if self.try_lock() {
+            return false;
+        }
+
+        self.lock();
+
+        true
+    }
+
     fn init(&mut self) -> Result<(), NtError> {
         unsafe { KeInitializeGuardedMutex(self.0.get()) };
         Ok(())
@@ -157,9 +288,45 @@ impl Mutex for GuardedMutex {
     }
 }
 
-impl Mutex for ResourceMutex {
+/// an ERESOURCE must be acquired with normal kernel APCs disabled, otherwise the owner thread can be suspended
+/// while holding it, so `ResourceMutex` enters a critical region on every acquisition and leaves it on release
+///
+/// `ResourceMutex<false>` leaves it to the caller, which must be in a critical region or a guarded region, or run at
+/// APC_LEVEL, when it acquires the mutex, it saves the two calls on a hot path which disables APCs already
+///
+/// see https://learn.microsoft.com/en-us/windows-hardware/drivers/ddi/wdm/nf-wdm-exacquireresourceexclusivelite for details
+impl<const REGION: bool> ResourceMutex<REGION> {
+    #[inline]
+    fn enter_region() {
+        if REGION {
+            unsafe { KeEnterCriticalRegion() };
+        } else {
+            debug_assert!(
+                crate::region::apcs_disabled() || unsafe { KeGetCurrentIrql() } >= APC_LEVEL as u8,
+                "ResourceMutex<false> acquired with normal kernel APCs enabled"
+            );
+        }
+    }
+
+    #[inline]
+    fn leave_region() {
+        if REGION {
+            unsafe { KeLeaveCriticalRegion() };
+        }
+    }
+}
+
+impl<const REGION: bool> Mutex for ResourceMutex<REGION> {
     type Target = Self;
 
+    fn uninit() -> Self {
+        Self(UnsafeCell::new(unsafe { mem::zeroed() }))
+    }
+
+    fn try_new() -> Result<Box<Self>, NtError> {
+        unsafe { try_new_zeroed(Self::init) }
+    }
+
     fn init(&mut self) -> Result<(), NtError> {
         cvt(unsafe { ExInitializeResourceLite(self.0.get()) })
     }
@@ -168,38 +335,70 @@ impl Mutex for ResourceMutex {
         true
     }
 
+    fn boostable() -> bool {
+        true
+    }
+
+    fn lock_contended(&self) -> bool {
+        if self.try_lock() {
+            return false;
+        }
+
+        self.lock();
+
+        true
+    }
+
     fn try_lock(&self) -> bool {
-        unsafe { ExAcquireResourceExclusiveLite(self.0.get(), FALSE as _) != 0 }
+        Self::enter_region();
+
+        let acquired = unsafe { ExAcquireResourceExclusiveLite(self.0.get(), FALSE as _) != 0 };
+
+        if !acquired {
+            Self::leave_region();
+        }
+
+        acquired
     }
 
     fn lock(&self) {
-        unsafe {
-            ExAcquireResourceExclusiveLite(self.0.get(), TRUE as _);
-        }
+        Self::enter_region();
+
+        unsafe { ExAcquireResourceExclusiveLite(self.0.get(), TRUE as _) };
     }
 
     fn unlock(&self) {
         unsafe { ExReleaseResourceLite(self.0.get()) };
+
+        Self::leave_region();
     }
 
     fn try_lock_shared(&self) -> bool {
-        unsafe { ExAcquireResourceSharedLite(self.0.get(), FALSE as _) != 0 }
+        Self::enter_region();
+
+        let acquired = unsafe { ExAcquireResourceSharedLite(self.0.get(), FALSE as _) != 0 };
+
+        if !acquired {
+            Self::leave_region();
+        }
+
+        acquired
     }
 
     fn lock_shared(&self) {
-        unsafe {
-            ExAcquireResourceSharedLite(self.0.get(), TRUE as _);
-        }
+        Self::enter_region();
+
+        unsafe { ExAcquireResourceSharedLite(self.0.get(), TRUE as _) };
     }
 
     fn unlock_shared(&self) {
-        unsafe {
-            ExReleaseResourceLite(self.0.get());
-        }
+        unsafe { ExReleaseResourceLite(self.0.get()) };
+
+        Self::leave_region();
     }
 }
 
-impl Drop for ResourceMutex {
+impl<const REGION: bool> Drop for ResourceMutex<REGION> {
     fn drop(&mut self) {
         unsafe {
             let _ = ExDeleteResourceLite(self.0.get());
@@ -215,6 +414,14 @@ struct SpinLockInner {
 impl Mutex for SpinMutex {
     type Target = Self;
 
+    fn uninit() -> Self {
+        Self(UnsafeCell::new(SpinLockInner { irql: 0, lock: 0 }))
+    }
+
+    fn try_new() -> Result<Box<Self>, NtError> {
+        unsafe { try_new_zeroed(Self::init) }
+    }
+
     fn init(&mut self) -> Result<(), NtError> {
         self.0.get_mut().irql = 0;
         unsafe { KeInitializeSpinLock(&mut self.0.get_mut().lock) };
@@ -229,6 +436,15 @@ impl Mutex for SpinMutex {
         }
     }
 
+    /// a held spin lock is not zero, so a lock seen held before spinning is a contention
+    fn lock_contended(&self) -> bool {
+        let held = unsafe { ptr::read_volatile(&(*self.0.get()).lock) } != 0;
+
+        self.lock();
+
+        held
+    }
+
     /// a spin lock can be used in IRQL >= DISPATCH_LEVEL and a more efficient function provided by Microsoft
     ///
     /// see https://learn.microsoft.com/en-us/windows-hardware/drivers/ddi/wdm/nf-wdm-keacquirespinlockatdpclevel for details
@@ -269,6 +485,10 @@ impl Mutex for SpinMutex {
 impl QueuedMutex for QueuedSpinMutex {
     type Target = Self;
 
+    fn try_new() -> Result<Box<Self>, NtError> {
+        unsafe { try_new_zeroed(Self::init) }
+    }
+
     fn init(&mut self) -> Result<(), NtError> {
         unsafe { KeInitializeSpinLock(self.0.get_mut()) }
         Ok(())
@@ -289,6 +509,15 @@ impl QueuedMutex for QueuedSpinMutex {
         }
     }
 
+    /// the lock word points to the last waiter while the lock is held
+    fn lock_contended(&self, handle: PKLOCK_QUEUE_HANDLE) -> bool {
+        let held = unsafe { ptr::read_volatile(self.0.get()) } != 0;
+
+        self.lock(handle);
+
+        held
+    }
+
     fn unlock(&self, handle: PKLOCK_QUEUE_HANDLE) {
         let irql = unsafe { KeGetCurrentIrql() };
 
@@ -307,14 +536,191 @@ impl QueuedMutex for QueuedSpinMutex {
     }
 }
 
+/// the contention statistics of a lock, it is empty without the `lock-stats` feature, a zeroed `Stats` is valid
+struct Stats {
+    #[cfg(feature = "lock-stats")]
+    counters: LockCounters,
+}
+
+impl Stats {
+    /// when the acquisition started, only if the statistics are enabled
+    #[inline]
+    fn start(&self) -> Option<KInstant> {
+        #[cfg(feature = "lock-stats")]
+        return self.counters.is_enabled().then(KInstant::now);
+
+        #[cfg(not(feature = "lock-stats"))]
+        None
+    }
+
+    /// record an acquisition which started at `start`, returns when the lock was acquired
+    #[inline]
+    fn record_acquire(&self, _contended: bool, start: Option<KInstant>) -> Option<KInstant> {
+        #[cfg(feature = "lock-stats")]
+        return start.map(|start| {
+            let now = KInstant::now();
+
+            self.counters
+                .record_acquire(_contended, now.saturating_duration_since(start));
+
+            now
+        });
+
+        #[cfg(not(feature = "lock-stats"))]
+        start
+    }
+
+    #[inline]
+    fn record_release(&self, _acquired: Option<KInstant>) {
+        #[cfg(feature = "lock-stats")]
+        if let Some(acquired) = _acquired {
+            self.counters.record_release(acquired.elapsed());
+        }
+    }
+}
+
 /// the internal layout for `Locked<T,M>`
 ///
-/// this has the same layout as `QueuedInnerData`
+/// `boost` is zeroed, which means disabled, unless the lock is created by `Locked::with_boost`, it only exists with
+/// the `lock-boost` feature
 struct InnerData<T, M: Mutex> {
     mutex: M::Target,
+    #[cfg(feature = "lock-boost")]
+    boost: UnsafeCell<Boost>,
+    stats: Stats,
     data: T,
 }
 
+impl<T, M: Mutex> InnerData<T, M> {
+    /// acquire the lock exclusively and boost its owner while it is contended, returns if it was contended, `None` if
+    /// the lock does not boost
+    #[inline]
+    unsafe fn lock_boosted(this: *mut Self) -> Option<bool> {
+        #[cfg(feature = "lock-boost")]
+        unsafe {
+            let boost = (*this).boost.get();
+
+            if (*boost).enabled {
+                let contended = !(*this).mutex.try_lock();
+
+                if contended {
+                    Boost::boost_owner(boost);
+                    (*this).mutex.lock();
+                }
+
+                Boost::acquired(boost);
+
+                return Some(contended);
+            }
+        }
+
+        let _ = this;
+
+        None
+    }
+
+    /// restore the priority of a boosted owner, it is called before the lock is released exclusively
+    #[inline]
+    unsafe fn unlock_boosted(this: *mut Self) {
+        #[cfg(feature = "lock-boost")]
+        unsafe {
+            let boost = (*this).boost.get();
+
+            if (*boost).enabled {
+                Boost::releasing(boost);
+            }
+        }
+
+        let _ = this;
+    }
+}
+
+/// the exclusive owner of a boosting `Locked`, a zeroed `Boost` is valid
+#[cfg(feature = "lock-boost")]
+struct Boost {
+    enabled: bool,
+    /// protects `owner` and `pending`, the owner can not release the lock while a waiter is boosting it
+    lock: KSPIN_LOCK,
+    owner: PKTHREAD,
+    /// the highest priority of the waiters which found no owner, the lock was acquired but the owner was not recorded
+    /// yet, the owner boosts itself to it once it is recorded
+    pending: KPRIORITY,
+    boosted: bool,
+    /// the priority of the owner before the first boost
+    saved: KPRIORITY,
+}
+
+#[cfg(feature = "lock-boost")]
+impl Boost {
+    /// raise `owner` to `priority` if it is lower, the lock of the boost must be held
+    unsafe fn raise(this: *mut Boost, owner: PKTHREAD, priority: KPRIORITY) {
+        unsafe {
+            if KeQueryPriorityThread(owner) < priority {
+                let old = KeSetPriorityThread(owner, priority);
+
+                // a later waiter may boost the owner again, the priority before the first boost is restored
+                if !(*this).boosted {
+                    (*this).saved = old;
+                    (*this).boosted = true;
+                }
+            }
+        }
+    }
+
+    /// raise the owner to the priority of the current thread if it is lower, it is called when the lock is contended
+    unsafe fn boost_owner(this: *mut Boost) {
+        unsafe {
+            let irql = KeAcquireSpinLockRaiseToDpc(&mut (*this).lock);
+            let owner = (*this).owner;
+            let priority = KeQueryPriorityThread(KeGetCurrentThread());
+
+            if owner.is_null() {
+                // the owner has not recorded itself yet, it picks the boost up in `acquired`
+                (*this).pending = (*this).pending.max(priority);
+            } else {
+                Self::raise(this, owner, priority);
+            }
+
+            KeReleaseSpinLock(&mut (*this).lock, irql);
+        }
+    }
+
+    /// record the current thread as the owner, it is called after the lock is acquired exclusively
+    ///
+    /// the waiters which came before the owner was recorded left their priority in `pending`
+    unsafe fn acquired(this: *mut Boost) {
+        unsafe {
+            let irql = KeAcquireSpinLockRaiseToDpc(&mut (*this).lock);
+            let owner = KeGetCurrentThread();
+
+            (*this).owner = owner;
+
+            let pending = mem::take(&mut (*this).pending);
+
+            if pending != 0 {
+                Self::raise(this, owner, pending);
+            }
+
+            KeReleaseSpinLock(&mut (*this).lock, irql);
+        }
+    }
+
+    /// clear the owner and restore its priority if it was boosted, it is called before the lock is released
+    unsafe fn releasing(this: *mut Boost) {
+        unsafe {
+            let irql = KeAcquireSpinLockRaiseToDpc(&mut (*this).lock);
+
+            (*this).owner = ptr::null_mut();
+
+            if mem::take(&mut (*this).boosted) {
+                KeSetPriorityThread(KeGetCurrentThread(), (*this).saved);
+            }
+
+            KeReleaseSpinLock(&mut (*this).lock, irql);
+        }
+    }
+}
+
 /// a strategy lock wrapper for FastMutex, GuardMutex, Spinlock, Resources
 ///
 /// it is used combined with FastMutex, GuardedMutex, SpinMutex, and ResourceMutex types
@@ -351,19 +757,26 @@ where
 }
 
 impl<T, M: Mutex> Locked<T, M> {
+    /// same as `try_new`
     pub fn new(data: T) -> Result<Self, NtError> {
+        Self::try_new(data)
+    }
+
+    /// allocate the lock from the nonpaged pool, it fails with STATUS_INSUFFICIENT_RESOURCES if the pool is exhausted
+    /// or the error of `Mutex::init`, `data` is dropped on failure
+    pub fn try_new(data: T) -> Result<Self, NtError> {
         let layout = ex_allocate_pool_zero(
             NonPagedPoolNx,
             mem::size_of::<InnerData<T, M>>() as _,
             MUTEX_TAG,
-        ) as *mut InnerData<T, M>;
-
-        if layout.is_null() {
-            return Err(STATUS_INSUFFICIENT_RESOURCES.into());
-        }
+        )? as *mut InnerData<T, M>;
 
         // initialize underlying mutex
-        unsafe { layout.as_mut().unwrap().mutex.init() }?;
+        if let Err(e) = unsafe { (*layout).mutex.init() } {
+            unsafe { ex_free_pool(layout.cast(), MUTEX_TAG) };
+
+            return Err(e);
+        }
 
         unsafe {
             // Rust does not actually "move" the `InnerData` into the memory location where the raw pointer `layout` points to
@@ -376,10 +789,73 @@ impl<T, M: Mutex> Locked<T, M> {
         };
 
         Ok(Self {
-            inner: NonNull::new(layout).expect("can not allocate memory for Locked<T,M>"),
+            inner: unsafe { NonNull::new_unchecked(layout) },
         })
     }
 
+    /// same as `try_new`, but a contended exclusive acquisition raises the priority of the owner thread to the
+    /// priority of the waiter, the owner gets its own priority back when it releases the lock, it requires the
+    /// `lock-boost` feature
+    ///
+    /// it mitigates the priority inversion between e.g. a real-time worker thread and a low-priority maintenance
+    /// thread which holds the lock but is starved by the threads in between, the shared acquisitions of a
+    /// `ResourceLocked` neither boost nor get boosted
+    ///
+    /// it fails with STATUS_NOT_SUPPORTED if `M` can not be boosted, e.g. the owner of a `SpinLocked` already runs at
+    /// DISPATCH_LEVEL
+    ///
+    /// # Example
+    /// ```
+    /// let cache = FastLocked::with_boost(Cache::new())?;
+    ///
+    /// // a low-priority maintenance thread holding the lock is boosted while a real-time worker waits for it
+    /// cache.lock()?.compact();
+    /// ```
+    #[cfg(feature = "lock-boost")]
+    pub fn with_boost(data: T) -> Result<Self, NtError> {
+        if !M::boostable() {
+            return Err(NtError::new(STATUS_NOT_SUPPORTED));
+        }
+
+        let locked = Self::try_new(data)?;
+
+        unsafe { (*(*locked.inner.as_ptr()).boost.get()).enabled = true };
+
+        Ok(locked)
+    }
+
+    /// start recording the contention statistics of this lock, see `lock_stats`, it requires the `lock-stats` feature
+    ///
+    /// it costs two performance counter queries per acquisition, and a `try_lock` for a contended `FastLocked`,
+    /// `GuardLocked` or `ResourceLocked`
+    #[cfg(feature = "lock-stats")]
+    pub fn enable_stats(&self) {
+        unsafe { (*self.inner.as_ptr()).stats.counters.enable() }
+    }
+
+    /// the contention statistics, `None` if `enable_stats` has not been called
+    ///
+    /// # Example
+    /// ```
+    /// let table = FastLocked::new(Table::new())?;
+    /// table.enable_stats();
+    ///
+    /// // later
+    /// if let Some(stats) = table.lock_stats() {
+    ///     println!("{}% contended, max wait {:?}", stats.contention_rate(), stats.max_wait);
+    /// }
+    /// ```
+    #[cfg(feature = "lock-stats")]
+    pub fn lock_stats(&self) -> Option<LockStats> {
+        unsafe { (*self.inner.as_ptr()).stats.counters.snapshot() }
+    }
+
+    /// clear the contention statistics
+    #[cfg(feature = "lock-stats")]
+    pub fn reset_lock_stats(&self) {
+        unsafe { (*self.inner.as_ptr()).stats.counters.reset() }
+    }
+
     /// Returns a mutable reference to the underlying data.
     ///
     /// # Safety
@@ -433,7 +909,7 @@ impl<T, M: Mutex> Locked<T, M> {
 
                 Err(NtError::from(STATUS_UNSUCCESSFUL))
             } else {
-                Ok(MutexGuard { locker: self })
+                Ok(MutexGuard::new(self))
             }
         }
     }
@@ -444,25 +920,9 @@ where
     T: Default,
     M: Mutex,
 {
+    /// it panics if the allocation fails, use `try_new(T::default())` where the failure must be handled
     fn default() -> Self {
-        let layout = ex_allocate_pool_zero(
-            NonPagedPoolNx,
-            mem::size_of::<InnerData<T, M>>() as _,
-            MUTEX_TAG,
-        ) as *mut InnerData<T, M>;
-
-        if layout.is_null() {
-            panic!("No Sufficient Memory")
-        }
-
-        unsafe {
-            let _ = layout.as_mut().unwrap().mutex.init().inspect_err(|_| panic!("Mutex failed to initialize"));
-            layout.as_mut().unwrap().data = Default::default();
-        };
-
-        Self {
-            inner: NonNull::new(layout).unwrap(),
-        }
+        Self::try_new(T::default()).expect("can not allocate memory for Locked<T,M>")
     }
 }
 
@@ -487,7 +947,7 @@ impl<T, M: Mutex> Drop for Locked<T, M> {
 
             drop_in_place(&mut self.inner.as_mut().mutex);
 
-            ExFreePoolWithTag(self.inner.as_ptr().cast(), MUTEX_TAG);
+            ex_free_pool(self.inner.as_ptr().cast(), MUTEX_TAG);
         }
     }
 }
@@ -511,17 +971,45 @@ impl<T: Display, M: Mutex> Debug for Locked<T, M> {
 /// otherwise it is an error and the `DerefMut()` will panic
 pub struct MutexGuard<'a, const EXCLUSIVE: bool, T, M: Mutex> {
     locker: &'a Locked<T, M>,
+    /// when the lock was acquired, only if the statistics are enabled
+    acquired: Option<KInstant>,
 }
 
 impl<'a, const EXCLUSIVE: bool, T, M: Mutex> MutexGuard<'a, EXCLUSIVE, T, M> {
     fn new(locker: &'a Locked<T, M>) -> Self {
-        if EXCLUSIVE {
-            unsafe { (*locker.inner.as_ptr()).mutex.lock() };
-        } else {
-            unsafe { (*locker.inner.as_ptr()).mutex.lock_shared() }
-        }
+        let inner = locker.inner.as_ptr();
+        let stats = unsafe { &(*inner).stats };
+        let start = stats.start();
+
+        let contended = unsafe {
+            if EXCLUSIVE {
+                match InnerData::lock_boosted(inner) {
+                    Some(contended) => contended,
+                    None if start.is_some() => (*inner).mutex.lock_contended(),
+                    None => {
+                        (*inner).mutex.lock();
+
+                        false
+                    }
+                }
+            } else if start.is_some() {
+                let contended = !(*inner).mutex.try_lock_shared();
+
+                if contended {
+                    (*inner).mutex.lock_shared();
+                }
+
+                contended
+            } else {
+                (*inner).mutex.lock_shared();
 
-        Self { locker }
+                false
+            }
+        };
+
+        let acquired = stats.record_acquire(contended, start);
+
+        Self { locker, acquired }
     }
 }
 
@@ -547,7 +1035,13 @@ impl<'a, const EXCLUSIVE: bool, T, M: Mutex> DerefMut for MutexGuard<'a, EXCLUSI
 impl<'a, const EXCLUSIVE: bool, T, M: Mutex> Drop for MutexGuard<'a, EXCLUSIVE, T, M> {
     fn drop(&mut self) {
         unsafe {
+            (*self.locker.inner.as_ptr())
+                .stats
+                .record_release(self.acquired);
+
             if EXCLUSIVE {
+                InnerData::unlock_boosted(self.locker.inner.as_ptr());
+
                 (*self.locker.inner.as_ptr()).mutex.unlock();
             } else {
                 (*self.locker.inner.as_ptr()).mutex.unlock_shared();
@@ -561,6 +1055,10 @@ pub struct QueuedEmptyMutex;
 impl QueuedMutex for QueuedEmptyMutex {
     type Target = Self;
 
+    fn try_new() -> Result<Box<Self>, NtError> {
+        try_box(QueuedEmptyMutex)
+    }
+
     fn init(&mut self) -> Result<(), NtError> {
         Ok(())
     }
@@ -580,6 +1078,7 @@ pub struct QueuedSpinMutex(UnsafeCell<KSPIN_LOCK>);
 
 struct QueuedInnerData<T, M: QueuedMutex> {
     mutex: M::Target,
+    stats: Stats,
     data: T,
 }
 
@@ -601,25 +1100,31 @@ pub struct StackQueueLocked<T, M: QueuedMutex> {
 }
 
 impl<T, M: QueuedMutex> StackQueueLocked<T, M> {
+    /// same as `try_new`
     pub fn new(data: T) -> Result<Self, NtError> {
+        Self::try_new(data)
+    }
+
+    /// allocate the lock from the nonpaged pool, it fails with STATUS_INSUFFICIENT_RESOURCES if the pool is exhausted
+    pub fn try_new(data: T) -> Result<Self, NtError> {
         let layout = ex_allocate_pool_zero(
             NonPagedPoolNx,
             mem::size_of::<QueuedInnerData<T, M>>() as _,
             MUTEX_TAG,
-        ) as *mut QueuedInnerData<T, M>;
+        )? as *mut QueuedInnerData<T, M>;
 
-        if layout.is_null() {
-            return Err(STATUS_INSUFFICIENT_RESOURCES.into());
-        }
+        if let Err(e) = unsafe { (*layout).mutex.init() } {
+            unsafe { ex_free_pool(layout.cast(), MUTEX_TAG) };
 
-        unsafe { layout.as_mut().unwrap().mutex.init() }?;
+            return Err(e);
+        }
 
         unsafe {
             ptr::write(&mut (*layout).data, data);
         }
 
         Ok(Self {
-            inner: NonNull::new(layout).unwrap(),
+            inner: unsafe { NonNull::new_unchecked(layout) },
         })
     }
 
@@ -640,6 +1145,23 @@ impl<T, M: QueuedMutex> StackQueueLocked<T, M> {
         self.lock(&mut handle).map(|v| v.clone())
     }
 
+    /// start recording the contention statistics of this lock, see `Locked::enable_stats`
+    #[cfg(feature = "lock-stats")]
+    pub fn enable_stats(&self) {
+        unsafe { (*self.inner.as_ptr()).stats.counters.enable() }
+    }
+
+    /// the contention statistics, `None` if `enable_stats` has not been called
+    #[cfg(feature = "lock-stats")]
+    pub fn lock_stats(&self) -> Option<LockStats> {
+        unsafe { (*self.inner.as_ptr()).stats.counters.snapshot() }
+    }
+
+    #[cfg(feature = "lock-stats")]
+    pub fn reset_lock_stats(&self) {
+        unsafe { (*self.inner.as_ptr()).stats.counters.reset() }
+    }
+
     pub fn lock<'a>(
         &'a self,
         handle: &'a mut LockedQuueHandle,
@@ -647,11 +1169,24 @@ impl<T, M: QueuedMutex> StackQueueLocked<T, M> {
         if !M::irql_ok() {
             Err(NtError::from(STATUS_UNSUCCESSFUL))
         } else {
-            unsafe { (*self.inner.as_ptr()).mutex.lock(&mut handle.0) };
+            let stats = unsafe { &(*self.inner.as_ptr()).stats };
+            let start = stats.start();
+
+            let contended = match start {
+                Some(_) => unsafe { (*self.inner.as_ptr()).mutex.lock_contended(&mut handle.0) },
+                None => {
+                    unsafe { (*self.inner.as_ptr()).mutex.lock(&mut handle.0) };
+
+                    false
+                }
+            };
+
+            let acquired = stats.record_acquire(contended, start);
 
             Ok(InStackMutexGuard {
                 handle,
                 locker: self,
+                acquired,
             })
         }
     }
@@ -662,25 +1197,9 @@ where
     T: Default,
     M: QueuedMutex,
 {
+    /// it panics if the allocation fails, use `try_new(T::default())` where the failure must be handled
     fn default() -> Self {
-        let layout = ex_allocate_pool_zero(
-            NonPagedPoolNx,
-            mem::size_of::<QueuedInnerData<T, M>>() as _,
-            MUTEX_TAG,
-        ) as *mut QueuedInnerData<T, M>;
-
-        if layout.is_null() {
-            panic!("No Sufficient Memory")
-        }
-
-        unsafe {
-            let _ = layout.as_mut().unwrap().mutex.init().inspect_err(|_| panic!("Mutex failed to initialize"));
-            layout.as_mut().unwrap().data = Default::default();
-        };
-
-        Self {
-            inner: NonNull::new(layout).unwrap(),
-        }
+        Self::try_new(T::default()).expect("can not allocate memory for StackQueueLocked<T,M>")
     }
 }
 
@@ -713,7 +1232,7 @@ impl<T, M: QueuedMutex> Drop for StackQueueLocked<T, M> {
 
             drop_in_place(&mut self.inner.as_mut().mutex);
 
-            ExFreePoolWithTag(self.inner.as_ptr().cast(), MUTEX_TAG);
+            ex_free_pool(self.inner.as_ptr().cast(), MUTEX_TAG);
         }
     }
 }
@@ -730,6 +1249,7 @@ impl LockedQuueHandle {
 pub struct InStackMutexGuard<'a, T, M: QueuedMutex> {
     handle: &'a mut LockedQuueHandle,
     locker: &'a StackQueueLocked<T, M>,
+    acquired: Option<KInstant>,
 }
 
 impl<'a, T, M: QueuedMutex> Deref for InStackMutexGuard<'a, T, M> {
@@ -748,6 +1268,8 @@ impl<'a, T, M: QueuedMutex> DerefMut for InStackMutexGuard<'a, T, M> {
 
 impl<'a, T, M: QueuedMutex> Drop for InStackMutexGuard<'a, T, M> {
     fn drop(&mut self) {
+        unsafe { &(*self.locker.inner.as_ptr()).stats }.record_release(self.acquired);
+
         unsafe {
             (*self.locker.inner.as_ptr())
                 .mutex
@@ -756,6 +1278,310 @@ impl<'a, T, M: QueuedMutex> Drop for InStackMutexGuard<'a, T, M> {
     }
 }
 
+/// a lock stored inline, e.g. in a user struct or a device extension, it does not allocate
+///
+/// `Locked<T,M>` keeps the mutex and `T` in one pool allocation, `InlineLocked<T,M>` keeps them in the memory
+/// of its owner instead, which is cheaper when many locks are embedded
+///
+/// an initialized kernel lock object must not be moved, so it is created uninitialized and initialized after it is
+/// pinned, by `init` or `new_in_place`; locking an uninitialized lock fails with STATUS_UNSUCCESSFUL
+///
+/// # Example
+/// ```
+/// struct Connection {
+///     state: InlineSpinLocked<State>,
+///     pending: InlineFastLocked<VecDeque<Request>>,
+/// }
+///
+/// let mut conn = Box::pin(Connection { state: InlineLocked::new(State::Idle), ... });
+///
+/// unsafe { Pin::new_unchecked(&mut conn.as_mut().get_unchecked_mut().state) }.init()?;
+///
+/// // or initialize it in place, e.g. in a device extension
+/// unsafe { InlineLocked::new_in_place(&mut (*extension).state, State::Idle) }?;
+///
+/// *conn.state.lock()? = State::Busy;
+/// ```
+pub struct InlineLocked<T, M: Mutex> {
+    mutex: ManuallyDrop<M>,
+    initialized: AtomicBool,
+    data: UnsafeCell<T>,
+    _pin: PhantomPinned,
+}
+
+impl<T, M: Mutex> InlineLocked<T, M> {
+    /// an uninitialized lock, it must be pinned and initialized by `init` before use
+    pub fn new(data: T) -> Self {
+        Self {
+            mutex: ManuallyDrop::new(M::uninit()),
+            initialized: AtomicBool::new(false),
+            data: UnsafeCell::new(data),
+            _pin: PhantomPinned,
+        }
+    }
+
+    /// initialize the mutex at its final address, it does nothing if it is already initialized
+    pub fn init(self: Pin<&mut Self>) -> Result<(), NtError> {
+        // Safety
+        // the mutex is initialized in place and never moved out
+        let this = unsafe { self.get_unchecked_mut() };
+
+        if *this.initialized.get_mut() {
+            return Ok(());
+        }
+
+        this.mutex.init()?;
+
+        this.initialized.store(true, Ordering::Release);
+
+        Ok(())
+    }
+
+    /// construct and initialize a lock at `slot`
+    ///
+    /// # Safety
+    /// - `slot` must be valid for writes and properly aligned, the previous content is not dropped
+    /// - the lock must not be moved until it is dropped
+    pub unsafe fn new_in_place(slot: *mut Self, data: T) -> Result<(), NtError> {
+        unsafe {
+            ptr::write(slot, Self::new(data));
+
+            Pin::new_unchecked(&mut *slot).init()
+        }
+    }
+
+    pub fn is_initialized(&self) -> bool {
+        self.initialized.load(Ordering::Acquire)
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        self.data.get_mut()
+    }
+
+    pub fn into_inner(self) -> T {
+        let mut this = ManuallyDrop::new(self);
+
+        unsafe {
+            if this.is_initialized() {
+                ManuallyDrop::drop(&mut this.mutex);
+            }
+
+            ptr::read(this.data.get())
+        }
+    }
+
+    fn check(&self) -> Result<(), NtError> {
+        if !self.is_initialized() || !M::irql_ok() {
+            Err(NtError::from(STATUS_UNSUCCESSFUL))
+        } else {
+            Ok(())
+        }
+    }
+
+    /// returns a guard for exclusive access, the same as `Locked::lock`
+    pub fn lock(&self) -> Result<InlineMutexGuard<'_, true, T, M>, NtError> {
+        self.check()?;
+
+        self.mutex.lock();
+
+        Ok(InlineMutexGuard { locker: self })
+    }
+
+    /// returns a guard for shared access, the same as `Locked::lock_shared`
+    pub fn lock_shared(&self) -> Result<InlineMutexGuard<'_, false, T, M>, NtError> {
+        self.check()?;
+
+        if !M::shareable() {
+            #[cfg(debug_assertions)]
+            panic!("Can not call lock_shared on a unshareable Mutex");
+
+            return Err(NtError::from(STATUS_UNSUCCESSFUL));
+        }
+
+        self.mutex.lock_shared();
+
+        Ok(InlineMutexGuard { locker: self })
+    }
+
+    /// try to acquire the lock exclusively without waiting
+    pub fn try_lock(&self) -> Option<InlineMutexGuard<'_, true, T, M>> {
+        self.check().ok()?;
+
+        #[cfg(feature = "fault-injection")]
+        if fault::inject(FaultSite::TryLock) {
+            return None;
+        }
+
+        if self.mutex.try_lock() {
+            Some(InlineMutexGuard { locker: self })
+        } else {
+            None
+        }
+    }
+}
+
+impl<T, M: Mutex> Drop for InlineLocked<T, M> {
+    fn drop(&mut self) {
+        if *self.initialized.get_mut() {
+            unsafe { ManuallyDrop::drop(&mut self.mutex) };
+        }
+    }
+}
+
+impl<T: Display, M: Mutex> Debug for InlineLocked<T, M> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "InlineLocked{{{}}}", unsafe { &*self.data.get() })
+    }
+}
+
+/// the guard of `InlineLocked`, see `MutexGuard`
+pub struct InlineMutexGuard<'a, const EXCLUSIVE: bool, T, M: Mutex> {
+    locker: &'a InlineLocked<T, M>,
+}
+
+impl<'a, const EXCLUSIVE: bool, T, M: Mutex> Deref for InlineMutexGuard<'a, EXCLUSIVE, T, M> {
+    type Target = T;
+    fn deref(&self) -> &Self::Target {
+        unsafe { &*self.locker.data.get() }
+    }
+}
+
+impl<'a, const EXCLUSIVE: bool, T, M: Mutex> DerefMut for InlineMutexGuard<'a, EXCLUSIVE, T, M> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        if EXCLUSIVE {
+            unsafe { &mut *self.locker.data.get() }
+        } else {
+            panic!("can not get a mutable ref of `T` when the lock is not held exclusively");
+        }
+    }
+}
+
+impl<'a, const EXCLUSIVE: bool, T, M: Mutex> Drop for InlineMutexGuard<'a, EXCLUSIVE, T, M> {
+    fn drop(&mut self) {
+        if EXCLUSIVE {
+            self.locker.mutex.unlock();
+        } else {
+            self.locker.mutex.unlock_shared();
+        }
+    }
+}
+
+unsafe impl<T: Send, M: Mutex> Send for InlineLocked<T, M> {}
+unsafe impl<T: Send + Sync, M: Mutex> Sync for InlineLocked<T, M> {}
+
+/// A spin lock that can be initialized in a `static`, it neither allocates nor needs a lazy wrapper
+///
+/// a KSPIN_LOCK is initialized to zero, so `new` is a `const fn`; the IRQL is saved in the guard rather than in the lock
+///
+/// # Example
+/// ```
+/// static STATE: StaticSpinLocked<Config> = StaticSpinLocked::new(Config::DEFAULT);
+///
+/// STATE.lock().verbose = true;
+/// ```
+pub struct StaticSpinLocked<T> {
+    lock: UnsafeCell<KSPIN_LOCK>,
+    data: UnsafeCell<T>,
+}
+
+impl<T> StaticSpinLocked<T> {
+    pub const fn new(data: T) -> Self {
+        Self {
+            lock: UnsafeCell::new(0),
+            data: UnsafeCell::new(data),
+        }
+    }
+
+    /// acquire the lock at IRQL <= DISPATCH_LEVEL, the IRQL is raised to DISPATCH_LEVEL while the guard lives
+    pub fn lock(&self) -> StaticSpinGuard<'_, T> {
+        unsafe {
+            let irql = KeGetCurrentIrql();
+
+            if irql >= DISPATCH_LEVEL as _ {
+                KeAcquireSpinLockAtDpcLevel(self.lock.get());
+
+                StaticSpinGuard {
+                    locker: self,
+                    irql: None,
+                }
+            } else {
+                StaticSpinGuard {
+                    locker: self,
+                    irql: Some(KeAcquireSpinLockRaiseToDpc(self.lock.get())),
+                }
+            }
+        }
+    }
+
+    /// try to acquire the lock without spinning, it only succeeds at DISPATCH_LEVEL like `SpinMutex::try_lock`
+    pub fn try_lock(&self) -> Option<StaticSpinGuard<'_, T>> {
+        if unsafe { KeGetCurrentIrql() } != DISPATCH_LEVEL as _ {
+            return None;
+        }
+
+        #[cfg(feature = "fault-injection")]
+        if fault::inject(FaultSite::TryLock) {
+            return None;
+        }
+
+        if unsafe { KeTryToAcquireSpinLockAtDpcLevel(self.lock.get()) } != 0 {
+            Some(StaticSpinGuard {
+                locker: self,
+                irql: None,
+            })
+        } else {
+            None
+        }
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        self.data.get_mut()
+    }
+
+    pub fn into_inner(self) -> T {
+        self.data.into_inner()
+    }
+}
+
+impl<T: Default> Default for StaticSpinLocked<T> {
+    fn default() -> Self {
+        Self::new(T::default())
+    }
+}
+
+/// the guard of `StaticSpinLocked`, the lock is released and the IRQL restored on drop
+pub struct StaticSpinGuard<'a, T> {
+    locker: &'a StaticSpinLocked<T>,
+    irql: Option<KIRQL>,
+}
+
+impl<'a, T> Deref for StaticSpinGuard<'a, T> {
+    type Target = T;
+    fn deref(&self) -> &Self::Target {
+        unsafe { &*self.locker.data.get() }
+    }
+}
+
+impl<'a, T> DerefMut for StaticSpinGuard<'a, T> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        unsafe { &mut *self.locker.data.get() }
+    }
+}
+
+impl<'a, T> Drop for StaticSpinGuard<'a, T> {
+    fn drop(&mut self) {
+        unsafe {
+            match self.irql {
+                Some(irql) => KeReleaseSpinLock(self.locker.lock.get(), irql),
+                None => KeReleaseSpinLockFromDpcLevel(self.locker.lock.get()),
+            }
+        }
+    }
+}
+
+unsafe impl<T: Send> Send for StaticSpinLocked<T> {}
+unsafe impl<T: Send> Sync for StaticSpinLocked<T> {}
+
 unsafe impl<T: Send, M: Mutex> Send for Locked<T, M> {}
 unsafe impl<T: Sync, M: Mutex> Sync for Locked<T, M> {}
 
@@ -765,5 +1591,19 @@ unsafe impl<T: Sync, M: QueuedMutex> Sync for StackQueueLocked<T, M> {}
 pub type GuardLocked<T> = Locked<T, GuardedMutex>;
 pub type FastLocked<T> = Locked<T, FastMutex>;
 pub type ResourceLocked<T> = Locked<T, ResourceMutex>;
+/// a `ResourceLocked` which does not enter a critical region, it must be locked inside one already
+pub type ResourceLockedInRegion<T> = Locked<T, ResourceMutex<false>>;
 pub type SpinLocked<T> = Locked<T, SpinMutex>;
 pub type InStackQueueLocked<T> = StackQueueLocked<T, QueuedSpinMutex>;
+
+pub type InlineGuardLocked<T> = InlineLocked<T, GuardedMutex>;
+pub type InlineFastLocked<T> = InlineLocked<T, FastMutex>;
+pub type InlineResourceLocked<T> = InlineLocked<T, ResourceMutex>;
+pub type InlineResourceLockedInRegion<T> = InlineLocked<T, ResourceMutex<false>>;
+pub type InlineSpinLocked<T> = InlineLocked<T, SpinMutex>;
+
+/// bare inline mutexes, which protect no data
+pub type InlineGuardedMutex = InlineLocked<(), GuardedMutex>;
+pub type InlineFastMutex = InlineLocked<(), FastMutex>;
+pub type InlineResourceMutex = InlineLocked<(), ResourceMutex>;
+pub type InlineSpinMutex = InlineLocked<(), SpinMutex>;
@@ -0,0 +1,224 @@
+//! this module provides `NotificationQueue<T>`, the inverted call pattern for user mode polling
+//!
+//! the client keeps read requests(usually an IOCTL) pending, and a kernel event completes one of them with the
+//! event serialized as `T`, the events arriving while no request is pending are buffered up to a capacity
+//!
+//! # Example
+//! ```
+//! static EVENTS: OnceLock<NotificationQueue<ProcessEvent>> = OnceLock::new();
+//!
+//! // in DriverEntry
+//! EVENTS.get_or_init(|| NotificationQueue::new(256, Overflow::DropOldest).unwrap());
+//!
+//! // in the dispatch routine
+//! match irp.ioctl_code() {
+//!     IOCTL_GET_EVENTS => EVENTS.get().unwrap().dispatch(irp.as_raw()),
+//!     ...
+//! }
+//!
+//! // in IRP_MJ_CLEANUP
+//! EVENTS.get().unwrap().cancel_all(file_object.cast());
+//!
+//! // in a process notify routine
+//! EVENTS.get().unwrap().push(ProcessEvent { pid, parent });
+//! ```
+use core::{
+    mem, ptr,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use alloc::collections::VecDeque;
+use wdk_sys::{
+    PIRP, PVOID, STATUS_BUFFER_TOO_SMALL, STATUS_INSUFFICIENT_RESOURCES,
+    STATUS_INVALID_DEVICE_REQUEST, STATUS_INVALID_PARAMETER, STATUS_SUCCESS,
+};
+
+use crate::{
+    csq::CancelSafeQueue,
+    irp::Irp,
+    mutex::StaticSpinLocked,
+    ntstatus::NtError,
+    pod::{self, Pod},
+    wdm::STATUS_IRP_QUEUED,
+};
+
+/// What to do with a new event when the buffer is full
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Overflow {
+    /// discard the oldest buffered event to make room
+    DropOldest,
+    /// discard the new event
+    DropNewest,
+}
+
+/// The statistics of a `NotificationQueue`
+#[derive(Debug, Clone, Copy, Default)]
+pub struct NotificationStats {
+    /// the events pushed
+    pub pushed: u64,
+    /// the events copied to the client
+    pub delivered: u64,
+    /// the events discarded by the overflow policy
+    pub dropped: u64,
+    /// the events buffered now
+    pub buffered: usize,
+}
+
+/// A queue of pending requests and buffered events of type `T`
+///
+/// the requests must be METHOD_BUFFERED, a request is completed with as many events as its output buffer can hold
+pub struct NotificationQueue<T: Pod> {
+    requests: CancelSafeQueue,
+    events: StaticSpinLocked<VecDeque<T>>,
+    capacity: usize,
+    overflow: Overflow,
+    pushed: AtomicU64,
+    delivered: AtomicU64,
+    dropped: AtomicU64,
+}
+
+impl<T: Pod> NotificationQueue<T> {
+    /// the buffer of `capacity` events is allocated here, `push` never allocates
+    pub fn new(capacity: usize, overflow: Overflow) -> Result<Self, NtError> {
+        if capacity == 0 || mem::size_of::<T>() == 0 {
+            return Err(NtError::new(STATUS_INVALID_PARAMETER));
+        }
+
+        let mut events = VecDeque::new();
+
+        events
+            .try_reserve_exact(capacity)
+            .map_err(|_| NtError::new(STATUS_INSUFFICIENT_RESOURCES))?;
+
+        Ok(Self {
+            requests: CancelSafeQueue::new()?,
+            events: StaticSpinLocked::new(events),
+            capacity,
+            overflow,
+            pushed: AtomicU64::new(0),
+            delivered: AtomicU64::new(0),
+            dropped: AtomicU64::new(0),
+        })
+    }
+
+    /// complete a pending request with `event`, or buffer it, it can be called at IRQL <= DISPATCH_LEVEL
+    pub fn push(&self, event: T) {
+        self.pushed.fetch_add(1, Ordering::Relaxed);
+
+        let mut events = self.events.lock();
+
+        // the buffered events go first, a request never stays pending while there are buffered events
+        if events.is_empty() {
+            if let Some(irp) = self.requests.remove_next(ptr::null_mut()) {
+                drop(events);
+
+                self.complete(irp, &[event]);
+                return;
+            }
+        }
+
+        if events.len() == self.capacity {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+
+            match self.overflow {
+                Overflow::DropOldest => {
+                    events.pop_front();
+                }
+                Overflow::DropNewest => return,
+            }
+        }
+
+        events.push_back(event);
+    }
+
+    /// handle a read request, it is completed at once if there are buffered events, otherwise it is pended
+    ///
+    /// the result is meant to be returned from `IrpDispatch::dispatch` as is
+    pub fn dispatch(&self, irp: PIRP) -> Result<u64, NtError> {
+        let mut request = unsafe { Irp::from_raw(irp) };
+
+        // a pended request is completed into its system buffer later
+        request.system_buffer_bytes()?;
+
+        let count = request.output_buffer_len() / mem::size_of::<T>();
+
+        if count == 0 {
+            return Err(NtError::new(STATUS_BUFFER_TOO_SMALL));
+        }
+
+        let mut events = self.events.lock();
+
+        if events.is_empty() {
+            // pended under the lock, so a `push` can not buffer an event in between
+            // the IRP is completed with the error by the dispatcher if it can not be queued, once queued a `push` may
+            // complete it as soon as the lock is dropped, so the dispatcher must not touch it
+            self.requests.insert(irp).map_err(|(_, e)| e)?;
+
+            return Err(NtError::new(STATUS_IRP_QUEUED));
+        }
+
+        let count = count.min(events.len());
+        let buffer = request.system_buffer_bytes()?;
+
+        for (chunk, event) in buffer
+            .chunks_exact_mut(mem::size_of::<T>())
+            .zip(events.drain(..count))
+        {
+            chunk.copy_from_slice(pod::as_bytes(&event));
+        }
+
+        drop(events);
+
+        self.delivered.fetch_add(count as _, Ordering::Relaxed);
+
+        Ok((count * mem::size_of::<T>()) as _)
+    }
+
+    fn complete(&self, irp: PIRP, events: &[T]) {
+        let mut request = unsafe { Irp::from_raw(irp) };
+
+        // the request was checked by `dispatch`
+        let Ok(buffer) = request.system_buffer_bytes() else {
+            request.complete(STATUS_INVALID_DEVICE_REQUEST, 0);
+            return;
+        };
+
+        buffer[..mem::size_of_val(events)].copy_from_slice(pod::slice_as_bytes(events));
+
+        self.delivered
+            .fetch_add(events.len() as _, Ordering::Relaxed);
+
+        request.complete(STATUS_SUCCESS, mem::size_of_val(events) as _);
+    }
+
+    /// cancel the pending requests of a file object(null for all), typically in IRP_MJ_CLEANUP
+    pub fn cancel_all(&self, file_object: PVOID) {
+        self.requests.cancel_all(file_object);
+    }
+
+    /// discard the buffered events
+    pub fn clear(&self) {
+        self.events.lock().clear();
+    }
+
+    pub fn len(&self) -> usize {
+        self.events.lock().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    pub fn stats(&self) -> NotificationStats {
+        NotificationStats {
+            pushed: self.pushed.load(Ordering::Relaxed),
+            delivered: self.delivered.load(Ordering::Relaxed),
+            dropped: self.dropped.load(Ordering::Relaxed),
+            buffered: self.len(),
+        }
+    }
+}
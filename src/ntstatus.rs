@@ -1,19 +1,97 @@
 use core::num::NonZeroI32;
 
-use wdk_sys::{NTSTATUS, STATUS_SUCCESS};
+use wdk_sys::{
+    NTSTATUS, STATUS_ACCESS_DENIED, STATUS_ACCESS_VIOLATION, STATUS_ALERTED,
+    STATUS_ALREADY_REGISTERED, STATUS_BUFFER_OVERFLOW, STATUS_BUFFER_TOO_SMALL, STATUS_CANCELLED,
+    STATUS_DATATYPE_MISALIGNMENT, STATUS_DELETE_PENDING, STATUS_DEVICE_BUSY,
+    STATUS_DEVICE_NOT_READY, STATUS_END_OF_FILE, STATUS_ILLEGAL_INSTRUCTION,
+    STATUS_INFO_LENGTH_MISMATCH, STATUS_INSUFFICIENT_RESOURCES, STATUS_INTERNAL_ERROR,
+    STATUS_INVALID_ADDRESS, STATUS_INVALID_BUFFER_SIZE, STATUS_INVALID_CID,
+    STATUS_INVALID_DEVICE_REQUEST, STATUS_INVALID_DEVICE_STATE, STATUS_INVALID_HANDLE,
+    STATUS_INVALID_INFO_CLASS, STATUS_INVALID_PARAMETER, STATUS_INVALID_USER_BUFFER,
+    STATUS_IO_TIMEOUT, STATUS_MORE_ENTRIES, STATUS_NAME_TOO_LONG, STATUS_NO_MEMORY,
+    STATUS_NO_MORE_ENTRIES, STATUS_NO_MORE_FILES, STATUS_NO_SUCH_DEVICE, STATUS_NO_SUCH_FILE,
+    STATUS_NOT_FOUND, STATUS_NOT_IMPLEMENTED, STATUS_NOT_SUPPORTED, STATUS_OBJECT_NAME_COLLISION,
+    STATUS_OBJECT_NAME_INVALID, STATUS_OBJECT_NAME_NOT_FOUND, STATUS_OBJECT_PATH_NOT_FOUND,
+    STATUS_OBJECT_PATH_SYNTAX_BAD, STATUS_OBJECT_TYPE_MISMATCH, STATUS_PENDING,
+    STATUS_PRIVILEGE_NOT_HELD, STATUS_PROCESS_IS_TERMINATING, STATUS_REPARSE,
+    STATUS_SHARING_VIOLATION, STATUS_STACK_OVERFLOW, STATUS_SUCCESS, STATUS_THREAD_IS_TERMINATING,
+    STATUS_TIMEOUT, STATUS_UNSUCCESSFUL, STATUS_USER_APC,
+};
 
 #[repr(transparent)]
 #[derive(Clone, Copy, PartialEq, Eq)]
 pub struct NtError(NonZeroI32);
 
+/// the severity of a NTSTATUS, the top 2 bits
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Severity {
+    Success = 0,
+    Informational = 1,
+    Warning = 2,
+    Error = 3,
+}
+
 impl NtError {
     pub fn new(status: NTSTATUS) -> Self {
         Self(NonZeroI32::new(status).unwrap())
     }
 
+    /// the full NTSTATUS value
     pub fn code(&self) -> NTSTATUS {
         self.0.get()
     }
+
+    pub fn severity(&self) -> Severity {
+        match (self.code() as u32) >> 30 {
+            0 => Severity::Success,
+            1 => Severity::Informational,
+            2 => Severity::Warning,
+            _ => Severity::Error,
+        }
+    }
+
+    #[inline]
+    pub fn is_error(&self) -> bool {
+        self.severity() == Severity::Error
+    }
+
+    #[inline]
+    pub fn is_warning(&self) -> bool {
+        self.severity() == Severity::Warning
+    }
+
+    /// a success or informational status, e.g. STATUS_PENDING, which `NT_SUCCESS` accepts
+    #[inline]
+    pub fn is_success(&self) -> bool {
+        self.code() >= 0
+    }
+
+    /// true if the customer bit is set, which means it is not defined by Microsoft
+    #[inline]
+    pub fn is_customer(&self) -> bool {
+        (self.code() as u32) & 0x2000_0000 != 0
+    }
+
+    /// the facility, bits 16 to 27, e.g. 0x7 for the statuses converted from win32 errors
+    #[inline]
+    pub fn facility(&self) -> u16 {
+        ((self.code() as u32 >> 16) & 0xFFF) as u16
+    }
+
+    /// the facility specific code, the low 16 bits
+    #[inline]
+    pub fn status_code(&self) -> u16 {
+        self.code() as u32 as u16
+    }
+
+    /// the symbolic name of a common status, e.g. "STATUS_ACCESS_DENIED"
+    pub fn name(&self) -> Option<&'static str> {
+        STATUS_NAMES
+            .iter()
+            .find(|(status, _)| *status == self.code())
+            .map(|(_, name)| *name)
+    }
 }
 
 impl core::convert::From<NTSTATUS> for NtError {
@@ -22,6 +100,12 @@ impl core::convert::From<NTSTATUS> for NtError {
     }
 }
 
+impl core::convert::From<NtError> for NTSTATUS {
+    fn from(value: NtError) -> Self {
+        value.code()
+    }
+}
+
 impl core::error::Error for NtError {}
 
 impl core::fmt::Debug for NtError {
@@ -31,7 +115,10 @@ impl core::fmt::Debug for NtError {
 }
 impl core::fmt::Display for NtError {
     fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
-        write!(f, "{:X}", self.0)
+        match self.name() {
+            Some(name) => write!(f, "{} ({:#X})", name, self.0),
+            None => write!(f, "{:X}", self.0),
+        }
     }
 }
 
@@ -44,3 +131,93 @@ pub fn cvt(status: NTSTATUS) -> Result {
         _ => Err(status.into()),
     }
 }
+
+/// convert a NTSTATUS to a Result like `NT_SUCCESS`, the success and informational statuses are kept in `Ok`
+pub fn check(status: NTSTATUS) -> core::result::Result<NTSTATUS, NtError> {
+    if status >= 0 {
+        Ok(status)
+    } else {
+        Err(status.into())
+    }
+}
+
+/// call a NTSTATUS returning expression and convert the status with `ntstatus::check`
+///
+/// the expression is not wrapped in `unsafe`, an unsafe call must be written in an `unsafe` block by the caller
+///
+/// # Example
+/// ```
+/// nt_try!(unsafe { ZwClose(handle) })?;
+///
+/// // STATUS_PENDING is a success
+/// let status = nt_try!(unsafe { IoCallDriver(device, irp) })?;
+/// ```
+#[macro_export]
+macro_rules! nt_try {
+    ($e:expr) => {
+        $crate::ntstatus::check($e)
+    };
+}
+
+macro_rules! status_names {
+    ($($status:ident),+ $(,)?) => {
+        /// the symbolic names of the common statuses
+        const STATUS_NAMES: &[(NTSTATUS, &str)] = &[$(($status, stringify!($status))),+];
+    };
+}
+
+status_names!(
+    STATUS_SUCCESS,
+    STATUS_PENDING,
+    STATUS_TIMEOUT,
+    STATUS_ALERTED,
+    STATUS_USER_APC,
+    STATUS_REPARSE,
+    STATUS_MORE_ENTRIES,
+    STATUS_BUFFER_OVERFLOW,
+    STATUS_NO_MORE_FILES,
+    STATUS_NO_MORE_ENTRIES,
+    STATUS_UNSUCCESSFUL,
+    STATUS_NOT_IMPLEMENTED,
+    STATUS_INVALID_INFO_CLASS,
+    STATUS_INFO_LENGTH_MISMATCH,
+    STATUS_ACCESS_VIOLATION,
+    STATUS_INVALID_HANDLE,
+    STATUS_INVALID_PARAMETER,
+    STATUS_NO_SUCH_DEVICE,
+    STATUS_NO_SUCH_FILE,
+    STATUS_INVALID_DEVICE_REQUEST,
+    STATUS_END_OF_FILE,
+    STATUS_NO_MEMORY,
+    STATUS_ACCESS_DENIED,
+    STATUS_BUFFER_TOO_SMALL,
+    STATUS_OBJECT_TYPE_MISMATCH,
+    STATUS_OBJECT_NAME_INVALID,
+    STATUS_OBJECT_NAME_NOT_FOUND,
+    STATUS_OBJECT_NAME_COLLISION,
+    STATUS_OBJECT_PATH_NOT_FOUND,
+    STATUS_OBJECT_PATH_SYNTAX_BAD,
+    STATUS_SHARING_VIOLATION,
+    STATUS_DELETE_PENDING,
+    STATUS_PRIVILEGE_NOT_HELD,
+    STATUS_INSUFFICIENT_RESOURCES,
+    STATUS_DEVICE_NOT_READY,
+    STATUS_IO_TIMEOUT,
+    STATUS_NOT_SUPPORTED,
+    STATUS_INTERNAL_ERROR,
+    STATUS_CANCELLED,
+    STATUS_INVALID_DEVICE_STATE,
+    STATUS_DEVICE_BUSY,
+    STATUS_INVALID_CID,
+    STATUS_NOT_FOUND,
+    STATUS_PROCESS_IS_TERMINATING,
+    STATUS_THREAD_IS_TERMINATING,
+    STATUS_INVALID_ADDRESS,
+    STATUS_INVALID_BUFFER_SIZE,
+    STATUS_INVALID_USER_BUFFER,
+    STATUS_NAME_TOO_LONG,
+    STATUS_ALREADY_REGISTERED,
+    STATUS_DATATYPE_MISALIGNMENT,
+    STATUS_ILLEGAL_INSTRUCTION,
+    STATUS_STACK_OVERFLOW,
+);
@@ -0,0 +1,216 @@
+//! this module provides the queries of the object manager namespace
+//!
+//! - `open_directory` opens a directory object, e.g. "\\Device" or "\\Driver", and lists its entries
+//! - `resolve_symlink` returns the target of a symbolic link object, e.g. "\\??\\C:" to
+//!   "\\Device\\HarddiskVolume3"
+//!
+//! all the functions must be called at PASSIVE_LEVEL
+//!
+//! # Example
+//! ```
+//! let devices = ob::open_directory("\\Device")?;
+//!
+//! for entry in devices.entries() {
+//!     let entry = entry?;
+//!
+//!     if entry.type_name.to_string_lossy() == "Device" {
+//!         println!("{}", entry.name);
+//!     }
+//! }
+//!
+//! let volume = ob::resolve_symlink("\\??\\C:")?;
+//! let client = DeviceClient::open(&volume.to_string_lossy())?;
+//! ```
+use core::ptr;
+
+use wdk_sys::{
+    ACCESS_MASK, BOOLEAN, FALSE, HANDLE, NTSTATUS, OBJ_CASE_INSENSITIVE, OBJ_KERNEL_HANDLE,
+    POBJECT_ATTRIBUTES, PULONG, PVOID, STATUS_NO_MORE_ENTRIES, TRUE, ULONG, UNICODE_STRING,
+    ntddk::{ZwOpenSymbolicLinkObject, ZwQuerySymbolicLinkObject},
+};
+
+use crate::{
+    handle::{ObjectHandle, query_variable},
+    initialize_object_attributes,
+    ntstatus::{NtError, cvt},
+    unicode::NtUnicodeString,
+};
+
+/// DIRECTORY_QUERY
+const DIRECTORY_QUERY: ACCESS_MASK = 0x0001;
+/// DIRECTORY_TRAVERSE
+const DIRECTORY_TRAVERSE: ACCESS_MASK = 0x0002;
+/// SYMBOLIC_LINK_QUERY
+const SYMBOLIC_LINK_QUERY: ACCESS_MASK = 0x0001;
+
+unsafe extern "C" {
+    pub fn ZwOpenDirectoryObject(
+        DirectoryHandle: *mut HANDLE,
+        DesiredAccess: ACCESS_MASK,
+        ObjectAttributes: POBJECT_ATTRIBUTES,
+    ) -> NTSTATUS;
+
+    pub fn ZwQueryDirectoryObject(
+        DirectoryHandle: HANDLE,
+        Buffer: PVOID,
+        Length: ULONG,
+        ReturnSingleEntry: BOOLEAN,
+        RestartScan: BOOLEAN,
+        Context: PULONG,
+        ReturnLength: PULONG,
+    ) -> NTSTATUS;
+}
+
+#[repr(C)]
+#[allow(non_snake_case)]
+struct OBJECT_DIRECTORY_INFORMATION {
+    Name: UNICODE_STRING,
+    TypeName: UNICODE_STRING,
+}
+
+/// open an object with `open` and the kernel attributes of `name`
+fn open_object<F>(name: &str, open: F) -> Result<ObjectHandle, NtError>
+where
+    F: FnOnce(*mut HANDLE, POBJECT_ATTRIBUTES) -> NTSTATUS,
+{
+    let name = NtUnicodeString::from_str(name)?;
+
+    let mut attributes = initialize_object_attributes!(
+        name.as_ptr(),
+        OBJ_KERNEL_HANDLE | OBJ_CASE_INSENSITIVE,
+        ptr::null_mut(),
+        ptr::null_mut()
+    );
+
+    let mut handle: HANDLE = ptr::null_mut();
+
+    cvt(open(&mut handle, &mut attributes))?;
+
+    Ok(ObjectHandle::new(handle))
+}
+
+/// An opened object directory, the handle is a kernel handle closed on drop
+pub struct Directory {
+    handle: ObjectHandle,
+}
+
+/// open the object directory `name`, e.g. "\\Device", for listing
+pub fn open_directory(name: &str) -> Result<Directory, NtError> {
+    let handle = open_object(name, |handle, attributes| unsafe {
+        ZwOpenDirectoryObject(handle, DIRECTORY_QUERY | DIRECTORY_TRAVERSE, attributes)
+    })?;
+
+    Ok(Directory { handle })
+}
+
+impl Directory {
+    pub fn as_raw(&self) -> HANDLE {
+        self.handle.get()
+    }
+
+    /// the entries from the start of the directory, the objects created or deleted meanwhile may be missed
+    pub fn entries(&self) -> DirectoryEntries<'_> {
+        DirectoryEntries {
+            directory: self,
+            context: 0,
+            restart: true,
+            done: false,
+        }
+    }
+}
+
+/// An object in a directory
+#[derive(Clone, Debug)]
+pub struct DirectoryEntry {
+    /// the name relative to the directory
+    pub name: NtUnicodeString,
+    /// the name of the object type, e.g. "Device", "SymbolicLink" or "Directory"
+    pub type_name: NtUnicodeString,
+}
+
+/// The iterator of `Directory::entries`, it stops after an error
+pub struct DirectoryEntries<'a> {
+    directory: &'a Directory,
+    /// the position kept by `ZwQueryDirectoryObject`
+    context: ULONG,
+    restart: bool,
+    done: bool,
+}
+
+impl DirectoryEntries<'_> {
+    fn query(&mut self) -> Result<DirectoryEntry, NtError> {
+        let handle = self.directory.as_raw();
+        let restart = if self.restart { TRUE } else { FALSE };
+        let context = &mut self.context;
+
+        // a single entry which does not fit leaves the position unchanged
+        let buffer = query_variable(|info, length, required| unsafe {
+            ZwQueryDirectoryObject(
+                handle,
+                info,
+                length,
+                TRUE as _,
+                restart as _,
+                &mut *context,
+                required,
+            )
+        })?;
+
+        self.restart = false;
+
+        let info = unsafe { &*buffer.as_ptr().cast::<OBJECT_DIRECTORY_INFORMATION>() };
+
+        Ok(DirectoryEntry {
+            name: unsafe { NtUnicodeString::from_raw(&info.Name)? },
+            type_name: unsafe { NtUnicodeString::from_raw(&info.TypeName)? },
+        })
+    }
+}
+
+impl Iterator for DirectoryEntries<'_> {
+    type Item = Result<DirectoryEntry, NtError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+
+        match self.query() {
+            Ok(entry) => Some(Ok(entry)),
+            Err(e) => {
+                self.done = true;
+
+                (e.code() != STATUS_NO_MORE_ENTRIES).then_some(Err(e))
+            }
+        }
+    }
+}
+
+/// the target of the symbolic link `name`, e.g. "\\??\\C:" or "\\GLOBAL??\\PhysicalDrive0"
+///
+/// only the link itself is resolved, the target may be a link as well
+pub fn resolve_symlink(name: &str) -> Result<NtUnicodeString, NtError> {
+    let handle = open_object(name, |handle, attributes| unsafe {
+        ZwOpenSymbolicLinkObject(handle, SYMBOLIC_LINK_QUERY, attributes)
+    })?;
+
+    let mut length = 0usize;
+
+    let buffer = query_variable(|info, capacity, required| {
+        let mut target = UNICODE_STRING {
+            Length: 0,
+            MaximumLength: capacity.min(0xFFFE) as _,
+            Buffer: info.cast(),
+        };
+
+        let status = unsafe { ZwQuerySymbolicLinkObject(handle.get(), &mut target, required) };
+
+        length = target.Length as usize / 2;
+
+        status
+    })?;
+
+    let chars = unsafe { core::slice::from_raw_parts(buffer.as_ptr().cast::<u16>(), length) };
+
+    NtUnicodeString::from_utf16(chars)
+}
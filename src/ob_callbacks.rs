@@ -0,0 +1,396 @@
+//! this module provides a safe wrapper of `ObRegisterCallbacks` for process and thread handle operations
+//!
+//! # Note
+//! `ObRegisterCallbacks` fails with STATUS_ACCESS_DENIED unless the driver image is linked with `/INTEGRITYCHECK`,
+//! use `Driver::disable_integrity_check` for debugging purpose
+//!
+//! # Example
+//! ```
+//! // strip PROCESS_TERMINATE from all the handles opened to a protected process
+//! let callbacks = ObCallbacks::builder("321000")
+//!     .on_process_pre(|op: &mut PreOperation| {
+//!         if !op.is_kernel_handle() && is_protected(op.object()) {
+//!             op.strip_access(PROCESS_TERMINATE);
+//!         }
+//!     })
+//!     .register()?;
+//!
+//! // unregistered when `callbacks` is dropped
+//! ```
+use core::{cell::UnsafeCell, mem, ptr};
+
+use alloc::{boxed::Box, vec::Vec};
+use wdk_sys::{
+    _OB_PREOP_CALLBACK_STATUS::OB_PREOP_SUCCESS,
+    ACCESS_MASK, EX_RUNDOWN_REF, NTSTATUS, OB_CALLBACK_REGISTRATION, OB_FLT_REGISTRATION_VERSION,
+    OB_OPERATION_HANDLE_CREATE, OB_OPERATION_HANDLE_DUPLICATE, OB_OPERATION_REGISTRATION,
+    OB_PREOP_CALLBACK_STATUS, POB_POST_OPERATION_INFORMATION, POB_PRE_OPERATION_INFORMATION,
+    POBJECT_TYPE, PVOID, PsProcessType, PsThreadType, STATUS_INSUFFICIENT_RESOURCES,
+    STATUS_INVALID_PARAMETER, UNICODE_STRING,
+    ntddk::{
+        ExAcquireRundownProtection, ExInitializeRundownProtection, ExReleaseRundownProtection,
+        ExWaitForRundownProtectionRelease, ObRegisterCallbacks, ObUnRegisterCallbacks,
+    },
+};
+
+use crate::{
+    ntstatus::{NtError, cvt},
+    utils,
+};
+
+/// the kind of handle operation
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum HandleOperation {
+    Create,
+    Duplicate,
+}
+
+impl HandleOperation {
+    fn from_raw(operation: u32) -> Self {
+        if operation == OB_OPERATION_HANDLE_DUPLICATE {
+            HandleOperation::Duplicate
+        } else {
+            HandleOperation::Create
+        }
+    }
+}
+
+/// information of a handle operation before it is performed
+pub struct PreOperation<'a> {
+    info: &'a mut wdk_sys::OB_PRE_OPERATION_INFORMATION,
+}
+
+impl<'a> PreOperation<'a> {
+    pub fn operation(&self) -> HandleOperation {
+        HandleOperation::from_raw(self.info.Operation)
+    }
+
+    /// the process or thread object(PEPROCESS or PETHREAD) the handle refers to
+    pub fn object(&self) -> PVOID {
+        self.info.Object
+    }
+
+    pub fn is_kernel_handle(&self) -> bool {
+        unsafe { self.info.__bindgen_anon_1.Flags & 1 != 0 }
+    }
+
+    pub fn desired_access(&self) -> ACCESS_MASK {
+        unsafe {
+            match self.operation() {
+                HandleOperation::Create => {
+                    (*self.info.Parameters)
+                        .CreateHandleInformation
+                        .DesiredAccess
+                }
+                HandleOperation::Duplicate => {
+                    (*self.info.Parameters)
+                        .DuplicateHandleInformation
+                        .DesiredAccess
+                }
+            }
+        }
+    }
+
+    pub fn original_desired_access(&self) -> ACCESS_MASK {
+        unsafe {
+            match self.operation() {
+                HandleOperation::Create => {
+                    (*self.info.Parameters)
+                        .CreateHandleInformation
+                        .OriginalDesiredAccess
+                }
+                HandleOperation::Duplicate => {
+                    (*self.info.Parameters)
+                        .DuplicateHandleInformation
+                        .OriginalDesiredAccess
+                }
+            }
+        }
+    }
+
+    /// replace the access that will be granted
+    pub fn set_desired_access(&mut self, access: ACCESS_MASK) {
+        unsafe {
+            match self.operation() {
+                HandleOperation::Create => {
+                    (*self.info.Parameters)
+                        .CreateHandleInformation
+                        .DesiredAccess = access
+                }
+                HandleOperation::Duplicate => {
+                    (*self.info.Parameters)
+                        .DuplicateHandleInformation
+                        .DesiredAccess = access
+                }
+            }
+        }
+    }
+
+    /// remove `access` from the access that will be granted
+    pub fn strip_access(&mut self, access: ACCESS_MASK) {
+        let desired = self.desired_access();
+
+        self.set_desired_access(desired & !access);
+    }
+}
+
+/// information of a handle operation after it is performed
+pub struct PostOperation<'a> {
+    info: &'a wdk_sys::OB_POST_OPERATION_INFORMATION,
+}
+
+impl<'a> PostOperation<'a> {
+    pub fn operation(&self) -> HandleOperation {
+        HandleOperation::from_raw(self.info.Operation)
+    }
+
+    pub fn object(&self) -> PVOID {
+        self.info.Object
+    }
+
+    pub fn is_kernel_handle(&self) -> bool {
+        unsafe { self.info.__bindgen_anon_1.Flags & 1 != 0 }
+    }
+
+    pub fn return_status(&self) -> NTSTATUS {
+        self.info.ReturnStatus
+    }
+
+    pub fn granted_access(&self) -> ACCESS_MASK {
+        unsafe {
+            match self.operation() {
+                HandleOperation::Create => {
+                    (*self.info.Parameters)
+                        .CreateHandleInformation
+                        .GrantedAccess
+                }
+                HandleOperation::Duplicate => {
+                    (*self.info.Parameters)
+                        .DuplicateHandleInformation
+                        .GrantedAccess
+                }
+            }
+        }
+    }
+}
+
+type PreHandler = Box<dyn Fn(&mut PreOperation) + Send + Sync>;
+type PostHandler = Box<dyn Fn(&PostOperation) + Send + Sync>;
+
+/// the registration context, it must stay at a fixed address until all the callbacks returned
+///
+/// the callbacks run concurrently on the context, so it is only reached through a shared reference, and the rundown
+/// reference is mutated through an `UnsafeCell`
+struct CallbackContext {
+    rundown: UnsafeCell<EX_RUNDOWN_REF>,
+    process_pre: Option<PreHandler>,
+    process_post: Option<PostHandler>,
+    thread_pre: Option<PreHandler>,
+    thread_post: Option<PostHandler>,
+}
+
+/// A builder to register handle operation callbacks
+pub struct ObCallbacksBuilder<'a> {
+    altitude: &'a str,
+    operations: u32,
+    context: CallbackContext,
+}
+
+impl<'a> ObCallbacksBuilder<'a> {
+    /// only intercept the specified operations, both create and duplicate are intercepted by default
+    pub fn operations(mut self, create: bool, duplicate: bool) -> Self {
+        self.operations = 0;
+
+        if create {
+            self.operations |= OB_OPERATION_HANDLE_CREATE;
+        }
+
+        if duplicate {
+            self.operations |= OB_OPERATION_HANDLE_DUPLICATE;
+        }
+
+        self
+    }
+
+    pub fn on_process_pre<F: Fn(&mut PreOperation) + Send + Sync + 'static>(
+        mut self,
+        f: F,
+    ) -> Self {
+        self.context.process_pre = Some(Box::new(f));
+        self
+    }
+
+    pub fn on_process_post<F: Fn(&PostOperation) + Send + Sync + 'static>(mut self, f: F) -> Self {
+        self.context.process_post = Some(Box::new(f));
+        self
+    }
+
+    pub fn on_thread_pre<F: Fn(&mut PreOperation) + Send + Sync + 'static>(mut self, f: F) -> Self {
+        self.context.thread_pre = Some(Box::new(f));
+        self
+    }
+
+    pub fn on_thread_post<F: Fn(&PostOperation) + Send + Sync + 'static>(mut self, f: F) -> Self {
+        self.context.thread_post = Some(Box::new(f));
+        self
+    }
+
+    pub fn register(self) -> Result<ObCallbacks, NtError> {
+        let context = Box::new(self.context);
+        let altitude: Box<UNICODE_STRING> = utils::utf16_from_str(self.altitude)
+            .ok_or(NtError::new(STATUS_INSUFFICIENT_RESOURCES))?;
+
+        unsafe { ExInitializeRundownProtection(context.rundown.get()) };
+
+        let mut operations: Vec<OB_OPERATION_REGISTRATION> = Vec::new();
+
+        let kinds = [
+            (
+                unsafe { PsProcessType },
+                context.process_pre.is_some(),
+                context.process_post.is_some(),
+            ),
+            (
+                unsafe { PsThreadType },
+                context.thread_pre.is_some(),
+                context.thread_post.is_some(),
+            ),
+        ];
+
+        for (object_type, pre, post) in kinds {
+            if !pre && !post {
+                continue;
+            }
+
+            operations.push(OB_OPERATION_REGISTRATION {
+                ObjectType: object_type,
+                Operations: self.operations,
+                PreOperation: if pre { Some(pre_operation_stub) } else { None },
+                PostOperation: if post {
+                    Some(post_operation_stub)
+                } else {
+                    None
+                },
+            });
+        }
+
+        if operations.is_empty() {
+            return Err(NtError::new(STATUS_INVALID_PARAMETER));
+        }
+
+        let mut registration = OB_CALLBACK_REGISTRATION {
+            Version: OB_FLT_REGISTRATION_VERSION as _,
+            OperationRegistrationCount: operations.len() as _,
+            Altitude: *altitude,
+            RegistrationContext: context.as_ref() as *const CallbackContext as _,
+            OperationRegistration: operations.as_mut_ptr(),
+        };
+
+        let mut handle: PVOID = ptr::null_mut();
+
+        cvt(unsafe { ObRegisterCallbacks(&mut registration, &mut handle) })?;
+
+        Ok(ObCallbacks {
+            handle,
+            context,
+            _altitude: altitude,
+        })
+    }
+}
+
+/// A registration of handle operation callbacks, it is unregistered on drop
+///
+/// drop waits until all the in-flight callbacks returned, so the closures can never be freed while they are running
+pub struct ObCallbacks {
+    handle: PVOID,
+    context: Box<CallbackContext>,
+    _altitude: Box<UNICODE_STRING>,
+}
+
+impl ObCallbacks {
+    /// start building a registration with the `altitude`, for example "321000"
+    pub fn builder(altitude: &str) -> ObCallbacksBuilder<'_> {
+        ObCallbacksBuilder {
+            altitude,
+            operations: OB_OPERATION_HANDLE_CREATE | OB_OPERATION_HANDLE_DUPLICATE,
+            context: CallbackContext {
+                rundown: UnsafeCell::new(unsafe { mem::zeroed() }),
+                process_pre: None,
+                process_post: None,
+                thread_pre: None,
+                thread_post: None,
+            },
+        }
+    }
+}
+
+impl Drop for ObCallbacks {
+    fn drop(&mut self) {
+        unsafe {
+            ObUnRegisterCallbacks(self.handle);
+
+            // wait for the callbacks that are still running
+            ExWaitForRundownProtectionRelease(self.context.rundown.get());
+        }
+    }
+}
+
+unsafe impl Send for ObCallbacks {}
+unsafe impl Sync for ObCallbacks {}
+
+fn is_process_type(object_type: POBJECT_TYPE) -> bool {
+    object_type == unsafe { *PsProcessType }
+}
+
+extern "C" fn pre_operation_stub(
+    context: PVOID,
+    info: POB_PRE_OPERATION_INFORMATION,
+) -> OB_PREOP_CALLBACK_STATUS {
+    let context = unsafe { &*(context as *const CallbackContext) };
+
+    if unsafe { ExAcquireRundownProtection(context.rundown.get()) } == 0 {
+        return OB_PREOP_SUCCESS;
+    }
+
+    let mut op = PreOperation {
+        info: unsafe { &mut *info },
+    };
+
+    let handler = if is_process_type(op.info.ObjectType) {
+        &context.process_pre
+    } else {
+        &context.thread_pre
+    };
+
+    if let Some(handler) = handler {
+        handler(&mut op);
+    }
+
+    unsafe { ExReleaseRundownProtection(context.rundown.get()) };
+
+    OB_PREOP_SUCCESS
+}
+
+extern "C" fn post_operation_stub(context: PVOID, info: POB_POST_OPERATION_INFORMATION) {
+    let context = unsafe { &*(context as *const CallbackContext) };
+
+    if unsafe { ExAcquireRundownProtection(context.rundown.get()) } == 0 {
+        return;
+    }
+
+    let op = PostOperation {
+        info: unsafe { &*info },
+    };
+
+    let handler = if is_process_type(op.info.ObjectType) {
+        &context.process_post
+    } else {
+        &context.thread_post
+    };
+
+    if let Some(handler) = handler {
+        handler(&op);
+    }
+
+    unsafe { ExReleaseRundownProtection(context.rundown.get()) };
+}
@@ -1,9 +1,23 @@
 use core::{
     marker::PhantomData,
-    mem::ManuallyDrop,
-    sync::atomic::{AtomicU32, Ordering},
+    mem::{self, ManuallyDrop},
+    ptr,
+    sync::atomic::{AtomicPtr, AtomicU32, Ordering},
 };
 
+use wdk_sys::{
+    _EVENT_TYPE::NotificationEvent,
+    _KWAIT_REASON::Executive,
+    _MODE::KernelMode,
+    _POOL_TYPE::NonPagedPoolNx,
+    DISPATCH_LEVEL, FALSE, KEVENT, PKEVENT,
+    ntddk::{KeGetCurrentIrql, KeInitializeEvent, KeSetEvent, KeWaitForSingleObject},
+};
+
+use crate::utils::{ex_allocate_pool_zero, ex_free_pool};
+
+const ONCE_TAG: u32 = u32::from_ne_bytes(*b"ecno");
+
 // internal states
 const INITIAL: u32 = 0;
 const INPROGRESS: u32 = 1;
@@ -26,9 +40,24 @@ pub enum CallState {
     Poisoned,
 }
 
-#[repr(transparent)]
-pub struct Once<T> {
+/// A synchronization primitive which runs a one-time initialization
+///
+/// like `std::sync::Once`, an initializer which panics(only possible when unwinding is enabled) poisons the `Once`,
+/// the later `call_once` panics while `call_once_force` can retry the initialization
+///
+/// the waiters below DISPATCH_LEVEL block on an event which is created on the first contended wait, so an initializer
+/// may block(e.g. do file I/O) without burning the CPUs of the waiters; the waiters at DISPATCH_LEVEL spin
+///
+/// # Example
+/// ```
+/// static INIT: Once = Once::new();
+///
+/// INIT.call_once(|| register_something());
+/// assert!(INIT.is_completed());
+/// ```
+pub struct Once<T = ()> {
     state: AtomicU32,
+    event: AtomicPtr<KEVENT>,
     _phantom: PhantomData<T>,
 }
 
@@ -36,6 +65,7 @@ impl<T> Once<T> {
     pub const fn new() -> Self {
         Self {
             state: AtomicU32::new(INITIAL),
+            event: AtomicPtr::new(ptr::null_mut()),
             _phantom: PhantomData,
         }
     }
@@ -43,6 +73,7 @@ impl<T> Once<T> {
     pub const fn poisoned() -> Self {
         Self {
             state: AtomicU32::new(POISONED),
+            event: AtomicPtr::new(ptr::null_mut()),
             _phantom: PhantomData,
         }
     }
@@ -57,33 +88,210 @@ impl<T> Once<T> {
         }
     }
 
-    /// call the `init_once` only once
+    /// true if an initializer has completed successfully
+    #[inline]
+    pub fn is_completed(&self) -> bool {
+        self.state.load(Ordering::Acquire) == COPMLETED
+    }
+
+    /// call the `init_once` only if no other initializer has started
     ///
-    /// return a `OnceGuard` temporarily hold the value of `T` if the `init_once` is successfully executed
-    pub fn call_once<F: FnOnce() -> T>(&self, init_once: F) -> Option<OnceGuard<T>> {
+    /// return a `OnceGuard` temporarily hold the value of `T` if the `init_once` is successfully executed,
+    /// the state becomes `Completed` when the guard is dropped
+    pub fn try_call_once<F: FnOnce() -> T>(&self, init_once: F) -> Option<OnceGuard<'_, T>> {
         if let Ok(_) =
             self.state
                 .compare_exchange(INITIAL, INPROGRESS, Ordering::SeqCst, Ordering::Relaxed)
         {
+            let poison = PoisonOnDrop(self);
+            let data = init_once();
+            mem::forget(poison);
+
             Some(OnceGuard {
                 once: self,
-                data: ManuallyDrop::new(init_once()),
+                data: ManuallyDrop::new(data),
             })
         } else {
             None
         }
     }
 
-    /// wait until the state change to `Completed` state
-    pub fn wait(&self) {
+    /// run `f` if no initializer has completed, the concurrent callers wait until it completes
+    ///
+    /// # Panics
+    /// panics if a previous initializer panicked, or `f` panics
+    pub fn call_once<F: FnOnce()>(&self, f: F) {
+        if self.is_completed() {
+            return;
+        }
+
+        self.call_inner(false, |_| f());
+    }
+
+    /// the same as `call_once`, but a poisoned `Once` is initialized again, `OnceState::is_poisoned` tells `f`
+    /// that a previous initializer panicked
+    pub fn call_once_force<F: FnOnce(&OnceState)>(&self, f: F) {
+        if self.is_completed() {
+            return;
+        }
+
+        self.call_inner(true, f);
+    }
+
+    fn call_inner<F: FnOnce(&OnceState)>(&self, ignore_poison: bool, f: F) {
+        let mut f = Some(f);
+
+        loop {
+            match self.state.load(Ordering::Acquire) {
+                COPMLETED => return,
+                POISONED if !ignore_poison => panic!("Once instance has previously been poisoned"),
+                INPROGRESS => self.wait_while_in_progress(),
+                state => {
+                    if self
+                        .state
+                        .compare_exchange(state, INPROGRESS, Ordering::Acquire, Ordering::Relaxed)
+                        .is_err()
+                    {
+                        continue;
+                    }
+
+                    let poison = PoisonOnDrop(self);
+
+                    (f.take().unwrap())(&OnceState {
+                        poisoned: state == POISONED,
+                    });
+
+                    mem::forget(poison);
+
+                    self.finish(COPMLETED);
+
+                    return;
+                }
+            }
+        }
+    }
+
+    /// publish the final state and release the blocked waiters
+    fn finish(&self, state: u32) {
+        self.state.store(state, Ordering::SeqCst);
+
+        self.signal();
+    }
+
+    fn signal(&self) {
+        let event = self.event.load(Ordering::SeqCst);
+
+        if !event.is_null() {
+            unsafe { KeSetEvent(event, 0, FALSE as _) };
+        }
+    }
+
+    /// the event the waiters block on, `None` if it can not be allocated
+    fn get_or_create_event(&self) -> Option<PKEVENT> {
+        let event = self.event.load(Ordering::SeqCst);
+
+        if !event.is_null() {
+            return Some(event);
+        }
+
+        let new = ex_allocate_pool_zero(NonPagedPoolNx, mem::size_of::<KEVENT>() as _, ONCE_TAG)
+            .ok()? as PKEVENT;
+
+        unsafe { KeInitializeEvent(new, NotificationEvent, FALSE as _) };
+
+        match self
+            .event
+            .compare_exchange(ptr::null_mut(), new, Ordering::SeqCst, Ordering::SeqCst)
+        {
+            Ok(_) => Some(new),
+            Err(event) => {
+                unsafe { ex_free_pool(new.cast(), ONCE_TAG) };
+                Some(event)
+            }
+        }
+    }
+
+    fn wait_while_in_progress(&self) {
         use core::arch::x86_64::_mm_pause;
 
-        while self.get_state() != CallState::Completed {
+        if self.state.load(Ordering::Acquire) != INPROGRESS {
+            return;
+        }
+
+        if unsafe { KeGetCurrentIrql() } < DISPATCH_LEVEL as _ {
+            if let Some(event) = self.get_or_create_event() {
+                // the initializer publishes its state before it reads the event, so either it signals the event
+                // or the state is seen here
+                while self.state.load(Ordering::SeqCst) == INPROGRESS {
+                    unsafe {
+                        KeWaitForSingleObject(
+                            event.cast(),
+                            Executive as _,
+                            KernelMode as _,
+                            FALSE as _,
+                            ptr::null_mut(),
+                        );
+                    }
+                }
+
+                return;
+            }
+        }
+
+        while self.state.load(Ordering::Acquire) == INPROGRESS {
             unsafe {
                 _mm_pause();
             }
         }
     }
+
+    /// wait until the state change to `Completed` state
+    ///
+    /// # Panics
+    /// panics if the initializer panicked
+    pub fn wait(&self) {
+        use core::arch::x86_64::_mm_pause;
+
+        loop {
+            match self.state.load(Ordering::Acquire) {
+                COPMLETED => return,
+                POISONED => panic!("Once instance has previously been poisoned"),
+                INPROGRESS => self.wait_while_in_progress(),
+                _ => unsafe { _mm_pause() },
+            }
+        }
+    }
+}
+
+impl<T> Drop for Once<T> {
+    fn drop(&mut self) {
+        let event = self.event.swap(ptr::null_mut(), Ordering::AcqRel);
+
+        if !event.is_null() {
+            unsafe { ex_free_pool(event.cast(), ONCE_TAG) };
+        }
+    }
+}
+
+/// the state passed to the initializer of `Once::call_once_force`
+pub struct OnceState {
+    poisoned: bool,
+}
+
+impl OnceState {
+    /// true if a previous initializer panicked
+    pub fn is_poisoned(&self) -> bool {
+        self.poisoned
+    }
+}
+
+/// poison the `Once` if the initializer unwinds
+struct PoisonOnDrop<'a, T>(&'a Once<T>);
+
+impl<'a, T> Drop for PoisonOnDrop<'a, T> {
+    fn drop(&mut self) {
+        self.0.finish(POISONED);
+    }
 }
 
 /// A guard type that temporarily hold value of `T`
@@ -110,11 +318,13 @@ impl<'a, T> OnceGuard<'a, T> {
 
 impl<'a, T> Drop for OnceGuard<'a, T> {
     fn drop(&mut self) {
-        let _ = self.once.state.compare_exchange(
-            INPROGRESS,
-            COPMLETED,
-            Ordering::SeqCst,
-            Ordering::Relaxed,
-        );
+        if self
+            .once
+            .state
+            .compare_exchange(INPROGRESS, COPMLETED, Ordering::SeqCst, Ordering::Relaxed)
+            .is_ok()
+        {
+            self.once.signal();
+        }
     }
 }
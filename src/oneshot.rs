@@ -0,0 +1,280 @@
+//! this module provides a oneshot channel, which hands a single value from a DPC, a work item or any other context
+//! over to a waiting thread
+//!
+//! the sender can complete the channel or be dropped at any IRQL, at IRQL <= DISPATCH_LEVEL the event of the
+//! channel is set directly, above DISPATCH_LEVEL a DPC embedded in the channel is queued to set it and to release the
+//! reference of the sender, so the channel is never freed above DISPATCH_LEVEL
+//!
+//! the receiver is a dispatchable object, it can block on `recv`, poll with `try_recv` or be waited together with
+//! other objects, with the `async` feature it is also a `Future`
+//!
+//! # Example
+//! ```
+//! let (tx, mut rx) = oneshot::channel()?;
+//!
+//! WorkItem::queue(move || {
+//!     let _ = tx.send(query_something());
+//! })?;
+//!
+//! let result = rx.recv_timeout(Duration::from_secs(5))?;
+//! ```
+use core::{
+    cell::UnsafeCell,
+    mem::{ManuallyDrop, MaybeUninit},
+    ptr,
+    sync::atomic::{AtomicU8, Ordering},
+    time::Duration,
+};
+
+use wdk_sys::{
+    _EVENT_TYPE::NotificationEvent,
+    _KDPC, DISPATCH_LEVEL, FALSE, KEVENT, PKDPC, PVOID, STATUS_PIPE_BROKEN, STATUS_TIMEOUT,
+    ntddk::{
+        KeGetCurrentIrql, KeInitializeDpc, KeInitializeEvent, KeInsertQueueDpc, KeReadStateEvent,
+        KeSetEvent,
+    },
+};
+
+#[cfg(feature = "async")]
+use core::{
+    future::Future,
+    pin::Pin,
+    task::{Context, Poll, Waker},
+};
+
+#[cfg(feature = "async")]
+use crate::mutex::StaticSpinLocked;
+use crate::{arc::KArc, kobject::Dispatchable, ntstatus::NtError, raw::AsRawObject};
+
+// internal states
+const EMPTY: u8 = 0;
+const SENT: u8 = 1;
+const RECEIVED: u8 = 2;
+const SENDER_DROPPED: u8 = 3;
+const RECEIVER_DROPPED: u8 = 4;
+
+struct Inner<T> {
+    state: AtomicU8,
+    value: UnsafeCell<MaybeUninit<T>>,
+    event: UnsafeCell<KEVENT>,
+    dpc: UnsafeCell<_KDPC>,
+    /// the task awaiting the receiver
+    #[cfg(feature = "async")]
+    waker: StaticSpinLocked<Option<Waker>>,
+}
+
+impl<T> Inner<T> {
+    /// take the value out, the state must have been moved from `SENT` by the caller
+    unsafe fn take(&self) -> T {
+        unsafe { (*self.value.get()).assume_init_read() }
+    }
+
+    /// set the event and wake the awaiting task, it must be called at IRQL <= DISPATCH_LEVEL
+    fn signal(&self) {
+        unsafe { KeSetEvent(self.event.get(), 0, FALSE as _) };
+
+        #[cfg(feature = "async")]
+        {
+            let waker = self.waker.lock().take();
+
+            if let Some(waker) = waker {
+                waker.wake();
+            }
+        }
+    }
+}
+
+unsafe impl<T: Send> Send for Inner<T> {}
+unsafe impl<T: Send> Sync for Inner<T> {}
+
+/// the DPC holds the reference of the sender passed in `arg1`, so the channel outlives the DPC
+extern "C" fn notify_routine<T>(_dpc: PKDPC, _context: PVOID, arg1: PVOID, _arg2: PVOID) {
+    let inner = unsafe { KArc::from_raw(arg1 as *const Inner<T>) };
+
+    // a needless signal is harmless, the receiver checks the state
+    inner.signal();
+}
+
+/// release the reference of the sender, waking up the receiver if `signal`, it can be called at any IRQL
+fn release<T>(inner: KArc<Inner<T>>, signal: bool) {
+    if unsafe { KeGetCurrentIrql() } <= DISPATCH_LEVEL as u8 {
+        if signal {
+            inner.signal();
+        }
+
+        return;
+    }
+
+    let dpc = inner.dpc.get();
+
+    // the DPC is queued only once since a sender is released only once
+    unsafe { KeInsertQueueDpc(dpc, KArc::into_raw(inner) as _, ptr::null_mut()) };
+}
+
+/// create a oneshot channel, it fails with STATUS_INSUFFICIENT_RESOURCES if the channel can not be allocated
+pub fn channel<T: Send>() -> Result<(Sender<T>, Receiver<T>), NtError> {
+    let inner = KArc::new(Inner {
+        state: AtomicU8::new(EMPTY),
+        value: UnsafeCell::new(MaybeUninit::uninit()),
+        event: UnsafeCell::new(KEVENT::default()),
+        dpc: UnsafeCell::new(_KDPC::default()),
+        #[cfg(feature = "async")]
+        waker: StaticSpinLocked::new(None),
+    })?;
+
+    unsafe {
+        KeInitializeEvent(inner.event.get(), NotificationEvent, FALSE as _);
+        KeInitializeDpc(inner.dpc.get(), Some(notify_routine::<T>), ptr::null_mut());
+    }
+
+    Ok((
+        Sender {
+            inner: ManuallyDrop::new(inner.clone()),
+        },
+        Receiver { inner },
+    ))
+}
+
+/// The sending half of a oneshot channel, the receiver sees STATUS_PIPE_BROKEN if it is dropped without sending
+pub struct Sender<T> {
+    /// released by `release` rather than dropped
+    inner: ManuallyDrop<KArc<Inner<T>>>,
+}
+
+impl<T> Sender<T> {
+    /// complete the channel, it can be called at any IRQL
+    ///
+    /// the value is given back if the receiver has been dropped
+    pub fn send(self, value: T) -> Result<(), T> {
+        let mut this = ManuallyDrop::new(self);
+        let inner = unsafe { ManuallyDrop::take(&mut this.inner) };
+
+        let result = if inner.state.load(Ordering::Acquire) == RECEIVER_DROPPED {
+            Err(value)
+        } else {
+            unsafe { (*inner.value.get()).write(value) };
+
+            match inner
+                .state
+                .compare_exchange(EMPTY, SENT, Ordering::AcqRel, Ordering::Acquire)
+            {
+                Ok(_) => Ok(()),
+                // the receiver is dropped in the meantime
+                Err(_) => Err(unsafe { inner.take() }),
+            }
+        };
+
+        release(inner, result.is_ok());
+
+        result
+    }
+
+    /// true if the receiver has been dropped, a value sent now is given back
+    pub fn is_closed(&self) -> bool {
+        self.inner.state.load(Ordering::Acquire) == RECEIVER_DROPPED
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        let inner = unsafe { ManuallyDrop::take(&mut self.inner) };
+
+        let signal = inner
+            .state
+            .compare_exchange(EMPTY, SENDER_DROPPED, Ordering::AcqRel, Ordering::Acquire)
+            .is_ok();
+
+        release(inner, signal);
+    }
+}
+
+/// The receiving half of a oneshot channel
+///
+/// it is signaled once the value is sent or the sender is dropped
+pub struct Receiver<T> {
+    inner: KArc<Inner<T>>,
+}
+
+impl<T> Receiver<T> {
+    /// take the value if it has been sent, it can be called at IRQL <= DISPATCH_LEVEL
+    ///
+    /// returns `Ok(None)` if the sender has not completed the channel yet, or STATUS_PIPE_BROKEN if the sender is
+    /// dropped or the value has been taken
+    pub fn try_recv(&mut self) -> Result<Option<T>, NtError> {
+        match self
+            .inner
+            .state
+            .compare_exchange(SENT, RECEIVED, Ordering::AcqRel, Ordering::Acquire)
+        {
+            Ok(_) => Ok(Some(unsafe { self.inner.take() })),
+            Err(EMPTY) => Ok(None),
+            Err(_) => Err(NtError::new(STATUS_PIPE_BROKEN)),
+        }
+    }
+
+    /// block until the value is sent, it must be called at IRQL <= APC_LEVEL
+    pub fn recv(mut self) -> Result<T, NtError> {
+        self.wait(false);
+
+        self.try_recv()?
+            .ok_or_else(|| NtError::new(STATUS_PIPE_BROKEN))
+    }
+
+    /// same as `recv`, but fails with STATUS_TIMEOUT if the value is not sent in time
+    ///
+    /// the receiver is kept on timeout, so it can be waited again
+    pub fn recv_timeout(&mut self, timeout: Duration) -> Result<T, NtError> {
+        if self.wait_for(timeout, false).timed_out() {
+            return Err(NtError::new(STATUS_TIMEOUT));
+        }
+
+        self.try_recv()?
+            .ok_or_else(|| NtError::new(STATUS_PIPE_BROKEN))
+    }
+
+    /// true if the sender has completed the channel, either sent a value or been dropped
+    pub fn is_ready(&self) -> bool {
+        unsafe { KeReadStateEvent(self.inner.event.get()) != 0 }
+    }
+}
+
+impl<T> AsRawObject for Receiver<T> {
+    type Target = KEVENT;
+
+    fn as_raw(&self) -> *mut Self::Target {
+        self.inner.event.get()
+    }
+}
+
+impl<T> Dispatchable for Receiver<T> {}
+
+impl<T> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        // a value sent but not received is dropped here
+        if self.inner.state.swap(RECEIVER_DROPPED, Ordering::AcqRel) == SENT {
+            drop(unsafe { self.inner.take() });
+        }
+    }
+}
+
+#[cfg(feature = "async")]
+impl<T> Future for Receiver<T> {
+    type Output = Result<T, NtError>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.get_mut();
+
+        if let Some(result) = this.try_recv().transpose() {
+            return Poll::Ready(result);
+        }
+
+        let old = this.inner.waker.lock().replace(cx.waker().clone());
+        drop(old);
+
+        // the sender may complete the channel before the waker is stored
+        match this.try_recv().transpose() {
+            Some(result) => Poll::Ready(result),
+            None => Poll::Pending,
+        }
+    }
+}
@@ -0,0 +1,177 @@
+//! this module provides the version of the running system and the feature gates built on it
+//!
+//! the version is queried with `RtlGetVersion` once at PASSIVE_LEVEL and cached, so the checks are cheap, `init`
+//! caches it in DriverEntry, so the checks above PASSIVE_LEVEL see the exact build number as well
+//!
+//! # Example
+//! ```
+//! // in DriverEntry
+//! os::init();
+//!
+//! if os::version() >= OsVersion::WIN10_2004 {
+//!     use_new_api();
+//! }
+//!
+//! let timer = if os::supports_ex_timer() { ... } else { ... };
+//! ```
+use core::{
+    fmt,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use wdk_sys::{
+    PASSIVE_LEVEL, RTL_OSVERSIONINFOW,
+    ntddk::{KeGetCurrentIrql, RtlGetVersion},
+};
+
+/// KUSER_SHARED_DATA, mapped at the same address in every process
+const KUSER_SHARED_DATA: usize = 0xFFFF_F780_0000_0000;
+/// KUSER_SHARED_DATA.NtBuildNumber, it is 0 before Windows 10
+const NT_BUILD_NUMBER_OFFSET: usize = 0x260;
+/// KUSER_SHARED_DATA.NtMajorVersion
+const NT_MAJOR_VERSION_OFFSET: usize = 0x26C;
+/// KUSER_SHARED_DATA.NtMinorVersion
+const NT_MINOR_VERSION_OFFSET: usize = 0x270;
+
+/// A system version, ordered by major, minor and build number
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Debug)]
+pub struct OsVersion {
+    pub major: u32,
+    pub minor: u32,
+    pub build: u32,
+}
+
+impl OsVersion {
+    pub const WIN7: Self = Self::new(6, 1, 7600);
+    pub const WIN8: Self = Self::new(6, 2, 9200);
+    pub const WIN8_1: Self = Self::new(6, 3, 9600);
+    pub const WIN10: Self = Self::new(10, 0, 10240);
+    pub const WIN10_1607: Self = Self::new(10, 0, 14393);
+    pub const WIN10_1809: Self = Self::new(10, 0, 17763);
+    pub const WIN10_2004: Self = Self::new(10, 0, 19041);
+    pub const WIN11: Self = Self::new(10, 0, 22000);
+    pub const WIN11_24H2: Self = Self::new(10, 0, 26100);
+
+    pub const fn new(major: u32, minor: u32, build: u32) -> Self {
+        Self {
+            major,
+            minor,
+            build,
+        }
+    }
+
+    pub fn is_at_least(&self, other: OsVersion) -> bool {
+        *self >= other
+    }
+
+    fn pack(&self) -> u64 {
+        ((self.major as u64 & 0xFF) << 56) | ((self.minor as u64 & 0xFF) << 48) | self.build as u64
+    }
+
+    fn unpack(value: u64) -> Self {
+        Self::new(
+            (value >> 56) as u32,
+            ((value >> 48) & 0xFF) as u32,
+            value as u32,
+        )
+    }
+}
+
+impl fmt::Display for OsVersion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}.{}.{}", self.major, self.minor, self.build)
+    }
+}
+
+/// the packed cached version, 0 if it is not queried yet
+static VERSION: AtomicU64 = AtomicU64::new(0);
+
+fn query() -> Option<OsVersion> {
+    let mut info = RTL_OSVERSIONINFOW {
+        dwOSVersionInfoSize: core::mem::size_of::<RTL_OSVERSIONINFOW>() as _,
+        ..Default::default()
+    };
+
+    if unsafe { RtlGetVersion(&mut info) } != 0 {
+        return None;
+    }
+
+    Some(OsVersion::new(
+        info.dwMajorVersion,
+        info.dwMinorVersion,
+        info.dwBuildNumber,
+    ))
+}
+
+/// read the version from KUSER_SHARED_DATA, it is usable at any IRQL
+///
+/// the build number is 0 before Windows 10, the RTM build of the release is used then
+fn read_shared_data() -> OsVersion {
+    let read =
+        |offset: usize| unsafe { ((KUSER_SHARED_DATA + offset) as *const u32).read_volatile() };
+
+    let major = read(NT_MAJOR_VERSION_OFFSET);
+    let minor = read(NT_MINOR_VERSION_OFFSET);
+
+    let build = match read(NT_BUILD_NUMBER_OFFSET) & 0xFFFF {
+        0 => [OsVersion::WIN7, OsVersion::WIN8, OsVersion::WIN8_1]
+            .iter()
+            .find(|v| v.major == major && v.minor == minor)
+            .map_or(0, |v| v.build),
+        build => build,
+    };
+
+    OsVersion::new(major, minor, build)
+}
+
+/// query the version with `RtlGetVersion` and cache it, it must be called at PASSIVE_LEVEL
+///
+/// call it once in DriverEntry, `SoftwareDriver::install` does, so `version` never falls back to
+/// KUSER_SHARED_DATA
+pub fn init() -> OsVersion {
+    crate::assert_irql!(== PASSIVE_LEVEL);
+
+    let version = query().unwrap_or_else(read_shared_data);
+
+    VERSION.store(version.pack(), Ordering::Relaxed);
+
+    version
+}
+
+/// the version of the running system
+///
+/// it is the version cached by `init`, or queried with `RtlGetVersion` on the first call at PASSIVE_LEVEL, a call at
+/// a higher IRQL before that reads KUSER_SHARED_DATA, which only has the build number of the release before
+/// Windows 10, not the one of the service pack or the update
+pub fn version() -> OsVersion {
+    let cached = VERSION.load(Ordering::Relaxed);
+
+    if cached != 0 {
+        return OsVersion::unpack(cached);
+    }
+
+    if unsafe { KeGetCurrentIrql() } != PASSIVE_LEVEL as u8 {
+        return read_shared_data();
+    }
+
+    init()
+}
+
+pub fn is_at_least(version: OsVersion) -> bool {
+    self::version() >= version
+}
+
+/// `ExAllocateTimer` and the high resolution timers, Windows 8.1+
+pub fn supports_ex_timer() -> bool {
+    is_at_least(OsVersion::WIN8_1)
+}
+
+/// `ExAllocatePool2`, Windows 10 2004+
+pub fn supports_pool2() -> bool {
+    is_at_least(OsVersion::WIN10_2004)
+}
+
+/// the processor groups and the group-aware APIs, Windows 7+
+pub fn supports_processor_groups() -> bool {
+    is_at_least(OsVersion::WIN7)
+}
@@ -0,0 +1,114 @@
+//! this module provides an opt-in panic handler(feature `panic_handler`) which bugchecks with the panic message
+//!
+//! a panic in a thread spawned by `thread::spawn` at PASSIVE_LEVEL, outside of a critical or guarded region,
+//! terminates the thread with STATUS_UNHANDLED_EXCEPTION and is returned by its `JoinHandle::join`, on any other
+//! panic:
+//! 1. the message and the location are formatted into a preallocated nonpaged buffer, no allocation happens
+//! 2. the message is emitted with `DbgPrintEx`
+//! 3. `KeBugCheckEx` is called with the configured bugcheck code, the parameters are
+//!    - 1: the address of the message
+//!    - 2: the length of the message
+//!    - 3: the line of the panic location
+//!    - 4: the processor which panicked first
+//!
+//! the message can be read from a crash dump with `da <parameter 1> L<parameter 2>`
+//!
+//! # Note
+//! do not link `wdk-panic` or any other panic handler together with this feature
+//!
+//! # Example
+//! ```
+//! // in DriverEntry, use a code reserved for the driver
+//! panic::set_bugcheck_code(0xE0001234);
+//! ```
+use core::{
+    cell::UnsafeCell,
+    fmt::Write,
+    sync::atomic::{AtomicBool, AtomicU32, Ordering},
+};
+
+use wdk_sys::{
+    _DPFLTR_TYPE::DPFLTR_IHVDRIVER_ID,
+    DPFLTR_ERROR_LEVEL,
+    ntddk::{DbgPrintEx, KeBugCheckEx, KeGetCurrentProcessorNumberEx},
+};
+
+use crate::trace::StackBuffer;
+
+/// MANUALLY_INITIATED_CRASH1
+pub const DEFAULT_BUGCHECK_CODE: u32 = 0xDEADDEAD;
+
+/// the size of the preallocated message buffer, longer messages are truncated
+pub const MAX_PANIC_MESSAGE_LEN: usize = 512;
+
+static BUGCHECK_CODE: AtomicU32 = AtomicU32::new(DEFAULT_BUGCHECK_CODE);
+
+static PANICKING: AtomicBool = AtomicBool::new(false);
+
+struct MessageBuffer(UnsafeCell<StackBuffer<MAX_PANIC_MESSAGE_LEN>>);
+
+// Safety
+// only the first panicking processor writes the buffer, guarded by `PANICKING`
+unsafe impl Sync for MessageBuffer {}
+
+/// a static buffer lives in the nonpaged image section, so it is usable at any IRQL
+static MESSAGE: MessageBuffer = MessageBuffer(UnsafeCell::new(StackBuffer::new()));
+
+/// set the bugcheck code used on panic
+pub fn set_bugcheck_code(code: u32) {
+    BUGCHECK_CODE.store(code, Ordering::Relaxed);
+}
+
+pub fn bugcheck_code() -> u32 {
+    BUGCHECK_CODE.load(Ordering::Relaxed)
+}
+
+#[cfg(not(test))]
+#[panic_handler]
+#[allow(unreachable_code)]
+fn panic(info: &core::panic::PanicInfo) -> ! {
+    // it does not return if the thread is terminated
+    crate::thread::exit_on_panic(info);
+
+    let code = bugcheck_code();
+    let processor = unsafe { KeGetCurrentProcessorNumberEx(core::ptr::null_mut()) };
+
+    // a nested or concurrent panic bugchecks without touching the buffer again
+    if PANICKING.swap(true, Ordering::AcqRel) {
+        unsafe { KeBugCheckEx(code, 0, 0, 0, processor as _) };
+    }
+
+    let message = unsafe { &mut *MESSAGE.0.get() };
+
+    let _ = write!(message, "{}", info.message());
+
+    let line = match info.location() {
+        Some(location) => {
+            let _ = write!(message, " at {}:{}", location.file(), location.line());
+            location.line()
+        }
+        None => 0,
+    };
+
+    let text = message.as_str();
+
+    unsafe {
+        DbgPrintEx(
+            DPFLTR_IHVDRIVER_ID as _,
+            DPFLTR_ERROR_LEVEL,
+            c"[PANIC] %.*s\n".as_ptr(),
+            text.len() as i32,
+            text.as_ptr(),
+        );
+
+        KeBugCheckEx(
+            code,
+            text.as_ptr() as _,
+            text.len() as _,
+            line as _,
+            processor as _,
+        );
+    }
+
+    loop {}
+}
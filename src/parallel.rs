@@ -0,0 +1,132 @@
+//! this module provides data parallel helpers which split work across the active processors
+//!
+//! the work is run by short-lived system threads, one per processor at most, and the calling thread takes part in it.
+//! all the threads are joined before the helpers return, so the closure and the items can be borrowed
+//!
+//! # Example
+//! ```
+//! let processes: Vec<ProcessInfo> = sysinfo::processes()?.collect();
+//!
+//! parallel::for_each(&processes, |process| {
+//!     scan_process(process.pid)
+//! })?;
+//! ```
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+use alloc::vec::Vec;
+use wdk_sys::STATUS_INSUFFICIENT_RESOURCES;
+
+use crate::{
+    kobject::Dispatchable,
+    mutex::StaticSpinLocked,
+    ntstatus::NtError,
+    thread::{self, JoinHandle},
+};
+
+struct Context<'a, T, F> {
+    items: &'a [T],
+    f: &'a F,
+    next: AtomicUsize,
+    stop: AtomicBool,
+    error: StaticSpinLocked<Option<NtError>>,
+}
+
+impl<'a, T, F> Context<'a, T, F>
+where
+    F: Fn(&T) -> Result<(), NtError>,
+{
+    fn run(&self) {
+        while !self.stop.load(Ordering::Relaxed) {
+            let index = self.next.fetch_add(1, Ordering::Relaxed);
+
+            let Some(item) = self.items.get(index) else {
+                break;
+            };
+
+            if let Err(e) = (self.f)(item) {
+                self.error.lock().get_or_insert(e);
+                self.stop.store(true, Ordering::Relaxed);
+            }
+        }
+    }
+}
+
+/// the number of threads used for `len` items, the calling thread included
+pub fn workers(len: usize) -> usize {
+    thread::available_parallelism().get().min(len)
+}
+
+/// call `f` for every item on min(active processors, items) threads, it must be called at PASSIVE_LEVEL
+///
+/// the items are handed out one by one, so the items of uneven cost are balanced; after the first error, no more
+/// items are started and the error is returned once the running calls have finished
+///
+/// if a thread can not be created, the work is done by fewer threads
+pub fn for_each<T, F>(items: &[T], f: F) -> Result<(), NtError>
+where
+    T: Sync,
+    F: Fn(&T) -> Result<(), NtError> + Sync,
+{
+    let context = Context {
+        items,
+        f: &f,
+        next: AtomicUsize::new(0),
+        stop: AtomicBool::new(false),
+        error: StaticSpinLocked::new(None),
+    };
+
+    let mut handles: Vec<JoinHandle> = Vec::new();
+
+    let _ = handles.try_reserve_exact(workers(items.len()).saturating_sub(1));
+
+    // the threads are joined below before `context` goes out of scope, so the borrow outlives them
+    let address = &context as *const Context<'_, T, F> as usize;
+
+    for _ in 1..workers(items.len()) {
+        if handles.len() == handles.capacity() {
+            break;
+        }
+
+        match thread::spawn(move || unsafe { (*(address as *const Context<'_, T, F>)).run() }) {
+            Ok(handle) => handles.push(handle),
+            Err(_) => break,
+        }
+    }
+
+    context.run();
+
+    for handle in handles.iter() {
+        let _ = handle.wait(false);
+    }
+
+    match context.error.lock().take() {
+        Some(e) => Err(e),
+        None => Ok(()),
+    }
+}
+
+/// the same as `for_each` but `f` is called with mutable items
+pub fn for_each_mut<T, F>(items: &mut [T], f: F) -> Result<(), NtError>
+where
+    T: Send,
+    F: Fn(&mut T) -> Result<(), NtError> + Sync,
+{
+    struct Slot<T>(*mut T);
+
+    // Safety
+    // every item is handed out to exactly one thread
+    unsafe impl<T: Send> Sync for Slot<T> {}
+
+    let slots: Vec<Slot<T>> = {
+        let mut slots = Vec::new();
+
+        slots
+            .try_reserve_exact(items.len())
+            .map_err(|_| NtError::new(STATUS_INSUFFICIENT_RESOURCES))?;
+
+        slots.extend(items.iter_mut().map(|item| Slot(item as *mut T)));
+        slots
+    };
+
+    for_each(&slots, |slot| f(unsafe { &mut *slot.0 }))
+}
@@ -0,0 +1,165 @@
+//! this module provides `Pod`, the plain data types that can be copied to and from a byte buffer
+//!
+//! the structures crossing the user boundary(IOCTL buffers, notifications) must implement it, and the buffers are
+//! converted with the helpers here instead of `transmute` or raw pointer casts:
+//! - `as_bytes` and `as_bytes_mut` view a value as bytes
+//! - `from_bytes_checked` and `from_bytes_checked_mut` view bytes as a value, the size and the alignment are validated
+//! - `read_from` copies a value out of a possibly unaligned buffer
+//!
+//! # Example
+//! ```
+//! #[repr(C)]
+//! #[derive(Clone, Copy)]
+//! struct ProcessEvent {
+//!     pid: u32,
+//!     parent: u32,
+//! }
+//!
+//! unsafe impl Pod for ProcessEvent {}
+//!
+//! let event = ProcessEvent::read_from(irp.system_buffer_bytes()?)?;
+//! output.copy_from_slice(pod::as_bytes(&event));
+//! ```
+use core::{mem, ptr, slice};
+
+use wdk_sys::{STATUS_BUFFER_TOO_SMALL, STATUS_DATATYPE_MISALIGNMENT, STATUS_INFO_LENGTH_MISMATCH};
+
+use crate::ntstatus::NtError;
+
+/// A plain data type, any bit pattern of its size is a valid value
+///
+/// # Safety
+/// the type must be `#[repr(C)]` or `#[repr(transparent)]`, have no padding, and contain only `Pod` fields,
+/// i.e. no references, pointers to be dereferenced, `bool`, `char` or enums
+pub unsafe trait Pod: Copy + 'static {}
+
+macro_rules! impl_pod {
+    ($($t:ty),+ $(,)?) => {
+        $(unsafe impl Pod for $t {})+
+    };
+}
+
+impl_pod!(
+    u8,
+    u16,
+    u32,
+    u64,
+    u128,
+    usize,
+    i8,
+    i16,
+    i32,
+    i64,
+    i128,
+    isize,
+    f32,
+    f64,
+    ()
+);
+
+unsafe impl<T: Pod, const N: usize> Pod for [T; N] {}
+
+/// the bytes of `value`
+pub fn as_bytes<T: Pod>(value: &T) -> &[u8] {
+    unsafe { slice::from_raw_parts(value as *const T as *const u8, mem::size_of::<T>()) }
+}
+
+pub fn as_bytes_mut<T: Pod>(value: &mut T) -> &mut [u8] {
+    unsafe { slice::from_raw_parts_mut(value as *mut T as *mut u8, mem::size_of::<T>()) }
+}
+
+/// the bytes of a slice of `T`
+pub fn slice_as_bytes<T: Pod>(values: &[T]) -> &[u8] {
+    unsafe { slice::from_raw_parts(values.as_ptr() as *const u8, mem::size_of_val(values)) }
+}
+
+fn check<T>(bytes: &[u8]) -> Result<(), NtError> {
+    if bytes.len() < mem::size_of::<T>() {
+        return Err(NtError::new(STATUS_BUFFER_TOO_SMALL));
+    }
+
+    if bytes.as_ptr() as usize % mem::align_of::<T>() != 0 {
+        return Err(NtError::new(STATUS_DATATYPE_MISALIGNMENT));
+    }
+
+    Ok(())
+}
+
+/// view the head of `bytes` as a `T`
+///
+/// fails with STATUS_BUFFER_TOO_SMALL if `bytes` is shorter than `T`, or STATUS_DATATYPE_MISALIGNMENT if it is not
+/// aligned for `T`, the extra bytes are ignored
+pub fn from_bytes_checked<T: Pod>(bytes: &[u8]) -> Result<&T, NtError> {
+    check::<T>(bytes)?;
+
+    Ok(unsafe { &*(bytes.as_ptr() as *const T) })
+}
+
+pub fn from_bytes_checked_mut<T: Pod>(bytes: &mut [u8]) -> Result<&mut T, NtError> {
+    check::<T>(bytes)?;
+
+    Ok(unsafe { &mut *(bytes.as_mut_ptr() as *mut T) })
+}
+
+/// view `bytes` as a slice of `T`, the length must be a multiple of the size of `T`
+pub fn slice_from_bytes_checked<T: Pod>(bytes: &[u8]) -> Result<&[T], NtError> {
+    if mem::size_of::<T>() == 0 || bytes.len() % mem::size_of::<T>() != 0 {
+        return Err(NtError::new(STATUS_INFO_LENGTH_MISMATCH));
+    }
+
+    if bytes.as_ptr() as usize % mem::align_of::<T>() != 0 {
+        return Err(NtError::new(STATUS_DATATYPE_MISALIGNMENT));
+    }
+
+    Ok(unsafe {
+        slice::from_raw_parts(
+            bytes.as_ptr() as *const T,
+            bytes.len() / mem::size_of::<T>(),
+        )
+    })
+}
+
+/// copy a `T` out of the head of `bytes`, which needs not be aligned
+pub fn read_from<T: Pod>(bytes: &[u8]) -> Result<T, NtError> {
+    if bytes.len() < mem::size_of::<T>() {
+        return Err(NtError::new(STATUS_BUFFER_TOO_SMALL));
+    }
+
+    Ok(unsafe { ptr::read_unaligned(bytes.as_ptr() as *const T) })
+}
+
+/// copy `value` into the head of `bytes`, returns the number of bytes written
+pub fn write_to<T: Pod>(value: &T, bytes: &mut [u8]) -> Result<usize, NtError> {
+    let size = mem::size_of::<T>();
+
+    bytes
+        .get_mut(..size)
+        .ok_or(NtError::new(STATUS_BUFFER_TOO_SMALL))?
+        .copy_from_slice(as_bytes(value));
+
+    Ok(size)
+}
+
+/// The conversions of a `Pod` from bytes as associated functions, it is implemented for all the `Pod` types
+pub trait FromBytes: Pod {
+    /// see `pod::from_bytes_checked`
+    fn ref_from(bytes: &[u8]) -> Result<&Self, NtError> {
+        from_bytes_checked(bytes)
+    }
+
+    fn mut_from(bytes: &mut [u8]) -> Result<&mut Self, NtError> {
+        from_bytes_checked_mut(bytes)
+    }
+
+    /// see `pod::read_from`
+    fn read_from(bytes: &[u8]) -> Result<Self, NtError> {
+        read_from(bytes)
+    }
+
+    /// a value of all zero bits
+    fn zeroed() -> Self {
+        unsafe { mem::zeroed() }
+    }
+}
+
+impl<T: Pod> FromBytes for T {}
@@ -0,0 +1,211 @@
+//! this module provides the accounting of the pool allocations of this crate by their tag
+//!
+//! every allocation made through the allocator layer of this crate is charged to its pool tag, a tag keeps the
+//! number of its live allocations and bytes, the peak of the bytes and an optional quota, an allocation which would
+//! exceed the quota fails with STATUS_QUOTA_EXCEEDED, so a leaking or flooded subsystem runs out of its own budget
+//! instead of the non-paged pool of the whole system
+//!
+//! the module is named `pool` rather than `alloc`, which is the name of the `alloc` crate
+//!
+//! a tag can also be marked zero-on-free with `set_zero_on_free`, its allocations are wiped before they go back to
+//! the pool, see also `secret::SecretBuffer`
+//!
+//! the counters live in a fixed table of `MAX_TAGS` slots, a tag is tracked from its first allocation or quota, the
+//! allocations of a tag which finds the table full are not tracked and never fail on a quota
+//!
+//! # Example
+//! ```
+//! const CACHE_TAG: u32 = u32::from_ne_bytes(*b"ehca");
+//!
+//! // in DriverEntry, at most 4MB of non-paged pool for the cache
+//! pool::set_quota(CACHE_TAG, Some(4 * 1024 * 1024))?;
+//!
+//! let mut entries = KVec::with_tag(CACHE_TAG);
+//! entries.try_reserve(4096)?;
+//!
+//! // later, e.g. in an IOCTL
+//! if let Some(usage) = pool::usage(CACHE_TAG) {
+//!     println!("{} allocations, {} bytes, peak {}", usage.allocations, usage.bytes, usage.peak_bytes);
+//! }
+//! ```
+use core::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
+
+use wdk_sys::{STATUS_INSUFFICIENT_RESOURCES, STATUS_QUOTA_EXCEEDED, ULONG};
+
+use crate::ntstatus::NtError;
+
+/// the number of the tags which can be tracked
+pub const MAX_TAGS: usize = 64;
+
+/// the quota of a slot without one
+const NO_QUOTA: u64 = u64::MAX;
+
+struct Slot {
+    /// 0 while the slot is free, a slot is never released once claimed
+    tag: AtomicU32,
+    allocations: AtomicU64,
+    bytes: AtomicU64,
+    peak: AtomicU64,
+    quota: AtomicU64,
+    rejected: AtomicU64,
+    zero_on_free: AtomicBool,
+}
+
+impl Slot {
+    const fn new() -> Self {
+        Self {
+            tag: AtomicU32::new(0),
+            allocations: AtomicU64::new(0),
+            bytes: AtomicU64::new(0),
+            peak: AtomicU64::new(0),
+            quota: AtomicU64::new(NO_QUOTA),
+            rejected: AtomicU64::new(0),
+            zero_on_free: AtomicBool::new(false),
+        }
+    }
+}
+
+static SLOTS: [Slot; MAX_TAGS] = [const { Slot::new() }; MAX_TAGS];
+
+/// the slot of `tag` if it is tracked
+fn find(tag: ULONG) -> Option<&'static Slot> {
+    if tag == 0 {
+        return None;
+    }
+
+    SLOTS
+        .iter()
+        .find(|slot| slot.tag.load(Ordering::Acquire) == tag)
+}
+
+/// the slot of `tag`, a free slot is claimed if it is not tracked yet
+fn find_or_claim(tag: ULONG) -> Option<&'static Slot> {
+    if tag == 0 {
+        return None;
+    }
+
+    for slot in SLOTS.iter() {
+        match slot
+            .tag
+            .compare_exchange(0, tag, Ordering::AcqRel, Ordering::Acquire)
+        {
+            Ok(_) => return Some(slot),
+            Err(current) if current == tag => return Some(slot),
+            Err(_) => continue,
+        }
+    }
+
+    None
+}
+
+/// The accounting of a pool tag
+#[derive(Clone, Copy, Debug)]
+pub struct PoolUsage {
+    pub tag: ULONG,
+    /// the number of the live allocations
+    pub allocations: u64,
+    /// the bytes of the live allocations, without the pool headers
+    pub bytes: u64,
+    pub peak_bytes: u64,
+    pub quota: Option<u64>,
+    /// the number of the allocations failed on the quota
+    pub rejected: u64,
+}
+
+impl PoolUsage {
+    fn of(slot: &Slot) -> Self {
+        let quota = slot.quota.load(Ordering::Relaxed);
+
+        Self {
+            tag: slot.tag.load(Ordering::Relaxed),
+            allocations: slot.allocations.load(Ordering::Relaxed),
+            bytes: slot.bytes.load(Ordering::Relaxed),
+            peak_bytes: slot.peak.load(Ordering::Relaxed),
+            quota: (quota != NO_QUOTA).then_some(quota),
+            rejected: slot.rejected.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// limit the live bytes of `tag` to `quota`, `None` removes the limit, it can be called at any IRQL
+///
+/// the allocations already made are kept even if they exceed the new quota, it fails with
+/// STATUS_INSUFFICIENT_RESOURCES if all the `MAX_TAGS` slots are taken by the other tags
+pub fn set_quota(tag: ULONG, quota: Option<u64>) -> Result<(), NtError> {
+    let slot = find_or_claim(tag).ok_or_else(|| NtError::new(STATUS_INSUFFICIENT_RESOURCES))?;
+
+    slot.quota
+        .store(quota.unwrap_or(NO_QUOTA), Ordering::Relaxed);
+
+    Ok(())
+}
+
+/// wipe the allocations of `tag` when they are freed, e.g. for the tag of the buffers which hold key material
+///
+/// it applies to the allocations freed after the call, it fails with STATUS_INSUFFICIENT_RESOURCES if all the
+/// `MAX_TAGS` slots are taken by the other tags
+pub fn set_zero_on_free(tag: ULONG, enable: bool) -> Result<(), NtError> {
+    let slot = find_or_claim(tag).ok_or_else(|| NtError::new(STATUS_INSUFFICIENT_RESOURCES))?;
+
+    slot.zero_on_free.store(enable, Ordering::Relaxed);
+
+    Ok(())
+}
+
+/// the accounting of `tag`, `None` if the tag is not tracked
+pub fn usage(tag: ULONG) -> Option<PoolUsage> {
+    find(tag).map(PoolUsage::of)
+}
+
+/// the accounting of all the tracked tags
+pub fn usages() -> impl Iterator<Item = PoolUsage> {
+    SLOTS
+        .iter()
+        .filter(|slot| slot.tag.load(Ordering::Acquire) != 0)
+        .map(PoolUsage::of)
+}
+
+/// reset the peaks and the rejected counts of all the tags to their current values
+pub fn reset_peaks() {
+    for slot in SLOTS.iter() {
+        slot.peak
+            .store(slot.bytes.load(Ordering::Relaxed), Ordering::Relaxed);
+        slot.rejected.store(0, Ordering::Relaxed);
+    }
+}
+
+/// charge an allocation of `size` bytes to `tag` before it is made
+///
+/// it fails with STATUS_QUOTA_EXCEEDED if the live bytes of `tag` would exceed its quota
+pub(crate) fn charge(tag: ULONG, size: u64) -> Result<(), NtError> {
+    let Some(slot) = find_or_claim(tag) else {
+        return Ok(());
+    };
+
+    let quota = slot.quota.load(Ordering::Relaxed);
+    let bytes = slot.bytes.fetch_add(size, Ordering::Relaxed) + size;
+
+    if bytes > quota {
+        slot.bytes.fetch_sub(size, Ordering::Relaxed);
+        slot.rejected.fetch_add(1, Ordering::Relaxed);
+
+        return Err(NtError::new(STATUS_QUOTA_EXCEEDED));
+    }
+
+    slot.allocations.fetch_add(1, Ordering::Relaxed);
+    slot.peak.fetch_max(bytes, Ordering::Relaxed);
+
+    Ok(())
+}
+
+/// give back the charge of an allocation which is freed or failed, returns true if `tag` is zero-on-free
+pub(crate) fn uncharge(tag: ULONG, size: u64) -> bool {
+    let Some(slot) = find(tag) else {
+        return false;
+    };
+
+    slot.bytes.fetch_sub(size, Ordering::Relaxed);
+    slot.allocations.fetch_sub(1, Ordering::Relaxed);
+
+    slot.zero_on_free.load(Ordering::Relaxed)
+}
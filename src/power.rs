@@ -0,0 +1,283 @@
+//! this module provides the notifications of the system power state
+//!
+//! `PowerStateCallback` is called around the sleep and hibernation transitions(S3/S4) and when the power source
+//! changes, through the `\Callback\PowerState` callback object, `PowerSettingCallback` is called when a power
+//! setting changes, e.g. the display is turned off or the battery runs low, see the GUIDs in `settings`
+//!
+//! the handlers run at PASSIVE_LEVEL, dropping a registration waits for the running handler, so a handler can hold
+//! the primitives of this crate, e.g. an event to pause a worker thread
+//!
+//! # Example
+//! ```
+//! let paused = Arc::new(NotificationEvent::new(false)?);
+//!
+//! let registration = {
+//!     let paused = paused.clone();
+//!
+//!     PowerStateCallback::register(move |transition| match transition {
+//!         PowerTransition::Sleeping => paused.set(),
+//!         PowerTransition::Resumed => paused.clear(),
+//!         PowerTransition::PowerSource(_) => {}
+//!     })?
+//! };
+//!
+//! let display = PowerSettingCallback::register(&settings::CONSOLE_DISPLAY_STATE, |value| {
+//!     // 0: off, 1: on, 2: dimmed
+//!     let state = value.first().copied().unwrap_or(1);
+//! })?;
+//! ```
+use core::{cell::UnsafeCell, mem, ptr, slice};
+
+use alloc::boxed::Box;
+use wdk_sys::{
+    EX_RUNDOWN_REF, GUID, LPCGUID, NTSTATUS, OBJ_CASE_INSENSITIVE, OBJ_KERNEL_HANDLE,
+    PCALLBACK_OBJECT, PVOID, STATUS_INSUFFICIENT_RESOURCES, STATUS_SUCCESS, ULONG,
+    ntddk::{
+        ExAcquireRundownProtection, ExCreateCallback, ExInitializeRundownProtection,
+        ExRegisterCallback, ExReleaseRundownProtection, ExUnregisterCallback,
+        ExWaitForRundownProtectionRelease, ObfDereferenceObject, PoRegisterPowerSettingCallback,
+        PoUnregisterPowerSettingCallback,
+    },
+};
+
+use crate::{
+    initialize_object_attributes,
+    ntstatus::{NtError, cvt},
+    unicode::NtUnicodeString,
+    utils::try_box,
+};
+
+/// PO_CB_AC_STATUS
+const PO_CB_AC_STATUS: usize = 1;
+/// PO_CB_SYSTEM_STATE_LOCK
+const PO_CB_SYSTEM_STATE_LOCK: usize = 3;
+
+/// the GUIDs of the common power settings
+pub mod settings {
+    use wdk_sys::GUID;
+
+    /// GUID_CONSOLE_DISPLAY_STATE, a u32: 0 off, 1 on, 2 dimmed
+    pub const CONSOLE_DISPLAY_STATE: GUID = GUID {
+        Data1: 0x6fe69556,
+        Data2: 0x704a,
+        Data3: 0x47a0,
+        Data4: [0x8f, 0x24, 0xc2, 0x8d, 0x93, 0x6f, 0xda, 0x47],
+    };
+
+    /// GUID_ACDC_POWER_SOURCE, a u32: 0 AC, 1 battery, 2 short-term source like an UPS
+    pub const ACDC_POWER_SOURCE: GUID = GUID {
+        Data1: 0x5d3e9a59,
+        Data2: 0xe9d5,
+        Data3: 0x4b00,
+        Data4: [0xa6, 0xbd, 0xff, 0x34, 0xff, 0x51, 0x65, 0x48],
+    };
+
+    /// GUID_BATTERY_PERCENTAGE_REMAINING, a u32 from 0 to 100
+    pub const BATTERY_PERCENTAGE_REMAINING: GUID = GUID {
+        Data1: 0xa7ad8041,
+        Data2: 0xb45a,
+        Data3: 0x4cae,
+        Data4: [0x87, 0xa3, 0xee, 0xcb, 0xb4, 0x68, 0xa9, 0xe1],
+    };
+
+    /// GUID_SYSTEM_AWAYMODE, a u32: 1 when the system enters the away mode
+    pub const SYSTEM_AWAYMODE: GUID = GUID {
+        Data1: 0x98a7f580,
+        Data2: 0x01f7,
+        Data3: 0x48aa,
+        Data4: [0x9c, 0x0f, 0x44, 0x35, 0x2c, 0x29, 0xe5, 0xc0],
+    };
+}
+
+/// A change of the system power state
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum PowerTransition {
+    /// the system is about to enter a sleep state or hibernate
+    Sleeping,
+    /// the system is back to the working state(S0)
+    Resumed,
+    /// the power source changed, true if the system runs on AC power
+    PowerSource(bool),
+}
+
+type StateHandler = Box<dyn Fn(PowerTransition) + Send + Sync>;
+type SettingHandler = Box<dyn Fn(&[u8]) + Send + Sync>;
+
+/// a handler with the rundown protection held by its running calls, boxed so it has a stable address
+///
+/// the callbacks share the context, so the rundown reference is only reached through a shared reference
+struct Context<F> {
+    rundown: UnsafeCell<EX_RUNDOWN_REF>,
+    handler: F,
+}
+
+impl<F> Context<F> {
+    fn new(handler: F) -> Result<Box<Self>, NtError> {
+        let context = try_box(Self {
+            rundown: UnsafeCell::new(unsafe { mem::zeroed() }),
+            handler,
+        })?;
+
+        unsafe { ExInitializeRundownProtection(context.rundown.get()) };
+
+        Ok(context)
+    }
+
+    /// run `f` with the handler unless the registration is being dropped
+    fn enter(context: PVOID, f: impl FnOnce(&F)) {
+        let context = unsafe { &*(context as *const Self) };
+
+        if unsafe { ExAcquireRundownProtection(context.rundown.get()) } == 0 {
+            return;
+        }
+
+        f(&context.handler);
+
+        unsafe { ExReleaseRundownProtection(context.rundown.get()) };
+    }
+
+    /// wait for the calls of the handler which are still running
+    fn wait(&self) {
+        unsafe { ExWaitForRundownProtectionRelease(self.rundown.get()) };
+    }
+}
+
+/// A registration on `\Callback\PowerState`, it is unregistered on drop
+pub struct PowerStateCallback {
+    object: PCALLBACK_OBJECT,
+    registration: PVOID,
+    context: Box<Context<StateHandler>>,
+}
+
+impl PowerStateCallback {
+    /// call `f` on every power state transition, it must be called at PASSIVE_LEVEL
+    pub fn register<F: Fn(PowerTransition) + Send + Sync + 'static>(f: F) -> Result<Self, NtError> {
+        crate::assert_irql!(== PASSIVE_LEVEL);
+
+        let context = Context::new(try_box(f)? as StateHandler)?;
+
+        let name = NtUnicodeString::from_str("\\Callback\\PowerState")?;
+
+        let mut attributes = initialize_object_attributes!(
+            name.as_ptr(),
+            OBJ_KERNEL_HANDLE | OBJ_CASE_INSENSITIVE,
+            ptr::null_mut(),
+            ptr::null_mut()
+        );
+
+        let mut object: PCALLBACK_OBJECT = ptr::null_mut();
+
+        cvt(unsafe { ExCreateCallback(&mut object, &mut attributes, 0, 1) })?;
+
+        let registration = unsafe {
+            ExRegisterCallback(
+                object,
+                Some(power_state_routine_stub),
+                context.as_ref() as *const _ as _,
+            )
+        };
+
+        if registration.is_null() {
+            unsafe { ObfDereferenceObject(object.cast()) };
+
+            return Err(NtError::new(STATUS_INSUFFICIENT_RESOURCES));
+        }
+
+        Ok(Self {
+            object,
+            registration,
+            context,
+        })
+    }
+}
+
+impl Drop for PowerStateCallback {
+    fn drop(&mut self) {
+        unsafe {
+            ExUnregisterCallback(self.registration);
+            ObfDereferenceObject(self.object.cast());
+        }
+
+        // wait for the handler that is still running
+        self.context.wait();
+    }
+}
+
+unsafe impl Send for PowerStateCallback {}
+unsafe impl Sync for PowerStateCallback {}
+
+extern "C" fn power_state_routine_stub(context: PVOID, argument1: PVOID, argument2: PVOID) {
+    let transition = match argument1 as usize {
+        PO_CB_SYSTEM_STATE_LOCK if argument2.is_null() => PowerTransition::Sleeping,
+        PO_CB_SYSTEM_STATE_LOCK => PowerTransition::Resumed,
+        PO_CB_AC_STATUS => PowerTransition::PowerSource(!argument2.is_null()),
+        _ => return,
+    };
+
+    Context::<StateHandler>::enter(context, |handler| handler(transition));
+}
+
+/// A registration of a power setting callback, it is unregistered on drop
+pub struct PowerSettingCallback {
+    handle: PVOID,
+    context: Box<Context<SettingHandler>>,
+}
+
+impl PowerSettingCallback {
+    /// call `f` with the new value whenever the setting `guid` changes, it must be called at PASSIVE_LEVEL
+    ///
+    /// the system calls `f` once with the current value right after the registration
+    pub fn register<F: Fn(&[u8]) + Send + Sync + 'static>(
+        guid: &GUID,
+        f: F,
+    ) -> Result<Self, NtError> {
+        crate::assert_irql!(== PASSIVE_LEVEL);
+
+        let context = Context::new(try_box(f)? as SettingHandler)?;
+
+        let mut handle: PVOID = ptr::null_mut();
+
+        cvt(unsafe {
+            PoRegisterPowerSettingCallback(
+                ptr::null_mut(),
+                guid,
+                Some(power_setting_routine_stub),
+                context.as_ref() as *const _ as _,
+                &mut handle,
+            )
+        })?;
+
+        Ok(Self { handle, context })
+    }
+}
+
+impl Drop for PowerSettingCallback {
+    fn drop(&mut self) {
+        unsafe {
+            PoUnregisterPowerSettingCallback(self.handle);
+        }
+
+        // wait for the handler that is still running
+        self.context.wait();
+    }
+}
+
+unsafe impl Send for PowerSettingCallback {}
+unsafe impl Sync for PowerSettingCallback {}
+
+extern "C" fn power_setting_routine_stub(
+    _setting: LPCGUID,
+    value: PVOID,
+    length: ULONG,
+    context: PVOID,
+) -> NTSTATUS {
+    let value = if value.is_null() || length == 0 {
+        &[][..]
+    } else {
+        unsafe { slice::from_raw_parts(value as *const u8, length as _) }
+    };
+
+    Context::<SettingHandler>::enter(context, |handler| handler(value));
+
+    STATUS_SUCCESS
+}
@@ -0,0 +1,388 @@
+//! this module provides `Process`, a referenced EPROCESS with the common Ps-layer queries
+//!
+//! # Example
+//! ```
+//! let process = Process::open(pid)?;
+//!
+//! println!("{} wow64: {}", process.name(), process.is_wow64());
+//!
+//! {
+//!     // the user address space of the process is accessible until the guard is dropped
+//!     let _attach = process.attach();
+//!     read_peb();
+//! }
+//!
+//! process.terminate(STATUS_ACCESS_DENIED)?;
+//!
+//! // the PEB of a 32-bit process is read from its 32-bit PEB
+//! let command_line = process.command_line()?;
+//! let image = process.image_file_name()?;
+//! ```
+use core::{ffi::CStr, mem, ptr};
+
+use alloc::{boxed::Box, string::String, vec::Vec};
+use wdk_sys::{
+    _KPROCESS,
+    _MODE::KernelMode,
+    HANDLE, KAPC_STATE, KPROCESSOR_MODE, LONG, NTSTATUS, OBJ_KERNEL_HANDLE, PEPROCESS, PKAPC_STATE,
+    PRKPROCESS, PROCESS_QUERY_LIMITED_INFORMATION, PROCESS_TERMINATE, PSIZE_T, PUCHAR, PULONG,
+    PUNICODE_STRING, PVOID, PsProcessType, SIZE_T, STATUS_ACCESS_VIOLATION,
+    STATUS_INSUFFICIENT_RESOURCES, STATUS_NOT_FOUND, ULONG, ULONG_PTR, UNICODE_STRING,
+    ntddk::{
+        ExFreePoolWithTag, IoGetCurrentProcess, ObOpenObjectByPointer, ObfReferenceObject,
+        PsGetProcessId,
+    },
+};
+
+use crate::{
+    handle::{self, ObjectHandle},
+    kobject::{Dispatchable, FromProcessId, ProcessObject},
+    ntstatus::{NtError, cvt},
+    pod::{self, Pod},
+    raw::AsRawObject,
+    unicode::NtUnicodeString,
+    utils,
+};
+
+unsafe extern "C" {
+    pub fn PsGetProcessImageFileName(Process: PEPROCESS) -> PUCHAR;
+
+    pub fn PsGetProcessWow64Process(Process: PEPROCESS) -> PVOID;
+
+    pub fn SeLocateProcessImageName(
+        Process: PEPROCESS,
+        pImageFileName: *mut PUNICODE_STRING,
+    ) -> NTSTATUS;
+
+    pub fn ZwTerminateProcess(ProcessHandle: HANDLE, ExitStatus: NTSTATUS) -> NTSTATUS;
+
+    pub fn KeStackAttachProcess(PROCESS: PRKPROCESS, ApcState: PKAPC_STATE);
+
+    pub fn KeUnstackDetachProcess(ApcState: PKAPC_STATE);
+
+    pub fn ZwQueryInformationProcess(
+        ProcessHandle: HANDLE,
+        ProcessInformationClass: ULONG,
+        ProcessInformation: PVOID,
+        ProcessInformationLength: ULONG,
+        ReturnLength: PULONG,
+    ) -> NTSTATUS;
+
+    /// it catches the faults on an invalid address, which can not be done in Rust
+    pub fn MmCopyVirtualMemory(
+        FromProcess: PEPROCESS,
+        FromAddress: PVOID,
+        ToProcess: PEPROCESS,
+        ToAddress: PVOID,
+        BufferSize: SIZE_T,
+        PreviousMode: KPROCESSOR_MODE,
+        NumberOfBytesCopied: PSIZE_T,
+    ) -> NTSTATUS;
+
+    pub static MmUserProbeAddress: ULONG_PTR;
+}
+
+/// PROCESSINFOCLASS
+const PROCESS_BASIC_INFORMATION_CLASS: ULONG = 0;
+const PROCESS_IMAGE_FILE_NAME_CLASS: ULONG = 27;
+
+#[repr(C)]
+pub struct PROCESS_BASIC_INFORMATION {
+    pub ExitStatus: NTSTATUS,
+    pub PebBaseAddress: PVOID,
+    pub AffinityMask: usize,
+    pub BasePriority: LONG,
+    pub UniqueProcessId: usize,
+    pub InheritedFromUniqueProcessId: usize,
+}
+
+impl Default for PROCESS_BASIC_INFORMATION {
+    fn default() -> Self {
+        unsafe { mem::zeroed() }
+    }
+}
+
+/// the offsets of `PEB::ProcessParameters` and `RTL_USER_PROCESS_PARAMETERS::CommandLine`
+const PEB64_PROCESS_PARAMETERS: usize = 0x20;
+const PARAMETERS64_COMMAND_LINE: usize = 0x70;
+const PEB32_PROCESS_PARAMETERS: usize = 0x10;
+const PARAMETERS32_COMMAND_LINE: usize = 0x40;
+
+/// UNICODE_STRING64
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct UnicodeString64 {
+    length: u16,
+    maximum_length: u16,
+    _padding: u32,
+    buffer: u64,
+}
+
+unsafe impl Pod for UnicodeString64 {}
+
+/// UNICODE_STRING32
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct UnicodeString32 {
+    length: u16,
+    maximum_length: u16,
+    buffer: u32,
+}
+
+unsafe impl Pod for UnicodeString32 {}
+
+/// A referenced process object, the reference is released on drop
+pub struct Process(ProcessObject);
+
+impl Process {
+    /// look up a process by its id
+    pub fn open(pid: u32) -> Result<Self, NtError> {
+        ProcessObject::from_process_id(pid as usize as HANDLE).map(Self)
+    }
+
+    /// the process in whose context the caller is running
+    pub fn current() -> Self {
+        let process = unsafe { IoGetCurrentProcess() };
+
+        unsafe { ObfReferenceObject(process.cast()) };
+
+        Self(ProcessObject::new(process).unwrap())
+    }
+
+    /// take the ownership of a referenced process
+    pub fn from_object(object: ProcessObject) -> Self {
+        Self(object)
+    }
+
+    pub fn as_object(&self) -> &ProcessObject {
+        &self.0
+    }
+
+    pub fn pid(&self) -> u32 {
+        unsafe { PsGetProcessId(self.0.as_ptr()) as usize as u32 }
+    }
+
+    /// the image file name truncated to 15 characters, e.g. "svchost.exe"
+    pub fn name(&self) -> String {
+        let name = unsafe { PsGetProcessImageFileName(self.0.as_ptr()) };
+
+        if name.is_null() {
+            return String::new();
+        }
+
+        unsafe { CStr::from_ptr(name.cast()) }
+            .to_string_lossy()
+            .into()
+    }
+
+    /// the full NT path of the image, e.g. "\Device\HarddiskVolume3\Windows\System32\svchost.exe"
+    ///
+    /// it must be called at PASSIVE_LEVEL
+    pub fn image_path(&self) -> Result<String, NtError> {
+        let mut name: PUNICODE_STRING = ptr::null_mut();
+
+        cvt(unsafe { SeLocateProcessImageName(self.0.as_ptr(), &mut name) })?;
+
+        let path = utils::unicode_to_string(name);
+
+        // the buffer is allocated by `SeLocateProcessImageName` without a documented tag
+        unsafe { ExFreePoolWithTag(name.cast(), 0) };
+
+        path.ok_or(NtError::new(STATUS_INSUFFICIENT_RESOURCES))
+    }
+
+    /// true if it is a 32-bit process running on a 64-bit system
+    pub fn is_wow64(&self) -> bool {
+        !unsafe { PsGetProcessWow64Process(self.0.as_ptr()) }.is_null()
+    }
+
+    /// the address of the native PEB, it must be called at PASSIVE_LEVEL
+    pub fn peb_base(&self) -> Result<PVOID, NtError> {
+        let handle = self.open_handle(PROCESS_QUERY_LIMITED_INFORMATION)?;
+
+        let mut length: ULONG = 0;
+        let mut info = PROCESS_BASIC_INFORMATION::default();
+
+        cvt(unsafe {
+            ZwQueryInformationProcess(
+                handle.get(),
+                PROCESS_BASIC_INFORMATION_CLASS,
+                &mut info as *mut _ as *mut _,
+                mem::size_of::<PROCESS_BASIC_INFORMATION>() as _,
+                &mut length,
+            )
+        })?;
+
+        Ok(info.PebBaseAddress)
+    }
+
+    /// the address of the 32-bit PEB of a WOW64 process, `None` for a native process
+    pub fn peb32_base(&self) -> Option<PVOID> {
+        let peb = unsafe { PsGetProcessWow64Process(self.0.as_ptr()) };
+
+        (!peb.is_null()).then_some(peb)
+    }
+
+    /// the full NT path of the image, queried by `ZwQueryInformationProcess(ProcessImageFileName)`
+    ///
+    /// it must be called at PASSIVE_LEVEL
+    pub fn image_file_name(&self) -> Result<NtUnicodeString, NtError> {
+        let handle = self.open_handle(PROCESS_QUERY_LIMITED_INFORMATION)?;
+
+        let buffer = handle::query_variable(|info, length, required| unsafe {
+            ZwQueryInformationProcess(
+                handle.get(),
+                PROCESS_IMAGE_FILE_NAME_CLASS,
+                info,
+                length,
+                required,
+            )
+        })?;
+
+        // the buffer is a UNICODE_STRING followed by the characters
+        unsafe { NtUnicodeString::from_raw(&*buffer.as_ptr().cast::<UNICODE_STRING>()) }
+    }
+
+    /// the command line in the process parameters of the PEB, the 32-bit PEB is read for a WOW64 process
+    ///
+    /// the user memory is read by `MmCopyVirtualMemory`, which attaches to the process and catches the faults,
+    /// so a process modifying or freeing its PEB can not crash the system
+    ///
+    /// it must be called at PASSIVE_LEVEL
+    pub fn command_line(&self) -> Result<NtUnicodeString, NtError> {
+        let (address, length) = match self.peb32_base() {
+            Some(peb) => {
+                let parameters: u32 = self.read_user(peb as usize + PEB32_PROCESS_PARAMETERS)?;
+                let s: UnicodeString32 =
+                    self.read_user(parameters as usize + PARAMETERS32_COMMAND_LINE)?;
+
+                (s.buffer as usize, s.length as usize)
+            }
+            None => {
+                let peb = self.peb_base()?;
+                let parameters: u64 = self.read_user(peb as usize + PEB64_PROCESS_PARAMETERS)?;
+                let s: UnicodeString64 =
+                    self.read_user(parameters as usize + PARAMETERS64_COMMAND_LINE)?;
+
+                (s.buffer as usize, s.length as usize)
+            }
+        };
+
+        if address == 0 {
+            return Err(NtError::new(STATUS_NOT_FOUND));
+        }
+
+        let mut chars: Vec<u16> = Vec::new();
+
+        chars
+            .try_reserve_exact(length / 2)
+            .map_err(|_| NtError::new(STATUS_INSUFFICIENT_RESOURCES))?;
+        chars.resize(length / 2, 0);
+
+        self.copy_user(address, chars.as_mut_ptr().cast(), length / 2 * 2)?;
+
+        NtUnicodeString::from_utf16(&chars)
+    }
+
+    /// read a `T` from the user address space of the process
+    fn read_user<T: Pod + Default>(&self, address: usize) -> Result<T, NtError> {
+        let mut value = T::default();
+
+        let bytes = pod::as_bytes_mut(&mut value);
+
+        self.copy_user(address, bytes.as_mut_ptr().cast(), bytes.len())?;
+
+        Ok(value)
+    }
+
+    fn copy_user(&self, address: usize, buffer: PVOID, len: usize) -> Result<(), NtError> {
+        let in_user_space = address != 0
+            && address
+                .checked_add(len)
+                .is_some_and(|end| end <= unsafe { MmUserProbeAddress } as usize);
+
+        if !in_user_space {
+            return Err(NtError::new(STATUS_ACCESS_VIOLATION));
+        }
+
+        let mut copied: SIZE_T = 0;
+
+        cvt(unsafe {
+            MmCopyVirtualMemory(
+                self.0.as_ptr(),
+                address as PVOID,
+                IoGetCurrentProcess(),
+                buffer,
+                len as _,
+                KernelMode as _,
+                &mut copied,
+            )
+        })
+    }
+
+    /// open a kernel handle of this process
+    fn open_handle(&self, access: u32) -> Result<ObjectHandle, NtError> {
+        let mut handle: HANDLE = ptr::null_mut();
+
+        cvt(unsafe {
+            ObOpenObjectByPointer(
+                self.0.as_ptr().cast(),
+                OBJ_KERNEL_HANDLE,
+                ptr::null_mut(),
+                access,
+                *PsProcessType,
+                KernelMode as _,
+                &mut handle,
+            )
+        })?;
+
+        Ok(ObjectHandle::new(handle))
+    }
+
+    /// terminate the process with `status` as its exit code
+    pub fn terminate(&self, status: NTSTATUS) -> Result<(), NtError> {
+        let handle = self.open_handle(PROCESS_TERMINATE)?;
+
+        cvt(unsafe { ZwTerminateProcess(handle.get(), status) })
+    }
+
+    /// attach the current thread to the address space of this process until the guard is dropped
+    ///
+    /// it must be called at IRQL <= APC_LEVEL, the guards of nested attaches must be dropped in reverse order
+    pub fn attach(&self) -> AttachGuard<'_> {
+        // the thread keeps a pointer to the saved state while attached, so it must not move
+        let mut state = Box::new(KAPC_STATE::default());
+
+        unsafe { KeStackAttachProcess(self.0.as_ptr(), state.as_mut()) };
+
+        AttachGuard {
+            state,
+            _process: self,
+        }
+    }
+}
+
+impl AsRawObject for Process {
+    type Target = _KPROCESS;
+    fn as_raw(&self) -> *mut Self::Target {
+        self.0.as_ptr()
+    }
+}
+
+/// wait for the process to exit
+impl Dispatchable for Process {}
+
+unsafe impl Send for Process {}
+unsafe impl Sync for Process {}
+
+/// An RAII guard of `KeStackAttachProcess`, it detaches from the process on drop
+pub struct AttachGuard<'a> {
+    state: Box<KAPC_STATE>,
+    _process: &'a Process,
+}
+
+impl<'a> Drop for AttachGuard<'a> {
+    fn drop(&mut self) {
+        unsafe { KeUnstackDetachProcess(self.state.as_mut()) };
+    }
+}
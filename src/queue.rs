@@ -0,0 +1,226 @@
+//! this module provides `KernelQueue<T>`, a wrapper of the kernel KQUEUE object
+//!
+//! a KQUEUE hands out entries to multiple waiting threads and throttles the number of threads running concurrently
+//! for it, it is the canonical way to build a thread pool in kernel mode
+use core::{
+    marker::PhantomData,
+    mem, ptr,
+    sync::atomic::{AtomicBool, Ordering},
+    time::Duration,
+};
+
+use alloc::boxed::Box;
+use wdk_sys::{
+    _KQUEUE, _MODE::KernelMode, _POOL_TYPE::NonPagedPoolNx, KPROCESSOR_MODE, LIST_ENTRY, LONG,
+    NTSTATUS, PLARGE_INTEGER, PLIST_ENTRY, PRKQUEUE, STATUS_ABANDONED, STATUS_TIMEOUT,
+    STATUS_USER_APC, ULONG,
+};
+
+use crate::{
+    kobject::Dispatchable,
+    ntstatus::NtError,
+    raw::AsRawObject,
+    time,
+    utils::{ex_allocate_pool_zero, ex_free_pool},
+};
+
+const QUEUE_TAG: u32 = u32::from_ne_bytes(*b"euqk");
+
+unsafe extern "C" {
+    pub fn KeInitializeQueue(Queue: PRKQUEUE, Count: ULONG);
+
+    pub fn KeInsertQueue(Queue: PRKQUEUE, Entry: PLIST_ENTRY) -> LONG;
+
+    pub fn KeInsertHeadQueue(Queue: PRKQUEUE, Entry: PLIST_ENTRY) -> LONG;
+
+    pub fn KeRemoveQueue(
+        Queue: PRKQUEUE,
+        WaitMode: KPROCESSOR_MODE,
+        Timeout: PLARGE_INTEGER,
+    ) -> PLIST_ENTRY;
+
+    pub fn KeRundownQueue(Queue: PRKQUEUE) -> PLIST_ENTRY;
+}
+
+/// the allocation of a `KernelQueue`, the KQUEUE comes first so the block is the PRKQUEUE
+#[repr(C)]
+struct QueueBlock {
+    queue: _KQUEUE,
+    /// the entry inserted by `shutdown`, a thread removing it puts it back for the next one
+    closed: LIST_ENTRY,
+    shutdown: AtomicBool,
+}
+
+/// the boxed item inserted into the KQUEUE, `link` must be the first field
+#[repr(C)]
+struct QueueItem<T> {
+    link: LIST_ENTRY,
+    value: T,
+}
+
+/// A kernel queue that distributes boxed items to worker threads
+///
+/// the KQUEUE is only run down on drop, so the workers blocked in `remove` are released by `shutdown` first,
+/// otherwise the workers holding a reference of the queue would wait forever
+///
+/// # Example
+/// ```
+/// let queue = Arc::new(KernelQueue::<Job>::new(0)?);
+/// let mut workers = Vec::new();
+///
+/// for _ in 0..available_parallelism().get() {
+///     let queue = queue.clone();
+///     workers.push(spawn(move || {
+///         // fails with STATUS_ABANDONED once the queue is shut down
+///         while let Ok(job) = queue.remove() {
+///             job.run();
+///         }
+///     })?);
+/// }
+///
+/// queue.insert(Job::new());
+///
+/// // on unload, the workers return and drop their references
+/// queue.shutdown();
+///
+/// for worker in workers {
+///     let _ = worker.join();
+/// }
+/// ```
+#[repr(transparent)]
+pub struct KernelQueue<T>(PRKQUEUE, PhantomData<T>);
+
+impl<T> KernelQueue<T> {
+    /// allocate a new queue object on the kernel heap
+    ///
+    /// # Parameters
+    /// - concurrency: the maximum number of threads that can run concurrently for this queue,
+    /// 0 means the number of processors
+    pub fn new(concurrency: u32) -> Result<Self, NtError> {
+        let layout =
+            ex_allocate_pool_zero(NonPagedPoolNx, mem::size_of::<QueueBlock>() as _, QUEUE_TAG)?;
+
+        unsafe { KeInitializeQueue(layout.cast(), concurrency) };
+
+        Ok(Self(layout.cast(), PhantomData))
+    }
+
+    fn into_node(value: T) -> PLIST_ENTRY {
+        let item = Box::new(QueueItem {
+            link: LIST_ENTRY {
+                Flink: ptr::null_mut(),
+                Blink: ptr::null_mut(),
+            },
+            value,
+        });
+
+        Box::into_raw(item).cast()
+    }
+
+    #[inline]
+    fn block(&self) -> *mut QueueBlock {
+        self.0.cast()
+    }
+
+    #[inline]
+    fn closed(&self) -> PLIST_ENTRY {
+        unsafe { &raw mut (*self.block()).closed }
+    }
+
+    fn from_node(&self, node: PLIST_ENTRY) -> Result<T, NtError> {
+        // KeRemoveQueue returns a status code instead of an entry if the wait is not satisfied
+        match node as usize {
+            0 => Err(NtError::new(STATUS_ABANDONED)),
+            x if x == STATUS_TIMEOUT as usize
+                || x == STATUS_USER_APC as usize
+                || x == STATUS_ABANDONED as usize =>
+            {
+                Err(NtError::new(x as NTSTATUS))
+            }
+            _ if node == self.closed() => {
+                // put it back at the head, so the next waiting thread is released as well
+                unsafe { KeInsertHeadQueue(self.0, node) };
+
+                Err(NtError::new(STATUS_ABANDONED))
+            }
+            _ => Ok(unsafe { Box::from_raw(node as *mut QueueItem<T>) }.value),
+        }
+    }
+
+    /// insert an item at the tail of the queue, one waiting thread will be released if any
+    pub fn insert(&self, value: T) {
+        unsafe { KeInsertQueue(self.0, Self::into_node(value)) };
+    }
+
+    /// insert an item at the head of the queue
+    pub fn insert_head(&self, value: T) {
+        unsafe { KeInsertHeadQueue(self.0, Self::into_node(value)) };
+    }
+
+    /// wait for an item, it fails with STATUS_ABANDONED once the queue is shut down
+    pub fn remove(&self) -> Result<T, NtError> {
+        self.from_node(unsafe { KeRemoveQueue(self.0, KernelMode as _, ptr::null_mut()) })
+    }
+
+    /// wait for an item for at most `timeout`, a `Duration::ZERO` polls the queue
+    ///
+    /// it fails with STATUS_TIMEOUT if no item arrives in time, or STATUS_ABANDONED once the queue is shut down
+    pub fn remove_timeout(&self, timeout: Duration) -> Result<T, NtError> {
+        let mut timeout = time::relative(timeout);
+
+        self.from_node(unsafe { KeRemoveQueue(self.0, KernelMode as _, &mut timeout) })
+    }
+
+    /// release all the threads waiting in `remove`, and make every later `remove` fail with STATUS_ABANDONED
+    ///
+    /// the items still in the queue, and the ones inserted after it, are never removed and are dropped with the queue
+    pub fn shutdown(&self) {
+        let block = self.block();
+
+        if !unsafe { (*block).shutdown.swap(true, Ordering::AcqRel) } {
+            unsafe { KeInsertQueue(self.0, self.closed()) };
+        }
+    }
+}
+
+impl<T> AsRawObject for KernelQueue<T> {
+    type Target = _KQUEUE;
+    fn as_raw(&self) -> *mut Self::Target {
+        self.0
+    }
+}
+
+impl<T> Dispatchable for KernelQueue<T> {}
+
+impl<T> Drop for KernelQueue<T> {
+    fn drop(&mut self) {
+        // run down the queue and drop all the remaining items
+        let head = unsafe { KeRundownQueue(self.0) };
+
+        if !head.is_null() {
+            let mut node = head;
+
+            loop {
+                let next = unsafe { (*node).Flink };
+
+                // the entry of `shutdown` lives in the block
+                if node != self.closed() {
+                    drop(unsafe { Box::from_raw(node as *mut QueueItem<T>) });
+                }
+
+                if next == head {
+                    break;
+                }
+
+                node = next;
+            }
+        }
+
+        unsafe {
+            ex_free_pool(self.0.cast(), QUEUE_TAG);
+        }
+    }
+}
+
+unsafe impl<T: Send> Send for KernelQueue<T> {}
+unsafe impl<T: Send> Sync for KernelQueue<T> {}
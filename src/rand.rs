@@ -0,0 +1,240 @@
+//! this module provides the random numbers of the crate
+//!
+//! - `fill` and `u64` return cryptographically secure random bytes, from `BCryptGenRandom` with the system preferred
+//!   RNG at PASSIVE_LEVEL, from the RDSEED/RDRAND instructions above it
+//! - `Xoshiro256` is a fast seedable PRNG which is not secure, e.g. for the backoff jitter or a sampling decision
+//!
+//! # Example
+//! ```
+//! let mut key = [0u8; 32];
+//! rand::fill(&mut key)?;
+//!
+//! let k0 = rand::u64()?;
+//! let k1 = rand::u64()?;
+//! let build = SipBuildHasher::new(k0, k1);
+//!
+//! let mut rng = Xoshiro256::from_entropy()?;
+//! let jitter = Duration::from_millis(rng.below(50));
+//! ```
+#[cfg(target_arch = "x86_64")]
+use core::sync::atomic::{AtomicU8, Ordering};
+
+use wdk_sys::{
+    NTSTATUS, PASSIVE_LEVEL, PVOID, STATUS_NOT_SUPPORTED, ULONG, ntddk::KeGetCurrentIrql,
+};
+
+use crate::ntstatus::{NtError, cvt};
+
+/// BCRYPT_USE_SYSTEM_PREFERRED_RNG
+const BCRYPT_USE_SYSTEM_PREFERRED_RNG: ULONG = 0x00000002;
+
+/// the attempts of RDSEED/RDRAND before giving up, they fail transiently when the entropy source is drained
+const HARDWARE_RETRIES: usize = 10;
+
+/// exported by ksecdd.sys, the kernel mode CNG
+#[link(name = "ksecdd")]
+unsafe extern "C" {
+    fn BCryptGenRandom(
+        hAlgorithm: PVOID,
+        pbBuffer: *mut u8,
+        cbBuffer: ULONG,
+        dwFlags: ULONG,
+    ) -> NTSTATUS;
+}
+
+/// fill `buffer` with secure random bytes, it can be called at IRQL <= DISPATCH_LEVEL
+///
+/// above PASSIVE_LEVEL it fails with STATUS_NOT_SUPPORTED if the processor has neither RDSEED nor RDRAND, or
+/// STATUS_RETRY if they keep failing
+pub fn fill(buffer: &mut [u8]) -> Result<(), NtError> {
+    if unsafe { KeGetCurrentIrql() } == PASSIVE_LEVEL as u8 {
+        for chunk in buffer.chunks_mut(ULONG::MAX as usize) {
+            cvt(unsafe {
+                BCryptGenRandom(
+                    core::ptr::null_mut(),
+                    chunk.as_mut_ptr(),
+                    chunk.len() as _,
+                    BCRYPT_USE_SYSTEM_PREFERRED_RNG,
+                )
+            })?;
+        }
+
+        return Ok(());
+    }
+
+    fill_hardware(buffer)
+}
+
+/// a secure random u64, see `fill`
+pub fn u64() -> Result<u64, NtError> {
+    let mut bytes = [0u8; 8];
+
+    fill(&mut bytes)?;
+
+    Ok(u64::from_ne_bytes(bytes))
+}
+
+/// fill `buffer` with RDSEED, or RDRAND if the processor has no RDSEED
+fn fill_hardware(buffer: &mut [u8]) -> Result<(), NtError> {
+    for chunk in buffer.chunks_mut(8) {
+        let word = hardware_u64()?;
+
+        chunk.copy_from_slice(&word.to_ne_bytes()[..chunk.len()]);
+    }
+
+    Ok(())
+}
+
+#[cfg(target_arch = "x86_64")]
+fn hardware_u64() -> Result<u64, NtError> {
+    use wdk_sys::STATUS_RETRY;
+
+    let features = hardware_features();
+
+    if features & (RDSEED | RDRAND) == 0 {
+        return Err(NtError::new(STATUS_NOT_SUPPORTED));
+    }
+
+    for _ in 0..HARDWARE_RETRIES {
+        let value = (features & RDSEED != 0)
+            .then(|| unsafe { rdseed() })
+            .flatten()
+            .or_else(|| {
+                (features & RDRAND != 0)
+                    .then(|| unsafe { rdrand() })
+                    .flatten()
+            });
+
+        if let Some(value) = value {
+            return Ok(value);
+        }
+
+        core::hint::spin_loop();
+    }
+
+    Err(NtError::new(STATUS_RETRY))
+}
+
+#[cfg(not(target_arch = "x86_64"))]
+fn hardware_u64() -> Result<u64, NtError> {
+    Err(NtError::new(STATUS_NOT_SUPPORTED))
+}
+
+#[cfg(target_arch = "x86_64")]
+const RDRAND: u8 = 1;
+#[cfg(target_arch = "x86_64")]
+const RDSEED: u8 = 2;
+/// set once the processor has been queried
+#[cfg(target_arch = "x86_64")]
+const QUERIED: u8 = 4;
+
+#[cfg(target_arch = "x86_64")]
+static FEATURES: AtomicU8 = AtomicU8::new(0);
+
+#[cfg(target_arch = "x86_64")]
+fn hardware_features() -> u8 {
+    use core::arch::x86_64::{__cpuid, __cpuid_count};
+
+    let features = FEATURES.load(Ordering::Relaxed);
+
+    if features & QUERIED != 0 {
+        return features;
+    }
+
+    let mut features = QUERIED;
+
+    // CPUID.01H:ECX.RDRAND[bit 30]
+    if __cpuid(1).ecx & (1 << 30) != 0 {
+        features |= RDRAND;
+    }
+
+    // CPUID.(EAX=07H,ECX=0):EBX.RDSEED[bit 18]
+    if __cpuid(0).eax >= 7 && __cpuid_count(7, 0).ebx & (1 << 18) != 0 {
+        features |= RDSEED;
+    }
+
+    FEATURES.store(features, Ordering::Relaxed);
+
+    features
+}
+
+#[cfg(target_arch = "x86_64")]
+#[target_feature(enable = "rdrand")]
+fn rdrand() -> Option<u64> {
+    let mut value = 0;
+
+    (core::arch::x86_64::_rdrand64_step(&mut value) == 1).then_some(value)
+}
+
+#[cfg(target_arch = "x86_64")]
+#[target_feature(enable = "rdseed")]
+fn rdseed() -> Option<u64> {
+    let mut value = 0;
+
+    (core::arch::x86_64::_rdseed64_step(&mut value) == 1).then_some(value)
+}
+
+/// xoshiro256**, a fast PRNG with a period of 2^256 - 1, it is not secure
+#[derive(Clone, Debug)]
+pub struct Xoshiro256 {
+    s: [u64; 4],
+}
+
+impl Xoshiro256 {
+    /// a generator whose state is expanded from `seed` by SplitMix64, the same seed gives the same sequence
+    pub const fn seed_from_u64(mut seed: u64) -> Self {
+        let mut s = [0u64; 4];
+        let mut i = 0;
+
+        while i < 4 {
+            seed = seed.wrapping_add(0x9e37_79b9_7f4a_7c15);
+
+            let mut z = seed;
+            z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+            z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+            s[i] = z ^ (z >> 31);
+
+            i += 1;
+        }
+
+        Self { s }
+    }
+
+    /// a generator seeded by `u64`
+    pub fn from_entropy() -> Result<Self, NtError> {
+        Ok(Self::seed_from_u64(u64()?))
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        let s = &mut self.s;
+        let result = s[1].wrapping_mul(5).rotate_left(7).wrapping_mul(9);
+        let t = s[1] << 17;
+
+        s[2] ^= s[0];
+        s[3] ^= s[1];
+        s[1] ^= s[2];
+        s[0] ^= s[3];
+        s[2] ^= t;
+        s[3] = s[3].rotate_left(45);
+
+        result
+    }
+
+    pub fn next_u32(&mut self) -> u32 {
+        (self.next_u64() >> 32) as u32
+    }
+
+    /// a number in `0..bound`, or 0 if `bound` is 0
+    pub fn below(&mut self, bound: u64) -> u64 {
+        // Lemire's multiply-shift, the bias is negligible for the non-crypto uses
+        ((self.next_u64() as u128 * bound as u128) >> 64) as u64
+    }
+
+    pub fn fill(&mut self, buffer: &mut [u8]) {
+        for chunk in buffer.chunks_mut(8) {
+            let word = self.next_u64().to_ne_bytes();
+
+            chunk.copy_from_slice(&word[..chunk.len()]);
+        }
+    }
+}
@@ -0,0 +1,183 @@
+//! this module provides a read-copy-update(RCU) style container for read-mostly data
+//!
+//! readers never block and never take a lock, they only register themselves in the current epoch.
+//! writers swap the pointer to a freshly built value and then wait for all the readers of the previous
+//! epoch to drain(the "grace period") before the old value is dropped
+//!
+//! it is a much cheaper alternative to `ResourceLocked<T>` for data that is read on every I/O but updated rarely
+//!
+//! # Example
+//! ```
+//! struct Config {
+//!     verbose: bool,
+//!     max_pending: u32,
+//! }
+//!
+//! static CONFIG: LazyLock<Rcu<Config>> = LazyLock::new(|| {
+//!     Rcu::new(Config {
+//!         verbose: false,
+//!         max_pending: 64,
+//!     })
+//! });
+//!
+//! // hot path, any IRQL
+//! fn dispatch() {
+//!     let config = CONFIG.read();
+//!     if config.verbose {
+//!         // ...
+//!     }
+//! } // reader leaves the epoch here
+//!
+//! // cold path, IRQL <= APC_LEVEL
+//! fn set_verbose(verbose: bool) {
+//!     let _ = CONFIG.update(|old| Config {
+//!         verbose,
+//!         max_pending: old.max_pending,
+//!     });
+//! }
+//! ```
+use core::{
+    ops::Deref,
+    sync::atomic::{AtomicBool, AtomicPtr, AtomicUsize, Ordering},
+    time::Duration,
+};
+
+use alloc::boxed::Box;
+use wdk_sys::{APC_LEVEL, STATUS_UNSUCCESSFUL, ntddk::KeGetCurrentIrql};
+
+use crate::{ntstatus::NtError, thread::this_thread, utils::try_box};
+
+/// A read-mostly value with lock-free readers and deferred reclamation
+///
+/// a reader retries its registration only when a writer flips the epoch at the same time, so it never waits for a
+/// writer but it is not wait-free
+///
+/// # Safety
+/// - `read()` can be called at any IRQL, the returned guard should be held as short as possible
+/// since it delays the reclamation of the old value
+/// - `update()` must be called at IRQL <= APC_LEVEL, since it waits for a grace period
+/// - NEVER call `update()` while holding a `RcuReadGuard` of the same `Rcu` in the same thread, it will never return
+pub struct Rcu<T> {
+    current: AtomicPtr<T>,
+    epoch: AtomicUsize,
+    readers: [AtomicUsize; 2],
+    updating: AtomicBool,
+}
+
+impl<T> Rcu<T> {
+    pub fn new(value: T) -> Self {
+        Self {
+            current: AtomicPtr::new(Box::into_raw(Box::new(value))),
+            epoch: AtomicUsize::new(0),
+            readers: [AtomicUsize::new(0), AtomicUsize::new(0)],
+            updating: AtomicBool::new(false),
+        }
+    }
+
+    /// enter a read-side critical section and returns a guard to the current value
+    pub fn read(&self) -> RcuReadGuard<'_, T> {
+        loop {
+            let epoch = self.epoch.load(Ordering::SeqCst);
+            let slot = epoch & 1;
+
+            self.readers[slot].fetch_add(1, Ordering::SeqCst);
+
+            // the writer may flip the epoch before we are registered, retry in the new epoch
+            // otherwise the writer can not see us and free the value under our feet
+            if self.epoch.load(Ordering::SeqCst) == epoch {
+                return RcuReadGuard {
+                    rcu: self,
+                    slot,
+                    value: self.current.load(Ordering::SeqCst),
+                };
+            }
+
+            self.readers[slot].fetch_sub(1, Ordering::Release);
+        }
+    }
+
+    /// build a new value from the current one and publish it
+    ///
+    /// the old value will be dropped after all the readers who may observe it have left
+    ///
+    /// it fails with STATUS_INSUFFICIENT_RESOURCES if the new value can not be allocated, the current one is kept
+    pub fn update<F: FnOnce(&T) -> T>(&self, f: F) -> Result<(), NtError> {
+        if unsafe { KeGetCurrentIrql() } > APC_LEVEL as u8 {
+            return Err(NtError::from(STATUS_UNSUCCESSFUL));
+        }
+
+        // writers are serialized, readers are not affected
+        while self
+            .updating
+            .compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
+            this_thread::pause();
+        }
+
+        let old = self.current.load(Ordering::Acquire);
+
+        let new = match try_box(f(unsafe { &*old })) {
+            Ok(new) => Box::into_raw(new),
+            Err(e) => {
+                self.updating.store(false, Ordering::Release);
+                return Err(e);
+            }
+        };
+
+        self.current.store(new, Ordering::SeqCst);
+
+        self.synchronize();
+
+        // no reader can reach `old` any more
+        let _ = unsafe { Box::from_raw(old) };
+
+        self.updating.store(false, Ordering::Release);
+
+        Ok(())
+    }
+
+    /// publish `value` without looking at the current one
+    pub fn replace(&self, value: T) -> Result<(), NtError> {
+        self.update(move |_| value)
+    }
+
+    /// wait for a grace period: flip the epoch and wait until readers of the previous epoch have drained
+    fn synchronize(&self) {
+        let slot = self.epoch.fetch_add(1, Ordering::SeqCst) & 1;
+
+        while self.readers[slot].load(Ordering::SeqCst) != 0 {
+            this_thread::sleep(Duration::from_millis(1));
+        }
+    }
+}
+
+impl<T> Drop for Rcu<T> {
+    fn drop(&mut self) {
+        // we have exclusive access here, no reader can be alive
+        let _ = unsafe { Box::from_raw(*self.current.get_mut()) };
+    }
+}
+
+unsafe impl<T: Send + Sync> Send for Rcu<T> {}
+unsafe impl<T: Send + Sync> Sync for Rcu<T> {}
+
+/// A read-side critical section of a `Rcu<T>`, the reader leaves the epoch when it is dropped
+pub struct RcuReadGuard<'a, T> {
+    rcu: &'a Rcu<T>,
+    slot: usize,
+    value: *const T,
+}
+
+impl<'a, T> Deref for RcuReadGuard<'a, T> {
+    type Target = T;
+    fn deref(&self) -> &Self::Target {
+        unsafe { &*self.value }
+    }
+}
+
+impl<'a, T> Drop for RcuReadGuard<'a, T> {
+    fn drop(&mut self) {
+        self.rcu.readers[self.slot].fetch_sub(1, Ordering::Release);
+    }
+}
@@ -0,0 +1,122 @@
+//! this module provides `ReentrancyGuard`, which detects a callback re-entered by the thread already running it
+//!
+//! a file system or registry callback which does I/O or touches the registry itself may be called again on the same
+//! thread, `enter` returns `None` in that case so the nested call can be passed through instead of recursing
+//!
+//! the threads inside are kept in a fixed table of `N` slots, so it neither allocates nor locks and can be used as
+//! a `static` at any IRQL
+//!
+//! # Example
+//! ```
+//! static GUARD: ReentrancyGuard = ReentrancyGuard::new();
+//!
+//! fn on_registry_notify(info: &RegNotifyInfo) -> NTSTATUS {
+//!     let Some(_entered) = GUARD.enter() else {
+//!         // triggered by our own registry access below
+//!         return STATUS_SUCCESS;
+//!     };
+//!
+//!     audit_to_registry(info);
+//!
+//!     STATUS_SUCCESS
+//! }
+//! ```
+use core::{
+    marker::PhantomData,
+    ptr,
+    sync::atomic::{AtomicPtr, AtomicU64, Ordering},
+};
+
+use wdk_sys::{_KTHREAD, PKTHREAD};
+
+use crate::utils::KeGetCurrentThread;
+
+/// the default number of threads which can be inside at the same time
+pub const DEFAULT_SLOTS: usize = 64;
+
+/// A per-thread recursion detector
+pub struct ReentrancyGuard<const N: usize = DEFAULT_SLOTS> {
+    slots: [AtomicPtr<_KTHREAD>; N],
+    overflows: AtomicU64,
+}
+
+impl<const N: usize> ReentrancyGuard<N> {
+    pub const fn new() -> Self {
+        Self {
+            slots: [const { AtomicPtr::new(ptr::null_mut()) }; N],
+            overflows: AtomicU64::new(0),
+        }
+    }
+
+    /// mark the current thread as inside, returns `None` if it is already inside
+    ///
+    /// the thread is inside until the returned guard is dropped, if all the slots are taken by other threads the
+    /// thread is let in without being recorded, see `overflows`
+    pub fn enter(&self) -> Option<EnterGuard<'_, N>> {
+        let current = KeGetCurrentThread();
+
+        if self.contains(current) {
+            return None;
+        }
+
+        // only the current thread stores itself, so no other slot can take `current` in the meantime
+        let slot = self.slots.iter().position(|slot| {
+            slot.compare_exchange(
+                ptr::null_mut(),
+                current,
+                Ordering::AcqRel,
+                Ordering::Relaxed,
+            )
+            .is_ok()
+        });
+
+        if slot.is_none() {
+            self.overflows.fetch_add(1, Ordering::Relaxed);
+        }
+
+        Some(EnterGuard {
+            owner: self,
+            slot,
+            _thread: PhantomData,
+        })
+    }
+
+    /// true if the current thread is inside
+    pub fn is_entered(&self) -> bool {
+        self.contains(KeGetCurrentThread())
+    }
+
+    /// the number of the threads let in untracked because the table was full
+    pub fn overflows(&self) -> u64 {
+        self.overflows.load(Ordering::Relaxed)
+    }
+
+    fn contains(&self, thread: PKTHREAD) -> bool {
+        self.slots
+            .iter()
+            .any(|slot| slot.load(Ordering::Acquire) == thread)
+    }
+}
+
+impl<const N: usize> Default for ReentrancyGuard<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The RAII guard of `ReentrancyGuard::enter`, the thread leaves when it is dropped
+///
+/// it is not `Send`, since it must be dropped on the thread which entered
+pub struct EnterGuard<'a, const N: usize> {
+    owner: &'a ReentrancyGuard<N>,
+    slot: Option<usize>,
+    _thread: PhantomData<*const ()>,
+}
+
+impl<const N: usize> Drop for EnterGuard<'_, N> {
+    fn drop(&mut self) {
+        if let Some(index) = self.slot {
+            self.owner.slots[index].store(ptr::null_mut(), Ordering::Release);
+        }
+    }
+}
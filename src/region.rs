@@ -0,0 +1,89 @@
+//! this module provides RAII helpers for critical regions and guarded regions
+//!
+//! - a critical region disables the delivery of normal kernel APCs, thus the thread can not be suspended,
+//! it is required when acquiring an ERESOURCE
+//! - a guarded region disables the delivery of all the APCs(include special kernel APCs)
+use core::marker::PhantomData;
+
+use wdk_sys::ntddk::{
+    KeAreAllApcsDisabled, KeAreApcsDisabled, KeEnterCriticalRegion, KeEnterGuardedRegion,
+    KeLeaveCriticalRegion, KeLeaveGuardedRegion,
+};
+
+/// An RAII guard of a critical region, the region is left when the guard is dropped
+///
+/// the guard is not `Send` since a critical region belongs to the current thread
+///
+/// # Example
+/// ```
+/// {
+///     let _region = CriticalRegionGuard::enter();
+///     // normal kernel APCs are disabled here
+/// }
+/// ```
+pub struct CriticalRegionGuard {
+    _not_send: PhantomData<*const ()>,
+}
+
+impl CriticalRegionGuard {
+    pub fn enter() -> Self {
+        unsafe { KeEnterCriticalRegion() };
+
+        Self {
+            _not_send: PhantomData,
+        }
+    }
+}
+
+impl Drop for CriticalRegionGuard {
+    fn drop(&mut self) {
+        unsafe { KeLeaveCriticalRegion() };
+    }
+}
+
+/// An RAII guard of a guarded region, the region is left when the guard is dropped
+pub struct GuardedRegionGuard {
+    _not_send: PhantomData<*const ()>,
+}
+
+impl GuardedRegionGuard {
+    pub fn enter() -> Self {
+        unsafe { KeEnterGuardedRegion() };
+
+        Self {
+            _not_send: PhantomData,
+        }
+    }
+}
+
+impl Drop for GuardedRegionGuard {
+    fn drop(&mut self) {
+        unsafe { KeLeaveGuardedRegion() };
+    }
+}
+
+/// run `f` inside a critical region
+pub fn critical_region<R, F: FnOnce() -> R>(f: F) -> R {
+    let _region = CriticalRegionGuard::enter();
+
+    f()
+}
+
+/// run `f` inside a guarded region
+pub fn guarded_region<R, F: FnOnce() -> R>(f: F) -> R {
+    let _region = GuardedRegionGuard::enter();
+
+    f()
+}
+
+/// returns true if the current thread is inside a critical region or a guarded region
+#[inline]
+pub fn apcs_disabled() -> bool {
+    unsafe { KeAreApcsDisabled() != 0 }
+}
+
+/// returns true if the current thread is inside a guarded region or IRQL >= APC_LEVEL
+#[inline]
+pub fn all_apcs_disabled() -> bool {
+    unsafe { KeAreAllApcsDisabled() != 0 }
+}
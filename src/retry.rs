@@ -0,0 +1,145 @@
+//! this module provides the retry helpers for the transient NTSTATUS failures
+//!
+//! `with_backoff` sleeps between the attempts, so it must be called at PASSIVE_LEVEL, `with_backoff_stall` stalls
+//! the processor instead and can be called at DISPATCH_LEVEL, its delays are capped at `MAX_STALL`
+//!
+//! # Example
+//! ```
+//! let policy = BackoffPolicy::new()
+//!     .max_attempts(5)
+//!     .initial_delay(Duration::from_millis(10))
+//!     .retry_on(&[STATUS_INSUFFICIENT_RESOURCES, STATUS_DEVICE_BUSY]);
+//!
+//! let handle = retry::with_backoff(&policy, || open_key(path))?;
+//! ```
+use core::time::Duration;
+
+use wdk_sys::{
+    NTSTATUS, STATUS_DEVICE_BUSY, STATUS_INSUFFICIENT_RESOURCES, ntddk::KeStallExecutionProcessor,
+};
+
+use crate::{ntstatus::NtError, thread::this_thread};
+
+/// the longest single delay of `with_backoff_stall`, a longer stall at DISPATCH_LEVEL hurts the whole system
+pub const MAX_STALL: Duration = Duration::from_micros(50);
+
+/// the statuses retried by the default policy
+pub const DEFAULT_RETRY_ON: &[NTSTATUS] = &[STATUS_INSUFFICIENT_RESOURCES, STATUS_DEVICE_BUSY];
+
+/// An exponential backoff policy
+///
+/// the delay before the attempt `n`(from 1) is `initial_delay * multiplier^(n - 1)`, capped at `max_delay`
+#[derive(Clone, Copy, Debug)]
+pub struct BackoffPolicy {
+    max_attempts: u32,
+    initial_delay: Duration,
+    max_delay: Duration,
+    multiplier: u32,
+    retry_on: &'static [NTSTATUS],
+}
+
+impl BackoffPolicy {
+    /// 3 attempts, 10ms doubled up to 1s, `DEFAULT_RETRY_ON` are retried
+    pub const fn new() -> Self {
+        Self {
+            max_attempts: 3,
+            initial_delay: Duration::from_millis(10),
+            max_delay: Duration::from_secs(1),
+            multiplier: 2,
+            retry_on: DEFAULT_RETRY_ON,
+        }
+    }
+
+    /// the total number of attempts including the first one, at least 1
+    pub const fn max_attempts(mut self, value: u32) -> Self {
+        self.max_attempts = if value == 0 { 1 } else { value };
+
+        self
+    }
+
+    pub const fn initial_delay(mut self, value: Duration) -> Self {
+        self.initial_delay = value;
+
+        self
+    }
+
+    pub const fn max_delay(mut self, value: Duration) -> Self {
+        self.max_delay = value;
+
+        self
+    }
+
+    /// 1 for a constant delay
+    pub const fn multiplier(mut self, value: u32) -> Self {
+        self.multiplier = value;
+
+        self
+    }
+
+    /// the statuses which are retried, the other errors are returned at once
+    pub const fn retry_on(mut self, statuses: &'static [NTSTATUS]) -> Self {
+        self.retry_on = statuses;
+
+        self
+    }
+
+    pub fn is_retryable(&self, error: NtError) -> bool {
+        self.retry_on.contains(&error.code())
+    }
+
+    /// the delay after the failed attempt `attempt`(from 1)
+    pub fn delay(&self, attempt: u32) -> Duration {
+        let factor = self.multiplier.saturating_pow(attempt.saturating_sub(1));
+
+        self.initial_delay
+            .checked_mul(factor)
+            .unwrap_or(self.max_delay)
+            .min(self.max_delay)
+    }
+}
+
+impl Default for BackoffPolicy {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn run<T, F, W>(policy: &BackoffPolicy, mut f: F, mut wait: W) -> Result<T, NtError>
+where
+    F: FnMut() -> Result<T, NtError>,
+    W: FnMut(Duration),
+{
+    let mut attempt = 1;
+
+    loop {
+        match f() {
+            Err(e) if attempt < policy.max_attempts && policy.is_retryable(e) => {
+                wait(policy.delay(attempt));
+                attempt += 1;
+            }
+            result => return result,
+        }
+    }
+}
+
+/// call `f` until it succeeds, fails with a status not in the policy or runs out of attempts
+///
+/// the last result is returned, it must be called at PASSIVE_LEVEL
+pub fn with_backoff<T, F>(policy: &BackoffPolicy, f: F) -> Result<T, NtError>
+where
+    F: FnMut() -> Result<T, NtError>,
+{
+    run(policy, f, this_thread::sleep)
+}
+
+/// the same as `with_backoff` but busy-waits between the attempts, it can be called at DISPATCH_LEVEL
+///
+/// every delay is capped at `MAX_STALL`
+pub fn with_backoff_stall<T, F>(policy: &BackoffPolicy, f: F) -> Result<T, NtError>
+where
+    F: FnMut() -> Result<T, NtError>,
+{
+    run(policy, f, |delay| unsafe {
+        KeStallExecutionProcessor(delay.min(MAX_STALL).as_micros() as _)
+    })
+}
@@ -0,0 +1,281 @@
+//! this module provides a lock-free single-producer single-consumer ring buffer
+//!
+//! the producer never allocates or waits, so it can push from a DPC, but not from an ISR, since it signals the
+//! consumer with `KeSetEvent`, the consumer can poll with `pop` or block with `pop_wait` at PASSIVE_LEVEL
+//!
+//! # Example
+//! ```
+//! let (producer, consumer) = RingBuffer::<Sample>::new(1024)?;
+//!
+//! // in a DPC
+//! if producer.push(sample).is_err() {
+//!     DROPPED.fetch_add(1, Ordering::Relaxed);
+//! }
+//!
+//! // in the logging thread
+//! loop {
+//!     let sample = consumer.pop_wait();
+//!     log(sample);
+//! }
+//! ```
+use core::{
+    cell::UnsafeCell,
+    mem::MaybeUninit,
+    sync::atomic::{AtomicBool, AtomicUsize, Ordering},
+    time::Duration,
+};
+
+use alloc::{boxed::Box, sync::Arc, vec::Vec};
+use wdk_sys::{STATUS_INSUFFICIENT_RESOURCES, STATUS_INVALID_PARAMETER};
+
+use crate::{
+    event::{Event, EventProperty},
+    kobject::Dispatchable,
+    ntstatus::NtError,
+};
+
+/// keep the producer index and the consumer index in different cache lines
+#[repr(align(64))]
+struct CachePadded<T>(T);
+
+struct Ring<T> {
+    buffer: Box<[UnsafeCell<MaybeUninit<T>>]>,
+    mask: usize,
+    /// the next slot to read, written by the consumer only
+    head: CachePadded<AtomicUsize>,
+    /// the next slot to write, written by the producer only
+    tail: CachePadded<AtomicUsize>,
+    /// true if the consumer is about to wait for `event`
+    waiting: AtomicBool,
+    event: Event,
+}
+
+impl<T> Ring<T> {
+    #[inline]
+    fn capacity(&self) -> usize {
+        self.mask + 1
+    }
+
+    #[inline]
+    fn slot(&self, index: usize) -> *mut MaybeUninit<T> {
+        self.buffer[index & self.mask].get()
+    }
+
+    fn len(&self) -> usize {
+        self.tail
+            .0
+            .load(Ordering::Acquire)
+            .wrapping_sub(self.head.0.load(Ordering::Acquire))
+    }
+
+    /// wake the consumer if it is waiting
+    fn notify(&self) {
+        if self.waiting.swap(false, Ordering::SeqCst) {
+            self.event.set();
+        }
+    }
+}
+
+impl<T> Drop for Ring<T> {
+    fn drop(&mut self) {
+        let mut head = *self.head.0.get_mut();
+        let tail = *self.tail.0.get_mut();
+
+        while head != tail {
+            unsafe { (*self.slot(head)).assume_init_drop() };
+            head = head.wrapping_add(1);
+        }
+    }
+}
+
+/// A bounded SPSC ring buffer, use `RingBuffer::new` to create a producer and consumer pair
+pub struct RingBuffer<T>(core::marker::PhantomData<T>);
+
+/// A ring buffer of bytes, the slices are copied in and out with `push_slice` and `pop_slice`
+pub type ByteRing = RingBuffer<u8>;
+
+impl<T: Send> RingBuffer<T> {
+    /// create a ring that holds at most `capacity` items, `capacity` is rounded up to a power of 2
+    pub fn new(capacity: usize) -> Result<(Producer<T>, Consumer<T>), NtError> {
+        if capacity == 0 {
+            return Err(NtError::new(STATUS_INVALID_PARAMETER));
+        }
+
+        let capacity = capacity
+            .checked_next_power_of_two()
+            .ok_or(NtError::new(STATUS_INVALID_PARAMETER))?;
+
+        let mut buffer = Vec::new();
+
+        buffer
+            .try_reserve_exact(capacity)
+            .map_err(|_| NtError::new(STATUS_INSUFFICIENT_RESOURCES))?;
+
+        buffer.resize_with(capacity, || UnsafeCell::new(MaybeUninit::uninit()));
+
+        let ring = Arc::new(Ring {
+            buffer: buffer.into_boxed_slice(),
+            mask: capacity - 1,
+            head: CachePadded(AtomicUsize::new(0)),
+            tail: CachePadded(AtomicUsize::new(0)),
+            waiting: AtomicBool::new(false),
+            event: EventProperty::new().auto_reset(true).new_event()?,
+        });
+
+        Ok((Producer(ring.clone()), Consumer(ring)))
+    }
+}
+
+/// The producer side of a ring buffer, it can be used at IRQL <= DISPATCH_LEVEL
+pub struct Producer<T>(Arc<Ring<T>>);
+
+impl<T> Producer<T> {
+    /// push an item, returns the item back if the ring is full
+    pub fn push(&self, value: T) -> Result<(), T> {
+        let ring = &self.0;
+        let tail = ring.tail.0.load(Ordering::Relaxed);
+
+        if tail.wrapping_sub(ring.head.0.load(Ordering::Acquire)) == ring.capacity() {
+            return Err(value);
+        }
+
+        unsafe { (*ring.slot(tail)).write(value) };
+
+        ring.tail.0.store(tail.wrapping_add(1), Ordering::SeqCst);
+
+        ring.notify();
+
+        Ok(())
+    }
+
+    /// push as many items of `values` as possible, returns the number of items pushed
+    pub fn push_slice(&self, values: &[T]) -> usize
+    where
+        T: Copy,
+    {
+        let ring = &self.0;
+        let tail = ring.tail.0.load(Ordering::Relaxed);
+        let free = ring.capacity() - tail.wrapping_sub(ring.head.0.load(Ordering::Acquire));
+        let count = free.min(values.len());
+
+        for (i, value) in values[..count].iter().enumerate() {
+            unsafe { (*ring.slot(tail.wrapping_add(i))).write(*value) };
+        }
+
+        if count > 0 {
+            ring.tail
+                .0
+                .store(tail.wrapping_add(count), Ordering::SeqCst);
+
+            ring.notify();
+        }
+
+        count
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.0.len() == self.0.capacity()
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.0.capacity()
+    }
+}
+
+/// The consumer side of a ring buffer
+pub struct Consumer<T>(Arc<Ring<T>>);
+
+impl<T> Consumer<T> {
+    /// pop an item without waiting
+    pub fn pop(&self) -> Option<T> {
+        let ring = &self.0;
+        let head = ring.head.0.load(Ordering::Relaxed);
+
+        if head == ring.tail.0.load(Ordering::Acquire) {
+            return None;
+        }
+
+        let value = unsafe { (*ring.slot(head)).assume_init_read() };
+
+        ring.head.0.store(head.wrapping_add(1), Ordering::Release);
+
+        Some(value)
+    }
+
+    /// pop as many items as `values` can hold, returns the number of items popped
+    pub fn pop_slice(&self, values: &mut [T]) -> usize
+    where
+        T: Copy,
+    {
+        let ring = &self.0;
+        let head = ring.head.0.load(Ordering::Relaxed);
+        let available = ring.tail.0.load(Ordering::Acquire).wrapping_sub(head);
+        let count = available.min(values.len());
+
+        for (i, value) in values[..count].iter_mut().enumerate() {
+            *value = unsafe { (*ring.slot(head.wrapping_add(i))).assume_init_read() };
+        }
+
+        ring.head
+            .0
+            .store(head.wrapping_add(count), Ordering::Release);
+
+        count
+    }
+
+    /// announce the wait, returns false if the ring becomes non-empty in the meantime
+    fn prepare_wait(&self) -> bool {
+        let ring = &self.0;
+
+        ring.waiting.store(true, Ordering::SeqCst);
+
+        if ring.head.0.load(Ordering::Relaxed) != ring.tail.0.load(Ordering::SeqCst) {
+            ring.waiting.store(false, Ordering::Relaxed);
+            return false;
+        }
+
+        true
+    }
+
+    /// pop an item, wait until one is available, it must be called at PASSIVE_LEVEL
+    pub fn pop_wait(&self) -> T {
+        loop {
+            if let Some(value) = self.pop() {
+                return value;
+            }
+
+            if self.prepare_wait() {
+                self.0.event.wait(false);
+            }
+        }
+    }
+
+    /// pop an item, wait at most `timeout` if the ring is empty
+    pub fn pop_wait_timeout(&self, timeout: Duration) -> Option<T> {
+        if let Some(value) = self.pop() {
+            return Some(value);
+        }
+
+        if self.prepare_wait() {
+            self.0.event.wait_for(timeout, false);
+            self.0.waiting.store(false, Ordering::Relaxed);
+        }
+
+        self.pop()
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.len() == 0
+    }
+}
+
+unsafe impl<T: Send> Send for Producer<T> {}
+unsafe impl<T: Send> Send for Consumer<T> {}
@@ -0,0 +1,301 @@
+//! this module provides `Scheduler`, a runner of the periodic and the timed jobs of a driver
+//!
+//! a single timer ticks at a fixed resolution and hands the due jobs over to the system worker threads, so a job
+//! runs at PASSIVE_LEVEL and never overlaps with itself, the jobs of the scheduler run in parallel up to
+//! `max_concurrent`
+//!
+//! - `Schedule::Every` runs a job on a monotonic interval, the changes of the system time do not affect it
+//! - `Schedule::At` runs a job once at a wall clock time, the due time is compared with the system time on every
+//!   tick, so the job still runs at the right time after the clock is changed
+//!
+//! a job which fails is retried after the delay of the backoff policy of the scheduler, the delay grows with the
+//! consecutive failures and is reset by a success
+//!
+//! # Example
+//! ```
+//! let scheduler = Scheduler::new(device, Duration::from_secs(1), 2)?;
+//!
+//! let flush = scheduler.schedule(Schedule::Every(Duration::from_secs(30)), || flush_logs())?;
+//!
+//! let midnight = KSystemTime::now() + until_midnight;
+//! scheduler.schedule(Schedule::At(midnight), || rotate_logs())?;
+//!
+//! // e.g. while the system is on battery
+//! scheduler.pause(flush);
+//! scheduler.resume(flush);
+//! ```
+use core::{
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+use alloc::{boxed::Box, vec::Vec};
+use wdk_sys::{PDEVICE_OBJECT, STATUS_INSUFFICIENT_RESOURCES, STATUS_INVALID_PARAMETER};
+
+use crate::{
+    arc::KArc,
+    mutex::StaticSpinLocked,
+    ntstatus::NtError,
+    retry::BackoffPolicy,
+    time::{KInstant, KSystemTime},
+    timer::Timer,
+    utils::try_box,
+    workitem::OrderedWorkQueue,
+};
+
+/// when a job runs
+#[derive(Clone, Copy, Debug)]
+pub enum Schedule {
+    /// every interval, the first run is one interval after the job is scheduled
+    Every(Duration),
+    /// once at a system time, the job runs at the next tick if the time has passed already
+    At(KSystemTime),
+}
+
+/// the identifier of a scheduled job
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Debug)]
+pub struct JobId(u64);
+
+type JobFn = Box<dyn Fn() -> Result<(), NtError> + Send + Sync>;
+
+#[derive(Clone, Copy)]
+enum Due {
+    Monotonic(KInstant),
+    Wall(KSystemTime),
+}
+
+impl Due {
+    fn is_due(&self, now: KInstant, wall: KSystemTime) -> bool {
+        match self {
+            Due::Monotonic(due) => *due <= now,
+            Due::Wall(due) => *due <= wall,
+        }
+    }
+}
+
+struct Job {
+    id: JobId,
+    schedule: Schedule,
+    f: KArc<JobFn>,
+    due: Due,
+    /// the consecutive failures
+    failures: u32,
+    paused: bool,
+    running: bool,
+}
+
+struct State {
+    jobs: Vec<Job>,
+    backoff: BackoffPolicy,
+}
+
+struct Inner {
+    state: StaticSpinLocked<State>,
+    queue: OrderedWorkQueue<JobId>,
+    next_id: AtomicU64,
+}
+
+impl Inner {
+    /// queue the due jobs, it runs in the DPC of the timer
+    fn tick(inner: &KArc<Self>) {
+        let now = KInstant::now();
+        let wall = KSystemTime::now();
+
+        let mut state = inner.state.lock();
+
+        for job in state.jobs.iter_mut() {
+            if job.paused || job.running || !job.due.is_due(now, wall) {
+                continue;
+            }
+
+            let queued = inner.clone();
+            let id = job.id;
+            let f = job.f.clone();
+
+            // the job is tried again on the next tick if it can not be queued
+            if inner
+                .queue
+                .push(id, move || queued.complete(id, f()))
+                .is_ok()
+            {
+                job.running = true;
+            }
+        }
+    }
+
+    /// set the next due time of a job after it has run
+    fn complete(&self, id: JobId, result: Result<(), NtError>) {
+        let mut state = self.state.lock();
+        let backoff = state.backoff;
+
+        let Some(index) = state.jobs.iter().position(|job| job.id == id) else {
+            // cancelled while it was running
+            return;
+        };
+
+        let job = &mut state.jobs[index];
+
+        job.running = false;
+
+        if result.is_err() {
+            job.failures = job.failures.saturating_add(1);
+            job.due = Due::Monotonic(KInstant::now() + backoff.delay(job.failures));
+
+            return;
+        }
+
+        job.failures = 0;
+
+        match job.schedule {
+            Schedule::Every(interval) => job.due = Due::Monotonic(KInstant::now() + interval),
+            Schedule::At(_) => {
+                state.jobs.swap_remove(index);
+            }
+        }
+    }
+}
+
+/// a scheduler of jobs, the pending jobs are dropped and the running ones are waited for when it is dropped
+pub struct Scheduler {
+    inner: KArc<Inner>,
+    timer: Timer,
+}
+
+impl Scheduler {
+    /// a scheduler which checks the jobs every `tick`, the jobs are run by the work items of `device`
+    ///
+    /// the failed jobs are retried after 1s doubled up to 5 minutes, see `set_backoff`, it fails with
+    /// STATUS_INVALID_PARAMETER if `tick` is shorter than 1ms, which the timer can not resolve, or `max_concurrent`
+    /// is zero
+    pub fn new(
+        device: PDEVICE_OBJECT,
+        tick: Duration,
+        max_concurrent: usize,
+    ) -> Result<Self, NtError> {
+        if tick < Duration::from_millis(1) {
+            return Err(NtError::new(STATUS_INVALID_PARAMETER));
+        }
+
+        let inner = KArc::new(Inner {
+            state: StaticSpinLocked::new(State {
+                jobs: Vec::new(),
+                backoff: BackoffPolicy::new()
+                    .initial_delay(Duration::from_secs(1))
+                    .max_delay(Duration::from_secs(300)),
+            }),
+            queue: OrderedWorkQueue::new(device, max_concurrent)?,
+            next_id: AtomicU64::new(1),
+        })?;
+
+        let timer = {
+            let inner = inner.clone();
+
+            Timer::try_new(move || Inner::tick(&inner), false)?
+        };
+
+        timer.start(tick, tick);
+
+        Ok(Self { inner, timer })
+    }
+
+    /// the backoff of the failed jobs, only its delays are used
+    pub fn set_backoff(&self, policy: BackoffPolicy) {
+        self.inner.state.lock().backoff = policy;
+    }
+
+    /// run `f` on `schedule`, an `Err` of `f` counts as a failure
+    ///
+    /// it can be called at IRQL <= DISPATCH_LEVEL, it fails with STATUS_INVALID_PARAMETER if the interval is zero
+    pub fn schedule<F>(&self, schedule: Schedule, f: F) -> Result<JobId, NtError>
+    where
+        F: Fn() -> Result<(), NtError> + Send + Sync + 'static,
+    {
+        let due = match schedule {
+            Schedule::Every(interval) if interval.is_zero() => {
+                return Err(NtError::new(STATUS_INVALID_PARAMETER));
+            }
+            Schedule::Every(interval) => Due::Monotonic(KInstant::now() + interval),
+            Schedule::At(time) => Due::Wall(time),
+        };
+
+        let f: JobFn = try_box(f)?;
+        let id = JobId(self.inner.next_id.fetch_add(1, Ordering::Relaxed));
+
+        let job = Job {
+            id,
+            schedule,
+            f: KArc::new(f)?,
+            due,
+            failures: 0,
+            paused: false,
+            running: false,
+        };
+
+        let mut state = self.inner.state.lock();
+
+        state
+            .jobs
+            .try_reserve(1)
+            .map_err(|_| NtError::new(STATUS_INSUFFICIENT_RESOURCES))?;
+        state.jobs.push(job);
+
+        Ok(id)
+    }
+
+    /// remove a job, a running job completes but is not run again, returns false if the job is gone already
+    pub fn cancel(&self, id: JobId) -> bool {
+        let job = {
+            let mut state = self.inner.state.lock();
+
+            let index = state.jobs.iter().position(|job| job.id == id);
+
+            index.map(|index| state.jobs.swap_remove(index))
+        };
+
+        // the closure is dropped out of the lock
+        job.is_some()
+    }
+
+    /// stop running a job until `resume`, a job due while it is paused runs once it is resumed
+    pub fn pause(&self, id: JobId) -> bool {
+        self.set_paused(id, true)
+    }
+
+    pub fn resume(&self, id: JobId) -> bool {
+        self.set_paused(id, false)
+    }
+
+    fn set_paused(&self, id: JobId, paused: bool) -> bool {
+        let mut state = self.inner.state.lock();
+
+        match state.jobs.iter_mut().find(|job| job.id == id) {
+            Some(job) => {
+                job.paused = paused;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// the number of the scheduled jobs
+    pub fn len(&self) -> usize {
+        self.inner.state.lock().jobs.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl Drop for Scheduler {
+    /// it must be dropped at PASSIVE_LEVEL
+    fn drop(&mut self) {
+        self.timer.stop_and_wait();
+
+        let jobs = core::mem::take(&mut self.inner.state.lock().jobs);
+
+        drop(jobs);
+
+        self.inner.queue.wait_idle();
+    }
+}
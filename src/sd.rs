@@ -0,0 +1,557 @@
+//! this module provides `SecurityDescriptor`, an absolute security descriptor with a DACL built in place
+//!
+//! it can be built from a subset of SDDL or with `SecurityDescriptorBuilder`, and applied to device objects and
+//! named objects so that only the intended principals(e.g. SYSTEM and the administrators) can open them
+//!
+//! the supported SDDL is a DACL only, `D:[P](ace)(ace)...`, where an ace is `type;flags;rights;;;sid`
+//! - type: `A`(allow) or `D`(deny)
+//! - flags: `OI`, `CI`, `NP`, `IO` or empty
+//! - rights: `GA`, `GR`, `GW`, `GX`, `FA`, `FR`, `FW`, `FX`, `RC`, `SD`, `WD`, `WO`, or a hex number `0x...`
+//! - sid: `SY`, `BA`, `BU`, `WD`, `AU`, `LS`, `NS`, `IU`, `RC`, `AC`, or a string sid `S-1-...`
+//!
+//! the generic rights are mapped by the object manager with the generic mapping of the object type
+//!
+//! # Example
+//! ```
+//! let sd = SecurityDescriptor::from_sddl("D:P(A;;GA;;;SY)(A;;GA;;;BA)")?;
+//!
+//! let device = Device::create_secure(driver, "MyDevice", FILE_DEVICE_UNKNOWN, control, &sd)?;
+//! let event = NamedEvent::create("\\BaseNamedObjects\\MyEvent", EventProperty::new(), Some(&sd))?;
+//!
+//! // the same as the SDDL above
+//! let sd = SecurityDescriptor::builder()
+//!     .allow(Sid::LOCAL_SYSTEM, GENERIC_ALL)
+//!     .allow(Sid::ADMINISTRATORS, GENERIC_ALL)
+//!     .protected(true)
+//!     .build()?;
+//! ```
+use core::{fmt, mem, ptr};
+
+use alloc::{boxed::Box, vec::Vec};
+use wdk_sys::{
+    _MODE::KernelMode,
+    ACCESS_MASK, DACL_SECURITY_INFORMATION, HANDLE, NTSTATUS, OBJ_KERNEL_HANDLE,
+    PSECURITY_DESCRIPTOR, PSID, PVOID, SECURITY_DESCRIPTOR, SECURITY_DESCRIPTOR_REVISION,
+    SECURITY_INFORMATION, STATUS_INSUFFICIENT_RESOURCES, STATUS_INVALID_PARAMETER,
+    STATUS_NOT_SUPPORTED, WRITE_DAC,
+    ntddk::{
+        ObOpenObjectByPointer, RtlCreateSecurityDescriptor, RtlSetDaclSecurityDescriptor,
+        RtlValidSecurityDescriptor, ZwClose,
+    },
+};
+
+use crate::{
+    ntstatus::{NtError, cvt},
+    utils,
+};
+
+unsafe extern "C" {
+    pub fn ZwSetSecurityObject(
+        Handle: HANDLE,
+        SecurityInformation: SECURITY_INFORMATION,
+        SecurityDescriptor: PSECURITY_DESCRIPTOR,
+    ) -> NTSTATUS;
+}
+
+const SID_MAX_SUB_AUTHORITIES: usize = 15;
+
+const ACL_REVISION: u8 = 2;
+
+const ACCESS_ALLOWED_ACE_TYPE: u8 = 0;
+const ACCESS_DENIED_ACE_TYPE: u8 = 1;
+
+const SE_DACL_PROTECTED: u16 = 0x1000;
+
+/// the ACE flags
+pub const OBJECT_INHERIT_ACE: u8 = 0x1;
+pub const CONTAINER_INHERIT_ACE: u8 = 0x2;
+pub const NO_PROPAGATE_INHERIT_ACE: u8 = 0x4;
+pub const INHERIT_ONLY_ACE: u8 = 0x8;
+
+/// A security identifier, at most 15 sub authorities
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct Sid {
+    authority: u64,
+    count: u8,
+    sub_authorities: [u32; SID_MAX_SUB_AUTHORITIES],
+}
+
+impl Sid {
+    /// S-1-5-18
+    pub const LOCAL_SYSTEM: Sid = Sid::new(5, &[18]);
+    /// S-1-5-32-544
+    pub const ADMINISTRATORS: Sid = Sid::new(5, &[32, 544]);
+    /// S-1-5-32-545
+    pub const USERS: Sid = Sid::new(5, &[32, 545]);
+    /// S-1-1-0
+    pub const EVERYONE: Sid = Sid::new(1, &[0]);
+    /// S-1-5-11
+    pub const AUTHENTICATED_USERS: Sid = Sid::new(5, &[11]);
+    /// S-1-5-19
+    pub const LOCAL_SERVICE: Sid = Sid::new(5, &[19]);
+    /// S-1-5-20
+    pub const NETWORK_SERVICE: Sid = Sid::new(5, &[20]);
+    /// S-1-5-4
+    pub const INTERACTIVE: Sid = Sid::new(5, &[4]);
+    /// S-1-5-12
+    pub const RESTRICTED: Sid = Sid::new(5, &[12]);
+    /// S-1-15-2-1
+    pub const ALL_APP_PACKAGES: Sid = Sid::new(15, &[2, 1]);
+
+    /// it fails to compile in a const context if there are more than 15 sub authorities
+    pub const fn new(authority: u64, sub_authorities: &[u32]) -> Self {
+        assert!(
+            sub_authorities.len() <= SID_MAX_SUB_AUTHORITIES,
+            "too many sub authorities"
+        );
+        assert!(authority < (1 << 48), "the identifier authority is 48 bits");
+
+        let mut sid = Self {
+            authority,
+            count: sub_authorities.len() as u8,
+            sub_authorities: [0; SID_MAX_SUB_AUTHORITIES],
+        };
+
+        let mut i = 0;
+
+        while i < sub_authorities.len() {
+            sid.sub_authorities[i] = sub_authorities[i];
+            i += 1;
+        }
+
+        sid
+    }
+
+    /// parse a SDDL sid alias like "SY" or a string sid like "S-1-5-18"
+    pub fn parse(s: &str) -> Result<Self, NtError> {
+        let sid = match s {
+            "SY" => Self::LOCAL_SYSTEM,
+            "BA" => Self::ADMINISTRATORS,
+            "BU" => Self::USERS,
+            "WD" => Self::EVERYONE,
+            "AU" => Self::AUTHENTICATED_USERS,
+            "LS" => Self::LOCAL_SERVICE,
+            "NS" => Self::NETWORK_SERVICE,
+            "IU" => Self::INTERACTIVE,
+            "RC" => Self::RESTRICTED,
+            "AC" => Self::ALL_APP_PACKAGES,
+            _ => {
+                let mut parts = s
+                    .strip_prefix("S-1-")
+                    .ok_or(NtError::new(STATUS_INVALID_PARAMETER))?
+                    .split('-');
+
+                let authority = parts
+                    .next()
+                    .and_then(|part| part.parse::<u64>().ok())
+                    .filter(|authority| *authority < (1 << 48))
+                    .ok_or(NtError::new(STATUS_INVALID_PARAMETER))?;
+
+                let mut sub_authorities = [0u32; SID_MAX_SUB_AUTHORITIES];
+                let mut count = 0;
+
+                for part in parts {
+                    if count == SID_MAX_SUB_AUTHORITIES {
+                        return Err(NtError::new(STATUS_INVALID_PARAMETER));
+                    }
+
+                    sub_authorities[count] = part
+                        .parse()
+                        .map_err(|_| NtError::new(STATUS_INVALID_PARAMETER))?;
+                    count += 1;
+                }
+
+                Self::new(authority, &sub_authorities[..count])
+            }
+        };
+
+        Ok(sid)
+    }
+
+    /// copy a binary SID, e.g. the `User.Sid` of a TOKEN_USER
+    ///
+    /// # Safety
+    /// `sid` must point to a valid SID
+    pub unsafe fn from_raw(sid: PSID) -> Result<Self, NtError> {
+        let header = unsafe { core::slice::from_raw_parts(sid.cast::<u8>(), 8) };
+
+        let count = header[1] as usize;
+
+        if header[0] != 1 || count > SID_MAX_SUB_AUTHORITIES {
+            return Err(NtError::new(STATUS_INVALID_PARAMETER));
+        }
+
+        let mut authority = [0u8; 8];
+        authority[2..].copy_from_slice(&header[2..8]);
+
+        let mut sub_authorities = [0u32; SID_MAX_SUB_AUTHORITIES];
+
+        for (i, sub) in sub_authorities[..count].iter_mut().enumerate() {
+            *sub = unsafe {
+                sid.cast::<u8>()
+                    .add(8 + i * 4)
+                    .cast::<u32>()
+                    .read_unaligned()
+            };
+        }
+
+        Ok(Self::new(
+            u64::from_be_bytes(authority),
+            &sub_authorities[..count],
+        ))
+    }
+
+    /// the size of the binary SID in bytes
+    pub fn size(&self) -> usize {
+        8 + 4 * self.count as usize
+    }
+
+    pub fn sub_authorities(&self) -> &[u32] {
+        &self.sub_authorities[..self.count as usize]
+    }
+
+    /// write the binary SID to `buffer`, which is at least `size()` bytes
+    fn write_to(&self, buffer: &mut [u8]) {
+        buffer[0] = 1;
+        buffer[1] = self.count;
+        buffer[2..8].copy_from_slice(&self.authority.to_be_bytes()[2..]);
+
+        for (i, sub) in self.sub_authorities().iter().enumerate() {
+            buffer[8 + i * 4..12 + i * 4].copy_from_slice(&sub.to_le_bytes());
+        }
+    }
+}
+
+impl fmt::Display for Sid {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "S-1-{}", self.authority)?;
+
+        for sub in self.sub_authorities() {
+            write!(f, "-{}", sub)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl fmt::Debug for Sid {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(self, f)
+    }
+}
+
+#[derive(Clone, Copy)]
+struct Ace {
+    kind: u8,
+    flags: u8,
+    mask: ACCESS_MASK,
+    sid: Sid,
+}
+
+impl Ace {
+    /// ACE_HEADER + Mask + the SID
+    fn len(&self) -> usize {
+        8 + self.sid.size()
+    }
+}
+
+/// A builder of `SecurityDescriptor`, the deny ACEs are placed before the allow ACEs in the DACL
+pub struct SecurityDescriptorBuilder {
+    aces: Vec<Ace>,
+    protected: bool,
+}
+
+impl SecurityDescriptorBuilder {
+    pub fn new() -> Self {
+        Self {
+            aces: Vec::new(),
+            protected: false,
+        }
+    }
+
+    pub fn allow(self, sid: Sid, mask: ACCESS_MASK) -> Self {
+        self.ace(ACCESS_ALLOWED_ACE_TYPE, 0, mask, sid)
+    }
+
+    pub fn deny(self, sid: Sid, mask: ACCESS_MASK) -> Self {
+        self.ace(ACCESS_DENIED_ACE_TYPE, 0, mask, sid)
+    }
+
+    /// an allow ACE with the inheritance flags, e.g. `OBJECT_INHERIT_ACE | CONTAINER_INHERIT_ACE`
+    pub fn allow_with_flags(self, sid: Sid, mask: ACCESS_MASK, flags: u8) -> Self {
+        self.ace(ACCESS_ALLOWED_ACE_TYPE, flags, mask, sid)
+    }
+
+    pub fn deny_with_flags(self, sid: Sid, mask: ACCESS_MASK, flags: u8) -> Self {
+        self.ace(ACCESS_DENIED_ACE_TYPE, flags, mask, sid)
+    }
+
+    fn ace(mut self, kind: u8, flags: u8, mask: ACCESS_MASK, sid: Sid) -> Self {
+        self.aces.push(Ace {
+            kind,
+            flags,
+            mask,
+            sid,
+        });
+
+        self
+    }
+
+    /// a protected DACL does not inherit the ACEs of the parent
+    pub fn protected(mut self, value: bool) -> Self {
+        self.protected = value;
+
+        self
+    }
+
+    pub fn build(self) -> Result<SecurityDescriptor, NtError> {
+        let size = mem::size_of::<u64>() + self.aces.iter().map(Ace::len).sum::<usize>();
+
+        if size > u16::MAX as usize {
+            return Err(NtError::new(STATUS_INVALID_PARAMETER));
+        }
+
+        // an ACL is 4 bytes aligned, keep it in u64s
+        let mut dacl: Vec<u64> = Vec::new();
+
+        dacl.try_reserve_exact(size.div_ceil(8))
+            .map_err(|_| NtError::new(STATUS_INSUFFICIENT_RESOURCES))?;
+        dacl.resize(size.div_ceil(8), 0);
+
+        let bytes =
+            unsafe { core::slice::from_raw_parts_mut(dacl.as_mut_ptr().cast::<u8>(), size) };
+
+        // ACL { AclRevision, Sbz1, AclSize, AceCount, Sbz2 }
+        bytes[0] = ACL_REVISION;
+        bytes[2..4].copy_from_slice(&(size as u16).to_le_bytes());
+        bytes[4..6].copy_from_slice(&(self.aces.len() as u16).to_le_bytes());
+
+        let denies = self
+            .aces
+            .iter()
+            .filter(|ace| ace.kind == ACCESS_DENIED_ACE_TYPE);
+        let allows = self
+            .aces
+            .iter()
+            .filter(|ace| ace.kind != ACCESS_DENIED_ACE_TYPE);
+
+        let mut offset = 8;
+
+        for ace in denies.chain(allows) {
+            let ace_bytes = &mut bytes[offset..offset + ace.len()];
+
+            // ACE_HEADER { AceType, AceFlags, AceSize }, Mask, SidStart
+            ace_bytes[0] = ace.kind;
+            ace_bytes[1] = ace.flags;
+            ace_bytes[2..4].copy_from_slice(&(ace.len() as u16).to_le_bytes());
+            ace_bytes[4..8].copy_from_slice(&ace.mask.to_le_bytes());
+            ace.sid.write_to(&mut ace_bytes[8..]);
+
+            offset += ace.len();
+        }
+
+        let mut descriptor = utils::try_box(unsafe { mem::zeroed::<SECURITY_DESCRIPTOR>() })?;
+
+        unsafe {
+            cvt(RtlCreateSecurityDescriptor(
+                descriptor.as_mut() as *mut _ as _,
+                SECURITY_DESCRIPTOR_REVISION,
+            ))?;
+
+            cvt(RtlSetDaclSecurityDescriptor(
+                descriptor.as_mut() as *mut _ as _,
+                1,
+                dacl.as_mut_ptr().cast(),
+                0,
+            ))?;
+        }
+
+        if self.protected {
+            descriptor.Control |= SE_DACL_PROTECTED;
+        }
+
+        Ok(SecurityDescriptor { descriptor, dacl })
+    }
+}
+
+impl Default for SecurityDescriptorBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// An absolute security descriptor with a DACL, the DACL is kept in a heap buffer owned by the descriptor
+pub struct SecurityDescriptor {
+    descriptor: Box<SECURITY_DESCRIPTOR>,
+    dacl: Vec<u64>,
+}
+
+impl SecurityDescriptor {
+    pub fn builder() -> SecurityDescriptorBuilder {
+        SecurityDescriptorBuilder::new()
+    }
+
+    /// build a descriptor from a SDDL DACL, see the module document for the supported subset
+    pub fn from_sddl(sddl: &str) -> Result<Self, NtError> {
+        let invalid = || NtError::new(STATUS_INVALID_PARAMETER);
+
+        // the owner, the group and the SACL are not supported
+        let mut rest = sddl
+            .trim()
+            .strip_prefix("D:")
+            .ok_or(NtError::new(STATUS_NOT_SUPPORTED))?;
+
+        let mut builder = Self::builder();
+
+        // the DACL flags before the first ACE
+        let flags_end = rest.find('(').unwrap_or(rest.len());
+
+        match &rest[..flags_end] {
+            "" => {}
+            "P" => builder = builder.protected(true),
+            _ => return Err(NtError::new(STATUS_NOT_SUPPORTED)),
+        }
+
+        rest = &rest[flags_end..];
+
+        while !rest.is_empty() {
+            let end = rest.find(')').ok_or_else(invalid)?;
+            let ace = rest[..end].strip_prefix('(').ok_or_else(invalid)?;
+
+            rest = &rest[end + 1..];
+
+            let fields: Vec<&str> = ace.split(';').collect();
+
+            let [kind, flags, rights, object, inherit_object, sid] = fields[..] else {
+                return Err(invalid());
+            };
+
+            // the object ACEs are not supported
+            if !object.is_empty() || !inherit_object.is_empty() {
+                return Err(NtError::new(STATUS_NOT_SUPPORTED));
+            }
+
+            let kind = match kind {
+                "A" => ACCESS_ALLOWED_ACE_TYPE,
+                "D" => ACCESS_DENIED_ACE_TYPE,
+                _ => return Err(NtError::new(STATUS_NOT_SUPPORTED)),
+            };
+
+            builder = builder.ace(
+                kind,
+                parse_ace_flags(flags)?,
+                parse_rights(rights)?,
+                Sid::parse(sid)?,
+            );
+        }
+
+        builder.build()
+    }
+
+    /// only SYSTEM and the administrators have full access, `D:P(A;;GA;;;SY)(A;;GA;;;BA)`
+    pub fn system_and_admins() -> Result<Self, NtError> {
+        Self::builder()
+            .allow(Sid::LOCAL_SYSTEM, wdk_sys::GENERIC_ALL)
+            .allow(Sid::ADMINISTRATORS, wdk_sys::GENERIC_ALL)
+            .protected(true)
+            .build()
+    }
+
+    /// the descriptor to be passed to the kernel APIs, e.g. `OBJECT_ATTRIBUTES::SecurityDescriptor`
+    pub fn as_ptr(&self) -> PSECURITY_DESCRIPTOR {
+        self.descriptor.as_ref() as *const _ as _
+    }
+
+    /// the size of the DACL in bytes
+    pub fn dacl_len(&self) -> usize {
+        self.dacl.len() * mem::size_of::<u64>()
+    }
+
+    pub fn is_valid(&self) -> bool {
+        unsafe { RtlValidSecurityDescriptor(self.as_ptr()) != 0 }
+    }
+
+    /// replace the DACL of a referenced object, e.g. a DEVICE_OBJECT or a KEVENT of a named event
+    ///
+    /// it must be called at PASSIVE_LEVEL
+    pub fn apply_to_object(&self, object: PVOID) -> Result<(), NtError> {
+        let mut handle: HANDLE = ptr::null_mut();
+
+        unsafe {
+            cvt(ObOpenObjectByPointer(
+                object,
+                OBJ_KERNEL_HANDLE,
+                ptr::null_mut(),
+                WRITE_DAC,
+                ptr::null_mut(),
+                KernelMode as _,
+                &mut handle,
+            ))?;
+
+            let status = ZwSetSecurityObject(handle, DACL_SECURITY_INFORMATION, self.as_ptr());
+
+            ZwClose(handle);
+
+            cvt(status)
+        }
+    }
+}
+
+fn parse_ace_flags(flags: &str) -> Result<u8, NtError> {
+    if flags.len() % 2 != 0 {
+        return Err(NtError::new(STATUS_INVALID_PARAMETER));
+    }
+
+    let mut value = 0;
+
+    // the pairs are sliced on the bytes, so a non-ASCII character is rejected rather than split
+    for pair in flags.as_bytes().chunks(2) {
+        value |= match pair {
+            b"OI" => OBJECT_INHERIT_ACE,
+            b"CI" => CONTAINER_INHERIT_ACE,
+            b"NP" => NO_PROPAGATE_INHERIT_ACE,
+            b"IO" => INHERIT_ONLY_ACE,
+            _ => return Err(NtError::new(STATUS_NOT_SUPPORTED)),
+        };
+    }
+
+    Ok(value)
+}
+
+fn parse_rights(rights: &str) -> Result<ACCESS_MASK, NtError> {
+    if let Some(hex) = rights.strip_prefix("0x").or(rights.strip_prefix("0X")) {
+        return ACCESS_MASK::from_str_radix(hex, 16)
+            .map_err(|_| NtError::new(STATUS_INVALID_PARAMETER));
+    }
+
+    if rights.len() % 2 != 0 {
+        return Err(NtError::new(STATUS_INVALID_PARAMETER));
+    }
+
+    let mut mask = 0;
+
+    for pair in rights.as_bytes().chunks(2) {
+        mask |= match pair {
+            b"GA" => wdk_sys::GENERIC_ALL,
+            b"GR" => wdk_sys::GENERIC_READ,
+            b"GW" => wdk_sys::GENERIC_WRITE,
+            b"GX" => wdk_sys::GENERIC_EXECUTE,
+            b"FA" => wdk_sys::FILE_ALL_ACCESS,
+            b"FR" => wdk_sys::FILE_GENERIC_READ,
+            b"FW" => wdk_sys::FILE_GENERIC_WRITE,
+            b"FX" => wdk_sys::FILE_GENERIC_EXECUTE,
+            b"RC" => wdk_sys::READ_CONTROL,
+            b"SD" => wdk_sys::DELETE,
+            b"WD" => wdk_sys::WRITE_DAC,
+            b"WO" => wdk_sys::WRITE_OWNER,
+            _ => return Err(NtError::new(STATUS_NOT_SUPPORTED)),
+        };
+    }
+
+    Ok(mask)
+}
+
+// Safety
+// the descriptor only points to the owned DACL, and it is not modified after it is built
+unsafe impl Send for SecurityDescriptor {}
+unsafe impl Sync for SecurityDescriptor {}
@@ -0,0 +1,106 @@
+//! this module provides `SecretBuffer`, a nonpaged buffer for key material which is wiped when it is dropped
+//!
+//! the buffer is never paged out, so the secret does not reach the page file, and it is zeroed with volatile writes
+//! the compiler can not elide before it goes back to the pool, so it does not linger in a crash dump
+//!
+//! the buffers of a whole subsystem can be wiped on free instead with `pool::set_zero_on_free`
+//!
+//! # Example
+//! ```
+//! let mut key = SecretBuffer::alloc(32)?;
+//!
+//! derive_key(password, key.as_mut_slice());
+//! encrypt(key.as_slice(), data);
+//!
+//! // the key is wiped here
+//! drop(key);
+//! ```
+use core::{
+    fmt, ptr, slice,
+    sync::atomic::{self, Ordering},
+};
+
+use wdk_sys::{_POOL_TYPE::NonPagedPoolNx, PVOID};
+
+use crate::{
+    ntstatus::NtError,
+    utils::{ex_allocate_pool_zero, ex_free_pool},
+};
+
+const SECRET_TAG: u32 = u32::from_ne_bytes(*b"tces");
+
+/// zero `buffer` like `RtlSecureZeroMemory`, the writes are not optimized away even if `buffer` is never read again
+pub fn secure_zero(buffer: &mut [u8]) {
+    for byte in buffer.iter_mut() {
+        unsafe { ptr::write_volatile(byte, 0) };
+    }
+
+    atomic::compiler_fence(Ordering::SeqCst);
+}
+
+/// A nonpaged buffer which is zeroed before it is freed
+pub struct SecretBuffer {
+    ptr: PVOID,
+    len: usize,
+}
+
+impl SecretBuffer {
+    /// a zeroed buffer of `len` bytes, it can be called at IRQL <= DISPATCH_LEVEL
+    ///
+    /// it fails with STATUS_INSUFFICIENT_RESOURCES if the buffer can not be allocated
+    pub fn alloc(len: usize) -> Result<Self, NtError> {
+        let ptr = ex_allocate_pool_zero(NonPagedPoolNx, len as _, SECRET_TAG)?;
+
+        Ok(Self { ptr, len })
+    }
+
+    /// a buffer with a copy of `data`, the caller is responsible for wiping `data`
+    pub fn from_slice(data: &[u8]) -> Result<Self, NtError> {
+        let mut buffer = Self::alloc(data.len())?;
+
+        buffer.as_mut_slice().copy_from_slice(data);
+
+        Ok(buffer)
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn as_slice(&self) -> &[u8] {
+        unsafe { slice::from_raw_parts(self.ptr.cast(), self.len) }
+    }
+
+    pub fn as_mut_slice(&mut self) -> &mut [u8] {
+        unsafe { slice::from_raw_parts_mut(self.ptr.cast(), self.len) }
+    }
+
+    /// zero the content now, the buffer stays allocated
+    pub fn wipe(&mut self) {
+        secure_zero(self.as_mut_slice());
+    }
+}
+
+impl Drop for SecretBuffer {
+    fn drop(&mut self) {
+        self.wipe();
+
+        unsafe { ex_free_pool(self.ptr, SECRET_TAG) };
+    }
+}
+
+/// the content is never printed
+impl fmt::Debug for SecretBuffer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SecretBuffer")
+            .field("len", &self.len)
+            .finish_non_exhaustive()
+    }
+}
+
+unsafe impl Send for SecretBuffer {}
+unsafe impl Sync for SecretBuffer {}
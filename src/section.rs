@@ -0,0 +1,282 @@
+//! this module provides `SharedSection<T, M>`, a named pagefile-backed section shared between a driver and
+//! user mode processes
+//!
+//! the section is mapped into system space on creation and can be mapped into user processes with `map_user`,
+//! the kernel side copies the typed content in and out under a lock of the chosen `Mutex`
+//!
+//! # Note
+//! - the content is pageable, so it can only be accessed at IRQL <= APC_LEVEL
+//! - the user side can not take the kernel lock, use atomics or a ring protocol in `T` for cross-mode synchronization
+//! - user mode can write the content at any time, so it is never borrowed: `SectionGuard::read` takes a copy which
+//!   is validated once and used, reading a field twice from the view would be a double fetch
+//!
+//! # Example
+//! ```
+//! #[repr(C)]
+//! #[derive(Clone, Copy)]
+//! struct Telemetry {
+//!     events: u64,
+//!     dropped: u64,
+//! }
+//!
+//! unsafe impl Pod for Telemetry {}
+//!
+//! let shared: SharedSection<Telemetry> = SharedSection::create("\\BaseNamedObjects\\MyTelemetry", None)?;
+//!
+//! if let Ok(mut guard) = shared.lock() {
+//!     let mut telemetry = guard.read();
+//!     telemetry.events += 1;
+//!     guard.write(&telemetry);
+//! }
+//!
+//! // in the context of the service process, e.g. in an IOCTL
+//! let view = shared.map_user(NtCurrentProcess)?;
+//! let address = view.as_ptr();
+//! ```
+use core::{marker::PhantomData, mem, ptr};
+
+use wdk_sys::{
+    _MODE::KernelMode,
+    _SECTION_INHERIT::ViewUnmap,
+    HANDLE, LARGE_INTEGER, OBJ_CASE_INSENSITIVE, OBJ_KERNEL_HANDLE, PAGE_READWRITE, POBJECT_TYPE,
+    PSECURITY_DESCRIPTOR, PVOID, SEC_COMMIT, SECTION_ALL_ACCESS, SECTION_MAP_READ,
+    SECTION_MAP_WRITE, SIZE_T, STATUS_INSUFFICIENT_RESOURCES, STATUS_INVALID_VIEW_SIZE,
+    ntddk::{
+        MmMapViewInSystemSpace, MmUnmapViewInSystemSpace, ObReferenceObjectByHandle,
+        ObfDereferenceObject, ZwClose, ZwCreateSection, ZwMapViewOfSection, ZwUnmapViewOfSection,
+    },
+};
+
+use crate::{
+    initialize_object_attributes,
+    mutex::{FastMutex, Locked, Mutex, MutexGuard},
+    ntstatus::{NtError, cvt},
+    pod::Pod,
+    utils,
+};
+
+unsafe extern "C" {
+    pub static MmSectionObjectType: *mut POBJECT_TYPE;
+}
+
+/// A named section holding a `T`, unmapped and closed on drop
+///
+/// `T` is `Pod`, since a new section is zero-filled and the user processes can write any bytes into it
+pub struct SharedSection<T: Pod, M: Mutex = FastMutex> {
+    handle: HANDLE,
+    section: PVOID,
+    base: *mut T,
+    lock: Locked<(), M>,
+}
+
+impl<T: Pod, M: Mutex> SharedSection<T, M> {
+    /// create the section `name`, for example "\BaseNamedObjects\MySection"
+    ///
+    /// # Parameters
+    /// - security_descriptor: the security descriptor of the section, `None` for the default one which
+    /// usually denies access from non-admin processes
+    ///
+    /// an existing section is never opened, it fails with STATUS_OBJECT_NAME_COLLISION if the name is taken, e.g. by
+    /// a user process squatting the name before the driver, which would control the security of the section
+    pub fn create(
+        name: &str,
+        security_descriptor: Option<PSECURITY_DESCRIPTOR>,
+    ) -> Result<Self, NtError> {
+        let lock = Locked::new(())?;
+
+        let mut name =
+            utils::utf16_from_str(name).ok_or(NtError::new(STATUS_INSUFFICIENT_RESOURCES))?;
+
+        let mut attr = initialize_object_attributes!(
+            name.as_mut(),
+            OBJ_CASE_INSENSITIVE | OBJ_KERNEL_HANDLE,
+            ptr::null_mut(),
+            security_descriptor.unwrap_or(ptr::null_mut())
+        );
+
+        let mut size = LARGE_INTEGER {
+            QuadPart: mem::size_of::<T>() as _,
+        };
+
+        let mut handle: HANDLE = ptr::null_mut();
+
+        cvt(unsafe {
+            ZwCreateSection(
+                &mut handle,
+                SECTION_ALL_ACCESS,
+                &mut attr,
+                &mut size,
+                PAGE_READWRITE,
+                SEC_COMMIT,
+                ptr::null_mut(),
+            )
+        })?;
+
+        let mut section: PVOID = ptr::null_mut();
+
+        if let Err(e) = cvt(unsafe {
+            ObReferenceObjectByHandle(
+                handle,
+                SECTION_MAP_READ | SECTION_MAP_WRITE,
+                *MmSectionObjectType,
+                KernelMode as _,
+                &mut section,
+                ptr::null_mut(),
+            )
+        }) {
+            unsafe { ZwClose(handle) };
+            return Err(e);
+        }
+
+        let mut base: PVOID = ptr::null_mut();
+        let mut view_size: SIZE_T = mem::size_of::<T>() as _;
+
+        if let Err(e) = cvt(unsafe { MmMapViewInSystemSpace(section, &mut base, &mut view_size) }) {
+            unsafe {
+                ObfDereferenceObject(section);
+                ZwClose(handle);
+            }
+            return Err(e);
+        }
+
+        if (view_size as usize) < mem::size_of::<T>() {
+            unsafe {
+                let _ = MmUnmapViewInSystemSpace(base);
+                ObfDereferenceObject(section);
+                ZwClose(handle);
+            }
+            return Err(NtError::new(STATUS_INVALID_VIEW_SIZE));
+        }
+
+        Ok(Self {
+            handle,
+            section,
+            base: base.cast(),
+            lock,
+        })
+    }
+
+    /// lock the content for exclusive access from kernel mode
+    pub fn lock(&self) -> Result<SectionGuard<'_, T, M>, NtError> {
+        let guard = self.lock.lock()?;
+
+        Ok(SectionGuard {
+            _guard: guard,
+            data: self.base,
+            _phantom: PhantomData,
+        })
+    }
+
+    /// the raw system space address of the content, the accesses are not synchronized
+    pub fn as_ptr(&self) -> *mut T {
+        self.base
+    }
+
+    pub fn handle(&self) -> HANDLE {
+        self.handle
+    }
+
+    /// map the section into the user address space of `process`
+    ///
+    /// `process` must be a kernel handle or a pseudo handle like `NtCurrentProcess`, it must be valid until the
+    /// returned view is dropped
+    pub fn map_user(&self, process: HANDLE) -> Result<UserView, NtError> {
+        let mut base: PVOID = ptr::null_mut();
+        let mut view_size: SIZE_T = 0;
+
+        cvt(unsafe {
+            ZwMapViewOfSection(
+                self.handle,
+                process,
+                &mut base,
+                0,
+                0,
+                ptr::null_mut(),
+                &mut view_size,
+                ViewUnmap,
+                0,
+                PAGE_READWRITE,
+            )
+        })?;
+
+        Ok(UserView {
+            process,
+            base,
+            size: view_size as _,
+        })
+    }
+}
+
+impl<T: Pod, M: Mutex> Drop for SharedSection<T, M> {
+    fn drop(&mut self) {
+        unsafe {
+            let _ = MmUnmapViewInSystemSpace(self.base.cast());
+
+            ObfDereferenceObject(self.section);
+
+            let _ = ZwClose(self.handle);
+        }
+    }
+}
+
+unsafe impl<T: Pod + Send, M: Mutex> Send for SharedSection<T, M> {}
+unsafe impl<T: Pod + Send, M: Mutex> Sync for SharedSection<T, M> {}
+
+/// An RAII guard of the content of a `SharedSection`
+///
+/// the content is copied in and out with volatile accesses instead of being borrowed, since user mode may write it
+/// concurrently
+pub struct SectionGuard<'a, T, M: Mutex> {
+    _guard: MutexGuard<'a, true, (), M>,
+    data: *mut T,
+    _phantom: PhantomData<&'a mut T>,
+}
+
+impl<'a, T: Pod, M: Mutex> SectionGuard<'a, T, M> {
+    /// take a copy of the content, any bit pattern written by user mode is a valid `T`
+    pub fn read(&self) -> T {
+        unsafe { ptr::read_volatile(self.data) }
+    }
+
+    /// overwrite the content with `value`
+    pub fn write(&mut self, value: &T) {
+        unsafe { ptr::write_volatile(self.data, *value) };
+    }
+}
+
+/// A view of a section in a user process, unmapped on drop
+pub struct UserView {
+    process: HANDLE,
+    base: PVOID,
+    size: usize,
+}
+
+impl UserView {
+    /// the user mode address of the view
+    pub fn as_ptr(&self) -> PVOID {
+        self.base
+    }
+
+    pub fn len(&self) -> usize {
+        self.size
+    }
+
+    /// keep the view mapped after drop, it is unmapped when the process exits
+    pub fn leak(self) -> PVOID {
+        let base = self.base;
+
+        mem::forget(self);
+
+        base
+    }
+}
+
+impl Drop for UserView {
+    fn drop(&mut self) {
+        unsafe {
+            let _ = ZwUnmapViewOfSection(self.process, self.base);
+        }
+    }
+}
+
+unsafe impl Send for UserView {}
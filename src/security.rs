@@ -0,0 +1,368 @@
+//! this module provides `Token`, a referenced access token with the common authorization queries
+//!
+//! it is mostly used to authorize the caller of an IOCTL, the dispatch routines run in the context of the
+//! requesting thread, so `current_token()` is the token of the caller
+//!
+//! # Example
+//! ```
+//! fn dispatch(&self, device: PDEVICE_OBJECT, irp: PIRP) -> Result<u64, NtError> {
+//!     let token = security::current_token()?;
+//!
+//!     if !token.is_admin() && !token.has_privilege(security::SE_DEBUG) {
+//!         return Err(NtError::new(STATUS_ACCESS_DENIED));
+//!     }
+//!
+//!     trace_info!("request from {}", token.sid_string()?);
+//! }
+//! ```
+//!
+//! `RequestorContext` captures who sent an IRP for audit logging, it is displayed as `key=value` fields
+//!
+//! # Example
+//! ```
+//! let requestor = RequestorContext::capture_with_token(irp);
+//!
+//! // "ioctl 0x222000 pid=1234 tid=5678 mode=user major=14 sid=S-1-5-21-..."
+//! trace_info!("ioctl {:#x} {}", code, requestor);
+//! ```
+use core::{fmt, ptr};
+
+use alloc::string::{String, ToString};
+use wdk_sys::{
+    _MODE::UserMode,
+    BOOLEAN, KPROCESSOR_MODE, NTSTATUS, PACCESS_TOKEN, PEPROCESS, PETHREAD, PIRP, PSID, PULONG,
+    PVOID, STATUS_BAD_IMPERSONATION_LEVEL, STATUS_NO_TOKEN,
+    ntddk::{
+        ExFreePoolWithTag, IoGetCurrentProcess, ObfDereferenceObject, PsGetCurrentProcessId,
+        PsGetCurrentThreadId,
+    },
+};
+
+use crate::{
+    handle_to_ulong,
+    irp::Irp,
+    ntstatus::{NtError, cvt},
+    process::Process,
+    raw::AsRawObject,
+    sd::Sid,
+    utils::KeGetCurrentThread,
+};
+
+unsafe extern "C" {
+    pub fn PsReferencePrimaryToken(Process: PEPROCESS) -> PACCESS_TOKEN;
+
+    pub fn PsReferenceImpersonationToken(
+        Thread: PETHREAD,
+        CopyOnOpen: *mut BOOLEAN,
+        EffectiveOnly: *mut BOOLEAN,
+        ImpersonationLevel: *mut u32,
+    ) -> PACCESS_TOKEN;
+
+    pub fn SeQueryInformationToken(
+        Token: PACCESS_TOKEN,
+        TokenInformationClass: u32,
+        TokenInformation: *mut PVOID,
+    ) -> NTSTATUS;
+
+    pub fn SeQuerySessionIdToken(Token: PACCESS_TOKEN, SessionId: PULONG) -> NTSTATUS;
+
+    pub fn SeTokenIsAdmin(Token: PACCESS_TOKEN) -> BOOLEAN;
+
+    pub fn SeTokenIsRestricted(Token: PACCESS_TOKEN) -> BOOLEAN;
+}
+
+/// TOKEN_INFORMATION_CLASS
+const TOKEN_USER: u32 = 1;
+const TOKEN_PRIVILEGES: u32 = 3;
+
+const SE_PRIVILEGE_ENABLED: u32 = 0x2;
+
+/// SECURITY_IMPERSONATION_LEVEL::SecurityImpersonation, a token below it only identifies its user
+const SECURITY_IMPERSONATION: u32 = 2;
+
+/// A privilege, the value is the LowPart of its LUID
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Privilege(pub u32);
+
+pub const SE_CREATE_TOKEN: Privilege = Privilege(2);
+pub const SE_ASSIGNPRIMARYTOKEN: Privilege = Privilege(3);
+pub const SE_LOCK_MEMORY: Privilege = Privilege(4);
+pub const SE_INCREASE_QUOTA: Privilege = Privilege(5);
+pub const SE_TCB: Privilege = Privilege(7);
+pub const SE_SECURITY: Privilege = Privilege(8);
+pub const SE_TAKE_OWNERSHIP: Privilege = Privilege(9);
+pub const SE_LOAD_DRIVER: Privilege = Privilege(10);
+pub const SE_BACKUP: Privilege = Privilege(17);
+pub const SE_RESTORE: Privilege = Privilege(18);
+pub const SE_SHUTDOWN: Privilege = Privilege(19);
+pub const SE_DEBUG: Privilege = Privilege(20);
+pub const SE_IMPERSONATE: Privilege = Privilege(29);
+
+/// LUID_AND_ATTRIBUTES
+#[repr(C)]
+struct LuidAndAttributes {
+    low_part: u32,
+    high_part: i32,
+    attributes: u32,
+}
+
+/// A referenced access token, the reference is released on drop
+pub struct Token {
+    token: PACCESS_TOKEN,
+    impersonation: bool,
+}
+
+/// the effective token of the current thread, the impersonation token if the thread is impersonating, otherwise
+/// the primary token of the current process
+///
+/// it fails with STATUS_BAD_IMPERSONATION_LEVEL if the thread impersonates at the anonymous or identification level,
+/// such a token must not be used to authorize the caller
+pub fn current_token() -> Result<Token, NtError> {
+    let mut copy_on_open: BOOLEAN = 0;
+    let mut effective_only: BOOLEAN = 0;
+    let mut level: u32 = 0;
+
+    let token = unsafe {
+        PsReferenceImpersonationToken(
+            KeGetCurrentThread().cast(),
+            &mut copy_on_open,
+            &mut effective_only,
+            &mut level,
+        )
+    };
+
+    if !token.is_null() {
+        if level < SECURITY_IMPERSONATION {
+            unsafe { ObfDereferenceObject(token) };
+
+            return Err(NtError::new(STATUS_BAD_IMPERSONATION_LEVEL));
+        }
+
+        return Ok(Token {
+            token,
+            impersonation: true,
+        });
+    }
+
+    unsafe { Token::from_process(IoGetCurrentProcess()) }
+}
+
+/// the primary token of `process`
+pub fn primary_token(process: &Process) -> Result<Token, NtError> {
+    unsafe { Token::from_process(process.as_raw().cast()) }
+}
+
+impl Token {
+    /// reference the primary token of a process
+    ///
+    /// # Safety
+    /// `process` must be a valid EPROCESS
+    pub unsafe fn from_process(process: PEPROCESS) -> Result<Self, NtError> {
+        let token = unsafe { PsReferencePrimaryToken(process) };
+
+        if token.is_null() {
+            return Err(NtError::new(STATUS_NO_TOKEN));
+        }
+
+        Ok(Self {
+            token,
+            impersonation: false,
+        })
+    }
+
+    pub fn as_raw(&self) -> PACCESS_TOKEN {
+        self.token
+    }
+
+    /// true if it is the impersonation token of a thread
+    pub fn is_impersonation(&self) -> bool {
+        self.impersonation
+    }
+
+    /// query a token information class, the buffer is allocated by the kernel and freed after `f` returns
+    fn query<R>(&self, class: u32, f: impl FnOnce(PVOID) -> R) -> Result<R, NtError> {
+        let mut buffer: PVOID = ptr::null_mut();
+
+        cvt(unsafe { SeQueryInformationToken(self.token, class, &mut buffer) })?;
+
+        let result = f(buffer);
+
+        unsafe { ExFreePoolWithTag(buffer, 0) };
+
+        Ok(result)
+    }
+
+    /// the SID of the user of the token
+    pub fn user(&self) -> Result<Sid, NtError> {
+        // TOKEN_USER { SID_AND_ATTRIBUTES { Sid, Attributes } }
+        self.query(TOKEN_USER, |buffer| unsafe {
+            Sid::from_raw(*buffer.cast::<PSID>())
+        })?
+    }
+
+    /// the user SID as a string, e.g. "S-1-5-18"
+    pub fn sid_string(&self) -> Result<String, NtError> {
+        self.user().map(|sid| sid.to_string())
+    }
+
+    /// true if the user is LocalSystem
+    pub fn is_system(&self) -> bool {
+        self.user().is_ok_and(|sid| sid == Sid::LOCAL_SYSTEM)
+    }
+
+    /// true if the administrators group is enabled in the token, which means the caller is elevated
+    pub fn is_admin(&self) -> bool {
+        unsafe { SeTokenIsAdmin(self.token) != 0 }
+    }
+
+    pub fn is_restricted(&self) -> bool {
+        unsafe { SeTokenIsRestricted(self.token) != 0 }
+    }
+
+    /// true if `privilege` is present and enabled in the token
+    pub fn has_privilege(&self, privilege: Privilege) -> bool {
+        self.query(TOKEN_PRIVILEGES, |buffer| unsafe {
+            // TOKEN_PRIVILEGES { PrivilegeCount, Privileges[] }
+            let count = *buffer.cast::<u32>() as usize;
+            let privileges = core::slice::from_raw_parts(
+                buffer.cast::<u8>().add(4).cast::<LuidAndAttributes>(),
+                count,
+            );
+
+            privileges.iter().any(|p| {
+                p.low_part == privilege.0
+                    && p.high_part == 0
+                    && p.attributes & SE_PRIVILEGE_ENABLED != 0
+            })
+        })
+        .unwrap_or(false)
+    }
+
+    /// the terminal services session of the token
+    pub fn session_id(&self) -> Result<u32, NtError> {
+        let mut session = 0;
+
+        cvt(unsafe { SeQuerySessionIdToken(self.token, &mut session) })?;
+
+        Ok(session)
+    }
+}
+
+impl Drop for Token {
+    fn drop(&mut self) {
+        // PsDereferencePrimaryToken and PsDereferenceImpersonationToken are ObDereferenceObject
+        unsafe { ObfDereferenceObject(self.token) };
+    }
+}
+
+unsafe impl Send for Token {}
+unsafe impl Sync for Token {}
+
+/// The requestor of an IRP, captured in the dispatch routine
+///
+/// the ids are those of the current thread, so it must be captured by the top level driver before the IRP is
+/// queued or passed to another thread
+pub struct RequestorContext {
+    pid: u32,
+    tid: u32,
+    mode: KPROCESSOR_MODE,
+    major_function: u32,
+    token: Option<Token>,
+    sid: Option<Sid>,
+}
+
+impl RequestorContext {
+    /// capture the requestor without its token
+    pub fn capture(irp: PIRP) -> Self {
+        let irp = unsafe { Irp::from_raw(irp) };
+
+        unsafe {
+            Self {
+                pid: handle_to_ulong!(PsGetCurrentProcessId()),
+                tid: handle_to_ulong!(PsGetCurrentThreadId()),
+                mode: irp.requestor_mode(),
+                major_function: irp.major_function(),
+                token: None,
+                sid: None,
+            }
+        }
+    }
+
+    /// capture the requestor with a reference to its effective token, the token is `None` if it can not be
+    /// referenced
+    ///
+    /// the user SID is queried here, so the context can be displayed at any IRQL later
+    pub fn capture_with_token(irp: PIRP) -> Self {
+        let token = current_token().ok();
+
+        Self {
+            sid: token.as_ref().and_then(|token| token.user().ok()),
+            token,
+            ..Self::capture(irp)
+        }
+    }
+
+    pub fn pid(&self) -> u32 {
+        self.pid
+    }
+
+    pub fn tid(&self) -> u32 {
+        self.tid
+    }
+
+    pub fn requestor_mode(&self) -> KPROCESSOR_MODE {
+        self.mode
+    }
+
+    pub fn is_user_mode(&self) -> bool {
+        self.mode == UserMode as KPROCESSOR_MODE
+    }
+
+    pub fn major_function(&self) -> u32 {
+        self.major_function
+    }
+
+    pub fn token(&self) -> Option<&Token> {
+        self.token.as_ref()
+    }
+
+    /// the user SID of the token
+    pub fn sid(&self) -> Option<&Sid> {
+        self.sid.as_ref()
+    }
+}
+
+impl fmt::Display for RequestorContext {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "pid={} tid={} mode={} major={}",
+            self.pid,
+            self.tid,
+            if self.is_user_mode() {
+                "user"
+            } else {
+                "kernel"
+            },
+            self.major_function
+        )?;
+
+        if let Some(sid) = &self.sid {
+            write!(f, " sid={}", sid)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl fmt::Debug for RequestorContext {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RequestorContext")
+            .field("pid", &self.pid)
+            .field("tid", &self.tid)
+            .field("mode", &self.mode)
+            .field("major_function", &self.major_function)
+            .field("sid", &self.sid)
+            .finish()
+    }
+}
@@ -0,0 +1,522 @@
+//! this module provides a self-test harness of the primitives, it is enabled by the `selftest` feature
+//!
+//! `cargo test` can not run kernel code, so the tests are built into the driver and run on the target machine,
+//! usually from an IOCTL of a test driver with Driver Verifier enabled
+//!
+//! # Example
+//! ```
+//! fn on_selftest(irp: &mut Irp) -> NTSTATUS {
+//!     let report = match selftest::run_all() {
+//!         Ok(report) => report,
+//!         Err(e) => return e.code(),
+//!     };
+//!
+//!     trace_info!("{}", report);
+//!
+//!     let written = report.copy_to(irp.system_buffer_bytes().unwrap_or_default());
+//!
+//!     irp.complete(STATUS_SUCCESS, written as _);
+//!     STATUS_SUCCESS
+//! }
+//! ```
+use core::{
+    fmt::{self, Display},
+    mem,
+    sync::atomic::{AtomicU32, Ordering},
+    time::Duration,
+};
+
+use alloc::vec::Vec;
+use wdk_sys::{
+    NTSTATUS, STATUS_ASSERTION_FAILURE, STATUS_INSUFFICIENT_RESOURCES, STATUS_SUCCESS,
+    STATUS_TIMEOUT, STATUS_UNHANDLED_EXCEPTION,
+};
+
+use crate::{
+    arc::KArc,
+    event::{Event, EventProperty},
+    fmt::StackString,
+    kobject::Dispatchable,
+    lazy::OnceLock,
+    mpsc,
+    mutex::{FastLocked, GuardLocked, ResourceLocked, SpinLocked, StaticSpinLocked},
+    ntstatus::NtError,
+    thread::{self, JoinError, JoinHandle, this_thread},
+    time::KInstant,
+    timer::Timer,
+    waitgroup::WaitGroup,
+};
+
+/// the length of the failure message of a test
+pub const MESSAGE_LEN: usize = 96;
+
+/// the length of the test name in a `RawTestResult`
+pub const RAW_NAME_LEN: usize = 32;
+
+const THREADS: u32 = 4;
+const ITERATIONS: u32 = 1000;
+const WAIT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The reason a test failed
+pub struct Failure {
+    status: NTSTATUS,
+    message: StackString<MESSAGE_LEN>,
+}
+
+impl Failure {
+    fn new(status: NTSTATUS, args: fmt::Arguments) -> Self {
+        Self {
+            status,
+            message: StackString::from_fmt(args),
+        }
+    }
+}
+
+impl From<NtError> for Failure {
+    fn from(e: NtError) -> Self {
+        Self::new(e.code(), format_args!("{:?}", e))
+    }
+}
+
+impl From<JoinError> for Failure {
+    fn from(e: JoinError) -> Self {
+        match e {
+            JoinError::Os(e) => e.into(),
+            JoinError::ThreadPanicked(message) => Self::new(
+                STATUS_UNHANDLED_EXCEPTION,
+                format_args!("thread panicked: {}", message),
+            ),
+        }
+    }
+}
+
+/// fail the test with STATUS_ASSERTION_FAILURE if the condition does not hold
+macro_rules! ensure {
+    ($cond:expr, $($arg:tt)+) => {
+        if !$cond {
+            return Err(Failure::new(STATUS_ASSERTION_FAILURE, format_args!($($arg)+)));
+        }
+    };
+}
+
+type TestFn = fn() -> Result<(), Failure>;
+
+const TESTS: &[(&str, TestFn)] = &[
+    ("mutex.fast", mutex_fast),
+    ("mutex.guarded", mutex_guarded),
+    ("mutex.resource", mutex_resource),
+    ("mutex.spin", mutex_spin),
+    ("mutex.static_spin", mutex_static_spin),
+    ("lazy.init_race", lazy_init_race),
+    ("event.ping_pong", event_ping_pong),
+    ("timer.accuracy", timer_accuracy),
+    ("mpsc.producers", mpsc_producers),
+    ("waitgroup.threads", waitgroup_threads),
+];
+
+/// The result of a test
+pub struct TestResult {
+    pub name: &'static str,
+    /// STATUS_SUCCESS if it passed
+    pub status: NTSTATUS,
+    pub message: StackString<MESSAGE_LEN>,
+    pub elapsed: Duration,
+}
+
+impl TestResult {
+    pub fn passed(&self) -> bool {
+        self.status == STATUS_SUCCESS
+    }
+}
+
+/// A result in the fixed layout of `Report::copy_to`, for an IOCTL output buffer
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct RawTestResult {
+    /// the name truncated to `RAW_NAME_LEN` bytes, padded with zeros
+    pub name: [u8; RAW_NAME_LEN],
+    pub status: NTSTATUS,
+    pub elapsed_us: u32,
+    pub message: [u8; MESSAGE_LEN],
+}
+
+/// The results of a run
+pub struct Report {
+    results: Vec<TestResult>,
+}
+
+impl Report {
+    pub fn results(&self) -> &[TestResult] {
+        &self.results
+    }
+
+    pub fn passed(&self) -> usize {
+        self.results.iter().filter(|r| r.passed()).count()
+    }
+
+    pub fn failed(&self) -> usize {
+        self.results.len() - self.passed()
+    }
+
+    pub fn all_passed(&self) -> bool {
+        self.failed() == 0
+    }
+
+    /// write as many results as fit into `buffer` as `RawTestResult`s, returns the number of bytes written
+    pub fn copy_to(&self, buffer: &mut [u8]) -> usize {
+        let size = mem::size_of::<RawTestResult>();
+        let mut written = 0;
+
+        for (result, chunk) in self.results.iter().zip(buffer.chunks_exact_mut(size)) {
+            let mut raw = RawTestResult {
+                name: [0; RAW_NAME_LEN],
+                status: result.status,
+                elapsed_us: result.elapsed.as_micros().min(u32::MAX as u128) as u32,
+                message: [0; MESSAGE_LEN],
+            };
+
+            let name = &result.name.as_bytes()[..result.name.len().min(RAW_NAME_LEN)];
+            raw.name[..name.len()].copy_from_slice(name);
+            raw.message[..result.message.len()].copy_from_slice(result.message.as_bytes());
+
+            unsafe { (chunk.as_mut_ptr() as *mut RawTestResult).write_unaligned(raw) };
+
+            written += size;
+        }
+
+        written
+    }
+}
+
+impl Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for result in &self.results {
+            writeln!(
+                f,
+                "[{}] {} ({}us) {}",
+                if result.passed() { "PASS" } else { "FAIL" },
+                result.name,
+                result.elapsed.as_micros(),
+                result.message
+            )?;
+        }
+
+        write!(f, "{} passed, {} failed", self.passed(), self.failed())
+    }
+}
+
+/// run all the tests at PASSIVE_LEVEL, it fails only if the report can not be allocated
+pub fn run_all() -> Result<Report, NtError> {
+    run("")
+}
+
+/// run the tests whose names start with `prefix`, e.g. `"mutex."`
+pub fn run(prefix: &str) -> Result<Report, NtError> {
+    let mut results = Vec::new();
+
+    results
+        .try_reserve_exact(TESTS.len())
+        .map_err(|_| NtError::new(STATUS_INSUFFICIENT_RESOURCES))?;
+
+    for (name, test) in TESTS.iter().filter(|(name, _)| name.starts_with(prefix)) {
+        let start = KInstant::now();
+        let outcome = test();
+        let elapsed = start.elapsed();
+
+        let (status, message) = match outcome {
+            Ok(()) => (STATUS_SUCCESS, StackString::new()),
+            Err(failure) => (failure.status, failure.message),
+        };
+
+        results.push(TestResult {
+            name,
+            status,
+            message,
+            elapsed,
+        });
+    }
+
+    Ok(Report { results })
+}
+
+/// the names of all the tests
+pub fn names() -> impl Iterator<Item = &'static str> {
+    TESTS.iter().map(|(name, _)| *name)
+}
+
+fn join_all(handles: Vec<JoinHandle>) -> Result<(), Failure> {
+    for handle in handles {
+        handle.join()?;
+    }
+
+    Ok(())
+}
+
+/// increment a counter behind `lock` from `THREADS` threads and check no increment is lost
+fn contend<L>(lock: L, increment: fn(&L), read: fn(&L) -> u32) -> Result<(), Failure>
+where
+    L: Send + Sync + 'static,
+{
+    let lock = KArc::new(lock)?;
+    let mut handles = Vec::new();
+
+    for _ in 0..THREADS {
+        let lock = lock.clone();
+
+        handles.push(thread::spawn(move || {
+            for _ in 0..ITERATIONS {
+                increment(&lock);
+            }
+        })?);
+    }
+
+    join_all(handles)?;
+
+    let value = read(&lock);
+
+    ensure!(
+        value == THREADS * ITERATIONS,
+        "counter is {}, expected {}",
+        value,
+        THREADS * ITERATIONS
+    );
+
+    Ok(())
+}
+
+fn mutex_fast() -> Result<(), Failure> {
+    contend(
+        FastLocked::new(0u32)?,
+        |l| {
+            if let Ok(mut guard) = l.lock() {
+                *guard += 1;
+            }
+        },
+        |l| l.lock().map_or(0, |guard| *guard),
+    )
+}
+
+fn mutex_guarded() -> Result<(), Failure> {
+    contend(
+        GuardLocked::new(0u32)?,
+        |l| {
+            if let Ok(mut guard) = l.lock() {
+                *guard += 1;
+            }
+        },
+        |l| l.lock().map_or(0, |guard| *guard),
+    )
+}
+
+fn mutex_resource() -> Result<(), Failure> {
+    contend(
+        ResourceLocked::new(0u32)?,
+        |l| {
+            if let Ok(mut guard) = l.lock() {
+                *guard += 1;
+            }
+        },
+        |l| l.lock_shared().map_or(0, |guard| *guard),
+    )
+}
+
+fn mutex_spin() -> Result<(), Failure> {
+    contend(
+        SpinLocked::new(0u32)?,
+        |l| {
+            if let Ok(mut guard) = l.lock() {
+                *guard += 1;
+            }
+        },
+        |l| l.lock().map_or(0, |guard| *guard),
+    )
+}
+
+fn mutex_static_spin() -> Result<(), Failure> {
+    contend(
+        StaticSpinLocked::new(0u32),
+        |l| *l.lock() += 1,
+        |l| *l.lock(),
+    )
+}
+
+/// race `THREADS` threads on an uninitialized `OnceLock`, the initializer must run exactly once
+fn lazy_init_race() -> Result<(), Failure> {
+    struct Shared {
+        cell: OnceLock<u32>,
+        inits: AtomicU32,
+        mismatches: AtomicU32,
+    }
+
+    let shared = KArc::new(Shared {
+        cell: OnceLock::new(),
+        inits: AtomicU32::new(0),
+        mismatches: AtomicU32::new(0),
+    })?;
+
+    let mut handles = Vec::new();
+
+    for _ in 0..THREADS {
+        let shared = shared.clone();
+
+        handles.push(thread::spawn(move || {
+            let value = shared.cell.get_or_init(|| {
+                shared.inits.fetch_add(1, Ordering::SeqCst);
+
+                // widen the window of the race
+                this_thread::sleep(Duration::from_millis(10));
+
+                42
+            });
+
+            if value != Some(&42) {
+                shared.mismatches.fetch_add(1, Ordering::SeqCst);
+            }
+        })?);
+    }
+
+    join_all(handles)?;
+
+    let inits = shared.inits.load(Ordering::SeqCst);
+    let mismatches = shared.mismatches.load(Ordering::SeqCst);
+
+    ensure!(inits == 1, "initializer ran {} times", inits);
+    ensure!(mismatches == 0, "{} threads saw a wrong value", mismatches);
+
+    Ok(())
+}
+
+/// bounce between two auto-reset events with another thread
+fn event_ping_pong() -> Result<(), Failure> {
+    const ROUNDS: u32 = 100;
+
+    struct Pair {
+        ping: Event,
+        pong: Event,
+    }
+
+    let pair = KArc::new(Pair {
+        ping: EventProperty::new().auto_reset(true).new_event()?,
+        pong: EventProperty::new().auto_reset(true).new_event()?,
+    })?;
+
+    let other = pair.clone();
+    let handle = thread::spawn(move || {
+        for _ in 0..ROUNDS {
+            if other.ping.wait_for(WAIT_TIMEOUT, false).timed_out() {
+                return;
+            }
+
+            other.pong.set();
+        }
+    })?;
+
+    for round in 0..ROUNDS {
+        pair.ping.set();
+
+        if pair.pong.wait_for(WAIT_TIMEOUT, false).timed_out() {
+            let _ = handle.join();
+
+            return Err(Failure::new(
+                STATUS_TIMEOUT,
+                format_args!("no pong in round {}", round),
+            ));
+        }
+    }
+
+    handle.join()?;
+
+    Ok(())
+}
+
+/// a 50ms timer must not expire early, and not later than a few clock ticks
+fn timer_accuracy() -> Result<(), Failure> {
+    const DUE: Duration = Duration::from_millis(50);
+    const SLACK: Duration = Duration::from_millis(200);
+
+    let start = KInstant::now();
+
+    Timer::after(DUE)?.recv()?;
+
+    let elapsed = start.elapsed();
+
+    // the interrupt time used by the timer and the performance counter may disagree by a bit
+    ensure!(
+        elapsed + Duration::from_millis(1) >= DUE,
+        "expired early after {}us",
+        elapsed.as_micros()
+    );
+    ensure!(
+        elapsed <= DUE + SLACK,
+        "expired late after {}us",
+        elapsed.as_micros()
+    );
+
+    Ok(())
+}
+
+/// `THREADS` producers send into a small channel, nothing may be lost or duplicated
+fn mpsc_producers() -> Result<(), Failure> {
+    const ITEMS: u32 = 200;
+
+    let (tx, rx) = mpsc::channel::<u32>(16)?;
+    let mut handles = Vec::new();
+
+    for producer in 0..THREADS {
+        let tx = tx.clone();
+
+        handles.push(thread::spawn(move || {
+            for i in 0..ITEMS {
+                let mut value = producer * ITEMS + i;
+
+                // the channel is full, retry until the consumer catches up
+                while let Err(back) = tx.send(value) {
+                    value = back;
+                    this_thread::sleep(Duration::from_millis(1));
+                }
+            }
+        })?);
+    }
+
+    drop(tx);
+
+    let mut count = 0u32;
+    let mut sum = 0u64;
+
+    while let Ok(value) = rx.recv_timeout(WAIT_TIMEOUT) {
+        count += 1;
+        sum += value as u64;
+    }
+
+    join_all(handles)?;
+
+    let total = THREADS * ITEMS;
+    let expected = (total as u64) * (total as u64 - 1) / 2;
+
+    ensure!(
+        count == total,
+        "received {} items, expected {}",
+        count,
+        total
+    );
+    ensure!(sum == expected, "sum is {}, expected {}", sum, expected);
+
+    Ok(())
+}
+
+fn waitgroup_threads() -> Result<(), Failure> {
+    let group = WaitGroup::new()?;
+
+    for _ in 0..THREADS {
+        let _ = thread::spawn_in(&group, || this_thread::sleep(Duration::from_millis(20)))?;
+    }
+
+    ensure!(
+        !group.wait_for(WAIT_TIMEOUT).timed_out(),
+        "{} threads still running",
+        group.count()
+    );
+    ensure!(group.count() == 0, "count is {}", group.count());
+
+    Ok(())
+}
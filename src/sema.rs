@@ -3,16 +3,15 @@ use core::{mem, ptr, time::Duration};
 use wdk_sys::{
     _KSEMAPHORE,
     _POOL_TYPE::NonPagedPoolNx,
-    PRKSEMAPHORE, STATUS_INSUFFICIENT_RESOURCES,
-    ntddk::{
-        ExFreePoolWithTag, KeInitializeSemaphore, KeReadStateSemaphore, KeReleaseSemaphore,
-    },
+    PRKSEMAPHORE,
+    ntddk::{KeInitializeSemaphore, KeReadStateSemaphore, KeReleaseSemaphore},
 };
 
 use crate::{
     kobject::{Dispatchable, WaitResult},
     ntstatus::NtError,
-    raw::AsRawObject, utils::ex_allocate_pool_zero,
+    raw::AsRawObject,
+    utils::{ex_allocate_pool_zero, ex_free_pool},
 };
 
 const SEMA_TAG: u32 = u32::from_ne_bytes(*b"ames");
@@ -33,11 +32,7 @@ impl Semaphore {
     /// it is normaly be set to `thread::available_parallelism`
     pub fn new(count: i32, limit: i32) -> Result<Self, NtError> {
         let layout =
-            ex_allocate_pool_zero(NonPagedPoolNx, mem::size_of::<_KSEMAPHORE>() as _, SEMA_TAG);
-
-        if layout.is_null() {
-            return Err(NtError::new(STATUS_INSUFFICIENT_RESOURCES));
-        }
+            ex_allocate_pool_zero(NonPagedPoolNx, mem::size_of::<_KSEMAPHORE>() as _, SEMA_TAG)?;
 
         unsafe {
             KeInitializeSemaphore(layout.cast(), count, limit);
@@ -96,7 +91,7 @@ impl Dispatchable for Semaphore {}
 impl Drop for Semaphore {
     fn drop(&mut self) {
         unsafe {
-            ExFreePoolWithTag(self.0.cast(), SEMA_TAG);
+            ex_free_pool(self.0.cast(), SEMA_TAG);
         }
     }
 }
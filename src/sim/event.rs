@@ -0,0 +1,178 @@
+use std::{
+    sync::{Condvar, Mutex, MutexGuard},
+    time::{Duration, Instant},
+};
+
+use super::{
+    kobject::{Dispatchable, WaitResult},
+    ntstatus::{NtError, STATUS_SUCCESS, STATUS_TIMEOUT},
+};
+
+/// the same builder as the kernel `EventProperty`
+#[derive(Clone, Copy, Default)]
+pub struct EventProperty {
+    auto_reset: bool,
+    initial_state: bool,
+}
+
+impl EventProperty {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn auto_reset(mut self, value: bool) -> Self {
+        self.auto_reset = value;
+        self
+    }
+
+    pub fn initial_state(mut self, value: bool) -> Self {
+        self.initial_state = value;
+        self
+    }
+
+    pub fn new_event(self) -> Result<Event, NtError> {
+        Event::new(self)
+    }
+}
+
+/// An event over a `Mutex<State>` and a `Condvar`
+///
+/// an auto-reset event releases a single waiter and is cleared by it, like a synchronization event
+pub struct Event {
+    state: Mutex<State>,
+    cond: Condvar,
+    auto_reset: bool,
+}
+
+struct State {
+    signaled: bool,
+    /// bumped by `pulse`, a waiter which sees it change was released by a pulse
+    generation: u64,
+    /// the waiter an auto-reset event releases on a pulse, taken by the first waiter to wake
+    pulsed: bool,
+}
+
+impl Event {
+    pub fn new(prop: EventProperty) -> Result<Self, NtError> {
+        Ok(Self {
+            state: Mutex::new(State {
+                signaled: prop.initial_state,
+                generation: 0,
+                pulsed: false,
+            }),
+            cond: Condvar::new(),
+            auto_reset: prop.auto_reset,
+        })
+    }
+
+    fn state(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    pub fn set(&self) {
+        self.state().signaled = true;
+
+        if self.auto_reset {
+            self.cond.notify_one();
+        } else {
+            self.cond.notify_all();
+        }
+    }
+
+    pub fn clear(&self) {
+        self.state().signaled = false;
+    }
+
+    /// clear the event, returns the previous state
+    pub fn reset(&self) -> bool {
+        core::mem::replace(&mut self.state().signaled, false)
+    }
+
+    pub fn get_state(&self) -> bool {
+        self.state().signaled
+    }
+
+    /// release the current waiters and leave the event cleared, returns the previous state
+    ///
+    /// the event is never observed set, the waiters are released by the new generation, an auto-reset event
+    /// releases one of them
+    pub fn pulse(&self) -> bool {
+        let mut state = self.state();
+        let previous = core::mem::replace(&mut state.signaled, false);
+
+        state.generation = state.generation.wrapping_add(1);
+        state.pulsed = true;
+
+        self.cond.notify_all();
+
+        previous
+    }
+
+    pub fn wait_timeout(&self, timeout: Duration) -> WaitResult {
+        self.wait_for(timeout, false)
+    }
+
+    /// whether a waiter which started at `generation` is released, it takes the signal of an auto-reset event
+    fn try_acquire(&self, state: &mut State, generation: &mut u64) -> bool {
+        if state.signaled {
+            if self.auto_reset {
+                state.signaled = false;
+            }
+
+            return true;
+        }
+
+        if state.generation != *generation {
+            if !self.auto_reset {
+                return true;
+            }
+
+            if core::mem::replace(&mut state.pulsed, false) {
+                return true;
+            }
+
+            // another waiter took the pulse
+            *generation = state.generation;
+        }
+
+        false
+    }
+}
+
+impl Dispatchable for Event {
+    fn wait(&self, _alertable: bool) -> WaitResult {
+        let mut state = self.state();
+        let mut generation = state.generation;
+
+        while !self.try_acquire(&mut state, &mut generation) {
+            state = self.cond.wait(state).unwrap_or_else(|e| e.into_inner());
+        }
+
+        WaitResult::new(STATUS_SUCCESS)
+    }
+
+    fn wait_for(&self, timeout: Duration, _alertable: bool) -> WaitResult {
+        let deadline = Instant::now().checked_add(timeout);
+        let mut state = self.state();
+        let mut generation = state.generation;
+
+        while !self.try_acquire(&mut state, &mut generation) {
+            let remaining = match deadline {
+                Some(deadline) => deadline.saturating_duration_since(Instant::now()),
+                None => Duration::MAX,
+            };
+
+            if remaining.is_zero() {
+                return WaitResult::new(STATUS_TIMEOUT);
+            }
+
+            state = self
+                .cond
+                .wait_timeout(state, remaining)
+                .unwrap_or_else(|e| e.into_inner())
+                .0;
+        }
+
+        WaitResult::new(STATUS_SUCCESS)
+    }
+}
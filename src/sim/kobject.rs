@@ -0,0 +1,41 @@
+use std::time::Duration;
+
+use super::ntstatus::{NTSTATUS, STATUS_SUCCESS, STATUS_TIMEOUT};
+
+#[repr(transparent)]
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct WaitResult(i32);
+
+impl WaitResult {
+    pub const fn new(status: NTSTATUS) -> Self {
+        Self(status)
+    }
+
+    #[inline]
+    pub fn success(self) -> bool {
+        self.0 == STATUS_SUCCESS
+    }
+
+    /// always false, the simulated waits are never alerted
+    #[inline]
+    pub fn alerted(self) -> bool {
+        false
+    }
+
+    #[inline]
+    pub fn timed_out(self) -> bool {
+        self.0 == STATUS_TIMEOUT
+    }
+
+    #[inline]
+    pub fn code(self) -> NTSTATUS {
+        self.0
+    }
+}
+
+/// The simulated objects which can be waited, the `alertable` arguments are ignored
+pub trait Dispatchable {
+    fn wait(&self, alertable: bool) -> WaitResult;
+
+    fn wait_for(&self, timeout: Duration, alertable: bool) -> WaitResult;
+}
@@ -0,0 +1,27 @@
+//! a host-side simulation of the synchronization primitives, enabled by the `std-sim` feature
+//!
+//! the kernel calls are replaced by the std equivalents, `std::sync` for the locks and the events, `std::thread`
+//! for the system threads and `std::time::Instant` for the timers, so the logic built on top of this crate can be
+//! unit tested with a plain `cargo test` on the host instead of a VM
+//!
+//! only the modules below are simulated, they are re-exported at the crate root under the same paths as the kernel
+//! ones, e.g. `ksync::mutex::FastLocked`, the IRQL requirements are not simulated
+//!
+//! # Example
+//! ```toml
+//! [dependencies]
+//! ksync = "0.1"
+//!
+//! [dev-dependencies]
+//! ksync = { version = "0.1", default-features = false, features = ["std-sim"] }
+//! ```
+pub mod event;
+pub mod kobject;
+pub mod mutex;
+pub mod ntstatus;
+pub mod thread;
+pub mod time;
+pub mod timer;
+
+#[cfg(test)]
+mod test;
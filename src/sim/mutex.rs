@@ -0,0 +1,105 @@
+use core::marker::PhantomData;
+use std::sync::{self, RwLock, RwLockReadGuard, RwLockWriteGuard};
+
+use super::ntstatus::NtError;
+
+/// The kind of a simulated lock, all of them are backed by the same std lock
+pub trait Mutex {}
+
+pub struct FastMutex;
+pub struct GuardedMutex;
+pub struct ResourceMutex<const REGION: bool = true>;
+pub struct SpinMutex;
+
+impl Mutex for FastMutex {}
+impl Mutex for GuardedMutex {}
+impl<const REGION: bool> Mutex for ResourceMutex<REGION> {}
+impl Mutex for SpinMutex {}
+
+/// A lock with the same api as the kernel `Locked`
+///
+/// a poisoned lock is still acquired, since a panic aborts the kernel there is nothing to recover from
+pub struct Locked<T, M: Mutex> {
+    inner: RwLock<T>,
+    _kind: PhantomData<fn() -> M>,
+}
+
+impl<T, M: Mutex> Locked<T, M> {
+    pub fn new(data: T) -> Result<Self, NtError> {
+        Self::try_new(data)
+    }
+
+    pub fn try_new(data: T) -> Result<Self, NtError> {
+        Ok(Self {
+            inner: RwLock::new(data),
+            _kind: PhantomData,
+        })
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        self.inner.get_mut().unwrap_or_else(|e| e.into_inner())
+    }
+
+    pub fn set(&mut self, value: T) {
+        *self.get_mut() = value;
+    }
+
+    pub fn get_cloned(&self) -> Result<T, NtError>
+    where
+        T: Clone,
+    {
+        Ok(self.lock_shared()?.clone())
+    }
+
+    pub fn lock(&self) -> Result<RwLockWriteGuard<'_, T>, NtError> {
+        Ok(self.inner.write().unwrap_or_else(|e| e.into_inner()))
+    }
+
+    /// a spin lock is always exclusive in the kernel, but a shared guard here is harmless
+    pub fn lock_shared(&self) -> Result<RwLockReadGuard<'_, T>, NtError> {
+        Ok(self.inner.read().unwrap_or_else(|e| e.into_inner()))
+    }
+
+    pub fn into_inner(self) -> T {
+        self.inner.into_inner().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// A spin lock which can be created in a const context, like the kernel `StaticSpinLocked`
+pub struct StaticSpinLocked<T> {
+    inner: sync::Mutex<T>,
+}
+
+impl<T> StaticSpinLocked<T> {
+    pub const fn new(data: T) -> Self {
+        Self {
+            inner: sync::Mutex::new(data),
+        }
+    }
+
+    pub fn lock(&self) -> sync::MutexGuard<'_, T> {
+        self.inner.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    pub fn try_lock(&self) -> Option<sync::MutexGuard<'_, T>> {
+        match self.inner.try_lock() {
+            Ok(guard) => Some(guard),
+            Err(sync::TryLockError::Poisoned(e)) => Some(e.into_inner()),
+            Err(sync::TryLockError::WouldBlock) => None,
+        }
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        self.inner.get_mut().unwrap_or_else(|e| e.into_inner())
+    }
+
+    pub fn into_inner(self) -> T {
+        self.inner.into_inner().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+pub type GuardLocked<T> = Locked<T, GuardedMutex>;
+pub type FastLocked<T> = Locked<T, FastMutex>;
+pub type ResourceLocked<T> = Locked<T, ResourceMutex>;
+pub type ResourceLockedInRegion<T> = Locked<T, ResourceMutex<false>>;
+pub type SpinLocked<T> = Locked<T, SpinMutex>;
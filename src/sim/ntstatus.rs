@@ -0,0 +1,85 @@
+use core::num::NonZeroI32;
+
+pub type NTSTATUS = i32;
+
+pub const STATUS_SUCCESS: NTSTATUS = 0;
+pub const STATUS_TIMEOUT: NTSTATUS = 0x0000_0102;
+pub const STATUS_PENDING: NTSTATUS = 0x0000_0103;
+pub const STATUS_UNSUCCESSFUL: NTSTATUS = 0xC000_0001_u32 as i32;
+pub const STATUS_NOT_IMPLEMENTED: NTSTATUS = 0xC000_0002_u32 as i32;
+pub const STATUS_INVALID_PARAMETER: NTSTATUS = 0xC000_000D_u32 as i32;
+pub const STATUS_ACCESS_DENIED: NTSTATUS = 0xC000_0022_u32 as i32;
+pub const STATUS_INSUFFICIENT_RESOURCES: NTSTATUS = 0xC000_009A_u32 as i32;
+pub const STATUS_NOT_SUPPORTED: NTSTATUS = 0xC000_00BB_u32 as i32;
+pub const STATUS_CANCELLED: NTSTATUS = 0xC000_0120_u32 as i32;
+pub const STATUS_UNHANDLED_EXCEPTION: NTSTATUS = 0xC000_0144_u32 as i32;
+pub const STATUS_PIPE_BROKEN: NTSTATUS = 0xC000_014B_u32 as i32;
+pub const STATUS_NOT_FOUND: NTSTATUS = 0xC000_0225_u32 as i32;
+
+/// the same as the kernel `NtError`, only the statuses above are defined by the simulation
+#[repr(transparent)]
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct NtError(NonZeroI32);
+
+impl NtError {
+    pub fn new(status: NTSTATUS) -> Self {
+        Self(NonZeroI32::new(status).unwrap())
+    }
+
+    pub fn code(&self) -> NTSTATUS {
+        self.0.get()
+    }
+
+    #[inline]
+    pub fn is_error(&self) -> bool {
+        (self.code() as u32) >> 30 == 3
+    }
+
+    #[inline]
+    pub fn is_success(&self) -> bool {
+        self.code() >= 0
+    }
+}
+
+impl From<NTSTATUS> for NtError {
+    fn from(value: NTSTATUS) -> Self {
+        NtError::new(value)
+    }
+}
+
+impl From<NtError> for NTSTATUS {
+    fn from(value: NtError) -> Self {
+        value.code()
+    }
+}
+
+impl std::error::Error for NtError {}
+
+impl core::fmt::Debug for NtError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "Status{{ {:X} }}", self.0)
+    }
+}
+
+impl core::fmt::Display for NtError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "{:X}", self.0)
+    }
+}
+
+pub type Result = core::result::Result<(), NtError>;
+
+pub fn cvt(status: NTSTATUS) -> Result {
+    match status {
+        STATUS_SUCCESS => Ok(()),
+        _ => Err(status.into()),
+    }
+}
+
+pub fn check(status: NTSTATUS) -> core::result::Result<NTSTATUS, NtError> {
+    if status >= 0 {
+        Ok(status)
+    } else {
+        Err(status.into())
+    }
+}
@@ -0,0 +1,284 @@
+// the host tests of the simulation, run them with `cargo test --no-default-features --features std-sim`
+use std::{
+    sync::{
+        Arc,
+        atomic::{AtomicU32, Ordering},
+    },
+    time::Duration,
+    vec::Vec,
+};
+
+use super::event::{Event, EventProperty};
+use super::kobject::Dispatchable;
+use super::mutex::{FastLocked, ResourceLocked, StaticSpinLocked};
+use super::ntstatus::STATUS_UNSUCCESSFUL;
+use super::thread::{self, Builder, JoinError, this_thread};
+use super::time::{KInstant, KSystemTime, Stopwatch};
+use super::timer::Timer;
+
+const SHORT: Duration = Duration::from_millis(50);
+const LONG: Duration = Duration::from_secs(5);
+
+#[test]
+fn test_event_signal_wait() {
+    let event = Arc::new(EventProperty::new().new_event().unwrap());
+    let signaled = event.clone();
+
+    let handle = thread::spawn(move || {
+        this_thread::sleep(SHORT);
+        signaled.set();
+    })
+    .unwrap();
+
+    assert!(event.wait_for(LONG, false).success());
+    // a manual-reset event stays signaled
+    assert!(event.get_state());
+    assert!(event.wait_for(Duration::ZERO, false).success());
+
+    handle.join().unwrap();
+}
+
+#[test]
+fn test_event_timeout() {
+    let event = EventProperty::new().new_event().unwrap();
+
+    let watch = Stopwatch::start_new();
+
+    assert!(event.wait_for(SHORT, false).timed_out());
+    assert!(watch.elapsed() >= SHORT);
+}
+
+#[test]
+fn test_event_auto_reset() {
+    let event = EventProperty::new()
+        .auto_reset(true)
+        .initial_state(true)
+        .new_event()
+        .unwrap();
+
+    // only one wait is satisfied by a signal
+    assert!(event.wait_for(Duration::ZERO, false).success());
+    assert!(!event.get_state());
+    assert!(event.wait_for(SHORT, false).timed_out());
+}
+
+#[test]
+fn test_event_pulse() {
+    let event = Arc::new(Event::new(EventProperty::new()).unwrap());
+    let released = Arc::new(AtomicU32::new(0));
+
+    let waiters: Vec<_> = (0..3)
+        .map(|_| {
+            let event = event.clone();
+            let released = released.clone();
+
+            thread::spawn(move || {
+                if event.wait_for(LONG, false).success() {
+                    released.fetch_add(1, Ordering::Relaxed);
+                }
+            })
+            .unwrap()
+        })
+        .collect();
+
+    // let the waiters block first
+    this_thread::sleep(SHORT);
+
+    event.pulse();
+
+    for waiter in waiters {
+        waiter.join().unwrap();
+    }
+
+    // a pulse releases the current waiters of a manual-reset event and leaves it reset
+    assert_eq!(released.load(Ordering::Relaxed), 3);
+    assert!(!event.get_state());
+}
+
+#[test]
+fn test_locked_counter() {
+    let counter = Arc::new(FastLocked::new(0u32).unwrap());
+
+    let handles: Vec<_> = (0..4)
+        .map(|_| {
+            let counter = counter.clone();
+
+            thread::spawn(move || {
+                for _ in 0..1000 {
+                    *counter.lock().unwrap() += 1;
+                }
+            })
+            .unwrap()
+        })
+        .collect();
+
+    for handle in handles {
+        handle.join().unwrap();
+    }
+
+    assert_eq!(counter.get_cloned().unwrap(), 4000);
+}
+
+#[test]
+fn test_resource_shared() {
+    let resource = ResourceLocked::new(7u32).unwrap();
+
+    let first = resource.lock_shared().unwrap();
+    let second = resource.lock_shared().unwrap();
+
+    assert_eq!(*first + *second, 14);
+}
+
+#[test]
+fn test_spin_try_lock() {
+    let lock = StaticSpinLocked::new(0u32);
+
+    let guard = lock.lock();
+
+    assert!(lock.try_lock().is_none());
+
+    drop(guard);
+
+    assert!(lock.try_lock().is_some());
+}
+
+#[test]
+fn test_timer_expiry() {
+    let fired = Arc::new(AtomicU32::new(0));
+    let counter = fired.clone();
+
+    let timer = Timer::new(
+        move || {
+            counter.fetch_add(1, Ordering::Relaxed);
+        },
+        false,
+    )
+    .unwrap();
+
+    timer.start(SHORT, Duration::ZERO);
+
+    assert!(timer.wait_for(LONG, false).success());
+
+    timer.stop_and_wait();
+
+    assert_eq!(fired.load(Ordering::Relaxed), 1);
+}
+
+#[test]
+fn test_timer_periodic_stop() {
+    let fired = Arc::new(AtomicU32::new(0));
+    let counter = fired.clone();
+
+    let timer = Timer::new(
+        move || {
+            counter.fetch_add(1, Ordering::Relaxed);
+        },
+        true,
+    )
+    .unwrap();
+
+    timer.start(Duration::ZERO, Duration::from_millis(10));
+
+    while fired.load(Ordering::Relaxed) < 3 {
+        this_thread::sleep(Duration::from_millis(10));
+    }
+
+    timer.stop_and_wait();
+
+    let count = fired.load(Ordering::Relaxed);
+
+    this_thread::sleep(SHORT);
+
+    // no callback runs once `stop_and_wait` has returned
+    assert_eq!(fired.load(Ordering::Relaxed), count);
+}
+
+#[test]
+fn test_timer_stop_before_expiry() {
+    let fired = Arc::new(AtomicU32::new(0));
+    let counter = fired.clone();
+
+    let timer = Timer::new(
+        move || {
+            counter.fetch_add(1, Ordering::Relaxed);
+        },
+        false,
+    )
+    .unwrap();
+
+    timer.start(SHORT, Duration::ZERO);
+
+    // the callback has not started, so it can not be running
+    assert!(!timer.stop());
+    assert!(timer.wait_for(SHORT * 2, false).timed_out());
+    assert_eq!(fired.load(Ordering::Relaxed), 0);
+}
+
+#[test]
+fn test_thread_exit_status() {
+    let handle = thread::spawn(|| {
+        this_thread::set_exit_status(STATUS_UNSUCCESSFUL);
+    })
+    .unwrap();
+
+    assert_eq!(handle.join().unwrap(), STATUS_UNSUCCESSFUL);
+}
+
+#[test]
+fn test_thread_panic() {
+    let handle = thread::spawn(|| panic!("boom")).unwrap();
+
+    match handle.join() {
+        Err(JoinError::ThreadPanicked(message)) => assert_eq!(message.as_str(), "boom"),
+        _ => panic!("the panic is not reported"),
+    }
+}
+
+#[test]
+fn test_thread_stop_token() {
+    let handle = Builder::new()
+        .spawn(|token| {
+            while !token.is_stopped() {
+                let _ = this_thread::sleep_cancellable(LONG, &token);
+            }
+        })
+        .unwrap();
+
+    assert!(handle.request_stop());
+    handle.join().unwrap();
+}
+
+#[test]
+fn test_time() {
+    let start = KInstant::now();
+
+    this_thread::sleep(SHORT);
+
+    assert!(start.elapsed() >= SHORT);
+    assert!(KInstant::now() - start >= SHORT);
+    assert!(start.checked_add(SHORT).unwrap() > start);
+
+    let now = KSystemTime::now();
+
+    assert!(now.since_unix_epoch().is_some());
+    assert!(KSystemTime::UNIX_EPOCH.duration_since(now).is_none());
+}
+
+#[test]
+fn test_stopwatch() {
+    let mut watch = Stopwatch::start_new();
+
+    this_thread::sleep(SHORT);
+
+    let elapsed = watch.stop();
+
+    assert!(elapsed >= SHORT);
+    assert!(!watch.is_running());
+
+    // a stopped watch does not advance
+    this_thread::sleep(SHORT);
+    assert_eq!(watch.elapsed(), elapsed);
+
+    watch.reset();
+    assert_eq!(watch.elapsed(), Duration::ZERO);
+}
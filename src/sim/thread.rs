@@ -0,0 +1,258 @@
+use core::{cell::RefCell, fmt, num::NonZero};
+use std::{
+    any::Any,
+    string::String,
+    sync::{
+        Arc,
+        atomic::{AtomicI32, AtomicU32, Ordering},
+    },
+    thread,
+    time::Duration,
+};
+
+use super::{
+    event::{Event, EventProperty},
+    kobject::{Dispatchable, WaitResult},
+    ntstatus::{
+        NTSTATUS, NtError, STATUS_INSUFFICIENT_RESOURCES, STATUS_SUCCESS,
+        STATUS_UNHANDLED_EXCEPTION,
+    },
+};
+
+thread_local! {
+    /// the exit status of a thread spawned by `spawn`
+    static EXIT_STATUS: RefCell<Option<Arc<AtomicI32>>> = const { RefCell::new(None) };
+}
+
+/// A handle to a std thread, like the kernel `JoinHandle`
+pub struct JoinHandle {
+    thread: thread::JoinHandle<()>,
+    status: Arc<AtomicI32>,
+    /// the token handed to the thread by `Builder::spawn`
+    stop: Option<StopToken>,
+}
+
+impl JoinHandle {
+    /// the token of a thread spawned by `Builder`
+    pub fn stop_token(&self) -> Option<&StopToken> {
+        self.stop.as_ref()
+    }
+
+    /// ask a thread spawned by `Builder` to stop, returns false if the thread has no token
+    pub fn request_stop(&self) -> bool {
+        self.stop.as_ref().map(StopToken::stop).is_some()
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.thread.is_finished()
+    }
+
+    /// wait for the thread to finish and return its exit status, see `this_thread::set_exit_status`
+    ///
+    /// it fails with `JoinError::ThreadPanicked` if the thread panicked
+    pub fn join(self) -> Result<NTSTATUS, JoinError> {
+        match self.thread.join() {
+            Ok(_) => Ok(self.status.load(Ordering::Relaxed)),
+            Err(payload) => Err(JoinError::ThreadPanicked(PanicMessage::from_payload(
+                payload,
+            ))),
+        }
+    }
+}
+
+/// the message of a panic in a spawned thread
+#[derive(Clone, Debug)]
+pub struct PanicMessage(String);
+
+impl PanicMessage {
+    fn from_payload(payload: Box<dyn Any + Send>) -> Self {
+        let message = match payload.downcast::<String>() {
+            Ok(message) => *message,
+            Err(payload) => payload
+                .downcast_ref::<&str>()
+                .map_or_else(String::new, |message| String::from(*message)),
+        };
+
+        Self(message)
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl fmt::Display for PanicMessage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// The error of `JoinHandle::join`, the same as the kernel one
+#[derive(Clone, Debug)]
+pub enum JoinError {
+    /// the thread could not be waited for
+    Os(NtError),
+    /// the thread panicked
+    ThreadPanicked(PanicMessage),
+}
+
+impl From<NtError> for JoinError {
+    fn from(e: NtError) -> Self {
+        Self::Os(e)
+    }
+}
+
+/// a panic becomes STATUS_UNHANDLED_EXCEPTION, so `join()?` works in the functions returning `NtError`
+impl From<JoinError> for NtError {
+    fn from(e: JoinError) -> Self {
+        match e {
+            JoinError::Os(e) => e,
+            JoinError::ThreadPanicked(_) => NtError::new(STATUS_UNHANDLED_EXCEPTION),
+        }
+    }
+}
+
+pub fn available_parallelism() -> NonZero<usize> {
+    thread::available_parallelism().unwrap_or(NonZero::<usize>::MIN)
+}
+
+/// spawn a std thread, it fails with STATUS_INSUFFICIENT_RESOURCES if the thread can not be created
+pub fn spawn<F: FnOnce() + Send + 'static>(f: F) -> Result<JoinHandle, NtError> {
+    let status = Arc::new(AtomicI32::new(STATUS_SUCCESS));
+    let exit = status.clone();
+
+    let thread = thread::Builder::new()
+        .spawn(move || {
+            EXIT_STATUS.with(|status| *status.borrow_mut() = Some(exit));
+            f()
+        })
+        .map_err(|_| NtError::new(STATUS_INSUFFICIENT_RESOURCES))?;
+
+    Ok(JoinHandle {
+        thread,
+        status,
+        stop: None,
+    })
+}
+
+/// A request to stop a thread, backed by a manual-reset `Event`, the same as the kernel `StopToken`
+#[derive(Clone)]
+pub struct StopToken(Arc<Event>);
+
+impl StopToken {
+    pub fn new() -> Result<Self, NtError> {
+        Ok(Self(Arc::new(EventProperty::new().new_event()?)))
+    }
+
+    pub fn stop(&self) {
+        self.0.set();
+    }
+
+    pub fn is_stopped(&self) -> bool {
+        self.0.get_state()
+    }
+}
+
+/// wait for the stop request
+impl Dispatchable for StopToken {
+    fn wait(&self, alertable: bool) -> WaitResult {
+        self.0.wait(alertable)
+    }
+
+    fn wait_for(&self, timeout: Duration, alertable: bool) -> WaitResult {
+        self.0.wait_for(timeout, alertable)
+    }
+}
+
+/// Spawn a thread which is handed a `StopToken`, the same as the kernel `Builder`
+#[derive(Default)]
+pub struct Builder {
+    stop: Option<StopToken>,
+}
+
+impl Builder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// hand `token` to the thread instead of a new one
+    pub fn stop_token(mut self, token: StopToken) -> Self {
+        self.stop = Some(token);
+        self
+    }
+
+    /// spawn a thread running `f` with the token, the token is kept by the `JoinHandle` as well
+    pub fn spawn<F: FnOnce(StopToken) + Send + 'static>(self, f: F) -> Result<JoinHandle, NtError> {
+        let token = match self.stop {
+            Some(token) => token,
+            None => StopToken::new()?,
+        };
+
+        let stop = token.clone();
+        let mut handle = spawn(move || f(stop))?;
+
+        handle.stop = Some(token);
+
+        Ok(handle)
+    }
+}
+
+pub mod this_thread {
+    use super::*;
+
+    /// How a `sleep_cancellable` ended
+    #[derive(Clone, Copy, PartialEq, Eq, Debug)]
+    pub enum SleepResult {
+        /// the whole duration has elapsed
+        Elapsed,
+        /// the token was stopped before
+        Stopped,
+    }
+
+    pub fn sleep(ms: Duration) {
+        thread::sleep(ms);
+    }
+
+    /// always false, the simulated threads are never alerted
+    pub fn sleep_alertable(ms: Duration) -> bool {
+        thread::sleep(ms);
+
+        false
+    }
+
+    /// set the status the current thread exits with, which `JoinHandle::join` returns, STATUS_SUCCESS by default
+    ///
+    /// returns false if the thread is not spawned by `spawn`
+    pub fn set_exit_status(status: NTSTATUS) -> bool {
+        EXIT_STATUS.with(|exit| {
+            exit.borrow()
+                .as_ref()
+                .map(|exit| exit.store(status, Ordering::Relaxed))
+                .is_some()
+        })
+    }
+
+    /// sleep for `d` unless `token` is stopped, it returns at once if the token is stopped already
+    pub fn sleep_cancellable(d: Duration, token: &StopToken) -> SleepResult {
+        if token.0.wait_timeout(d).timed_out() {
+            SleepResult::Elapsed
+        } else {
+            SleepResult::Stopped
+        }
+    }
+
+    pub fn pause() {
+        core::hint::spin_loop();
+    }
+
+    /// a small unique id of the current thread, std does not expose the os thread id
+    pub fn id() -> u32 {
+        static NEXT: AtomicU32 = AtomicU32::new(1);
+
+        thread_local! {
+            static ID: u32 = NEXT.fetch_add(1, Ordering::Relaxed);
+        }
+
+        ID.with(|id| *id)
+    }
+}
@@ -0,0 +1,196 @@
+use core::ops::{Add, Sub};
+use std::{
+    sync::OnceLock,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+/// the simulated performance counter runs in nanoseconds
+pub fn performance_frequency() -> u64 {
+    1_000_000_000
+}
+
+fn base() -> Instant {
+    static BASE: OnceLock<Instant> = OnceLock::new();
+
+    *BASE.get_or_init(Instant::now)
+}
+
+/// A monotonic instant, in nanoseconds since the first measurement
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct KInstant(u64);
+
+impl KInstant {
+    pub fn now() -> Self {
+        let base = base();
+
+        Self(Instant::now().duration_since(base).as_nanos() as u64)
+    }
+
+    pub fn ticks(&self) -> u64 {
+        self.0
+    }
+
+    pub fn from_ticks(ticks: u64) -> Self {
+        Self(ticks)
+    }
+
+    pub fn elapsed(&self) -> Duration {
+        Self::now().saturating_duration_since(*self)
+    }
+
+    pub fn duration_since(&self, earlier: KInstant) -> Duration {
+        self.checked_duration_since(earlier)
+            .expect("supplied instant is later than self")
+    }
+
+    pub fn checked_duration_since(&self, earlier: KInstant) -> Option<Duration> {
+        self.0.checked_sub(earlier.0).map(Duration::from_nanos)
+    }
+
+    pub fn saturating_duration_since(&self, earlier: KInstant) -> Duration {
+        self.checked_duration_since(earlier).unwrap_or_default()
+    }
+
+    pub fn checked_add(&self, d: Duration) -> Option<KInstant> {
+        u64::try_from(d.as_nanos())
+            .ok()
+            .and_then(|nanos| self.0.checked_add(nanos))
+            .map(Self)
+    }
+
+    pub fn checked_sub(&self, d: Duration) -> Option<KInstant> {
+        u64::try_from(d.as_nanos())
+            .ok()
+            .and_then(|nanos| self.0.checked_sub(nanos))
+            .map(Self)
+    }
+}
+
+impl Add<Duration> for KInstant {
+    type Output = KInstant;
+    fn add(self, rhs: Duration) -> Self::Output {
+        self.checked_add(rhs)
+            .expect("overflow when adding duration to instant")
+    }
+}
+
+impl Sub<Duration> for KInstant {
+    type Output = KInstant;
+    fn sub(self, rhs: Duration) -> Self::Output {
+        self.checked_sub(rhs)
+            .expect("overflow when subtracting duration from instant")
+    }
+}
+
+impl Sub<KInstant> for KInstant {
+    type Output = Duration;
+    fn sub(self, rhs: KInstant) -> Self::Output {
+        self.duration_since(rhs)
+    }
+}
+
+/// A system time in 100ns units since January 1, 1601 (UTC), the same unit as the kernel one
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct KSystemTime(i64);
+
+/// the 100ns units between January 1, 1601 and January 1, 1970
+const UNIX_EPOCH_UNITS: i64 = 116_444_736_000_000_000;
+
+impl KSystemTime {
+    pub const UNIX_EPOCH: KSystemTime = KSystemTime(UNIX_EPOCH_UNITS);
+
+    pub fn now() -> Self {
+        let since_epoch = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+
+        Self(UNIX_EPOCH_UNITS + (since_epoch.as_nanos() / 100) as i64)
+    }
+
+    pub const fn from_units(units: i64) -> Self {
+        Self(units)
+    }
+
+    pub const fn as_units(&self) -> i64 {
+        self.0
+    }
+
+    /// the duration since the unix epoch, `None` if it is earlier than the epoch
+    pub fn since_unix_epoch(&self) -> Option<Duration> {
+        self.duration_since(Self::UNIX_EPOCH)
+    }
+
+    pub fn duration_since(&self, earlier: KSystemTime) -> Option<Duration> {
+        self.0
+            .checked_sub(earlier.0)
+            .and_then(|units| u64::try_from(units).ok())
+            .map(|units| Duration::from_nanos(units.saturating_mul(100)))
+    }
+
+    pub fn elapsed(&self) -> Option<Duration> {
+        Self::now().duration_since(*self)
+    }
+}
+
+/// the same as the kernel `Stopwatch`
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Stopwatch {
+    elapsed: Duration,
+    started: Option<KInstant>,
+}
+
+impl Stopwatch {
+    pub const fn new() -> Self {
+        Self {
+            elapsed: Duration::ZERO,
+            started: None,
+        }
+    }
+
+    pub fn start_new() -> Self {
+        let mut watch = Self::new();
+
+        watch.start();
+
+        watch
+    }
+
+    pub fn start(&mut self) {
+        if self.started.is_none() {
+            self.started = Some(KInstant::now());
+        }
+    }
+
+    pub fn stop(&mut self) -> Duration {
+        if let Some(started) = self.started.take() {
+            self.elapsed += started.elapsed();
+        }
+
+        self.elapsed
+    }
+
+    pub fn reset(&mut self) {
+        self.elapsed = Duration::ZERO;
+        self.started = None;
+    }
+
+    pub fn restart(&mut self) -> Duration {
+        let elapsed = self.elapsed();
+
+        self.elapsed = Duration::ZERO;
+        self.started = Some(KInstant::now());
+
+        elapsed
+    }
+
+    pub fn is_running(&self) -> bool {
+        self.started.is_some()
+    }
+
+    pub fn elapsed(&self) -> Duration {
+        match self.started {
+            Some(started) => self.elapsed + started.elapsed(),
+            None => self.elapsed,
+        }
+    }
+}
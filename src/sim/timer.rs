@@ -0,0 +1,248 @@
+use std::{
+    sync::{Arc, Condvar, Mutex, MutexGuard},
+    thread,
+    time::{Duration, Instant},
+};
+
+use super::{
+    event::{Event, EventProperty},
+    kobject::{Dispatchable, WaitResult},
+    ntstatus::{NtError, STATUS_INSUFFICIENT_RESOURCES, STATUS_INVALID_PARAMETER},
+    time::KSystemTime,
+};
+
+/// run `f` once after `after` on a std thread
+pub trait DelayRun {
+    fn delay_run<F: Fn() + Send + 'static>(f: F, after: Duration) -> Result<(), NtError>;
+}
+
+struct Schedule {
+    /// bumped by every `start` and `stop`, a thread exits once its generation is stale
+    generation: u64,
+    due: Option<Instant>,
+    period: Duration,
+    /// the period in 100ns units of `start_periodic_aligned`, the next due time is the next boundary of it
+    aligned: Option<i64>,
+    /// the callbacks which are running
+    running: usize,
+}
+
+impl Schedule {
+    fn is_periodic(&self) -> bool {
+        !self.period.is_zero() || self.aligned.is_some()
+    }
+}
+
+struct Shared {
+    schedule: Mutex<Schedule>,
+    cond: Condvar,
+    /// the timer object, a notification or a synchronization event
+    signaled: Event,
+    /// the callbacks of a periodic timer take turns like the DPCs of the kernel one
+    callback: Mutex<Box<dyn FnMut() + Send>>,
+}
+
+impl Shared {
+    fn schedule(&self) -> MutexGuard<'_, Schedule> {
+        self.schedule.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// the instant of the next boundary of `period` units since January 1, 1601 (UTC)
+fn next_boundary(period: i64) -> Option<Instant> {
+    let now = KSystemTime::now().as_units();
+    let next = KSystemTime::from_units((now / period + 1).saturating_mul(period));
+
+    until(next)
+}
+
+/// the instant of the system time `at`, now if it has passed already
+fn until(at: KSystemTime) -> Option<Instant> {
+    Instant::now().checked_add(at.duration_since(KSystemTime::now()).unwrap_or_default())
+}
+
+/// A timer which runs its callback on a std thread instead of a DPC
+pub struct Timer {
+    shared: Arc<Shared>,
+}
+
+impl Timer {
+    pub fn new<F: FnMut() + Send + 'static>(f: F, is_synch: bool) -> Result<Self, NtError> {
+        Self::try_new(f, is_synch)
+    }
+
+    pub fn try_new<F: FnMut() + Send + 'static>(f: F, is_synch: bool) -> Result<Self, NtError> {
+        Ok(Self {
+            shared: Arc::new(Shared {
+                schedule: Mutex::new(Schedule {
+                    generation: 0,
+                    due: None,
+                    period: Duration::ZERO,
+                    aligned: None,
+                    running: 0,
+                }),
+                cond: Condvar::new(),
+                signaled: EventProperty::new().auto_reset(is_synch).new_event()?,
+                callback: Mutex::new(Box::new(f)),
+            }),
+        })
+    }
+
+    pub fn get_state(&self) -> bool {
+        self.shared.signaled.get_state()
+    }
+
+    /// start or restart the timer, a zero `period` makes it a one-shot timer
+    pub fn start(&self, after: Duration, period: Duration) {
+        self.arm(Instant::now().checked_add(after), period, None);
+    }
+
+    /// start the timer once at the system time `at`, it expires immediately if `at` has passed already
+    ///
+    /// unlike the kernel `Timer`, the due time is converted to an instant, so it does not follow the changes of the
+    /// system time
+    pub fn start_at(&self, at: KSystemTime) {
+        self.arm(until(at), Duration::ZERO, None);
+    }
+
+    /// start the timer on the wall clock boundaries of `period`, see the kernel `Timer`
+    ///
+    /// it fails with STATUS_INVALID_PARAMETER if `period` is shorter than 100ns
+    pub fn start_periodic_aligned(&self, period: Duration) -> Result<(), NtError> {
+        let units = i64::try_from(period.as_nanos() / 100).unwrap_or(i64::MAX);
+
+        if units == 0 {
+            return Err(NtError::new(STATUS_INVALID_PARAMETER));
+        }
+
+        self.arm(next_boundary(units), Duration::ZERO, Some(units));
+
+        Ok(())
+    }
+
+    fn arm(&self, due: Option<Instant>, period: Duration, aligned: Option<i64>) {
+        let generation = {
+            let mut schedule = self.shared.schedule();
+
+            schedule.generation += 1;
+            schedule.due = due;
+            schedule.period = period;
+            schedule.aligned = aligned;
+
+            schedule.generation
+        };
+
+        self.shared.signaled.clear();
+        self.shared.cond.notify_all();
+
+        let shared = self.shared.clone();
+
+        thread::spawn(move || run(shared, generation));
+    }
+
+    /// stop the timer, returns true if the callback may still be running, see `stop_and_wait`
+    pub fn stop(&self) -> bool {
+        let running = {
+            let mut schedule = self.shared.schedule();
+            let cancelled = schedule.due.take().is_some();
+
+            schedule.generation += 1;
+
+            !cancelled || schedule.is_periodic()
+        };
+
+        self.shared.cond.notify_all();
+
+        running
+    }
+
+    /// stop the timer and wait until its callback has returned
+    pub fn stop_and_wait(&self) {
+        self.stop();
+
+        let mut schedule = self.shared.schedule();
+
+        while schedule.running != 0 {
+            schedule = self
+                .shared
+                .cond
+                .wait(schedule)
+                .unwrap_or_else(|e| e.into_inner());
+        }
+    }
+
+    /// same as `stop_and_wait`
+    pub fn stop_sync(&self) {
+        self.stop_and_wait();
+    }
+}
+
+fn run(shared: Arc<Shared>, generation: u64) {
+    let mut schedule = shared.schedule();
+
+    loop {
+        if schedule.generation != generation {
+            return;
+        }
+
+        let Some(due) = schedule.due else {
+            return;
+        };
+
+        let now = Instant::now();
+
+        if now < due {
+            schedule = shared
+                .cond
+                .wait_timeout(schedule, due - now)
+                .unwrap_or_else(|e| e.into_inner())
+                .0;
+            continue;
+        }
+
+        schedule.due = match (schedule.aligned, schedule.period.is_zero()) {
+            (Some(units), _) => next_boundary(units),
+            (None, true) => None,
+            (None, false) => due.checked_add(schedule.period),
+        };
+        schedule.running += 1;
+
+        drop(schedule);
+
+        shared.signaled.set();
+        (shared.callback.lock().unwrap_or_else(|e| e.into_inner()))();
+
+        schedule = shared.schedule();
+        schedule.running -= 1;
+
+        shared.cond.notify_all();
+    }
+}
+
+impl Dispatchable for Timer {
+    fn wait(&self, alertable: bool) -> WaitResult {
+        self.shared.signaled.wait(alertable)
+    }
+
+    fn wait_for(&self, timeout: Duration, alertable: bool) -> WaitResult {
+        self.shared.signaled.wait_for(timeout, alertable)
+    }
+}
+
+impl Drop for Timer {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+impl DelayRun for Timer {
+    fn delay_run<F: Fn() + Send + 'static>(f: F, after: Duration) -> Result<(), NtError> {
+        thread::Builder::new()
+            .spawn(move || {
+                thread::sleep(after);
+                f();
+            })
+            .map(|_| ())
+            .map_err(|_| NtError::new(STATUS_INSUFFICIENT_RESOURCES))
+    }
+}
@@ -0,0 +1,235 @@
+//! this module provides `SoftwareDriver`, the scaffold of a software-only(non-PnP) driver
+//!
+//! it creates the control device and its symbolic link, installs the dispatch routines and `DriverUnload`, so a
+//! driver only supplies the handlers of its IOCTLs:
+//! - IRP_MJ_CREATE, IRP_MJ_CLOSE and IRP_MJ_CLEANUP succeed
+//! - IRP_MJ_DEVICE_CONTROL is routed to the handler registered for the control code, an unknown code fails with
+//!   STATUS_INVALID_DEVICE_REQUEST
+//! - the other requests fail with STATUS_INVALID_DEVICE_REQUEST
+//!
+//! on unload the `on_unload` handler runs first, while the control device still exists, then the device and its
+//! symbolic link are deleted
+//!
+//! # Example
+//! ```
+//! #[unsafe(export_name = "DriverEntry")]
+//! pub unsafe extern "system" fn driver_entry(driver: PDRIVER_OBJECT, _: PCUNICODE_STRING) -> NTSTATUS {
+//!     let result = SoftwareDriver::new("Telemetry")
+//!         .symbolic_link("Telemetry")
+//!         .ioctl(IOCTL_QUERY_VERSION, |irp| irp.write_output(&VERSION))
+//!         .ioctl(IOCTL_QUERY_STATS, |irp| irp.write_output(&STATS.snapshot()))
+//!         .on_unload(|| events::shutdown())
+//!         .install(driver);
+//!
+//!     match result {
+//!         Ok(_) => STATUS_SUCCESS,
+//!         Err(e) => e.code(),
+//!     }
+//! }
+//! ```
+use alloc::{boxed::Box, vec::Vec};
+use wdk_sys::{
+    FILE_DEVICE_UNKNOWN, IRP_MJ_CLEANUP, IRP_MJ_CLOSE, IRP_MJ_CREATE, IRP_MJ_DEVICE_CONTROL,
+    PDEVICE_OBJECT, PDRIVER_OBJECT, PIRP, STATUS_INSUFFICIENT_RESOURCES,
+    STATUS_INVALID_DEVICE_REQUEST, STATUS_INVALID_DEVICE_STATE,
+};
+
+use crate::{
+    device::Device,
+    irp::Irp,
+    mutex::StaticSpinLocked,
+    ntstatus::NtError,
+    os,
+    sd::SecurityDescriptor,
+    utils::try_box,
+    wdm::{Driver, IrpDispatch},
+};
+
+/// the handler of an IOCTL, it returns the information of the IRP, i.e. the bytes written to the output
+pub type IoctlHandler = Box<dyn Fn(&mut Irp) -> Result<u64, NtError> + Send + Sync>;
+
+type UnloadHandler = Box<dyn FnOnce() + Send>;
+
+/// the dispatch handler of the control device
+struct Control {
+    ioctls: Vec<(u32, IoctlHandler)>,
+}
+
+impl IrpDispatch for Control {
+    fn dispatch(&self, _device: PDEVICE_OBJECT, irp: PIRP) -> Result<u64, NtError> {
+        let mut irp = unsafe { Irp::from_raw(irp) };
+
+        match irp.major_function() {
+            IRP_MJ_CREATE | IRP_MJ_CLOSE | IRP_MJ_CLEANUP => Ok(0),
+            IRP_MJ_DEVICE_CONTROL => {
+                let code = irp.ioctl_code();
+
+                let (_, handler) = self
+                    .ioctls
+                    .iter()
+                    .find(|(c, _)| *c == code)
+                    .ok_or_else(|| NtError::new(STATUS_INVALID_DEVICE_REQUEST))?;
+
+                handler(&mut irp)
+            }
+            _ => Err(NtError::new(STATUS_INVALID_DEVICE_REQUEST)),
+        }
+    }
+}
+
+/// the installed driver, dropped by `DriverUnload`
+struct Instance {
+    device: Device<Control>,
+    on_unload: Option<UnloadHandler>,
+}
+
+static INSTANCE: StaticSpinLocked<Option<Instance>> = StaticSpinLocked::new(None);
+
+/// The builder of a software-only driver, see the module documents
+pub struct SoftwareDriver<'a> {
+    name: &'a str,
+    symbolic_link: Option<&'a str>,
+    device_type: u32,
+    security: Option<&'a SecurityDescriptor>,
+    ioctls: Vec<(u32, IoctlHandler)>,
+    on_unload: Option<UnloadHandler>,
+    /// the first failed allocation of the builder, returned by `install`
+    error: Option<NtError>,
+}
+
+impl<'a> SoftwareDriver<'a> {
+    /// a driver with the control device `\Device\{name}` of FILE_DEVICE_UNKNOWN
+    pub fn new(name: &'a str) -> Self {
+        Self {
+            name,
+            symbolic_link: None,
+            device_type: FILE_DEVICE_UNKNOWN,
+            security: None,
+            ioctls: Vec::new(),
+            on_unload: None,
+            error: None,
+        }
+    }
+
+    /// keep the first error for `install`
+    fn fail(&mut self, e: NtError) {
+        if self.error.is_none() {
+            self.error = Some(e);
+        }
+    }
+
+    /// create the symbolic link `\DosDevices\{link}`, so user mode can open `\\.\{link}`
+    pub fn symbolic_link(mut self, link: &'a str) -> Self {
+        self.symbolic_link = Some(link);
+        self
+    }
+
+    pub fn device_type(mut self, device_type: u32) -> Self {
+        self.device_type = device_type;
+        self
+    }
+
+    /// replace the DACL of the control device by `sd` before it can be opened
+    pub fn security(mut self, sd: &'a SecurityDescriptor) -> Self {
+        self.security = Some(sd);
+        self
+    }
+
+    /// handle the IOCTL `code` with `handler`, it runs in the context of the caller at PASSIVE_LEVEL
+    ///
+    /// the handler of the same code registered later replaces the former one, a handler which can not be allocated
+    /// fails `install` with STATUS_INSUFFICIENT_RESOURCES
+    pub fn ioctl<F>(mut self, code: u32, handler: F) -> Self
+    where
+        F: Fn(&mut Irp) -> Result<u64, NtError> + Send + Sync + 'static,
+    {
+        self.ioctls.retain(|(c, _)| *c != code);
+
+        if self.ioctls.try_reserve(1).is_err() {
+            self.fail(NtError::new(STATUS_INSUFFICIENT_RESOURCES));
+            return self;
+        }
+
+        match try_box(handler) {
+            Ok(handler) => self.ioctls.push((code, handler as IoctlHandler)),
+            Err(e) => self.fail(e),
+        }
+
+        self
+    }
+
+    /// run `f` in `DriverUnload` before the control device is deleted, e.g. to stop the worker threads
+    pub fn on_unload<F: FnOnce() + Send + 'static>(mut self, f: F) -> Self {
+        match try_box(f) {
+            Ok(f) => self.on_unload = Some(f as UnloadHandler),
+            Err(e) => self.fail(e),
+        }
+
+        self
+    }
+
+    /// create the control device and install the dispatch routines and `DriverUnload` on `driver`
+    ///
+    /// it must be called once in `DriverEntry`, it fails with STATUS_INVALID_DEVICE_STATE if a driver is installed
+    /// already, the error of a handler which could not be allocated by the builder, or the error of the device
+    /// creation
+    pub fn install(self, driver: PDRIVER_OBJECT) -> Result<(), NtError> {
+        crate::assert_irql!(== PASSIVE_LEVEL);
+
+        if let Some(e) = self.error {
+            return Err(e);
+        }
+
+        // the version checks above PASSIVE_LEVEL see the exact build then
+        os::init();
+
+        if INSTANCE.lock().is_some() {
+            return Err(NtError::new(STATUS_INVALID_DEVICE_STATE));
+        }
+
+        // it only sets up the dispatch routines, the devices are owned by the instance
+        let _ = Driver::new(driver);
+
+        let control = Control {
+            ioctls: self.ioctls,
+        };
+
+        let mut device = match self.security {
+            Some(sd) => Device::create_secure(driver, self.name, self.device_type, control, sd)?,
+            None => Device::create(driver, self.name, self.device_type, control)?,
+        };
+
+        if let Some(link) = self.symbolic_link {
+            device.create_symbolic_link(link)?;
+        }
+
+        *INSTANCE.lock() = Some(Instance {
+            device,
+            on_unload: self.on_unload,
+        });
+
+        unsafe { (*driver).DriverUnload = Some(driver_unload_stub) };
+
+        Ok(())
+    }
+
+    /// the control device of the installed driver, e.g. to allocate the work items
+    pub fn device() -> Option<PDEVICE_OBJECT> {
+        INSTANCE
+            .lock()
+            .as_ref()
+            .map(|instance| instance.device.as_raw())
+    }
+}
+
+extern "C" fn driver_unload_stub(_driver: PDRIVER_OBJECT) {
+    let instance = INSTANCE.lock().take();
+
+    if let Some(mut instance) = instance {
+        if let Some(on_unload) = instance.on_unload.take() {
+            on_unload();
+        }
+
+        drop(instance);
+    }
+}
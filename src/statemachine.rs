@@ -0,0 +1,200 @@
+//! this module provides `StateMachine`, an atomic lifecycle state which threads can wait on
+//!
+//! the state moves with compare-exchange, so of two racing transitions from the same state only one wins, e.g. a
+//! request arriving after the teardown has started sees `transition(Running, Running)` fail instead of using freed
+//! resources
+//!
+//! the legal transitions can be declared with `transitions!`, a transition not in the list fails a debug assertion
+//!
+//! # Example
+//! ```
+//! #[derive(Clone, Copy, PartialEq, Eq)]
+//! enum State {
+//!     Init,
+//!     Running,
+//!     Stopping,
+//!     Stopped,
+//! }
+//!
+//! impl From<State> for u32 {
+//!     fn from(state: State) -> u32 {
+//!         state as u32
+//!     }
+//! }
+//!
+//! let state = StateMachine::with_transitions(
+//!     State::Init,
+//!     transitions![
+//!         State::Init => State::Running,
+//!         State::Running => State::Stopping,
+//!         State::Stopping => State::Stopped,
+//!     ],
+//! );
+//!
+//! // in DriverEntry
+//! state.transition(State::Init, State::Running);
+//!
+//! // in DriverUnload
+//! if state.transition(State::Running, State::Stopping) {
+//!     stop_workers();
+//!     state.transition(State::Stopping, State::Stopped);
+//! }
+//!
+//! // in a worker which must not outlive the teardown
+//! state.wait_for(State::Stopped, Duration::from_secs(5))?;
+//! ```
+use core::{
+    marker::PhantomData,
+    sync::atomic::{AtomicU32, Ordering},
+    time::Duration,
+};
+
+use alloc::vec::Vec;
+use wdk_sys::STATUS_INSUFFICIENT_RESOURCES;
+
+use crate::{
+    event::{Event, EventProperty},
+    kobject::Dispatchable,
+    mutex::StaticSpinLocked,
+    ntstatus::NtError,
+};
+
+/// declare the legal transitions of a `StateMachine`, it expands to a `fn(S, S) -> bool`
+///
+/// every side of a transition is a pattern, so `State::Stopping | State::Running => State::Stopped` is accepted
+#[macro_export]
+macro_rules! transitions {
+    ($($from:pat => $to:pat),* $(,)?) => {
+        |from, to| matches!((from, to), $(($from, $to))|*)
+    };
+}
+
+/// a thread waiting for `state`, its event lives on the stack of `wait_for`
+struct Waiter {
+    state: u32,
+    event: *const Event,
+}
+
+// the event is only set while the waiter is in the list
+unsafe impl Send for Waiter {}
+
+/// An atomic state, see the module documents
+pub struct StateMachine<S> {
+    state: AtomicU32,
+    legal: Option<fn(S, S) -> bool>,
+    waiters: StaticSpinLocked<Vec<Waiter>>,
+    _marker: PhantomData<fn() -> S>,
+}
+
+impl<S: Copy + Into<u32>> StateMachine<S> {
+    /// a state machine which accepts any transition
+    pub fn new(initial: S) -> Self {
+        Self {
+            state: AtomicU32::new(initial.into()),
+            legal: None,
+            waiters: StaticSpinLocked::new(Vec::new()),
+            _marker: PhantomData,
+        }
+    }
+
+    /// a state machine which checks every transition with `legal` in the debug builds, see `transitions!`
+    pub fn with_transitions(initial: S, legal: fn(S, S) -> bool) -> Self {
+        Self {
+            legal: Some(legal),
+            ..Self::new(initial)
+        }
+    }
+
+    /// move from `from` to `to` if the state is `from`, returns false if it is not
+    ///
+    /// it can be called at IRQL <= DISPATCH_LEVEL, the threads waiting for `to` are released
+    pub fn transition(&self, from: S, to: S) -> bool {
+        debug_assert!(
+            self.legal.is_none_or(|legal| legal(from, to)),
+            "illegal state transition {} -> {}",
+            from.into(),
+            to.into()
+        );
+
+        let moved = self
+            .state
+            .compare_exchange(from.into(), to.into(), Ordering::AcqRel, Ordering::Acquire)
+            .is_ok();
+
+        if moved {
+            self.notify(to.into());
+        }
+
+        moved
+    }
+
+    /// move to `to` whatever the state is, returns the raw value of the old state
+    ///
+    /// it bypasses the legal transitions, e.g. for an error path
+    pub fn force(&self, to: S) -> u32 {
+        let old = self.state.swap(to.into(), Ordering::AcqRel);
+
+        self.notify(to.into());
+
+        old
+    }
+
+    /// the raw value of the current state
+    pub fn current_raw(&self) -> u32 {
+        self.state.load(Ordering::Acquire)
+    }
+
+    pub fn is(&self, state: S) -> bool {
+        self.current_raw() == state.into()
+    }
+
+    /// wait until the state becomes `state`, returns false on timeout, it must be called at IRQL <= APC_LEVEL
+    ///
+    /// it fails with STATUS_INSUFFICIENT_RESOURCES if the wait can not be set up
+    pub fn wait_for(&self, state: S, timeout: Duration) -> Result<bool, NtError> {
+        crate::assert_irql!(<= APC_LEVEL);
+
+        let state = state.into();
+        let event = EventProperty::new().new_event()?;
+
+        {
+            let mut waiters = self.waiters.lock();
+
+            // checked under the lock of the waiters, so a transition made after it must signal the event
+            if self.current_raw() == state {
+                return Ok(true);
+            }
+
+            waiters
+                .try_reserve(1)
+                .map_err(|_| NtError::new(STATUS_INSUFFICIENT_RESOURCES))?;
+            waiters.push(Waiter {
+                state,
+                event: &event,
+            });
+        }
+
+        let reached = !event.wait_for(timeout, false).timed_out();
+
+        self.waiters
+            .lock()
+            .retain(|waiter| !core::ptr::eq(waiter.event, &event));
+
+        Ok(reached)
+    }
+
+    fn notify(&self, state: u32) {
+        let waiters = self.waiters.lock();
+
+        for waiter in waiters.iter().filter(|waiter| waiter.state == state) {
+            unsafe { (*waiter.event).set() };
+        }
+    }
+}
+
+impl<S: Copy + Into<u32> + TryFrom<u32>> StateMachine<S> {
+    /// the current state, `None` if the raw value is not a valid `S`
+    pub fn current(&self) -> Option<S> {
+        S::try_from(self.current_raw()).ok()
+    }
+}
@@ -0,0 +1,345 @@
+//! this module provides the statistics of timing probes
+//!
+//! a `Probe` is a static counter of a call site, it records the count and the min/max/total elapsed time of the
+//! measured scopes, a probe registers itself in a global lock-free list on its first record, so all the probes
+//! which have run can be enumerated with `probes()`
+//!
+//! recording never allocates or waits, so the probes can be used at any IRQL
+//!
+//! the locks keep their contention statistics in a `LockCounters` once `enable_stats` is called on them, see
+//! `Locked::lock_stats`, it requires the `lock-stats` feature, without it the locks carry no counters
+//!
+//! # Example
+//! ```
+//! fn process_packet(&self, packet: &Packet) {
+//!     time_scope!("process_packet");
+//!
+//!     let mut queue = self.queue.lock().unwrap();
+//!     queue.push(packet);
+//! }
+//!
+//! // later, e.g. in an IOCTL
+//! for probe in stats::probes() {
+//!     let stats = probe.snapshot();
+//!     println!("{}: {} calls, avg {:?}, max {:?}", stats.name, stats.count, stats.avg, stats.max);
+//! }
+//! ```
+use core::{
+    ptr,
+    sync::atomic::{AtomicBool, AtomicPtr, AtomicU64, Ordering},
+    time::Duration,
+};
+
+#[cfg(feature = "fault-injection")]
+use crate::fault::{self, FaultSite};
+use crate::time::KInstant;
+
+/// the head of the registered probes
+static PROBES: AtomicPtr<Probe> = AtomicPtr::new(ptr::null_mut());
+
+/// A timing probe of a call site, it must be a `static`
+pub struct Probe {
+    name: &'static str,
+    file: &'static str,
+    line: u32,
+    count: AtomicU64,
+    /// in nanoseconds
+    total: AtomicU64,
+    min: AtomicU64,
+    max: AtomicU64,
+    registered: AtomicBool,
+    next: AtomicPtr<Probe>,
+}
+
+impl Probe {
+    pub const fn new(name: &'static str, file: &'static str, line: u32) -> Self {
+        Self {
+            name,
+            file,
+            line,
+            count: AtomicU64::new(0),
+            total: AtomicU64::new(0),
+            min: AtomicU64::new(u64::MAX),
+            max: AtomicU64::new(0),
+            registered: AtomicBool::new(false),
+            next: AtomicPtr::new(ptr::null_mut()),
+        }
+    }
+
+    /// start measuring a scope, the elapsed time is recorded when the returned guard is dropped
+    pub fn start(&'static self) -> ScopeTimer {
+        ScopeTimer {
+            probe: self,
+            start: KInstant::now(),
+        }
+    }
+
+    /// record a measured duration
+    pub fn record(&'static self, elapsed: Duration) {
+        if !self.registered.swap(true, Ordering::AcqRel) {
+            self.register();
+        }
+
+        let nanos = u64::try_from(elapsed.as_nanos()).unwrap_or(u64::MAX);
+
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.total.fetch_add(nanos, Ordering::Relaxed);
+        self.min.fetch_min(nanos, Ordering::Relaxed);
+        self.max.fetch_max(nanos, Ordering::Relaxed);
+    }
+
+    fn register(&'static self) {
+        let this = self as *const Probe as *mut Probe;
+        let mut head = PROBES.load(Ordering::Acquire);
+
+        loop {
+            self.next.store(head, Ordering::Relaxed);
+
+            match PROBES.compare_exchange_weak(head, this, Ordering::AcqRel, Ordering::Acquire) {
+                Ok(_) => break,
+                Err(current) => head = current,
+            }
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    /// the source location of the probe
+    pub fn location(&self) -> (&'static str, u32) {
+        (self.file, self.line)
+    }
+
+    /// a copy of the current statistics, the fields are read separately so they may be slightly inconsistent
+    /// if the probe is being recorded concurrently
+    pub fn snapshot(&self) -> ProbeStats {
+        let count = self.count.load(Ordering::Relaxed);
+        let total = self.total.load(Ordering::Relaxed);
+        let min = self.min.load(Ordering::Relaxed);
+        let max = self.max.load(Ordering::Relaxed);
+
+        ProbeStats {
+            name: self.name,
+            file: self.file,
+            line: self.line,
+            count,
+            total: Duration::from_nanos(total),
+            min: if count == 0 {
+                Duration::ZERO
+            } else {
+                Duration::from_nanos(min)
+            },
+            max: Duration::from_nanos(max),
+            avg: if count == 0 {
+                Duration::ZERO
+            } else {
+                Duration::from_nanos(total / count)
+            },
+        }
+    }
+
+    /// clear the statistics, the probe stays registered
+    pub fn reset(&self) {
+        self.count.store(0, Ordering::Relaxed);
+        self.total.store(0, Ordering::Relaxed);
+        self.min.store(u64::MAX, Ordering::Relaxed);
+        self.max.store(0, Ordering::Relaxed);
+    }
+}
+
+/// The statistics of a probe
+#[derive(Debug, Clone, Copy)]
+pub struct ProbeStats {
+    pub name: &'static str,
+    pub file: &'static str,
+    pub line: u32,
+    pub count: u64,
+    pub total: Duration,
+    pub min: Duration,
+    pub max: Duration,
+    pub avg: Duration,
+}
+
+/// An RAII guard which records the elapsed time into its probe on drop
+pub struct ScopeTimer {
+    probe: &'static Probe,
+    start: KInstant,
+}
+
+impl ScopeTimer {
+    /// the elapsed time so far
+    pub fn elapsed(&self) -> Duration {
+        self.start.elapsed()
+    }
+}
+
+impl Drop for ScopeTimer {
+    fn drop(&mut self) {
+        self.probe.record(self.start.elapsed());
+    }
+}
+
+/// An iterator of the registered probes, the latest registered first
+pub struct Probes(*const Probe);
+
+impl Iterator for Probes {
+    type Item = &'static Probe;
+    fn next(&mut self) -> Option<Self::Item> {
+        let probe = unsafe { self.0.as_ref() }?;
+
+        self.0 = probe.next.load(Ordering::Acquire);
+
+        Some(probe)
+    }
+}
+
+/// enumerate the probes which have recorded at least once
+pub fn probes() -> Probes {
+    Probes(PROBES.load(Ordering::Acquire))
+}
+
+/// clear the statistics of all the registered probes
+pub fn reset_all() {
+    probes().for_each(|probe| probe.reset());
+}
+
+/// The contention counters of a lock, all zero means disabled
+///
+/// the lock records every acquisition once it is enabled, a zeroed `LockCounters` is valid
+pub struct LockCounters {
+    enabled: AtomicBool,
+    acquisitions: AtomicU64,
+    contentions: AtomicU64,
+    /// in nanoseconds
+    max_wait: AtomicU64,
+    total_hold: AtomicU64,
+}
+
+impl LockCounters {
+    pub const fn new() -> Self {
+        Self {
+            enabled: AtomicBool::new(false),
+            acquisitions: AtomicU64::new(0),
+            contentions: AtomicU64::new(0),
+            max_wait: AtomicU64::new(0),
+            total_hold: AtomicU64::new(0),
+        }
+    }
+
+    pub fn enable(&self) {
+        self.enabled.store(true, Ordering::Relaxed);
+    }
+
+    #[inline]
+    pub fn is_enabled(&self) -> bool {
+        self.enabled.load(Ordering::Relaxed)
+    }
+
+    /// record an acquisition, `wait` is the time spent acquiring the lock
+    pub fn record_acquire(&self, contended: bool, wait: Duration) {
+        self.acquisitions.fetch_add(1, Ordering::Relaxed);
+
+        if contended {
+            let nanos = u64::try_from(wait.as_nanos()).unwrap_or(u64::MAX);
+
+            self.contentions.fetch_add(1, Ordering::Relaxed);
+            self.max_wait.fetch_max(nanos, Ordering::Relaxed);
+        }
+    }
+
+    /// record a release, `held` is the time since the acquisition
+    pub fn record_release(&self, held: Duration) {
+        let nanos = u64::try_from(held.as_nanos()).unwrap_or(u64::MAX);
+
+        self.total_hold.fetch_add(nanos, Ordering::Relaxed);
+    }
+
+    /// clear the counters, the lock stays enabled
+    pub fn reset(&self) {
+        self.acquisitions.store(0, Ordering::Relaxed);
+        self.contentions.store(0, Ordering::Relaxed);
+        self.max_wait.store(0, Ordering::Relaxed);
+        self.total_hold.store(0, Ordering::Relaxed);
+    }
+
+    /// a copy of the counters, `None` if the statistics are not enabled
+    pub fn snapshot(&self) -> Option<LockStats> {
+        if !self.is_enabled() {
+            return None;
+        }
+
+        Some(LockStats {
+            acquisitions: self.acquisitions.load(Ordering::Relaxed),
+            contentions: self.contentions.load(Ordering::Relaxed),
+            max_wait: Duration::from_nanos(self.max_wait.load(Ordering::Relaxed)),
+            total_hold: Duration::from_nanos(self.total_hold.load(Ordering::Relaxed)),
+        })
+    }
+}
+
+impl Default for LockCounters {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The contention statistics of a lock
+#[derive(Debug, Clone, Copy, Default)]
+pub struct LockStats {
+    pub acquisitions: u64,
+    /// the acquisitions which found the lock held
+    pub contentions: u64,
+    /// the longest wait of a contended acquisition
+    pub max_wait: Duration,
+    /// the total time the lock was held, a hold is counted when it is released
+    pub total_hold: Duration,
+}
+
+impl LockStats {
+    /// the contended acquisitions in percent
+    pub fn contention_rate(&self) -> u32 {
+        match self.acquisitions {
+            0 => 0,
+            n => (self.contentions * 100 / n) as u32,
+        }
+    }
+}
+
+/// The number of the failures injected by the `fault` module
+#[cfg(feature = "fault-injection")]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct FaultStats {
+    pub allocations: u64,
+    pub try_locks: u64,
+}
+
+/// the failures injected since the start or the last `fault::reset_counters`
+#[cfg(feature = "fault-injection")]
+pub fn faults() -> FaultStats {
+    FaultStats {
+        allocations: fault::injected(FaultSite::Allocation),
+        try_locks: fault::injected(FaultSite::TryLock),
+    }
+}
+
+/// measure the time from here to the end of the enclosing scope, the statistics are recorded in a static
+/// `Probe` of this call site
+///
+/// # Example
+/// ```
+/// {
+///     time_scope!("flush");
+///     flush_cache();
+/// }
+/// ```
+#[macro_export]
+macro_rules! time_scope {
+    ($name:expr) => {
+        let _time_scope = {
+            static PROBE: $crate::stats::Probe = $crate::stats::Probe::new($name, file!(), line!());
+
+            PROBE.start()
+        };
+    };
+}
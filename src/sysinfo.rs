@@ -0,0 +1,312 @@
+//! this module provides typed queries of ZwQuerySystemInformation
+//!
+//! the buffer is sized automatically and the query is retried while the information grows between the calls,
+//! the results are copied into owned structs so nothing refers to the query buffer
+//!
+//! all the queries must be called at PASSIVE_LEVEL
+//!
+//! # Example
+//! ```
+//! for process in sysinfo::processes()? {
+//!     println!("{} {} threads: {}", process.pid, process.name, process.thread_count);
+//! }
+//!
+//! let ntoskrnl = sysinfo::kernel_modules()?.next();
+//! ```
+use core::{mem, ptr, slice, time::Duration};
+
+use alloc::{string::String, vec::Vec};
+use wdk_sys::{
+    CLIENT_ID, HANDLE, LARGE_INTEGER, LONG, NTSTATUS, PULONG, PVOID, SIZE_T,
+    STATUS_BUFFER_TOO_SMALL, STATUS_INFO_LENGTH_MISMATCH, STATUS_INSUFFICIENT_RESOURCES, ULONG,
+    ULONG_PTR, UNICODE_STRING,
+};
+
+use crate::{
+    ntstatus::{NtError, cvt},
+    time::{self, KSystemTime},
+    utils,
+};
+
+unsafe extern "C" {
+    pub fn ZwQuerySystemInformation(
+        SystemInformationClass: ULONG,
+        SystemInformation: PVOID,
+        SystemInformationLength: ULONG,
+        ReturnLength: PULONG,
+    ) -> NTSTATUS;
+}
+
+pub const SystemProcessInformation: ULONG = 5;
+pub const SystemModuleInformation: ULONG = 11;
+
+/// the extra space in case the information grows between the calls
+const SLACK: usize = 0x1000;
+
+#[repr(C)]
+pub struct SYSTEM_PROCESS_INFORMATION {
+    pub NextEntryOffset: ULONG,
+    pub NumberOfThreads: ULONG,
+    pub WorkingSetPrivateSize: LARGE_INTEGER,
+    pub HardFaultCount: ULONG,
+    pub NumberOfThreadsHighWatermark: ULONG,
+    pub CycleTime: u64,
+    pub CreateTime: LARGE_INTEGER,
+    pub UserTime: LARGE_INTEGER,
+    pub KernelTime: LARGE_INTEGER,
+    pub ImageName: UNICODE_STRING,
+    pub BasePriority: LONG,
+    pub UniqueProcessId: HANDLE,
+    pub InheritedFromUniqueProcessId: HANDLE,
+    pub HandleCount: ULONG,
+    pub SessionId: ULONG,
+    pub UniqueProcessKey: ULONG_PTR,
+    pub PeakVirtualSize: SIZE_T,
+    pub VirtualSize: SIZE_T,
+    pub PageFaultCount: ULONG,
+    pub PeakWorkingSetSize: SIZE_T,
+    pub WorkingSetSize: SIZE_T,
+    pub QuotaPeakPagedPoolUsage: SIZE_T,
+    pub QuotaPagedPoolUsage: SIZE_T,
+    pub QuotaPeakNonPagedPoolUsage: SIZE_T,
+    pub QuotaNonPagedPoolUsage: SIZE_T,
+    pub PagefileUsage: SIZE_T,
+    pub PeakPagefileUsage: SIZE_T,
+    pub PrivatePageCount: SIZE_T,
+    pub ReadOperationCount: LARGE_INTEGER,
+    pub WriteOperationCount: LARGE_INTEGER,
+    pub OtherOperationCount: LARGE_INTEGER,
+    pub ReadTransferCount: LARGE_INTEGER,
+    pub WriteTransferCount: LARGE_INTEGER,
+    pub OtherTransferCount: LARGE_INTEGER,
+}
+
+/// follows `SYSTEM_PROCESS_INFORMATION`, one per thread of the process
+#[repr(C)]
+pub struct SYSTEM_THREAD_INFORMATION {
+    pub KernelTime: LARGE_INTEGER,
+    pub UserTime: LARGE_INTEGER,
+    pub CreateTime: LARGE_INTEGER,
+    pub WaitTime: ULONG,
+    pub StartAddress: PVOID,
+    pub ClientId: CLIENT_ID,
+    pub Priority: LONG,
+    pub BasePriority: LONG,
+    pub ContextSwitches: ULONG,
+    pub ThreadState: ULONG,
+    pub WaitReason: ULONG,
+}
+
+#[repr(C)]
+pub struct RTL_PROCESS_MODULE_INFORMATION {
+    pub Section: HANDLE,
+    pub MappedBase: PVOID,
+    pub ImageBase: PVOID,
+    pub ImageSize: ULONG,
+    pub Flags: ULONG,
+    pub LoadOrderIndex: u16,
+    pub InitOrderIndex: u16,
+    pub LoadCount: u16,
+    pub OffsetToFileName: u16,
+    pub FullPathName: [u8; 256],
+}
+
+#[repr(C)]
+pub struct RTL_PROCESS_MODULES {
+    pub NumberOfModules: ULONG,
+    pub Modules: [RTL_PROCESS_MODULE_INFORMATION; 1],
+}
+
+/// query the information of `class` into an 8-byte aligned buffer
+///
+/// the buffer is grown and the query retried on STATUS_INFO_LENGTH_MISMATCH or STATUS_BUFFER_TOO_SMALL
+pub fn query(class: ULONG) -> Result<Vec<u64>, NtError> {
+    let mut required: ULONG = 0;
+
+    let _ = unsafe { ZwQuerySystemInformation(class, ptr::null_mut(), 0, &mut required) };
+
+    loop {
+        let words = (required as usize + SLACK).div_ceil(mem::size_of::<u64>());
+
+        let mut buffer: Vec<u64> = Vec::new();
+
+        buffer
+            .try_reserve_exact(words)
+            .map_err(|_| NtError::new(STATUS_INSUFFICIENT_RESOURCES))?;
+
+        buffer.resize(words, 0);
+
+        let status = unsafe {
+            ZwQuerySystemInformation(
+                class,
+                buffer.as_mut_ptr().cast(),
+                (words * mem::size_of::<u64>()) as _,
+                &mut required,
+            )
+        };
+
+        match status {
+            STATUS_INFO_LENGTH_MISMATCH | STATUS_BUFFER_TOO_SMALL => continue,
+            status => cvt(status)?,
+        }
+
+        return Ok(buffer);
+    }
+}
+
+/// A snapshot of a process
+#[derive(Debug, Clone)]
+pub struct ProcessInfo {
+    pub pid: usize,
+    pub parent_pid: usize,
+    /// the image file name, empty for the idle process
+    pub name: String,
+    pub thread_count: u32,
+    pub handle_count: u32,
+    pub session_id: u32,
+    pub base_priority: i32,
+    pub create_time: KSystemTime,
+    pub user_time: Duration,
+    pub kernel_time: Duration,
+    pub working_set_size: usize,
+    pub peak_working_set_size: usize,
+    pub virtual_size: usize,
+    pub private_bytes: usize,
+    pub thread_ids: Vec<u32>,
+}
+
+impl ProcessInfo {
+    fn new(info: &SYSTEM_PROCESS_INFORMATION) -> Self {
+        let threads = unsafe {
+            slice::from_raw_parts(
+                (info as *const SYSTEM_PROCESS_INFORMATION)
+                    .add(1)
+                    .cast::<SYSTEM_THREAD_INFORMATION>(),
+                info.NumberOfThreads as _,
+            )
+        };
+
+        unsafe {
+            Self {
+                pid: info.UniqueProcessId as _,
+                parent_pid: info.InheritedFromUniqueProcessId as _,
+                name: utils::unicode_to_string(&info.ImageName as *const _ as _)
+                    .unwrap_or_default(),
+                thread_count: info.NumberOfThreads,
+                handle_count: info.HandleCount,
+                session_id: info.SessionId,
+                base_priority: info.BasePriority,
+                create_time: KSystemTime::from_large_integer(info.CreateTime),
+                user_time: time::from_units(info.UserTime.QuadPart),
+                kernel_time: time::from_units(info.KernelTime.QuadPart),
+                working_set_size: info.WorkingSetSize as _,
+                peak_working_set_size: info.PeakWorkingSetSize as _,
+                virtual_size: info.VirtualSize as _,
+                private_bytes: info.PagefileUsage as _,
+                thread_ids: threads
+                    .iter()
+                    .map(|thread| thread.ClientId.UniqueThread as usize as u32)
+                    .collect(),
+            }
+        }
+    }
+}
+
+/// An iterator of the processes in a snapshot
+pub struct Processes {
+    buffer: Vec<u64>,
+    offset: Option<usize>,
+}
+
+impl Iterator for Processes {
+    type Item = ProcessInfo;
+    fn next(&mut self) -> Option<Self::Item> {
+        let offset = self.offset?;
+
+        let info = unsafe {
+            &*(self.buffer.as_ptr() as *const u8)
+                .add(offset)
+                .cast::<SYSTEM_PROCESS_INFORMATION>()
+        };
+
+        self.offset = match info.NextEntryOffset {
+            0 => None,
+            next => Some(offset + next as usize),
+        };
+
+        Some(ProcessInfo::new(info))
+    }
+}
+
+/// take a snapshot of the running processes
+pub fn processes() -> Result<Processes, NtError> {
+    let buffer = query(SystemProcessInformation)?;
+
+    Ok(Processes {
+        buffer,
+        offset: Some(0),
+    })
+}
+
+/// A snapshot of a loaded kernel module
+#[derive(Debug, Clone)]
+pub struct ModuleInfo {
+    pub image_base: usize,
+    pub image_size: u32,
+    pub load_order: u16,
+    /// the file name, e.g. "ntoskrnl.exe"
+    pub name: String,
+    /// the full path, e.g. "\SystemRoot\system32\ntoskrnl.exe"
+    pub full_path: String,
+}
+
+impl ModuleInfo {
+    fn new(info: &RTL_PROCESS_MODULE_INFORMATION) -> Self {
+        let len = info
+            .FullPathName
+            .iter()
+            .position(|c| *c == 0)
+            .unwrap_or(info.FullPathName.len());
+
+        let path = &info.FullPathName[..len];
+        let name = path.get(info.OffsetToFileName as usize..).unwrap_or(path);
+
+        Self {
+            image_base: info.ImageBase as _,
+            image_size: info.ImageSize,
+            load_order: info.LoadOrderIndex,
+            name: String::from_utf8_lossy(name).into(),
+            full_path: String::from_utf8_lossy(path).into(),
+        }
+    }
+}
+
+/// An iterator of the kernel modules in a snapshot, the kernel image comes first
+pub struct Modules {
+    buffer: Vec<u64>,
+    index: usize,
+}
+
+impl Iterator for Modules {
+    type Item = ModuleInfo;
+    fn next(&mut self) -> Option<Self::Item> {
+        let modules = unsafe { &*(self.buffer.as_ptr().cast::<RTL_PROCESS_MODULES>()) };
+
+        if self.index >= modules.NumberOfModules as usize {
+            return None;
+        }
+
+        let info = unsafe { &*modules.Modules.as_ptr().add(self.index) };
+
+        self.index += 1;
+
+        Some(ModuleInfo::new(info))
+    }
+}
+
+/// take a snapshot of the loaded kernel modules
+pub fn kernel_modules() -> Result<Modules, NtError> {
+    let buffer = query(SystemModuleInformation)?;
+
+    Ok(Modules { buffer, index: 0 })
+}
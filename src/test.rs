@@ -469,3 +469,79 @@ fn test_workitem() {
         );
     }
 }
+
+fn test_rcu() {
+    let config = Arc::new(crate::rcu::Rcu::new(0u32));
+
+    // readers
+    for _ in 0..4 {
+        let config = config.clone();
+
+        let _ = spawn(move || {
+            for _ in 0..100 {
+                let value = config.read();
+                println!("thread {} read config: {}", this_thread::id(), *value);
+            }
+        });
+    }
+
+    // writer
+    for i in 1..10 {
+        let _ = config.update(|old| *old + i);
+        this_thread::sleep(Duration::from_millis(10));
+    }
+}
+
+fn test_karc() {
+    let shared = crate::arc::KArc::new(GuardLocked::new(0u32).unwrap()).unwrap();
+    let observer = crate::arc::KArc::downgrade(&shared);
+
+    let mut handles: Vec<JoinHandle> = Vec::new();
+
+    for _ in 0..4 {
+        let counter = shared.clone();
+
+        handles.push(
+            spawn(move || {
+                for _ in 0..100 {
+                    if let Ok(mut guard) = counter.lock() {
+                        *guard += 1;
+                    }
+                }
+            })
+            .unwrap(),
+        );
+    }
+
+    for h in handles {
+        h.join().expect("join thread failed");
+    }
+
+    drop(shared);
+
+    // all the strong references are gone
+    println!("upgrade after drop: {}", observer.upgrade().is_some());
+}
+
+fn test_ring_buffer() {
+    let (producer, consumer) = crate::ring::RingBuffer::<u32>::new(16).unwrap();
+
+    let reader = spawn(move || {
+        let mut sum = 0u32;
+
+        for _ in 0..100 {
+            sum += consumer.pop_wait();
+        }
+
+        println!("ring buffer sum: {}", sum);
+    })
+    .unwrap();
+
+    for i in 0..100 {
+        while producer.push(i).is_err() {
+            this_thread::sleep(Duration::from_millis(1));
+        }
+    }
+
+    reader.join().expect("join thread failed");
+}
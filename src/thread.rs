@@ -1,25 +1,45 @@
+use core::cell::UnsafeCell;
+use core::fmt;
 use core::mem::MaybeUninit;
 use core::num::NonZero;
 use core::ops::{Deref, DerefMut};
+use core::sync::atomic::{AtomicBool, AtomicI32, AtomicPtr, Ordering};
 use core::{mem, ptr};
 
-use alloc::boxed::Box;
+use alloc::{boxed::Box, vec::Vec};
 use wdk::nt_success;
 use wdk_sys::LARGE_INTEGER;
-use wdk_sys::ntddk::{KeQueryActiveProcessorCount, ObfDereferenceObject};
+use wdk_sys::ntddk::{
+    KeQueryActiveProcessorCount, KeQueryPriorityThread, KeSetPriorityThread,
+    MmGetSystemRoutineAddress, ObOpenObjectByPointer, ObfDereferenceObject, ObfReferenceObject,
+    PsGetThreadId, PsGetThreadProcessId, PsTerminateSystemThread, ZwSetInformationThread,
+};
 use wdk_sys::{
+    _KEVENT, _KTHREAD,
     _KWAIT_REASON::Executive,
     _MODE::KernelMode,
-    _THREADINFOCLASS::ThreadBasicInformation,
-    CLIENT_ID, FALSE, GENERIC_ALL, HANDLE, LONG, NTSTATUS, OBJ_KERNEL_HANDLE, PETHREAD, PULONG,
-    PVOID, PsThreadType, STATUS_SUCCESS, THREAD_QUERY_LIMITED_INFORMATION, ULONG,
-    ntddk::{KeWaitForSingleObject, ObReferenceObjectByHandle, PsCreateSystemThread, ZwClose},
+    _THREADINFOCLASS::{ThreadAffinityMask, ThreadBasicInformation},
+    BOOLEAN, CLIENT_ID, FALSE, GENERIC_ALL, HANDLE, KAFFINITY, KPRIORITY, KPROCESSOR_MODE, LONG,
+    NTSTATUS, OBJ_KERNEL_HANDLE, PETHREAD, PKTHREAD, PULONG, PVOID, PsThreadType,
+    STATUS_INSUFFICIENT_RESOURCES, STATUS_INVALID_CID, STATUS_NOT_SUPPORTED, STATUS_SUCCESS,
+    STATUS_UNHANDLED_EXCEPTION, SYNCHRONIZE, THREAD_QUERY_LIMITED_INFORMATION,
+    THREAD_SET_INFORMATION, ULONG,
+    ntddk::{KeWaitForSingleObject, PsCreateSystemThread, ZwClose},
 };
 
 use crate::NtCurrentProcess;
 use crate::{
-    initialize_object_attributes,
+    arc::KArc,
+    event::NotificationEvent,
+    fmt::StackString,
+    handle_to_ulong, initialize_object_attributes,
+    kobject::{Dispatchable, FromThreadId, ThreadObject},
+    mutex::StaticSpinLocked,
     ntstatus::{NtError, cvt},
+    raw::AsRawObject,
+    sysinfo, tls,
+    utils::{self, KeGetCurrentThread, try_box},
+    waitgroup::{WaitGroup, WaitGroupToken},
 };
 
 #[repr(C)]
@@ -47,6 +67,11 @@ unsafe extern "C" {
         ReturnLength: PULONG,
     ) -> NTSTATUS;
 
+    pub fn KeAlertThread(Thread: PKTHREAD, AlertMode: KPROCESSOR_MODE) -> BOOLEAN;
+
+    pub fn KeTestAlertThread(AlertMode: KPROCESSOR_MODE) -> BOOLEAN;
+
+    pub fn PsIsThreadTerminating(Thread: PETHREAD) -> BOOLEAN;
 }
 
 #[repr(transparent)]
@@ -77,33 +102,46 @@ impl DerefMut for OwnedHandle {
     }
 }
 
-#[repr(transparent)]
-pub struct JoinHandle(OwnedHandle);
+/// An owned thread handle together with a reference to the thread object, which is taken once at spawn
+///
+/// it is `Dispatchable`, so a thread can be waited together with other objects, see `wait::MultiWait`
+pub struct JoinHandle {
+    handle: OwnedHandle,
+    thread: ThreadObject,
+    exit: KArc<ExitState>,
+    /// the token handed to the thread by `Builder::spawn`
+    stop: Option<StopToken>,
+}
 
 impl JoinHandle {
-    pub fn is_finished(&self) -> bool {
-        let mut thread: PVOID = ptr::null_mut();
+    /// the token of a thread spawned by `Builder`
+    pub fn stop_token(&self) -> Option<&StopToken> {
+        self.stop.as_ref()
+    }
 
-        let mut status = unsafe {
-            ObReferenceObjectByHandle(
-                *self.0,
-                THREAD_QUERY_LIMITED_INFORMATION,
-                *PsThreadType,
-                KernelMode as _,
-                &mut thread,
-                ptr::null_mut(),
-            )
-        };
+    /// ask a thread spawned by `Builder` to stop, returns false if the thread has no token
+    pub fn request_stop(&self) -> bool {
+        self.stop.as_ref().map(StopToken::stop).is_some()
+    }
 
-        if !nt_success(status) {
-            return false;
-        }
+    /// a `Waker` that interrupts the alertable sleeps of this thread
+    pub fn waker(&self) -> Result<Waker, NtError> {
+        unsafe { ObfReferenceObject(self.thread.as_ptr().cast()) };
 
+        ThreadObject::new(self.thread.as_ptr()).map(Waker)
+    }
+
+    /// the referenced thread object
+    pub fn thread(&self) -> &ThreadObject {
+        &self.thread
+    }
+
+    pub fn is_finished(&self) -> bool {
         let mut timeout = LARGE_INTEGER { QuadPart: 0 };
 
-        status = unsafe {
+        let status = unsafe {
             KeWaitForSingleObject(
-                thread,
+                self.thread.as_ptr().cast(),
                 Executive as _,
                 KernelMode as _,
                 FALSE as _,
@@ -114,25 +152,15 @@ impl JoinHandle {
         status == STATUS_SUCCESS
     }
 
-    pub fn join(self) -> Result<NTSTATUS, NtError> {
-        let mut thread: PVOID = ptr::null_mut();
+    /// wait for the thread to exit and return its exit status, see `this_thread::set_exit_status`
+    ///
+    /// it fails with `JoinError::ThreadPanicked` if the thread panicked, see `exit_on_panic`
+    pub fn join(self) -> Result<NTSTATUS, JoinError> {
+        crate::irql_scope!(apc);
 
         let mut status = unsafe {
-            ObReferenceObjectByHandle(
-                *self.0,
-                THREAD_QUERY_LIMITED_INFORMATION,
-                *PsThreadType,
-                KernelMode as _,
-                &mut thread,
-                ptr::null_mut(),
-            )
-        };
-
-        cvt(status)?;
-
-        status = unsafe {
             KeWaitForSingleObject(
-                thread,
+                self.thread.as_ptr().cast(),
                 Executive as _,
                 KernelMode as _,
                 FALSE as _,
@@ -142,7 +170,12 @@ impl JoinHandle {
 
         cvt(status)?;
 
-        unsafe { ObfDereferenceObject(thread) };
+        if self.exit.panicked.load(Ordering::Acquire) {
+            // the thread has exited, the message is not written anymore
+            let message = unsafe { (*self.exit.message.get()).clone() };
+
+            return Err(JoinError::ThreadPanicked(PanicMessage(message)));
+        }
 
         // unconditionally set self.exit_status no matter a wait failure or a query failure occurrs
         let mut length: ULONG = 0;
@@ -150,7 +183,7 @@ impl JoinHandle {
 
         status = unsafe {
             ZwQueryInformationThread(
-                *self.0,
+                *self.handle,
                 ThreadBasicInformation as _,
                 &mut info as *mut _ as *mut _,
                 mem::size_of::<THREAD_BASIC_INFORMATION>() as _,
@@ -164,14 +197,450 @@ impl JoinHandle {
     }
 }
 
+/// PsSuspendThread/PsResumeThread
+type PsSuspendResumeThread = unsafe extern "C" fn(PETHREAD, PULONG) -> NTSTATUS;
+
+/// A referenced thread object which may be created by others, the reference is released on drop
+pub struct Thread(ThreadObject);
+
+impl Thread {
+    /// look up a thread by its id
+    pub fn open(tid: u32) -> Result<Self, NtError> {
+        ThreadObject::from_thread_id(tid as usize as HANDLE).map(Self)
+    }
+
+    /// the calling thread
+    pub fn current() -> Self {
+        let thread = KeGetCurrentThread();
+
+        unsafe { ObfReferenceObject(thread.cast()) };
+
+        Self(ThreadObject::new(thread).unwrap())
+    }
+
+    pub fn as_object(&self) -> &ThreadObject {
+        &self.0
+    }
+
+    pub fn id(&self) -> u32 {
+        unsafe { handle_to_ulong!(PsGetThreadId(self.0.as_ptr())) }
+    }
+
+    /// the id of the owning process
+    pub fn process_id(&self) -> u32 {
+        unsafe { handle_to_ulong!(PsGetThreadProcessId(self.0.as_ptr())) }
+    }
+
+    /// the current dynamic priority
+    pub fn priority(&self) -> KPRIORITY {
+        unsafe { KeQueryPriorityThread(self.0.as_ptr()) }
+    }
+
+    /// set the priority(LOW_PRIORITY to HIGH_PRIORITY), returns the old one
+    pub fn set_priority(&self, priority: KPRIORITY) -> KPRIORITY {
+        unsafe { KeSetPriorityThread(self.0.as_ptr(), priority) }
+    }
+
+    /// open a kernel handle of this thread
+    fn open_handle(&self, access: u32) -> Result<OwnedHandle, NtError> {
+        let mut handle: HANDLE = ptr::null_mut();
+
+        cvt(unsafe {
+            ObOpenObjectByPointer(
+                self.0.as_ptr().cast(),
+                OBJ_KERNEL_HANDLE,
+                ptr::null_mut(),
+                access,
+                *PsThreadType,
+                KernelMode as _,
+                &mut handle,
+            )
+        })?;
+
+        Ok(OwnedHandle(handle))
+    }
+
+    /// the processors the thread may run on, it must be called at PASSIVE_LEVEL
+    pub fn affinity(&self) -> Result<KAFFINITY, NtError> {
+        let handle = self.open_handle(THREAD_QUERY_LIMITED_INFORMATION)?;
+
+        let mut length: ULONG = 0;
+        let mut info = THREAD_BASIC_INFORMATION::default();
+
+        cvt(unsafe {
+            ZwQueryInformationThread(
+                *handle,
+                ThreadBasicInformation as _,
+                &mut info as *mut _ as *mut _,
+                mem::size_of::<THREAD_BASIC_INFORMATION>() as _,
+                &mut length,
+            )
+        })?;
+
+        Ok(info.AffinityMask as _)
+    }
+
+    /// restrict the processors the thread may run on, `affinity` must be a subset of the process affinity
+    ///
+    /// it must be called at PASSIVE_LEVEL
+    pub fn set_affinity(&self, affinity: KAFFINITY) -> Result<(), NtError> {
+        let handle = self.open_handle(THREAD_SET_INFORMATION)?;
+
+        let mut affinity = affinity;
+
+        cvt(unsafe {
+            ZwSetInformationThread(
+                *handle,
+                ThreadAffinityMask,
+                &mut affinity as *mut _ as *mut _,
+                mem::size_of::<KAFFINITY>() as _,
+            )
+        })
+    }
+
+    fn suspend_resume(&self, routine: &str) -> Result<u32, NtError> {
+        let mut name =
+            utils::utf16_from_str(routine).ok_or(NtError::new(STATUS_INSUFFICIENT_RESOURCES))?;
+
+        let address = unsafe { MmGetSystemRoutineAddress(name.as_mut()) };
+
+        if address.is_null() {
+            return Err(NtError::new(STATUS_NOT_SUPPORTED));
+        }
+
+        let routine = unsafe { mem::transmute::<PVOID, PsSuspendResumeThread>(address) };
+
+        let mut previous: ULONG = 0;
+
+        cvt(unsafe { routine(self.0.as_ptr(), &mut previous) })?;
+
+        Ok(previous)
+    }
+
+    /// suspend the thread, returns the previous suspend count
+    ///
+    /// fails with STATUS_NOT_SUPPORTED if PsSuspendThread is not exported by this system
+    pub fn suspend(&self) -> Result<u32, NtError> {
+        self.suspend_resume("PsSuspendThread")
+    }
+
+    /// resume the thread, returns the previous suspend count
+    ///
+    /// fails with STATUS_NOT_SUPPORTED if PsResumeThread is not exported by this system
+    pub fn resume(&self) -> Result<u32, NtError> {
+        self.suspend_resume("PsResumeThread")
+    }
+}
+
+impl AsRawObject for Thread {
+    type Target = _KTHREAD;
+    fn as_raw(&self) -> *mut Self::Target {
+        self.0.as_ptr()
+    }
+}
+
+/// wait for the thread to exit
+impl Dispatchable for Thread {}
+
+impl AsRawObject for JoinHandle {
+    type Target = _KTHREAD;
+    fn as_raw(&self) -> *mut Self::Target {
+        self.thread.as_ptr()
+    }
+}
+
+/// wait for the thread to exit
+impl Dispatchable for JoinHandle {}
+
+unsafe impl Send for JoinHandle {}
+unsafe impl Sync for JoinHandle {}
+
+unsafe impl Send for Thread {}
+unsafe impl Sync for Thread {}
+
+/// A handle that wakes a thread from `this_thread::sleep_alertable`
+///
+/// the wake is kept if the thread is not sleeping, so the next alertable sleep returns immediately,
+/// a thread can discard a pending wake with `this_thread::clear_wake`
+pub struct Waker(ThreadObject);
+
+impl Waker {
+    /// a waker of any thread, e.g. one opened with `Thread::open`
+    pub fn from_thread(thread: Thread) -> Self {
+        Self(thread.0)
+    }
+
+    /// interrupt the alertable sleep of the thread, it can be called at IRQL <= DISPATCH_LEVEL
+    pub fn wake(&self) {
+        unsafe { KeAlertThread(self.0.as_ptr(), KernelMode as _) };
+    }
+}
+
+impl Clone for Waker {
+    fn clone(&self) -> Self {
+        unsafe { ObfReferenceObject(self.0.as_ptr().cast()) };
+
+        Self(ThreadObject::new(self.0.as_ptr()).unwrap())
+    }
+}
+
+unsafe impl Send for Waker {}
+unsafe impl Sync for Waker {}
+
+/// the ids of the threads of process `pid`, it must be called at PASSIVE_LEVEL
+pub fn thread_ids(pid: u32) -> Result<Vec<u32>, NtError> {
+    sysinfo::processes()?
+        .find(|process| process.pid == pid as usize)
+        .map(|process| process.thread_ids)
+        .ok_or(NtError::new(STATUS_INVALID_CID))
+}
+
+/// the longest panic message kept for `JoinHandle::join`, a longer message is truncated
+pub const MAX_PANIC_MESSAGE_LEN: usize = 256;
+
+/// the message and the location of a panic in a spawned thread
+#[derive(Clone, Debug)]
+pub struct PanicMessage(StackString<MAX_PANIC_MESSAGE_LEN>);
+
+impl PanicMessage {
+    pub fn as_str(&self) -> &str {
+        self.0.as_str()
+    }
+}
+
+impl fmt::Display for PanicMessage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// The error of `JoinHandle::join`
+#[derive(Clone, Debug)]
+pub enum JoinError {
+    /// the thread could not be waited for or queried
+    Os(NtError),
+    /// the thread panicked and was terminated with STATUS_UNHANDLED_EXCEPTION
+    ThreadPanicked(PanicMessage),
+}
+
+impl From<NtError> for JoinError {
+    fn from(e: NtError) -> Self {
+        Self::Os(e)
+    }
+}
+
+/// a panic becomes STATUS_UNHANDLED_EXCEPTION, so `join()?` works in the functions returning `NtError`
+impl From<JoinError> for NtError {
+    fn from(e: JoinError) -> Self {
+        match e {
+            JoinError::Os(e) => e,
+            JoinError::ThreadPanicked(_) => NtError::new(STATUS_UNHANDLED_EXCEPTION),
+        }
+    }
+}
+
+/// the end of a spawned thread, shared by the thread and its `JoinHandle`
+///
+/// it is linked into `RUNNING` while the thread runs, so the panic handler finds it by the current thread, nothing
+/// is allocated by the thread itself
+struct ExitState {
+    /// the status passed to `PsTerminateSystemThread`
+    status: AtomicI32,
+    /// written by the thread itself before `panicked` is set
+    message: UnsafeCell<StackString<MAX_PANIC_MESSAGE_LEN>>,
+    panicked: AtomicBool,
+    /// the running thread, referenced while it is linked, so its address can not be taken by another thread
+    thread: AtomicPtr<_KTHREAD>,
+    /// the next running thread, guarded by `RUNNING`
+    next: UnsafeCell<*const ExitState>,
+    /// the token of `spawn_in`, released by `exited`
+    token: UnsafeCell<Option<WaitGroupToken>>,
+}
+
+impl ExitState {
+    fn new(token: Option<WaitGroupToken>) -> Result<KArc<Self>, NtError> {
+        KArc::new(Self {
+            status: AtomicI32::new(STATUS_SUCCESS),
+            message: UnsafeCell::new(StackString::new()),
+            panicked: AtomicBool::new(false),
+            thread: AtomicPtr::new(ptr::null_mut()),
+            next: UnsafeCell::new(ptr::null()),
+            token: UnsafeCell::new(token),
+        })
+    }
+
+    /// release what the thread holds until it exits, it is called once by whoever unlinked the state
+    fn exited(&self) {
+        let thread = self.thread.swap(ptr::null_mut(), Ordering::Relaxed);
+
+        if !thread.is_null() {
+            unsafe { ObfDereferenceObject(thread.cast()) };
+        }
+
+        drop(unsafe { (*self.token.get()).take() });
+    }
+}
+
+unsafe impl Send for ExitState {}
+unsafe impl Sync for ExitState {}
+
+/// the head of the running spawned threads, each link holds a reference from `KArc::into_raw`
+struct Running(*const ExitState);
+
+unsafe impl Send for Running {}
+
+static RUNNING: StaticSpinLocked<Running> = StaticSpinLocked::new(Running(ptr::null()));
+
+/// link `exit` to the current thread
+///
+/// the states left by the threads which called `PsTerminateSystemThread` themselves are released first
+fn track(exit: KArc<ExitState>) {
+    let current = KeGetCurrentThread();
+
+    while let Some(stale) = unlink(
+        |exit| unsafe { PsIsThreadTerminating(exit.thread.load(Ordering::Relaxed).cast()) } != 0,
+    ) {
+        stale.exited();
+    }
+
+    unsafe { ObfReferenceObject(current.cast()) };
+
+    exit.thread.store(current, Ordering::Relaxed);
+
+    let exit = KArc::into_raw(exit);
+    let mut running = RUNNING.lock();
+
+    unsafe { *(*exit).next.get() = running.0 };
+
+    running.0 = exit;
+}
+
+/// unlink the state of the current thread and release what it holds
+fn untrack() -> Option<KArc<ExitState>> {
+    let current = KeGetCurrentThread();
+
+    let exit = unlink(|exit| exit.thread.load(Ordering::Relaxed) == current)?;
+
+    exit.exited();
+
+    Some(exit)
+}
+
+/// unlink the first state matching `f`
+fn unlink<F: FnMut(&ExitState) -> bool>(mut f: F) -> Option<KArc<ExitState>> {
+    let mut running = RUNNING.lock();
+    let mut link: *mut *const ExitState = &mut running.0;
+
+    unsafe {
+        while !(*link).is_null() {
+            let exit = *link;
+
+            if f(&*exit) {
+                *link = *(*exit).next.get();
+
+                return Some(KArc::from_raw(exit));
+            }
+
+            link = (*exit).next.get();
+        }
+    }
+
+    None
+}
+
+/// run `f` with the state of the current thread, `None` if it is not spawned by `spawn`
+fn with_current<R, F: FnOnce(&ExitState) -> R>(f: F) -> Option<R> {
+    let current = KeGetCurrentThread();
+    let running = RUNNING.lock();
+    let mut exit = running.0;
+
+    unsafe {
+        while !exit.is_null() {
+            if (*exit).thread.load(Ordering::Relaxed) == current {
+                return Some(f(&*exit));
+            }
+
+            exit = *(*exit).next.get();
+        }
+    }
+
+    None
+}
+
+/// terminate the current thread with STATUS_UNHANDLED_EXCEPTION if it is spawned by `spawn`, the panic handler
+/// calls it first
+///
+/// it returns if the thread is not spawned by `spawn` or it may hold a lock, i.e. above PASSIVE_LEVEL or in a
+/// critical or guarded region, since the lock would never be released, the panic bugchecks then
+///
+/// there is no unwinding, the captures of the thread routine and its locals are leaked without being dropped, what
+/// the crate holds for the thread, e.g. the token of `spawn_in`, is released
+#[cfg(feature = "panic_handler")]
+pub(crate) fn exit_on_panic(info: &core::panic::PanicInfo) {
+    use core::fmt::Write;
+
+    use wdk_sys::{DPFLTR_ERROR_LEVEL, PASSIVE_LEVEL, ntddk::KeGetCurrentIrql};
+
+    use crate::region;
+
+    if unsafe { KeGetCurrentIrql() } != PASSIVE_LEVEL as u8 || region::apcs_disabled() {
+        return;
+    }
+
+    let Some(exit) = untrack() else {
+        return;
+    };
+
+    let message = unsafe { &mut *exit.message.get() };
+
+    let _ = write!(message, "{}", info.message());
+
+    if let Some(location) = info.location() {
+        let _ = write!(message, " at {}:{}", location.file(), location.line());
+    }
+
+    crate::fmt::print(
+        DPFLTR_ERROR_LEVEL,
+        format_args!(
+            "[PANIC] thread {:x}: {}",
+            this_thread::id(),
+            message.as_str()
+        ),
+    );
+
+    exit.panicked.store(true, Ordering::Release);
+
+    drop(exit);
+
+    tls::thread_exit();
+
+    unsafe { PsTerminateSystemThread(STATUS_UNHANDLED_EXCEPTION) };
+}
+
+/// the context of a spawned thread
+struct Start<F> {
+    f: F,
+    exit: KArc<ExitState>,
+}
+
 /// trampolion for `F`, using static binding here
 ///
 /// `F` is inferred as `impl Fn` which rust know it exactly, it is essentially a function pointer.
-/// so the call `ctx()` here will call the function pointer "passed in" from `spawn` method
+/// so the call `f()` here will call the function pointer "passed in" from `spawn` method
+///
+/// the thread is terminated with its exit status rather than returning, so the status can be queried
 extern "C" fn start_routine_stub<F: FnOnce()>(context: PVOID) {
-    let ctx: Box<F> = unsafe { Box::from_raw(mem::transmute::<_, *mut F>(context)) };
+    let Start { f, exit } = *unsafe { Box::from_raw(context.cast::<Start<F>>()) };
+
+    track(exit);
 
-    ctx();
+    f();
+
+    tls::thread_exit();
+
+    let status = untrack().map_or(STATUS_SUCCESS, |exit| exit.status.load(Ordering::Relaxed));
+
+    unsafe { PsTerminateSystemThread(status) };
 }
 
 pub fn available_parallelism() -> NonZero<usize> {
@@ -180,9 +649,21 @@ pub fn available_parallelism() -> NonZero<usize> {
     NonZero::new(num_cores as usize).unwrap()
 }
 
+/// spawn a system thread running `f`
+///
+/// a panic of the thread terminates it, see `exit_on_panic`, the captures of `f` are leaked then
 pub fn spawn<F: FnOnce() + Send + 'static>(f: F) -> Result<JoinHandle, NtError> {
+    spawn_with(f, None)
+}
+
+fn spawn_with<F: FnOnce() + Send + 'static>(
+    f: F,
+    token: Option<WaitGroupToken>,
+) -> Result<JoinHandle, NtError> {
     let mut handle: HANDLE = ptr::null_mut();
 
+    let exit = ExitState::new(token)?;
+
     unsafe {
         let mut attr = initialize_object_attributes!(
             ptr::null_mut(),
@@ -192,7 +673,10 @@ pub fn spawn<F: FnOnce() + Send + 'static>(f: F) -> Result<JoinHandle, NtError>
         );
 
         // `F` is inferred as `impl Fn`
-        let buf = Box::new(f);
+        let buf = try_box(Start {
+            f,
+            exit: exit.clone(),
+        })?;
 
         // Box will be dropped in `start_routine_stub::<F>`
         let context = Box::into_raw(buf);
@@ -213,30 +697,174 @@ pub fn spawn<F: FnOnce() + Send + 'static>(f: F) -> Result<JoinHandle, NtError>
         }
     }
 
-    Ok(JoinHandle(OwnedHandle(handle)))
+    let handle = OwnedHandle(handle);
+
+    // the thread keeps running if it can not be referenced, only the handle is closed
+    let thread = ThreadObject::from_handle(*handle, SYNCHRONIZE)?;
+
+    Ok(JoinHandle {
+        handle,
+        thread,
+        exit,
+        stop: None,
+    })
+}
+
+/// spawn a thread counted by `group`, `group.wait()` returns only after the thread routine returns or the thread
+/// panics
+pub fn spawn_in<F: FnOnce() + Send + 'static>(
+    group: &WaitGroup,
+    f: F,
+) -> Result<JoinHandle, NtError> {
+    // the token is dropped with the state if the thread can not be created
+    spawn_with(f, Some(group.enter()))
+}
+
+/// A request to stop a thread, backed by a notification event
+///
+/// the clones share the request, once it is stopped the pending and the later `this_thread::sleep_cancellable`
+/// return at once, it is `Dispatchable`, so it can be waited together with other objects
+#[derive(Clone)]
+pub struct StopToken(KArc<NotificationEvent>);
+
+impl StopToken {
+    pub fn new() -> Result<Self, NtError> {
+        Ok(Self(KArc::new(NotificationEvent::new(false)?)?))
+    }
+
+    /// request the stop, it can be called at IRQL <= DISPATCH_LEVEL
+    pub fn stop(&self) {
+        self.0.set();
+    }
+
+    pub fn is_stopped(&self) -> bool {
+        self.0.get_state()
+    }
+}
+
+impl AsRawObject for StopToken {
+    type Target = _KEVENT;
+    fn as_raw(&self) -> *mut Self::Target {
+        self.0.as_raw()
+    }
+}
+
+/// wait for the stop request
+impl Dispatchable for StopToken {}
+
+/// Spawn a thread which is handed a `StopToken`
+///
+/// # Example
+/// ```
+/// let worker = thread::Builder::new().spawn(|stop| {
+///     while this_thread::sleep_cancellable(Duration::from_secs(30), &stop) == SleepResult::Elapsed {
+///         flush();
+///     }
+/// })?;
+///
+/// // on unload, the worker exits without finishing its sleep
+/// worker.request_stop();
+/// worker.join()?;
+/// ```
+#[derive(Default)]
+pub struct Builder {
+    stop: Option<StopToken>,
+}
+
+impl Builder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// hand `token` to the thread instead of a new one, e.g. to stop several workers at once
+    pub fn stop_token(mut self, token: StopToken) -> Self {
+        self.stop = Some(token);
+        self
+    }
+
+    /// spawn a thread running `f` with the token, the token is kept by the `JoinHandle` as well
+    pub fn spawn<F: FnOnce(StopToken) + Send + 'static>(self, f: F) -> Result<JoinHandle, NtError> {
+        let token = match self.stop {
+            Some(token) => token,
+            None => StopToken::new()?,
+        };
+
+        let stop = token.clone();
+        let mut handle = spawn(move || f(stop))?;
+
+        handle.stop = Some(token);
+
+        Ok(handle)
+    }
 }
 
 pub mod this_thread {
-    use core::{arch::x86_64::_mm_pause, time::Duration};
+    use core::{arch::x86_64::_mm_pause, sync::atomic::Ordering, time::Duration};
 
     use wdk_sys::{
         _MODE::KernelMode,
-        FALSE, LARGE_INTEGER, ULONG,
+        FALSE, STATUS_ALERTED, TRUE, ULONG,
         ntddk::{KeDelayExecutionThread, PsGetCurrentThreadId},
     };
 
-    use crate::handle_to_ulong;
+    use wdk_sys::NTSTATUS;
+
+    use super::{KeTestAlertThread, StopToken};
+    use crate::{handle_to_ulong, time};
+
+    /// How a `sleep_cancellable` ended
+    #[derive(Clone, Copy, PartialEq, Eq, Debug)]
+    pub enum SleepResult {
+        /// the whole duration has elapsed
+        Elapsed,
+        /// the token was stopped before
+        Stopped,
+    }
 
     pub fn sleep(ms: Duration) {
-        let mut timeout = LARGE_INTEGER {
-            QuadPart: -1 * ms.as_nanos() as i64 / 100,
-        };
+        crate::irql_scope!(apc);
+
+        let mut timeout = time::relative(ms);
 
         unsafe {
             let _ = KeDelayExecutionThread(KernelMode as i8, FALSE as u8, &mut timeout);
         }
     }
 
+    /// sleep for `ms` unless a `Waker` of this thread wakes it, returns true if it was woken early
+    pub fn sleep_alertable(ms: Duration) -> bool {
+        crate::irql_scope!(apc);
+
+        let mut timeout = time::relative(ms);
+
+        let status = unsafe { KeDelayExecutionThread(KernelMode as i8, TRUE as u8, &mut timeout) };
+
+        status == STATUS_ALERTED
+    }
+
+    /// sleep for `d` unless `token` is stopped, it returns at once if the token is stopped already
+    pub fn sleep_cancellable(d: Duration, token: &StopToken) -> SleepResult {
+        crate::irql_scope!(apc);
+
+        if token.0.wait_timeout(d).timed_out() {
+            SleepResult::Elapsed
+        } else {
+            SleepResult::Stopped
+        }
+    }
+
+    /// set the status the current thread exits with, which `JoinHandle::join` returns, STATUS_SUCCESS by default
+    ///
+    /// returns false if the thread is not spawned by `spawn`
+    pub fn set_exit_status(status: NTSTATUS) -> bool {
+        super::with_current(|exit| exit.status.store(status, Ordering::Relaxed)).is_some()
+    }
+
+    /// discard a pending wake, returns true if there was one
+    pub fn clear_wake() -> bool {
+        unsafe { KeTestAlertThread(KernelMode as _) != 0 }
+    }
+
     pub fn pause() {
         unsafe { _mm_pause() };
     }
@@ -0,0 +1,150 @@
+//! this module provides the primitives to throttle the work triggered by callback storms
+//!
+//! - `RateLimiter`: a token bucket refilled by the performance counter, it can be used at any IRQL
+//! - `Debounce`: run a closure once after the triggers stop for a delay(trailing edge), based on `Timer`
+//!
+//! # Example
+//! ```
+//! // at most 100 events per second with bursts of 20
+//! static EVENTS: RateLimiter = RateLimiter::new(100, 20);
+//!
+//! fn on_registry_callback() {
+//!     if !EVENTS.try_acquire() {
+//!         DROPPED.fetch_add(1, Ordering::Relaxed);
+//!         return;
+//!     }
+//!     report();
+//! }
+//!
+//! // reload the configuration 500ms after the last change
+//! let reload = Debounce::new(Duration::from_millis(500), || reload_config())?;
+//!
+//! fn on_config_changed() {
+//!     reload.trigger();
+//! }
+//! ```
+use core::time::Duration;
+
+use wdk_sys::ntddk::KeFlushQueuedDpcs;
+
+use crate::{mutex::StaticSpinLocked, ntstatus::NtError, time, timer::Timer};
+
+struct Bucket {
+    /// the tokens scaled by the performance counter frequency
+    tokens: u128,
+    /// the performance counter of the last refill, 0 before the first use
+    last: u64,
+}
+
+/// A token bucket rate limiter, `rate` tokens per second are added up to `burst` tokens
+///
+/// the bucket is full when it is created, `new` is a `const fn` so it can be declared as a `static`
+pub struct RateLimiter {
+    rate: u32,
+    burst: u32,
+    bucket: StaticSpinLocked<Bucket>,
+}
+
+impl RateLimiter {
+    pub const fn new(rate: u32, burst: u32) -> Self {
+        Self {
+            rate,
+            burst,
+            bucket: StaticSpinLocked::new(Bucket { tokens: 0, last: 0 }),
+        }
+    }
+
+    /// take `n` tokens if they are available, it can be called at IRQL <= DISPATCH_LEVEL
+    pub fn try_acquire_n(&self, n: u32) -> bool {
+        let frequency = time::performance_frequency() as u128;
+        let now = time::KInstant::now().ticks();
+
+        let mut bucket = self.bucket.lock();
+
+        let capacity = self.burst as u128 * frequency;
+
+        if bucket.last == 0 {
+            bucket.tokens = capacity;
+        } else {
+            let elapsed = now.saturating_sub(bucket.last) as u128;
+
+            bucket.tokens = (bucket.tokens + elapsed * self.rate as u128).min(capacity);
+        }
+
+        bucket.last = now;
+
+        let required = n as u128 * frequency;
+
+        if bucket.tokens >= required {
+            bucket.tokens -= required;
+            true
+        } else {
+            false
+        }
+    }
+
+    pub fn try_acquire(&self) -> bool {
+        self.try_acquire_n(1)
+    }
+
+    /// the whole tokens in the bucket at the last acquisition
+    pub fn available(&self) -> u32 {
+        let bucket = self.bucket.lock();
+
+        if bucket.last == 0 {
+            self.burst
+        } else {
+            (bucket.tokens / time::performance_frequency() as u128) as u32
+        }
+    }
+
+    pub fn rate(&self) -> u32 {
+        self.rate
+    }
+
+    pub fn burst(&self) -> u32 {
+        self.burst
+    }
+}
+
+/// A trailing-edge debouncer, the closure is run once when no trigger happens for `delay`
+///
+/// every `trigger` restarts the delay, the closure is run in a DPC at DISPATCH_LEVEL
+pub struct Debounce {
+    timer: Timer,
+    delay: Duration,
+}
+
+impl Debounce {
+    pub fn new<F: Fn() + Send + 'static>(delay: Duration, f: F) -> Result<Self, NtError> {
+        Ok(Self {
+            timer: Timer::try_new(f, false)?,
+            delay,
+        })
+    }
+
+    /// (re)start the delay, it can be called at IRQL <= DISPATCH_LEVEL
+    pub fn trigger(&self) {
+        // setting a pending timer cancels it first
+        self.timer.start(self.delay, Duration::ZERO);
+    }
+
+    /// cancel a pending run
+    pub fn cancel(&self) {
+        self.timer.stop();
+    }
+
+    pub fn delay(&self) -> Duration {
+        self.delay
+    }
+}
+
+impl Drop for Debounce {
+    /// it must be called at PASSIVE_LEVEL, a pending run is canceled
+    fn drop(&mut self) {
+        self.timer.stop();
+
+        // wait for a running closure before the timer is freed
+        unsafe { KeFlushQueuedDpcs() };
+    }
+}
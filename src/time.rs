@@ -0,0 +1,356 @@
+//! this module provides the kernel clocks and the conversions between `Duration` and the 100ns based
+//! `LARGE_INTEGER` used by the kernel APIs
+//!
+//! - `KInstant` is a monotonic clock based on the performance counter, use it to measure elapsed time
+//! - `KSystemTime` is the wall clock time in 100ns units since January 1, 1601 (UTC)
+//!
+//! the kernel interprets a due time or a timeout as:
+//! - a negative value: a relative time which is not affected by the system time changes, see `relative`
+//! - a positive value: an absolute system time, see `KSystemTime::as_large_integer`
+//!
+//! # Example
+//! ```
+//! let start = KInstant::now();
+//!
+//! do_something();
+//!
+//! println!("took {}us", start.elapsed().as_micros());
+//!
+//! // wait until a point of the wall clock
+//! let deadline = KSystemTime::now() + Duration::from_secs(5);
+//! let mut timeout = deadline.as_large_integer();
+//! ```
+use core::{
+    ops::{Add, AddAssign, Sub, SubAssign},
+    ptr,
+    time::Duration,
+};
+
+use wdk_sys::{
+    LARGE_INTEGER,
+    ntddk::{KeQueryPerformanceCounter, KeQuerySystemTimePrecise},
+};
+
+use crate::lazy::OnceLock;
+
+/// number of 100ns units in a second
+pub const UNITS_PER_SEC: u64 = 10_000_000;
+
+/// 100ns units between January 1, 1601 and January 1, 1970
+const UNIX_EPOCH_UNITS: i64 = 116_444_736_000_000_000;
+
+/// the performance counter frequency never changes after boot
+static FREQUENCY: OnceLock<u64> = OnceLock::new();
+
+/// convert a `Duration` to 100ns units, saturates at `i64::MAX`
+#[inline]
+pub fn to_units(d: Duration) -> i64 {
+    i64::try_from(d.as_nanos() / 100).unwrap_or(i64::MAX)
+}
+
+/// convert 100ns units to a `Duration`, a negative value is treated as zero
+#[inline]
+pub fn from_units(units: i64) -> Duration {
+    let units = units.max(0) as u64;
+
+    Duration::new(units / UNITS_PER_SEC, (units % UNITS_PER_SEC) as u32 * 100)
+}
+
+/// a relative timeout of `d` in the kernel convention(negative 100ns units)
+///
+/// the sub 100ns part is rounded up, so a non-zero duration never becomes an immediate timeout
+#[inline]
+pub fn relative(d: Duration) -> LARGE_INTEGER {
+    let units = i64::try_from(d.as_nanos().div_ceil(100)).unwrap_or(i64::MAX);
+
+    LARGE_INTEGER { QuadPart: -units }
+}
+
+/// the frequency of the performance counter in ticks per second
+pub fn performance_frequency() -> u64 {
+    *FREQUENCY
+        .get_or_init(|| {
+            let mut frequency = LARGE_INTEGER { QuadPart: 0 };
+
+            unsafe { KeQueryPerformanceCounter(&mut frequency) };
+
+            unsafe { frequency.QuadPart as u64 }
+        })
+        .unwrap()
+}
+
+#[inline]
+fn ticks_to_duration(ticks: u64) -> Duration {
+    let frequency = performance_frequency();
+
+    let secs = ticks / frequency;
+    let nanos = (ticks % frequency) as u128 * 1_000_000_000 / frequency as u128;
+
+    Duration::new(secs, nanos as u32)
+}
+
+#[inline]
+fn duration_to_ticks(d: Duration) -> Option<u64> {
+    let ticks = d.as_nanos() * performance_frequency() as u128 / 1_000_000_000;
+
+    u64::try_from(ticks).ok()
+}
+
+/// A monotonic clock reading based on the performance counter, it can be taken at any IRQL
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct KInstant(u64);
+
+impl KInstant {
+    pub fn now() -> Self {
+        // make sure the frequency is cached before the first measurement
+        let _ = performance_frequency();
+
+        Self(unsafe { KeQueryPerformanceCounter(ptr::null_mut()).QuadPart as u64 })
+    }
+
+    /// the raw performance counter ticks
+    pub fn ticks(&self) -> u64 {
+        self.0
+    }
+
+    /// the reading of the raw performance counter `ticks`, e.g. a value kept in an atomic
+    pub fn from_ticks(ticks: u64) -> Self {
+        Self(ticks)
+    }
+
+    pub fn elapsed(&self) -> Duration {
+        Self::now().saturating_duration_since(*self)
+    }
+
+    /// panics if `earlier` is later than `self`
+    pub fn duration_since(&self, earlier: KInstant) -> Duration {
+        self.checked_duration_since(earlier)
+            .expect("supplied instant is later than self")
+    }
+
+    pub fn checked_duration_since(&self, earlier: KInstant) -> Option<Duration> {
+        self.0.checked_sub(earlier.0).map(ticks_to_duration)
+    }
+
+    pub fn saturating_duration_since(&self, earlier: KInstant) -> Duration {
+        self.checked_duration_since(earlier).unwrap_or_default()
+    }
+
+    pub fn checked_add(&self, d: Duration) -> Option<KInstant> {
+        duration_to_ticks(d)
+            .and_then(|ticks| self.0.checked_add(ticks))
+            .map(Self)
+    }
+
+    pub fn checked_sub(&self, d: Duration) -> Option<KInstant> {
+        duration_to_ticks(d)
+            .and_then(|ticks| self.0.checked_sub(ticks))
+            .map(Self)
+    }
+}
+
+impl Add<Duration> for KInstant {
+    type Output = KInstant;
+    fn add(self, rhs: Duration) -> Self::Output {
+        self.checked_add(rhs)
+            .expect("overflow when adding duration to instant")
+    }
+}
+
+impl AddAssign<Duration> for KInstant {
+    fn add_assign(&mut self, rhs: Duration) {
+        *self = *self + rhs;
+    }
+}
+
+impl Sub<Duration> for KInstant {
+    type Output = KInstant;
+    fn sub(self, rhs: Duration) -> Self::Output {
+        self.checked_sub(rhs)
+            .expect("overflow when subtracting duration from instant")
+    }
+}
+
+impl SubAssign<Duration> for KInstant {
+    fn sub_assign(&mut self, rhs: Duration) {
+        *self = *self - rhs;
+    }
+}
+
+impl Sub<KInstant> for KInstant {
+    type Output = Duration;
+    fn sub(self, rhs: KInstant) -> Self::Output {
+        self.duration_since(rhs)
+    }
+}
+
+/// A system time in 100ns units since January 1, 1601 (UTC)
+///
+/// the system time can be adjusted by the user or the time service, use `KInstant` to measure intervals
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct KSystemTime(i64);
+
+impl KSystemTime {
+    /// January 1, 1970 (UTC)
+    pub const UNIX_EPOCH: KSystemTime = KSystemTime(UNIX_EPOCH_UNITS);
+
+    pub fn now() -> Self {
+        let mut time = LARGE_INTEGER { QuadPart: 0 };
+
+        unsafe { KeQuerySystemTimePrecise(&mut time) };
+
+        Self(unsafe { time.QuadPart })
+    }
+
+    /// a system time from 100ns units since January 1, 1601
+    pub const fn from_units(units: i64) -> Self {
+        Self(units)
+    }
+
+    pub const fn as_units(&self) -> i64 {
+        self.0
+    }
+
+    /// a system time from a `LARGE_INTEGER`, e.g. `CreateTime` of a file
+    pub fn from_large_integer(time: LARGE_INTEGER) -> Self {
+        Self(unsafe { time.QuadPart })
+    }
+
+    /// an absolute due time or timeout in the kernel convention(positive 100ns units)
+    pub fn as_large_integer(&self) -> LARGE_INTEGER {
+        LARGE_INTEGER { QuadPart: self.0 }
+    }
+
+    /// the duration since the unix epoch, `None` if it is earlier than the epoch
+    pub fn since_unix_epoch(&self) -> Option<Duration> {
+        self.duration_since(Self::UNIX_EPOCH)
+    }
+
+    /// `None` if `earlier` is later than `self`
+    pub fn duration_since(&self, earlier: KSystemTime) -> Option<Duration> {
+        self.0
+            .checked_sub(earlier.0)
+            .filter(|units| *units >= 0)
+            .map(from_units)
+    }
+
+    /// `None` if the system time has been set back to an earlier point
+    pub fn elapsed(&self) -> Option<Duration> {
+        Self::now().duration_since(*self)
+    }
+
+    pub fn checked_add(&self, d: Duration) -> Option<KSystemTime> {
+        self.0.checked_add(to_units(d)).map(Self)
+    }
+
+    pub fn checked_sub(&self, d: Duration) -> Option<KSystemTime> {
+        self.0.checked_sub(to_units(d)).map(Self)
+    }
+}
+
+impl Add<Duration> for KSystemTime {
+    type Output = KSystemTime;
+    fn add(self, rhs: Duration) -> Self::Output {
+        self.checked_add(rhs)
+            .expect("overflow when adding duration to system time")
+    }
+}
+
+impl AddAssign<Duration> for KSystemTime {
+    fn add_assign(&mut self, rhs: Duration) {
+        *self = *self + rhs;
+    }
+}
+
+impl Sub<Duration> for KSystemTime {
+    type Output = KSystemTime;
+    fn sub(self, rhs: Duration) -> Self::Output {
+        self.checked_sub(rhs)
+            .expect("overflow when subtracting duration from system time")
+    }
+}
+
+impl SubAssign<Duration> for KSystemTime {
+    fn sub_assign(&mut self, rhs: Duration) {
+        *self = *self - rhs;
+    }
+}
+
+/// A stopwatch based on `KInstant`, the elapsed time is accumulated across `start`/`stop` pairs
+///
+/// # Example
+/// ```
+/// let mut watch = Stopwatch::start_new();
+///
+/// do_something();
+///
+/// watch.stop();
+/// println!("took {}us", watch.elapsed().as_micros());
+/// ```
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Stopwatch {
+    elapsed: Duration,
+    started: Option<KInstant>,
+}
+
+impl Stopwatch {
+    /// a stopped stopwatch with zero elapsed time
+    pub const fn new() -> Self {
+        Self {
+            elapsed: Duration::ZERO,
+            started: None,
+        }
+    }
+
+    pub fn start_new() -> Self {
+        let mut watch = Self::new();
+
+        watch.start();
+
+        watch
+    }
+
+    /// start or resume measuring, it does nothing if the stopwatch is running
+    pub fn start(&mut self) {
+        if self.started.is_none() {
+            self.started = Some(KInstant::now());
+        }
+    }
+
+    /// stop measuring, returns the total elapsed time
+    pub fn stop(&mut self) -> Duration {
+        if let Some(started) = self.started.take() {
+            self.elapsed += started.elapsed();
+        }
+
+        self.elapsed
+    }
+
+    /// stop and clear the elapsed time
+    pub fn reset(&mut self) {
+        self.elapsed = Duration::ZERO;
+        self.started = None;
+    }
+
+    /// clear the elapsed time and start again, returns the elapsed time before restart
+    pub fn restart(&mut self) -> Duration {
+        let elapsed = self.elapsed();
+
+        self.elapsed = Duration::ZERO;
+        self.started = Some(KInstant::now());
+
+        elapsed
+    }
+
+    pub fn is_running(&self) -> bool {
+        self.started.is_some()
+    }
+
+    /// the total elapsed time, including the running period
+    pub fn elapsed(&self) -> Duration {
+        match self.started {
+            Some(started) => self.elapsed + started.elapsed(),
+            None => self.elapsed,
+        }
+    }
+}
@@ -1,17 +1,23 @@
-use core::{mem, ptr, time::Duration};
+use core::{
+    cell::UnsafeCell,
+    mem::{self, ManuallyDrop},
+    ptr,
+    sync::atomic::{AtomicBool, AtomicI64, AtomicUsize, Ordering},
+    time::Duration,
+};
 
-use alloc::boxed::Box;
+use alloc::{boxed::Box, vec::Vec};
 use wdk_sys::{
     ntddk::{
         ExAllocateTimer, ExCancelTimer, ExDeleteTimer, ExFreePoolWithTag, ExSetTimer,
-        ExSetTimerResolution, KeCancelTimer, KeInitializeDpc, KeInitializeTimerEx,
+        ExSetTimerResolution, KeCancelTimer, KeGetCurrentIrql, KeInitializeDpc, KeInitializeTimerEx,
         KeReadStateTimer, KeSetTimerEx,
-    }, EXT_DELETE_PARAMETERS, EX_TIMER_HIGH_RESOLUTION, EX_TIMER_NOTIFICATION, KTIMER, LARGE_INTEGER, PEXT_CALLBACK, PEX_TIMER, PKTIMER, PVOID, STATUS_INSUFFICIENT_RESOURCES, _EX_TIMER, _KDPC, _KTIMER, _POOL_TYPE::NonPagedPoolNx, _TIMER_TYPE::{NotificationTimer, SynchronizationTimer}
+    }, EXT_DELETE_PARAMETERS, EX_TIMER_HIGH_RESOLUTION, EX_TIMER_NOTIFICATION, KTIMER, LARGE_INTEGER, PEXT_CALLBACK, PASSIVE_LEVEL, PEX_TIMER, PKDPC, PKTIMER, STATUS_INVALID_PARAMETER, PVOID, STATUS_INSUFFICIENT_RESOURCES, STATUS_NOT_SUPPORTED, _EX_TIMER, _KDPC, _KTIMER, _POOL_TYPE::NonPagedPoolNx, _TIMER_TYPE::{NotificationTimer, SynchronizationTimer}
 };
 
 use crate::{
-    dpc::Dpc, kobject::Dispatchable, utils::ex_allocate_pool_zero, ntstatus::NtError,
-    raw::AsRawObject,
+    dpc::{self, RawDpc}, kobject::Dispatchable, utils::{ex_allocate_pool_zero, ex_free_pool, try_box}, ntstatus::NtError,
+    mutex::StaticSpinLocked, oneshot, os, raw::AsRawObject, thread::this_thread, time::{self, KSystemTime},
 };
 
 const TIMER_TAG: u32 = u32::from_ne_bytes(*b"rimt");
@@ -27,9 +33,59 @@ pub trait DelayRun {
     fn delay_run<F: Fn() + 'static>(f: F, after: Duration) -> Result<(), NtError>;
 }
 
+/// the allocation of a `Timer`, the KTIMER comes first so the block is the PKTIMER
+#[repr(C)]
+struct TimerBlock {
+    timer: UnsafeCell<KTIMER>,
+    /// the period of `start_periodic_aligned` in 100ns units, 0 if the timer is not aligned
+    aligned: AtomicI64,
+    dpc: PKDPC,
+    /// serializes `rearm` with the arming and the cancelling of `Timer`, a zeroed block holds an unlocked lock
+    arming: StaticSpinLocked<()>,
+}
+
+impl TimerBlock {
+    /// arm the timer for the next boundary of the aligned period, it runs after the callback in the DPC
+    ///
+    /// a `start`, `start_at` or `stop` in the meantime clears `aligned` under the lock, so the timer is armed again
+    /// only if nothing else has armed or cancelled it since the DPC was queued
+    fn rearm(&self) {
+        let _arming = self.arming.lock();
+
+        let period = self.aligned.load(Ordering::Acquire);
+
+        if period == 0 {
+            return;
+        }
+
+        unsafe { KeSetTimerEx(self.timer.get(), next_boundary(period), 0, self.dpc) };
+    }
+}
+
+/// the block as seen by the callback, which outlives the `Timer` only when the block is leaked as well
+struct BlockPtr(*const TimerBlock);
+
+impl BlockPtr {
+    fn rearm(&self) {
+        unsafe { (*self.0).rearm() }
+    }
+}
+
+unsafe impl Send for BlockPtr {}
+
+/// the absolute due time of the first multiple of `period` since January 1, 1601 after now
+fn next_boundary(period: i64) -> LARGE_INTEGER {
+    let now = KSystemTime::now().as_units();
+
+    KSystemTime::from_units((now / period + 1).saturating_mul(period)).as_large_integer()
+}
+
 pub struct Timer {
     inner: PKTIMER,
-    dpc: Dpc,
+    /// the DPC and the callback, it is kept when the timer is dropped while the callback may be running
+    dpc: ManuallyDrop<RawDpc>,
+    /// a periodic timer may queue its DPC again while the previous one is running
+    periodic: AtomicBool,
 }
 
 impl Timer {
@@ -38,12 +94,33 @@ impl Timer {
     /// # Parameters
     /// - f: routine will be called when timer expired
     /// - is_synch: specify the type of timer, NotificationTimer or SynchronizationTimer will be created
-    pub fn new<F: Fn() + 'static>(f: F, is_synch: bool) -> Result<Self, NtError> {
+    ///
+    /// the callback can keep a mutable state, the DPCs of a periodic timer running on different processors take
+    /// turns
+    pub fn new<F: FnMut() + Send + 'static>(f: F, is_synch: bool) -> Result<Self, NtError> {
+        Self::try_new(f, is_synch)
+    }
+
+    /// same as `new`, it fails with STATUS_INSUFFICIENT_RESOURCES if the timer or its DPC can not be allocated
+    pub fn try_new<F: FnMut() + Send + 'static>(mut f: F, is_synch: bool) -> Result<Self, NtError> {
         let layout =
-            ex_allocate_pool_zero(NonPagedPoolNx, mem::size_of::<KTIMER>() as _, TIMER_TAG);
+            ex_allocate_pool_zero(NonPagedPoolNx, mem::size_of::<TimerBlock>() as _, TIMER_TAG)?;
+
+        let block = BlockPtr(layout.cast());
+
+        let dpc = match RawDpc::for_timer(move || {
+            f();
+            block.rearm();
+        }) {
+            Ok(dpc) => dpc,
+            Err(e) => {
+                unsafe { ex_free_pool(layout, TIMER_TAG) };
+                return Err(e);
+            }
+        };
 
-        if layout.is_null() {
-            return Err(NtError::new(STATUS_INSUFFICIENT_RESOURCES));
+        unsafe {
+            (*layout.cast::<TimerBlock>()).dpc = dpc.get();
         }
 
         unsafe {
@@ -59,7 +136,8 @@ impl Timer {
 
         Ok(Self {
             inner: layout.cast(),
-            dpc: Dpc::new(f)?,
+            dpc: ManuallyDrop::new(dpc),
+            periodic: AtomicBool::new(false),
         })
     }
 
@@ -72,9 +150,12 @@ impl Timer {
     /// - after: start this timer after amount of time, the timer will expired immdediately if a `Duration::ZERO` specified
     /// - period: timer expire period, the timer will not expire periodically if sepcify `Duration::ZERO` which means a one-shot timer
     pub fn start(&self, after: Duration, period: Duration) {
-        let due_time = LARGE_INTEGER {
-            QuadPart: -1 * after.as_millis() as i64 * 1_0000,
-        };
+        let due_time = time::relative(after);
+
+        let _arming = self.block().arming.lock();
+
+        self.block().aligned.store(0, Ordering::Release);
+        self.periodic.store(!period.is_zero(), Ordering::Relaxed);
 
         unsafe {
             KeSetTimerEx(
@@ -86,11 +167,220 @@ impl Timer {
         }
     }
 
-    /// stop this timer
-    pub fn stop(&self) {
+    /// start this timer once at the system time `at`, it expires immediately if `at` has passed already
+    ///
+    /// the due time is absolute, so the timer follows the changes of the system time, e.g. a timer set for 12:00
+    /// still expires at 12:00 after the clock is set back an hour
+    pub fn start_at(&self, at: KSystemTime) {
+        let _arming = self.block().arming.lock();
+
+        self.block().aligned.store(0, Ordering::Release);
+        self.periodic.store(false, Ordering::Relaxed);
+
+        unsafe {
+            KeSetTimerEx(self.inner, at.as_large_integer(), 0, self.dpc.get());
+        }
+    }
+
+    /// start this timer on the wall clock boundaries of `period`, e.g. every minute at :00 for 60s
+    ///
+    /// the boundaries are the multiples of `period` since January 1, 1601 (UTC), so a day is aligned on midnight
+    /// UTC, the timer is armed for the next boundary with an absolute due time every time it expires, so it stays
+    /// aligned across the changes of the system time and the timer resolution
+    ///
+    /// it fails with STATUS_INVALID_PARAMETER if `period` is shorter than 100ns
+    pub fn start_periodic_aligned(&self, period: Duration) -> Result<(), NtError> {
+        let units = time::to_units(period);
+
+        if units == 0 {
+            return Err(NtError::new(STATUS_INVALID_PARAMETER));
+        }
+
+        let _arming = self.block().arming.lock();
+
+        self.periodic.store(true, Ordering::Relaxed);
+        self.block().aligned.store(units, Ordering::Release);
+
+        unsafe {
+            KeSetTimerEx(self.inner, next_boundary(units), 0, self.dpc.get());
+        }
+
+        Ok(())
+    }
+
+    fn block(&self) -> &TimerBlock {
+        unsafe { &*self.inner.cast::<TimerBlock>() }
+    }
+
+    /// stop this timer and remove its DPC from the queue
+    ///
+    /// returns true if the DPC may still be running, i.e. the timer had already expired or it is periodic, the
+    /// callback must not be freed until the DPC returns, see `stop_and_wait`
+    pub fn stop(&self) -> bool {
+        let cancelled = {
+            let _arming = self.block().arming.lock();
+
+            // an aligned timer is not armed again by a DPC which is running
+            self.block().aligned.store(0, Ordering::Release);
+
+            unsafe { KeCancelTimer(self.inner) != 0 }
+        };
+
+        self.dpc.cancel();
+
+        !cancelled || self.periodic.load(Ordering::Relaxed)
+    }
+
+    /// stop this timer and wait until its DPC has returned, it must be called at PASSIVE_LEVEL
+    ///
+    /// the callback is not running anymore when it returns, so the timer and the data used by the callback can be
+    /// freed safely
+    pub fn stop_and_wait(&self) {
+        crate::assert_irql!(== PASSIVE_LEVEL);
+
+        if self.stop() {
+            dpc::flush_all();
+        }
+    }
+
+    /// same as `stop_and_wait`
+    pub fn stop_sync(&self) {
+        self.stop_and_wait();
+    }
+
+    /// returns a receiver which is completed once `after` elapses, the timer frees itself when it expires
+    ///
+    /// the pending timers are cancelled by `cancel_after_timers`, which `UnloadGuard::shutdown` calls, their
+    /// receivers see STATUS_PIPE_BROKEN then
+    ///
+    /// # Example
+    /// ```
+    /// let rx = Timer::after(Duration::from_millis(500))?;
+    ///
+    /// // do something else, then wait for the rest of the 500ms
+    /// rx.recv()?;
+    /// ```
+    pub fn after(after: Duration) -> Result<oneshot::Receiver<()>, NtError> {
+        let (sender, receiver) = oneshot::channel()?;
+
+        let mut context = try_box(AfterContext {
+            sender,
+            timer: _KTIMER::default(),
+            dpc: _KDPC::default(),
+        })?;
+
+        unsafe {
+            KeInitializeTimerEx(&mut context.timer, NotificationTimer);
+            KeInitializeDpc(
+                &mut context.dpc,
+                Some(after_dpc_routine),
+                context.as_mut() as *mut AfterContext as _,
+            );
+        }
+
+        let mut pending = PENDING_AFTER.lock();
+
+        pending
+            .0
+            .try_reserve(1)
+            .map_err(|_| NtError::new(STATUS_INSUFFICIENT_RESOURCES))?;
+
+        let context = Box::into_raw(context);
+
+        pending.0.push(context);
+
+        // it is set under the lock, so `cancel_after_timers` never sees a context which is not armed yet
         unsafe {
-            KeCancelTimer(self.inner);
+            KeSetTimerEx(
+                &mut (*context).timer,
+                time::relative(after),
+                0,
+                &mut (*context).dpc,
+            );
+        }
+
+        Ok(receiver)
+    }
+
+    /// same as `after`, the receiver is awaited to get the delay in an async fn
+    ///
+    /// # Example
+    /// ```
+    /// Timer::delay_async(Duration::from_secs(1))?.await?;
+    /// ```
+    #[cfg(feature = "async")]
+    pub fn delay_async(after: Duration) -> Result<oneshot::Receiver<()>, NtError> {
+        Self::after(after)
+    }
+}
+
+struct AfterContext {
+    sender: oneshot::Sender<()>,
+    timer: _KTIMER,
+    dpc: _KDPC,
+}
+
+/// the contexts of the pending `Timer::after`
+struct PendingAfter(Vec<*mut AfterContext>);
+
+unsafe impl Send for PendingAfter {}
+
+static PENDING_AFTER: StaticSpinLocked<PendingAfter> =
+    StaticSpinLocked::new(PendingAfter(Vec::new()));
+
+/// the `after_dpc_routine` which have left `PENDING_AFTER` but not returned yet
+static RUNNING_AFTER: AtomicUsize = AtomicUsize::new(0);
+
+extern "C" fn after_dpc_routine(_dpc: *mut _KDPC, context: PVOID, _arg1: PVOID, _arg2: PVOID) {
+    let context = context as *mut AfterContext;
+
+    {
+        let mut pending = PENDING_AFTER.lock();
+
+        if let Some(index) = pending.0.iter().position(|&pending| pending == context) {
+            pending.0.swap_remove(index);
         }
+
+        RUNNING_AFTER.fetch_add(1, Ordering::AcqRel);
+    }
+
+    // neither the timer nor the DPC is touched by the system once the routine is called
+    let AfterContext { sender, .. } = *unsafe { Box::from_raw(context) };
+
+    // the receiver may have been dropped already
+    let _ = sender.send(());
+
+    RUNNING_AFTER.fetch_sub(1, Ordering::AcqRel);
+}
+
+/// cancel the pending `Timer::after` and wait for the expired ones to complete their receivers
+///
+/// it must be called at PASSIVE_LEVEL, typically in `DriverUnload`, the cancelled receivers see STATUS_PIPE_BROKEN
+pub fn cancel_after_timers() {
+    crate::irql_scope!(passive);
+
+    loop {
+        let cancelled = {
+            let mut pending = PENDING_AFTER.lock();
+
+            // an expired timer is left to its DPC, which removes it
+            let index = pending
+                .0
+                .iter()
+                .position(|&context| unsafe { KeCancelTimer(&mut (*context).timer) } != 0);
+
+            index.map(|index| pending.0.swap_remove(index))
+        };
+
+        match cancelled {
+            // the sender is dropped out of the lock
+            Some(context) => drop(unsafe { Box::from_raw(context) }),
+            None => break,
+        }
+    }
+
+    while !PENDING_AFTER.lock().0.is_empty() || RUNNING_AFTER.load(Ordering::Acquire) != 0 {
+        this_thread::sleep(Duration::from_millis(1));
     }
 }
 
@@ -126,13 +416,13 @@ extern "C" fn oneshot_dpc_routine<F: Fn()>(
 
 impl DelayRun for Timer {
     fn delay_run<F: Fn() + 'static>(f: F, after: Duration) -> Result<(), NtError> {
-        let mut dpc = Box::new(_KDPC::default());
+        let mut dpc = try_box(_KDPC::default())?;
 
         // allocate DPC context
-        let mut context = Box::new(OneShotContex {
-            callback: Box::new(f),
-            timer: Box::new(_KTIMER::default()),
-        });
+        let mut context = try_box(OneShotContex {
+            callback: try_box(f)?,
+            timer: try_box(_KTIMER::default())?,
+        })?;
 
         unsafe {
             KeInitializeDpc(
@@ -144,9 +434,7 @@ impl DelayRun for Timer {
             KeInitializeTimerEx(context.timer.as_mut(), NotificationTimer);
         }
 
-        let due_time = LARGE_INTEGER {
-            QuadPart: -1 * after.as_millis() as i64 * 1_0000,
-        };
+        let due_time = time::relative(after);
 
         unsafe {
             KeSetTimerEx(context.timer.as_mut(), due_time, 0, dpc.as_mut());
@@ -160,9 +448,24 @@ impl DelayRun for Timer {
 }
 
 impl Drop for Timer {
+    /// the callback is dropped only once its DPC can not run anymore
+    ///
+    /// at PASSIVE_LEVEL the queued DPCs are flushed first, above PASSIVE_LEVEL there is no way to wait for them, so
+    /// the callback and the timer are leaked if it may still be running rather than freed under the routine
     fn drop(&mut self) {
+        // a timer still in the queue must not be freed
+        let running = self.stop();
+
+        if unsafe { KeGetCurrentIrql() } == PASSIVE_LEVEL as u8 {
+            dpc::flush_all();
+        } else if running {
+            // the callback re-arms through the block, so both are leaked
+            return;
+        }
+
         unsafe {
-            ExFreePoolWithTag(self.inner.cast(), TIMER_TAG);
+            ManuallyDrop::drop(&mut self.dpc);
+            ex_free_pool(self.inner.cast(), TIMER_TAG);
         }
     }
 }
@@ -183,18 +486,37 @@ impl HRTimer {
     /// create a high resolution timer with or without a callback
     ///
     /// if a timer is created without callback, it will also satisfy the thread who waits on it to be signaled
-    pub fn new<F: Fn() + 'static>(f: Option<F>) -> Result<Self, NtError> {
+    pub fn new<F: Fn() + Send + Sync + 'static>(f: Option<F>) -> Result<Self, NtError> {
+        Self::try_new(f)
+    }
+
+    /// same as `new`, it fails with STATUS_INSUFFICIENT_RESOURCES if the timer or the callback can not be allocated
+    ///
+    /// it fails with STATUS_NOT_SUPPORTED before Windows 8.1
+    pub fn try_new<F: Fn() + Send + Sync + 'static>(f: Option<F>) -> Result<Self, NtError> {
+        if !os::supports_ex_timer() {
+            return Err(NtError::new(STATUS_NOT_SUPPORTED));
+        }
+
         let mut callback_stub: PEXT_CALLBACK = None;
         let mut callback: *mut F = ptr::null_mut();
 
-        if f.is_some() {
-            callback = Box::into_raw(Box::new(f.unwrap()));
+        if let Some(f) = f {
+            callback = Box::into_raw(try_box(f)?);
             callback_stub = Some(hr_timer_routine_stub::<F>);
         }
 
         let timer =
             unsafe { ExAllocateTimer(callback_stub, callback as _, EX_TIMER_HIGH_RESOLUTION) };
 
+        if timer.is_null() {
+            if !callback.is_null() {
+                let _ = unsafe { Box::from_raw(callback) };
+            }
+
+            return Err(NtError::new(STATUS_INSUFFICIENT_RESOURCES));
+        }
+
         Ok(Self(timer))
     }
 
@@ -205,8 +527,8 @@ impl HRTimer {
         unsafe {
             ExSetTimer(
                 self.0,
-                -1 * after.as_millis() as i64 * 1_0000,
-                (period.as_micros() * 10) as _,
+                time::relative(after).QuadPart,
+                time::to_units(period),
                 ptr::null_mut(),
             );
         }
@@ -242,7 +564,7 @@ extern "C" fn hr_timer_routine_once_stub<F: FnOnce()>(timer: PEX_TIMER, context:
 
 impl DelayRun for HRTimer {
     fn delay_run<F: FnOnce() + 'static>(f: F, after: Duration) -> Result<(), NtError> {
-        let callback = Box::into_raw(Box::new(f));
+        let callback = Box::into_raw(try_box(f)?);
 
         let timer = unsafe {
             ExAllocateTimer(
@@ -252,19 +574,24 @@ impl DelayRun for HRTimer {
             )
         };
 
+        if timer.is_null() {
+            let _ = unsafe { Box::from_raw(callback) };
+
+            return Err(NtError::new(STATUS_INSUFFICIENT_RESOURCES));
+        }
+
         unsafe {
-            ExSetTimer(
-                timer,
-                -1 * after.as_millis() as i64 * 1_0000,
-                0,
-                ptr::null_mut(),
-            );
+            ExSetTimer(timer, time::relative(after).QuadPart, 0, ptr::null_mut());
         }
 
         Ok(())
     }
 }
 
+// Safety
+// the timer is owned and the callback is `Send + Sync`, it runs in a DPC anyway
+unsafe impl Send for HRTimer {}
+
 impl Drop for HRTimer {
     fn drop(&mut self) {
         unsafe {
@@ -336,13 +663,15 @@ pub fn resotre_timer_resolution() {
 pub struct ThreadTimer(PKTIMER);
 
 impl ThreadTimer {
+    /// same as `try_new`
     pub fn new(is_synch: bool) -> Result<Self, NtError> {
-        let layout =
-            ex_allocate_pool_zero(NonPagedPoolNx, mem::size_of::<KTIMER>() as _, TIMER_TAG);
+        Self::try_new(is_synch)
+    }
 
-        if layout.is_null() {
-            return Err(NtError::new(STATUS_INSUFFICIENT_RESOURCES));
-        }
+    /// it fails with STATUS_INSUFFICIENT_RESOURCES if the timer can not be allocated
+    pub fn try_new(is_synch: bool) -> Result<Self, NtError> {
+        let layout =
+            ex_allocate_pool_zero(NonPagedPoolNx, mem::size_of::<KTIMER>() as _, TIMER_TAG)?;
 
         unsafe {
             KeInitializeTimerEx(
@@ -372,7 +701,7 @@ unsafe impl Sync for ThreadTimer {}
 
 impl Drop for ThreadTimer {
     fn drop(&mut self) {
-        unsafe { ExFreePoolWithTag(self.0.cast(), TIMER_TAG); }
+        unsafe { ex_free_pool(self.0.cast(), TIMER_TAG); }
     }
 }
 
@@ -395,6 +724,17 @@ impl ThreadHRTimer {
     /// # Refer
     /// see https://learn.microsoft.com/en-us/windows-hardware/drivers/ddi/wdm/nf-wdm-exallocatetimer for details
     pub fn new(is_sync: bool) -> Result<Self, NtError> {
+        Self::try_new(is_sync)
+    }
+
+    /// same as `new`, it fails with STATUS_INSUFFICIENT_RESOURCES if the timer can not be allocated
+    ///
+    /// it fails with STATUS_NOT_SUPPORTED before Windows 8.1
+    pub fn try_new(is_sync: bool) -> Result<Self, NtError> {
+        if !os::supports_ex_timer() {
+            return Err(NtError::new(STATUS_NOT_SUPPORTED));
+        }
+
         let mut attr: u32 = EX_TIMER_HIGH_RESOLUTION;
 
         if !is_sync {
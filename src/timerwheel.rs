@@ -0,0 +1,389 @@
+//! this module provides `TimerWheel`, a hashed timer wheel for a large number of coarse timeouts
+//!
+//! a single periodic KTIMER and its DPC advance the wheel by one tick every `granularity`, a timeout is hashed into
+//! the slot of its deadline tick, so scheduling, resetting and canceling a timeout are O(1) and no KTIMER is
+//! allocated per timeout
+//!
+//! the callbacks are run in the DPC at DISPATCH_LEVEL, after the lock of the wheel is released, so a callback can
+//! schedule or cancel other timeouts
+//!
+//! # Example
+//! ```
+//! // 100ms ticks, 512 slots cover 51.2 seconds per round
+//! let wheel = TimerWheel::new(Duration::from_millis(100), 512)?;
+//!
+//! let handle = wheel.schedule(Duration::from_secs(30), move || {
+//!     expire_connection(id);
+//! })?;
+//!
+//! // the connection is active again
+//! wheel.reset(handle, Duration::from_secs(30));
+//!
+//! // the connection is closed
+//! wheel.cancel(handle);
+//! ```
+use core::{cell::UnsafeCell, time::Duration};
+
+use alloc::{boxed::Box, vec::Vec};
+use wdk_sys::{
+    _KDPC, _KTIMER,
+    _TIMER_TYPE::NotificationTimer,
+    PKDPC, PVOID, STATUS_INSUFFICIENT_RESOURCES, STATUS_INVALID_PARAMETER,
+    ntddk::{KeCancelTimer, KeFlushQueuedDpcs, KeInitializeDpc, KeInitializeTimerEx, KeSetTimerEx},
+};
+
+use crate::{mutex::StaticSpinLocked, ntstatus::NtError, time, utils::try_box};
+
+const NIL: u32 = u32::MAX;
+
+type Callback = Box<dyn FnOnce() + Send>;
+
+/// A handle of a scheduled timeout, it is stale once the timeout is run or canceled
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct TimeoutHandle {
+    index: u32,
+    generation: u32,
+}
+
+struct Entry {
+    prev: u32,
+    next: u32,
+    /// the slot the entry is linked in, the expired list is `slots`, NIL if it is free
+    list: u32,
+    deadline: u64,
+    generation: u32,
+    callback: Option<Callback>,
+}
+
+struct State {
+    entries: Vec<Entry>,
+    /// the free entries are linked by `next`
+    free: u32,
+    /// the heads of the slots followed by the head of the expired list
+    heads: Vec<u32>,
+    tick: u64,
+    len: usize,
+}
+
+impl State {
+    fn link(&mut self, index: u32, list: u32) {
+        let head = self.heads[list as usize];
+
+        let entry = &mut self.entries[index as usize];
+        entry.list = list;
+        entry.prev = NIL;
+        entry.next = head;
+
+        if head != NIL {
+            self.entries[head as usize].prev = index;
+        }
+
+        self.heads[list as usize] = index;
+    }
+
+    fn unlink(&mut self, index: u32) {
+        let Entry {
+            prev, next, list, ..
+        } = self.entries[index as usize];
+
+        if prev == NIL {
+            self.heads[list as usize] = next;
+        } else {
+            self.entries[prev as usize].next = next;
+        }
+
+        if next != NIL {
+            self.entries[next as usize].prev = prev;
+        }
+    }
+
+    fn allocate(&mut self) -> Result<u32, NtError> {
+        if self.free != NIL {
+            let index = self.free;
+            self.free = self.entries[index as usize].next;
+            return Ok(index);
+        }
+
+        if self.entries.len() >= NIL as usize {
+            return Err(NtError::new(STATUS_INSUFFICIENT_RESOURCES));
+        }
+
+        self.entries
+            .try_reserve(1)
+            .map_err(|_| NtError::new(STATUS_INSUFFICIENT_RESOURCES))?;
+
+        self.entries.push(Entry {
+            prev: NIL,
+            next: NIL,
+            list: NIL,
+            deadline: 0,
+            generation: 0,
+            callback: None,
+        });
+
+        Ok((self.entries.len() - 1) as u32)
+    }
+
+    /// unlink a linked entry and return its callback, the entry is released to the free list
+    fn remove(&mut self, index: u32) -> Option<Callback> {
+        self.unlink(index);
+
+        let free = self.free;
+
+        self.free = index;
+        self.len -= 1;
+
+        let entry = &mut self.entries[index as usize];
+        entry.list = NIL;
+        entry.generation = entry.generation.wrapping_add(1);
+        entry.next = free;
+
+        entry.callback.take()
+    }
+
+    fn find(&self, handle: TimeoutHandle) -> Option<u32> {
+        self.entries
+            .get(handle.index as usize)
+            .filter(|entry| entry.generation == handle.generation && entry.list != NIL)
+            .map(|_| handle.index)
+    }
+}
+
+struct Inner {
+    timer: UnsafeCell<_KTIMER>,
+    dpc: UnsafeCell<_KDPC>,
+    state: StaticSpinLocked<State>,
+    granularity: Duration,
+    slots: u32,
+}
+
+impl Inner {
+    /// the number of ticks until `after` expires, at least one
+    fn ticks(&self, after: Duration) -> u64 {
+        let ticks = after.as_nanos().div_ceil(self.granularity.as_nanos());
+
+        ticks.clamp(1, u64::MAX as u128) as u64
+    }
+
+    fn tick(&self) {
+        let expired = self.slots;
+
+        {
+            let mut state = self.state.lock();
+
+            state.tick += 1;
+
+            let now = state.tick;
+            let mut index = state.heads[(now % self.slots as u64) as usize];
+
+            // the entries of the later rounds stay in the slot
+            while index != NIL {
+                let next = state.entries[index as usize].next;
+
+                if state.entries[index as usize].deadline <= now {
+                    state.unlink(index);
+                    state.link(index, expired);
+                }
+
+                index = next;
+            }
+        }
+
+        // the callbacks are run without the lock, one at a time
+        loop {
+            let callback = {
+                let mut state = self.state.lock();
+
+                let index = state.heads[expired as usize];
+
+                if index == NIL {
+                    break;
+                }
+
+                state.remove(index)
+            };
+
+            if let Some(callback) = callback {
+                callback();
+            }
+        }
+    }
+}
+
+extern "C" fn tick_routine(_dpc: PKDPC, context: PVOID, _arg1: PVOID, _arg2: PVOID) {
+    let inner = unsafe { &*(context as *const Inner) };
+
+    inner.tick();
+}
+
+/// A hashed timer wheel driven by a single periodic timer, the timer is stopped on drop
+pub struct TimerWheel {
+    inner: Box<Inner>,
+}
+
+impl TimerWheel {
+    /// create a wheel of `slots` slots which ticks every `granularity`, the granularity is rounded up to a whole
+    /// number of milliseconds, which is the resolution of the period of the timer, see `granularity`
+    ///
+    /// a timeout longer than `granularity * slots` stays in its slot for more than one round, so pick enough slots
+    /// to cover the common timeouts
+    pub fn new(granularity: Duration, slots: usize) -> Result<Self, NtError> {
+        if slots == 0 || slots >= NIL as usize {
+            return Err(NtError::new(STATUS_INVALID_PARAMETER));
+        }
+
+        // the period of KeSetTimerEx is a LONG of milliseconds, the ticks are counted with the same value
+        let millis = granularity
+            .as_nanos()
+            .div_ceil(1_000_000)
+            .clamp(1, i32::MAX as u128) as u64;
+        let granularity = Duration::from_millis(millis);
+
+        let mut heads = Vec::new();
+
+        heads
+            .try_reserve_exact(slots + 1)
+            .map_err(|_| NtError::new(STATUS_INSUFFICIENT_RESOURCES))?;
+        heads.resize(slots + 1, NIL);
+
+        let inner = try_box(Inner {
+            timer: UnsafeCell::new(_KTIMER::default()),
+            dpc: UnsafeCell::new(_KDPC::default()),
+            state: StaticSpinLocked::new(State {
+                entries: Vec::new(),
+                free: NIL,
+                heads,
+                tick: 0,
+                len: 0,
+            }),
+            granularity,
+            slots: slots as u32,
+        })?;
+
+        unsafe {
+            KeInitializeTimerEx(inner.timer.get(), NotificationTimer);
+            KeInitializeDpc(
+                inner.dpc.get(),
+                Some(tick_routine),
+                inner.as_ref() as *const Inner as _,
+            );
+
+            KeSetTimerEx(
+                inner.timer.get(),
+                time::relative(granularity),
+                granularity.as_millis() as _,
+                inner.dpc.get(),
+            );
+        }
+
+        Ok(Self { inner })
+    }
+
+    /// pre-allocate the entries of `additional` more timeouts, so `schedule` does not allocate in a hot path
+    pub fn reserve(&self, additional: usize) -> Result<(), NtError> {
+        self.inner
+            .state
+            .lock()
+            .entries
+            .try_reserve(additional)
+            .map_err(|_| NtError::new(STATUS_INSUFFICIENT_RESOURCES))
+    }
+
+    /// run `f` in the DPC of the wheel after `after`, it can be called at IRQL <= DISPATCH_LEVEL
+    ///
+    /// the timeout expires on a tick boundary, up to one `granularity` later than `after`
+    pub fn schedule<F>(&self, after: Duration, f: F) -> Result<TimeoutHandle, NtError>
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        let callback: Callback = try_box(f)?;
+
+        let mut state = self.inner.state.lock();
+
+        let index = state.allocate()?;
+        let deadline = state.tick.saturating_add(self.inner.ticks(after));
+
+        let entry = &mut state.entries[index as usize];
+        entry.deadline = deadline;
+        entry.callback = Some(callback);
+
+        let generation = entry.generation;
+
+        state.link(index, (deadline % self.inner.slots as u64) as u32);
+        state.len += 1;
+
+        Ok(TimeoutHandle { index, generation })
+    }
+
+    /// cancel a timeout, returns false if it has been run or canceled
+    pub fn cancel(&self, handle: TimeoutHandle) -> bool {
+        let callback = {
+            let mut state = self.inner.state.lock();
+
+            match state.find(handle) {
+                Some(index) => state.remove(index),
+                None => return false,
+            }
+        };
+
+        // the closure is dropped without the lock
+        drop(callback);
+
+        true
+    }
+
+    /// push the deadline of a pending timeout to `after` from now, returns false if it has been run or canceled
+    pub fn reset(&self, handle: TimeoutHandle, after: Duration) -> bool {
+        let mut state = self.inner.state.lock();
+
+        let Some(index) = state.find(handle) else {
+            return false;
+        };
+
+        let deadline = state.tick.saturating_add(self.inner.ticks(after));
+
+        state.unlink(index);
+        state.entries[index as usize].deadline = deadline;
+        state.link(index, (deadline % self.inner.slots as u64) as u32);
+
+        true
+    }
+
+    /// true if the timeout is neither run nor canceled
+    pub fn is_pending(&self, handle: TimeoutHandle) -> bool {
+        self.inner.state.lock().find(handle).is_some()
+    }
+
+    /// the number of pending timeouts
+    pub fn len(&self) -> usize {
+        self.inner.state.lock().len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// the rounded granularity, the actual tick of the wheel
+    pub fn granularity(&self) -> Duration {
+        self.inner.granularity
+    }
+
+    pub fn slots(&self) -> usize {
+        self.inner.slots as usize
+    }
+}
+
+impl Drop for TimerWheel {
+    /// it must be called at PASSIVE_LEVEL, the pending timeouts are dropped without being run
+    fn drop(&mut self) {
+        unsafe {
+            KeCancelTimer(self.inner.timer.get());
+
+            // wait for a running tick
+            KeFlushQueuedDpcs();
+        }
+    }
+}
+
+unsafe impl Send for TimerWheel {}
+unsafe impl Sync for TimerWheel {}
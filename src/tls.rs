@@ -0,0 +1,272 @@
+//! this module provides `KTls<T>`, a thread-local storage emulation for the kernel threads
+//!
+//! the values are kept in a `KHashMap` keyed by the current `PKTHREAD`, a thread spawned by `thread::spawn`
+//! removes its values from all the live `KTls` when its routine returns, any other thread, e.g. a system worker
+//! thread or a thread calling into a callback, must call `remove` before it leaves, since a freed thread object may
+//! be reused by a new thread which would then see the stale value
+//!
+//! the values are accessed under a spin lock of the map, so the closures of `with` run at DISPATCH_LEVEL
+//!
+//! # Example
+//! ```
+//! struct Depth(u32);
+//!
+//! let depth: KArc<KTls<Depth>> = KArc::new(KTls::new()?)?;
+//! let tls = depth.clone();
+//!
+//! thread::spawn(move || {
+//!     let _ = tls.set(Depth(0));
+//!
+//!     tls.with(|depth| depth.0 += 1);
+//!
+//!     // the value is removed when the thread routine returns
+//! })?;
+//! ```
+use core::mem::{self, ManuallyDrop};
+
+use alloc::vec::Vec;
+use wdk_sys::STATUS_INSUFFICIENT_RESOURCES;
+
+use crate::{
+    arc::KArc, hashmap::KHashMap, mutex::StaticSpinLocked, ntstatus::NtError,
+    utils::KeGetCurrentThread,
+};
+
+/// a live `KTls` with its type erased, it holds a strong reference
+#[derive(Clone, Copy)]
+struct Registration {
+    inner: *const (),
+    retain: unsafe fn(*const ()),
+    release: unsafe fn(*const ()),
+    remove: unsafe fn(*const (), usize),
+    contains: unsafe fn(*const (), usize) -> bool,
+}
+
+// the erased `KArc` is only touched through the functions of `T: Send`
+unsafe impl Send for Registration {}
+
+static REGISTRY: StaticSpinLocked<Vec<Registration>> = StaticSpinLocked::new(Vec::new());
+
+struct Inner<T> {
+    values: KHashMap<usize, T>,
+}
+
+unsafe fn retain<T>(inner: *const ()) {
+    let arc = ManuallyDrop::new(unsafe { KArc::from_raw(inner as *const Inner<T>) });
+
+    let _ = KArc::into_raw(KArc::clone(&arc));
+}
+
+unsafe fn release<T>(inner: *const ()) {
+    drop(unsafe { KArc::from_raw(inner as *const Inner<T>) });
+}
+
+unsafe fn remove<T>(inner: *const (), thread: usize) {
+    let inner = unsafe { &*(inner as *const Inner<T>) };
+
+    drop(inner.values.remove(&thread));
+}
+
+unsafe fn contains<T>(inner: *const (), thread: usize) -> bool {
+    let inner = unsafe { &*(inner as *const Inner<T>) };
+
+    inner.values.contains_key(&thread)
+}
+
+fn current() -> usize {
+    KeGetCurrentThread() as usize
+}
+
+/// A value per kernel thread
+pub struct KTls<T: Send> {
+    inner: KArc<Inner<T>>,
+}
+
+impl<T: Send> KTls<T> {
+    /// create an empty storage, it fails with STATUS_INSUFFICIENT_RESOURCES if it can not be allocated
+    pub fn new() -> Result<Self, NtError> {
+        let inner = KArc::new(Inner {
+            values: KHashMap::new()?,
+        })?;
+
+        let registration = Registration {
+            inner: KArc::into_raw(inner.clone()).cast(),
+            retain: retain::<T>,
+            release: release::<T>,
+            remove: remove::<T>,
+            contains: contains::<T>,
+        };
+
+        let pushed = {
+            let mut registry = REGISTRY.lock();
+
+            match registry.try_reserve(1) {
+                Ok(_) => {
+                    registry.push(registration);
+                    true
+                }
+                Err(_) => false,
+            }
+        };
+
+        if !pushed {
+            unsafe { release::<T>(registration.inner) };
+
+            return Err(NtError::new(STATUS_INSUFFICIENT_RESOURCES));
+        }
+
+        Ok(Self { inner })
+    }
+
+    /// set the value of the current thread, returns the old one
+    pub fn set(&self, value: T) -> Result<Option<T>, NtError> {
+        self.inner.values.insert(current(), value)
+    }
+
+    /// run `f` with the value of the current thread, `None` if it has no value
+    pub fn with<R, F: FnOnce(&mut T) -> R>(&self, f: F) -> Option<R> {
+        self.inner
+            .values
+            .entry(current(), |entry| entry.get_mut().map(f))
+            .ok()
+            .flatten()
+    }
+
+    /// run `f` with the value of the current thread, the value is created by `init` if it has none
+    pub fn with_or_insert<R, I, F>(&self, init: I, f: F) -> Result<R, NtError>
+    where
+        I: FnOnce() -> T,
+        F: FnOnce(&mut T) -> R,
+    {
+        self.inner
+            .values
+            .entry(current(), |entry| f(entry.or_insert_with(init)))
+    }
+
+    pub fn get_cloned(&self) -> Option<T>
+    where
+        T: Clone,
+    {
+        self.inner.values.get_cloned(&current())
+    }
+
+    /// take the value of the current thread out
+    ///
+    /// a thread not spawned by `thread::spawn` must call it before it leaves
+    pub fn remove(&self) -> Option<T> {
+        self.inner.values.remove(&current())
+    }
+
+    /// the number of the threads which have a value
+    pub fn len(&self) -> usize {
+        self.inner.values.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl<T: Send> Drop for KTls<T> {
+    fn drop(&mut self) {
+        let inner = KArc::as_ptr(&self.inner).cast::<()>();
+
+        let (registration, emptied) = {
+            let mut registry = REGISTRY.lock();
+
+            let registration = registry
+                .iter()
+                .position(|registration| registration.inner == inner)
+                .map(|index| registry.swap_remove(index));
+
+            // the last `KTls` frees the registry, so nothing is left allocated once the driver unloads
+            let emptied = match registry.is_empty() {
+                true => mem::take(&mut *registry),
+                false => Vec::new(),
+            };
+
+            (registration, emptied)
+        };
+
+        drop(emptied);
+
+        // the values left are dropped with the last reference, which may be held by an exiting thread
+        if let Some(registration) = registration {
+            unsafe { (registration.release)(registration.inner) };
+        }
+    }
+}
+
+unsafe impl<T: Send> Send for KTls<T> {}
+unsafe impl<T: Send> Sync for KTls<T> {}
+
+/// remove the values of the current thread from all the live `KTls`, it is called when a thread spawned by
+/// `thread::spawn` returns from its routine
+pub(crate) fn thread_exit() {
+    let thread = current();
+    let mut live: Vec<Registration> = Vec::new();
+
+    // the registrations are copied out, so the values are not dropped under the spin lock
+    loop {
+        let count = REGISTRY.lock().len();
+
+        if count == 0 {
+            return;
+        }
+
+        if live.try_reserve_exact(count).is_err() {
+            return thread_exit_one_by_one(thread);
+        }
+
+        let registry = REGISTRY.lock();
+
+        if registry.len() > live.capacity() {
+            continue;
+        }
+
+        for registration in registry.iter() {
+            unsafe { (registration.retain)(registration.inner) };
+            live.push(*registration);
+        }
+
+        break;
+    }
+
+    for registration in live {
+        unsafe {
+            (registration.remove)(registration.inner, thread);
+            (registration.release)(registration.inner);
+        }
+    }
+}
+
+/// same as `thread_exit` without an allocation, when the registrations can not be copied out
+///
+/// it picks the live `KTls` which still holds a value of the thread one at a time, each pick scans the registry
+fn thread_exit_one_by_one(thread: usize) {
+    loop {
+        let registration = {
+            let registry = REGISTRY.lock();
+
+            let found = registry
+                .iter()
+                .find(|registration| unsafe { (registration.contains)(registration.inner, thread) })
+                .copied();
+
+            if let Some(registration) = found {
+                unsafe { (registration.retain)(registration.inner) };
+            }
+
+            found
+        };
+
+        let Some(registration) = registration else {
+            return;
+        };
+
+        unsafe {
+            (registration.remove)(registration.inner, thread);
+            (registration.release)(registration.inner);
+        }
+    }
+}
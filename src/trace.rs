@@ -0,0 +1,897 @@
+//! this module provides a lightweight tracing subsystem that can be used at any IRQL
+//!
+//! a message is formatted into a fixed-size stack buffer(no heap allocation) and handed to the installed `Sink`,
+//! `DbgPrintSink` is installed by default
+//!
+//! messages are filtered by a global maximum level and a component mask, each component is a bit of the mask
+//!
+//! `DeferredSink` queues the messages into a nonpaged ring and forwards them to another sink from a system thread,
+//! so a sink which must run at PASSIVE_LEVEL can be used from DPCs and spin lock protected sections
+//!
+//! `FileSink` appends the messages to a log file with size-based rotation, it is usually installed behind a
+//! `DeferredSink`
+//!
+//! # Example
+//! ```
+//! const NET: u32 = 1 << 1;
+//!
+//! trace::set_level(Level::Debug);
+//! trace::enable_components(NET);
+//!
+//! trace_info!("driver loaded, version {}", 1);
+//! trace_debug!(component: NET, "{} bytes received", size);
+//! ```
+use core::{
+    cell::UnsafeCell,
+    fmt::{self, Write},
+    mem, ptr,
+    sync::atomic::{AtomicBool, AtomicPtr, AtomicU8, AtomicU32, AtomicU64, AtomicUsize, Ordering},
+    time::Duration,
+};
+
+use alloc::{boxed::Box, vec::Vec};
+use wdk_sys::{
+    _DPFLTR_TYPE::DPFLTR_IHVDRIVER_ID,
+    _FILE_INFORMATION_CLASS::{FileRenameInformation, FileStandardInformation},
+    BOOLEAN, DELETE, DISPATCH_LEVEL, DPFLTR_ERROR_LEVEL, DPFLTR_INFO_LEVEL, DPFLTR_TRACE_LEVEL,
+    DPFLTR_WARNING_LEVEL, FILE_APPEND_DATA, FILE_ATTRIBUTE_NORMAL, FILE_NON_DIRECTORY_FILE,
+    FILE_OPEN, FILE_OPEN_IF, FILE_OVERWRITE_IF, FILE_SHARE_DELETE, FILE_SHARE_READ,
+    FILE_STANDARD_INFORMATION, FILE_SYNCHRONOUS_IO_NONALERT, FILE_WRITE_THROUGH, HANDLE,
+    IO_STATUS_BLOCK, NTSTATUS, OBJ_CASE_INSENSITIVE, OBJ_KERNEL_HANDLE, PASSIVE_LEVEL,
+    PIO_STATUS_BLOCK, STATUS_INSUFFICIENT_RESOURCES, STATUS_INVALID_PARAMETER, SYNCHRONIZE, TRUE,
+    ULONG,
+    ntddk::{
+        DbgPrintEx, KeGetCurrentIrql, ZwCreateFile, ZwQueryInformationFile, ZwSetInformationFile,
+        ZwWriteFile,
+    },
+};
+
+use crate::{
+    arc::KArc,
+    event::{Event, EventProperty},
+    handle::ObjectHandle,
+    initialize_object_attributes,
+    kobject::Dispatchable,
+    kvec::KString,
+    mutex::{FastLocked, StaticSpinLocked},
+    ntstatus::{NtError, cvt},
+    thread::{self, JoinHandle},
+    time::{KInstant, KSystemTime},
+    unicode::NtUnicodeString,
+};
+
+/// the size of the stack buffer a message is formatted into, longer messages are truncated
+pub const MAX_MESSAGE_LEN: usize = 512;
+
+/// the component used by the macros when no component is specified
+pub const DEFAULT_COMPONENT: u32 = 1;
+
+#[repr(u8)]
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub enum Level {
+    Error = 1,
+    Warn = 2,
+    Info = 3,
+    Debug = 4,
+}
+
+impl Level {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Level::Error => "ERROR",
+            Level::Warn => "WARN",
+            Level::Info => "INFO",
+            Level::Debug => "DEBUG",
+        }
+    }
+}
+
+/// A destination of trace messages
+///
+/// `write` may be called at any IRQL up to HIGH_LEVEL, so it must not allocate, wait or touch paged memory
+pub trait Sink: Sync {
+    fn write(&self, level: Level, component: u32, message: &str);
+
+    /// called at PASSIVE_LEVEL by the flush thread of a `DeferredSink` at least every `FLUSH_INTERVAL`, a buffering
+    /// sink can flush the messages here so they do not stay in memory when the writes stop
+    fn periodic_flush(&self) {}
+}
+
+/// A sink writes messages to the kernel debugger with `DbgPrintEx`
+///
+/// the messages can be viewed in WinDbg or DebugView, with the `IHVDRIVER` component filter enabled
+pub struct DbgPrintSink;
+
+impl Sink for DbgPrintSink {
+    fn write(&self, level: Level, component: u32, message: &str) {
+        let dbg_level = match level {
+            Level::Error => DPFLTR_ERROR_LEVEL,
+            Level::Warn => DPFLTR_WARNING_LEVEL,
+            Level::Info => DPFLTR_TRACE_LEVEL,
+            Level::Debug => DPFLTR_INFO_LEVEL,
+        };
+
+        unsafe {
+            DbgPrintEx(
+                DPFLTR_IHVDRIVER_ID as _,
+                dbg_level,
+                c"[%s][%x] %.*s\n".as_ptr(),
+                level_cstr(level),
+                component,
+                message.len() as i32,
+                message.as_ptr(),
+            );
+        }
+    }
+}
+
+fn level_cstr(level: Level) -> *const i8 {
+    match level {
+        Level::Error => c"ERROR".as_ptr(),
+        Level::Warn => c"WARN".as_ptr(),
+        Level::Info => c"INFO".as_ptr(),
+        Level::Debug => c"DEBUG".as_ptr(),
+    }
+}
+
+static DEFAULT_SINK: DbgPrintSink = DbgPrintSink;
+
+static MAX_LEVEL: AtomicU8 = AtomicU8::new(Level::Info as u8);
+
+static COMPONENTS: AtomicU32 = AtomicU32::new(u32::MAX);
+
+/// null means the `DEFAULT_SINK`
+static SINK: AtomicPtr<&'static dyn Sink> = AtomicPtr::new(ptr::null_mut());
+
+/// set the maximum level of the messages to be emitted
+pub fn set_level(level: Level) {
+    MAX_LEVEL.store(level as u8, Ordering::Relaxed);
+}
+
+pub fn level() -> Level {
+    match MAX_LEVEL.load(Ordering::Relaxed) {
+        1 => Level::Error,
+        2 => Level::Warn,
+        3 => Level::Info,
+        _ => Level::Debug,
+    }
+}
+
+/// only emit messages of the components in `mask`, all the components are enabled by default
+pub fn set_components(mask: u32) {
+    COMPONENTS.store(mask, Ordering::Relaxed);
+}
+
+pub fn enable_components(mask: u32) {
+    COMPONENTS.fetch_or(mask, Ordering::Relaxed);
+}
+
+pub fn disable_components(mask: u32) {
+    COMPONENTS.fetch_and(!mask, Ordering::Relaxed);
+}
+
+/// returns true if a message of `level` and `component` will be emitted
+#[inline]
+pub fn enabled(level: Level, component: u32) -> bool {
+    level as u8 <= MAX_LEVEL.load(Ordering::Relaxed)
+        && COMPONENTS.load(Ordering::Relaxed) & component != 0
+}
+
+/// install a sink, it must be called at PASSIVE_LEVEL
+///
+/// # Note
+/// the previous installed sink may still be in use by other processors, so the small slot holding it is leaked
+pub fn set_sink(sink: &'static dyn Sink) {
+    let slot = Box::into_raw(Box::new(sink));
+
+    SINK.store(slot, Ordering::Release);
+}
+
+/// restore the default `DbgPrintSink`, it must be called before the installed sink is freed
+pub fn reset_sink() {
+    SINK.store(ptr::null_mut(), Ordering::Release);
+}
+
+fn sink() -> &'static dyn Sink {
+    let slot = SINK.load(Ordering::Acquire);
+
+    if slot.is_null() {
+        &DEFAULT_SINK
+    } else {
+        unsafe { *slot }
+    }
+}
+
+/// A fixed-size buffer implementing `fmt::Write`, it is the `StackString` of the `fmt` module
+pub use crate::fmt::StackString as StackBuffer;
+
+/// format and emit a message, use the `trace_xxx!` macros instead
+pub fn write(level: Level, component: u32, args: fmt::Arguments) {
+    let mut buffer = StackBuffer::<MAX_MESSAGE_LEN>::new();
+
+    // a truncated message is still emitted
+    let _ = buffer.write_fmt(args);
+
+    sink().write(level, component, buffer.as_str());
+}
+
+/// the default number of messages a `DeferredSink` can hold
+pub const DEFAULT_RING_CAPACITY: usize = 256;
+
+/// the flush thread drains the ring at least this often, the messages written above DISPATCH_LEVEL can not wake it
+const FLUSH_INTERVAL: Duration = Duration::from_millis(100);
+
+struct Record {
+    /// the position the record is ready for, see `RecordRing`
+    sequence: AtomicUsize,
+    level: UnsafeCell<Level>,
+    component: UnsafeCell<u32>,
+    len: UnsafeCell<usize>,
+    data: UnsafeCell<[u8; MAX_MESSAGE_LEN]>,
+}
+
+/// a bounded multi-producer single-consumer ring of records, writing a record never waits so it works at any IRQL
+///
+/// a record at position `pos` can be written when its sequence is `pos`, and read when it is `pos + 1`
+struct RecordRing {
+    records: Box<[Record]>,
+    mask: usize,
+    head: AtomicUsize,
+    tail: AtomicUsize,
+}
+
+impl RecordRing {
+    fn new(capacity: usize) -> Result<Self, NtError> {
+        let capacity = capacity
+            .checked_next_power_of_two()
+            .ok_or_else(|| NtError::new(STATUS_INVALID_PARAMETER))?;
+
+        let mut records = Vec::new();
+
+        records
+            .try_reserve_exact(capacity)
+            .map_err(|_| NtError::new(STATUS_INSUFFICIENT_RESOURCES))?;
+
+        for sequence in 0..capacity {
+            records.push(Record {
+                sequence: AtomicUsize::new(sequence),
+                level: UnsafeCell::new(Level::Info),
+                component: UnsafeCell::new(0),
+                len: UnsafeCell::new(0),
+                data: UnsafeCell::new([0; MAX_MESSAGE_LEN]),
+            });
+        }
+
+        Ok(Self {
+            records: records.into_boxed_slice(),
+            mask: capacity - 1,
+            head: AtomicUsize::new(0),
+            tail: AtomicUsize::new(0),
+        })
+    }
+
+    /// returns false if the ring is full
+    fn push(&self, level: Level, component: u32, message: &str) -> bool {
+        let mut pos = self.tail.load(Ordering::Relaxed);
+
+        loop {
+            let record = &self.records[pos & self.mask];
+            let sequence = record.sequence.load(Ordering::Acquire);
+
+            match (sequence as isize).wrapping_sub(pos as isize) {
+                0 => match self.tail.compare_exchange_weak(
+                    pos,
+                    pos.wrapping_add(1),
+                    Ordering::Relaxed,
+                    Ordering::Relaxed,
+                ) {
+                    Ok(_) => {
+                        let len = message.len().min(MAX_MESSAGE_LEN);
+
+                        unsafe {
+                            *record.level.get() = level;
+                            *record.component.get() = component;
+                            *record.len.get() = len;
+                            (*record.data.get())[..len].copy_from_slice(&message.as_bytes()[..len]);
+                        }
+
+                        record
+                            .sequence
+                            .store(pos.wrapping_add(1), Ordering::Release);
+
+                        return true;
+                    }
+                    Err(current) => pos = current,
+                },
+                // the record of the previous round has not been read
+                diff if diff < 0 => return false,
+                _ => pos = self.tail.load(Ordering::Relaxed),
+            }
+        }
+    }
+
+    /// hand the oldest record to `f`, returns false if the ring is empty, it is only called by the flush thread
+    fn pop<F: FnOnce(Level, u32, &str)>(&self, f: F) -> bool {
+        let pos = self.head.load(Ordering::Relaxed);
+        let record = &self.records[pos & self.mask];
+
+        if record.sequence.load(Ordering::Acquire) != pos.wrapping_add(1) {
+            return false;
+        }
+
+        unsafe {
+            let data = &(*record.data.get())[..*record.len.get()];
+
+            // the message is copied from a `str`, it is cut at a char boundary by `StackBuffer`
+            f(
+                *record.level.get(),
+                *record.component.get(),
+                core::str::from_utf8(data).unwrap_or("<invalid utf-8>"),
+            );
+        }
+
+        record
+            .sequence
+            .store(pos.wrapping_add(self.mask + 1), Ordering::Release);
+        self.head.store(pos.wrapping_add(1), Ordering::Relaxed);
+
+        true
+    }
+
+    fn len(&self) -> usize {
+        self.tail
+            .load(Ordering::Relaxed)
+            .wrapping_sub(self.head.load(Ordering::Relaxed))
+            .min(self.mask + 1)
+    }
+}
+
+struct Deferred {
+    ring: RecordRing,
+    target: &'static dyn Sink,
+    /// an auto-reset event waking the flush thread
+    event: Event,
+    /// true if the event is set and the flush thread has not drained the ring yet
+    signaled: AtomicBool,
+    stop: AtomicBool,
+    dropped: AtomicU64,
+    /// the drop count last reported to the target, only used by the flush thread
+    reported: AtomicU64,
+}
+
+unsafe impl Send for Deferred {}
+unsafe impl Sync for Deferred {}
+
+impl Deferred {
+    fn drain(&self) {
+        while self
+            .ring
+            .pop(|level, component, message| self.target.write(level, component, message))
+        {}
+
+        let dropped = self.dropped.load(Ordering::Relaxed);
+        let reported = self.reported.swap(dropped, Ordering::Relaxed);
+
+        if dropped != reported {
+            let mut buffer = StackBuffer::<64>::new();
+
+            let _ = write!(buffer, "{} trace messages dropped", dropped - reported);
+
+            self.target
+                .write(Level::Warn, DEFAULT_COMPONENT, buffer.as_str());
+        }
+    }
+
+    fn flush_loop(&self) {
+        loop {
+            // the messages written before `stop` are drained by the last round
+            let stop = self.stop.load(Ordering::Acquire);
+
+            self.signaled.store(false, Ordering::SeqCst);
+            self.drain();
+            self.target.periodic_flush();
+
+            if stop {
+                break;
+            }
+
+            self.event.wait_for(FLUSH_INTERVAL, false);
+        }
+    }
+}
+
+/// A sink which queues the messages into a nonpaged ring, a system thread forwards them to `target` at PASSIVE_LEVEL
+///
+/// the messages are dropped and counted when the ring is full, the flush thread reports the count to `target`
+///
+/// # Example
+/// ```
+/// static FILE: OnceLock<FileSink> = OnceLock::new();
+/// static DEFERRED: OnceLock<DeferredSink> = OnceLock::new();
+///
+/// let file = FILE.get_or_try_init(|| FileSink::create("\\??\\C:\\Logs\\driver.log", FileSinkOptions::new()))?;
+/// let deferred = DEFERRED.get_or_try_init(|| DeferredSink::new(1024, file))?;
+///
+/// trace::set_sink(deferred);
+///
+/// // on unload
+/// trace::reset_sink();
+/// deferred.shutdown();
+/// ```
+pub struct DeferredSink {
+    inner: KArc<Deferred>,
+    thread: StaticSpinLocked<Option<JoinHandle>>,
+}
+
+impl DeferredSink {
+    /// create a sink holding up to `capacity` messages(rounded up to a power of two) and start its flush thread
+    ///
+    /// it must be called at PASSIVE_LEVEL, each message takes `MAX_MESSAGE_LEN` bytes of nonpaged pool
+    pub fn new(capacity: usize, target: &'static dyn Sink) -> Result<Self, NtError> {
+        if capacity == 0 {
+            return Err(NtError::new(STATUS_INVALID_PARAMETER));
+        }
+
+        let inner = KArc::new(Deferred {
+            ring: RecordRing::new(capacity)?,
+            target,
+            event: EventProperty::new().auto_reset(true).new_event()?,
+            signaled: AtomicBool::new(false),
+            stop: AtomicBool::new(false),
+            dropped: AtomicU64::new(0),
+            reported: AtomicU64::new(0),
+        })?;
+
+        let flusher = inner.clone();
+        let thread = thread::spawn(move || flusher.flush_loop())?;
+
+        Ok(Self {
+            inner,
+            thread: StaticSpinLocked::new(Some(thread)),
+        })
+    }
+
+    /// the number of messages dropped because the ring was full
+    pub fn dropped(&self) -> u64 {
+        self.inner.dropped.load(Ordering::Relaxed)
+    }
+
+    /// the number of messages waiting to be flushed
+    pub fn pending(&self) -> usize {
+        self.inner.ring.len()
+    }
+
+    /// flush the pending messages and stop the flush thread, it must be called at PASSIVE_LEVEL
+    ///
+    /// the messages written after it are kept in the ring until it is full, so reset the sink first
+    pub fn shutdown(&self) {
+        let thread = self.thread.lock().take();
+
+        if let Some(thread) = thread {
+            self.inner.stop.store(true, Ordering::Release);
+            self.inner.event.set();
+
+            let _ = thread.join();
+        }
+    }
+}
+
+impl Sink for DeferredSink {
+    fn write(&self, level: Level, component: u32, message: &str) {
+        let inner = &self.inner;
+
+        if !inner.ring.push(level, component, message) {
+            inner.dropped.fetch_add(1, Ordering::Relaxed);
+            return;
+        }
+
+        // above DISPATCH_LEVEL the message is picked up on the next `FLUSH_INTERVAL`
+        if unsafe { KeGetCurrentIrql() } <= DISPATCH_LEVEL as u8
+            && !inner.signaled.swap(true, Ordering::SeqCst)
+        {
+            inner.event.set();
+        }
+    }
+}
+
+impl Drop for DeferredSink {
+    fn drop(&mut self) {
+        self.shutdown();
+    }
+}
+
+#[macro_export]
+macro_rules! trace {
+    ($level:expr, component: $component:expr, $($arg:tt)+) => {
+        if $crate::trace::enabled($level, $component) {
+            $crate::trace::write($level, $component, format_args!($($arg)+));
+        }
+    };
+    ($level:expr, $($arg:tt)+) => {
+        $crate::trace!($level, component: $crate::trace::DEFAULT_COMPONENT, $($arg)+)
+    };
+}
+
+#[macro_export]
+macro_rules! trace_error {
+    ($($arg:tt)+) => {
+        $crate::trace!($crate::trace::Level::Error, $($arg)+)
+    };
+}
+
+#[macro_export]
+macro_rules! trace_warn {
+    ($($arg:tt)+) => {
+        $crate::trace!($crate::trace::Level::Warn, $($arg)+)
+    };
+}
+
+#[macro_export]
+macro_rules! trace_info {
+    ($($arg:tt)+) => {
+        $crate::trace!($crate::trace::Level::Info, $($arg)+)
+    };
+}
+
+#[macro_export]
+macro_rules! trace_debug {
+    ($($arg:tt)+) => {
+        $crate::trace!($crate::trace::Level::Debug, $($arg)+)
+    };
+}
+
+unsafe extern "C" {
+    pub fn ZwFlushBuffersFile(FileHandle: HANDLE, IoStatusBlock: PIO_STATUS_BLOCK) -> NTSTATUS;
+}
+
+/// the header of `FILE_RENAME_INFORMATION`, the name follows `file_name_length`
+#[repr(C)]
+struct FileRenameHeader {
+    replace_if_exists: BOOLEAN,
+    root_directory: HANDLE,
+    file_name_length: ULONG,
+}
+
+/// the offset of `FileName` in `FILE_RENAME_INFORMATION`
+const RENAME_NAME_OFFSET: usize = mem::offset_of!(FileRenameHeader, file_name_length) + 4;
+
+/// The options of a `FileSink`
+#[derive(Clone, Copy, Debug)]
+pub struct FileSinkOptions {
+    max_size: u64,
+    max_files: u32,
+    write_through: bool,
+    flush_interval: Duration,
+}
+
+impl FileSinkOptions {
+    /// no rotation, 3 backups once `max_size` is set, buffered writes flushed every second
+    pub const fn new() -> Self {
+        Self {
+            max_size: 0,
+            max_files: 3,
+            write_through: false,
+            flush_interval: Duration::from_secs(1),
+        }
+    }
+
+    /// rotate the file once it grows beyond `value` bytes, 0 disables the rotation
+    pub const fn max_size(mut self, value: u64) -> Self {
+        self.max_size = value;
+
+        self
+    }
+
+    /// the number of rotated files kept as `<path>.1`(the newest) to `<path>.<n>`, 0 truncates the file instead
+    pub const fn max_files(mut self, value: u32) -> Self {
+        self.max_files = value;
+
+        self
+    }
+
+    /// open the file with FILE_WRITE_THROUGH, every message hits the disk before `write` returns
+    ///
+    /// it is the safest choice if the logs must survive a bugcheck, at the cost of a disk write per message
+    pub const fn write_through(mut self, value: bool) -> Self {
+        self.write_through = value;
+
+        self
+    }
+
+    /// flush the buffers of the file when the last flush is older than `value`
+    ///
+    /// the flush happens on the next write, or on the next round of the flush thread if the sink is behind a
+    /// `DeferredSink`, so the last messages are flushed even if no more messages are written
+    pub const fn flush_interval(mut self, value: Duration) -> Self {
+        self.flush_interval = value;
+
+        self
+    }
+}
+
+impl Default for FileSinkOptions {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+struct LogFile {
+    handle: ObjectHandle,
+    size: u64,
+    last_flush: KInstant,
+    /// true if data was written since the last flush
+    dirty: bool,
+}
+
+/// A sink appending the messages to a log file, one line per message
+///
+/// the file is opened when the sink is created, the messages written above PASSIVE_LEVEL are dropped and counted,
+/// so install it behind a `DeferredSink` to log from any IRQL
+pub struct FileSink {
+    path: KString,
+    options: FileSinkOptions,
+    file: FastLocked<LogFile>,
+    dropped: AtomicU64,
+}
+
+impl FileSink {
+    /// open or create the log file at `path`(e.g. `\\??\\C:\\Logs\\driver.log`) for appending, it must be called at
+    /// PASSIVE_LEVEL
+    pub fn create(path: &str, options: FileSinkOptions) -> Result<Self, NtError> {
+        crate::irql_scope!(passive);
+
+        let handle = open_log(path, FILE_OPEN_IF, options.write_through)?;
+
+        let mut info = FILE_STANDARD_INFORMATION::default();
+        let mut io_status = IO_STATUS_BLOCK::default();
+
+        cvt(unsafe {
+            ZwQueryInformationFile(
+                handle.get(),
+                &mut io_status,
+                (&mut info as *mut FILE_STANDARD_INFORMATION).cast(),
+                mem::size_of::<FILE_STANDARD_INFORMATION>() as _,
+                FileStandardInformation,
+            )
+        })?;
+
+        Ok(Self {
+            path: KString::try_from_str(path)?,
+            options,
+            file: FastLocked::new(LogFile {
+                handle,
+                size: unsafe { info.EndOfFile.QuadPart } as u64,
+                last_flush: KInstant::now(),
+                dirty: false,
+            })?,
+            dropped: AtomicU64::new(0),
+        })
+    }
+
+    /// flush the buffers of the file to the disk, it must be called at PASSIVE_LEVEL
+    pub fn flush(&self) -> Result<(), NtError> {
+        crate::irql_scope!(passive);
+
+        let mut file = self.file.lock()?;
+
+        flush_log(&mut file)
+    }
+
+    /// the number of messages dropped because they were written above PASSIVE_LEVEL or the file could not be written
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    /// the size of the current file
+    pub fn size(&self) -> u64 {
+        self.file.lock().map_or(0, |file| file.size)
+    }
+
+    fn write_line(&self, line: &str) -> Result<(), NtError> {
+        let mut file = self.file.lock()?;
+
+        if self.options.max_size != 0
+            && file.size != 0
+            && file.size + line.len() as u64 > self.options.max_size
+        {
+            // keep writing to the current file if it can not be rotated
+            let _ = self.rotate(&mut file);
+        }
+
+        let mut io_status = IO_STATUS_BLOCK::default();
+
+        // the file is opened with FILE_APPEND_DATA only, so the data is always appended
+        cvt(unsafe {
+            ZwWriteFile(
+                file.handle.get(),
+                ptr::null_mut(),
+                None,
+                ptr::null_mut(),
+                &mut io_status,
+                line.as_ptr() as _,
+                line.len() as _,
+                ptr::null_mut(),
+                ptr::null_mut(),
+            )
+        })?;
+
+        file.size += line.len() as u64;
+        file.dirty = !self.options.write_through;
+
+        if !self.options.write_through && file.last_flush.elapsed() >= self.options.flush_interval {
+            flush_log(&mut file)?;
+        }
+
+        Ok(())
+    }
+
+    /// shift `<path>.<n>` to `<path>.<n + 1>`, move the current file to `<path>.1` and start a new one
+    fn rotate(&self, file: &mut LogFile) -> Result<(), NtError> {
+        let _ = flush_log(file);
+
+        if self.options.max_files != 0 {
+            for index in (1..self.options.max_files).rev() {
+                let from = self.backup_name(index)?;
+
+                // the backup does not exist yet
+                let Ok(handle) = open_log(&from, FILE_OPEN, false) else {
+                    continue;
+                };
+
+                rename(&handle, &self.backup_name(index + 1)?)?;
+            }
+
+            rename(&file.handle, &self.backup_name(1)?)?;
+        }
+
+        file.handle = open_log(&self.path, FILE_OVERWRITE_IF, self.options.write_through)?;
+        file.size = 0;
+
+        Ok(())
+    }
+
+    fn backup_name(&self, index: u32) -> Result<KString, NtError> {
+        let mut name = KString::new();
+
+        write!(name, "{}.{}", self.path, index)
+            .map_err(|_| NtError::new(STATUS_INSUFFICIENT_RESOURCES))?;
+
+        Ok(name)
+    }
+}
+
+impl Sink for FileSink {
+    fn write(&self, level: Level, component: u32, message: &str) {
+        if unsafe { KeGetCurrentIrql() } != PASSIVE_LEVEL as u8 {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+            return;
+        }
+
+        let mut line = StackBuffer::<{ MAX_MESSAGE_LEN + 64 }>::new();
+
+        let time = KSystemTime::now().since_unix_epoch().unwrap_or_default();
+
+        let _ = write!(
+            line,
+            "{}.{:03} [{}][{:x}] {}\r\n",
+            time.as_secs(),
+            time.subsec_millis(),
+            level.as_str(),
+            component,
+            message
+        );
+
+        if self.write_line(line.as_str()).is_err() {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    fn periodic_flush(&self) {
+        let Ok(mut file) = self.file.lock() else {
+            return;
+        };
+
+        if file.dirty && file.last_flush.elapsed() >= self.options.flush_interval {
+            let _ = flush_log(&mut file);
+        }
+    }
+}
+
+impl Drop for FileSink {
+    fn drop(&mut self) {
+        let _ = self.flush();
+    }
+}
+
+// Safety
+// the handle of the file is only used with the lock held
+unsafe impl Send for FileSink {}
+unsafe impl Sync for FileSink {}
+
+fn open_log(path: &str, disposition: ULONG, write_through: bool) -> Result<ObjectHandle, NtError> {
+    let name = NtUnicodeString::from_str(path)?;
+
+    let mut attributes = initialize_object_attributes!(
+        name.as_ptr(),
+        OBJ_KERNEL_HANDLE | OBJ_CASE_INSENSITIVE,
+        ptr::null_mut(),
+        ptr::null_mut()
+    );
+
+    let mut handle: HANDLE = ptr::null_mut();
+    let mut io_status = IO_STATUS_BLOCK::default();
+
+    let mut options = FILE_NON_DIRECTORY_FILE | FILE_SYNCHRONOUS_IO_NONALERT;
+
+    if write_through {
+        options |= FILE_WRITE_THROUGH;
+    }
+
+    cvt(unsafe {
+        ZwCreateFile(
+            &mut handle,
+            FILE_APPEND_DATA | DELETE | SYNCHRONIZE,
+            &mut attributes,
+            &mut io_status,
+            ptr::null_mut(),
+            FILE_ATTRIBUTE_NORMAL,
+            FILE_SHARE_READ | FILE_SHARE_DELETE,
+            disposition,
+            options,
+            ptr::null_mut(),
+            0,
+        )
+    })?;
+
+    Ok(ObjectHandle::new(handle))
+}
+
+fn flush_log(file: &mut LogFile) -> Result<(), NtError> {
+    let mut io_status = IO_STATUS_BLOCK::default();
+
+    file.last_flush = KInstant::now();
+    file.dirty = false;
+
+    cvt(unsafe { ZwFlushBuffersFile(file.handle.get(), &mut io_status) })
+}
+
+/// rename the file of `handle` to `path`, an existing file at `path` is replaced
+fn rename(handle: &ObjectHandle, path: &str) -> Result<(), NtError> {
+    let name = NtUnicodeString::from_str(path)?;
+    let name = name.as_slice();
+
+    let length = RENAME_NAME_OFFSET + mem::size_of_val(name);
+
+    let mut buffer: Vec<u64> = Vec::new();
+
+    buffer
+        .try_reserve_exact(length.div_ceil(8))
+        .map_err(|_| NtError::new(STATUS_INSUFFICIENT_RESOURCES))?;
+    buffer.resize(length.div_ceil(8), 0);
+
+    unsafe {
+        let info = buffer.as_mut_ptr() as *mut FileRenameHeader;
+
+        (*info).replace_if_exists = TRUE as _;
+        (*info).root_directory = ptr::null_mut();
+        (*info).file_name_length = mem::size_of_val(name) as _;
+
+        ptr::copy_nonoverlapping(
+            name.as_ptr(),
+            (info as *mut u8).add(RENAME_NAME_OFFSET) as *mut u16,
+            name.len(),
+        );
+    }
+
+    let mut io_status = IO_STATUS_BLOCK::default();
+
+    cvt(unsafe {
+        ZwSetInformationFile(
+            handle.get(),
+            &mut io_status,
+            buffer.as_mut_ptr().cast(),
+            length as _,
+            FileRenameInformation,
+        )
+    })
+}
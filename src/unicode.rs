@@ -0,0 +1,334 @@
+//! this module provides `NtUnicodeString`, an owned `UNICODE_STRING`
+//!
+//! the characters are kept in a heap buffer owned by the string, so the `UNICODE_STRING` header can be passed to
+//! the kernel APIs for as long as the `NtUnicodeString` lives
+//!
+//! # Example
+//! ```
+//! let name = NtUnicodeString::from_str("\\Device\\MyDevice")?;
+//!
+//! IoCreateDevice(driver, 0, name.as_ptr(), ...);
+//!
+//! let copy = unsafe { NtUnicodeString::from_raw(&(*file_object).FileName) }?;
+//! println!("{}", copy);
+//! ```
+//!
+//! the constant strings can be encoded at compile time instead, they live in the image without any allocation
+//!
+//! # Example
+//! ```
+//! // a null terminated `&'static [u16; N]`
+//! let path = w!("\\Registry\\Machine\\Software");
+//!
+//! // a `&'static ConstUnicodeString`
+//! let name = const_unicode_string!("\\Device\\MyDevice");
+//! IoCreateDevice(driver, 0, name.as_ptr(), ...);
+//! ```
+use core::{fmt, ops::Deref, slice};
+
+use alloc::{string::String, vec::Vec};
+use wdk_sys::{
+    PUNICODE_STRING, STATUS_INSUFFICIENT_RESOURCES, STATUS_NAME_TOO_LONG, UNICODE_STRING,
+};
+
+use crate::ntstatus::NtError;
+
+/// An owned counted UTF-16 string, it is not null terminated
+pub struct NtUnicodeString {
+    header: UNICODE_STRING,
+    buffer: Vec<u16>,
+}
+
+impl NtUnicodeString {
+    /// an empty string
+    pub fn new() -> Self {
+        Self {
+            header: UNICODE_STRING::default(),
+            buffer: Vec::new(),
+        }
+    }
+
+    /// copy UTF-16 characters, it fails with STATUS_NAME_TOO_LONG if it is longer than 32767 characters
+    pub fn from_utf16(chars: &[u16]) -> Result<Self, NtError> {
+        if chars.len() * 2 > u16::MAX as usize {
+            return Err(NtError::new(STATUS_NAME_TOO_LONG));
+        }
+
+        let mut buffer = Vec::new();
+
+        buffer
+            .try_reserve_exact(chars.len())
+            .map_err(|_| NtError::new(STATUS_INSUFFICIENT_RESOURCES))?;
+
+        buffer.extend_from_slice(chars);
+
+        let header = UNICODE_STRING {
+            Length: (buffer.len() * 2) as _,
+            MaximumLength: (buffer.len() * 2) as _,
+            Buffer: buffer.as_mut_ptr(),
+        };
+
+        Ok(Self { header, buffer })
+    }
+
+    pub fn from_str(s: &str) -> Result<Self, NtError> {
+        let chars: Vec<u16> = s.encode_utf16().collect();
+
+        Self::from_utf16(&chars)
+    }
+
+    /// copy a `UNICODE_STRING`
+    ///
+    /// # Safety
+    /// `s.Buffer` must be valid for `s.Length` bytes
+    pub unsafe fn from_raw(s: &UNICODE_STRING) -> Result<Self, NtError> {
+        if s.Buffer.is_null() || s.Length == 0 {
+            return Ok(Self::new());
+        }
+
+        Self::from_utf16(unsafe { slice::from_raw_parts(s.Buffer, s.Length as usize / 2) })
+    }
+
+    /// the header to be passed to the kernel APIs, it must not be modified
+    pub fn as_ptr(&self) -> PUNICODE_STRING {
+        &self.header as *const _ as _
+    }
+
+    pub fn as_raw(&self) -> &UNICODE_STRING {
+        &self.header
+    }
+
+    pub fn as_slice(&self) -> &[u16] {
+        &self.buffer
+    }
+
+    /// the number of UTF-16 characters
+    pub fn len(&self) -> usize {
+        self.buffer.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.buffer.is_empty()
+    }
+
+    pub fn to_string_lossy(&self) -> String {
+        String::from_utf16_lossy(&self.buffer)
+    }
+}
+
+impl Clone for NtUnicodeString {
+    fn clone(&self) -> Self {
+        Self::from_utf16(&self.buffer).expect("can not allocate memory for NtUnicodeString")
+    }
+}
+
+impl fmt::Display for NtUnicodeString {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for c in char::decode_utf16(self.buffer.iter().copied()) {
+            fmt::Write::write_char(f, c.unwrap_or(char::REPLACEMENT_CHARACTER))?;
+        }
+
+        Ok(())
+    }
+}
+
+impl fmt::Debug for NtUnicodeString {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "\"{}\"", self)
+    }
+}
+
+impl PartialEq for NtUnicodeString {
+    fn eq(&self, other: &Self) -> bool {
+        self.buffer == other.buffer
+    }
+}
+
+impl Eq for NtUnicodeString {}
+
+// Safety
+// the header only points to the owned buffer
+unsafe impl Send for NtUnicodeString {}
+unsafe impl Sync for NtUnicodeString {}
+
+/// the number of UTF-16 code units of `s`, without the null terminator
+pub const fn utf16_len(s: &str) -> usize {
+    let bytes = s.as_bytes();
+
+    let mut i = 0;
+    let mut len = 0;
+
+    while i < bytes.len() {
+        let b = bytes[i];
+
+        if b < 0x80 {
+            i += 1;
+            len += 1;
+        } else if b < 0xE0 {
+            i += 2;
+            len += 1;
+        } else if b < 0xF0 {
+            i += 3;
+            len += 1;
+        } else {
+            // a supplementary character is encoded as a surrogate pair
+            i += 4;
+            len += 2;
+        }
+    }
+
+    len
+}
+
+/// encode `s` to UTF-16 at compile time, the rest of the array is filled with null characters
+///
+/// it fails to compile if `N` is not greater than `utf16_len(s)`, use the `w!` macro instead
+pub const fn encode_utf16<const N: usize>(s: &str) -> [u16; N] {
+    let bytes = s.as_bytes();
+    let mut buffer = [0u16; N];
+
+    let mut i = 0;
+    let mut j = 0;
+
+    while i < bytes.len() {
+        let b = bytes[i] as u32;
+
+        let (c, width) = if b < 0x80 {
+            (b, 1)
+        } else if b < 0xE0 {
+            (((b & 0x1F) << 6) | (bytes[i + 1] as u32 & 0x3F), 2)
+        } else if b < 0xF0 {
+            (
+                ((b & 0x0F) << 12)
+                    | ((bytes[i + 1] as u32 & 0x3F) << 6)
+                    | (bytes[i + 2] as u32 & 0x3F),
+                3,
+            )
+        } else {
+            (
+                ((b & 0x07) << 18)
+                    | ((bytes[i + 1] as u32 & 0x3F) << 12)
+                    | ((bytes[i + 2] as u32 & 0x3F) << 6)
+                    | (bytes[i + 3] as u32 & 0x3F),
+                4,
+            )
+        };
+
+        if c >= 0x10000 {
+            let c = c - 0x10000;
+            buffer[j] = 0xD800 | (c >> 10) as u16;
+            buffer[j + 1] = 0xDC00 | (c & 0x3FF) as u16;
+            j += 2;
+        } else {
+            buffer[j] = c as u16;
+            j += 1;
+        }
+
+        i += width;
+    }
+
+    assert!(j < N, "the buffer has no room for the null terminator");
+
+    buffer
+}
+
+/// A `UNICODE_STRING` referencing a static buffer, it is created by `const_unicode_string!`
+#[repr(transparent)]
+pub struct ConstUnicodeString(UNICODE_STRING);
+
+impl ConstUnicodeString {
+    /// `buffer` is a null terminated string, the terminator is not counted in `Length`
+    pub const fn new(buffer: &'static [u16]) -> Self {
+        assert!(
+            !buffer.is_empty() && buffer[buffer.len() - 1] == 0,
+            "the buffer is not null terminated"
+        );
+        assert!(
+            buffer.len() * 2 <= u16::MAX as usize,
+            "the string is longer than 32767 characters"
+        );
+
+        Self(UNICODE_STRING {
+            Length: ((buffer.len() - 1) * 2) as _,
+            MaximumLength: (buffer.len() * 2) as _,
+            Buffer: buffer.as_ptr() as *mut u16,
+        })
+    }
+
+    /// the header to be passed to the kernel APIs, the buffer is read-only and must not be modified
+    pub const fn as_ptr(&self) -> PUNICODE_STRING {
+        &self.0 as *const _ as _
+    }
+
+    pub const fn as_raw(&self) -> &UNICODE_STRING {
+        &self.0
+    }
+
+    /// the characters without the null terminator
+    pub const fn as_slice(&self) -> &[u16] {
+        unsafe { slice::from_raw_parts(self.0.Buffer, self.0.Length as usize / 2) }
+    }
+
+    pub fn to_unicode_string(&self) -> Result<NtUnicodeString, NtError> {
+        NtUnicodeString::from_utf16(self.as_slice())
+    }
+}
+
+impl Deref for ConstUnicodeString {
+    type Target = UNICODE_STRING;
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl fmt::Display for ConstUnicodeString {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for c in char::decode_utf16(self.as_slice().iter().copied()) {
+            fmt::Write::write_char(f, c.unwrap_or(char::REPLACEMENT_CHARACTER))?;
+        }
+
+        Ok(())
+    }
+}
+
+impl fmt::Debug for ConstUnicodeString {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "\"{}\"", self)
+    }
+}
+
+// Safety
+// the buffer is static and never modified
+unsafe impl Send for ConstUnicodeString {}
+unsafe impl Sync for ConstUnicodeString {}
+
+/// encode a string literal to a static null terminated UTF-16 array at compile time, it is a `&'static [u16; N]`
+///
+/// # Example
+/// ```
+/// let name: PCWSTR = w!("FltMgr").as_ptr();
+/// ```
+#[macro_export]
+macro_rules! w {
+    ($s:expr) => {{
+        const S: &str = $s;
+        const N: usize = $crate::unicode::utf16_len(S) + 1;
+        static BUFFER: [u16; N] = $crate::unicode::encode_utf16::<N>(S);
+        &BUFFER
+    }};
+}
+
+/// create a static `UNICODE_STRING` from a string literal at compile time, it is a `&'static ConstUnicodeString`
+///
+/// # Example
+/// ```
+/// static DEVICE_NAME: &ConstUnicodeString = const_unicode_string!("\\Device\\MyDevice");
+/// ```
+#[macro_export]
+macro_rules! const_unicode_string {
+    ($s:expr) => {{
+        static STRING: $crate::unicode::ConstUnicodeString =
+            $crate::unicode::ConstUnicodeString::new($crate::w!($s));
+        &STRING
+    }};
+}
@@ -0,0 +1,221 @@
+//! this module provides `UnloadGuard`, a coordinator of the driver teardown
+//!
+//! the resources are registered while the driver runs, and `shutdown` releases them in an order that
+//! no callback can run into freed code or data:
+//! 1. unregister the callbacks, so no new work comes in
+//! 2. cancel the timers, including the pending `Timer::after` of this crate
+//! 3. signal the stop events and wake the threads
+//! 4. join the threads
+//! 5. flush the queued DPCs(`KeFlushQueuedDpcs`), then free the timers, DPCs and work items
+//! 6. wait for the rundowns, including the fire-and-forget APCs of this crate
+//!
+//! # Example
+//! ```
+//! static UNLOAD: OnceLock<UnloadGuard> = OnceLock::new();
+//!
+//! // in DriverEntry
+//! let guard = UNLOAD.get_or_init(|| UnloadGuard::new().unwrap()).unwrap();
+//!
+//! let stop = Arc::new(EventProperty::new().new_event()?);
+//! let worker = {
+//!     let stop = stop.clone();
+//!     thread::spawn(move || while !stop.get_state() { poll() })?
+//! };
+//!
+//! guard.on_stop(move || stop.set())?;
+//! guard.register_thread(worker)?;
+//! guard.register_timer(timer)?;
+//! guard.register_callback(ob_callbacks)?;
+//!
+//! // in DriverUnload
+//! UNLOAD.get().unwrap().shutdown();
+//! ```
+use core::{any::Any, mem};
+
+use alloc::{boxed::Box, vec::Vec};
+use wdk_sys::{STATUS_INSUFFICIENT_RESOURCES, ntddk::KeFlushQueuedDpcs};
+
+use crate::{
+    apc,
+    dpc::{Dpc, ThreadedDpc},
+    mutex::FastLocked,
+    ntstatus::NtError,
+    thread::{JoinHandle, Waker},
+    timer::{self, HRTimer, Timer},
+    utils::try_box,
+    workitem::WorkItem,
+};
+
+/// A timer that can be cancelled before it is freed
+pub trait CancelTimer {
+    fn cancel(&self);
+}
+
+impl CancelTimer for Timer {
+    fn cancel(&self) {
+        self.stop();
+    }
+}
+
+impl CancelTimer for HRTimer {
+    fn cancel(&self) {
+        self.stop();
+    }
+}
+
+#[derive(Default)]
+struct Resources {
+    callbacks: Vec<Box<dyn Any + Send>>,
+    timers: Vec<Box<dyn CancelTimer + Send>>,
+    stops: Vec<Box<dyn FnOnce() + Send>>,
+    wakers: Vec<Waker>,
+    threads: Vec<JoinHandle>,
+    deferred: Vec<Box<dyn Any + Send>>,
+    rundowns: Vec<Box<dyn FnOnce() + Send>>,
+}
+
+/// push `value` to `list` without panicking on an allocation failure
+fn try_push<T>(list: &mut Vec<T>, value: T) -> Result<(), NtError> {
+    list.try_reserve(1)
+        .map_err(|_| NtError::new(STATUS_INSUFFICIENT_RESOURCES))?;
+    list.push(value);
+
+    Ok(())
+}
+
+/// The registry of the resources to be released at unload, `shutdown` is called on drop
+///
+/// all the methods must be called at IRQL <= APC_LEVEL, and `shutdown` at PASSIVE_LEVEL, a registration fails with
+/// STATUS_UNSUCCESSFUL above APC_LEVEL and with STATUS_INSUFFICIENT_RESOURCES if it can not be allocated, the
+/// resource is dropped at once then
+pub struct UnloadGuard(FastLocked<Resources>);
+
+impl UnloadGuard {
+    pub fn new() -> Result<Self, NtError> {
+        Ok(Self(FastLocked::new(Resources::default())?))
+    }
+
+    fn with<R, F: FnOnce(&mut Resources) -> R>(&self, f: F) -> Result<R, NtError> {
+        let mut resources = self.0.lock()?;
+
+        Ok(f(&mut resources))
+    }
+
+    /// a registration of callbacks, e.g. `ObCallbacks` or `RegistryCallback`, it is unregistered by drop first
+    pub fn register_callback<T: Send + 'static>(&self, registration: T) -> Result<(), NtError> {
+        let registration = try_box(registration)?;
+
+        self.with(|r| try_push(&mut r.callbacks, registration))?
+    }
+
+    /// a timer, it is cancelled before the threads are stopped and freed after the DPCs are flushed
+    pub fn register_timer<T: CancelTimer + Send + 'static>(&self, timer: T) -> Result<(), NtError> {
+        let timer = try_box(timer)?;
+
+        self.with(|r| try_push(&mut r.timers, timer))?
+    }
+
+    /// a routine that signals the threads to stop, e.g. setting a stop event
+    pub fn on_stop<F: FnOnce() + Send + 'static>(&self, f: F) -> Result<(), NtError> {
+        let f = try_box(f)?;
+
+        self.with(|r| try_push(&mut r.stops, f))?
+    }
+
+    /// a waker to interrupt the alertable sleep of a thread on stop
+    pub fn register_waker(&self, waker: Waker) -> Result<(), NtError> {
+        self.with(|r| try_push(&mut r.wakers, waker))?
+    }
+
+    /// a thread to be joined after the stop routines run
+    pub fn register_thread(&self, thread: JoinHandle) -> Result<(), NtError> {
+        self.with(|r| try_push(&mut r.threads, thread))?
+    }
+
+    pub fn register_dpc(&self, dpc: Dpc) -> Result<(), NtError> {
+        self.register_deferred(dpc)
+    }
+
+    pub fn register_threaded_dpc(&self, dpc: ThreadedDpc) -> Result<(), NtError> {
+        self.register_deferred(dpc)
+    }
+
+    pub fn register_work_item(&self, work_item: WorkItem) -> Result<(), NtError> {
+        self.register_deferred(work_item)
+    }
+
+    fn register_deferred<T: Send + 'static>(&self, deferred: T) -> Result<(), NtError> {
+        let deferred = try_box(deferred)?;
+
+        self.with(|r| try_push(&mut r.deferred, deferred))?
+    }
+
+    /// a routine that waits for the outstanding work to drain, it runs last
+    pub fn on_rundown<F: FnOnce() + Send + 'static>(&self, f: F) -> Result<(), NtError> {
+        let f = try_box(f)?;
+
+        self.with(|r| try_push(&mut r.rundowns, f))?
+    }
+
+    /// release all the registered resources in order, the guard can be reused after that
+    ///
+    /// it does nothing if it is called above APC_LEVEL
+    pub fn shutdown(&self) {
+        let mut resources = Resources::default();
+
+        if self.with(|r| mem::swap(r, &mut resources)).is_err() {
+            return;
+        }
+
+        // the registrations made last are released first in each stage
+        while let Some(registration) = resources.callbacks.pop() {
+            drop(registration);
+        }
+
+        resources
+            .timers
+            .iter()
+            .rev()
+            .for_each(|timer| timer.cancel());
+
+        timer::cancel_after_timers();
+
+        while let Some(stop) = resources.stops.pop() {
+            stop();
+        }
+
+        resources.wakers.iter().for_each(|waker| waker.wake());
+
+        while let Some(thread) = resources.threads.pop() {
+            let _ = thread.join();
+        }
+
+        // the DPCs of the cancelled timers may still be running
+        unsafe { KeFlushQueuedDpcs() };
+
+        while let Some(timer) = resources.timers.pop() {
+            drop(timer);
+        }
+
+        while let Some(deferred) = resources.deferred.pop() {
+            drop(deferred);
+        }
+
+        while let Some(rundown) = resources.rundowns.pop() {
+            rundown();
+        }
+
+        apc::flush();
+    }
+}
+
+impl Drop for UnloadGuard {
+    fn drop(&mut self) {
+        self.shutdown();
+    }
+}
+
+// Safety
+// the resources are accessed under the lock, and released at PASSIVE_LEVEL in `shutdown`
+unsafe impl Send for UnloadGuard {}
+unsafe impl Sync for UnloadGuard {}
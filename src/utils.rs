@@ -1,10 +1,16 @@
-use alloc::{boxed::Box, vec::Vec};
-use core::{alloc::Layout, arch::asm, mem, ptr};
+use alloc::{boxed::Box, string::String, vec::Vec};
+use core::{alloc::Layout, arch::asm, mem, ptr, slice};
 use wdk_sys::{
-    _POOL_TYPE::PagedPool, PIO_STACK_LOCATION, PIRP, PKTHREAD, POOL_TYPE, PUNICODE_STRING, PVOID,
-    SIZE_T, SL_PENDING_RETURNED, ULONG, ULONG_PTR, UNICODE_STRING, WCHAR, ntddk::ExFreePoolWithTag,
+    _POOL_TYPE::{NonPagedPool, NonPagedPoolNx, PagedPool},
+    PKTHREAD, POOL_TYPE, PUNICODE_STRING, PVOID, SIZE_T, STATUS_INSUFFICIENT_RESOURCES, ULONG,
+    ULONG_PTR, UNICODE_STRING, WCHAR,
+    ntddk::ExFreePoolWithTag,
 };
 
+#[cfg(feature = "fault-injection")]
+use crate::fault::{self, FaultSite};
+use crate::{dynimport, ntstatus::NtError, os, pool, secret};
+
 #[macro_export]
 macro_rules! handle_to_ulong {
     ($a:expr) => {
@@ -60,25 +66,21 @@ pub(crate) const fn ctl_code(dev_type: u32, function: u32, method: u32, access:
     (dev_type << 16) | ((access) << 14) | ((function) << 2) | (method)
 }
 
-#[allow(non_snake_case)]
-pub(crate) fn IoGetCurrentIrpStackLocation(irp: PIRP) -> PIO_STACK_LOCATION {
-    if !(unsafe { (*irp).CurrentLocation <= (*irp).StackCount }) {
-        panic!();
+/// convert a counted unicode string to an owned `String`
+pub(crate) fn unicode_to_string(s: PUNICODE_STRING) -> Option<String> {
+    if s.is_null() {
+        return None;
     }
 
-    unsafe {
-        (*irp)
-            .Tail
-            .Overlay
-            .__bindgen_anon_2
-            .__bindgen_anon_1
-            .CurrentStackLocation
+    let s = unsafe { &*s };
+
+    if s.Buffer.is_null() {
+        return None;
     }
-}
 
-#[allow(non_snake_case)]
-pub(crate) fn IoMarkIrpPending(irp: PIRP) {
-    (unsafe { *IoGetCurrentIrpStackLocation(irp) }).Control |= SL_PENDING_RETURNED as u8;
+    let buffer = unsafe { slice::from_raw_parts(s.Buffer, s.Length as usize / 2) };
+
+    Some(String::from_utf16_lossy(buffer))
 }
 
 pub(crate) fn utf16_from_str(s: &str) -> Option<Box<UNICODE_STRING>> {
@@ -89,7 +91,76 @@ unsafe extern "C" {
     pub fn ExAllocatePoolWithTag(pool_type: POOL_TYPE, size: SIZE_T, tag: ULONG) -> PVOID;
 }
 
-pub(crate) fn ex_allocate_pool_zero(pool_type: POOL_TYPE, size: SIZE_T, tag: ULONG) -> PVOID {
+/// POOL_FLAG_NON_PAGED
+const POOL_FLAG_NON_PAGED: u64 = 0x40;
+/// POOL_FLAG_NON_PAGED_EXECUTE
+const POOL_FLAG_NON_PAGED_EXECUTE: u64 = 0x80;
+/// POOL_FLAG_PAGED
+const POOL_FLAG_PAGED: u64 = 0x100;
+
+/// the `ExAllocatePool2` flags of a basic pool type
+fn pool_flags(pool_type: POOL_TYPE) -> Option<u64> {
+    match pool_type {
+        NonPagedPool => Some(POOL_FLAG_NON_PAGED_EXECUTE),
+        NonPagedPoolNx => Some(POOL_FLAG_NON_PAGED),
+        PagedPool => Some(POOL_FLAG_PAGED),
+        _ => None,
+    }
+}
+
+/// the header in front of every allocation of `ex_allocate_pool_zero`, it keeps the size charged to the tag and
+/// the alignment of the pool
+#[repr(C, align(16))]
+struct PoolHeader {
+    size: SIZE_T,
+}
+
+const POOL_HEADER_SIZE: usize = mem::size_of::<PoolHeader>();
+
+/// allocate zeroed memory with `ExAllocatePool2` when the system exports it, `ExAllocatePoolWithTag` otherwise
+///
+/// the allocation is charged to `tag`, see the `pool` module, it fails with STATUS_QUOTA_EXCEEDED if the quota of
+/// `tag` is exhausted or STATUS_INSUFFICIENT_RESOURCES, the memory must be freed by `ex_free_pool`
+pub(crate) fn ex_allocate_pool_zero(
+    pool_type: POOL_TYPE,
+    size: SIZE_T,
+    tag: ULONG,
+) -> Result<PVOID, NtError> {
+    #[cfg(feature = "fault-injection")]
+    if fault::inject(FaultSite::Allocation) {
+        return Err(NtError::new(STATUS_INSUFFICIENT_RESOURCES));
+    }
+
+    let total = size
+        .checked_add(POOL_HEADER_SIZE as _)
+        .ok_or_else(|| NtError::new(STATUS_INSUFFICIENT_RESOURCES))?;
+
+    pool::charge(tag, size)?;
+
+    let ptr = allocate_raw(pool_type, total, tag);
+
+    if ptr.is_null() {
+        pool::uncharge(tag, size);
+
+        return Err(NtError::new(STATUS_INSUFFICIENT_RESOURCES));
+    }
+
+    unsafe {
+        ptr.cast::<PoolHeader>().write(PoolHeader { size });
+
+        Ok(ptr.byte_add(POOL_HEADER_SIZE))
+    }
+}
+
+fn allocate_raw(pool_type: POOL_TYPE, size: SIZE_T, tag: ULONG) -> PVOID {
+    if os::supports_pool2() {
+        if let (Some(allocate), Some(flags)) =
+            (dynimport::ExAllocatePool2.get(), pool_flags(pool_type))
+        {
+            return unsafe { allocate(flags, size, tag) };
+        }
+    }
+
     let ptr = unsafe { ExAllocatePoolWithTag(pool_type, size, tag) };
 
     if !ptr.is_null() {
@@ -99,6 +170,53 @@ pub(crate) fn ex_allocate_pool_zero(pool_type: POOL_TYPE, size: SIZE_T, tag: ULO
     ptr
 }
 
+/// free the memory allocated by `ex_allocate_pool_zero` with the same `tag` and give back its charge, the memory is
+/// wiped first if `tag` is zero-on-free
+///
+/// # Safety
+/// `ptr` must be returned by `ex_allocate_pool_zero` and not freed yet
+pub(crate) unsafe fn ex_free_pool(ptr: PVOID, tag: ULONG) {
+    unsafe {
+        let header = ptr.byte_sub(POOL_HEADER_SIZE);
+        let size = header.cast::<PoolHeader>().read().size;
+
+        if pool::uncharge(tag, size) {
+            secret::secure_zero(slice::from_raw_parts_mut(ptr.cast(), size as _));
+        }
+
+        ExFreePoolWithTag(header, tag);
+    }
+}
+
+/// a fallible `Box::new`, `Box::try_new` is not stable yet
+///
+/// it returns STATUS_INSUFFICIENT_RESOURCES instead of calling the allocation error handler which bugchecks
+pub(crate) fn try_box<T>(value: T) -> Result<Box<T>, NtError> {
+    let layout = Layout::new::<T>();
+
+    // a zero sized `T` does not allocate
+    if layout.size() == 0 {
+        return Ok(Box::new(value));
+    }
+
+    #[cfg(feature = "fault-injection")]
+    if fault::inject(FaultSite::Allocation) {
+        return Err(NtError::new(STATUS_INSUFFICIENT_RESOURCES));
+    }
+
+    let ptr = unsafe { alloc::alloc::alloc(layout) } as *mut T;
+
+    if ptr.is_null() {
+        return Err(NtError::new(STATUS_INSUFFICIENT_RESOURCES));
+    }
+
+    unsafe {
+        ptr::write(ptr, value);
+
+        Ok(Box::from_raw(ptr))
+    }
+}
+
 /// stable rust forbids to use a customized allocator with Box<T> like this:
 ///
 /// type PagedBox<T> = alloc::boxed::Box<T, PagedAllocator>;
@@ -108,17 +226,12 @@ const RUST_PAGED_TAG: ULONG = u32::from_ne_bytes(*b"egap");
 
 impl PagedAllocator {
     pub fn allocate(&self, layout: core::alloc::Layout) -> *mut u8 {
-        let ptr = ex_allocate_pool_zero(PagedPool, layout.size() as u64, RUST_PAGED_TAG);
-
-        if ptr == ptr::null_mut() {
-            return ptr::null_mut();
-        }
-
-        ptr.cast()
+        ex_allocate_pool_zero(PagedPool, layout.size() as u64, RUST_PAGED_TAG)
+            .map_or(ptr::null_mut(), |ptr| ptr.cast())
     }
 
     pub fn dealloc(&self, ptr: *mut u8, layout: core::alloc::Layout) {
-        unsafe { ExFreePoolWithTag(ptr.cast(), RUST_PAGED_TAG) };
+        unsafe { ex_free_pool(ptr.cast(), RUST_PAGED_TAG) };
     }
 }
 
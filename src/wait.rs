@@ -0,0 +1,167 @@
+//! this module provides `MultiWait`, a builder of `KeWaitForMultipleObjects`
+//!
+//! any `Dispatchable` object(event, semaphore, timer, thread, process, queue, etc.) can be waited together
+//!
+//! # Example
+//! ```
+//! let mut wait = MultiWait::new()
+//!     .add(&stop)
+//!     .add(&timer)
+//!     .add(&semaphore)
+//!     .timeout(Duration::from_secs(1));
+//!
+//! loop {
+//!     match wait.wait_any()?.status() {
+//!         WaitStatus::Signaled(0) => break,
+//!         WaitStatus::Signaled(1) => on_timer(),
+//!         WaitStatus::Signaled(_) => on_request(),
+//!         _ => on_idle(),
+//!     }
+//! }
+//! ```
+use core::{marker::PhantomData, mem, ptr, time::Duration};
+
+use wdk_sys::{
+    _KWAIT_REASON::Executive,
+    _MODE::KernelMode,
+    _POOL_TYPE::NonPagedPoolNx,
+    _WAIT_TYPE::{WaitAll, WaitAny},
+    KWAIT_BLOCK, MAXIMUM_WAIT_OBJECTS, PKWAIT_BLOCK, PVOID, STATUS_INVALID_PARAMETER,
+    THREAD_WAIT_OBJECTS, WAIT_TYPE,
+    ntddk::KeWaitForMultipleObjects,
+};
+
+use crate::{
+    kobject::{Dispatchable, WaitResult},
+    ntstatus::NtError,
+    raw::AsRawObject,
+    time,
+    utils::{ex_allocate_pool_zero, ex_free_pool},
+};
+
+const WAIT_TAG: u32 = u32::from_ne_bytes(*b"tiaw");
+
+/// A set of up to `MAXIMUM_WAIT_OBJECTS`(64) objects to be waited together, it must be used at IRQL <= APC_LEVEL
+///
+/// the objects are borrowed for the lifetime of the `MultiWait`; when more than `THREAD_WAIT_OBJECTS`(3) objects are
+/// waited, the wait blocks are allocated from the nonpaged pool on the first wait and reused until it is dropped
+pub struct MultiWait<'a> {
+    objects: [PVOID; MAXIMUM_WAIT_OBJECTS as usize],
+    count: usize,
+    /// more than `MAXIMUM_WAIT_OBJECTS` objects were added
+    overflow: bool,
+    timeout: Option<Duration>,
+    alertable: bool,
+    wait_blocks: PKWAIT_BLOCK,
+    _marker: PhantomData<&'a dyn Sync>,
+}
+
+impl<'a> MultiWait<'a> {
+    pub fn new() -> Self {
+        Self {
+            objects: [ptr::null_mut(); MAXIMUM_WAIT_OBJECTS as usize],
+            count: 0,
+            overflow: false,
+            timeout: None,
+            alertable: false,
+            wait_blocks: ptr::null_mut(),
+            _marker: PhantomData,
+        }
+    }
+
+    /// add an object, its index in the wait is the number of the objects added before it
+    ///
+    /// the wait fails with STATUS_INVALID_PARAMETER if more than `MAXIMUM_WAIT_OBJECTS` objects are added
+    pub fn add<D: Dispatchable>(mut self, object: &'a D) -> Self {
+        if self.count < self.objects.len() {
+            self.objects[self.count] = object.as_raw().cast();
+            self.count += 1;
+        } else {
+            self.overflow = true;
+        }
+
+        self
+    }
+
+    /// wait at most `timeout`, the wait is infinite by default
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// the wait can be interrupted by an alert or a user APC
+    pub fn alertable(mut self, alertable: bool) -> Self {
+        self.alertable = alertable;
+        self
+    }
+
+    pub fn len(&self) -> usize {
+        self.count
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.count == 0
+    }
+
+    /// wait until any of the objects is signaled, `WaitResult::index` tells which one
+    pub fn wait_any(&mut self) -> Result<WaitResult, NtError> {
+        self.wait(WaitAny)
+    }
+
+    /// wait until all the objects are signaled
+    pub fn wait_all(&mut self) -> Result<WaitResult, NtError> {
+        self.wait(WaitAll)
+    }
+
+    fn wait(&mut self, wait_type: WAIT_TYPE) -> Result<WaitResult, NtError> {
+        #[cfg(debug_assertions)]
+        if self.timeout != Some(Duration::ZERO) {
+            crate::assert_irql!(<= APC_LEVEL);
+        }
+
+        if self.count == 0 || self.overflow {
+            return Err(NtError::new(STATUS_INVALID_PARAMETER));
+        }
+
+        // the wait blocks embedded in the thread object are used for a few objects
+        if self.count > THREAD_WAIT_OBJECTS as usize && self.wait_blocks.is_null() {
+            self.wait_blocks = ex_allocate_pool_zero(
+                NonPagedPoolNx,
+                (mem::size_of::<KWAIT_BLOCK>() * MAXIMUM_WAIT_OBJECTS as usize) as _,
+                WAIT_TAG,
+            )?
+            .cast();
+        }
+
+        let mut timeout = self.timeout.map(time::relative);
+
+        let status = unsafe {
+            KeWaitForMultipleObjects(
+                self.count as _,
+                self.objects.as_mut_ptr(),
+                wait_type,
+                Executive,
+                KernelMode as _,
+                self.alertable as _,
+                timeout
+                    .as_mut()
+                    .map_or(ptr::null_mut(), |timeout| timeout as *mut _),
+                if self.count > THREAD_WAIT_OBJECTS as usize {
+                    self.wait_blocks
+                } else {
+                    ptr::null_mut()
+                },
+            )
+        };
+
+        Ok(WaitResult::new(status))
+    }
+}
+
+impl<'a> Drop for MultiWait<'a> {
+    fn drop(&mut self) {
+        if !self.wait_blocks.is_null() {
+            unsafe { ex_free_pool(self.wait_blocks.cast(), WAIT_TAG) };
+        }
+    }
+}
@@ -0,0 +1,117 @@
+//! this module provides `WaitGroup`, a countdown of the outstanding operations
+//!
+//! each operation is counted by `add`(or `enter`) and uncounted by `done`, `wait` returns once the count drops to zero.
+//! it is typically used on a shutdown path which must wait until all the asynchronous work(threads, work items, DPCs,
+//! completion routines) has finished before the driver unloads
+//!
+//! # Example
+//! ```
+//! let group = WaitGroup::new()?;
+//!
+//! for _ in 0..4 {
+//!     let _ = thread::spawn_in(&group, || work());
+//! }
+//!
+//! WorkItem::post_in(&group, || flush(), device)?;
+//!
+//! // any IRQL <= DISPATCH_LEVEL
+//! let token = group.enter();
+//! dpc::run_once(move || {
+//!     complete();
+//!     drop(token);
+//! });
+//!
+//! // PASSIVE_LEVEL
+//! group.wait();
+//! ```
+use core::time::Duration;
+
+use crate::{
+    arc::KArc,
+    event::{Event, EventProperty},
+    kobject::{Dispatchable, WaitResult},
+    mutex::StaticSpinLocked,
+    ntstatus::NtError,
+};
+
+struct Inner {
+    count: StaticSpinLocked<usize>,
+    /// a notification event which is signaled while the count is zero
+    idle: Event,
+}
+
+/// A shared counter of outstanding operations, cloning it shares the same counter
+///
+/// `add`, `done` and `enter` can be called at IRQL <= DISPATCH_LEVEL, `wait` at IRQL <= APC_LEVEL
+#[derive(Clone)]
+pub struct WaitGroup(KArc<Inner>);
+
+impl WaitGroup {
+    pub fn new() -> Result<Self, NtError> {
+        let idle = EventProperty::new().initial_state(true).new_event()?;
+
+        Ok(Self(KArc::new(Inner {
+            count: StaticSpinLocked::new(0),
+            idle,
+        })?))
+    }
+
+    /// count `n` more outstanding operations
+    pub fn add(&self, n: usize) {
+        if n == 0 {
+            return;
+        }
+
+        let mut count = self.0.count.lock();
+
+        // the event changes with the count under the lock, so a waiter never sees it signaled with a non-zero count
+        if *count == 0 {
+            self.0.idle.clear();
+        }
+
+        *count += n;
+    }
+
+    /// an operation has finished, the waiters are released when it is the last one
+    pub fn done(&self) {
+        let mut count = self.0.count.lock();
+
+        debug_assert!(*count > 0, "WaitGroup::done called more times than add");
+
+        *count = count.saturating_sub(1);
+
+        if *count == 0 {
+            self.0.idle.set();
+        }
+    }
+
+    /// count one operation which is finished when the returned token is dropped
+    pub fn enter(&self) -> WaitGroupToken {
+        self.add(1);
+
+        WaitGroupToken(self.clone())
+    }
+
+    /// the number of the outstanding operations
+    pub fn count(&self) -> usize {
+        *self.0.count.lock()
+    }
+
+    /// wait until the count drops to zero
+    pub fn wait(&self) -> WaitResult {
+        self.0.idle.wait(false)
+    }
+
+    pub fn wait_for(&self, timeout: Duration) -> WaitResult {
+        self.0.idle.wait_for(timeout, false)
+    }
+}
+
+/// An outstanding operation of a `WaitGroup`, see `WaitGroup::enter`
+pub struct WaitGroupToken(WaitGroup);
+
+impl Drop for WaitGroupToken {
+    fn drop(&mut self) {
+        self.0.done();
+    }
+}
@@ -0,0 +1,196 @@
+//! this module provides `Watchdog`, a liveness monitor of the long-running worker threads
+//!
+//! every monitored thread registers a `Heartbeat` and beats it on each round of its loop, a periodic timer checks
+//! the heartbeats and reports a thread which has missed `missed_limit` intervals in a row to the user callback, e.g.
+//! to log it, set its stop event or bugcheck with a useful code, a thread is reported again only after it has
+//! recovered and got stuck once more
+//!
+//! # Example
+//! ```
+//! let watchdog = Watchdog::new(Duration::from_secs(1), 5, |stuck| {
+//!     trace_error!(0, "{} has not responded for {:?}", stuck.name, stuck.silent_for);
+//! })?;
+//!
+//! let heartbeat = watchdog.register("scanner")?;
+//!
+//! thread::spawn(move || {
+//!     while let Ok(request) = rx.recv() {
+//!         heartbeat.beat();
+//!         scan(request);
+//!     }
+//! })?;
+//! ```
+use core::{
+    sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+    time::Duration,
+};
+
+use alloc::{boxed::Box, vec::Vec};
+use wdk_sys::{PKTHREAD, STATUS_INSUFFICIENT_RESOURCES, STATUS_INVALID_PARAMETER};
+
+use crate::{
+    arc::KArc,
+    mutex::StaticSpinLocked,
+    ntstatus::NtError,
+    time::KInstant,
+    timer::Timer,
+    utils::{KeGetCurrentThread, try_box},
+};
+
+/// A thread reported by the watchdog
+#[derive(Clone, Copy, Debug)]
+pub struct StuckThread {
+    pub name: &'static str,
+    /// the thread which beat the heartbeat last
+    pub thread: PKTHREAD,
+    /// the time since the last beat
+    pub silent_for: Duration,
+}
+
+struct Slot {
+    name: &'static str,
+    thread: AtomicUsize,
+    /// the performance counter of the last beat
+    last: AtomicU64,
+    reported: AtomicBool,
+}
+
+type StuckHandler = Box<dyn Fn(&StuckThread) + Send + Sync>;
+
+struct Inner {
+    slots: StaticSpinLocked<Vec<KArc<Slot>>>,
+    /// how long a thread may stay silent before it is reported
+    limit: Duration,
+    on_stuck: StuckHandler,
+}
+
+impl Inner {
+    /// report the silent threads, it runs in the DPC of the timer
+    fn check(&self) {
+        let now = KInstant::now();
+        let slots = self.slots.lock();
+
+        for slot in slots.iter() {
+            let silent_for = now
+                .saturating_duration_since(KInstant::from_ticks(slot.last.load(Ordering::Relaxed)));
+
+            if silent_for < self.limit || slot.reported.swap(true, Ordering::Relaxed) {
+                continue;
+            }
+
+            (self.on_stuck)(&StuckThread {
+                name: slot.name,
+                thread: slot.thread.load(Ordering::Relaxed) as _,
+                silent_for,
+            });
+        }
+    }
+}
+
+/// A monitor of the registered heartbeats, it stops checking when dropped
+pub struct Watchdog {
+    inner: KArc<Inner>,
+    /// the checking DPC is flushed when the timer is dropped
+    _timer: Timer,
+}
+
+impl Watchdog {
+    /// check the heartbeats every `interval`, a thread which has not beaten for `missed_limit` intervals is
+    /// reported to `on_stuck`
+    ///
+    /// `on_stuck` runs in a DPC at DISPATCH_LEVEL with the heartbeats locked, so it must not register or drop a
+    /// `Heartbeat`, it fails with STATUS_INVALID_PARAMETER if `interval` or `missed_limit` is zero
+    pub fn new<F: Fn(&StuckThread) + Send + Sync + 'static>(
+        interval: Duration,
+        missed_limit: u32,
+        on_stuck: F,
+    ) -> Result<Self, NtError> {
+        if interval.is_zero() || missed_limit == 0 {
+            return Err(NtError::new(STATUS_INVALID_PARAMETER));
+        }
+
+        let inner = KArc::new(Inner {
+            slots: StaticSpinLocked::new(Vec::new()),
+            limit: interval.saturating_mul(missed_limit),
+            on_stuck: try_box(on_stuck)?,
+        })?;
+
+        let timer = {
+            let inner = inner.clone();
+
+            Timer::try_new(move || inner.check(), false)?
+        };
+
+        timer.start(interval, interval);
+
+        Ok(Self {
+            inner,
+            _timer: timer,
+        })
+    }
+
+    /// monitor the current thread as `name`, it can be called at IRQL <= DISPATCH_LEVEL
+    ///
+    /// the heartbeat starts beaten, the thread is monitored until it is dropped
+    pub fn register(&self, name: &'static str) -> Result<Heartbeat, NtError> {
+        let slot = KArc::new(Slot {
+            name,
+            thread: AtomicUsize::new(KeGetCurrentThread() as _),
+            last: AtomicU64::new(KInstant::now().ticks()),
+            reported: AtomicBool::new(false),
+        })?;
+
+        {
+            let mut slots = self.inner.slots.lock();
+
+            slots
+                .try_reserve(1)
+                .map_err(|_| NtError::new(STATUS_INSUFFICIENT_RESOURCES))?;
+            slots.push(slot.clone());
+        }
+
+        Ok(Heartbeat {
+            inner: self.inner.clone(),
+            slot,
+        })
+    }
+
+    /// the number of the monitored threads
+    pub fn len(&self) -> usize {
+        self.inner.slots.lock().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// The heartbeat of a monitored thread, see `Watchdog::register`
+///
+/// it is usually moved into the thread it monitors, the thread which beats it last is the one reported
+pub struct Heartbeat {
+    inner: KArc<Inner>,
+    slot: KArc<Slot>,
+}
+
+impl Heartbeat {
+    /// tell the watchdog the thread is alive, it can be called at any IRQL
+    pub fn beat(&self) {
+        self.slot
+            .last
+            .store(KInstant::now().ticks(), Ordering::Relaxed);
+        self.slot
+            .thread
+            .store(KeGetCurrentThread() as _, Ordering::Relaxed);
+        self.slot.reported.store(false, Ordering::Relaxed);
+    }
+}
+
+impl Drop for Heartbeat {
+    fn drop(&mut self) {
+        self.inner
+            .slots
+            .lock()
+            .retain(|slot| !KArc::ptr_eq(slot, &self.slot));
+    }
+}
@@ -1,5 +1,5 @@
 use core::{
-    mem::{self},
+    mem::{self, ManuallyDrop},
     ops::{Deref, DerefMut},
     ptr::{self, NonNull},
 };
@@ -13,20 +13,21 @@ use alloc::{borrow::ToOwned, boxed::Box, vec::Vec};
 
 use wdk::nt_success;
 use wdk_sys::{
-    _DEVICE_OBJECT, _DRIVER_OBJECT, _UNICODE_STRING, DEVICE_OBJECT, DRIVER_OBJECT,
-    FILE_DEVICE_SECURE_OPEN, FILE_DEVICE_UNKNOWN, FILE_READ_DATA, IO_NO_INCREMENT,
-    IRP_MJ_MAXIMUM_FUNCTION, LIST_ENTRY, NTSTATUS, PDEVICE_OBJECT, PDRIVER_OBJECT, PFILE_OBJECT,
-    PIRP, PUNICODE_STRING, STATUS_DEVICE_ALREADY_ATTACHED, STATUS_INSUFFICIENT_RESOURCES,
-    STATUS_INVALID_PARAMETER_2, STATUS_NOT_FOUND, STATUS_NOT_IMPLEMENTED, STATUS_PENDING,
-    STATUS_SUCCESS, UNICODE_STRING,
+    _DEVICE_OBJECT, _DRIVER_OBJECT, _UNICODE_STRING, DEVICE_OBJECT, DO_BUFFERED_IO,
+    DO_DEVICE_INITIALIZING, DO_DIRECT_IO, DO_POWER_PAGABLE, DRIVER_OBJECT, FILE_DEVICE_SECURE_OPEN,
+    FILE_DEVICE_UNKNOWN, FILE_READ_DATA, IO_NO_INCREMENT, IRP_MJ_MAXIMUM_FUNCTION, LIST_ENTRY,
+    NTSTATUS, PDEVICE_OBJECT, PDRIVER_OBJECT, PFILE_OBJECT, PIRP, PUNICODE_STRING,
+    STATUS_DEVICE_ALREADY_ATTACHED, STATUS_INSUFFICIENT_RESOURCES, STATUS_INVALID_PARAMETER_2,
+    STATUS_NOT_FOUND, STATUS_NOT_IMPLEMENTED, STATUS_PENDING, STATUS_SUCCESS, UNICODE_STRING,
     ntddk::{
         IoAttachDeviceToDeviceStackSafe, IoCreateDevice, IoCreateSymbolicLink, IoDeleteDevice,
         IoDeleteSymbolicLink, IoDetachDevice, IoGetAttachedDeviceReference,
         IoGetDeviceObjectPointer, IoGetRelatedDeviceObject, IofCompleteRequest,
+        ObfDereferenceObject,
     },
 };
 
-use crate::utils::IoMarkIrpPending;
+use crate::irp::{IoMarkIrpPending, pass_down};
 
 #[allow(non_snake_case, non_camel_case_types)]
 #[repr(C)]
@@ -129,13 +130,31 @@ pub trait IrpDispatch {
     /// - an Err(e) indicates the IRP can not be compleete successfully, there is two cases</br>
     /// 1). if `e.code()` != STATUS_PENDING, the `Irp.IoStatus.Status` will be set to `e.code()` and the IRP will be completed immediately</br>
     /// 2). if `e.code()` == is STATUS_PENDINGthe, the `Irp.IoStatus.Status` will be set to `e.code()` and the IRP will be marked as pending, IRP is not completed </br>
+    /// 3). if `e.code()` == STATUS_IRP_QUEUED, the IRP is not touched at all and STATUS_PENDING is returned, see `STATUS_IRP_QUEUED`
     fn dispatch(&self, device: PDEVICE_OBJECT, irp: PIRP) -> Result<u64, NtError>;
+
+    /// only asked on the filter device of a `DeviceStack`, return `false` to handle the IRP in `dispatch`
+    ///
+    /// by default all the IRPs are passed down to the lower device untouched
+    fn pass_through(&self, _device: PDEVICE_OBJECT, _irp: PIRP) -> bool {
+        true
+    }
 }
 
+/// the IRP has been marked as pending and handed over to a queue which completes it, e.g. a `CancelSafeQueue`
+///
+/// return `Err(NtError::new(STATUS_IRP_QUEUED))` from `IrpDispatch::dispatch` in this case, the IRP may already be
+/// completed and freed on another processor, so the dispatcher returns STATUS_PENDING without touching it
+///
+/// it is an informational status with the customer bit set, so it never collides with a status of the system
+pub const STATUS_IRP_QUEUED: NTSTATUS = 0x6000_0103;
+
 /// just a helper structure for IRP dispatch handler and device stack manipulation
-struct DispatchContext<'a> {
-    irp_handler: &'a mut dyn IrpDispatch,
-    attach_to: PDEVICE_OBJECT,
+pub(crate) struct DispatchContext<'a> {
+    pub(crate) irp_handler: &'a mut dyn IrpDispatch,
+    pub(crate) attach_to: PDEVICE_OBJECT,
+    /// the device is the filter of a `DeviceStack`, the IRPs are passed down by default
+    pub(crate) filter: bool,
 }
 
 /// A Driver Wrapper for WDM device model, Not Owned
@@ -354,6 +373,7 @@ impl OwnedDevice {
                     DispatchContext {
                         irp_handler: value.as_mut(),
                         attach_to: ptr::null_mut(),
+                        filter: false,
                     },
                 );
             }
@@ -416,6 +436,7 @@ impl OwnedDevice {
                     DispatchContext {
                         irp_handler: value.as_mut(),
                         attach_to: ptr::null_mut(),
+                        filter: false,
                     },
                 );
             }
@@ -560,10 +581,20 @@ extern "C" fn irp_dispatch_stub(device: PDEVICE_OBJECT, irp: PIRP) -> NTSTATUS {
     // read the dynamic handler out from device extension
     let dispatch_context = unsafe { ptr::read(ext as *mut DispatchContext) };
 
+    // a filter does not complete the IRPs it is not interested in, the lower driver does
+    if dispatch_context.filter
+        && !dispatch_context.attach_to.is_null()
+        && dispatch_context.irp_handler.pass_through(device, irp)
+    {
+        return pass_down(dispatch_context.attach_to, irp);
+    }
+
     // there is an alternative:
     // read a &dyn IrpDispatch(size of 16 bytes) from a *const &dyn IrpDispatch(size of 8 bytes)
     // let dispatch_context: &dyn IrpDispatch = unsafe { ptr::read(ext as _) };
     match dispatch_context.irp_handler.dispatch(device, irp) {
+        // the IRP belongs to a queue now, it must not be touched
+        Err(e) if e.code() == STATUS_IRP_QUEUED => return STATUS_PENDING,
         Ok(length) => {
             status = STATUS_SUCCESS;
             unsafe { (*irp).IoStatus.Information = length }
@@ -626,3 +657,121 @@ impl Deref for DeviceObject {
         &self.0
     }
 }
+
+/// A filter device attached on top of the device stack of a named device, i.e. a legacy filter
+///
+/// the filter takes the type, characteristics and I/O flags of the device it is attached to,
+/// every IRP is passed down to the lower device unless `IrpDispatch::pass_through` of the handler returns `false`
+///
+/// on drop, the filter is detached first so no new IRP comes in, then it is deleted, and the target device is
+/// dereferenced at last
+///
+/// # Example
+/// ```
+/// struct KeyboardFilter;
+///
+/// impl IrpDispatch for KeyboardFilter {
+///     fn dispatch(&self, device: PDEVICE_OBJECT, irp: PIRP) -> Result<u64, NtError> {
+///         // only the IRPs the filter does not pass through get here, they are completed with the result
+///         Err(NtError::new(STATUS_ACCESS_DENIED))
+///     }
+///
+///     fn pass_through(&self, device: PDEVICE_OBJECT, irp: PIRP) -> bool {
+///         unsafe { Irp::from_raw(irp) }.major_function() != IRP_MJ_CREATE
+///     }
+/// }
+///
+/// let stack = DeviceStack::attach(&driver, "\\Device\\KeyboardClass0", Some(Box::new(KeyboardFilter)))?;
+/// ```
+///
+/// # Note
+/// the driver must not unload while the IRPs handled by the filter are still in flight
+pub struct DeviceStack {
+    device: ManuallyDrop<OwnedDevice>,
+    /// the file object referencing the target device, see `IoGetDeviceObjectPointer`
+    file_object: PFILE_OBJECT,
+}
+
+impl DeviceStack {
+    /// create an unnamed filter device and attach it to the device stack of `target_name`
+    ///
+    /// all the IRPs are passed down if `dispatch_handler` is `None`, it must be called at PASSIVE_LEVEL
+    pub fn attach(
+        driver: &Driver,
+        target_name: &str,
+        dispatch_handler: Option<Box<dyn IrpDispatch>>,
+    ) -> Result<Self, NtError> {
+        let mut target: PDEVICE_OBJECT = ptr::null_mut();
+        let mut file_object: PFILE_OBJECT = ptr::null_mut();
+
+        let mut name: Box<_UNICODE_STRING> = utils::utf16_from_str(target_name)
+            .ok_or(NtError::new(STATUS_INSUFFICIENT_RESOURCES))?;
+
+        cvt(unsafe {
+            IoGetDeviceObjectPointer(name.as_mut(), FILE_READ_DATA, &mut file_object, &mut target)
+        })?;
+
+        let property = DeviceProperty::new()
+            .set_type(unsafe { (*target).DeviceType })
+            .set_characteristics(unsafe { (*target).Characteristics });
+
+        let handler = dispatch_handler.unwrap_or_else(|| Box::new(EmptyDispatch(())));
+
+        let mut device = match OwnedDevice::new(driver, property, Some(handler)) {
+            Ok(device) => device,
+            Err(e) => {
+                unsafe { ObfDereferenceObject(file_object.cast()) };
+                return Err(e);
+            }
+        };
+
+        device.get_ext_mut().filter = true;
+
+        // the device stack may grow between `IoGetDeviceObjectPointer` and here, the filter is attached to
+        // whatever device on the top of the stack
+        if let Err(e) = device.attach(target) {
+            drop(device);
+            unsafe { ObfDereferenceObject(file_object.cast()) };
+            return Err(e);
+        }
+
+        let lower = device.get_attached_device();
+
+        device.Flags |=
+            unsafe { (*lower).Flags } & (DO_BUFFERED_IO | DO_DIRECT_IO | DO_POWER_PAGABLE);
+        device.Flags &= !DO_DEVICE_INITIALIZING;
+
+        Ok(Self {
+            device: ManuallyDrop::new(device),
+            file_object,
+        })
+    }
+
+    /// the filter device
+    pub fn device(&self) -> &OwnedDevice {
+        &self.device
+    }
+
+    pub fn as_raw(&self) -> PDEVICE_OBJECT {
+        self.device.as_raw()
+    }
+
+    /// the device right below the filter, the IRPs are passed down to it
+    pub fn lower(&self) -> PDEVICE_OBJECT {
+        self.device.get_attached_device()
+    }
+}
+
+impl Drop for DeviceStack {
+    fn drop(&mut self) {
+        // detach and delete the filter before the target device can go away
+        unsafe {
+            ManuallyDrop::drop(&mut self.device);
+
+            ObfDereferenceObject(self.file_object.cast());
+        }
+    }
+}
+
+unsafe impl Send for DeviceStack {}
+unsafe impl Sync for DeviceStack {}
@@ -1,23 +1,33 @@
-use core::{mem, ptr};
+use core::{mem, ptr, time::Duration};
 
-use alloc::boxed::Box;
+use alloc::{boxed::Box, collections::VecDeque, vec::Vec};
 use wdk_sys::{
     _WORK_QUEUE_TYPE::DelayedWorkQueue,
-    PDEVICE_OBJECT, PIO_WORKITEM, PVOID, STATUS_INSUFFICIENT_RESOURCES,
+    PDEVICE_OBJECT, PIO_WORKITEM, PVOID, STATUS_INSUFFICIENT_RESOURCES, STATUS_INVALID_PARAMETER,
     ntddk::{IoAllocateWorkItem, IoFreeWorkItem, IoQueueWorkItemEx},
 };
 
-use crate::ntstatus::NtError;
+use crate::{
+    arc::KArc,
+    kobject::WaitResult,
+    mutex::StaticSpinLocked,
+    ntstatus::NtError,
+    utils::try_box,
+    waitgroup::{WaitGroup, WaitGroupToken},
+};
 
 /// Owned Active workitem wrapper
 pub struct WorkItem {
     inner: PIO_WORKITEM,
-    callback: Box<dyn Fn()>,
+    callback: Box<dyn Fn() + Send + Sync>,
 }
 
 impl WorkItem {
     /// Create a workitem
-    pub fn new<F: Fn() + 'static>(f: F, device: PDEVICE_OBJECT) -> Result<Self, NtError> {
+    pub fn new<F: Fn() + Send + Sync + 'static>(
+        f: F,
+        device: PDEVICE_OBJECT,
+    ) -> Result<Self, NtError> {
         let workitem = unsafe { IoAllocateWorkItem(device) };
 
         if workitem.is_null() {
@@ -65,17 +75,307 @@ impl WorkItem {
             return Err(NtError::new(STATUS_INSUFFICIENT_RESOURCES));
         }
 
-        unsafe {
-            IoQueueWorkItemEx(
-                workitem,
-                Some(worker_routine_oneshot_stub::<F>),
-                DelayedWorkQueue,
-                Box::into_raw(callback) as _,
-            );
+        unsafe { queue_oneshot(workitem, callback) };
+
+        Ok(())
+    }
+
+    /// the same as `post`, the work item is counted by `group` until `f` returns
+    pub fn post_in<F: FnOnce() + 'static>(
+        group: &WaitGroup,
+        f: F,
+        device: PDEVICE_OBJECT,
+    ) -> Result<(), NtError> {
+        let token = group.enter();
+
+        Self::post(
+            move || {
+                let _token = token;
+                f()
+            },
+            device,
+        )
+    }
+}
+
+// Safety
+// the work item is owned and the callback is `Send + Sync`, it runs in a system worker thread anyway
+unsafe impl Send for WorkItem {}
+
+type Job = Box<dyn FnOnce() + Send>;
+
+struct QueueState<K> {
+    /// the pending items of every key which has a worker or waits for one, sorted by the key
+    ///
+    /// a sorted `Vec` rather than a `BTreeMap`, so an insertion can be reserved fallibly under the spin lock
+    pending: Vec<(K, VecDeque<Job>)>,
+    /// the keys with pending items but no worker, in the order they became ready
+    ready: VecDeque<K>,
+    in_flight: usize,
+}
+
+impl<K: Ord> QueueState<K> {
+    /// the index of `key` in `pending`, or the index it is inserted at
+    fn find(&self, key: &K) -> Result<usize, usize> {
+        self.pending.binary_search_by(|(k, _)| k.cmp(key))
+    }
+
+    fn items(&mut self, key: &K) -> Option<&mut VecDeque<Job>> {
+        let index = self.find(key).ok()?;
+
+        Some(&mut self.pending[index].1)
+    }
+}
+
+struct QueueInner<K> {
+    device: PDEVICE_OBJECT,
+    max_in_flight: usize,
+    state: StaticSpinLocked<QueueState<K>>,
+    /// counts the running workers
+    workers: WaitGroup,
+}
+
+unsafe impl<K: Send> Send for QueueInner<K> {}
+unsafe impl<K: Send> Sync for QueueInner<K> {}
+
+/// A work queue which runs the items of the same key one after another, in the order they are pushed
+///
+/// the items of different keys run in parallel on the system worker threads, up to `max_in_flight` workers at
+/// once, a worker keeps the slot and moves on to the next ready key when its key has no more items
+///
+/// # Example
+/// ```
+/// let queue = OrderedWorkQueue::new(device, 4)?;
+///
+/// // the events of a process are handled in order, the processes are handled in parallel
+/// queue.push(pid, move || handle_event(event))?;
+///
+/// // on unload, at PASSIVE_LEVEL
+/// queue.wait_idle();
+/// ```
+pub struct OrderedWorkQueue<K> {
+    inner: KArc<QueueInner<K>>,
+}
+
+impl<K: Ord + Clone + Send + 'static> OrderedWorkQueue<K> {
+    /// the work items are allocated for `device`, which must outlive the queued items
+    ///
+    /// it fails with STATUS_INVALID_PARAMETER if `max_in_flight` is 0
+    pub fn new(device: PDEVICE_OBJECT, max_in_flight: usize) -> Result<Self, NtError> {
+        if max_in_flight == 0 {
+            return Err(NtError::new(STATUS_INVALID_PARAMETER));
         }
 
+        Ok(Self {
+            inner: KArc::new(QueueInner {
+                device,
+                max_in_flight,
+                state: StaticSpinLocked::new(QueueState {
+                    pending: Vec::new(),
+                    ready: VecDeque::new(),
+                    in_flight: 0,
+                }),
+                workers: WaitGroup::new()?,
+            })?,
+        })
+    }
+
+    /// queue `f` behind the items of `key`, it can be called at IRQL <= DISPATCH_LEVEL
+    ///
+    /// it fails with STATUS_INSUFFICIENT_RESOURCES if the item or a work item can not be allocated, `f` is dropped
+    /// in that case
+    pub fn push<F: FnOnce() + Send + 'static>(&self, key: K, f: F) -> Result<(), NtError> {
+        let mut job: Job = try_box(f)?;
+
+        // most items are pushed behind a key which has a worker already
+        match self.append(&key, job) {
+            Ok(()) => return Ok(()),
+            Err(rejected) => job = rejected,
+        }
+
+        // the work item is allocated before the key is inserted, so a key is never left without a worker
+        let workitem = unsafe { IoAllocateWorkItem(self.inner.device) };
+
+        if workitem.is_null() {
+            return Err(NtError::new(STATUS_INSUFFICIENT_RESOURCES));
+        }
+
+        let worker = {
+            let inner = self.inner.clone();
+            let key = key.clone();
+            let token = self.inner.workers.enter();
+
+            try_box(move || run_worker(inner, key, token))
+        };
+
+        let worker = match worker {
+            Ok(worker) => worker,
+            Err(e) => {
+                unsafe { IoFreeWorkItem(workitem) };
+                return Err(e);
+            }
+        };
+
+        let mut items = VecDeque::new();
+
+        if items.try_reserve(1).is_err() {
+            unsafe { IoFreeWorkItem(workitem) };
+            return Err(NtError::new(STATUS_INSUFFICIENT_RESOURCES));
+        }
+
+        // everything is reserved before it is changed, so nothing is allocated infallibly under the spin lock and a
+        // failure leaves the state as it was
+        let start = {
+            let mut state = self.inner.state.lock();
+
+            match state.find(&key) {
+                // another item of the key is pushed in the meantime
+                Ok(index) => {
+                    let pending = &mut state.pending[index].1;
+
+                    match pending.try_reserve(1) {
+                        Ok(_) => {
+                            pending.push_back(job);
+                            Ok(false)
+                        }
+                        Err(_) => Err(job),
+                    }
+                }
+                Err(index) => {
+                    let reserved =
+                        state.pending.try_reserve(1).is_ok() && state.ready.try_reserve(1).is_ok();
+
+                    if !reserved {
+                        Err(job)
+                    } else {
+                        items.push_back(job);
+                        state.pending.insert(index, (key.clone(), items));
+
+                        if state.in_flight < self.inner.max_in_flight {
+                            state.in_flight += 1;
+                            Ok(true)
+                        } else {
+                            state.ready.push_back(key);
+                            Ok(false)
+                        }
+                    }
+                }
+            }
+        };
+
+        if matches!(start, Ok(true)) {
+            unsafe { queue_oneshot(workitem, worker) };
+        } else {
+            // the worker is dropped without running, which drops its token
+            drop(worker);
+            unsafe { IoFreeWorkItem(workitem) };
+        }
+
+        match start {
+            Ok(_) => Ok(()),
+            Err(job) => {
+                drop(job);
+                Err(NtError::new(STATUS_INSUFFICIENT_RESOURCES))
+            }
+        }
+    }
+
+    /// push `job` behind the items of `key` if it has any, otherwise `job` is given back
+    fn append(&self, key: &K, job: Job) -> Result<(), Job> {
+        let mut state = self.inner.state.lock();
+
+        let Some(items) = state.items(key) else {
+            return Err(job);
+        };
+
+        if items.try_reserve(1).is_err() {
+            return Err(job);
+        }
+
+        items.push_back(job);
+
         Ok(())
     }
+
+    /// the number of the items which have not started yet
+    pub fn pending(&self) -> usize {
+        self.inner
+            .state
+            .lock()
+            .pending
+            .iter()
+            .map(|(_, items)| items.len())
+            .sum()
+    }
+
+    /// the number of the running workers
+    pub fn in_flight(&self) -> usize {
+        self.inner.state.lock().in_flight
+    }
+
+    /// wait until all the pushed items have run, it must be called at IRQL <= APC_LEVEL
+    pub fn wait_idle(&self) -> WaitResult {
+        self.inner.workers.wait()
+    }
+
+    pub fn wait_idle_for(&self, timeout: Duration) -> WaitResult {
+        self.inner.workers.wait_for(timeout)
+    }
+}
+
+/// run the items of `key`, then the ones of the ready keys, until no key is ready
+fn run_worker<K: Ord + Clone>(inner: KArc<QueueInner<K>>, mut key: K, _token: WaitGroupToken) {
+    loop {
+        let job = {
+            let mut state = inner.state.lock();
+
+            loop {
+                let has_items = state.items(&key).is_some_and(|items| !items.is_empty());
+
+                if !has_items {
+                    if let Ok(index) = state.find(&key) {
+                        state.pending.remove(index);
+                    }
+
+                    match state.ready.pop_front() {
+                        Some(next) => {
+                            key = next;
+                            continue;
+                        }
+                        None => {
+                            state.in_flight -= 1;
+                            break None;
+                        }
+                    }
+                }
+
+                // a busy key yields to the keys waiting for a worker, its items stay in order
+                if let Some(next) = state.ready.pop_front() {
+                    state.ready.push_back(key.clone());
+                    key = next;
+                }
+
+                break state.items(&key).and_then(VecDeque::pop_front);
+            }
+        };
+
+        match job {
+            Some(job) => job(),
+            None => return,
+        }
+    }
+}
+
+/// queue `callback` on a allocated work item, both of them are freed after `callback` runs
+unsafe fn queue_oneshot<F: FnOnce()>(workitem: PIO_WORKITEM, callback: Box<F>) {
+    unsafe {
+        IoQueueWorkItemEx(
+            workitem,
+            Some(worker_routine_oneshot_stub::<F>),
+            DelayedWorkQueue,
+            Box::into_raw(callback) as _,
+        );
+    }
 }
 
 extern "C" fn worker_routine_oneshot_stub<F: FnOnce()>(